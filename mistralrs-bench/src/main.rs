@@ -3,12 +3,15 @@ use clap::Parser;
 use cli_table::{format::Justify, print_stdout, Cell, CellStruct, Style, Table};
 use mistralrs_core::{
     get_auto_device_map_params, get_model_dtype, initialize_logging, paged_attn_supported,
-    parse_isq_value, Constraint, DefaultSchedulerMethod, DeviceLayerMapMetadata, DeviceMapMetadata,
-    DeviceMapSetting, DrySamplingParams, IsqType, Loader, LoaderBuilder, MemoryGpuConfig,
-    MistralRs, MistralRsBuilder, ModelSelected, NormalRequest, PagedAttentionConfig, Request,
-    RequestMessage, Response, SamplingParams, SchedulerConfig, TokenSource, Usage,
+    parse_isq_value, BenchmarkConfig, BenchmarkResults, Constraint, DefaultSchedulerMethod,
+    DeviceLayerMapMetadata, DeviceMapMetadata, DeviceMapSetting, DrySamplingParams, IsqType,
+    Loader, LoaderBuilder, MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelBenchmark,
+    ModelSelected, NormalRequest, PagedAttentionConfig, PreprocessingBenchmark,
+    PreprocessingBenchmarkResults, Request, RequestMessage, Response, SamplerBackend,
+    SamplingParams, SchedulerConfig, TokenSource, Usage,
 };
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{fmt::Display, num::NonZeroUsize};
 use tokio::sync::mpsc::channel;
 use tracing::{info, warn};
@@ -16,6 +19,12 @@ use tracing::{info, warn};
 enum TestName {
     Prompt(usize),
     Gen(usize),
+    /// Generation with the layers mapped across devices, with or without overlapping the work
+    /// of the devices.
+    MixedGen {
+        n: usize,
+        pipelined: bool,
+    },
 }
 
 impl Display for TestName {
@@ -23,6 +32,11 @@ impl Display for TestName {
         let name = match self {
             TestName::Prompt(n) => format!("pp {}", n),
             TestName::Gen(n) => format!("tg {}", n),
+            TestName::MixedGen { n, pipelined } => format!(
+                "tg {} ({})",
+                n,
+                if *pipelined { "pipelined" } else { "serial" }
+            ),
         };
         write!(f, "{}", name)
     }
@@ -61,11 +75,23 @@ fn run_bench(
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repeat_last_n: None,
         max_len: Some(n_gen),
         stop_toks: None,
         logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        include_stop_str_in_output: false,
+        stop_on_balanced: None,
+        reserved_output_tokens: 0,
+        continue_word: false,
+        tfs_z: None,
+        length_penalty: None,
+        sampler: SamplerBackend::Native,
+        strip_response_prefix: None,
+        hybrid_decode: None,
+        adaptive_temperature: None,
+        stop_lookback: None,
     };
     let sender = mistralrs.get_sender().unwrap();
     let (tx, mut rx) = channel(10_000);
@@ -84,6 +110,12 @@ fn run_bench(
         logits_processors: None,
         return_raw_logits: false,
         web_search_options: None,
+        enable_thinking: None,
+        strict_system_role: false,
+        dynamic_grammar: None,
+        return_effective_params: false,
+        documents: None,
+        adapters: None,
     });
 
     let mut usages = Vec::new();
@@ -130,6 +162,95 @@ fn run_bench(
     })
 }
 
+/// Number of distinct tokens after the shared prefix of each request with `--shared-prefix`.
+const SHARED_PREFIX_SUFFIX_LEN: usize = 16;
+
+struct SharedPrefixResult {
+    prefix_cache: bool,
+    concurrency: usize,
+    /// Wall-clock time of each wave of concurrent requests.
+    wave_times: Vec<Duration>,
+}
+
+/// Send `repetitions` waves of `concurrency` requests which share their first `n_prefix` tokens,
+/// and measure the wall-clock time of each wave. With the prefix cache, an unmeasured first wave
+/// fills the cache.
+fn run_shared_prefix_bench(
+    mistralrs: Arc<MistralRs>,
+    n_prefix: usize,
+    concurrency: usize,
+    repetitions: usize,
+    prefix_cache: bool,
+) -> anyhow::Result<SharedPrefixResult> {
+    let sender = mistralrs.get_sender().unwrap();
+    let (tx, mut rx) = channel(10_000);
+    let prefix = (1000..1000 + n_prefix as u32).collect::<Vec<_>>();
+
+    let mut wave_times = Vec::new();
+    let mut n_requests = 0;
+    for wave in 0..repetitions + usize::from(prefix_cache) {
+        let start = Instant::now();
+        for _ in 0..concurrency {
+            // The first token after the prefix differs between requests.
+            let mut tokens = prefix.clone();
+            tokens.push(500 + (n_requests % 500) as u32);
+            tokens.extend([1u32].repeat(SHARED_PREFIX_SUFFIX_LEN - 1));
+            n_requests += 1;
+
+            let req = Request::Normal(NormalRequest {
+                id: mistralrs.next_request_id(),
+                messages: RequestMessage::CompletionTokens(tokens),
+                sampling_params: SamplingParams {
+                    max_len: Some(1),
+                    ..SamplingParams::deterministic()
+                },
+                response: tx.clone(),
+                return_logprobs: false,
+                is_streaming: false,
+                constraint: Constraint::None,
+                suffix: None,
+                tools: None,
+                tool_choice: None,
+                logits_processors: None,
+                return_raw_logits: false,
+                web_search_options: None,
+                enable_thinking: None,
+                strict_system_role: false,
+                dynamic_grammar: None,
+                return_effective_params: false,
+                documents: None,
+                adapters: None,
+            });
+            sender.blocking_send(req).expect("Expected receiver.");
+        }
+        for _ in 0..concurrency {
+            match rx.blocking_recv() {
+                Some(Response::CompletionDone(_)) => {}
+                Some(Response::InternalError(e)) => {
+                    unreachable!("Got an internal error: {e:?}");
+                }
+                Some(Response::CompletionModelError(e, _)) => {
+                    unreachable!("Got a model error: {e:?}");
+                }
+                Some(Response::ValidationError(e)) => {
+                    unreachable!("Got a validation error: {e:?}");
+                }
+                Some(_) => unreachable!(),
+                None => unreachable!("Expected a Done response, got None",),
+            }
+        }
+        if !(prefix_cache && wave == 0) {
+            wave_times.push(start.elapsed());
+        }
+    }
+
+    Ok(SharedPrefixResult {
+        prefix_cache,
+        concurrency,
+        wave_times,
+    })
+}
+
 fn get_tok_s(result: &BenchResult) -> UncertainTokSec {
     let ts_measurements = match result.test_name {
         TestName::Prompt(_) => result
@@ -137,7 +258,7 @@ fn get_tok_s(result: &BenchResult) -> UncertainTokSec {
             .iter()
             .map(|u| u.avg_prompt_tok_per_sec)
             .collect::<Vec<_>>(),
-        TestName::Gen(_) => result
+        TestName::Gen(_) | TestName::MixedGen { .. } => result
             .usages
             .iter()
             .map(|u| u.avg_compl_tok_per_sec)
@@ -161,7 +282,7 @@ fn get_ms_tok(result: &BenchResult) -> UncertainTokSec {
             .iter()
             .map(|u| 1000. / u.avg_prompt_tok_per_sec)
             .collect::<Vec<_>>(),
-        TestName::Gen(_) => result
+        TestName::Gen(_) | TestName::MixedGen { .. } => result
             .usages
             .iter()
             .map(|u| 1000. / u.avg_compl_tok_per_sec)
@@ -219,6 +340,153 @@ fn print_usage(model: &str, device: &Device, results: Vec<BenchResult>) {
     print_stdout(table).expect("print table");
 }
 
+fn print_shared_prefix_usage(
+    model: &str,
+    device: &Device,
+    n_prefix: usize,
+    results: Vec<SharedPrefixResult>,
+) {
+    let backend = match device {
+        Device::Cpu => "CPU",
+        Device::Cuda(_) => "CUDA",
+        Device::Metal(_) => "Metal",
+    };
+    let mean_ms = |r: &SharedPrefixResult| {
+        r.wave_times
+            .iter()
+            .map(|t| t.as_secs_f32() * 1000.)
+            .sum::<f32>()
+            / r.wave_times.len() as f32
+    };
+    let rows: Vec<Vec<CellStruct>> = results
+        .iter()
+        .map(|r| {
+            let mean = mean_ms(r);
+            let variance = r
+                .wave_times
+                .iter()
+                .map(|t| (mean - t.as_secs_f32() * 1000.).powf(2.))
+                .sum::<f32>()
+                / r.wave_times.len() as f32;
+            let uncached = results
+                .iter()
+                .find(|u| !u.prefix_cache && u.concurrency == r.concurrency)
+                .map(mean_ms)
+                .unwrap_or(mean);
+            vec![
+                model.cell(),
+                backend.cell(),
+                format!("pp {n_prefix}+{SHARED_PREFIX_SUFFIX_LEN}").cell(),
+                if r.prefix_cache { "on" } else { "off" }.cell(),
+                r.concurrency.cell().justify(Justify::Right),
+                UncertainTokSec {
+                    mean,
+                    std_dev: variance.sqrt(),
+                }
+                .cell()
+                .justify(Justify::Right),
+                format!("{:.2}x", uncached / mean)
+                    .cell()
+                    .justify(Justify::Right),
+            ]
+        })
+        .collect();
+
+    let table = rows
+        .table()
+        .title(vec![
+            "model".cell().bold(true),
+            "backend".cell().bold(true),
+            "test".cell().bold(true),
+            "prefix cache".cell().bold(true),
+            "concurrency".cell().bold(true),
+            "ms/wave".cell().bold(true),
+            "speedup".cell().bold(true),
+        ])
+        .bold(true);
+    print_stdout(table).expect("print table");
+}
+
+fn print_preprocessing_usage(model: &str, results: PreprocessingBenchmarkResults) {
+    let row = |mode: String, rps: f64| {
+        vec![
+            model.cell(),
+            mode.cell(),
+            results.requests.cell().justify(Justify::Right),
+            results.prompt_tokens.cell().justify(Justify::Right),
+            format!("{rps:.2}").cell().justify(Justify::Right),
+        ]
+    };
+    let rows = vec![
+        row("sequential".to_string(), results.sequential_rps),
+        row(
+            format!("pool ({} threads)", results.pool_threads),
+            results.pool_rps,
+        ),
+    ];
+
+    let table = rows
+        .table()
+        .title(vec![
+            "model".cell().bold(true),
+            "preprocessing".cell().bold(true),
+            "requests".cell().bold(true),
+            "prompt tokens".cell().bold(true),
+            "req/s".cell().bold(true),
+        ])
+        .bold(true);
+    print_stdout(table).expect("print table");
+}
+
+fn print_direct_usage(model: &str, device: &Device, results: BenchmarkResults) {
+    let backend = match device {
+        Device::Cpu => "CPU",
+        Device::Cuda(_) => "CUDA",
+        Device::Metal(_) => "Metal",
+    };
+    let rows: Vec<Vec<CellStruct>> = results
+        .runs
+        .iter()
+        .map(|r| {
+            vec![
+                model.cell(),
+                backend.cell(),
+                r.prompt_len.cell().justify(Justify::Right),
+                r.decode_len.cell().justify(Justify::Right),
+                r.batch_size.cell().justify(Justify::Right),
+                format!("{:.2}", r.time_to_first_token.as_secs_f64() * 1000.)
+                    .cell()
+                    .justify(Justify::Right),
+                format!("{:.2}", r.prompt_throughput_tps)
+                    .cell()
+                    .justify(Justify::Right),
+                format!("{:.2}", r.decode_throughput_tps)
+                    .cell()
+                    .justify(Justify::Right),
+            ]
+        })
+        .collect();
+
+    let table = rows
+        .table()
+        .title(vec![
+            "model".cell().bold(true),
+            "backend".cell().bold(true),
+            "pp".cell().bold(true),
+            "tg".cell().bold(true),
+            "batch".cell().bold(true),
+            "ttft ms".cell().bold(true),
+            "pp t/s".cell().bold(true),
+            "tg t/s".cell().bold(true),
+        ])
+        .bold(true);
+    print_stdout(table).expect("print table");
+    println!(
+        "Overall: {:.2} prompt t/s, {:.2} decode t/s, {} MB of device memory used",
+        results.prompt_throughput_tps, results.decode_throughput_tps, results.memory_used_mb
+    );
+}
+
 fn warmup_run(mistralrs: Arc<MistralRs>) {
     let sampling_params = SamplingParams {
         temperature: Some(0.1),
@@ -228,11 +496,23 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repeat_last_n: None,
         max_len: Some(5),
         stop_toks: None,
         logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        include_stop_str_in_output: false,
+        stop_on_balanced: None,
+        reserved_output_tokens: 0,
+        continue_word: false,
+        tfs_z: None,
+        length_penalty: None,
+        sampler: SamplerBackend::Native,
+        strip_response_prefix: None,
+        hybrid_decode: None,
+        adaptive_temperature: None,
+        stop_lookback: None,
     };
     let sender = mistralrs.get_sender().unwrap();
     let (tx, mut rx) = channel(10_000);
@@ -255,6 +535,12 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         logits_processors: None,
         return_raw_logits: false,
         web_search_options: None,
+        enable_thinking: None,
+        strict_system_role: false,
+        dynamic_grammar: None,
+        return_effective_params: false,
+        documents: None,
+        adapters: None,
     });
 
     sender
@@ -338,6 +624,48 @@ struct Args {
     /// Number of tokens to batch the prompt step into. This can help with OOM errors when in the prompt step, but reduces performance.
     #[arg(long = "prompt-batchsize")]
     prompt_chunksize: Option<usize>,
+
+    /// Compare generation with and without overlapping the work of the devices when the layers are mapped
+    /// across several devices (for example with `--num-device-layers`). This requires a concurrency of at least 2.
+    #[arg(long = "mixed-placement", default_value_t = false)]
+    mixed_placement: bool,
+
+    /// Run the model directly on synthetic inputs, without the scheduler or sampling, and report the
+    /// time to first token and the prompt and decode throughput. PagedAttention is disabled.
+    #[arg(long, default_value_t = false)]
+    direct: bool,
+
+    /// Prompt lengths to run with `--direct`. Defaults to `--n-prompt`.
+    #[arg(long, value_parser, value_delimiter = ',')]
+    prompt_lengths: Option<Vec<usize>>,
+
+    /// Numbers of tokens to decode with `--direct`. Defaults to `--n-gen`.
+    #[arg(long, value_parser, value_delimiter = ',')]
+    decode_lengths: Option<Vec<usize>>,
+
+    /// Number of unmeasured runs of each shape with `--direct`.
+    #[arg(long, default_value_t = 1)]
+    warmup_runs: usize,
+
+    /// Measure how many chat requests per second can be rendered with the chat template and
+    /// tokenized, one after the other and on the preprocessing thread pool, without running the model.
+    /// The prompts have about `--n-prompt` words.
+    #[arg(long, default_value_t = false)]
+    preprocessing: bool,
+
+    /// Number of requests to preprocess with `--preprocessing`.
+    #[arg(long, default_value_t = 1000)]
+    preprocessing_requests: usize,
+
+    /// Number of threads of the preprocessing thread pool. Defaults to the available parallelism, up to 4.
+    #[arg(long)]
+    preprocessing_threads: Option<usize>,
+
+    /// Compare the wall-clock time of requests sharing a prefix of `--n-prompt` tokens, such as a
+    /// system prompt, with and without the prefix cache. Each request adds 16 distinct tokens and
+    /// generates one. PagedAttention is disabled, as the prefix cache does not support it.
+    #[arg(long, default_value_t = false)]
+    shared_prefix: bool,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -398,12 +726,13 @@ fn main() -> anyhow::Result<()> {
     info!("Model kind is: {}", loader.get_kind().to_string());
 
     // Parse device mapper
-    let mapper = if let Some(device_layers) = args.num_device_layers {
+    let device_map = args.num_device_layers.map(|device_layers| {
         if device_layers.len() == 1 && device_layers[0].parse::<usize>().is_ok() {
             let layers = device_layers[0].parse::<usize>().unwrap();
-            DeviceMapSetting::Map(DeviceMapMetadata::from_num_device_layers(vec![
-                DeviceLayerMapMetadata { ordinal: 0, layers },
-            ]))
+            DeviceMapMetadata::from_num_device_layers(vec![DeviceLayerMapMetadata {
+                ordinal: 0,
+                layers,
+            }])
         } else {
             let mut mapping = Vec::new();
             for layer in device_layers {
@@ -427,13 +756,23 @@ fn main() -> anyhow::Result<()> {
                     layers: num,
                 });
             }
-            DeviceMapSetting::Map(DeviceMapMetadata::from_num_device_layers(mapping))
+            DeviceMapMetadata::from_num_device_layers(mapping)
         }
-    } else {
-        DeviceMapSetting::Auto(auto_device_map_params)
+    });
+    if args.mixed_placement && device_map.is_none() {
+        anyhow::bail!("`--mixed-placement` requires `--num-device-layers`.");
+    }
+    // With `--mixed-placement`, the model is first loaded with pipelining and then reloaded without.
+    let mapper = match &device_map {
+        Some(device_map) => {
+            DeviceMapSetting::Map(device_map.clone().with_pipelining(args.mixed_placement))
+        }
+        None => DeviceMapSetting::Auto(auto_device_map_params),
     };
 
-    let no_paged_attn = if device.is_cuda() || mistralrs_core::distributed::use_nccl() {
+    let no_paged_attn = if args.direct || args.shared_prefix {
+        true
+    } else if device.is_cuda() || mistralrs_core::distributed::use_nccl() {
         args.no_paged_attn
     } else if device.is_metal() {
         !args.paged_attn
@@ -500,7 +839,7 @@ fn main() -> anyhow::Result<()> {
 
     let pipeline = loader.load_model_from_hf(
         None,
-        token_source,
+        token_source.clone(),
         &dtype,
         &device,
         false,
@@ -510,6 +849,31 @@ fn main() -> anyhow::Result<()> {
     )?;
     info!("Model loaded.");
 
+    if args.direct {
+        let config = BenchmarkConfig {
+            prompt_lengths: args.prompt_lengths.unwrap_or(vec![args.n_prompt]),
+            batch_sizes: args.concurrency.unwrap(),
+            decode_lengths: args.decode_lengths.unwrap_or(vec![args.n_gen]),
+            warmup_runs: args.warmup_runs,
+        };
+        info!("Starting direct benchmarks.");
+        let results = ModelBenchmark::run(&mut *pipeline.blocking_lock(), config)?;
+        print_direct_usage(&model_name, &device, results);
+        return Ok(());
+    }
+
+    if args.preprocessing {
+        info!("Starting preprocessing benchmarks.");
+        let results = PreprocessingBenchmark::run(
+            &*pipeline.blocking_lock(),
+            args.preprocessing_requests,
+            args.n_prompt,
+            args.preprocessing_threads,
+        )?;
+        print_preprocessing_usage(&model_name, results);
+        return Ok(());
+    }
+
     let scheduler_config = if cache_config.is_some() {
         // Handle case where we may have device mapping
         if let Some(ref cache_config) = pipeline.blocking_lock().get_metadata().cache_config {
@@ -535,7 +899,7 @@ fn main() -> anyhow::Result<()> {
             ),
         }
     };
-    let mistralrs = MistralRsBuilder::new(pipeline, scheduler_config, false, None)
+    let mistralrs = MistralRsBuilder::new(pipeline.clone(), scheduler_config.clone(), false, None)
         .with_no_prefix_cache(true)
         .with_disable_eos_stop(true)
         .build();
@@ -543,11 +907,50 @@ fn main() -> anyhow::Result<()> {
     info!("Starting warmup run.");
     warmup_run(mistralrs.clone());
     info!("Finished warmup run.");
+
+    if args.shared_prefix {
+        let cached = MistralRsBuilder::new(pipeline, scheduler_config, false, None)
+            .with_disable_eos_stop(true)
+            .build();
+        info!("Starting shared prefix benchmarks.");
+        let mut results = Vec::new();
+        for concurrency in args.concurrency.as_ref().unwrap() {
+            for (engine, prefix_cache) in [(&mistralrs, false), (&cached, true)] {
+                results.push(run_shared_prefix_bench(
+                    engine.clone(),
+                    args.n_prompt,
+                    *concurrency,
+                    args.repetitions,
+                    prefix_cache,
+                )?);
+            }
+        }
+        print_shared_prefix_usage(&model_name, &device, args.n_prompt, results);
+        return Ok(());
+    }
     info!("Starting benchmarks.");
 
+    let mut all_results = vec![];
     for concurrency in args.concurrency.as_ref().unwrap() {
         let mut results = vec![];
-        if args.n_gen > 0 {
+        if args.n_gen > 0 && args.mixed_placement {
+            let r = run_bench(
+                mistralrs.clone(),
+                RequestMessage::Completion {
+                    text: "Rust".to_string(),
+                    echo_prompt: false,
+                    best_of: None,
+                },
+                args.n_gen - 1,
+                *concurrency,
+                args.repetitions,
+                TestName::MixedGen {
+                    n: args.n_gen,
+                    pipelined: true,
+                },
+            )?;
+            results.push(r);
+        } else if args.n_gen > 0 {
             let r = run_bench(
                 mistralrs.clone(),
                 RequestMessage::Completion {
@@ -577,6 +980,55 @@ fn main() -> anyhow::Result<()> {
             results.push(r);
         }
 
+        all_results.push(results);
+    }
+
+    if args.n_gen > 0 && args.mixed_placement {
+        // Free the pipelined model before loading the model again without pipelining.
+        drop(mistralrs);
+        drop(pipeline);
+        let pipeline = loader.load_model_from_hf(
+            None,
+            token_source,
+            &dtype,
+            &device,
+            false,
+            DeviceMapSetting::Map(device_map.unwrap().with_pipelining(false)),
+            args.in_situ_quant,
+            cache_config,
+        )?;
+        let mistralrs = MistralRsBuilder::new(pipeline, scheduler_config, false, None)
+            .with_no_prefix_cache(true)
+            .with_disable_eos_stop(true)
+            .build();
+        warmup_run(mistralrs.clone());
+        for (concurrency, results) in args
+            .concurrency
+            .as_ref()
+            .unwrap()
+            .iter()
+            .zip(&mut all_results)
+        {
+            let r = run_bench(
+                mistralrs.clone(),
+                RequestMessage::Completion {
+                    text: "Rust".to_string(),
+                    echo_prompt: false,
+                    best_of: None,
+                },
+                args.n_gen - 1,
+                *concurrency,
+                args.repetitions,
+                TestName::MixedGen {
+                    n: args.n_gen,
+                    pipelined: false,
+                },
+            )?;
+            results.insert(0, r);
+        }
+    }
+
+    for results in all_results {
         print_usage(&model_name, &device, results);
     }
 
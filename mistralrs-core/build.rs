@@ -7,7 +7,10 @@ fn main() {
         use std::{path::PathBuf, vec};
         println!("cargo:rerun-if-changed=build.rs");
         let build_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
-        let lib_files = vec!["src/cuda/nonzero_bitwise.cu", "src/cuda/sort.cu"];
+        let mut lib_files = vec!["src/cuda/nonzero_bitwise.cu", "src/cuda/sort.cu"];
+        if cfg!(feature = "flash-decoding") {
+            lib_files.push("src/cuda/flash_decoding.cu");
+        }
         for lib_file in lib_files.iter() {
             println!("cargo:rerun-if-changed={lib_file}");
         }
@@ -3,7 +3,11 @@
 #[cfg(feature = "metal")]
 use std::sync::atomic::AtomicUsize;
 
-use crate::{pipeline::text_models_inputs_processor::FlashParams, MemoryUsage};
+use crate::{
+    flash_decoding::{flash_decoding, FLASH_DECODING_MIN_KV_LEN},
+    pipeline::text_models_inputs_processor::FlashParams,
+    MemoryUsage,
+};
 
 use candle_core::{Device, Result, Tensor};
 use mistralrs_quant::MatMul;
@@ -283,9 +287,10 @@ impl Sdpa {
     /// - v: (b_sz, n_kv_heads, q_len, head_dim)
     ///
     /// The attention implementation is dispatched as follows:
-    /// 1) If `use_flash_attn == true` (CUDA), use a flash attention V2 kernel
-    /// 2) If decoding and using a Metal device, use a fused kkernel
-    /// 2) Otherwise, use the "naive" SDPA implementation (with optimized mask+softmax+scale application)
+    /// 1) If decoding over a long KV cache (CUDA, `flash-decoding` feature), use a Flash Decoding kernel
+    /// 2) If `use_flash_attn == true` (CUDA), use a flash attention V2 kernel
+    /// 3) If decoding and using a Metal device, use a fused kkernel
+    /// 4) Otherwise, use the "naive" SDPA implementation (with optimized mask+softmax+scale application)
     #[allow(unused_variables, clippy::too_many_arguments)]
     pub fn run_attention(
         &self,
@@ -298,7 +303,23 @@ impl Sdpa {
     ) -> Result<Tensor> {
         let (b_sz, n_attn_heads, seq_len, head_dim) = q.dims4()?;
         let (_, _, _, k_head_dim) = k.dims4()?;
-        let (_, _, _, v_head_dim) = v.dims4()?;
+        let (_, _, kv_len, v_head_dim) = v.dims4()?;
+        // Decoding with one block per head leaves most of the GPU idle while the KV cache is
+        // read, so it is split over more blocks.
+        if cfg!(feature = "flash-decoding")
+            && seq_len == 1
+            && q.device().is_cuda()
+            && mask.is_none()
+            && head_dim == k_head_dim
+            && k_head_dim == v_head_dim
+            && kv_len >= FLASH_DECODING_MIN_KV_LEN
+            && sdpa_params
+                .sliding_window
+                .is_none_or(|window| kv_len <= window)
+        {
+            return flash_decoding(q, k, v, sdpa_params);
+        }
+
         if sdpa_params.use_flash_attn && q.device().is_cuda() {
             // flash-attn expects (b_sz, seq_len, nheads, head_dim)
             let q = q.transpose(1, 2)?;
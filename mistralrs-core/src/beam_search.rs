@@ -0,0 +1,442 @@
+//! Beam search decoding. The sequences of a beam search request are its live beams: after each
+//! step the most likely continuations of all of them are merged by cumulative logprob to pick the
+//! next beams, and the continuations ending with an EOS token are collected as finished
+//! hypotheses, of which the one with the best length-normalized score is returned.
+
+use serde::{Deserialize, Serialize};
+
+use crate::sampler::Logprobs;
+
+fn default_length_penalty() -> f32 {
+    1.0
+}
+
+/// The score of a hypothesis of `len` generated tokens, its cumulative logprob divided by the
+/// GNMT length penalty `((5 + len) / 6)^length_penalty`. Beam search and `best_of` both rank by it.
+#[allow(clippy::cast_precision_loss)]
+pub(crate) fn length_normalized_score(
+    cumulative_logprob: f32,
+    len: usize,
+    length_penalty: f32,
+) -> f32 {
+    cumulative_logprob / ((5. + len as f32) / 6.).powf(length_penalty)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+/// Parameters of beam search decoding.
+pub struct BeamSearchConfig {
+    /// Number of hypotheses kept at each step.
+    pub num_beams: usize,
+    /// Exponent of the GNMT length normalization of the final scores: the cumulative logprob of a
+    /// hypothesis is divided by `((5 + len) / 6)^length_penalty`, as for `best_of`. Higher values
+    /// favor longer hypotheses, 0 ranks by the cumulative logprob alone.
+    #[serde(default = "default_length_penalty")]
+    pub length_penalty: f32,
+    /// Stop as soon as `num_beams` hypotheses are finished, instead of once none of the live
+    /// beams can score better than them.
+    #[serde(default)]
+    pub early_stopping: bool,
+}
+
+impl BeamSearchConfig {
+    /// Beam search with `num_beams` beams, a length penalty of 1 and no early stopping.
+    pub fn new(num_beams: usize) -> Self {
+        Self {
+            num_beams,
+            length_penalty: default_length_penalty(),
+            early_stopping: false,
+        }
+    }
+
+    /// The length-normalized score of a hypothesis of `len` generated tokens.
+    pub(crate) fn score(&self, cumulative_logprob: f32, len: usize) -> f32 {
+        length_normalized_score(cumulative_logprob, len, self.length_penalty)
+    }
+
+    /// Merge the candidates of the live beams, the most likely continuations of each beam, by
+    /// cumulative logprob. The first `n_beams` continuations which do not end with an EOS token
+    /// are the next beams, and the EOS continuations ranked among the first `n_beams` finish a
+    /// hypothesis.
+    fn select(
+        &self,
+        cumulative_logprobs: &[f32],
+        candidates: &[Vec<Logprobs>],
+        n_beams: usize,
+        is_eos: impl Fn(u32) -> bool,
+    ) -> BeamStep {
+        let mut ranked = candidates
+            .iter()
+            .enumerate()
+            .flat_map(|(beam, candidates)| candidates.iter().map(move |c| (beam, c)))
+            .collect::<Vec<_>>();
+        // Stable, so ties keep the order of the beams and of their candidates.
+        ranked.sort_by(|(a, x), (b, y)| {
+            (cumulative_logprobs[*b] + y.logprob).total_cmp(&(cumulative_logprobs[*a] + x.logprob))
+        });
+
+        let mut step = BeamStep::default();
+        for (rank, (beam, candidate)) in ranked.into_iter().enumerate() {
+            if step.next.len() == n_beams {
+                break;
+            }
+            if !is_eos(candidate.token) {
+                step.next.push((beam, candidate.clone()));
+            } else if rank < n_beams {
+                step.finished.push((beam, candidate.clone()));
+            }
+        }
+        step
+    }
+}
+
+#[derive(Debug, Default)]
+struct BeamStep {
+    /// The next beams, as the index of the beam they continue and their token.
+    next: Vec<(usize, Logprobs)>,
+    /// The continuations which finish a hypothesis with an EOS token.
+    finished: Vec<(usize, Logprobs)>,
+}
+
+/// The state of a beam search: the best finished hypotheses, at most `num_beams`, with their
+/// scores.
+pub(crate) struct BeamHypotheses<T> {
+    config: BeamSearchConfig,
+    finished: Vec<(f32, T)>,
+}
+
+impl<T> BeamHypotheses<T> {
+    pub(crate) fn new(config: BeamSearchConfig) -> Self {
+        Self {
+            config,
+            finished: Vec::new(),
+        }
+    }
+
+    /// Add a finished hypothesis, dropping the worst one if there are more than `num_beams`.
+    fn add(&mut self, score: f32, hypothesis: T) {
+        self.finished.push((score, hypothesis));
+        if self.finished.len() > self.config.num_beams {
+            if let Some(worst) = self.worst() {
+                self.finished.remove(worst);
+            }
+        }
+    }
+
+    /// The hypothesis with the worst score, the last one added among equal scores.
+    fn worst(&self) -> Option<usize> {
+        self.finished
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| b.0.total_cmp(&a.0))
+            .map(|(i, _)| i)
+    }
+
+    /// Whether the search is over given the best cumulative logprob of the live beams after
+    /// `len` generated tokens: `num_beams` hypotheses are finished and either early stopping is
+    /// set or none of the live beams scores better than the worst of them.
+    fn is_done(&self, best_live_logprob: f32, len: usize) -> bool {
+        if self.finished.len() < self.config.num_beams {
+            return false;
+        }
+        self.config.early_stopping
+            || self.worst().is_some_and(|worst| {
+                self.finished[worst].0 >= self.config.score(best_live_logprob, len)
+            })
+    }
+
+    /// Advance the search by one token. `candidates` are the most likely continuations of the
+    /// live beams with the given cumulative logprobs, ordered by decreasing logprob, and `len` is
+    /// the number of generated tokens including the next one.
+    ///
+    /// Returns up to `n_beams` next beams as the index of the beam they continue and their token,
+    /// or `None` if the search is over, in which case the result is given by [`Self::take_best`].
+    /// If it is over because `at_max_len` is set, the next beams are hypotheses too. `hypothesis`
+    /// builds a hypothesis from a beam and its last token.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn step(
+        &mut self,
+        cumulative_logprobs: &[f32],
+        candidates: &[Vec<Logprobs>],
+        n_beams: usize,
+        len: usize,
+        at_max_len: bool,
+        is_eos: impl Fn(u32) -> bool,
+        mut hypothesis: impl FnMut(usize, &Logprobs) -> T,
+    ) -> Option<Vec<(usize, Logprobs)>> {
+        let step = self
+            .config
+            .select(cumulative_logprobs, candidates, n_beams, is_eos);
+        for (beam, tok) in &step.finished {
+            let score = self
+                .config
+                .score(cumulative_logprobs[*beam] + tok.logprob, len);
+            self.add(score, hypothesis(*beam, tok));
+        }
+
+        if at_max_len {
+            for (beam, tok) in &step.next {
+                let score = self
+                    .config
+                    .score(cumulative_logprobs[*beam] + tok.logprob, len);
+                self.add(score, hypothesis(*beam, tok));
+            }
+            return None;
+        }
+        let is_done = match step.next.first() {
+            Some((beam, tok)) => self.is_done(cumulative_logprobs[*beam] + tok.logprob, len),
+            None => true,
+        };
+        (!is_done).then_some(step.next)
+    }
+
+    /// Remove the hypothesis with the best score, the first one added among equal scores.
+    pub(crate) fn take_best(&mut self) -> Option<T> {
+        let best = self
+            .finished
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| b.0.total_cmp(&a.0))
+            .map(|(i, _)| i)?;
+        Some(self.finished.remove(best).1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use candle_core::{Device, Tensor};
+    use rand::{Rng, SeedableRng};
+    use rand_isaac::Isaac64Rng;
+
+    use super::{BeamHypotheses, BeamSearchConfig};
+    use crate::sampler::{Logprobs, Sampler, SamplerBackend};
+
+    const EOS: u32 = 0;
+    const VOCAB: usize = 16;
+
+    fn sampler() -> Sampler {
+        Sampler::new(
+            None,
+            0,
+            None,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            1.0,
+            0.0,
+            None,
+            SamplerBackend::Native,
+            None,
+            None,
+            vec![],
+        )
+        .unwrap()
+    }
+
+    /// Logits which only depend on the context, so the decoding is reproducible.
+    fn logits(context: &[u32]) -> Tensor {
+        let seed = context.iter().fold(17u64, |acc, t| {
+            acc.wrapping_mul(31).wrapping_add(u64::from(*t))
+        });
+        let mut rng = Isaac64Rng::seed_from_u64(seed);
+        let logits = (0..VOCAB)
+            .map(|_| rng.random::<f32>() * 6.)
+            .collect::<Vec<_>>();
+        Tensor::new(logits, &Device::Cpu).unwrap()
+    }
+
+    fn greedy(prompt: &[u32], max_len: usize) -> Vec<u32> {
+        let sampler = sampler();
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(0)));
+        let mut toks = prompt.to_vec();
+        while toks.len() - prompt.len() < max_len {
+            let tok = sampler
+                .sample(logits(&toks), &toks, false, rng.clone(), false)
+                .unwrap()
+                .token;
+            toks.push(tok);
+            if tok == EOS {
+                break;
+            }
+        }
+        toks[prompt.len()..].to_vec()
+    }
+
+    /// Beam search over `logits`, with the beams as (tokens, cumulative logprob).
+    fn beam_search(prompt: &[u32], max_len: usize, config: BeamSearchConfig) -> Vec<u32> {
+        let sampler = sampler();
+        let mut hypotheses = BeamHypotheses::new(config);
+        let mut beams = vec![(prompt.to_vec(), 0f32)];
+        loop {
+            let candidates = beams
+                .iter()
+                .map(|(toks, _)| {
+                    sampler
+                        .beam_candidates(logits(toks), toks, 2 * config.num_beams, false)
+                        .unwrap()
+                })
+                .collect::<Vec<_>>();
+            let cumulative = beams.iter().map(|(_, lp)| *lp).collect::<Vec<_>>();
+            let len = beams[0].0.len() - prompt.len() + 1;
+            let next = hypotheses.step(
+                &cumulative,
+                &candidates,
+                config.num_beams,
+                len,
+                len == max_len,
+                |tok| tok == EOS,
+                |beam, tok: &Logprobs| {
+                    let mut toks = beams[beam].0[prompt.len()..].to_vec();
+                    toks.push(tok.token);
+                    toks
+                },
+            );
+            match next {
+                Some(next) => {
+                    beams = next
+                        .into_iter()
+                        .map(|(beam, tok)| {
+                            let mut toks = beams[beam].0.clone();
+                            toks.push(tok.token);
+                            (toks, beams[beam].1 + tok.logprob)
+                        })
+                        .collect();
+                }
+                None => return hypotheses.take_best().unwrap(),
+            }
+        }
+    }
+
+    #[test]
+    fn one_beam_matches_greedy() {
+        for prompt in [vec![1], vec![3, 5], vec![7, 2, 9], vec![4, 4, 4, 4]] {
+            for max_len in [1, 4, 32] {
+                for early_stopping in [false, true] {
+                    let config = BeamSearchConfig {
+                        num_beams: 1,
+                        length_penalty: 1.0,
+                        early_stopping,
+                    };
+                    assert_eq!(
+                        beam_search(&prompt, max_len, config),
+                        greedy(&prompt, max_len),
+                        "prompt {prompt:?}, max_len {max_len}"
+                    );
+                }
+            }
+        }
+    }
+
+    fn candidate(token: u32, prob: f32) -> Logprobs {
+        Logprobs {
+            token,
+            logprob: prob.log10(),
+            bytes: None,
+            top_logprobs: None,
+        }
+    }
+
+    #[test]
+    fn finds_more_likely_sequence_than_greedy() {
+        // Greedy takes 1 (0.6) then 3 (0.3), beam search 2 (0.4) then 4 (0.9).
+        let mut hypotheses = BeamHypotheses::new(BeamSearchConfig::new(2));
+        let first = hypotheses
+            .step(
+                &[0.],
+                &[vec![candidate(1, 0.6), candidate(2, 0.4)]],
+                2,
+                1,
+                false,
+                |tok| tok == EOS,
+                |_, tok| vec![tok.token],
+            )
+            .unwrap();
+        assert_eq!(
+            first.iter().map(|(b, t)| (*b, t.token)).collect::<Vec<_>>(),
+            [(0, 1), (0, 2)]
+        );
+
+        let cumulative = [0.6f32.log10(), 0.4f32.log10()];
+        let candidates = [
+            vec![candidate(3, 0.3), candidate(5, 0.3)],
+            vec![candidate(4, 0.9), candidate(6, 0.1)],
+        ];
+        let next = hypotheses.step(
+            &cumulative,
+            &candidates,
+            2,
+            2,
+            true,
+            |tok| tok == EOS,
+            |beam, tok| vec![first[beam].1.token, tok.token],
+        );
+        assert!(next.is_none());
+        assert_eq!(hypotheses.take_best().unwrap(), [2, 4]);
+    }
+
+    #[test]
+    fn eos_finishes_hypotheses() {
+        let config = BeamSearchConfig::new(2);
+        let mut hypotheses = BeamHypotheses::new(config);
+        // The EOS continuation ranked third is not a hypothesis.
+        let next = hypotheses
+            .step(
+                &[0., -0.1],
+                &[
+                    vec![candidate(EOS, 0.5), candidate(1, 0.4)],
+                    vec![candidate(EOS, 0.3), candidate(2, 0.2)],
+                ],
+                2,
+                1,
+                false,
+                |tok| tok == EOS,
+                |beam, tok| (1, beam, tok.token),
+            )
+            .unwrap();
+        assert_eq!(
+            next.iter().map(|(b, t)| (*b, t.token)).collect::<Vec<_>>(),
+            [(0, 1), (1, 2)]
+        );
+        assert_eq!(hypotheses.finished.len(), 1);
+
+        // A second finished hypothesis better than the live beams ends the search.
+        let next = hypotheses.step(
+            &[0.4f32.log10(), -0.1 + 0.2f32.log10()],
+            &[
+                vec![candidate(EOS, 0.9), candidate(3, 0.1)],
+                vec![candidate(4, 0.5), candidate(5, 0.5)],
+            ],
+            2,
+            2,
+            false,
+            |tok| tok == EOS,
+            |beam, tok| (2, beam, tok.token),
+        );
+        assert!(next.is_none());
+        assert_eq!(hypotheses.take_best().unwrap(), (2, 0, EOS));
+    }
+
+    #[test]
+    fn length_penalty_favors_longer_hypotheses() {
+        let short = BeamSearchConfig {
+            num_beams: 2,
+            length_penalty: 0.0,
+            early_stopping: false,
+        };
+        let long = BeamSearchConfig {
+            length_penalty: 2.0,
+            ..short
+        };
+        for (config, expected) in [(short, "short"), (long, "long")] {
+            let mut hypotheses = BeamHypotheses::new(config);
+            hypotheses.add(config.score(-1.0, 2), "short");
+            hypotheses.add(config.score(-1.5, 6), "long");
+            assert_eq!(hypotheses.take_best().unwrap(), expected);
+        }
+        // -1 / (7 / 6)^2
+        assert!((long.score(-1.0, 2) + 36. / 49.).abs() < 1e-6);
+    }
+}
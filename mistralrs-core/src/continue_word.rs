@@ -0,0 +1,100 @@
+//! Bias the first generated token toward continuing the word at the end of the prompt, by masking
+//! the tokens which start a new word.
+
+use candle_core::{Result, Tensor};
+use llguidance::toktrie::TokTrie;
+
+use crate::CustomLogitsProcessor;
+
+/// The SentencePiece word boundary marker, `▁`.
+const METASPACE: &[u8] = "▁".as_bytes();
+
+fn starts_word(token: &[u8]) -> bool {
+    token.starts_with(b" ") || token.starts_with(METASPACE)
+}
+
+/// Mask the tokens which start a new word for the first token generated after a prompt of
+/// `prompt_len` tokens. Later tokens are not affected.
+pub(crate) struct ContinueWordProcessor {
+    prompt_len: usize,
+    /// `-inf` for the tokens which start a new word and 0 otherwise, indexed by token id.
+    bias: Vec<f32>,
+}
+
+impl ContinueWordProcessor {
+    #[allow(clippy::cast_possible_truncation)]
+    pub(crate) fn new(tok_trie: &TokTrie, prompt_len: usize) -> Self {
+        Self::from_tokens(
+            (0..tok_trie.vocab_size() as u32).map(|id| tok_trie.token(id)),
+            prompt_len,
+        )
+    }
+
+    fn from_tokens<'a>(tokens: impl Iterator<Item = &'a [u8]>, prompt_len: usize) -> Self {
+        // Special tokens are prefixed with a marker byte in the trie, so they are never masked.
+        let bias = tokens
+            .map(|token| {
+                if starts_word(token) {
+                    f32::NEG_INFINITY
+                } else {
+                    0.
+                }
+            })
+            .collect();
+        Self { prompt_len, bias }
+    }
+}
+
+impl CustomLogitsProcessor for ContinueWordProcessor {
+    fn apply(&self, logits: &Tensor, context: &[u32]) -> Result<Tensor> {
+        if context.len() != self.prompt_len {
+            return Ok(logits.clone());
+        }
+        // The model vocabulary may be padded past the tokenizer vocabulary.
+        let mut bias = self.bias.clone();
+        bias.resize(logits.dim(0)?, 0.);
+        let bias = Tensor::from_vec(bias, logits.dim(0)?, logits.device())?;
+        logits + bias.to_dtype(logits.dtype())?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::ContinueWordProcessor;
+    use crate::CustomLogitsProcessor;
+
+    #[test]
+    fn completes_current_word() {
+        let vocab: [&[u8]; 5] = [
+            b"e",
+            b"es",
+            " apple".as_bytes(),
+            "▁new".as_bytes(),
+            b"\xffeos",
+        ];
+        let processor = ContinueWordProcessor::from_tokens(vocab.into_iter(), 1);
+
+        // After the prompt "appl", the space-prefixed words are the most likely continuations.
+        let logits = Tensor::new(&[1f32, 2., 5., 4., 0.], &Device::Cpu).unwrap();
+        let argmax = |logits: &Tensor| logits.argmax(0).unwrap().to_scalar::<u32>().unwrap();
+        assert_eq!(argmax(&logits), 2);
+
+        let prompt = [0];
+        let biased = processor.apply(&logits, &prompt).unwrap();
+        assert_eq!(argmax(&biased), 1);
+        let biased = biased.to_vec1::<f32>().unwrap();
+        assert!(biased[2].is_infinite() && biased[3].is_infinite());
+        assert_eq!(biased[4], 0.);
+
+        // Only the first generated token is biased.
+        let generated = [0, 1];
+        assert_eq!(argmax(&processor.apply(&logits, &generated).unwrap()), 2);
+
+        // A padded model vocabulary is left unmasked past the tokenizer vocabulary.
+        let padded = Tensor::new(&[1f32, 2., 5., 4., 0., 3.], &Device::Cpu).unwrap();
+        let biased = processor.apply(&padded, &prompt).unwrap();
+        assert_eq!(argmax(&biased), 5);
+    }
+}
@@ -288,4 +288,74 @@ extern "C" {
         inplace: bool,
         stream: i64,
     );
+
+    #[cfg(feature = "flash-decoding")]
+    pub(crate) fn flash_decoding_f32(
+        q: *const c_void,
+        k: *const c_void,
+        v: *const c_void,
+        out: *mut c_void,
+        tmp_out: *mut f32,
+        tmp_max: *mut f32,
+        tmp_sum: *mut f32,
+        num_seqs: i32,
+        num_heads: i32,
+        num_kv_heads: i32,
+        head_dim: i32,
+        kv_len: i32,
+        partition_size: i32,
+        k_seq_stride: i64,
+        k_head_stride: i64,
+        v_seq_stride: i64,
+        v_head_stride: i64,
+        scale: f32,
+        softcap: f32,
+        stream: i64,
+    );
+    #[cfg(feature = "flash-decoding")]
+    pub(crate) fn flash_decoding_f16(
+        q: *const c_void,
+        k: *const c_void,
+        v: *const c_void,
+        out: *mut c_void,
+        tmp_out: *mut f32,
+        tmp_max: *mut f32,
+        tmp_sum: *mut f32,
+        num_seqs: i32,
+        num_heads: i32,
+        num_kv_heads: i32,
+        head_dim: i32,
+        kv_len: i32,
+        partition_size: i32,
+        k_seq_stride: i64,
+        k_head_stride: i64,
+        v_seq_stride: i64,
+        v_head_stride: i64,
+        scale: f32,
+        softcap: f32,
+        stream: i64,
+    );
+    #[cfg(feature = "flash-decoding")]
+    pub(crate) fn flash_decoding_bf16(
+        q: *const c_void,
+        k: *const c_void,
+        v: *const c_void,
+        out: *mut c_void,
+        tmp_out: *mut f32,
+        tmp_max: *mut f32,
+        tmp_sum: *mut f32,
+        num_seqs: i32,
+        num_heads: i32,
+        num_kv_heads: i32,
+        head_dim: i32,
+        kv_len: i32,
+        partition_size: i32,
+        k_seq_stride: i64,
+        k_head_stride: i64,
+        v_seq_stride: i64,
+        v_head_stride: i64,
+        scale: f32,
+        softcap: f32,
+        stream: i64,
+    );
 }
@@ -0,0 +1,205 @@
+// Flash Decoding: attention of a single query token over a long KV cache.
+//
+// Decoding has one query per head, so one block per head leaves most of the GPU idle while it
+// streams the KV cache. Instead, the KV cache is split into partitions which are processed by
+// separate blocks, each producing an unnormalized partial output together with its softmax max
+// and denominator. A second kernel rescales and combines the partial outputs.
+
+#include "cuda_bf16.h"
+#include "cuda_fp16.h"
+#include <float.h>
+#include <stdint.h>
+
+#define WARP_SIZE 32
+#define NUM_THREADS 128
+#define NUM_WARPS (NUM_THREADS / WARP_SIZE)
+
+inline __device__ float to_float(float x) { return x; }
+inline __device__ float to_float(__half x) { return __half2float(x); }
+inline __device__ float to_float(__nv_bfloat16 x) { return __bfloat162float(x); }
+
+inline __device__ void from_float(float &dst, float x) { dst = x; }
+inline __device__ void from_float(__half &dst, float x) { dst = __float2half(x); }
+inline __device__ void from_float(__nv_bfloat16 &dst, float x) {
+  dst = __float2bfloat16(x);
+}
+
+inline __device__ float warp_max(float x) {
+#pragma unroll
+  for (int offset = WARP_SIZE / 2; offset > 0; offset /= 2) {
+    x = fmaxf(x, __shfl_xor_sync(0xffffffff, x, offset));
+  }
+  return x;
+}
+
+inline __device__ float warp_sum(float x) {
+#pragma unroll
+  for (int offset = WARP_SIZE / 2; offset > 0; offset /= 2) {
+    x += __shfl_xor_sync(0xffffffff, x, offset);
+  }
+  return x;
+}
+
+// Reduce over the block, the result is returned to all threads. `red` holds one float per warp.
+template <bool IS_MAX>
+inline __device__ float block_reduce(float x, float *red) {
+  const int warp = threadIdx.x / WARP_SIZE;
+  const int lane = threadIdx.x % WARP_SIZE;
+  x = IS_MAX ? warp_max(x) : warp_sum(x);
+  if (lane == 0) {
+    red[warp] = x;
+  }
+  __syncthreads();
+  x = lane < NUM_WARPS ? red[lane] : (IS_MAX ? -FLT_MAX : 0.f);
+  x = IS_MAX ? warp_max(x) : warp_sum(x);
+  // `red` may be reused right after.
+  __syncthreads();
+  return x;
+}
+
+// Grid: (num_heads, num_seqs, num_partitions).
+// Each block computes the attention of one query head over one partition of the KV cache, without
+// normalizing by the softmax denominator.
+template <typename T>
+__global__ void flash_decoding_split_kernel(
+    const T *__restrict__ q,     // [num_seqs, num_heads, head_dim]
+    const T *__restrict__ k,     // [num_seqs, num_kv_heads, kv_len, head_dim], strided
+    const T *__restrict__ v,     // [num_seqs, num_kv_heads, kv_len, head_dim], strided
+    float *__restrict__ tmp_out, // [num_seqs, num_heads, num_partitions, head_dim]
+    float *__restrict__ tmp_max, // [num_seqs, num_heads, num_partitions]
+    float *__restrict__ tmp_sum, // [num_seqs, num_heads, num_partitions]
+    const int num_kv_heads, const int head_dim, const int kv_len,
+    const int partition_size, const int64_t k_seq_stride,
+    const int64_t k_head_stride, const int64_t v_seq_stride,
+    const int64_t v_head_stride, const float scale, const float softcap) {
+  const int head = blockIdx.x;
+  const int num_heads = gridDim.x;
+  const int seq = blockIdx.y;
+  const int partition = blockIdx.z;
+  const int num_partitions = gridDim.z;
+  const int kv_head = head / (num_heads / num_kv_heads);
+  const int start = partition * partition_size;
+  const int end = min(start + partition_size, kv_len);
+
+  extern __shared__ float smem[];
+  float *q_s = smem;                // [head_dim]
+  float *logits = smem + head_dim;  // [partition_size]
+  __shared__ float red[NUM_WARPS];
+
+  const T *q_ptr = q + ((int64_t)seq * num_heads + head) * head_dim;
+  for (int i = threadIdx.x; i < head_dim; i += NUM_THREADS) {
+    q_s[i] = to_float(q_ptr[i]);
+  }
+  __syncthreads();
+
+  const T *k_ptr = k + seq * k_seq_stride + kv_head * k_head_stride;
+  const T *v_ptr = v + seq * v_seq_stride + kv_head * v_head_stride;
+  const int warp = threadIdx.x / WARP_SIZE;
+  const int lane = threadIdx.x % WARP_SIZE;
+
+  // One warp per key.
+  float row_max = -FLT_MAX;
+  for (int j = start + warp; j < end; j += NUM_WARPS) {
+    const T *k_row = k_ptr + (int64_t)j * head_dim;
+    float dot = 0.f;
+    for (int i = lane; i < head_dim; i += WARP_SIZE) {
+      dot += q_s[i] * to_float(k_row[i]);
+    }
+    dot = warp_sum(dot) * scale;
+    if (softcap > 0.f) {
+      dot = softcap * tanhf(dot / softcap);
+    }
+    if (lane == 0) {
+      logits[j - start] = dot;
+    }
+    row_max = fmaxf(row_max, dot);
+  }
+  row_max = block_reduce<true>(row_max, red);
+
+  float sum = 0.f;
+  for (int j = start + threadIdx.x; j < end; j += NUM_THREADS) {
+    const float p = __expf(logits[j - start] - row_max);
+    logits[j - start] = p;
+    sum += p;
+  }
+  // Also makes the probabilities visible to all threads.
+  sum = block_reduce<false>(sum, red);
+
+  // One thread per output dimension, so the reads of each row of V are coalesced.
+  const int64_t row = ((int64_t)seq * num_heads + head) * num_partitions + partition;
+  for (int i = threadIdx.x; i < head_dim; i += NUM_THREADS) {
+    float acc = 0.f;
+    for (int j = start; j < end; ++j) {
+      acc += logits[j - start] * to_float(v_ptr[(int64_t)j * head_dim + i]);
+    }
+    tmp_out[row * head_dim + i] = acc;
+  }
+  if (threadIdx.x == 0) {
+    tmp_max[row] = row_max;
+    tmp_sum[row] = sum;
+  }
+}
+
+// Grid: (num_heads, num_seqs).
+// Rescales the partial outputs by their softmax max and normalizes by the total denominator.
+template <typename T>
+__global__ void flash_decoding_combine_kernel(
+    T *__restrict__ out,               // [num_seqs, num_heads, head_dim]
+    const float *__restrict__ tmp_out, // [num_seqs, num_heads, num_partitions, head_dim]
+    const float *__restrict__ tmp_max, // [num_seqs, num_heads, num_partitions]
+    const float *__restrict__ tmp_sum, // [num_seqs, num_heads, num_partitions]
+    const int head_dim, const int num_partitions) {
+  const int64_t row = (int64_t)blockIdx.y * gridDim.x + blockIdx.x;
+  const float *maxs = tmp_max + row * num_partitions;
+  const float *sums = tmp_sum + row * num_partitions;
+
+  float row_max = -FLT_MAX;
+  for (int p = 0; p < num_partitions; ++p) {
+    row_max = fmaxf(row_max, maxs[p]);
+  }
+  float denom = 0.f;
+  for (int p = 0; p < num_partitions; ++p) {
+    denom += sums[p] * __expf(maxs[p] - row_max);
+  }
+  const float inv_denom = 1.f / denom;
+
+  for (int i = threadIdx.x; i < head_dim; i += NUM_THREADS) {
+    float acc = 0.f;
+    for (int p = 0; p < num_partitions; ++p) {
+      acc += tmp_out[(row * num_partitions + p) * head_dim + i] *
+             __expf(maxs[p] - row_max);
+    }
+    from_float(out[row * head_dim + i], acc * inv_denom);
+  }
+}
+
+#define FLASH_DECODING_OP(T, RUST_NAME)                                        \
+  extern "C" void RUST_NAME(                                                   \
+      const void *q, const void *k, const void *v, void *out, float *tmp_out,  \
+      float *tmp_max, float *tmp_sum, const int32_t num_seqs,                  \
+      const int32_t num_heads, const int32_t num_kv_heads,                     \
+      const int32_t head_dim, const int32_t kv_len,                            \
+      const int32_t partition_size, const int64_t k_seq_stride,                \
+      const int64_t k_head_stride, const int64_t v_seq_stride,                 \
+      const int64_t v_head_stride, const float scale, const float softcap,     \
+      const int64_t stream) {                                                  \
+    const cudaStream_t custream = (cudaStream_t)stream;                        \
+    const int num_partitions = (kv_len + partition_size - 1) / partition_size; \
+    const size_t smem_size = (head_dim + partition_size) * sizeof(float);      \
+    const dim3 split_grid(num_heads, num_seqs, num_partitions);                \
+    flash_decoding_split_kernel<T>                                             \
+        <<<split_grid, NUM_THREADS, smem_size, custream>>>(                    \
+            reinterpret_cast<const T *>(q), reinterpret_cast<const T *>(k),    \
+            reinterpret_cast<const T *>(v), tmp_out, tmp_max, tmp_sum,         \
+            num_kv_heads, head_dim, kv_len, partition_size, k_seq_stride,      \
+            k_head_stride, v_seq_stride, v_head_stride, scale, softcap);       \
+    const dim3 combine_grid(num_heads, num_seqs);                              \
+    flash_decoding_combine_kernel<T>                                           \
+        <<<combine_grid, NUM_THREADS, 0, custream>>>(                          \
+            reinterpret_cast<T *>(out), tmp_out, tmp_max, tmp_sum, head_dim,   \
+            num_partitions);                                                   \
+  }
+
+FLASH_DECODING_OP(float, flash_decoding_f32)
+FLASH_DECODING_OP(__half, flash_decoding_f16)
+FLASH_DECODING_OP(__nv_bfloat16, flash_decoding_bf16)
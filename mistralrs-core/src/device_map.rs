@@ -1,4 +1,4 @@
-use std::{fmt::Debug, sync::Arc};
+use std::{fmt::Debug, ops::Range, sync::Arc};
 
 use crate::{
     pipeline::AutoDeviceMapParams,
@@ -36,6 +36,8 @@ pub enum DeviceMapSetting {
 pub struct DeviceMapMetadata {
     device_layers: Option<Vec<DeviceLayerMapMetadata>>,
     host_layers: Option<usize>,
+    #[serde(default)]
+    pipelining: bool,
 }
 
 impl DeviceMapMetadata {
@@ -43,6 +45,7 @@ impl DeviceMapMetadata {
         Self {
             device_layers: Some(device_layers),
             host_layers: None,
+            pipelining: false,
         }
     }
     /// A device mapper to not map device.
@@ -50,8 +53,16 @@ impl DeviceMapMetadata {
         Self {
             device_layers: None,
             host_layers: None,
+            pipelining: false,
         }
     }
+    /// Overlap the work of the devices when the layers are mapped across several devices: batches
+    /// of more than one sequence are split into two micro-batches which run as a pipeline across
+    /// the device boundaries. This is disabled by default.
+    pub fn with_pipelining(mut self, pipelining: bool) -> Self {
+        self.pipelining = pipelining;
+        self
+    }
 }
 
 impl DeviceMapSetting {
@@ -87,6 +98,7 @@ impl DeviceMapSetting {
             Self::Map(DeviceMapMetadata {
                 device_layers,
                 host_layers,
+                pipelining,
             }) => {
                 if let Some(topology) = topology {
                     if topology.0.iter().all(|x| x.is_none()) {
@@ -113,6 +125,7 @@ impl DeviceMapSetting {
                         return Ok(Box::new(LayerDeviceMapper {
                             mappings: layers,
                             nm_device: device.clone(),
+                            pipelining: *pipelining,
                         }));
                     }
                 }
@@ -225,6 +238,7 @@ impl DeviceMapSetting {
                 Ok(Box::new(LayerDeviceMapper {
                     mappings: combined,
                     nm_device: device.clone(),
+                    pipelining: *pipelining,
                 }))
             }
             Self::Auto(_) => {
@@ -238,6 +252,11 @@ pub trait DeviceMapper: Debug {
     // === DURING RUNTIME ===
     /// Map during runtime
     fn map(&self, input: Tensor, layer: usize) -> Result<Tensor>;
+    /// Whether to pipeline micro-batches across the devices, see
+    /// [`DeviceMapMetadata::with_pipelining`].
+    fn pipelining(&self) -> bool {
+        false
+    }
 
     // === DURING LOADING TIME ===
     /// If ISQ layer, then do not change the device. *They will do it later in NormalModel::quantize*
@@ -267,12 +286,16 @@ pub trait DeviceMapper: Debug {
 pub struct LayerDeviceMapper {
     mappings: Vec<Device>,
     nm_device: Device,
+    pipelining: bool,
 }
 
 impl DeviceMapper for LayerDeviceMapper {
     fn map(&self, input: Tensor, layer: usize) -> Result<Tensor> {
         input.to_device(&self.mappings[layer])
     }
+    fn pipelining(&self) -> bool {
+        self.pipelining
+    }
     fn set_device<'a>(
         &self,
         layer: usize,
@@ -595,3 +618,91 @@ pub fn get_all_similar_devices(base: &Device) -> Result<Vec<Device>> {
     }
     Ok(devices)
 }
+
+/// Consecutive layers which are mapped to the same device.
+#[derive(Debug, Clone)]
+pub struct DeviceSegment {
+    pub layers: Range<usize>,
+    pub device: Device,
+}
+
+/// Split the layers into runs of consecutive layers on the same device.
+pub fn device_segments(mapper: &dyn DeviceMapper, num_layers: usize) -> Vec<DeviceSegment> {
+    let mut segments: Vec<DeviceSegment> = Vec::new();
+    for layer in 0..num_layers {
+        let Some(device) = mapper.device_for(layer, false) else {
+            continue;
+        };
+        match segments.last_mut() {
+            Some(last) if last.device.same_device(device) && last.layers.end == layer => {
+                last.layers.end = layer + 1;
+            }
+            _ => segments.push(DeviceSegment {
+                layers: layer..layer + 1,
+                device: device.clone(),
+            }),
+        }
+    }
+    segments
+}
+
+/// Order in which micro-batches run through the device segments, as a list of steps of
+/// `(micro_batch, segment)` pairs. Each step holds at most one micro-batch per segment, so that
+/// while micro-batch `m` runs segment `s`, micro-batch `m + 1` runs segment `s - 1` on the previous
+/// device. Within a step, earlier segments come first so that asynchronous (GPU) work is queued
+/// before synchronous (CPU) work. Activations should be moved to the next device only after all
+/// the work of a step has been issued.
+pub fn micro_batch_schedule(
+    num_micro_batches: usize,
+    num_segments: usize,
+) -> Vec<Vec<(usize, usize)>> {
+    if num_micro_batches == 0 || num_segments == 0 {
+        return Vec::new();
+    }
+    (0..num_micro_batches + num_segments - 1)
+        .map(|step| {
+            (0..num_segments)
+                .filter(|segment| step >= *segment && step - segment < num_micro_batches)
+                .map(|segment| (step - segment, segment))
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::micro_batch_schedule;
+
+    #[test]
+    fn two_stage_schedule() {
+        assert_eq!(
+            micro_batch_schedule(3, 2),
+            vec![
+                vec![(0, 0)],
+                vec![(1, 0), (0, 1)],
+                vec![(2, 0), (1, 1)],
+                vec![(2, 1)],
+            ]
+        );
+        assert_eq!(micro_batch_schedule(2, 1), vec![vec![(0, 0)], vec![(1, 0)]]);
+        assert!(micro_batch_schedule(0, 2).is_empty());
+    }
+
+    #[test]
+    fn every_micro_batch_runs_every_segment_in_order() {
+        let (num_micro_batches, num_segments) = (4, 3);
+        let steps = micro_batch_schedule(num_micro_batches, num_segments);
+        let mut next_segment = vec![0; num_micro_batches];
+        for step in &steps {
+            for &(micro_batch, segment) in step {
+                assert_eq!(next_segment[micro_batch], segment);
+                next_segment[micro_batch] += 1;
+            }
+            // No segment runs two micro-batches in the same step.
+            let mut segments = step.iter().map(|(_, s)| *s).collect::<Vec<_>>();
+            segments.dedup();
+            assert_eq!(segments.len(), step.len());
+        }
+        assert!(next_segment.iter().all(|s| *s == num_segments));
+    }
+}
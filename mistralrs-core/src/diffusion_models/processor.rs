@@ -27,6 +27,7 @@ impl Processor for DiffusionProcessor {
         _add_generation_prompt: bool,
         _add_special_tokens: bool,
         _tools: Vec<crate::Tool>,
+        _enable_thinking: Option<bool>,
     ) -> Result<(Vec<u32>, String)> {
         anyhow::bail!(
             "DiffusionProcessor::process should not be used. It does not expect chat messages."
@@ -0,0 +1,376 @@
+//! Pipeline parallelism across machines. The layers of a model are split in shards, one per rank,
+//! and the hidden states are sent from each rank to the next over TCP, in a ring which ends at
+//! rank 0.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    ops::Range,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use candle_core::{Device, Tensor};
+use safetensors::SafeTensors;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+};
+use tracing::info;
+
+/// Time to wait for the neighbouring ranks, which may load their shard slower.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+/// Largest payload of hidden states accepted from the previous rank, 4 GiB, so that a peer can
+/// not make a rank allocate arbitrary memory.
+const MAX_ACTIVATIONS_LEN: u64 = 4 << 30;
+
+/// Position of a machine among the `world_size` machines running a model, as in
+/// `torch.distributed`. Rank 0 runs the [`DistributedCoordinator`], the others [`serve_shard`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DistributedRank {
+    rank: usize,
+    world_size: usize,
+}
+
+impl DistributedRank {
+    pub fn new(rank: usize, world_size: usize) -> anyhow::Result<Self> {
+        if rank >= world_size {
+            anyhow::bail!("Rank {rank} must be below the world size {world_size}.");
+        }
+        Ok(Self { rank, world_size })
+    }
+
+    pub fn rank(&self) -> usize {
+        self.rank
+    }
+
+    pub fn world_size(&self) -> usize {
+        self.world_size
+    }
+
+    /// The layers of this rank out of `num_layers`. Each rank has a contiguous range of layers,
+    /// and the first ranks have one more layer if the world size does not divide `num_layers`.
+    pub fn layers(&self, num_layers: usize) -> Range<usize> {
+        let per_rank = num_layers / self.world_size;
+        let extra = num_layers % self.world_size;
+        let start = self.rank * per_rank + self.rank.min(extra);
+        start..start + per_rank + usize::from(self.rank < extra)
+    }
+}
+
+/// The layers of a model run by a rank, see [`DistributedRank::layers`].
+pub trait PipelineShard: Send + Sync {
+    /// Run the layers of this shard on the hidden states of the previous rank, or on the input of
+    /// the model for rank 0.
+    fn forward(&self, xs: &Tensor, seqlen_offsets: &[usize]) -> candle_core::Result<Tensor>;
+}
+
+/// Write hidden states as a safetensors payload prefixed by its length, with the sequence
+/// offsets in the metadata.
+async fn write_activations<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    xs: &Tensor,
+    seqlen_offsets: &[usize],
+) -> anyhow::Result<()> {
+    let metadata = HashMap::from([(
+        "seqlen_offsets".to_string(),
+        serde_json::to_string(seqlen_offsets)?,
+    )]);
+    let xs = xs.contiguous()?.to_device(&Device::Cpu)?;
+    let payload = safetensors::serialize([("xs", xs)], &Some(metadata))?;
+    stream.write_u64_le(payload.len() as u64).await?;
+    stream.write_all(&payload).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Read hidden states written by [`write_activations`], or `None` if the stream was closed
+/// between two payloads.
+async fn read_activations<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    device: &Device,
+) -> anyhow::Result<Option<(Tensor, Vec<usize>)>> {
+    let mut prefix = [0; 8];
+    let mut read = 0;
+    while read < prefix.len() {
+        match stream.read(&mut prefix[read..]).await? {
+            0 if read == 0 => return Ok(None),
+            0 => anyhow::bail!(
+                "The stream was closed after {read} of the {} bytes of the payload length.",
+                prefix.len()
+            ),
+            n => read += n,
+        }
+    }
+    let len = u64::from_le_bytes(prefix);
+    if len > MAX_ACTIVATIONS_LEN {
+        anyhow::bail!(
+            "The hidden states payload of {len} bytes is above the limit of {MAX_ACTIVATIONS_LEN} bytes."
+        );
+    }
+    // Grow the buffer as the payload arrives rather than trusting the length up front.
+    let mut payload = Vec::new();
+    (&mut *stream).take(len).read_to_end(&mut payload).await?;
+    if payload.len() as u64 != len {
+        anyhow::bail!(
+            "The stream was closed after {} of the {len} bytes of the hidden states.",
+            payload.len()
+        );
+    }
+
+    let (_, metadata) = SafeTensors::read_metadata(&payload)?;
+    let seqlen_offsets = metadata
+        .metadata()
+        .as_ref()
+        .and_then(|metadata| metadata.get("seqlen_offsets"))
+        .context("missing `seqlen_offsets` in the metadata")?;
+    let seqlen_offsets = serde_json::from_str(seqlen_offsets)?;
+    let xs = candle_core::safetensors::load_buffer(&payload, device)?
+        .remove("xs")
+        .context("missing the hidden states")?;
+    Ok(Some((xs, seqlen_offsets)))
+}
+
+/// Accept the connection of the previous rank while connecting to the next one, which may not
+/// listen yet. Returns the streams from the previous rank and to the next one.
+async fn connect_ring(
+    listener: TcpListener,
+    next: SocketAddr,
+) -> anyhow::Result<(TcpStream, TcpStream)> {
+    let accept = async {
+        let (stream, addr) = listener.accept().await?;
+        info!("Accepted the previous rank from {addr}.");
+        anyhow::Ok(stream)
+    };
+    let connect = async {
+        let start = Instant::now();
+        loop {
+            match TcpStream::connect(next).await {
+                Ok(stream) => return anyhow::Ok(stream),
+                Err(e) if start.elapsed() > CONNECT_TIMEOUT => {
+                    anyhow::bail!("Failed to connect to the next rank at {next}: {e}")
+                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        }
+    };
+    let (prev, next) =
+        tokio::time::timeout(CONNECT_TIMEOUT, async { tokio::try_join!(accept, connect) })
+            .await
+            .context("The previous rank did not connect due to timeout")??;
+    prev.set_nodelay(true)?;
+    next.set_nodelay(true)?;
+    Ok((prev, next))
+}
+
+/// Runs the shard of rank 0 and sends the hidden states through the shards of the other ranks,
+/// in order. Each of them runs [`serve_shard`], and the last one sends the hidden states back.
+pub struct DistributedCoordinator {
+    rank: DistributedRank,
+    shard: Arc<dyn PipelineShard>,
+    device: Device,
+    /// Streams from the last rank and to rank 1, `None` on a single machine.
+    ring: Option<Mutex<(TcpStream, TcpStream)>>,
+}
+
+impl DistributedCoordinator {
+    /// The last rank connects to `listener`, and `next` is the address of rank 1. Both are
+    /// unused if `world_size` is 1.
+    pub async fn connect(
+        world_size: usize,
+        shard: Arc<dyn PipelineShard>,
+        device: Device,
+        listener: TcpListener,
+        next: SocketAddr,
+    ) -> anyhow::Result<Self> {
+        let rank = DistributedRank::new(0, world_size)?;
+        let ring = if world_size > 1 {
+            info!("Rank 0 of {world_size} connecting to rank 1 at {next}.");
+            Some(Mutex::new(connect_ring(listener, next).await?))
+        } else {
+            None
+        };
+        Ok(Self {
+            rank,
+            shard,
+            device,
+            ring,
+        })
+    }
+
+    pub fn rank(&self) -> DistributedRank {
+        self.rank
+    }
+
+    /// Run the hidden states through the shards of all ranks, in order.
+    pub async fn forward(&self, xs: &Tensor, seqlen_offsets: &[usize]) -> anyhow::Result<Tensor> {
+        let xs = self.shard.forward(xs, seqlen_offsets)?;
+        let Some(ring) = &self.ring else {
+            return Ok(xs);
+        };
+        let mut ring = ring.lock().await;
+        let (prev, next) = &mut *ring;
+        write_activations(next, &xs, seqlen_offsets).await?;
+        let (xs, _) = read_activations(prev, &self.device)
+            .await?
+            .context("The last rank closed its connection.")?;
+        Ok(xs)
+    }
+}
+
+/// Run the shard of a rank other than 0: receive the hidden states from the previous rank, run
+/// the shard and send the result to the next rank, until the previous rank disconnects.
+///
+/// The previous rank connects to `listener`, and `next` is the address of the next rank, or that
+/// of rank 0 for the last rank.
+pub async fn serve_shard(
+    rank: DistributedRank,
+    shard: Arc<dyn PipelineShard>,
+    device: Device,
+    listener: TcpListener,
+    next: SocketAddr,
+) -> anyhow::Result<()> {
+    if rank.rank() == 0 {
+        anyhow::bail!("Rank 0 runs the `DistributedCoordinator`.");
+    }
+    info!(
+        "Rank {} of {} connecting to the next rank at {next}.",
+        rank.rank(),
+        rank.world_size()
+    );
+    let (mut prev, mut next) = connect_ring(listener, next).await?;
+    while let Some((xs, seqlen_offsets)) = read_activations(&mut prev, &device).await? {
+        let xs = shard.forward(&xs, &seqlen_offsets)?;
+        write_activations(&mut next, &xs, &seqlen_offsets).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use candle_core::{Device, Tensor};
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    use super::{
+        read_activations, serve_shard, write_activations, DistributedCoordinator, DistributedRank,
+        PipelineShard,
+    };
+
+    /// Adds its value to the hidden states.
+    struct Add(f64);
+
+    impl PipelineShard for Add {
+        fn forward(&self, xs: &Tensor, seqlen_offsets: &[usize]) -> candle_core::Result<Tensor> {
+            assert_eq!(seqlen_offsets, [3]);
+            xs + self.0
+        }
+    }
+
+    #[test]
+    fn layers() {
+        let layers = |world_size, num_layers| {
+            (0..world_size)
+                .map(|rank| {
+                    DistributedRank::new(rank, world_size)
+                        .unwrap()
+                        .layers(num_layers)
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(layers(2, 32), [0..16, 16..32]);
+        assert_eq!(layers(3, 32), [0..11, 11..22, 22..32]);
+        assert_eq!(layers(1, 126), [0..126]);
+        assert!(DistributedRank::new(2, 2).is_err());
+    }
+
+    #[tokio::test]
+    async fn activations_roundtrip() -> anyhow::Result<()> {
+        let dev = Device::Cpu;
+        let xs = Tensor::new(&[[1f32, 2.], [3., 4.]], &dev)?;
+        let (mut a, mut b) = tokio::io::duplex(1 << 16);
+        write_activations(&mut a, &xs.t()?, &[5, 7]).await?;
+        drop(a);
+
+        let (ys, seqlen_offsets) = read_activations(&mut b, &dev).await?.unwrap();
+        assert_eq!(ys.to_vec2::<f32>()?, [[1., 3.], [2., 4.]]);
+        assert_eq!(seqlen_offsets, [5, 7]);
+        assert!(read_activations(&mut b, &dev).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_and_oversized_payloads() -> anyhow::Result<()> {
+        let dev = Device::Cpu;
+
+        // Closed in the middle of the length.
+        let (mut a, mut b) = tokio::io::duplex(1 << 16);
+        a.write_all(&[1, 0, 0]).await?;
+        drop(a);
+        let err = read_activations(&mut b, &dev).await.unwrap_err();
+        assert!(err.to_string().contains("after 3 of the 8 bytes"), "{err}");
+
+        // Closed in the middle of the payload.
+        let (mut a, mut b) = tokio::io::duplex(1 << 16);
+        a.write_u64_le(100).await?;
+        a.write_all(&[0; 10]).await?;
+        drop(a);
+        let err = read_activations(&mut b, &dev).await.unwrap_err();
+        assert!(
+            err.to_string().contains("after 10 of the 100 bytes"),
+            "{err}"
+        );
+
+        // A length above the limit is rejected before reading the payload.
+        let (mut a, mut b) = tokio::io::duplex(1 << 16);
+        a.write_u64_le(u64::MAX).await?;
+        let err = read_activations(&mut b, &dev).await.unwrap_err();
+        assert!(err.to_string().contains("above the limit"), "{err}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn forward_through_ranks() -> anyhow::Result<()> {
+        let dev = Device::Cpu;
+        let mut listeners = Vec::new();
+        for _ in 0..3 {
+            listeners.push(TcpListener::bind("127.0.0.1:0").await?);
+        }
+        let addrs = listeners
+            .iter()
+            .map(|listener| listener.local_addr())
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        let mut listeners = listeners.into_iter();
+        let listener = listeners.next().unwrap();
+        let mut workers = Vec::new();
+        for (rank, worker_listener) in (1..3).zip(listeners) {
+            workers.push(tokio::spawn(serve_shard(
+                DistributedRank::new(rank, 3)?,
+                Arc::new(Add(10. * rank as f64)),
+                dev.clone(),
+                worker_listener,
+                addrs[(rank + 1) % 3],
+            )));
+        }
+        let coordinator =
+            DistributedCoordinator::connect(3, Arc::new(Add(1.)), dev.clone(), listener, addrs[1])
+                .await?;
+
+        let xs = Tensor::new(&[[1f32, 2.]], &dev)?;
+        for _ in 0..2 {
+            let ys = coordinator.forward(&xs, &[3]).await?;
+            assert_eq!(ys.to_vec2::<f32>()?, [[32., 33.]]);
+        }
+
+        // The workers stop once rank 0 disconnects.
+        drop(coordinator);
+        for worker in workers {
+            worker.await??;
+        }
+        Ok(())
+    }
+}
@@ -19,6 +19,10 @@ use crate::device_map::DeviceMapper;
 use crate::pipeline::{DeviceMappedModelLoader, IsqModelLoader};
 use crate::{DeviceMapSetting, IsqOrganization, ModelPaths};
 
+mod coordinator;
+
+pub use coordinator::{serve_shard, DistributedCoordinator, DistributedRank, PipelineShard};
+
 pub(crate) const IS_DAEMON_FLAG: &str = "__MISTRALRS_DAEMON_INTERNAL";
 
 pub fn is_daemon() -> bool {
@@ -1,6 +1,8 @@
 /// The higher-level manager of the blocks allocated. Operations performed by the block engine do
-/// not directly change memory.
+/// not directly change memory, so it is shared with the real PagedAttention module.
+#[path = "../paged_attention/block_engine.rs"]
 mod block_engine;
+#[path = "../paged_attention/block_engine_sequence.rs"]
 mod block_engine_sequence;
 /// This is the lower-level manager of the cache. It manages swapping and copying the blocks and
 /// actually allocates the KV cache for the CPU and GPU. It is used by the LLMEngine to execute
@@ -11,7 +13,9 @@ mod layers;
 mod scheduler;
 pub const _PAD_SLOT_ID: i64 = -1;
 
-pub use block_engine::{BlockEngine, BlockTables, LogicalTokenBlock};
+pub use block_engine::{
+    full_blocks, BlockEngine, BlockTables, LogicalTokenBlock, SharedPrefixBlockAllocator,
+};
 pub use block_engine_sequence::BlockEngineSequence;
 pub use cache_engine::{CacheConfig, CacheEngine};
 use candle_core::{DType, Device};
@@ -19,7 +19,7 @@ use tracing::warn;
 use crate::{
     get_mut_arcmutex,
     paged_attention::BlockEngine,
-    scheduler::{Scheduler, SchedulerOutput},
+    scheduler::{Scheduler, SchedulerLimits, SchedulerOutput},
     sequence::{Sequence, SequenceState, StopReason},
     TERMINATE_ALL_NEXT_STEP,
 };
@@ -36,6 +36,8 @@ pub struct PagedAttentionSchedulerOutput {
 
 pub struct PagedAttentionSchedulerConfig {
     pub max_num_seqs: usize,
+    /// Maximum number of prompt tokens of the sequences admitted in one step.
+    pub max_prefill_tokens: Option<usize>,
 }
 
 pub struct PagedAttentionScheduler {
@@ -69,14 +71,26 @@ impl PagedAttentionScheduler {
         if self.swapped_out.is_empty() {
             let mut scheduled = VecDeque::new();
             let mut did_ignore = false;
+            let mut prefill_tokens = 0;
             while !self.waiting.is_empty() {
                 let seq = self.waiting.front().unwrap().clone();
 
                 // If adding this seq means we will have too many, stop as no more could be added.
-                if self.config.max_num_seqs == self.running.len() + 1 {
+                if self.running.len() + 1 >= self.config.max_num_seqs {
                     break;
                 }
 
+                // Admit at least one sequence, then stay within the prefill token budget.
+                let prompt_len = get_mut_arcmutex!(seq).get_toks().len();
+                if self
+                    .config
+                    .max_prefill_tokens
+                    .is_some_and(|max| prefill_tokens > 0 && prefill_tokens + prompt_len > max)
+                {
+                    break;
+                }
+                prefill_tokens += prompt_len;
+
                 // If we cannot allocate either now or in the future, either do not continue or remove the sequence.
                 let can_allocate = self.block_engine.can_allocate(&*get_mut_arcmutex!(seq));
                 match can_allocate {
@@ -350,6 +364,22 @@ impl Scheduler for PagedAttentionScheduler {
     fn running_len(&self) -> usize {
         self.running.len()
     }
+    fn prefill_queue_depth(&self) -> usize {
+        self.waiting.len()
+            + self
+                .running
+                .iter()
+                .filter(|seq| get_mut_arcmutex!(seq).is_prompt())
+                .count()
+    }
+    fn decode_queue_depth(&self) -> usize {
+        self.swapped_out.len()
+            + self
+                .running
+                .iter()
+                .filter(|seq| get_mut_arcmutex!(seq).is_completion())
+                .count()
+    }
     fn block_tables(&self) -> Option<&BlockTables> {
         Some(&self.block_engine.block_tables)
     }
@@ -362,4 +392,20 @@ impl Scheduler for PagedAttentionScheduler {
     fn block_engine(&mut self) -> Option<&mut BlockEngine> {
         Some(&mut self.block_engine)
     }
+    fn limits(&self) -> SchedulerLimits {
+        SchedulerLimits {
+            max_num_seqs: self.config.max_num_seqs,
+            max_prefill_tokens: self.config.max_prefill_tokens,
+            max_decode_seqs: None,
+        }
+    }
+    fn set_limits(&mut self, limits: SchedulerLimits, drain: bool) -> Result<(), String> {
+        if limits.max_decode_seqs.is_some() {
+            return Err("`max_decode_seqs` is not supported with PagedAttention.".to_string());
+        }
+        limits.validate(self.running.len(), drain)?;
+        self.config.max_num_seqs = limits.max_num_seqs;
+        self.config.max_prefill_tokens = limits.max_prefill_tokens;
+        Ok(())
+    }
 }
@@ -0,0 +1,83 @@
+//! Early exit from the decoder layers when the logit lens prediction of an intermediate layer is
+//! confident enough, to save compute on easy tokens.
+
+use candle_core::{DType, Result, Tensor, D};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EarlyExitConfig {
+    /// Index of the first layer after which the model may exit.
+    pub min_layer: usize,
+    /// Minimum probability of the top token, predicted by applying the final norm and LM head
+    /// to the hidden state of an intermediate layer, to exit after that layer.
+    pub confidence: f32,
+}
+
+/// Depths at which the decode steps run with early exit ended.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct EarlyExitStats {
+    /// Number of decode steps.
+    pub steps: usize,
+    /// Number of decode steps which exited before the last layer.
+    pub early_exits: usize,
+    /// Total number of layers run over all decode steps.
+    pub total_depth: usize,
+}
+
+impl EarlyExitStats {
+    /// Record a decode step which ran `depth` of `num_layers` layers.
+    pub fn record(&mut self, depth: usize, num_layers: usize) {
+        self.steps += 1;
+        self.total_depth += depth;
+        if depth < num_layers {
+            self.early_exits += 1;
+        }
+    }
+
+    /// Average number of layers run per decode step.
+    pub fn average_exit_depth(&self) -> Option<f64> {
+        #[allow(clippy::cast_precision_loss)]
+        (self.steps > 0).then(|| self.total_depth as f64 / self.steps as f64)
+    }
+}
+
+/// Whether the top token of every row of `logits`, of shape `(batch, vocab)`, has a probability
+/// of at least `confidence`. All sequences of a batch must be confident to exit together.
+pub(crate) fn is_confident(logits: &Tensor, confidence: f32) -> Result<bool> {
+    let probs = candle_nn::ops::softmax_last_dim(&logits.to_dtype(DType::F32)?)?;
+    let min_top_prob = probs.max(D::Minus1)?.min(0)?.to_scalar::<f32>()?;
+    Ok(min_top_prob >= confidence)
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::{is_confident, EarlyExitStats};
+
+    #[test]
+    fn stats_average_the_exit_depth() {
+        let mut stats = EarlyExitStats::default();
+        assert_eq!(stats.average_exit_depth(), None);
+
+        stats.record(4, 4);
+        stats.record(2, 4);
+        stats.record(1, 4);
+        assert_eq!(
+            stats,
+            EarlyExitStats {
+                steps: 3,
+                early_exits: 2,
+                total_depth: 7,
+            }
+        );
+        assert_eq!(stats.average_exit_depth(), Some(7. / 3.));
+    }
+
+    #[test]
+    fn whole_batch_must_be_confident() {
+        let logits = Tensor::new(&[[10f32, 0., 0.], [1., 1., 1.]], &Device::Cpu).unwrap();
+        assert!(!is_confident(&logits, 0.9).unwrap());
+        assert!(is_confident(&logits.narrow(0, 0, 1).unwrap(), 0.9).unwrap());
+    }
+}
@@ -4,7 +4,7 @@ use candle_core::{DType, Device, Result, Tensor};
 use candle_nn::{embedding, layer_norm, linear, Embedding, LayerNorm, Linear, Module, VarBuilder};
 use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
 use serde::Deserialize;
-use tokenizers::Tokenizer;
+use tokenizers::{PaddingParams, PaddingStrategy, Tokenizer};
 
 use crate::{
     engine::BertEmbeddingModel, layers::Activation, utils::log::once_log_info, GLOBAL_HF_CACHE,
@@ -438,4 +438,42 @@ impl BertPipeline {
         let model = BertModel::load(vb, &config)?;
         Ok(Self { model, tokenizer })
     }
+
+    /// Embed each of the texts by mean pooling over the non-padding tokens, normalized to unit length.
+    pub fn embed_batch(&mut self, texts: &[String], device: &Device) -> Result<Vec<Vec<f32>>> {
+        if texts.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.tokenizer.with_padding(Some(PaddingParams {
+            strategy: PaddingStrategy::BatchLongest,
+            ..Default::default()
+        }));
+        let encodings = self
+            .tokenizer
+            .encode_batch(texts.to_vec(), true)
+            .map_err(candle_core::Error::msg)?;
+        let token_ids = encodings
+            .iter()
+            .map(|e| Tensor::new(e.get_ids(), device))
+            .collect::<Result<Vec<_>>>()?;
+        let attention_mask = encodings
+            .iter()
+            .map(|e| Tensor::new(e.get_attention_mask(), device))
+            .collect::<Result<Vec<_>>>()?;
+        let token_ids = Tensor::stack(&token_ids, 0)?;
+        let attention_mask = Tensor::stack(&attention_mask, 0)?;
+        let token_type_ids = token_ids.zeros_like()?;
+
+        let embeddings = self
+            .model
+            .forward(&token_ids, &token_type_ids, Some(&attention_mask))?
+            .to_dtype(DType::F32)?;
+        let mask = attention_mask.to_dtype(DType::F32)?.unsqueeze(2)?;
+        let pooled = embeddings
+            .broadcast_mul(&mask)?
+            .sum(1)?
+            .broadcast_div(&mask.sum(1)?)?;
+        let normalized = pooled.broadcast_div(&pooled.sqr()?.sum_keepdim(1)?.sqrt()?)?;
+        normalized.to_vec2::<f32>()
+    }
 }
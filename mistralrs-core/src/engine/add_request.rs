@@ -1,10 +1,18 @@
 use crate::{
+    beam_search::BeamHypotheses,
+    continue_word::ContinueWordProcessor,
+    lora::blend_scalings,
     pipeline::NormalCache,
-    request::{DetokenizationRequest, NormalRequest, SearchContextSize, TokenizationRequest},
+    request::{
+        ContextCheckRequest, DetokenizationRequest, NormalRequest, PrefixCacheOp,
+        PrefixCacheRequest, SchedulerLimitsRequest, SchedulerMetricsRequest, SearchContextSize,
+        TokenizationRequest,
+    },
     search::{self, SearchFunctionParameters, SearchResult},
     sequence::SeqStepType,
     tools::{ToolCallingMatcher, ToolChoice},
-    MessageContent, RequestMessage, Response, ResponseOk,
+    ContextCheck, DocumentUsage, MessageContent, RequestMessage, Response, ResponseOk,
+    SchedulerMetrics,
 };
 use candle_core::Tensor;
 use either::Either;
@@ -21,24 +29,40 @@ use tracing::{info, warn};
 use crate::{
     get_mut_arcmutex, handle_seq_error,
     request::Request,
-    sampler::Sampler,
+    sampler::{Sampler, SamplerBackend},
     sequence::{Sequence, SequenceGroup},
-    StopTokens,
+    EffectiveSamplingParams, StopTokens,
 };
 
-use super::{Engine, TERMINATE_ALL_NEXT_STEP};
+use super::{
+    preprocess::{process_with_documents, PreparedPrompt},
+    Engine, EngineEvent, SEED, TERMINATE_ALL_NEXT_STEP,
+};
 
 impl Engine {
-    pub async fn handle_request(self: Arc<Self>, request: Request) {
+    /// `prompt` is the prompt of a chat request rendered and tokenized ahead of time.
+    pub async fn handle_request(self: Arc<Self>, request: Request, prompt: Option<PreparedPrompt>) {
         match request {
             Request::Normal(request) => {
-                if matches!(
-                    request.messages,
-                    RequestMessage::Chat { .. } | RequestMessage::VisionChat { .. }
-                ) && request.web_search_options.is_some()
-                    && !request.is_streaming
-                    && get_mut_arcmutex!(self.bert_pipeline).is_some()
-                {
+                let device_error = self
+                    .device_error
+                    .lock()
+                    .expect("`device_error` was poisoned")
+                    .clone();
+                if let Some(device_error) = device_error {
+                    request
+                        .response
+                        .send(Response::InternalError(
+                            format!(
+                                "The engine encountered an unrecoverable device error: {device_error}"
+                            )
+                            .into(),
+                        ))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
+                if self.runs_web_search(&request) {
                     let Some(web_search_options) = request.web_search_options.clone() else {
                         unreachable!()
                     };
@@ -59,7 +83,7 @@ impl Engine {
                         second_request.response = new_sender;
                         std::mem::swap(&mut first_request.response, &mut second_request.response);
 
-                        this.add_request(first_request).await;
+                        this.add_request(first_request, None).await;
                         let ResponseOk::Done(done) =
                             first_receiver.recv().await.unwrap().as_result().unwrap()
                         else {
@@ -197,11 +221,11 @@ impl Engine {
                             messages.push(message);
                         }
 
-                        this.add_request(second_request).await;
+                        this.add_request(second_request, None).await;
                     });
                     get_mut_arcmutex!(self.handles).push(handle);
                 } else {
-                    self.add_request(request).await
+                    self.add_request(request, prompt).await
                 }
             }
             Request::ReIsq(level) => {
@@ -211,6 +235,10 @@ impl Engine {
             }
             Request::Tokenize(req) => self.tokenize_text(req).await,
             Request::Detokenize(req) => self.detokenize_text(req).await,
+            Request::SchedulerLimits(req) => self.update_scheduler_limits(req).await,
+            Request::SchedulerMetrics(req) => self.scheduler_metrics(req).await,
+            Request::PrefixCache(req) => self.update_prefix_cache(req).await,
+            Request::ContextCheck(req) => self.check_context(req).await,
             Request::Terminate => (),
             Request::TerminateAllSeqsNextStep => {
                 TERMINATE_ALL_NEXT_STEP.store(true, Ordering::SeqCst)
@@ -218,7 +246,131 @@ impl Engine {
         }
     }
 
-    async fn add_request(&self, request: NormalRequest) {
+    /// Whether a request first runs with the search tool, for the model to decide on a web search.
+    fn runs_web_search(&self, request: &NormalRequest) -> bool {
+        matches!(
+            request.messages,
+            RequestMessage::Chat { .. } | RequestMessage::VisionChat { .. }
+        ) && request.web_search_options.is_some()
+            && !request.is_streaming
+            && get_mut_arcmutex!(self.bert_pipeline).is_some()
+    }
+
+    /// Render and tokenize the prompt of a request as it is run, packing its documents, before it
+    /// is fitted to the context. Context checks measure their prompts with this too, so that they
+    /// agree with the requests. The error is the response to send.
+    fn render_prompt(
+        &self,
+        request: &mut NormalRequest,
+        prompt: Option<PreparedPrompt>,
+    ) -> Result<RenderedPrompt, Response> {
+        let is_chat = matches!(
+            request.messages,
+            RequestMessage::Chat(_) | RequestMessage::VisionChat { .. }
+        );
+        let pipeline = &*get_mut_arcmutex!(self.pipeline);
+        if is_chat
+            && !pipeline
+                .get_chat_template()
+                .as_ref()
+                .is_some_and(|ch_t| ch_t.has_chat_template())
+        {
+            return Err(Response::ValidationError(
+                "Received messages for a model which does not have a chat template. Either use a different model or pass a single string as the prompt".into(),
+            ));
+        }
+        if request.documents.is_some() && !is_chat {
+            return Err(Response::ValidationError(
+                "Documents are only supported for chat requests.".into(),
+            ));
+        }
+
+        let mut documents = None;
+        let (tokens, text) = match (&request.messages, prompt) {
+            (RequestMessage::Chat(_), Some(prompt)) => prompt.map_err(internal_error)?,
+            (
+                RequestMessage::Chat(messages)
+                | RequestMessage::VisionChat {
+                    images: _,
+                    messages,
+                },
+                _,
+            ) => {
+                let messages = match pipeline.get_chat_template() {
+                    Some(chat_template) => chat_template
+                        .adapt_system_messages(messages.clone(), request.strict_system_role)
+                        .map_err(|e| Response::ValidationError(e.into()))?,
+                    None => messages.clone(),
+                };
+                let tools = request.tools.clone().unwrap_or_default();
+                match request.documents.take() {
+                    Some(request_documents) => {
+                        let params = &request.sampling_params;
+                        let reserved_tokens = params
+                            .reserved_output_tokens
+                            .max(params.max_len.unwrap_or(0));
+                        let (toks, prompt, usage) = process_with_documents(
+                            pipeline,
+                            messages,
+                            tools,
+                            request.enable_thinking,
+                            request_documents,
+                            reserved_tokens,
+                        )
+                        .map_err(internal_error)?;
+                        documents = Some(usage);
+                        (toks, prompt)
+                    }
+                    None => pipeline
+                        .get_processor()
+                        .process(
+                            pipeline,
+                            messages,
+                            true,
+                            true,
+                            tools,
+                            request.enable_thinking,
+                        )
+                        .map_err(internal_error)?,
+                }
+            }
+            (RequestMessage::Completion { text, .. }, _) => {
+                let Some(tokenizer) = pipeline.tokenizer() else {
+                    return Err(Response::ValidationError(
+                        "Completion requests require the pipeline to have a tokenizer".into(),
+                    ));
+                };
+                let prompt = tokenizer
+                    .encode_fast(text.clone(), true)
+                    .map_err(|e| internal_error(anyhow::Error::msg(e)))?;
+                (prompt.get_ids().to_vec(), text.clone())
+            }
+            (RequestMessage::ImageGeneration { prompt, .. }, _) => (vec![u32::MAX], prompt.clone()),
+            (RequestMessage::CompletionTokens(it), _) => {
+                let Some(tokenizer) = pipeline.tokenizer() else {
+                    return Err(Response::ValidationError(
+                        "Completion requests w/ raw tokens require the pipeline to have a tokenizer".into(),
+                    ));
+                };
+                let prompt = tokenizer
+                    .decode(it, false)
+                    .map_err(|e| internal_error(anyhow::Error::msg(e.to_string())))?;
+                (it.clone(), prompt)
+            }
+        };
+        if tokens.is_empty() {
+            return Err(Response::ValidationError(
+                "Received an empty prompt.".into(),
+            ));
+        }
+        Ok(RenderedPrompt {
+            tokens,
+            text,
+            documents,
+        })
+    }
+
+    async fn add_request(&self, mut request: NormalRequest, prompt: Option<PreparedPrompt>) {
         let is_chat = matches!(
             request.messages,
             RequestMessage::Chat(_) | RequestMessage::VisionChat { .. }
@@ -238,19 +390,21 @@ impl Engine {
             | RequestMessage::VisionChat { .. }
             | RequestMessage::ImageGeneration { .. } => None,
         };
-        if is_chat
-            && !get_mut_arcmutex!(self.pipeline)
-                .get_chat_template()
-                .as_ref()
-                .is_some_and(|ch_t| ch_t.has_chat_template())
-        {
-            request
+        let RenderedPrompt {
+            tokens: mut prompt_tokens,
+            text: prompt_text,
+            documents: document_usage,
+        } = match self.render_prompt(&mut request, prompt) {
+            Ok(rendered) => rendered,
+            Err(response) => {
+                request
                     .response
-                    .send(Response::ValidationError(
-                        "Received messages for a model which does not have a chat template. Either use a different model or pass a single string as the prompt".into(),
-                    )).await.expect("Expected receiver.");
-            return;
-        }
+                    .send(response)
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+        };
 
         let images = match request.messages {
             RequestMessage::VisionChat {
@@ -282,102 +436,70 @@ impl Engine {
             _ => None,
         };
 
-        let (mut prompt_tokens, prompt_text) = match request.messages {
-            RequestMessage::Chat(messages)
-            | RequestMessage::VisionChat {
-                images: _,
-                messages,
-            } => {
-                let pipeline = &*get_mut_arcmutex!(self.pipeline);
-                let tools = request.tools.unwrap_or_default();
-                let template = pipeline
-                    .get_processor()
-                    .process(pipeline, messages, true, true, tools);
-                handle_seq_error!(template, request.response)
-            }
-            RequestMessage::Completion { text, .. } => {
-                let Some(tokenizer) = &get_mut_arcmutex!(self.pipeline).tokenizer() else {
-                    request
-                        .response
-                        .send(Response::ValidationError(
-                            "Completion requests require the pipeline to have a tokenizer".into(),
-                        ))
-                        .await
-                        .expect("Expected receiver.");
-                    return;
-                };
-                let prompt = tokenizer
-                    .encode_fast(text.clone(), true)
-                    .map_err(anyhow::Error::msg);
-                (
-                    handle_seq_error!(prompt, request.response)
-                        .get_ids()
-                        .to_vec(),
-                    text,
-                )
-            }
-            RequestMessage::ImageGeneration { prompt, .. } => (vec![u32::MAX], prompt),
-            RequestMessage::CompletionTokens(it) => {
-                let Some(tokenizer) = &get_mut_arcmutex!(self.pipeline).tokenizer() else {
-                    request
-                        .response
-                        .send(Response::ValidationError(
-                            "Completion requests w/ raw tokens require the pipeline to have a tokenizer".into(),
-                        ))
-                        .await
-                        .expect("Expected receiver.");
-                    return;
-                };
-                let prompt = tokenizer
-                    .decode(&it, false)
-                    .map_err(|e| anyhow::Error::msg(e.to_string()));
-                (it, handle_seq_error!(prompt, request.response))
+        let adapter_scalings = match &request.adapters {
+            Some(adapters) => {
+                let lora_adapters = get_mut_arcmutex!(self.pipeline)
+                    .get_metadata()
+                    .lora_adapters
+                    .clone();
+                match blend_scalings(adapters, &lora_adapters) {
+                    Ok(scalings) => Some(scalings),
+                    Err(e) => {
+                        request
+                            .response
+                            .send(Response::ValidationError(e.into()))
+                            .await
+                            .expect("Expected receiver.");
+                        return;
+                    }
+                }
             }
+            None => None,
         };
-        if prompt_tokens.is_empty() {
-            request
-                .response
-                .send(Response::ValidationError(
-                    "Received an empty prompt.".into(),
-                ))
-                .await
-                .expect("Expected receiver.");
-            return;
-        }
 
-        if prompt_tokens.len() > get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len {
-            if !self.truncate_sequence {
+        let max_seq_len = get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len;
+        let prompt_len = prompt_tokens.len();
+        match fit_prompt_to_context(
+            prompt_len,
+            max_seq_len,
+            request.sampling_params.reserved_output_tokens,
+            request.sampling_params.max_len,
+            self.truncate_sequence,
+        ) {
+            Ok(0) => (),
+            Ok(n_truncated) => {
+                prompt_tokens = prompt_tokens[n_truncated..].to_vec();
+                warn!("Prompt for request {} was {prompt_len} tokens, which does not leave space for generation in the model maximum length of {max_seq_len}. The first {n_truncated} tokens were truncated.", request.id);
+            }
+            Err(e) => {
                 request
                     .response
-                    .send(Response::ValidationError(
-                        format!("Prompt sequence length is greater than {}, perhaps consider using `truncate_sequence`?", get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len).into(),
-                    )).await.expect("Expected receiver.");
+                    .send(Response::ValidationError(e.into()))
+                    .await
+                    .expect("Expected receiver.");
                 return;
-            } else {
-                let prompt_len = prompt_tokens.len();
-                let max_len = get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len;
-                let currently_over = prompt_len - max_len;
-                let sampling_max = if let Some(sampling_max) = request.sampling_params.max_len {
-                    if currently_over + sampling_max >= prompt_len {
-                        10
-                    } else {
-                        sampling_max
-                    }
-                } else {
-                    10
-                };
-                prompt_tokens = prompt_tokens[(currently_over + sampling_max)..].to_vec();
-                warn!("Prompt for request {} was {} tokens over the model maximum length. The last {} tokens were truncated to make space for generation.", request.id, currently_over, prompt_len - prompt_tokens.len());
             }
         }
-        let prefill_cache = handle_seq_error!(
-            get_mut_arcmutex!(self.prefix_cacher).search_for_matching_cache(
-                &prompt_tokens,
-                images.as_ref().is_some_and(|x| !x.is_empty())
-            ),
-            request.response
-        );
+        // Sequences blending adapters are not added to the prefix cache, and do not use it.
+        let prefill_cache = if adapter_scalings.is_some() {
+            None
+        } else {
+            handle_seq_error!(
+                get_mut_arcmutex!(self.prefix_cacher).search_for_matching_cache(
+                    &prompt_tokens,
+                    images.as_ref().is_some_and(|x| !x.is_empty())
+                ),
+                request.response
+            )
+        };
 
+        let generation_defaults = get_mut_arcmutex!(self.pipeline)
+            .get_metadata()
+            .generation_defaults
+            .clone();
+        request.sampling_params = request
+            .sampling_params
+            .with_defaults(&self.sampling_defaults, &generation_defaults);
         let topk = request
             .sampling_params
             .top_k
@@ -460,26 +582,116 @@ impl Engine {
             }
         };
 
-        let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
+        let beam_search = match request.sampling_params.sampler {
+            SamplerBackend::BeamSearch(config) => Some(config),
+            SamplerBackend::Native | SamplerBackend::GumbelMax => None,
+        };
+        if let Some(config) = beam_search {
+            let paged_attn = get_mut_arcmutex!(self.pipeline)
+                .get_metadata()
+                .cache_config
+                .is_some();
+            let unsupported = if config.num_beams == 0 {
+                Some("Beam search requires at least one beam.")
+            } else if request.sampling_params.n_choices != 1 {
+                Some("Beam search returns a single choice.")
+            } else if request.is_streaming {
+                Some("Beam search does not support streaming.")
+            } else if paged_attn {
+                Some("Beam search does not support PagedAttention.")
+            } else if request.constraint.kind().is_some() || request.dynamic_grammar.is_some() {
+                Some("Beam search does not support grammar constraints.")
+            } else if !stop_strings.is_empty() || request.sampling_params.stop_on_balanced.is_some()
+            {
+                Some("Beam search supports stop tokens, but not stop strings.")
+            } else {
+                None
+            };
+            if let Some(err) = unsupported {
+                request
+                    .response
+                    .send(Response::ValidationError(err.into()))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+        }
+
+        let mut group = SequenceGroup::new(
             request.sampling_params.n_choices,
             request.is_streaming,
             is_chat,
             best_of,
-        )));
+            request.sampling_params.length_penalty,
+        );
+        group.documents = document_usage;
+        group.adapters = request.adapters.clone();
+        if request.return_effective_params {
+            let params = &request.sampling_params;
+            group.effective_params = Some(EffectiveSamplingParams {
+                // Sampling is greedy for temperatures this low.
+                temperature: params.temperature.filter(|t| *t >= 1e-7),
+                top_k: params.top_k,
+                top_p: topp,
+                min_p: minp,
+                frequency_penalty: params.frequency_penalty,
+                presence_penalty: params.presence_penalty,
+                repeat_last_n: params.repeat_last_n,
+                dry_multiplier: params.dry_params.as_ref().map(|dry| dry.multiplier),
+                tfs_z: params.tfs_z,
+                max_len: params.max_len,
+                stop_toks: stop_toks.clone(),
+                stop_strings: stop_strings.clone(),
+                seed: SEED,
+                constraint: match (&request.constraint, &request.dynamic_grammar) {
+                    (_, Some(_)) => Some("dynamic".to_string()),
+                    (constraint, None) => constraint.kind().map(String::from),
+                },
+            });
+        }
+        group.beam_hypotheses = beam_search.map(BeamHypotheses::new);
+        let group = Arc::new(tokio::sync::Mutex::new(group));
 
         let tokenizer = get_mut_arcmutex!(self.pipeline).tokenizer();
 
+        let mut logits_processors = request.logits_processors.unwrap_or_default();
+        if request.sampling_params.continue_word {
+            let tok_env = get_mut_arcmutex!(self.pipeline)
+                .get_metadata()
+                .tok_env
+                .clone();
+            let Some(tok_env) = tok_env else {
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        "`continue_word` requires a tokenizer.".into(),
+                    ))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            };
+            logits_processors.push(Arc::new(ContinueWordProcessor::new(
+                tok_env.tok_trie(),
+                prompt_tokens.len(),
+            )));
+        }
+
         let sampler = Sampler::new(
-            Some(request.sampling_params.temperature.unwrap_or(1.0)),
+            request.sampling_params.temperature,
             request.sampling_params.top_n_logprobs,
             tokenizer,
             request.sampling_params.frequency_penalty,
             request.sampling_params.presence_penalty,
+            request.sampling_params.repeat_last_n,
             request.sampling_params.dry_params,
             topk,
             topp,
             minp,
-            request.logits_processors.unwrap_or_default(),
+            request.sampling_params.tfs_z,
+            request.sampling_params.sampler,
+            request.sampling_params.hybrid_decode,
+            request.sampling_params.adaptive_temperature,
+            logits_processors,
         );
         let sampler = handle_seq_error!(sampler, request.response);
 
@@ -494,13 +706,31 @@ impl Engine {
             return;
         }
 
-        // Add sequences
-        for response_index in 0..request.sampling_params.n_choices {
+        if let Some((open, close)) = request.sampling_params.stop_on_balanced {
+            if let Err(e) = validate_balanced_delimiters(open, close) {
+                request
+                    .response
+                    .send(Response::ValidationError(e.into()))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+        }
+
+        // Add sequences. The beams of a beam search are the sequences of its single choice.
+        let n_seqs =
+            beam_search.map_or(request.sampling_params.n_choices, |config| config.num_beams);
+        for seq_index in 0..n_seqs {
+            let response_index = if beam_search.is_some() { 0 } else { seq_index };
             let trie = get_mut_arcmutex!(self.pipeline)
                 .get_metadata()
                 .tok_env
                 .clone();
-            let recognizer = match Self::build_sequence_recognizer(&trie, &request.constraint) {
+            let (recognizer, dynamic_grammar) = match Self::build_sequence_recognizer(
+                &trie,
+                &request.constraint,
+                request.dynamic_grammar.as_ref(),
+            ) {
                 Ok(recognizer) => recognizer,
                 Err(err) => {
                     request
@@ -609,6 +839,10 @@ impl Engine {
                 sampler.clone(),
                 stop_toks.clone(),
                 stop_strings.clone(),
+                request.sampling_params.include_stop_str_in_output,
+                request.sampling_params.stop_lookback,
+                request.sampling_params.stop_on_balanced,
+                request.sampling_params.strip_response_prefix.clone(),
                 request.sampling_params.max_len,
                 request.return_logprobs,
                 get_mut_arcmutex!(self.pipeline).get_metadata().is_xlora,
@@ -631,7 +865,11 @@ impl Engine {
                 seq_preallocated_cache,
                 request.return_raw_logits,
                 eos_toks,
-            );
+            )
+            .with_dynamic_grammar(dynamic_grammar)
+            .with_beam_search(beam_search)
+            .with_adapter_scalings(adapter_scalings.clone())
+            .with_request_id(request.id);
             self.logger.add_new_sequence();
             let seq = if let Some(prefill_cache) = prefill_cache.clone() {
                 self.logger.add_prefix_cache_hit();
@@ -644,23 +882,116 @@ impl Engine {
             } else {
                 seq
             };
+            self.events.emit(EngineEvent::RequestQueued {
+                request_id: request.id,
+                seq_id: *seq.id(),
+            });
             *get_mut_arcmutex!(self.id) += 1;
             get_mut_arcmutex!(self.scheduler).add_seq(seq);
         }
     }
 
+    /// The limits are applied here, between scheduling steps, so they take effect at the next one.
+    async fn update_scheduler_limits(&self, request: SchedulerLimitsRequest) {
+        let mut scheduler = get_mut_arcmutex!(self.scheduler);
+        let previous = scheduler.limits();
+        let result = match request.limits {
+            Some(limits) => match scheduler.set_limits(limits, request.drain) {
+                Ok(()) => {
+                    info!("Scheduler limits changed from {previous:?} to {limits:?}.");
+                    self.events.emit(EngineEvent::SchedulerLimitsChanged {
+                        previous,
+                        limits,
+                        drain: request.drain,
+                    });
+                    Ok(limits)
+                }
+                Err(e) => Err(anyhow::anyhow!(e)),
+            },
+            None => Ok(previous),
+        };
+        drop(scheduler);
+        request
+            .response
+            .send(result)
+            .await
+            .expect("Expected receiver.");
+    }
+
+    async fn scheduler_metrics(&self, request: SchedulerMetricsRequest) {
+        let scheduler = get_mut_arcmutex!(self.scheduler);
+        let (prefill_queue_depth, decode_queue_depth) = (
+            scheduler.prefill_queue_depth(),
+            scheduler.decode_queue_depth(),
+        );
+        drop(scheduler);
+        let throughput = get_mut_arcmutex!(self.pipeline)
+            .get_metadata()
+            .throughput
+            .estimate();
+        request
+            .response
+            .send(SchedulerMetrics {
+                prefill_queue_depth,
+                decode_queue_depth,
+                prefill_throughput_tps: throughput.prompt_tps,
+                decode_throughput_tps: throughput.decode_tps,
+            })
+            .await
+            .expect("Expected receiver.");
+    }
+
+    async fn update_prefix_cache(&self, request: PrefixCacheRequest) {
+        let mut prefix_cacher = get_mut_arcmutex!(self.prefix_cacher);
+        let result = if prefix_cacher.is_disabled() {
+            Err(anyhow::anyhow!("Prefix caching is disabled."))
+        } else {
+            match request.op {
+                PrefixCacheOp::Metrics => (),
+                PrefixCacheOp::Pin(toks) => {
+                    if prefix_cacher.pin(toks) {
+                        info!("Pinned a prefix in the prefix cache.");
+                    }
+                }
+                PrefixCacheOp::Unpin(toks) => {
+                    if prefix_cacher.unpin(&toks) {
+                        info!("Unpinned a prefix in the prefix cache.");
+                    }
+                }
+                PrefixCacheOp::Flush { include_pinned } => {
+                    let n = prefix_cacher.flush(include_pinned);
+                    info!("Flushed {n} prefixes from the prefix cache.");
+                }
+            }
+            Ok(prefix_cacher.metrics())
+        };
+        drop(prefix_cacher);
+        request
+            .response
+            .send(result)
+            .await
+            .expect("Expected receiver.");
+    }
+
     async fn tokenize_text(&self, request: TokenizationRequest) {
         match request.text {
             Either::Left(messages) => {
                 let pipeline = &*get_mut_arcmutex!(self.pipeline);
                 let tools = request.tools.unwrap_or_default();
-                let template = pipeline.get_processor().process(
-                    pipeline,
-                    messages,
-                    request.add_generation_prompt,
-                    request.add_special_tokens,
-                    tools,
-                );
+                let messages = match pipeline.get_chat_template() {
+                    Some(chat_template) => chat_template.adapt_system_messages(messages, false),
+                    None => Ok(messages),
+                };
+                let template = messages.and_then(|messages| {
+                    pipeline.get_processor().process(
+                        pipeline,
+                        messages,
+                        request.add_generation_prompt,
+                        request.add_special_tokens,
+                        tools,
+                        None,
+                    )
+                });
                 let toks = match template {
                     Ok((toks, _)) => toks,
                     Err(e) => {
@@ -715,6 +1046,61 @@ impl Engine {
         };
     }
 
+    async fn check_context(&self, request: ContextCheckRequest) {
+        let ContextCheckRequest {
+            request: mut checked,
+            response,
+        } = request;
+        if self.runs_web_search(&checked) {
+            let options = checked.web_search_options.as_ref().expect("Checked above.");
+            match search::get_search_tool(options) {
+                Ok(tool) => checked.tools.get_or_insert_with(Vec::new).push(tool),
+                Err(e) => {
+                    response.send(Err(e)).await.expect("Expected receiver.");
+                    return;
+                }
+            }
+        }
+        let rendered = match self.render_prompt(&mut checked, None) {
+            Ok(rendered) => rendered,
+            Err(Response::ValidationError(e) | Response::InternalError(e)) => {
+                response
+                    .send(Err(anyhow::Error::msg(e)))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+            Err(_) => unreachable!("Rendering a prompt only fails with an error response."),
+        };
+
+        let max_seq_len = get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len;
+        let prompt_tokens = rendered.tokens.len();
+        let params = &checked.sampling_params;
+        let (truncated_tokens, rejected) = match fit_prompt_to_context(
+            prompt_tokens,
+            max_seq_len,
+            params.reserved_output_tokens,
+            params.max_len,
+            self.truncate_sequence,
+        ) {
+            Ok(n_truncated) => (n_truncated, None),
+            Err(e) => (0, Some(e)),
+        };
+        let check = ContextCheck {
+            prompt_tokens,
+            max_seq_len,
+            truncated_tokens,
+            remaining_tokens: max_seq_len
+                .saturating_sub(prompt_tokens.saturating_sub(truncated_tokens)),
+            documents: rendered.documents,
+            rejected,
+        };
+        response
+            .send(Ok(check))
+            .await
+            .expect("Sender disconnected unexpectedly!");
+    }
+
     async fn detokenize_text(&self, request: DetokenizationRequest) {
         let pipeline = &*get_mut_arcmutex!(self.pipeline);
         let tokenizer = pipeline.tokenizer();
@@ -750,3 +1136,128 @@ impl Engine {
             .expect("Sender disconnected unexpectedly!");
     }
 }
+
+/// The prompt of a request as it is run, before it is fitted to the context.
+struct RenderedPrompt {
+    tokens: Vec<u32>,
+    text: String,
+    /// The documents packed into the prompt, if the request has documents.
+    documents: Option<Vec<DocumentUsage>>,
+}
+
+fn internal_error(e: anyhow::Error) -> Response {
+    Response::InternalError(e.into())
+}
+
+/// Number of tokens to truncate from the start of a prompt of `prompt_len` tokens so that it fits
+/// in the context with room for generation. Prompts are only truncated if `truncate_sequence` is
+/// set, otherwise a prompt which does not fit is rejected.
+///
+/// If `reserved_output_tokens` is nonzero, the prompt must be at most
+/// `max_seq_len - reserved_output_tokens` tokens. Otherwise, the prompt must fit in the context
+/// and is truncated to leave room for `max_len` tokens (or 10 tokens if that is not possible).
+fn fit_prompt_to_context(
+    prompt_len: usize,
+    max_seq_len: usize,
+    reserved_output_tokens: usize,
+    max_len: Option<usize>,
+    truncate_sequence: bool,
+) -> Result<usize, String> {
+    if reserved_output_tokens > 0 {
+        if reserved_output_tokens >= max_seq_len {
+            return Err(format!("`reserved_output_tokens` ({reserved_output_tokens}) must be less than the model maximum length of {max_seq_len}."));
+        }
+        let n_over = prompt_len.saturating_sub(max_seq_len - reserved_output_tokens);
+        if n_over > 0 && !truncate_sequence {
+            return Err(format!("Prompt sequence length ({prompt_len}) does not leave `reserved_output_tokens` ({reserved_output_tokens}) in the model maximum length of {max_seq_len}, perhaps consider using `truncate_sequence`?"));
+        }
+        return Ok(n_over);
+    }
+    if prompt_len <= max_seq_len {
+        return Ok(0);
+    }
+    if !truncate_sequence {
+        return Err(format!("Prompt sequence length is greater than {max_seq_len}, perhaps consider using `truncate_sequence` or `reserved_output_tokens`?"));
+    }
+    let currently_over = prompt_len - max_seq_len;
+    let sampling_max = match max_len {
+        Some(sampling_max) if currently_over + sampling_max < prompt_len => sampling_max,
+        _ => 10,
+    };
+    Ok(currently_over + sampling_max)
+}
+
+/// Check that `stop_on_balanced` can balance: the delimiters must differ, and neither may be the
+/// double quote or backslash which delimit and escape the strings in which delimiters are ignored.
+fn validate_balanced_delimiters(open: char, close: char) -> Result<(), String> {
+    if open == close {
+        return Err(format!(
+            "`stop_on_balanced` delimiters must differ, got `{open}` for both."
+        ));
+    }
+    if let Some(c) = [open, close].into_iter().find(|c| matches!(c, '"' | '\\')) {
+        return Err(format!(
+            "`stop_on_balanced` delimiters cannot be `{c}`, which is used for strings."
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fit_prompt_to_context, validate_balanced_delimiters};
+
+    #[test]
+    fn balanced_delimiters_must_be_distinct_and_not_string_characters() {
+        assert!(validate_balanced_delimiters('{', '}').is_ok());
+        assert!(validate_balanced_delimiters('<', '>').is_ok());
+        assert!(validate_balanced_delimiters('|', '|').is_err());
+        assert!(validate_balanced_delimiters('"', '}').is_err());
+        assert!(validate_balanced_delimiters('{', '"').is_err());
+        assert!(validate_balanced_delimiters('\\', ')').is_err());
+        assert!(validate_balanced_delimiters('(', '\\').is_err());
+    }
+
+    #[test]
+    fn reserved_output_tokens_truncates_full_prompt() {
+        let max_seq_len = 4096;
+        let reserved = 512;
+        let n_truncated =
+            fit_prompt_to_context(max_seq_len, max_seq_len, reserved, None, true).unwrap();
+        let prompt_len = max_seq_len - n_truncated;
+        assert_eq!(prompt_len, max_seq_len - reserved);
+        // Generation can produce up to the reserved number of tokens before reaching the limit.
+        assert_eq!(max_seq_len - prompt_len, reserved);
+    }
+
+    #[test]
+    fn reserved_output_tokens_keeps_short_prompt() {
+        assert_eq!(fit_prompt_to_context(100, 4096, 512, None, false), Ok(0));
+        assert_eq!(fit_prompt_to_context(3584, 4096, 512, None, false), Ok(0));
+        assert_eq!(fit_prompt_to_context(3585, 4096, 512, None, true), Ok(1));
+        assert!(fit_prompt_to_context(100, 4096, 4096, None, true).is_err());
+    }
+
+    #[test]
+    fn reserved_output_tokens_rejects_without_truncate_sequence() {
+        // The prompt fits in the context, but not with the reserved room.
+        let e = fit_prompt_to_context(3585, 4096, 512, None, false).unwrap_err();
+        assert!(e.contains("`reserved_output_tokens` (512)"), "{e}");
+        assert!(fit_prompt_to_context(4096, 4096, 512, Some(100), false).is_err());
+        assert!(fit_prompt_to_context(5000, 4096, 512, None, false).is_err());
+    }
+
+    #[test]
+    fn truncate_sequence_without_reservation() {
+        assert_eq!(fit_prompt_to_context(4096, 4096, 0, None, false), Ok(0));
+        assert!(fit_prompt_to_context(4100, 4096, 0, None, false).is_err());
+        assert_eq!(
+            fit_prompt_to_context(4100, 4096, 0, Some(100), true),
+            Ok(104)
+        );
+        assert_eq!(
+            fit_prompt_to_context(4100, 4096, 0, Some(5000), true),
+            Ok(14)
+        );
+    }
+}
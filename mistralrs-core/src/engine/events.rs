@@ -0,0 +1,241 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError, error::TryRecvError};
+
+use crate::{
+    sequence::{Sequence, SequenceState},
+    SchedulerLimits,
+};
+
+/// Default number of events buffered for each subscriber before the oldest are dropped.
+pub const DEFAULT_ENGINE_EVENT_CAPACITY: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MemoryPressureLevel {
+    /// KV cache blocks had to be swapped out to make room for running sequences.
+    High,
+    /// A forward pass failed because the device ran out of memory.
+    OutOfMemory,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct EngineEventUsage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+}
+
+/// A lifecycle event emitted by the engine.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EngineEvent {
+    /// The engine started with this model. This is emitted again if the engine is rebooted.
+    ModelLoaded {
+        model_id: String,
+    },
+    /// A sequence was created for the request and added to the scheduler. A request with `n`
+    /// choices creates `n` sequences, which share the `request_id`.
+    RequestQueued {
+        request_id: usize,
+        seq_id: usize,
+    },
+    /// The prompt of the sequence was processed.
+    RequestStarted {
+        request_id: usize,
+        seq_id: usize,
+    },
+    /// The sequence finished, `reason` is the finish reason reported in the response.
+    RequestFinished {
+        request_id: usize,
+        seq_id: usize,
+        usage: EngineEventUsage,
+        reason: String,
+    },
+    MemoryPressure {
+        level: MemoryPressureLevel,
+    },
+    /// PagedAttention swapped KV cache blocks between the device and the CPU.
+    SequenceSwapped {
+        swapped_out_blocks: usize,
+        swapped_in_blocks: usize,
+    },
+    /// A LoRA adapter was loaded from disk.
+    AdapterLoaded {
+        name: String,
+    },
+    /// The scheduler limits were changed at runtime.
+    SchedulerLimitsChanged {
+        previous: SchedulerLimits,
+        limits: SchedulerLimits,
+        drain: bool,
+    },
+}
+
+/// Broadcast channel of [`EngineEvent`]s.
+///
+/// Emitting never blocks: each subscriber has a bounded buffer and a subscriber which falls
+/// behind loses the oldest events, which are counted in [`EngineEventSubscriber::dropped`].
+#[derive(Clone)]
+pub struct EngineEventBus {
+    sender: broadcast::Sender<EngineEvent>,
+    emitted: Arc<AtomicU64>,
+}
+
+impl EngineEventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self {
+            sender,
+            emitted: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn subscribe(&self) -> EngineEventSubscriber {
+        EngineEventSubscriber {
+            receiver: self.sender.subscribe(),
+            dropped: 0,
+        }
+    }
+
+    /// Total number of events emitted.
+    pub fn emitted(&self) -> u64 {
+        self.emitted.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn emit(&self, event: EngineEvent) {
+        self.emitted.fetch_add(1, Ordering::Relaxed);
+        // This only fails if there are no subscribers.
+        let _ = self.sender.send(event);
+    }
+
+    /// Emit `RequestFinished` for each of the sequences which is done.
+    pub(crate) fn emit_finished<'a>(&self, seqs: impl Iterator<Item = &'a Sequence>) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        for seq in seqs {
+            let reason = match seq.getstate() {
+                SequenceState::Done(reason) => reason.to_string(),
+                SequenceState::Error => "error".to_string(),
+                SequenceState::FinishedAborted | SequenceState::FinishedIgnored => {
+                    "aborted".to_string()
+                }
+                _ => continue,
+            };
+            let prompt_tokens = seq.prompt_tokens();
+            let total_tokens = seq.get_toks().len();
+            self.emit(EngineEvent::RequestFinished {
+                request_id: seq.request_id(),
+                seq_id: *seq.id(),
+                usage: EngineEventUsage {
+                    prompt_tokens,
+                    completion_tokens: total_tokens.saturating_sub(prompt_tokens),
+                    total_tokens,
+                },
+                reason,
+            });
+        }
+    }
+}
+
+impl Default for EngineEventBus {
+    fn default() -> Self {
+        Self::new(DEFAULT_ENGINE_EVENT_CAPACITY)
+    }
+}
+
+pub struct EngineEventSubscriber {
+    receiver: broadcast::Receiver<EngineEvent>,
+    dropped: u64,
+}
+
+impl EngineEventSubscriber {
+    /// Wait for the next event. Returns `None` once the event bus has been dropped.
+    pub async fn recv(&mut self) -> Option<EngineEvent> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) => return Some(event),
+                Err(RecvError::Lagged(n)) => self.dropped += n,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// Get the next event if one is available.
+    pub fn try_recv(&mut self) -> Option<EngineEvent> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(event) => return Some(event),
+                Err(TryRecvError::Lagged(n)) => self.dropped += n,
+                Err(TryRecvError::Empty | TryRecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// Number of events this subscriber missed because it fell behind.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+/// Whether a forward pass error was caused by the device running out of memory.
+pub(crate) fn is_oom_error(err: &impl std::fmt::Display) -> bool {
+    let msg = err.to_string().to_lowercase();
+    msg.contains("out of memory") || msg.contains("outofmemory")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EngineEvent, EngineEventBus};
+
+    #[test]
+    fn slow_subscriber_counts_dropped() {
+        let bus = EngineEventBus::new(2);
+        let mut fast = bus.subscribe();
+        let mut slow = bus.subscribe();
+
+        bus.emit(EngineEvent::RequestStarted {
+            request_id: 0,
+            seq_id: 0,
+        });
+        assert!(matches!(
+            fast.try_recv(),
+            Some(EngineEvent::RequestStarted { seq_id: 0, .. })
+        ));
+        for seq_id in 1..5 {
+            bus.emit(EngineEvent::RequestStarted {
+                request_id: 0,
+                seq_id,
+            });
+        }
+
+        // The slow subscriber only keeps the 2 most recent events.
+        assert!(matches!(
+            slow.try_recv(),
+            Some(EngineEvent::RequestStarted { seq_id: 3, .. })
+        ));
+        assert_eq!(slow.dropped(), 3);
+        assert!(matches!(
+            slow.try_recv(),
+            Some(EngineEvent::RequestStarted { seq_id: 4, .. })
+        ));
+        assert!(slow.try_recv().is_none());
+        assert_eq!(bus.emitted(), 5);
+    }
+
+    #[test]
+    fn emit_without_subscribers() {
+        let bus = EngineEventBus::default();
+        bus.emit(EngineEvent::ModelLoaded {
+            model_id: "model".to_string(),
+        });
+        let mut subscriber = bus.subscribe();
+        assert!(subscriber.try_recv().is_none());
+        assert_eq!(subscriber.dropped(), 0);
+    }
+}
@@ -2,15 +2,18 @@ use crate::{
     distributed,
     embedding::bert::BertPipeline,
     pipeline::{
-        llg::{constraint_from_llg_grammar, llg_grammar_from_constraint},
+        llg::{constraint_from_llg_grammar, llg_grammar_from_constraint, DynamicRecognizer},
         text_models_inputs_processor::PagedAttentionMeta,
-        CacheBackendMetadata, CacheInstruction,
+        CacheBackendMetadata, CacheInstruction, EitherCache,
+    },
+    prefix_cacher::{
+        persistence_fingerprint, PrefixCacheEvictionConfig, PrefixCacheManagerV2,
+        PrefixCachePersistence,
     },
-    prefix_cacher::PrefixCacheManagerV2,
     response::CompletionChoice,
     scheduler::{Scheduler, SchedulerOutput},
     sequence::{SeqStepType, StopReason},
-    CompletionResponse, SchedulerConfig, DEBUG,
+    CompletionResponse, SamplingDefaults, SchedulerConfig, DEBUG,
 };
 use interprocess::local_socket::{traits::Listener, ListenerOptions};
 use llguidance::toktrie::TokEnv;
@@ -39,11 +42,22 @@ use crate::{
     request::Request,
     response::{ChatCompletionResponse, Choice, ResponseMessage},
     sequence::{SequenceRecognizer, SequenceState},
-    Constraint,
+    Constraint, DynamicGrammar, RequestMessage,
 };
 
 mod add_request;
+mod events;
 mod logger;
+mod preprocess;
+mod recovery;
+
+pub use events::{
+    EngineEvent, EngineEventBus, EngineEventSubscriber, EngineEventUsage, MemoryPressureLevel,
+    DEFAULT_ENGINE_EVENT_CAPACITY,
+};
+pub(crate) use preprocess::PreprocessingPool;
+use preprocess::{ChatPreprocessor, PreparedPrompt, PreprocessJob};
+pub use preprocess::{PreprocessingBenchmark, PreprocessingBenchmarkResults};
 
 pub enum EngineInstruction {
     Terminate,
@@ -74,11 +88,17 @@ pub struct Engine {
     truncate_sequence: bool,
     no_kv_cache: bool,
     prefix_cacher: Arc<Mutex<PrefixCacheManagerV2>>,
+    prefix_cache_persistence: Option<PrefixCachePersistence>,
+    preprocessing_pool: PreprocessingPool,
+    sampling_defaults: SamplingDefaults,
     is_debug: bool,
     disable_eos_stop: bool,
     throughput_logging_enabled: bool,
     logger: IntervalLogger,
     handles: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    events: EngineEventBus,
+    /// Set after an unrecoverable device error.
+    device_error: std::sync::Mutex<Option<String>>,
 }
 
 impl Drop for Engine {
@@ -99,15 +119,34 @@ impl Engine {
         mut no_kv_cache: bool,
         mut no_prefix_cache: bool,
         prefix_cache_n: usize,
+        mut prefix_cache_persistence: Option<PrefixCachePersistence>,
+        prefix_cache_eviction: PrefixCacheEvictionConfig,
+        preprocessing_threads: usize,
+        sampling_defaults: SamplingDefaults,
         disable_eos_stop: bool,
         throughput_logging_enabled: bool,
         search_embedding_model: Option<BertEmbeddingModel>,
+        events: EngineEventBus,
     ) -> anyhow::Result<Self> {
-        no_kv_cache |= get_mut_arcmutex!(pipeline).get_metadata().no_kv_cache;
+        let metadata = get_mut_arcmutex!(pipeline).get_metadata();
+        no_kv_cache |= metadata.no_kv_cache;
 
         no_prefix_cache = matches!(config, SchedulerConfig::PagedAttentionMeta { .. })
             || no_prefix_cache
-            || no_kv_cache;
+            || no_kv_cache
+            || metadata.no_prefix_cache;
+
+        // Daemons hold the same prefix cache as the main process.
+        if no_prefix_cache || distributed::is_daemon() {
+            prefix_cache_persistence = None;
+        } else if prefix_cache_persistence.is_some()
+            && persistence_fingerprint(get_mut_arcmutex!(pipeline).get_metadata()).is_none()
+        {
+            tracing::warn!(
+                "The model could not be fingerprinted, so the prefix cache will not be persisted."
+            );
+            prefix_cache_persistence = None;
+        }
 
         let bert_pipeline = match search_embedding_model {
             Some(search_embedding_model) => Some(BertPipeline::new(
@@ -125,15 +164,20 @@ impl Engine {
             id: Arc::new(Mutex::new(0)),
             truncate_sequence,
             no_kv_cache,
-            prefix_cacher: Arc::new(Mutex::new(PrefixCacheManagerV2::new(
-                prefix_cache_n,
-                no_prefix_cache,
-            ))),
+            prefix_cacher: Arc::new(Mutex::new(
+                PrefixCacheManagerV2::new(prefix_cache_n, no_prefix_cache)
+                    .with_eviction(&prefix_cache_eviction),
+            )),
+            prefix_cache_persistence,
+            preprocessing_pool: PreprocessingPool::new(preprocessing_threads)?,
+            sampling_defaults,
             is_debug: DEBUG.load(Ordering::Relaxed),
             disable_eos_stop,
             throughput_logging_enabled,
             logger: IntervalLogger::new(Duration::from_secs(5)),
             handles: Arc::new(Mutex::new(Vec::new())),
+            events,
+            device_error: std::sync::Mutex::new(None),
         })
     }
 
@@ -142,6 +186,14 @@ impl Engine {
             self.logger.enable_logging();
         }
 
+        self.events.emit(EngineEvent::ModelLoaded {
+            model_id: get_mut_arcmutex!(self.pipeline).name(),
+        });
+
+        if let Some(persistence) = self.prefix_cache_persistence.clone() {
+            self.load_persisted_prefix_cache(persistence);
+        }
+
         let rng = Arc::new(std::sync::Mutex::new(Isaac64Rng::seed_from_u64(SEED)));
         let mut last_completion_ids: Vec<usize> = vec![];
         'lp: loop {
@@ -156,12 +208,22 @@ impl Engine {
                 break 'lp;
             }
 
+            let mut requests = Vec::new();
+            let mut terminate = false;
             while let Ok(request) = get_mut_arcmutex!(self.rx).try_recv() {
                 self.replicate_request_to_daemons(&request);
                 if matches!(request, Request::Terminate) {
-                    break 'lp;
+                    terminate = true;
+                    break;
                 }
-                self.clone().handle_request(request).await;
+                requests.push(request);
+            }
+            let prompts = self.preprocess(&requests).await;
+            for (request, prompt) in requests.into_iter().zip(prompts) {
+                self.clone().handle_request(request, prompt).await;
+            }
+            if terminate {
+                break 'lp;
             }
 
             if TERMINATE_ALL_NEXT_STEP.load(Ordering::SeqCst) {
@@ -206,20 +268,22 @@ impl Engine {
                                 "All sequences must either return raw logits, or not."
                             );
 
-                            pipeline
-                                .step(
-                                    &mut scheduled.completion,
-                                    false,
-                                    return_raw_logits,
-                                    &mut *get_mut_arcmutex!(self.prefix_cacher),
-                                    self.disable_eos_stop,
-                                    rng.clone(),
-                                    CacheBackendMetadata::DefaultInstructions { pre_op, post_op },
-                                )
-                                .await
+                            self.step_with_bisection(
+                                &mut *pipeline,
+                                &mut scheduled.completion,
+                                false,
+                                return_raw_logits,
+                                &mut *get_mut_arcmutex!(self.prefix_cacher),
+                                rng.clone(),
+                                pre_op,
+                                post_op,
+                            )
+                            .await
                         };
 
-                        handle_pipeline_forward_error!(
+                        self.maybe_emit_oom(&res);
+                        self.maybe_set_device_error(&res);
+                        let (_, bisected) = handle_pipeline_forward_error!(
                             "completion step",
                             res,
                             &mut scheduled.completion,
@@ -229,8 +293,20 @@ impl Engine {
                         );
 
                         self.logger.add_tokens_processed(scheduled.completion.len());
+                        self.events
+                            .emit_finished(scheduled.completion.iter().map(|seq| &**seq));
 
-                        last_completion_ids = current_completion_ids;
+                        // Beam search moves the KV caches between its sequences, so they are
+                        // loaded again.
+                        let has_beams = scheduled
+                            .completion
+                            .iter()
+                            .any(|seq| seq.beam_search().is_some());
+                        last_completion_ids = if bisected || has_beams {
+                            vec![]
+                        } else {
+                            current_completion_ids
+                        };
                     }
 
                     if !scheduled.prompt.is_empty() {
@@ -267,20 +343,22 @@ impl Engine {
                                 }
                             };
 
-                            pipeline
-                                .step(
-                                    &mut scheduled.prompt,
-                                    true,
-                                    return_raw_logits,
-                                    &mut *get_mut_arcmutex!(self.prefix_cacher),
-                                    self.disable_eos_stop,
-                                    rng.clone(),
-                                    CacheBackendMetadata::DefaultInstructions { pre_op, post_op },
-                                )
-                                .await
+                            self.step_with_bisection(
+                                &mut *pipeline,
+                                &mut scheduled.prompt,
+                                true,
+                                return_raw_logits,
+                                &mut *get_mut_arcmutex!(self.prefix_cacher),
+                                rng.clone(),
+                                pre_op,
+                                post_op,
+                            )
+                            .await
                         };
 
-                        let prompt_exec_time = handle_pipeline_forward_error!(
+                        self.maybe_emit_oom(&prompt_exec_time);
+                        self.maybe_set_device_error(&prompt_exec_time);
+                        let (prompt_exec_time, _) = handle_pipeline_forward_error!(
                             "prompt step",
                             prompt_exec_time,
                             &mut scheduled.prompt,
@@ -297,6 +375,10 @@ impl Engine {
                         self.logger.add_tokens_processed(total_processed_tokens);
 
                         for seq in scheduled.prompt.iter_mut() {
+                            if seq.getstate() == SequenceState::Error {
+                                // Removed from the batch after failing in isolation.
+                                continue;
+                            }
                             match seq.sequence_stepping_type() {
                                 SeqStepType::OneShot => {
                                     seq.set_state(SequenceState::Done(StopReason::GeneratedImage))
@@ -314,7 +396,13 @@ impl Engine {
                                 seq.len() as f32 / prompt_exec_time.as_secs_f32();
                             seq.prompt_tok_per_sec = prompt_tok_per_sec;
                             seq.prompt_timestamp = Some(now);
+                            self.events.emit(EngineEvent::RequestStarted {
+                                request_id: seq.request_id(),
+                                seq_id: *seq.id(),
+                            });
                         }
+                        self.events
+                            .emit_finished(scheduled.prompt.iter().map(|seq| &**seq));
                         last_completion_ids = vec![];
                     }
 
@@ -346,6 +434,18 @@ impl Engine {
                     }
                 }
                 SchedulerOutput::PagedAttention { mut output } => {
+                    if !output.blocks_to_swap_out.is_empty() || !output.blocks_to_swap_in.is_empty()
+                    {
+                        if !output.blocks_to_swap_out.is_empty() {
+                            self.events.emit(EngineEvent::MemoryPressure {
+                                level: MemoryPressureLevel::High,
+                            });
+                        }
+                        self.events.emit(EngineEvent::SequenceSwapped {
+                            swapped_out_blocks: output.blocks_to_swap_out.len(),
+                            swapped_in_blocks: output.blocks_to_swap_in.len(),
+                        });
+                    }
                     if !output.scheduled.is_empty() {
                         let is_prompt = get_mut_arcmutex!(output.scheduled[0]).is_prompt();
 
@@ -395,6 +495,8 @@ impl Engine {
                                 .await
                         };
 
+                        self.maybe_emit_oom(&res);
+                        self.maybe_set_device_error(&res);
                         handle_pipeline_forward_error!(
                             "step",
                             res,
@@ -442,7 +544,7 @@ impl Engine {
                         }
 
                         if is_prompt {
-                            for mut seq in guards {
+                            for seq in guards.iter_mut() {
                                 let now = SystemTime::now()
                                     .duration_since(UNIX_EPOCH)
                                     .expect("Time travel has occurred!")
@@ -452,28 +554,186 @@ impl Engine {
                                     seq.len() as f32 / (now - seq.timestamp()) as f32;
                                 seq.prompt_tok_per_sec = prompt_tok_per_sec * 1000.;
                                 seq.prompt_timestamp = Some(now);
+                                self.events.emit(EngineEvent::RequestStarted {
+                                    request_id: seq.request_id(),
+                                    seq_id: *seq.id(),
+                                });
                             }
                         }
+                        self.events.emit_finished(guards.iter().map(|seq| &**seq));
                     }
                 }
             }
 
             scheduler.free_finished_sequence_groups();
         }
+
+        self.persist_prefix_cache();
+    }
+
+    /// Load the persisted prefix cache in the background. Each prefix can be matched as soon as it
+    /// is loaded.
+    fn load_persisted_prefix_cache(&self, persistence: PrefixCachePersistence) {
+        let (fingerprint, layer_devices) = {
+            let pipeline = get_mut_arcmutex!(self.pipeline);
+            let Some(fingerprint) = persistence_fingerprint(pipeline.get_metadata()) else {
+                return;
+            };
+            if !matches!(pipeline.cache(), EitherCache::Normal(_)) {
+                return;
+            }
+            let n_layers = pipeline.cache().normal().0.len();
+            let device = pipeline.device();
+            let layer_devices = (0..n_layers)
+                .map(|layer| {
+                    pipeline
+                        .device_mapper()
+                        .and_then(|mapper| mapper.device_for(layer, false))
+                        .unwrap_or(&device)
+                        .clone()
+                })
+                .collect::<Vec<_>>();
+            (fingerprint, layer_devices)
+        };
+
+        let prefix_cacher = self.prefix_cacher.clone();
+        let handle = tokio::task::spawn_blocking(move || {
+            let start = Instant::now();
+            let n_loaded = persistence.load(&fingerprint, layer_devices.len(), |prefix| {
+                prefix_cacher
+                    .blocking_lock()
+                    .add_persisted(prefix, &layer_devices)
+            });
+            if n_loaded > 0 {
+                tracing::info!(
+                    "Loaded {n_loaded} persisted prefix caches from `{}` in {:.2}s.",
+                    persistence.dir.display(),
+                    start.elapsed().as_secs_f64()
+                );
+            }
+        });
+        get_mut_arcmutex!(self.handles).push(handle);
+    }
+
+    /// Persist the prefix cache when the engine terminates.
+    fn persist_prefix_cache(&self) {
+        let Some(persistence) = &self.prefix_cache_persistence else {
+            return;
+        };
+        if self
+            .device_error
+            .lock()
+            .expect("`device_error` was poisoned")
+            .is_some()
+        {
+            tracing::warn!("Not persisting the prefix cache after a device error.");
+            return;
+        }
+        let Some(fingerprint) =
+            persistence_fingerprint(get_mut_arcmutex!(self.pipeline).get_metadata())
+        else {
+            return;
+        };
+        match get_mut_arcmutex!(self.prefix_cacher).persist(persistence, &fingerprint) {
+            Ok(n_persisted) => tracing::info!(
+                "Persisted {n_persisted} prefix caches to `{}`.",
+                persistence.dir.display()
+            ),
+            Err(e) => tracing::warn!("Could not persist the prefix cache: {e}"),
+        }
+    }
+
+    /// Render and tokenize the chat requests of a batch in parallel on the preprocessing pool.
+    /// The other requests are preprocessed when they are added.
+    async fn preprocess(&self, requests: &[Request]) -> Vec<Option<PreparedPrompt>> {
+        let mut prompts = requests.iter().map(|_| None).collect::<Vec<_>>();
+        let Some(preprocessor) = ChatPreprocessor::new(&*get_mut_arcmutex!(self.pipeline)) else {
+            return prompts;
+        };
+        let mut jobs = Vec::new();
+        let mut indices = Vec::new();
+        for (i, request) in requests.iter().enumerate() {
+            // Web searches add a tool and documents are packed when the request is handled.
+            let Request::Normal(request) = request else {
+                continue;
+            };
+            let RequestMessage::Chat(messages) = &request.messages else {
+                continue;
+            };
+            if request.web_search_options.is_some() || request.documents.is_some() {
+                continue;
+            }
+            // Invalid system messages are reported when the request is added.
+            let Ok(messages) = preprocessor
+                .chat_template
+                .adapt_system_messages(messages.clone(), request.strict_system_role)
+            else {
+                continue;
+            };
+            jobs.push(PreprocessJob {
+                messages,
+                tools: request.tools.clone().unwrap_or_default(),
+                enable_thinking: request.enable_thinking,
+                documents: Vec::new(),
+            });
+            indices.push(i);
+        }
+        let batch = self
+            .preprocessing_pool
+            .process_batch(preprocessor, jobs)
+            .await;
+        for (i, prompt) in indices.into_iter().zip(batch) {
+            prompts[i] = Some(prompt);
+        }
+        prompts
     }
 
     fn build_sequence_recognizer(
         tok_env: &Option<TokEnv>,
         constraint: &Constraint,
-    ) -> anyhow::Result<SequenceRecognizer> {
+        dynamic_grammar: Option<&DynamicGrammar>,
+    ) -> anyhow::Result<(SequenceRecognizer, Option<DynamicRecognizer>)> {
+        if let Some(grammar) = dynamic_grammar {
+            if !matches!(constraint, Constraint::None) {
+                anyhow::bail!("A dynamic grammar cannot be combined with a constraint.");
+            }
+            let tok_env = tok_env
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("No token environment found."))?;
+            let mut dynamic = DynamicRecognizer::new(grammar.clone(), tok_env.clone());
+            return Ok((dynamic.initial()?, Some(dynamic)));
+        }
         if let Some(grm) = llg_grammar_from_constraint(constraint)? {
             let tok_env = tok_env
                 .as_ref()
                 .ok_or_else(|| anyhow::anyhow!("No token environment found."))?;
             let llg = constraint_from_llg_grammar(tok_env.clone(), grm)?;
-            Ok(SequenceRecognizer::Llguidance(Box::new(llg)))
+            Ok((SequenceRecognizer::Llguidance(Box::new(llg)), None))
         } else {
-            Ok(SequenceRecognizer::None)
+            Ok((SequenceRecognizer::None, None))
+        }
+    }
+
+    fn maybe_emit_oom<T, E: std::fmt::Display>(&self, res: &Result<T, E>) {
+        if let Err(e) = res {
+            if events::is_oom_error(e) {
+                self.events.emit(EngineEvent::MemoryPressure {
+                    level: MemoryPressureLevel::OutOfMemory,
+                });
+            }
+        }
+    }
+
+    /// After an unrecoverable device error, mark the engine as errored so new requests fail fast.
+    fn maybe_set_device_error<T>(&self, res: &Result<T, candle_core::Error>) {
+        if let Err(e) = res {
+            if recovery::classify_forward_error(e) == recovery::ForwardErrorKind::Unrecoverable {
+                tracing::error!("Unrecoverable device error, new requests will be rejected: {e}");
+                *self
+                    .device_error
+                    .lock()
+                    .expect("`device_error` was poisoned") = Some(e.to_string());
+            }
         }
     }
 
@@ -0,0 +1,306 @@
+//! Chat template rendering and tokenization of requests on a dedicated thread pool, so that
+//! preprocessing does not hold the pipeline lock nor the engine task.
+
+use std::{
+    cell::RefCell,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use either::Either;
+use indexmap::IndexMap;
+use rayon::{
+    iter::{IntoParallelIterator, ParallelIterator},
+    ThreadPool, ThreadPoolBuilder,
+};
+use serde::Serialize;
+use tokenizers::Tokenizer;
+use tokio_rayon::AsyncThreadPool;
+
+use crate::{
+    pipeline::{chat_template::ChatTemplate, render_chat_template},
+    search::documents::{
+        add_documents_section, pack_documents, render_documents_section, template_documents,
+        DOCUMENTS_PLACEHOLDER,
+    },
+    DocumentUsage, MessageContent, ModelCategory, Pipeline, RequestDocuments, Tool,
+};
+
+/// The tokens and the rendered prompt of a request.
+pub(crate) type PreparedPrompt = Result<(Vec<u32>, String)>;
+
+/// A chat request to render and tokenize, with the system messages already adapted to the chat
+/// template.
+pub(crate) struct PreprocessJob {
+    pub messages: Vec<IndexMap<String, MessageContent>>,
+    pub tools: Vec<Tool>,
+    pub enable_thinking: Option<bool>,
+    /// Documents for chat templates with a documents slot.
+    pub documents: Vec<IndexMap<String, String>>,
+}
+
+/// What is needed of the pipeline to preprocess chat requests without locking it.
+#[derive(Clone)]
+pub(crate) struct ChatPreprocessor {
+    pub chat_template: Arc<ChatTemplate>,
+    tokenizer: Arc<Tokenizer>,
+}
+
+thread_local! {
+    /// Tokenizer of each preprocessing thread, cloned from the shared tokenizer so the threads do
+    /// not contend on its caches, and reused by all the jobs of the thread.
+    static TOKENIZER: RefCell<Option<(Arc<Tokenizer>, Tokenizer)>> = const { RefCell::new(None) };
+}
+
+impl ChatPreprocessor {
+    /// `None` unless the pipeline is a text model with a chat template and a tokenizer, as other
+    /// pipelines have their own processor which needs the pipeline.
+    pub fn new(pipeline: &dyn Pipeline) -> Option<Self> {
+        if !matches!(pipeline.category(), ModelCategory::Text) {
+            return None;
+        }
+        Some(Self {
+            chat_template: pipeline
+                .get_chat_template()
+                .filter(|chat_template| chat_template.has_chat_template())?,
+            tokenizer: pipeline.tokenizer()?,
+        })
+    }
+
+    /// Render and tokenize the prompt, as the default `Processor::process`.
+    pub fn process(&self, job: PreprocessJob) -> PreparedPrompt {
+        let prompt = render_chat_template(
+            &self.chat_template,
+            job.messages,
+            true,
+            job.tools,
+            job.enable_thinking,
+            job.documents,
+        )?;
+        let encoding = TOKENIZER.with_borrow_mut(|tokenizer| {
+            if !matches!(tokenizer, Some((shared, _)) if Arc::ptr_eq(shared, &self.tokenizer)) {
+                *tokenizer = Some((self.tokenizer.clone(), (*self.tokenizer).clone()));
+            }
+            let (_, tokenizer) = tokenizer.as_ref().expect("The tokenizer was just set.");
+            tokenizer
+                .encode_fast(prompt.as_str(), true)
+                .map_err(anyhow::Error::msg)
+        })?;
+        Ok((encoding.get_ids().to_vec(), prompt))
+    }
+
+    fn process_batch(&self, jobs: Vec<PreprocessJob>) -> Vec<PreparedPrompt> {
+        jobs.into_par_iter().map(|job| self.process(job)).collect()
+    }
+}
+
+/// Render and tokenize a chat request with the documents which fit in the context left after the
+/// messages and `reserved_tokens` generated tokens. The documents are given to the chat template if
+/// it has a documents slot, otherwise they are rendered in a section of the system message.
+pub(crate) fn process_with_documents(
+    pipeline: &dyn Pipeline,
+    messages: Vec<IndexMap<String, MessageContent>>,
+    tools: Vec<Tool>,
+    enable_thinking: Option<bool>,
+    RequestDocuments { documents, packing }: RequestDocuments,
+    reserved_tokens: usize,
+) -> Result<(Vec<u32>, String, Vec<DocumentUsage>)> {
+    let processor = pipeline.get_processor();
+    let (toks, prompt) = processor.process(
+        pipeline,
+        messages.clone(),
+        true,
+        true,
+        tools.clone(),
+        enable_thinking,
+    )?;
+    let tokenizer = pipeline
+        .tokenizer()
+        .context("Packing documents requires the model to have a tokenizer.")?;
+    let token_ends = |text: &str| -> Result<Vec<usize>> {
+        let encoding = tokenizer.encode(text, false).map_err(anyhow::Error::msg)?;
+        Ok(encoding.get_offsets().iter().map(|(_, end)| *end).collect())
+    };
+
+    let native = ChatPreprocessor::new(pipeline)
+        .filter(|preprocessor| preprocessor.chat_template.has_documents_slot());
+    let section_tokens = match native {
+        Some(_) => 0,
+        None => token_ends(&packing.section_format.replace(DOCUMENTS_PLACEHOLDER, ""))?.len(),
+    };
+    let budget = pipeline
+        .get_metadata()
+        .max_seq_len
+        .saturating_sub(toks.len() + reserved_tokens + section_tokens);
+    let packed = pack_documents(documents, &packing, budget, token_ends)?;
+    if packed.is_empty() {
+        return Ok((toks, prompt, Vec::new()));
+    }
+
+    let (toks, prompt) = match native {
+        Some(preprocessor) => preprocessor.process(PreprocessJob {
+            messages,
+            tools,
+            enable_thinking,
+            documents: template_documents(&packed),
+        })?,
+        None => processor.process(
+            pipeline,
+            add_documents_section(messages, render_documents_section(&packing, &packed)),
+            true,
+            true,
+            tools,
+            enable_thinking,
+        )?,
+    };
+    Ok((
+        toks,
+        prompt,
+        packed.into_iter().map(|document| document.usage).collect(),
+    ))
+}
+
+/// Thread pool preprocessing requests, sized independently of the tokio workers.
+pub(crate) struct PreprocessingPool {
+    pool: ThreadPool,
+}
+
+impl PreprocessingPool {
+    /// Number of threads if not configured: the available parallelism, up to 4 threads.
+    pub fn default_threads() -> usize {
+        std::thread::available_parallelism().map_or(1, |n| n.get().min(4))
+    }
+
+    pub fn new(threads: usize) -> Result<Self> {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("mistralrs-preprocess-{i}"))
+            .build()
+            .context("Could not create the preprocessing thread pool.")?;
+        Ok(Self { pool })
+    }
+
+    pub fn threads(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+
+    /// Preprocess a batch of jobs in parallel, returning the prompts in the order of the jobs.
+    pub async fn process_batch(
+        &self,
+        preprocessor: ChatPreprocessor,
+        jobs: Vec<PreprocessJob>,
+    ) -> Vec<PreparedPrompt> {
+        if jobs.is_empty() {
+            return Vec::new();
+        }
+        self.pool
+            .spawn_async(move || preprocessor.process_batch(jobs))
+            .await
+    }
+}
+
+/// Requests per second of chat template rendering and tokenization.
+#[derive(Clone, Debug, Serialize)]
+pub struct PreprocessingBenchmarkResults {
+    pub requests: usize,
+    /// Tokens of each prompt.
+    pub prompt_tokens: usize,
+    /// Preprocessing the requests one after the other, as when they are added to the engine
+    /// without the preprocessing pool.
+    pub sequential_rps: f64,
+    /// Preprocessing the requests as one batch on the preprocessing pool.
+    pub pool_rps: f64,
+    pub pool_threads: usize,
+}
+
+/// Measures the admission rate of chat requests, bounded by their preprocessing, with synthetic
+/// conversations and without running the model.
+pub struct PreprocessingBenchmark;
+
+impl PreprocessingBenchmark {
+    pub fn run(
+        pipeline: &dyn Pipeline,
+        requests: usize,
+        prompt_words: usize,
+        threads: Option<usize>,
+    ) -> Result<PreprocessingBenchmarkResults> {
+        let preprocessor = ChatPreprocessor::new(pipeline).context(
+            "Benchmarking preprocessing requires a text model with a chat template and a tokenizer.",
+        )?;
+        let pool =
+            PreprocessingPool::new(threads.unwrap_or_else(PreprocessingPool::default_threads))?;
+        let conversations = (0..requests)
+            .map(|i| synthetic_conversation(i, prompt_words))
+            .collect::<Vec<_>>();
+
+        let start = Instant::now();
+        let mut prompt_tokens = 0;
+        for messages in conversations.clone() {
+            let (toks, _) = pipeline.get_processor().process(
+                pipeline,
+                messages,
+                true,
+                true,
+                Vec::new(),
+                None,
+            )?;
+            prompt_tokens = toks.len();
+        }
+        let sequential = start.elapsed();
+
+        let jobs = conversations
+            .into_iter()
+            .map(|messages| PreprocessJob {
+                messages,
+                tools: Vec::new(),
+                enable_thinking: None,
+                documents: Vec::new(),
+            })
+            .collect();
+        let start = Instant::now();
+        let prompts = pool.pool.install(|| preprocessor.process_batch(jobs));
+        let batched = start.elapsed();
+        for prompt in prompts {
+            prompt?;
+        }
+
+        Ok(PreprocessingBenchmarkResults {
+            requests,
+            prompt_tokens,
+            sequential_rps: rps(requests, sequential),
+            pool_rps: rps(requests, batched),
+            pool_threads: pool.threads(),
+        })
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn rps(requests: usize, time: Duration) -> f64 {
+    if time.is_zero() {
+        0.
+    } else {
+        requests as f64 / time.as_secs_f64()
+    }
+}
+
+/// A system and a user message of about `words` words, different for every request.
+fn synthetic_conversation(i: usize, words: usize) -> Vec<IndexMap<String, MessageContent>> {
+    const WORDS: [&str; 8] = [
+        "the", "quick", "brown", "fox", "jumps", "over", "lazy", "dogs",
+    ];
+    let text = (0..words)
+        .map(|w| WORDS[(w + i) % WORDS.len()])
+        .collect::<Vec<_>>()
+        .join(" ");
+    let message = |role: &str, content: String| {
+        IndexMap::from([
+            ("role".to_string(), Either::Left(role.to_string())),
+            ("content".to_string(), Either::Left(content)),
+        ])
+    };
+    vec![
+        message("system", format!("You are assistant number {i}.")),
+        message("user", text),
+    ]
+}
@@ -0,0 +1,179 @@
+use std::{sync::Arc, time::Duration};
+
+use rand_isaac::Isaac64Rng;
+use tracing::{error, warn};
+
+use crate::{
+    pipeline::{CacheBackendMetadata, CacheInstruction, Pipeline},
+    prefix_cacher::PrefixCacheManagerV2,
+    response::Response,
+    sequence::{Sequence, SequenceState},
+};
+
+use super::Engine;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ForwardErrorKind {
+    /// The step may succeed with fewer sequences, for example an out of memory error.
+    Recoverable,
+    /// The device is in an unusable state, for example after an illegal memory access.
+    Unrecoverable,
+    Other,
+}
+
+const UNRECOVERABLE_ERRORS: &[&str] = &[
+    "illegal memory access",
+    "illegal address",
+    "cuda_error_illegal",
+    "device-side assert",
+    "unspecified launch failure",
+    "cuda_error_launch_failed",
+    "uncorrectable ecc error",
+    "cuda_error_ecc_uncorrectable",
+];
+
+const RECOVERABLE_ERRORS: &[&str] = &[
+    "out of memory",
+    "outofmemory",
+    "cuda_error_out_of_memory",
+    "failed to allocate",
+];
+
+pub(crate) fn classify_forward_error(err: &candle_core::Error) -> ForwardErrorKind {
+    let msg = format!("{err:?}").to_lowercase();
+    if UNRECOVERABLE_ERRORS.iter().any(|pat| msg.contains(pat)) {
+        ForwardErrorKind::Unrecoverable
+    } else if RECOVERABLE_ERRORS.iter().any(|pat| msg.contains(pat)) {
+        ForwardErrorKind::Recoverable
+    } else {
+        ForwardErrorKind::Other
+    }
+}
+
+impl Engine {
+    /// Run a step with the default cache instructions. If it fails with a recoverable error, the
+    /// batch is bisected and retried to isolate the offending sequences, which are failed
+    /// individually while the rest of the batch proceeds.
+    ///
+    /// Retrying is possible because the sequences are only updated once a forward pass succeeds:
+    /// inputs are rebuilt from the sequences and the KV cache is reloaded from them.
+    ///
+    /// Returns the execution time and whether the batch was bisected. After bisection, the model
+    /// cache no longer holds the state of the whole batch.
+    #[allow(clippy::too_many_arguments)]
+    pub(super) async fn step_with_bisection(
+        &self,
+        pipeline: &mut dyn Pipeline,
+        seqs: &mut [&mut Sequence],
+        is_prompt: bool,
+        return_raw_logits: bool,
+        prefix_cacher: &mut PrefixCacheManagerV2,
+        rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+        pre_op: CacheInstruction,
+        post_op: CacheInstruction,
+    ) -> Result<(Duration, bool), candle_core::Error> {
+        let err = match pipeline
+            .step(
+                seqs,
+                is_prompt,
+                return_raw_logits,
+                prefix_cacher,
+                self.disable_eos_stop,
+                rng.clone(),
+                CacheBackendMetadata::DefaultInstructions { pre_op, post_op },
+            )
+            .await
+        {
+            Ok(duration) => return Ok((duration, false)),
+            Err(e) => e,
+        };
+        if seqs.len() == 1 || classify_forward_error(&err) != ForwardErrorKind::Recoverable {
+            return Err(err);
+        }
+        warn!(
+            "Step over {} sequences failed with a recoverable error, bisecting the batch: {err}",
+            seqs.len()
+        );
+
+        // The model cache is in an unknown state, so a retry must load it from the sequences.
+        let retry_pre_op = if is_prompt {
+            pre_op
+        } else {
+            CacheInstruction::In
+        };
+        let mut total = Duration::ZERO;
+        let mid = seqs.len() / 2;
+        let mut pending = vec![mid..seqs.len(), 0..mid];
+        while let Some(range) = pending.pop() {
+            let chunk = &mut seqs[range.clone()];
+            let res = pipeline
+                .step(
+                    chunk,
+                    is_prompt,
+                    return_raw_logits,
+                    prefix_cacher,
+                    self.disable_eos_stop,
+                    rng.clone(),
+                    CacheBackendMetadata::DefaultInstructions {
+                        pre_op: retry_pre_op,
+                        post_op,
+                    },
+                )
+                .await;
+            match res {
+                Ok(duration) => total += duration,
+                Err(e) if classify_forward_error(&e) == ForwardErrorKind::Recoverable => {
+                    if chunk.len() > 1 {
+                        let mid = range.start + chunk.len() / 2;
+                        pending.push(mid..range.end);
+                        pending.push(range.start..mid);
+                    } else {
+                        let seq = &mut *chunk[0];
+                        error!(
+                            "Sequence {} failed in isolation, removing it: {e}",
+                            seq.id()
+                        );
+                        seq.responder()
+                            .send(Response::InternalError(
+                                format!(
+                                    "This sequence could not be processed and was removed from the batch: {e}"
+                                )
+                                .into(),
+                            ))
+                            .await
+                            .expect("Expected receiver.");
+                        seq.set_state(SequenceState::Error);
+                        pipeline.set_none_cache(chunk, false, false, false);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((total, true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{classify_forward_error, ForwardErrorKind};
+
+    #[test]
+    fn classify_errors() {
+        let oom = candle_core::Error::Msg(
+            "DriverError(CUDA_ERROR_OUT_OF_MEMORY, \"out of memory\")".to_string(),
+        );
+        assert_eq!(classify_forward_error(&oom), ForwardErrorKind::Recoverable);
+
+        let illegal = candle_core::Error::Msg(
+            "DriverError(CUDA_ERROR_ILLEGAL_ADDRESS, \"an illegal memory access was encountered\")"
+                .to_string(),
+        );
+        assert_eq!(
+            classify_forward_error(&illegal),
+            ForwardErrorKind::Unrecoverable
+        );
+
+        let shape = candle_core::Error::Msg("shape mismatch in matmul".to_string());
+        assert_eq!(classify_forward_error(&shape), ForwardErrorKind::Other);
+    }
+}
@@ -0,0 +1,200 @@
+//! Fingerprint of the loaded model, sent as the `system_fingerprint` of responses so that outputs
+//! can be attributed to the exact weights which produced them.
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use candle_core::quantized::gguf_file;
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::{
+    pipeline::{AdapterPaths, ModelPaths},
+    SYSTEM_FINGERPRINT,
+};
+
+/// Number of evenly spaced samples of the tensor data hashed per file.
+const NUM_SAMPLES: u64 = 16;
+/// Bytes hashed per sample of the tensor data.
+const SAMPLE_BYTES: u64 = 64 * 1024;
+/// Bytes treated as the header of files whose header cannot be parsed.
+const FALLBACK_HEADER_BYTES: u64 = 1024 * 1024;
+
+/// Length of the header of a safetensors or GGUF file, which holds the names, shapes, types and
+/// offsets of the tensors.
+fn header_len(file: &mut File, path: &Path) -> io::Result<u64> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("safetensors") => {
+            let mut len = [0u8; 8];
+            file.read_exact(&mut len)?;
+            Ok(8 + u64::from_le_bytes(len))
+        }
+        Some("gguf") => gguf_file::Content::read(file)
+            .map(|content| content.tensor_data_offset)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        _ => Ok(FALLBACK_HEADER_BYTES),
+    }
+}
+
+/// Digest of a file. Files in the Hugging Face cache are stored as blobs named by their hash, which
+/// is used directly. Other files are not read in full, as weights are often many gigabytes: the
+/// digest covers the header with the tensor metadata and evenly spaced samples of the tensor data.
+fn file_digest(path: &Path) -> io::Result<String> {
+    let path = path.canonicalize()?;
+    let in_blobs = path
+        .parent()
+        .and_then(Path::file_name)
+        .is_some_and(|dir| dir == "blobs");
+    if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+        let is_hash = matches!(name.len(), 40 | 64) && name.chars().all(|c| c.is_ascii_hexdigit());
+        if in_blobs && is_hash {
+            return Ok(name.to_string());
+        }
+    }
+
+    let mut file = File::open(&path)?;
+    let len = file.metadata()?.len();
+    let header = header_len(&mut file, &path)
+        .unwrap_or(FALLBACK_HEADER_BYTES)
+        .min(len);
+    let mut hasher = Sha256::new();
+    let mut hash_range = |file: &mut File, start: u64, n: u64| -> io::Result<()> {
+        file.seek(SeekFrom::Start(start))?;
+        io::copy(&mut file.take(n), &mut hasher)?;
+        Ok(())
+    };
+    hash_range(&mut file, 0, header)?;
+    let data = len - header;
+    if data <= NUM_SAMPLES * SAMPLE_BYTES {
+        hash_range(&mut file, header, data)?;
+    } else {
+        for i in 0..NUM_SAMPLES {
+            let offset = i * (data - SAMPLE_BYTES) / (NUM_SAMPLES - 1);
+            hash_range(&mut file, header + offset, SAMPLE_BYTES)?;
+        }
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Fingerprint of a set of files and the quantization applied to them, for example
+/// `fp_3f2a9c01d4e5b6a7_q4k`.
+pub(crate) fn fingerprint_files(
+    files: &[PathBuf],
+    quantization: Option<&str>,
+) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    for file in files {
+        hasher.update(file_digest(file)?.as_bytes());
+        hasher.update(file.metadata()?.len().to_le_bytes());
+    }
+    let digest = format!("{:x}", hasher.finalize());
+    let mut fingerprint = format!("fp_{}", &digest[..16]);
+    if let Some(quantization) = quantization {
+        fingerprint.push('_');
+        fingerprint.push_str(&quantization.to_lowercase());
+    }
+    Ok(fingerprint)
+}
+
+/// Fingerprint of the weights and adapters of a model. Falls back to [`SYSTEM_FINGERPRINT`] if the
+/// files cannot be read.
+pub(crate) fn model_fingerprint(paths: &dyn ModelPaths, quantization: Option<&str>) -> String {
+    let mut files = paths.get_weight_filenames().to_vec();
+    match paths.get_adapter_paths() {
+        AdapterPaths::XLora {
+            adapter_safetensors,
+            classifier_path,
+            ..
+        } => {
+            files.extend(
+                adapter_safetensors
+                    .iter()
+                    .flatten()
+                    .map(|(_, path)| path.clone()),
+            );
+            files.extend(classifier_path.clone());
+        }
+        AdapterPaths::Lora(adapters) => {
+            files.extend(adapters.iter().map(|adapter| adapter.adapter_path.clone()));
+        }
+        AdapterPaths::None => (),
+    }
+
+    fingerprint_files(&files, quantization).unwrap_or_else(|e| {
+        warn!("Could not compute the model fingerprint: {e}");
+        SYSTEM_FINGERPRINT.to_string()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fingerprint_files, NUM_SAMPLES, SAMPLE_BYTES};
+
+    #[test]
+    fn distinct_fingerprints() {
+        let dir = std::env::temp_dir().join("mistralrs_fingerprint");
+        std::fs::create_dir_all(&dir).unwrap();
+        let q4 = dir.join("model-Q4_K_M.gguf");
+        let q8 = dir.join("model-Q8_0.gguf");
+        std::fs::write(&q4, b"GGUF q4 weights").unwrap();
+        std::fs::write(&q8, b"GGUF q8 weights").unwrap();
+
+        let fp_q4 = fingerprint_files(&[q4.clone()], Some("Q4K")).unwrap();
+        let fp_q8 = fingerprint_files(&[q8.clone()], Some("Q8_0")).unwrap();
+        assert_ne!(fp_q4, fp_q8);
+        assert!(fp_q4.starts_with("fp_") && fp_q4.ends_with("_q4k"));
+        assert_eq!(
+            fp_q4,
+            fingerprint_files(&[q4.clone()], Some("Q4K")).unwrap()
+        );
+
+        // Changing the file changes the fingerprint.
+        std::fs::write(&q4, b"GGUF q4 weights, requantized").unwrap();
+        assert_ne!(fp_q4, fingerprint_files(&[q4], Some("Q4K")).unwrap());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn large_files_are_sampled() {
+        let dir =
+            std::env::temp_dir().join(format!("mistralrs_fingerprint_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("model.safetensors");
+        let header = br#"{"w":{"dtype":"U8","shape":[2097152],"data_offsets":[0,2097152]}}"#;
+        let mut file = (header.len() as u64).to_le_bytes().to_vec();
+        file.extend_from_slice(header);
+        let data_start = file.len();
+        file.resize(
+            data_start + 2 * NUM_SAMPLES as usize * SAMPLE_BYTES as usize,
+            0,
+        );
+        std::fs::write(&path, &file).unwrap();
+        let fingerprint = fingerprint_files(&[path.clone()], None).unwrap();
+
+        // Changes to the tensor metadata or the sampled data change the fingerprint.
+        let mut changed = file.clone();
+        changed[data_start - 4] = b'3';
+        std::fs::write(&path, &changed).unwrap();
+        assert_ne!(
+            fingerprint,
+            fingerprint_files(&[path.clone()], None).unwrap()
+        );
+        let mut changed = file.clone();
+        changed[data_start] = 1;
+        std::fs::write(&path, &changed).unwrap();
+        assert_ne!(
+            fingerprint,
+            fingerprint_files(&[path.clone()], None).unwrap()
+        );
+        let mut changed = file;
+        *changed.last_mut().unwrap() = 1;
+        std::fs::write(&path, &changed).unwrap();
+        assert_ne!(fingerprint, fingerprint_files(&[path], None).unwrap());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
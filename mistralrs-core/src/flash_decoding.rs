@@ -0,0 +1,405 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+//! Flash Decoding: attention of a single query token over a long KV cache. The KV cache is split
+//! into partitions which are processed in parallel, and the partial results are combined using
+//! their softmax max and denominator.
+
+use candle_core::{CpuStorage, CustomOp3, Layout, Result, Shape, Tensor, WithDType};
+
+use crate::attention::SdpaParams;
+#[cfg(feature = "flash-decoding")]
+use crate::cuda::ffi;
+
+/// Number of keys processed by one CUDA block.
+const PARTITION_SIZE: usize = 512;
+
+/// Below this KV cache length there are too few partitions for Flash Decoding to pay off.
+pub(crate) const FLASH_DECODING_MIN_KV_LEN: usize = 2048;
+
+struct FlashDecoding {
+    softmax_scale: f32,
+    /// 0 disables softcapping.
+    softcap: f32,
+    partition_size: usize,
+}
+
+/// Dimensions `(b_sz, n_attn_heads, n_kv_heads, kv_len, head_dim)`.
+fn dims(q_l: &Layout, k_l: &Layout, v_l: &Layout) -> Result<(usize, usize, usize, usize, usize)> {
+    let (b_sz, n_attn_heads, q_len, head_dim) = q_l.shape().dims4()?;
+    let (k_b_sz, n_kv_heads, kv_len, k_head_dim) = k_l.shape().dims4()?;
+    if q_len != 1 {
+        candle_core::bail!("Flash Decoding expects a single query token, got {q_len}.");
+    }
+    if k_l.shape() != v_l.shape() || k_b_sz != b_sz || k_head_dim != head_dim {
+        candle_core::bail!(
+            "Flash Decoding shape mismatch: q {:?}, k {:?}, v {:?}.",
+            q_l.shape(),
+            k_l.shape(),
+            v_l.shape()
+        );
+    }
+    if n_kv_heads == 0 || n_attn_heads % n_kv_heads != 0 || kv_len == 0 {
+        candle_core::bail!(
+            "Flash Decoding cannot attend {n_attn_heads} heads over {n_kv_heads} KV heads of length {kv_len}."
+        );
+    }
+    Ok((b_sz, n_attn_heads, n_kv_heads, kv_len, head_dim))
+}
+
+impl FlashDecoding {
+    fn softcap(&self, logit: f32) -> f32 {
+        if self.softcap > 0. {
+            self.softcap * (logit / self.softcap).tanh()
+        } else {
+            logit
+        }
+    }
+
+    /// Same algorithm as the CUDA kernel, partition by partition.
+    fn cpu_fwd_t<T: WithDType>(
+        &self,
+        q: &[T],
+        q_l: &Layout,
+        k: &[T],
+        k_l: &Layout,
+        v: &[T],
+        v_l: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        let (b_sz, n_attn_heads, n_kv_heads, kv_len, head_dim) = dims(q_l, k_l, v_l)?;
+        let at = |l: &Layout, idx: [usize; 4]| {
+            l.start_offset()
+                + idx
+                    .iter()
+                    .zip(l.stride())
+                    .map(|(i, stride)| i * stride)
+                    .sum::<usize>()
+        };
+
+        let mut out = Vec::with_capacity(b_sz * n_attn_heads * head_dim);
+        for seq in 0..b_sz {
+            for head in 0..n_attn_heads {
+                let kv_head = head / (n_attn_heads / n_kv_heads);
+                let q_row = (0..head_dim)
+                    .map(|i| q[at(q_l, [seq, head, 0, i])].to_f64() as f32)
+                    .collect::<Vec<_>>();
+
+                // (max, denominator, unnormalized output) of each partition
+                let mut partials = Vec::new();
+                for start in (0..kv_len).step_by(self.partition_size) {
+                    let keys = start..(start + self.partition_size).min(kv_len);
+                    let logits = keys
+                        .clone()
+                        .map(|j| {
+                            let dot = (0..head_dim)
+                                .map(|i| {
+                                    q_row[i] * k[at(k_l, [seq, kv_head, j, i])].to_f64() as f32
+                                })
+                                .sum::<f32>();
+                            self.softcap(dot * self.softmax_scale)
+                        })
+                        .collect::<Vec<_>>();
+                    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                    let probs = logits.iter().map(|x| (x - max).exp()).collect::<Vec<_>>();
+                    let partial_out = (0..head_dim)
+                        .map(|i| {
+                            probs
+                                .iter()
+                                .zip(keys.clone())
+                                .map(|(p, j)| p * v[at(v_l, [seq, kv_head, j, i])].to_f64() as f32)
+                                .sum::<f32>()
+                        })
+                        .collect::<Vec<_>>();
+                    partials.push((max, probs.iter().sum::<f32>(), partial_out));
+                }
+
+                let max = partials
+                    .iter()
+                    .map(|(max, _, _)| *max)
+                    .fold(f32::NEG_INFINITY, f32::max);
+                let denom = partials
+                    .iter()
+                    .map(|(m, sum, _)| sum * (m - max).exp())
+                    .sum::<f32>();
+                for i in 0..head_dim {
+                    let acc = partials
+                        .iter()
+                        .map(|(m, _, partial_out)| partial_out[i] * (m - max).exp())
+                        .sum::<f32>();
+                    out.push(T::from_f64((acc / denom).into()));
+                }
+            }
+        }
+
+        Ok((
+            T::to_cpu_storage_owned(out),
+            Shape::from((b_sz, n_attn_heads, 1, head_dim)),
+        ))
+    }
+}
+
+impl CustomOp3 for FlashDecoding {
+    fn name(&self) -> &'static str {
+        "flash-decoding"
+    }
+
+    fn cpu_fwd(
+        &self,
+        q: &CpuStorage,
+        q_l: &Layout,
+        k: &CpuStorage,
+        k_l: &Layout,
+        v: &CpuStorage,
+        v_l: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        match (q, k, v) {
+            (CpuStorage::F32(q), CpuStorage::F32(k), CpuStorage::F32(v)) => {
+                self.cpu_fwd_t(q, q_l, k, k_l, v, v_l)
+            }
+            (CpuStorage::F16(q), CpuStorage::F16(k), CpuStorage::F16(v)) => {
+                self.cpu_fwd_t(q, q_l, k, k_l, v, v_l)
+            }
+            (CpuStorage::BF16(q), CpuStorage::BF16(k), CpuStorage::BF16(v)) => {
+                self.cpu_fwd_t(q, q_l, k, k_l, v, v_l)
+            }
+            _ => {
+                candle_core::bail!("Flash Decoding expects q, k and v to all be F32, F16 or BF16.")
+            }
+        }
+    }
+
+    #[cfg(feature = "flash-decoding")]
+    fn cuda_fwd(
+        &self,
+        q: &candle_core::CudaStorage,
+        q_l: &Layout,
+        k: &candle_core::CudaStorage,
+        k_l: &Layout,
+        v: &candle_core::CudaStorage,
+        v_l: &Layout,
+    ) -> Result<(candle_core::CudaStorage, Shape)> {
+        use candle_core::backend::BackendStorage;
+        use candle_core::cuda_backend::cudarc::driver::DevicePtr;
+        use candle_core::cuda_backend::{CudaStorageSlice, WrapErr};
+        use half::{bf16, f16};
+        use std::ffi::c_void;
+
+        let (b_sz, n_attn_heads, n_kv_heads, kv_len, head_dim) = dims(q_l, k_l, v_l)?;
+        if !q_l.is_contiguous() {
+            candle_core::bail!("Flash Decoding expects q to be contiguous.");
+        }
+        for l in [k_l, v_l] {
+            if l.stride()[3] != 1 || l.stride()[2] != head_dim {
+                candle_core::bail!("Flash Decoding expects the rows of k and v to be contiguous.");
+            }
+        }
+
+        let dev = q.device();
+        let num_partitions = kv_len.div_ceil(self.partition_size);
+        let num_rows = b_sz * n_attn_heads;
+        let tmp_out = unsafe { dev.alloc::<f32>(num_rows * num_partitions * head_dim) }.w()?;
+        let tmp_max = unsafe { dev.alloc::<f32>(num_rows * num_partitions) }.w()?;
+        let tmp_sum = unsafe { dev.alloc::<f32>(num_rows * num_partitions) }.w()?;
+        let stream = *dev.cu_stream() as i64;
+
+        macro_rules! launch {
+            ($ty:ty, $variant:ident, $kernel:ident) => {{
+                let (
+                    CudaStorageSlice::$variant(q),
+                    CudaStorageSlice::$variant(k),
+                    CudaStorageSlice::$variant(v),
+                ) = (&q.slice, &k.slice, &v.slice)
+                else {
+                    candle_core::bail!("Flash Decoding expects q, k and v to have the same dtype.");
+                };
+                let q = q.slice(q_l.start_offset()..);
+                let k = k.slice(k_l.start_offset()..);
+                let v = v.slice(v_l.start_offset()..);
+                let out = unsafe { dev.alloc::<$ty>(num_rows * head_dim) }.w()?;
+                unsafe {
+                    ffi::$kernel(
+                        *q.device_ptr() as *const c_void,
+                        *k.device_ptr() as *const c_void,
+                        *v.device_ptr() as *const c_void,
+                        *out.device_ptr() as *mut c_void,
+                        *tmp_out.device_ptr() as *mut f32,
+                        *tmp_max.device_ptr() as *mut f32,
+                        *tmp_sum.device_ptr() as *mut f32,
+                        b_sz as i32,
+                        n_attn_heads as i32,
+                        n_kv_heads as i32,
+                        head_dim as i32,
+                        kv_len as i32,
+                        self.partition_size as i32,
+                        k_l.stride()[0] as i64,
+                        k_l.stride()[1] as i64,
+                        v_l.stride()[0] as i64,
+                        v_l.stride()[1] as i64,
+                        self.softmax_scale,
+                        self.softcap,
+                        stream,
+                    )
+                };
+                CudaStorageSlice::$variant(out)
+            }};
+        }
+
+        let slice = match q.dtype() {
+            candle_core::DType::F32 => launch!(f32, F32, flash_decoding_f32),
+            candle_core::DType::F16 => launch!(f16, F16, flash_decoding_f16),
+            candle_core::DType::BF16 => launch!(bf16, BF16, flash_decoding_bf16),
+            other => candle_core::bail!("Flash Decoding does not support {other:?}."),
+        };
+        Ok((
+            candle_core::CudaStorage {
+                slice,
+                device: dev.clone(),
+            },
+            Shape::from((b_sz, n_attn_heads, 1, head_dim)),
+        ))
+    }
+}
+
+/// Attention of a single query token over the KV cache.
+///
+/// Inputs:
+/// - q: (b_sz, n_attn_heads, 1, head_dim)
+/// - k: (b_sz, n_kv_heads, kv_len, head_dim)
+/// - v: (b_sz, n_kv_heads, kv_len, head_dim)
+///
+/// Grouped query attention is handled by the kernel, so `k` and `v` must not be repeated.
+pub(crate) fn flash_decoding(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    sdpa_params: &SdpaParams,
+) -> Result<Tensor> {
+    // Only the rows need to be contiguous, so a narrowed view of the KV cache is not copied.
+    let rows_contiguous = |x: &Tensor| {
+        let head_dim = x.dim(3)?;
+        if x.stride()[2..] == [head_dim, 1] {
+            Ok(x.clone())
+        } else {
+            x.contiguous()
+        }
+    };
+    q.contiguous()?.apply_op3_no_bwd(
+        &rows_contiguous(k)?,
+        &rows_contiguous(v)?,
+        &FlashDecoding {
+            softmax_scale: sdpa_params.softmax_scale,
+            softcap: sdpa_params.softcap.unwrap_or(0.),
+            partition_size: PARTITION_SIZE,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Result, Tensor};
+
+    use super::flash_decoding;
+    use crate::attention::SdpaParams;
+
+    fn sdpa_params(n_kv_groups: usize, softcap: Option<f32>) -> SdpaParams {
+        SdpaParams {
+            n_kv_groups,
+            use_flash_attn: false,
+            softcap,
+            softmax_scale: 0.25,
+            sliding_window: None,
+        }
+    }
+
+    /// softmax(QK^T * scale)V with the KV heads repeated for each group.
+    fn standard_attention(
+        q: &Tensor,
+        k: &Tensor,
+        v: &Tensor,
+        params: &SdpaParams,
+    ) -> Result<Tensor> {
+        let (b_sz, n_kv_heads, kv_len, head_dim) = k.dims4()?;
+        let repeat = |x: &Tensor| {
+            x.unsqueeze(2)?
+                .expand((b_sz, n_kv_heads, params.n_kv_groups, kv_len, head_dim))?
+                .reshape((b_sz, n_kv_heads * params.n_kv_groups, kv_len, head_dim))
+        };
+        let mut att = (q.matmul(&repeat(k)?.t()?)? * params.softmax_scale as f64)?;
+        if let Some(softcap) = params.softcap {
+            att = ((att / softcap as f64)?.tanh()? * softcap as f64)?;
+        }
+        candle_nn::ops::softmax_last_dim(&att)?.matmul(&repeat(v)?.contiguous()?)
+    }
+
+    #[test]
+    fn matches_standard_attention() -> Result<()> {
+        let dev = Device::Cpu;
+        // Three partitions, the last one partial, and two query heads per KV head.
+        let kv_len = 1100;
+        let q = Tensor::randn(0f32, 1., (2, 4, 1, 16), &dev)?;
+        // A view into a larger KV cache, as during decoding.
+        let cache = Tensor::randn(0f32, 1., (2, 2, 2, 1536, 16), &dev)?;
+        let k = cache.get_on_dim(2, 0)?.narrow(2, 0, kv_len)?;
+        let v = cache.get_on_dim(2, 1)?.narrow(2, 0, kv_len)?;
+        assert!(!k.is_contiguous());
+
+        for softcap in [None, Some(5.)] {
+            let params = sdpa_params(2, softcap);
+            let expected = standard_attention(&q, &k, &v, &params)?;
+            let actual = flash_decoding(&q, &k, &v, &params)?;
+            assert_eq!(actual.dims(), [2, 4, 1, 16]);
+            let diff = (actual - expected)?.abs()?.max_all()?.to_scalar::<f32>()?;
+            assert!(diff < 1e-4, "max abs diff {diff}");
+        }
+
+        let params = sdpa_params(2, None);
+        let expected = standard_attention(&q, &k, &v, &params)?;
+        let actual = flash_decoding(
+            &q.to_dtype(DType::BF16)?,
+            &k.to_dtype(DType::BF16)?,
+            &v.to_dtype(DType::BF16)?,
+            &params,
+        )?;
+        let diff = (actual.to_dtype(DType::F32)? - expected)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert!(diff < 5e-2, "max abs diff {diff}");
+        Ok(())
+    }
+
+    /// Compare against the standard attention at long contexts, with the shapes of Llama 3 8B.
+    /// Run with `cargo test -p mistralrs-core --features flash-decoding --release flash_decoding -- --ignored --nocapture`.
+    #[cfg(feature = "flash-decoding")]
+    #[test]
+    #[ignore = "benchmark, needs a CUDA device"]
+    fn bench_long_context() -> Result<()> {
+        use std::time::Instant;
+
+        const ITERS: u32 = 100;
+        let dev = Device::new_cuda(0)?;
+        let params = sdpa_params(4, None);
+        for kv_len in [16384, 32768, 65536] {
+            let q = Tensor::randn(0f32, 1., (1, 32, 1, 128), &dev)?.to_dtype(DType::BF16)?;
+            let k = Tensor::randn(0f32, 1., (1, 8, kv_len, 128), &dev)?.to_dtype(DType::BF16)?;
+            let v = Tensor::randn(0f32, 1., (1, 8, kv_len, 128), &dev)?.to_dtype(DType::BF16)?;
+
+            let time = |f: &dyn Fn() -> Result<Tensor>| -> Result<f64> {
+                f()?;
+                dev.synchronize()?;
+                let start = Instant::now();
+                for _ in 0..ITERS {
+                    f()?;
+                }
+                dev.synchronize()?;
+                Ok(start.elapsed().as_secs_f64() * 1000. / f64::from(ITERS))
+            };
+            let flash = time(&|| flash_decoding(&q, &k, &v, &params))?;
+            let standard = time(&|| standard_attention(&q, &k, &v, &params))?;
+            println!(
+                "kv_len {kv_len}: flash decoding {flash:.3} ms, standard attention {standard:.3} ms ({:.2}x)",
+                standard / flash
+            );
+        }
+        Ok(())
+    }
+}
@@ -1,14 +1,18 @@
-use std::{collections::HashMap, fs};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::Path,
+};
 
-use anyhow::Context;
 use candle_core::{
     quantized::{
-        gguf_file::{self, TensorInfo, Value},
-        QTensor,
+        gguf_file::{self, Value},
+        GgmlDType, QTensor,
     },
-    Device, Result,
+    DType, Device, Result, Tensor,
 };
 use indexmap::IndexMap;
+use serde::Serialize;
 use tracing::info;
 
 use crate::DEBUG;
@@ -37,6 +41,211 @@ fn parse_gguf_value(value: &Value) -> String {
     }
 }
 
+/// Number of tensors of each GGML quantization type.
+pub type QuantTypeCounts = BTreeMap<String, usize>;
+
+/// Breakdown of the quantization types used by the tensors of a GGUF model. Some GGUF files mix
+/// quantization types across layers, for example Q6K for the attention of some layers and Q4K elsewhere.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct GgufQuantBreakdown {
+    /// Quantization types used by the tensors of each repeating layer (`blk.{i}.*`).
+    pub layers: BTreeMap<usize, QuantTypeCounts>,
+    /// Quantization types used by the tensors outside of the repeating layers.
+    pub other: QuantTypeCounts,
+}
+
+impl GgufQuantBreakdown {
+    fn add(&mut self, name: &str, dtype: GgmlDType) {
+        let layer = name
+            .strip_prefix("blk.")
+            .and_then(|rest| rest.split('.').next())
+            .and_then(|i| i.parse::<usize>().ok());
+        let counts = match layer {
+            Some(i) => self.layers.entry(i).or_default(),
+            None => &mut self.other,
+        };
+        *counts.entry(format!("{dtype:?}")).or_default() += 1;
+    }
+
+    /// Number of tensors of each quantization type over the whole model.
+    pub fn totals(&self) -> QuantTypeCounts {
+        let mut totals = self.other.clone();
+        for counts in self.layers.values() {
+            for (dtype, n) in counts {
+                *totals.entry(dtype.clone()).or_default() += n;
+            }
+        }
+        totals
+    }
+
+    /// The quantization type of most tensors, ignoring the F32 norms and biases.
+    pub fn dominant_type(&self) -> Option<String> {
+        let totals = self.totals();
+        totals
+            .iter()
+            .filter(|(dtype, _)| *dtype != "F32")
+            .max_by_key(|(_, n)| **n)
+            .or_else(|| totals.iter().next())
+            .map(|(dtype, _)| dtype.clone())
+    }
+
+    /// Whether the repeating layers do not all use the same quantization types.
+    pub fn is_mixed(&self) -> bool {
+        let mut layers = self.layers.values();
+        match layers.next() {
+            Some(first) => layers.any(|counts| counts != first),
+            None => false,
+        }
+    }
+
+    fn format_counts(counts: &QuantTypeCounts) -> String {
+        counts
+            .iter()
+            .map(|(dtype, n)| format!("{dtype} x{n}"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Log the breakdown, with consecutive layers using the same types collapsed into a range.
+    pub fn log(&self) {
+        info!(
+            "Quantization types: {}",
+            Self::format_counts(&self.totals())
+        );
+        if !self.is_mixed() {
+            return;
+        }
+        let mut layers = self.layers.iter().peekable();
+        while let Some((start, counts)) = layers.next() {
+            let mut end = *start;
+            while let Some((i, _)) = layers.next_if(|(i, c)| **i == end + 1 && *c == counts) {
+                end = *i;
+            }
+            if end == *start {
+                info!("Layer {start}: {}", Self::format_counts(counts));
+            } else {
+                info!("Layers {start}-{end}: {}", Self::format_counts(counts));
+            }
+        }
+    }
+}
+
+/// A model which the model of a GGUF file was finetuned or merged from.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct GgufBaseModel {
+    pub name: Option<String>,
+    pub organization: Option<String>,
+    pub version: Option<String>,
+    pub repo_url: Option<String>,
+}
+
+/// Provenance of the model of a GGUF file, from the `general.*` metadata, for example to
+/// attribute the model or check its license. Fields missing from the file are `None`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Provenance {
+    pub name: Option<String>,
+    pub author: Option<String>,
+    pub organization: Option<String>,
+    pub version: Option<String>,
+    /// What the base model was finetuned for, for example `Instruct`.
+    pub finetune: Option<String>,
+    pub license: Option<String>,
+    pub license_name: Option<String>,
+    pub license_link: Option<String>,
+    pub url: Option<String>,
+    pub repo_url: Option<String>,
+    /// Where the weights which were converted to GGUF come from.
+    pub source_url: Option<String>,
+    pub source_repo_url: Option<String>,
+    pub base_models: Vec<GgufBaseModel>,
+    pub quantized_by: Option<String>,
+    /// The `general.file_type` of llama.cpp, the quantization type of most tensors.
+    pub file_type: Option<u32>,
+    pub quantization_version: Option<u32>,
+}
+
+impl Provenance {
+    fn from_metadata(metadata: &HashMap<String, Value>) -> Self {
+        let string = |key: &str| {
+            metadata
+                .get(&format!("general.{key}"))
+                .and_then(|v| v.to_string().ok())
+                .cloned()
+        };
+        let int = |key: &str| {
+            metadata
+                .get(&format!("general.{key}"))
+                .and_then(|v| v.to_u32().ok())
+        };
+        let base_models = (0..int("base_model.count").unwrap_or(0))
+            .map(|i| GgufBaseModel {
+                name: string(&format!("base_model.{i}.name")),
+                organization: string(&format!("base_model.{i}.organization")),
+                version: string(&format!("base_model.{i}.version")),
+                repo_url: string(&format!("base_model.{i}.repo_url")),
+            })
+            .collect();
+        Self {
+            name: string("name"),
+            author: string("author"),
+            organization: string("organization"),
+            version: string("version"),
+            finetune: string("finetune"),
+            license: string("license"),
+            license_name: string("license.name"),
+            license_link: string("license.link"),
+            url: string("url"),
+            repo_url: string("repo_url"),
+            source_url: string("source.url"),
+            source_repo_url: string("source.repo_url"),
+            base_models,
+            quantized_by: string("quantized_by"),
+            file_type: int("file_type"),
+            quantization_version: int("quantization_version"),
+        }
+    }
+
+    /// Log the license, the finetune and the origin of the model, if the file has them.
+    pub fn log(&self) {
+        if let Some(license) = &self.license {
+            match &self.license_link {
+                Some(link) => info!("Model license: {license} ({link})"),
+                None => info!("Model license: {license}"),
+            }
+        }
+        if let Some(finetune) = &self.finetune {
+            info!("Model finetune: {finetune}");
+        }
+        for base in &self.base_models {
+            if let Some(name) = base.name.as_ref().or(base.repo_url.as_ref()) {
+                info!("Base model: {name}");
+            }
+        }
+        if let Some(source) = self.source_repo_url.as_ref().or(self.source_url.as_ref()) {
+            info!("Model source: {source}");
+        }
+        if let Some(quantized_by) = &self.quantized_by {
+            info!("Quantized by: {quantized_by}");
+        }
+    }
+}
+
+fn is_granite(metadata: &HashMap<String, Value>) -> bool {
+    let ibm = metadata
+        .get("general.organization")
+        .and_then(|v| v.to_string().ok())
+        .is_some_and(|org| org.to_ascii_lowercase().contains("ibm"));
+    ibm || metadata.contains_key("llama.attention.use_projection_bias")
+        || metadata.contains_key("granite.attention.use_projection_bias")
+}
+
+fn is_jais(metadata: &HashMap<String, Value>) -> bool {
+    metadata
+        .get("general.name")
+        .and_then(|v| v.to_string().ok())
+        .is_some_and(|name| name.to_ascii_lowercase().contains("jais"))
+}
+
 // Internal invariant: contents and readers must be paired.
 /// This abstracts the files for a GGUF model and enables multiple files to be used.
 pub struct Content<'a, R: std::io::Seek + std::io::Read> {
@@ -44,11 +253,23 @@ pub struct Content<'a, R: std::io::Seek + std::io::Read> {
     readers: &'a mut [&'a mut R],
     arch: GGUFArchitecture,
     all_metadata: HashMap<String, Value>,
+    /// F16 tensors which replace the tensors of the same name in the GGUF files.
+    companion: HashMap<String, Tensor>,
 }
 
 impl<'a, R: std::io::Seek + std::io::Read> Content<'a, R> {
     /// Create a `Content` from a set of file readers.
     pub fn from_readers(readers: &'a mut [&'a mut R]) -> Result<Self> {
+        Self::from_readers_with_arch(readers, None)
+    }
+
+    /// Create a `Content` from a set of file readers, loading it as `force_arch` instead of the
+    /// declared architecture if set. The tensor names are checked, so that files which do not
+    /// match the forced architecture fail here instead of during loading.
+    pub fn from_readers_with_arch(
+        readers: &'a mut [&'a mut R],
+        force_arch: Option<GGUFArchitecture>,
+    ) -> Result<Self> {
         let mut contents = Vec::new();
         let n_readers = readers.len();
         for reader in readers.iter_mut() {
@@ -80,51 +301,144 @@ impl<'a, R: std::io::Seek + std::io::Read> Content<'a, R> {
             info!("GGUF file has been split into {} shards", n_splits[0]);
         }
 
-        let mut arch = None;
+        let mut declared_arch = None;
         for ct in &contents {
-            if !ct.metadata.contains_key("general.architecture") {
-                continue;
+            if let Some(arch) = ct.metadata.get("general.architecture") {
+                declared_arch = Some(arch.to_string()?.clone());
             }
-
-            arch = Some(
-                ct.metadata["general.architecture"]
-                    .to_string()
-                    .context("Model metadata should have declared an architecture")
-                    .and_then(GGUFArchitecture::from_value)
-                    .unwrap(),
-            );
         }
-        let arch = arch.expect("GGUF files must specify `general.architecture`");
+        let declared_arch = declared_arch.ok_or_else(|| {
+            candle_core::Error::Msg("GGUF files must specify `general.architecture`".to_string())
+        })?;
 
         let mut all_metadata = HashMap::new();
         for content in &contents {
             all_metadata.extend(content.metadata.clone())
         }
 
+        let arch = match force_arch {
+            Some(arch) => {
+                let Some(required) = arch.required_tensors() else {
+                    candle_core::bail!(
+                        "Cannot force the `{arch}` architecture, supported architectures are {}.",
+                        GGUFArchitecture::supported_list()
+                    );
+                };
+                let missing = required
+                    .iter()
+                    .filter(|name| {
+                        !contents
+                            .iter()
+                            .any(|ct| ct.tensor_infos.contains_key(**name))
+                    })
+                    .map(|name| format!("`{name}`"))
+                    .collect::<Vec<_>>();
+                if !missing.is_empty() {
+                    candle_core::bail!(
+                        "Cannot load the GGUF file declaring `{declared_arch}` as `{arch}`, it is missing the tensors {}.",
+                        missing.join(", ")
+                    );
+                }
+                info!("Loading the GGUF file declaring `{declared_arch}` as `{arch}`.");
+                arch
+            }
+            None => match GGUFArchitecture::from_value(&declared_arch) {
+                // Granite GGUFs may declare the `llama` architecture.
+                Ok(GGUFArchitecture::Llama) if is_granite(&all_metadata) => {
+                    info!("Detected an IBM Granite model declared as `llama`.");
+                    GGUFArchitecture::Granite
+                }
+                // Jais GGUFs may declare the GPT-2 architecture they derive from, or a custom
+                // name. The Llama-based Jais models are loaded as Llama.
+                Ok(GGUFArchitecture::Gpt2) | Err(_) if is_jais(&all_metadata) => {
+                    info!("Detected a Jais model declared as `{declared_arch}`.");
+                    GGUFArchitecture::Jais
+                }
+                arch => arch.map_err(candle_core::Error::msg)?,
+            },
+        };
+
+        // Expose the metadata of an alias or wrongly declared architecture under the prefix of the
+        // architecture it is loaded as.
+        if declared_arch != arch.to_string() {
+            let metadata = all_metadata
+                .iter()
+                .filter_map(|(k, v)| {
+                    k.strip_prefix(&format!("{declared_arch}."))
+                        .map(|rest| (format!("{arch}.{rest}"), v.clone()))
+                })
+                .collect::<Vec<_>>();
+            all_metadata.extend(metadata);
+            all_metadata.insert(
+                "general.architecture".to_string(),
+                Value::String(arch.to_string()),
+            );
+        }
+
         Ok(Self {
             contents,
             readers,
             arch,
             all_metadata,
+            companion: HashMap::new(),
         })
     }
 
+    /// Load an accuracy companion: a safetensors file with higher precision copies of some
+    /// tensors, keyed by their GGUF names. These tensors are loaded as F16 instead of the
+    /// quantized tensors of the GGUF files, which recovers accuracy for the layers most sensitive
+    /// to quantization at a small memory cost.
+    pub fn with_accuracy_companion(mut self, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let tensors = candle_core::safetensors::load(path, &Device::Cpu)?;
+        for (name, tensor) in tensors {
+            let Some(info) = self.tensor_info_in_files(&name) else {
+                candle_core::bail!(
+                    "The accuracy companion `{}` contains `{name}`, which is not a tensor of the GGUF model.",
+                    path.display()
+                );
+            };
+            if info.shape.dims() != tensor.dims() {
+                candle_core::bail!(
+                    "The accuracy companion tensor `{name}` has shape {:?}, but the GGUF tensor has shape {:?}.",
+                    tensor.dims(),
+                    info.shape.dims()
+                );
+            }
+            self.companion.insert(name, tensor.to_dtype(DType::F16)?);
+        }
+        info!(
+            "Loaded {} F16 tensors from the accuracy companion `{}`.",
+            self.companion.len(),
+            path.display()
+        );
+        Ok(self)
+    }
+
     pub fn arch(&self) -> GGUFArchitecture {
         self.arch
     }
 
+    fn tensor_info_in_files(&self, name: &str) -> Option<&gguf_file::TensorInfo> {
+        self.contents
+            .iter()
+            .find_map(|ct| ct.tensor_infos.get(name))
+    }
+
     /// Retrieve a tensor info, searching through each content.
-    pub fn tensor_info(&self, name: &str) -> Result<&TensorInfo> {
-        for ct in &self.contents {
-            if let Some(tensor_info) = ct.tensor_infos.get(name) {
-                return Ok(tensor_info);
-            }
+    /// This describes the tensor in the GGUF files, even if it is replaced by the accuracy companion.
+    pub fn tensor_info(&self, name: &str) -> Result<&gguf_file::TensorInfo> {
+        match self.tensor_info_in_files(name) {
+            Some(tensor_info) => Ok(tensor_info),
+            None => candle_core::bail!("Cannot find tensor info for {name}"),
         }
-        candle_core::bail!("Cannot find tensor info for {name}")
     }
 
-    /// Retrieve a tensor, searching through each content.
+    /// Retrieve a tensor, preferring the accuracy companion and then searching through each content.
     pub fn tensor(&mut self, name: &str, device: &Device) -> Result<QTensor> {
+        if let Some(tensor) = self.companion.get(name) {
+            return QTensor::quantize(&tensor.to_device(device)?, GgmlDType::F16);
+        }
         for (ct, reader) in self.contents.iter().zip(self.readers.iter_mut()) {
             if let Some(tensor_info) = ct.tensor_infos.get(name) {
                 return tensor_info.read(reader, ct.tensor_data_offset, device);
@@ -143,6 +457,27 @@ impl<'a, R: std::io::Seek + std::io::Read> Content<'a, R> {
         false
     }
 
+    /// Compute the per-layer breakdown of tensor quantization types over all contents, counting the
+    /// tensors replaced by the accuracy companion as F16.
+    pub fn quant_breakdown(&self) -> GgufQuantBreakdown {
+        let mut breakdown = GgufQuantBreakdown::default();
+        for ct in &self.contents {
+            for (name, info) in &ct.tensor_infos {
+                if self.companion.contains_key(name) {
+                    breakdown.add(name, GgmlDType::F16);
+                } else {
+                    breakdown.add(name, info.ggml_dtype);
+                }
+            }
+        }
+        breakdown
+    }
+
+    /// The provenance of the model, from the `general.*` metadata of all contents.
+    pub fn provenance(&self) -> Provenance {
+        Provenance::from_metadata(&self.all_metadata)
+    }
+
     /// Print metadata for these contents.
     /// This will also log tensor name, shape and dtype to `mistralrs_gguf_tensors.txt` is DEBUG is enabled.
     pub fn print_metadata(&self) -> anyhow::Result<()> {
@@ -198,3 +533,351 @@ impl<'a, R: std::io::Seek + std::io::Read> Content<'a, R> {
         &self.all_metadata
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, io::Cursor};
+
+    use candle_core::{
+        quantized::{gguf_file, GgmlDType, QTensor},
+        Device, Tensor,
+    };
+
+    use super::{Content, Provenance};
+    use crate::gguf::GGUFArchitecture;
+
+    /// Write a small GGUF file where layer 1 uses different quantization types than layer 0.
+    fn mixed_gguf() -> candle_core::Result<Vec<u8>> {
+        let dev = Device::Cpu;
+        let weight = Tensor::randn(0f32, 1f32, (8, 256), &dev)?;
+        let norm = Tensor::ones(256, candle_core::DType::F32, &dev)?;
+        let tensors = [
+            (
+                "token_embd.weight",
+                QTensor::quantize(&weight, GgmlDType::Q8_0)?,
+            ),
+            (
+                "blk.0.attn_q.weight",
+                QTensor::quantize(&weight, GgmlDType::Q4K)?,
+            ),
+            (
+                "blk.0.ffn_up.weight",
+                QTensor::quantize(&weight, GgmlDType::Q4K)?,
+            ),
+            (
+                "blk.0.attn_norm.weight",
+                QTensor::quantize(&norm, GgmlDType::F32)?,
+            ),
+            (
+                "blk.1.attn_q.weight",
+                QTensor::quantize(&weight, GgmlDType::Q6K)?,
+            ),
+            (
+                "blk.1.ffn_up.weight",
+                QTensor::quantize(&weight, GgmlDType::Q4K)?,
+            ),
+            (
+                "blk.1.attn_norm.weight",
+                QTensor::quantize(&norm, GgmlDType::F32)?,
+            ),
+            ("output.weight", QTensor::quantize(&weight, GgmlDType::Q6K)?),
+        ];
+        let arch = gguf_file::Value::String("llama".to_string());
+        let mut buf = Cursor::new(Vec::new());
+        gguf_file::write(
+            &mut buf,
+            &[("general.architecture", &arch)],
+            &tensors.iter().map(|(n, t)| (*n, t)).collect::<Vec<_>>(),
+        )?;
+        Ok(buf.into_inner())
+    }
+
+    #[test]
+    fn mixed_quant_breakdown() -> candle_core::Result<()> {
+        let mut reader = Cursor::new(mixed_gguf()?);
+        let mut readers = [&mut reader];
+        let content = Content::from_readers(&mut readers)?;
+        let breakdown = content.quant_breakdown();
+
+        assert!(breakdown.is_mixed());
+        assert_eq!(breakdown.layers.len(), 2);
+        assert_eq!(breakdown.layers[&0]["Q4K"], 2);
+        assert_eq!(breakdown.layers[&0]["F32"], 1);
+        assert_eq!(breakdown.layers[&1]["Q6K"], 1);
+        assert_eq!(breakdown.layers[&1]["Q4K"], 1);
+        assert_eq!(breakdown.other["Q8_0"], 1);
+        assert_eq!(breakdown.other["Q6K"], 1);
+        let totals = breakdown.totals();
+        assert_eq!(totals["Q4K"], 3);
+        assert_eq!(totals["Q6K"], 2);
+        assert_eq!(breakdown.dominant_type().as_deref(), Some("Q4K"));
+        Ok(())
+    }
+
+    #[test]
+    fn provenance() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let weight =
+            QTensor::quantize(&Tensor::randn(0f32, 1f32, (8, 256), &dev)?, GgmlDType::Q8_0)?;
+        let string = |s: &str| gguf_file::Value::String(s.to_string());
+        let write = |metadata: &[(&str, gguf_file::Value)]| -> candle_core::Result<Vec<u8>> {
+            let mut buf = Cursor::new(Vec::new());
+            gguf_file::write(
+                &mut buf,
+                &metadata.iter().map(|(k, v)| (*k, v)).collect::<Vec<_>>(),
+                &[("token_embd.weight", &weight)],
+            )?;
+            Ok(buf.into_inner())
+        };
+
+        let file = write(&[
+            ("general.architecture", string("llama")),
+            ("general.name", string("Tiny Llama Chat")),
+            ("general.finetune", string("Chat")),
+            ("general.license", string("apache-2.0")),
+            (
+                "general.source.url",
+                string("https://example.com/tiny-llama"),
+            ),
+            ("general.base_model.count", gguf_file::Value::U32(1)),
+            ("general.base_model.0.name", string("Tiny Llama")),
+            ("general.file_type", gguf_file::Value::U32(7)),
+        ])?;
+        let mut reader = Cursor::new(file);
+        let mut readers = [&mut reader];
+        let provenance = Content::from_readers(&mut readers)?.provenance();
+        assert_eq!(provenance.name.as_deref(), Some("Tiny Llama Chat"));
+        assert_eq!(provenance.finetune.as_deref(), Some("Chat"));
+        assert_eq!(provenance.license.as_deref(), Some("apache-2.0"));
+        assert_eq!(
+            provenance.source_url.as_deref(),
+            Some("https://example.com/tiny-llama")
+        );
+        assert_eq!(provenance.base_models.len(), 1);
+        assert_eq!(
+            provenance.base_models[0].name.as_deref(),
+            Some("Tiny Llama")
+        );
+        assert_eq!(provenance.file_type, Some(7));
+        assert_eq!(provenance.author, None);
+
+        let file = write(&[("general.architecture", string("llama"))])?;
+        let mut reader = Cursor::new(file);
+        let mut readers = [&mut reader];
+        let provenance = Content::from_readers(&mut readers)?.provenance();
+        assert_eq!(provenance, Provenance::default());
+        Ok(())
+    }
+
+    #[test]
+    fn mixed_quant_tensors_load() -> candle_core::Result<()> {
+        let mut reader = Cursor::new(mixed_gguf()?);
+        let mut readers = [&mut reader];
+        let mut content = Content::from_readers(&mut readers)?;
+
+        for (name, dtype) in [
+            ("blk.0.attn_q.weight", GgmlDType::Q4K),
+            ("blk.1.attn_q.weight", GgmlDType::Q6K),
+            ("token_embd.weight", GgmlDType::Q8_0),
+        ] {
+            let t = content.tensor(name, &Device::Cpu)?;
+            assert_eq!(t.dtype(), dtype);
+            assert_eq!(t.shape().dims(), &[8, 256]);
+        }
+
+        // Dequantizing each quantization type should give the same weights, up to quantization error.
+        let q4 = content
+            .tensor("blk.0.attn_q.weight", &Device::Cpu)?
+            .dequantize(&Device::Cpu)?;
+        let q6 = content
+            .tensor("blk.1.attn_q.weight", &Device::Cpu)?
+            .dequantize(&Device::Cpu)?;
+        let diff = (q4 - q6)?.abs()?.mean_all()?.to_scalar::<f32>()?;
+        assert!(diff < 0.25, "{diff}");
+        Ok(())
+    }
+
+    #[test]
+    fn accuracy_companion() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let weight = Tensor::randn(0f32, 1f32, (8, 256), &dev)?;
+        let q4 = QTensor::quantize(&weight, GgmlDType::Q4K)?;
+        let arch = gguf_file::Value::String("llama".to_string());
+        let mut buf = Cursor::new(Vec::new());
+        gguf_file::write(
+            &mut buf,
+            &[("general.architecture", &arch)],
+            &[("blk.0.attn_q.weight", &q4), ("blk.0.ffn_up.weight", &q4)],
+        )?;
+        let file = buf.into_inner();
+
+        let dir = std::env::temp_dir().join(format!(
+            "mistralrs_accuracy_companion_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir)?;
+        let companion = dir.join("companion.safetensors");
+        candle_core::safetensors::save(
+            &HashMap::from([("blk.0.attn_q.weight".to_string(), weight.clone())]),
+            &companion,
+        )?;
+
+        let mut reader = Cursor::new(file.clone());
+        let mut readers = [&mut reader];
+        let mut content =
+            Content::from_readers(&mut readers)?.with_accuracy_companion(&companion)?;
+        let breakdown = content.quant_breakdown();
+        assert_eq!(breakdown.layers[&0]["F16"], 1);
+        assert_eq!(breakdown.layers[&0]["Q4K"], 1);
+
+        // The companion tensor is preferred, the others still come from the GGUF file.
+        let attn_q = content.tensor("blk.0.attn_q.weight", &dev)?;
+        assert_eq!(attn_q.dtype(), GgmlDType::F16);
+        let ffn_up = content.tensor("blk.0.ffn_up.weight", &dev)?;
+        assert_eq!(ffn_up.dtype(), GgmlDType::Q4K);
+
+        let error = |t: &QTensor| -> candle_core::Result<f32> {
+            (t.dequantize(&dev)? - &weight)?
+                .abs()?
+                .mean_all()?
+                .to_scalar::<f32>()
+        };
+        assert!(error(&attn_q)? < 1e-3);
+        assert!(error(&attn_q)? < error(&ffn_up)?);
+
+        // Companion tensors must match a GGUF tensor by name and shape.
+        let bad = dir.join("bad.safetensors");
+        candle_core::safetensors::save(
+            &HashMap::from([("blk.0.attn_k.weight".to_string(), weight.clone())]),
+            &bad,
+        )?;
+        let mut reader = Cursor::new(file.clone());
+        let mut readers = [&mut reader];
+        let err = Content::from_readers(&mut readers)?
+            .with_accuracy_companion(&bad)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("`blk.0.attn_k.weight`"), "{err}");
+
+        candle_core::safetensors::save(
+            &HashMap::from([("blk.0.attn_q.weight".to_string(), weight.t()?.contiguous()?)]),
+            &bad,
+        )?;
+        let mut reader = Cursor::new(file);
+        let mut readers = [&mut reader];
+        assert!(Content::from_readers(&mut readers)?
+            .with_accuracy_companion(&bad)
+            .is_err());
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    /// Write a GGUF file declaring `arch` with one layer of small F32 tensors.
+    fn gguf_with_arch(arch: &str, tensor_names: &[&str]) -> candle_core::Result<Vec<u8>> {
+        let weight = Tensor::zeros((2, 2), candle_core::DType::F32, &Device::Cpu)?;
+        let tensors = tensor_names
+            .iter()
+            .map(|name| Ok((*name, QTensor::quantize(&weight, GgmlDType::F32)?)))
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let arch_value = gguf_file::Value::String(arch.to_string());
+        let block_count = gguf_file::Value::U32(1);
+        let block_count_key = format!("{arch}.block_count");
+        let mut buf = Cursor::new(Vec::new());
+        gguf_file::write(
+            &mut buf,
+            &[
+                ("general.architecture", &arch_value),
+                (&block_count_key, &block_count),
+            ],
+            &tensors.iter().map(|(n, t)| (*n, t)).collect::<Vec<_>>(),
+        )?;
+        Ok(buf.into_inner())
+    }
+
+    #[test]
+    fn force_architecture() -> candle_core::Result<()> {
+        let llama_tensors = GGUFArchitecture::Llama.required_tensors().unwrap();
+
+        // Aliases are loaded with the metadata of the architecture they map to.
+        let mut reader = Cursor::new(gguf_with_arch("mistral", llama_tensors)?);
+        let mut readers = [&mut reader];
+        let content = Content::from_readers(&mut readers)?;
+        assert_eq!(content.arch(), GGUFArchitecture::Llama);
+        assert_eq!(content.get_metadata()["llama.block_count"].to_u32()?, 1);
+
+        let file = gguf_with_arch("llama-v2-community", llama_tensors)?;
+        let mut reader = Cursor::new(file.clone());
+        let mut readers = [&mut reader];
+        let err = Content::from_readers(&mut readers)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("`llama-v2-community`"), "{err}");
+        assert!(err.contains("--force-arch"), "{err}");
+
+        let mut reader = Cursor::new(file.clone());
+        let mut readers = [&mut reader];
+        let content = Content::from_readers_with_arch(&mut readers, Some(GGUFArchitecture::Llama))?;
+        assert_eq!(content.arch(), GGUFArchitecture::Llama);
+        assert_eq!(content.get_metadata()["llama.block_count"].to_u32()?, 1);
+        assert_eq!(
+            content.get_metadata()["general.architecture"].to_string()?,
+            "llama"
+        );
+
+        // Files whose tensors do not match the forced architecture are rejected before loading.
+        let mut reader = Cursor::new(file);
+        let mut readers = [&mut reader];
+        let err = Content::from_readers_with_arch(&mut readers, Some(GGUFArchitecture::Phi3))
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("`blk.0.attn_qkv.weight`"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn detect_jais() -> candle_core::Result<()> {
+        let gguf = |arch: &str, name: &str| -> candle_core::Result<Vec<u8>> {
+            let arch_value = gguf_file::Value::String(arch.to_string());
+            let name = gguf_file::Value::String(name.to_string());
+            let block_count = gguf_file::Value::U32(1);
+            let block_count_key = format!("{arch}.block_count");
+            let mut buf = Cursor::new(Vec::new());
+            gguf_file::write(
+                &mut buf,
+                &[
+                    ("general.architecture", &arch_value),
+                    ("general.name", &name),
+                    (&block_count_key, &block_count),
+                ],
+                &[],
+            )?;
+            Ok(buf.into_inner())
+        };
+        let arch_of = |file: Vec<u8>| -> candle_core::Result<GGUFArchitecture> {
+            let mut reader = Cursor::new(file);
+            let mut readers = [&mut reader];
+            Ok(Content::from_readers(&mut readers)?.arch())
+        };
+
+        for declared in ["jais", "gpt2", "jais-13b"] {
+            let mut reader = Cursor::new(gguf(declared, "Jais-13b-chat")?);
+            let mut readers = [&mut reader];
+            let content = Content::from_readers(&mut readers)?;
+            assert_eq!(content.arch(), GGUFArchitecture::Jais, "{declared}");
+            assert_eq!(content.get_metadata()["jais.block_count"].to_u32()?, 1);
+        }
+        // The Llama-based Jais models are loaded as Llama, and other GPT-2 models are untouched.
+        assert_eq!(
+            arch_of(gguf("llama", "jais-adapted-7b")?)?,
+            GGUFArchitecture::Llama
+        );
+        assert_eq!(
+            arch_of(gguf("gpt2", "gpt2-medium")?)?,
+            GGUFArchitecture::Gpt2
+        );
+        Ok(())
+    }
+}
@@ -1,8 +1,8 @@
 // https://github.com/huggingface/transformers/blob/8685b3c5d2dd2550527773d2a02499495a759e31/src/transformers/convert_slow_tokenizer.py
 
-use std::{collections::HashMap, sync::atomic::Ordering};
+use std::{collections::HashMap, path::Path, sync::atomic::Ordering};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use itertools::Itertools;
 use tokenizers::{
     decoders::{
@@ -17,9 +17,9 @@ use tokenizers::{
     },
     AddedToken, DecoderWrapper, ModelWrapper, NormalizerWrapper, Tokenizer,
 };
-use tracing::info;
+use tracing::{info, warn};
 
-use crate::utils::gguf_metadata::ContentMetadata;
+use crate::utils::{gguf_metadata::ContentMetadata, tokenizer::get_tokenizer};
 use crate::DEBUG;
 
 use super::Content;
@@ -111,6 +111,188 @@ pub fn convert_gguf_to_hf_tokenizer<R: std::io::Seek + std::io::Read>(
     })
 }
 
+/// Load the tokenizer of a GGUF model from the first source which works, in order: the
+/// `tokenizer.json` given with the model, the tokenizer embedded in the GGUF file and a
+/// SentencePiece `sentencepiece_filename`. This fails only if all of them fail.
+///
+/// `tokenizer_filename` is empty if no `tokenizer.json` was given.
+pub(crate) fn get_gguf_tokenizer<R: std::io::Seek + std::io::Read>(
+    tokenizer_filename: &Path,
+    content: &Content<'_, R>,
+    sentencepiece_filename: Option<&Path>,
+) -> Result<GgufTokenizerConversion> {
+    let mut errors = Vec::new();
+    if !tokenizer_filename.as_os_str().is_empty() {
+        match get_tokenizer(tokenizer_filename, None) {
+            Ok(tokenizer) => {
+                return Ok(GgufTokenizerConversion {
+                    tokenizer,
+                    bos: None,
+                    eos: None,
+                    unk: None,
+                })
+            }
+            Err(e) => {
+                warn!(
+                    "Could not load the tokenizer at `{}`, falling back to the GGUF tokenizer: {e}",
+                    tokenizer_filename.display()
+                );
+                errors.push(format!("`{}`: {e}", tokenizer_filename.display()));
+            }
+        }
+    }
+
+    match convert_gguf_to_hf_tokenizer(content) {
+        Ok(conversion) => return Ok(conversion),
+        Err(e) => {
+            warn!("Could not convert the GGUF tokenizer: {e}");
+            errors.push(format!("GGUF tokenizer: {e}"));
+        }
+    }
+
+    if let Some(path) = sentencepiece_filename.filter(|path| path.exists()) {
+        info!(
+            "Loading the SentencePiece tokenizer at `{}`",
+            path.display()
+        );
+        let conversion = std::fs::read(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|model| convert_sentencepiece_to_hf_tokenizer(&model));
+        match conversion {
+            Ok(conversion) => return Ok(conversion),
+            Err(e) => errors.push(format!("`{}`: {e}", path.display())),
+        }
+    }
+
+    anyhow::bail!(
+        "Could not load a tokenizer from any source: {}",
+        errors.join("; ")
+    )
+}
+
+/// A field of a protobuf message.
+enum ProtoValue<'a> {
+    Varint(u64),
+    Fixed64,
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+fn proto_take<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if buf.len() < n {
+        anyhow::bail!("Truncated protobuf message");
+    }
+    let (head, rest) = buf.split_at(n);
+    *buf = rest;
+    Ok(head)
+}
+
+fn proto_varint(buf: &mut &[u8]) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = proto_take(buf, 1)?[0];
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    anyhow::bail!("Invalid protobuf varint")
+}
+
+/// The fields of a protobuf message with their numbers, in order.
+fn proto_fields(mut buf: &[u8]) -> Result<Vec<(u64, ProtoValue<'_>)>> {
+    let mut fields = Vec::new();
+    while !buf.is_empty() {
+        let key = proto_varint(&mut buf)?;
+        let value = match key & 7 {
+            0 => ProtoValue::Varint(proto_varint(&mut buf)?),
+            1 => {
+                proto_take(&mut buf, 8)?;
+                ProtoValue::Fixed64
+            }
+            2 => {
+                let len = proto_varint(&mut buf)?;
+                ProtoValue::Bytes(proto_take(&mut buf, usize::try_from(len)?)?)
+            }
+            5 => {
+                let bytes = proto_take(&mut buf, 4)?;
+                ProtoValue::Fixed32(u32::from_le_bytes(bytes.try_into()?))
+            }
+            wire_type => anyhow::bail!("Unsupported protobuf wire type {wire_type}"),
+        };
+        fields.push((key >> 3, value));
+    }
+    Ok(fields)
+}
+
+/// Convert a SentencePiece `tokenizer.model` to a unigram tokenizer, like the `llama` tokenizers
+/// of GGUF files. Only the pieces, their scores and the special token ids are used.
+pub(crate) fn convert_sentencepiece_to_hf_tokenizer(
+    model: &[u8],
+) -> Result<GgufTokenizerConversion> {
+    let mut tokens = Vec::new();
+    let mut scores = Vec::new();
+    // The defaults of the `TrainerSpec` of SentencePiece.
+    let (mut unk, mut bos, mut eos) = (0, 1, 2);
+    for (field, value) in proto_fields(model)? {
+        match (field, value) {
+            // `ModelProto.pieces`
+            (1, ProtoValue::Bytes(piece)) => {
+                let mut token = None;
+                let mut score = 0.;
+                for (field, value) in proto_fields(piece)? {
+                    match (field, value) {
+                        (1, ProtoValue::Bytes(text)) => {
+                            token = Some(String::from_utf8(text.to_vec())?)
+                        }
+                        (2, ProtoValue::Fixed32(bits)) => score = f32::from_bits(bits),
+                        _ => (),
+                    }
+                }
+                tokens.push(token.context("SentencePiece piece without text")?);
+                scores.push(score);
+            }
+            // `ModelProto.trainer_spec`, a negative id disables the token.
+            (2, ProtoValue::Bytes(trainer_spec)) => {
+                for (field, value) in proto_fields(trainer_spec)? {
+                    match (field, value) {
+                        (40, ProtoValue::Varint(id)) => unk = id as i32,
+                        (41, ProtoValue::Varint(id)) => bos = id as i32,
+                        (42, ProtoValue::Varint(id)) => eos = id as i32,
+                        _ => (),
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    let id = |name: &str, id: i32| match u32::try_from(id) {
+        Ok(id) if (id as usize) < tokens.len() => Ok(id),
+        _ => Err(anyhow::anyhow!(
+            "SentencePiece {name} token id {id} is not in the {} pieces",
+            tokens.len()
+        )),
+    };
+    let props = PropsGGUF {
+        model: "llama".to_string(),
+        unk: Some(id("unknown", unk)?),
+        eos: id("end of sequence", eos)?,
+        bos: id("beginning of sequence", bos)?,
+        tokens,
+        added_tokens: None,
+        scores: Some(scores),
+        merges: None,
+        add_bos_token: None,
+    };
+    let (tokenizer, _, AddedTokensCollection { bos, eos, unk }) = unigram_tokenizer(&props)?;
+    Ok(GgufTokenizerConversion {
+        tokenizer,
+        bos: Some(bos),
+        eos: Some(eos),
+        unk,
+    })
+}
+
 // TODO: Add support for additional tokenizer models: WordPiece, WordLevel
 // https://docs.rs/tokenizers/latest/tokenizers/models/enum.ModelWrapper.html
 #[derive(Debug)]
@@ -561,4 +743,104 @@ mod tests {
 
         Ok(())
     }
+
+    const TEST_TOKENS: [&str; 7] = ["<unk>", "<s>", "</s>", "▁hello", "▁world", "▁", "o"];
+
+    /// A GGUF file with the `llama` unigram tokenizer of [`TEST_TOKENS`], or no tokenizer.
+    fn tokenizer_gguf(with_tokenizer: bool) -> Result<Vec<u8>> {
+        use candle_core::quantized::gguf_file::{self, Value};
+
+        let mut metadata = vec![("general.architecture", Value::String("llama".to_string()))];
+        if with_tokenizer {
+            metadata.extend([
+                ("tokenizer.ggml.model", Value::String("llama".to_string())),
+                (
+                    "tokenizer.ggml.tokens",
+                    Value::Array(
+                        TEST_TOKENS
+                            .iter()
+                            .map(|t| Value::String(t.to_string()))
+                            .collect(),
+                    ),
+                ),
+                (
+                    "tokenizer.ggml.scores",
+                    Value::Array(vec![Value::F32(-1.); TEST_TOKENS.len()]),
+                ),
+                ("tokenizer.ggml.unknown_token_id", Value::U32(0)),
+                ("tokenizer.ggml.bos_token_id", Value::U32(1)),
+                ("tokenizer.ggml.eos_token_id", Value::U32(2)),
+            ]);
+        }
+        let mut buf = std::io::Cursor::new(Vec::new());
+        gguf_file::write(
+            &mut buf,
+            &metadata.iter().map(|(k, v)| (*k, v)).collect::<Vec<_>>(),
+            &[],
+        )?;
+        Ok(buf.into_inner())
+    }
+
+    /// A SentencePiece `tokenizer.model` with the pieces of [`TEST_TOKENS`].
+    fn sentencepiece_model() -> Vec<u8> {
+        let bytes_field = |number: u8, bytes: &[u8]| {
+            let mut field = vec![number << 3 | 2, bytes.len() as u8];
+            field.extend_from_slice(bytes);
+            field
+        };
+        let mut model = Vec::new();
+        for token in TEST_TOKENS {
+            // The text and the score of the piece.
+            let mut piece = bytes_field(1, token.as_bytes());
+            piece.push(2 << 3 | 5);
+            piece.extend_from_slice(&(-1f32).to_le_bytes());
+            model.extend(bytes_field(1, &piece));
+        }
+        model
+    }
+
+    fn load(dir: &std::path::Path, gguf: &[u8]) -> Result<super::GgufTokenizerConversion> {
+        let mut reader = std::io::Cursor::new(gguf);
+        let mut readers = [&mut reader];
+        let content = crate::gguf::Content::from_readers(&mut readers)?;
+        super::get_gguf_tokenizer(
+            &dir.join("tokenizer.json"),
+            &content,
+            Some(&dir.join("tokenizer.model")),
+        )
+    }
+
+    #[test]
+    fn corrupt_tokenizer_falls_back() -> Result<()> {
+        let dir = std::env::temp_dir().join("mistralrs_tokenizer_fallback_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(
+            dir.join("tokenizer.json"),
+            "{\"version\": \"1.0\", \"model\":",
+        )?;
+
+        // The tokenizer embedded in the GGUF file is used first.
+        let conversion = load(&dir, &tokenizer_gguf(true)?)?;
+        assert_eq!(conversion.bos.as_deref(), Some("<s>"));
+        let ids = conversion
+            .tokenizer
+            .encode_fast("hello world", false)
+            .map_err(anyhow::Error::msg)?;
+        assert_eq!(ids.get_ids(), [3, 4]);
+
+        // Then the SentencePiece model next to the tokenizer.
+        assert!(load(&dir, &tokenizer_gguf(false)?).is_err());
+        std::fs::write(dir.join("tokenizer.model"), sentencepiece_model())?;
+        let conversion = load(&dir, &tokenizer_gguf(false)?)?;
+        assert_eq!(conversion.eos.as_deref(), Some("</s>"));
+        let ids = conversion
+            .tokenizer
+            .encode_fast("hello world", false)
+            .map_err(anyhow::Error::msg)?;
+        assert_eq!(ids.get_ids(), [3, 4]);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
 }
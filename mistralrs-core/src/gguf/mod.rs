@@ -1,17 +1,20 @@
 mod chat_template;
 mod content;
 mod gguf_tokenizer;
+mod var_builder;
 use strum::EnumString;
 
-use anyhow::{Context, Result};
+use anyhow::Result;
 pub(crate) use chat_template::get_gguf_chat_template;
 pub(crate) use content::Content;
-pub(crate) use gguf_tokenizer::{convert_gguf_to_hf_tokenizer, GgufTokenizerConversion};
+pub use content::{GgufBaseModel, GgufQuantBreakdown, Provenance, QuantTypeCounts};
+pub(crate) use gguf_tokenizer::{get_gguf_tokenizer, GgufTokenizerConversion};
 use std::str::FromStr;
+pub use var_builder::GgufVarBuilder;
 
 pub const GGUF_MULTI_FILE_DELIMITER: &str = " ";
 
-#[derive(Debug, EnumString, Clone, Copy, strum::Display)]
+#[derive(Debug, EnumString, Clone, Copy, PartialEq, Eq, strum::Display)]
 #[strum(serialize_all = "lowercase")]
 pub enum GGUFArchitecture {
     Llama,
@@ -27,15 +30,192 @@ pub enum GGUFArchitecture {
     Phi3,
     Starcoder2,
     Qwen2,
+    Granite,
+    ChatGlm,
+    Gemma,
+    Gemma3,
+    Jais,
 }
 
 // Wraps from_str() for some convenience:
-// - Case-insensitive variant matching (TODO: is this desirable?)
+// - Matching ignores case, hyphens and underscores, and accepts the aliases of other converters
 // - Customized error until potential upstream support: https://github.com/Peternator7/strum/issues/332
 impl GGUFArchitecture {
+    /// Architectures which can be loaded from GGUF files.
+    pub const SUPPORTED: &[Self] = &[
+        Self::Llama,
+        Self::Phi2,
+        Self::Phi3,
+        Self::Starcoder2,
+        Self::Qwen2,
+        Self::Granite,
+        Self::ChatGlm,
+        Self::Gemma,
+        Self::Gemma3,
+        Self::Jais,
+        Self::Falcon,
+        Self::Mpt,
+        Self::Gptneox,
+        Self::Gpt2,
+        Self::Bloom,
+        Self::Mamba,
+        Self::Rwkv,
+    ];
+
+    /// Architecture names written by some converters, after normalization.
+    const ALIASES: &[(&str, Self)] = &[
+        ("falconmamba", Self::Mamba),
+        ("mistral", Self::Llama),
+        ("mixtral", Self::Llama),
+        ("rwkv6", Self::Rwkv),
+    ];
+
     pub fn from_value<T: AsRef<str> + std::fmt::Display>(value: T) -> Result<Self> {
-        Self::from_str(&value.as_ref().to_ascii_lowercase())
-            .with_context(|| format!("Unknown GGUF architecture `{value}`"))
-            .map_err(anyhow::Error::msg)
+        let normalized = value.as_ref().to_ascii_lowercase().replace(['-', '_'], "");
+        if let Some((_, arch)) = Self::ALIASES.iter().find(|(alias, _)| *alias == normalized) {
+            return Ok(*arch);
+        }
+        Self::from_str(&normalized).map_err(|_| {
+            anyhow::anyhow!(
+                "Unknown GGUF architecture `{value}`. Supported architectures are {}. The architecture of files with wrong metadata can be set with `--force-arch`.",
+                Self::supported_list()
+            )
+        })
+    }
+
+    pub(crate) fn supported_list() -> String {
+        Self::SUPPORTED
+            .iter()
+            .map(|arch| format!("`{arch}`"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Tensors which every file of this architecture has, to check files loaded with a forced
+    /// architecture. `None` for architectures which cannot be loaded.
+    pub(crate) fn required_tensors(&self) -> Option<&'static [&'static str]> {
+        let tensors: &[&str] = match self {
+            Self::Llama | Self::Granite | Self::Starcoder2 | Self::Qwen2 | Self::Gemma => &[
+                "token_embd.weight",
+                "output_norm.weight",
+                "blk.0.attn_norm.weight",
+                "blk.0.attn_q.weight",
+                "blk.0.attn_k.weight",
+                "blk.0.attn_v.weight",
+                "blk.0.attn_output.weight",
+                "blk.0.ffn_norm.weight",
+            ],
+            Self::Phi2 | Self::Falcon | Self::Mpt => &[
+                "token_embd.weight",
+                "output_norm.weight",
+                "blk.0.attn_norm.weight",
+                "blk.0.attn_qkv.weight",
+                "blk.0.attn_output.weight",
+                "blk.0.ffn_up.weight",
+                "blk.0.ffn_down.weight",
+            ],
+            Self::Phi3 | Self::Gptneox | Self::Gpt2 | Self::Bloom => &[
+                "token_embd.weight",
+                "output_norm.weight",
+                "blk.0.attn_norm.weight",
+                "blk.0.attn_qkv.weight",
+                "blk.0.attn_output.weight",
+                "blk.0.ffn_norm.weight",
+                "blk.0.ffn_up.weight",
+                "blk.0.ffn_down.weight",
+            ],
+            // The query, key and value projections may or may not be fused.
+            Self::ChatGlm => &[
+                "token_embd.weight",
+                "output_norm.weight",
+                "blk.0.attn_norm.weight",
+                "blk.0.attn_output.weight",
+                "blk.0.ffn_norm.weight",
+                "blk.0.ffn_up.weight",
+                "blk.0.ffn_down.weight",
+            ],
+            Self::Gemma3 => &[
+                "token_embd.weight",
+                "output_norm.weight",
+                "blk.0.attn_norm.weight",
+                "blk.0.attn_q.weight",
+                "blk.0.attn_q_norm.weight",
+                "blk.0.attn_k_norm.weight",
+                "blk.0.post_attention_norm.weight",
+                "blk.0.post_ffw_norm.weight",
+            ],
+            Self::Jais => &[
+                "token_embd.weight",
+                "output_norm.weight",
+                "blk.0.attn_norm.weight",
+                "blk.0.attn_qkv.weight",
+                "blk.0.attn_output.weight",
+                "blk.0.ffn_norm.weight",
+                "blk.0.ffn_gate.weight",
+                "blk.0.ffn_up.weight",
+                "blk.0.ffn_down.weight",
+            ],
+            Self::Mamba => &[
+                "token_embd.weight",
+                "output_norm.weight",
+                "blk.0.attn_norm.weight",
+                "blk.0.ssm_in.weight",
+                "blk.0.ssm_conv1d.weight",
+                "blk.0.ssm_x.weight",
+                "blk.0.ssm_dt.weight",
+                "blk.0.ssm_a",
+                "blk.0.ssm_d",
+                "blk.0.ssm_out.weight",
+            ],
+            Self::Rwkv => &[
+                "token_embd.weight",
+                "token_embd_norm.weight",
+                "output_norm.weight",
+                "blk.0.attn_norm.weight",
+                "blk.0.time_mix_w1.weight",
+                "blk.0.time_mix_w2.weight",
+                "blk.0.time_mix_first.weight",
+                "blk.0.time_mix_key.weight",
+                "blk.0.time_mix_receptance.weight",
+                "blk.0.channel_mix_key.weight",
+            ],
+            Self::Gptj => return None,
+        };
+        Some(tensors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GGUFArchitecture;
+
+    #[test]
+    fn parse_architecture() {
+        let parse = |name: &str| GGUFArchitecture::from_value(name).unwrap().to_string();
+        assert_eq!(parse("llama"), "llama");
+        assert_eq!(parse("StarCoder2"), "starcoder2");
+        assert_eq!(parse("gpt-neox"), "gptneox");
+        assert_eq!(parse("chat_glm"), "chatglm");
+        assert_eq!(parse("falcon-mamba"), "mamba");
+        assert_eq!(parse("Mistral"), "llama");
+        assert_eq!(parse("gemma"), "gemma");
+        assert_eq!(parse("gemma3"), "gemma3");
+
+        let err = GGUFArchitecture::from_value("falcon-h1")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("`falcon-h1`"), "{err}");
+        assert!(err.contains("`llama`, `phi2`"), "{err}");
+    }
+
+    #[test]
+    fn supported_architectures_have_required_tensors() {
+        for arch in GGUFArchitecture::SUPPORTED {
+            let tensors = arch.required_tensors().unwrap();
+            assert!(tensors.contains(&"token_embd.weight"), "{arch}");
+            assert!(tensors.contains(&"blk.0.attn_norm.weight"), "{arch}");
+        }
+        assert!(!GGUFArchitecture::SUPPORTED.contains(&GGUFArchitecture::Gptj));
+        assert!(GGUFArchitecture::Gptj.required_tensors().is_none());
     }
 }
@@ -0,0 +1,159 @@
+use std::{collections::HashMap, sync::Arc};
+
+use candle_core::{
+    quantized::{gguf_file, QTensor},
+    DType, Device, Result, Shape, Tensor,
+};
+use candle_nn::var_builder::SimpleBackend;
+use mistralrs_quant::{ShardedSafeTensors, ShardedVarBuilder};
+
+/// The tensors of a GGUF file, looked up by name like the weights of a safetensors model. This
+/// lets models written against a `VarBuilder` load their weights from GGUF files, with the
+/// tensors dequantized when they are retrieved.
+#[derive(Clone)]
+pub struct GgufVarBuilder {
+    tensors: HashMap<String, Arc<QTensor>>,
+    device: Device,
+}
+
+impl GgufVarBuilder {
+    /// Read all the tensors of `content` from `reader` onto `device`.
+    pub fn from_gguf<R: std::io::Seek + std::io::Read>(
+        content: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        let mut tensors = HashMap::new();
+        for name in content.tensor_infos.keys() {
+            let tensor = content.tensor(reader, name, device)?;
+            tensors.insert(name.clone(), Arc::new(tensor));
+        }
+        Ok(Self {
+            tensors,
+            device: device.clone(),
+        })
+    }
+
+    /// The quantized tensor `name`, without dequantizing it.
+    pub fn get_qtensor(&self, name: &str) -> Result<Arc<QTensor>> {
+        match self.tensors.get(name) {
+            Some(tensor) => Ok(tensor.clone()),
+            None => candle_core::bail!("Cannot find tensor info for {name}"),
+        }
+    }
+
+    /// The tensor `name` dequantized to F32, checking that it has the expected shape.
+    pub fn get<S: Into<Shape>>(&self, shape: S, name: &str) -> Result<Tensor> {
+        let tensor = self.get_qtensor(name)?;
+        let shape = shape.into();
+        if tensor.shape() != &shape {
+            candle_core::bail!(
+                "Tensor `{name}` has shape {:?}, expected {:?}.",
+                tensor.shape().dims(),
+                shape.dims()
+            );
+        }
+        tensor.dequantize(&self.device)
+    }
+
+    pub fn contains_tensor(&self, name: &str) -> bool {
+        self.tensors.contains_key(name)
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// A `VarBuilder` over these tensors, dequantizing them to `dtype`, as used to load the
+    /// safetensors models.
+    pub fn into_var_builder(self, dtype: DType) -> ShardedVarBuilder {
+        let device = self.device.clone();
+        ShardedSafeTensors::wrap(Box::new(self), dtype, device)
+    }
+}
+
+impl SimpleBackend for GgufVarBuilder {
+    fn get(
+        &self,
+        s: Shape,
+        name: &str,
+        _: candle_nn::Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Tensor> {
+        GgufVarBuilder::get(self, s, name)?
+            .to_dtype(dtype)?
+            .to_device(dev)
+    }
+
+    fn get_unchecked(&self, name: &str, dtype: DType, dev: &Device) -> Result<Tensor> {
+        self.get_qtensor(name)?
+            .dequantize(&self.device)?
+            .to_dtype(dtype)?
+            .to_device(dev)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        GgufVarBuilder::contains_tensor(self, name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use candle_core::{
+        quantized::{gguf_file, GgmlDType, QTensor},
+        DType, Device, Tensor,
+    };
+
+    use super::GgufVarBuilder;
+
+    fn var_builder(weight: &Tensor) -> candle_core::Result<GgufVarBuilder> {
+        let q8 = QTensor::quantize(weight, GgmlDType::Q8_0)?;
+        let arch = gguf_file::Value::String("llama".to_string());
+        let mut buf = Cursor::new(Vec::new());
+        gguf_file::write(
+            &mut buf,
+            &[("general.architecture", &arch)],
+            &[("blk.0.attn_q.weight", &q8)],
+        )?;
+        let mut reader = Cursor::new(buf.into_inner());
+        let content = gguf_file::Content::read(&mut reader)?;
+        GgufVarBuilder::from_gguf(content, &mut reader, &Device::Cpu)
+    }
+
+    #[test]
+    fn dequantizes_by_name() -> candle_core::Result<()> {
+        let weight = Tensor::randn(0f32, 1f32, (8, 256), &Device::Cpu)?;
+        let vb = var_builder(&weight)?;
+
+        let tensor = vb.get((8, 256), "blk.0.attn_q.weight")?;
+        assert_eq!(tensor.dtype(), DType::F32);
+        let diff = (tensor - &weight)?.abs()?.max_all()?.to_scalar::<f32>()?;
+        assert!(diff < 0.05, "{diff}");
+
+        assert!(vb.get((256, 8), "blk.0.attn_q.weight").is_err());
+        assert!(vb.get((8, 256), "blk.0.attn_k.weight").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn loads_through_var_builder() -> candle_core::Result<()> {
+        let weight = Tensor::randn(0f32, 1f32, (8, 256), &Device::Cpu)?;
+        let gguf = var_builder(&weight)?;
+        let expected = gguf.get((8, 256), "blk.0.attn_q.weight")?;
+
+        let vb = gguf.into_var_builder(DType::F16);
+        let vb = vb.pp("blk.0");
+        assert!(vb.contains_tensor("attn_q.weight"));
+        let tensor = vb.get((8, 256), "attn_q.weight")?;
+        assert_eq!(tensor.dtype(), DType::F16);
+        let diff = (tensor.to_dtype(DType::F32)? - expected)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert!(diff < 0.01, "{diff}");
+        Ok(())
+    }
+}
@@ -3,7 +3,7 @@
 use std::{f32::consts::PI, ops::Mul, str::FromStr, sync::Arc};
 
 use candle_core::{
-    quantized::{QMatMul, QTensor},
+    quantized::{ggml_file::qtensor_from_ggml, QMatMul, QTensor},
     Context, DType, Device, IndexOp, Result, Tensor, D,
 };
 use candle_nn::{
@@ -229,6 +229,42 @@ impl QRmsNorm {
     pub fn forward(&self, x: &Tensor) -> Result<Tensor> {
         candle_nn::ops::rms_norm(&x.contiguous()?, &self.weight, self.eps as f32)
     }
+
+    pub fn weight(&self) -> &Tensor {
+        &self.weight
+    }
+
+    pub fn set_weight(&mut self, weight: Tensor) {
+        self.weight = weight;
+    }
+}
+
+/// Split a quantized tensor into `parts` tensors along its first dimension, without dequantizing
+/// it: the raw data is stored row after row, each row being made of whole blocks. The first
+/// dimension of a stack of matrices, such as the experts of a MoE layer, is removed when each part
+/// is a single matrix.
+pub fn split_qtensor(weight: &QTensor, parts: usize, device: &Device) -> Result<Vec<QTensor>> {
+    let dims = weight.shape().dims();
+    if dims.len() < 2 || dims[0] % parts != 0 {
+        candle_core::bail!("Cannot split a weight of shape {dims:?} in {parts} parts.");
+    }
+    let mut part_dims = dims.to_vec();
+    part_dims[0] /= parts;
+    if part_dims.len() > 2 && part_dims[0] == 1 {
+        part_dims.remove(0);
+    }
+    let data = weight.data()?;
+    let part_len = data.len() / parts;
+    (0..parts)
+        .map(|i| {
+            qtensor_from_ggml(
+                weight.dtype(),
+                &data[i * part_len..(i + 1) * part_len],
+                part_dims.clone(),
+                device,
+            )
+        })
+        .collect()
 }
 
 /// RoPE supporting LongRope
@@ -1533,10 +1569,31 @@ impl RotaryEmbedding {
         device: &Device,
         is_gpt_neox: bool,
         dtype: DType,
+    ) -> Result<Self> {
+        Self::new_linear_scaled(
+            base,
+            head_dim,
+            max_position_embeddings,
+            1.,
+            device,
+            is_gpt_neox,
+            dtype,
+        )
+    }
+
+    /// RoPE with linear scaling, where the positions are divided by `factor`.
+    pub fn new_linear_scaled(
+        base: f32,
+        head_dim: usize,
+        max_position_embeddings: usize,
+        factor: f32,
+        device: &Device,
+        is_gpt_neox: bool,
+        dtype: DType,
     ) -> Result<Self> {
         let inv_freq: Vec<_> = (0..head_dim)
             .step_by(2)
-            .map(|i| 1f32 / base.powf(i as f32 / head_dim as f32))
+            .map(|i| 1f32 / base.powf(i as f32 / head_dim as f32) / factor)
             .collect();
         let inv_freq_len = inv_freq.len();
         let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), device)?;
@@ -1827,6 +1884,50 @@ impl TensorInfExtend for Tensor {
     }
 }
 
+/// ALiBi positions, used instead of position embeddings by Jais and MPT: each head penalizes the
+/// keys linearly in their distance to the query.
+#[derive(Debug, Clone)]
+pub struct Alibi {
+    /// Slope of each head, of shape `(1, n_head, 1, 1)`.
+    slopes: Tensor,
+}
+
+impl Alibi {
+    pub fn new(n_head: usize, max_bias: f32, device: &Device) -> Result<Self> {
+        Ok(Self {
+            slopes: Tensor::from_vec(Self::slopes(n_head, max_bias), (1, n_head, 1, 1), device)?,
+        })
+    }
+
+    /// ALiBi slope of each head. For a power of two number of heads `n`, the slopes are the
+    /// geometric sequence `2^(-max_bias / n * i)`. Otherwise the heads past the largest power of
+    /// two `m` below `n` interleave the slopes of `2m` heads, as in BLOOM, MPT and llama.cpp.
+    pub fn slopes(n_head: usize, max_bias: f32) -> Vec<f32> {
+        let n_head_log2 = 1 << n_head.ilog2();
+        let m0 = 2f32.powf(-max_bias / n_head_log2 as f32);
+        let m1 = 2f32.powf(-max_bias / 2. / n_head_log2 as f32);
+        (0..n_head)
+            .map(|h| {
+                if h < n_head_log2 {
+                    m0.powi(h as i32 + 1)
+                } else {
+                    m1.powi(2 * (h - n_head_log2) as i32 + 1)
+                }
+            })
+            .collect()
+    }
+
+    /// Bias of shape `(1, n_head, seq_len, past_kv_len + seq_len)` to add to the attention scores.
+    pub fn bias(&self, seq_len: usize, past_kv_len: usize, dtype: DType) -> Result<Tensor> {
+        let kv_len = past_kv_len + seq_len;
+        let distances = (0..seq_len)
+            .flat_map(|i| (0..kv_len).map(move |j| j as f32 - (past_kv_len + i) as f32))
+            .collect::<Vec<_>>();
+        let distances = Tensor::from_vec(distances, (1, 1, seq_len, kv_len), self.slopes.device())?;
+        distances.broadcast_mul(&self.slopes)?.to_dtype(dtype)
+    }
+}
+
 pub fn clamp_for_f16(xs: &Tensor) -> Result<Tensor> {
     let mut max = match xs.dtype() {
         DType::U8 => u8::MAX as f32 - 1000.,
@@ -2167,3 +2268,68 @@ impl Module for ScaledEmbedding {
         xs.apply(&self.embedding)? * self.scale
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{
+        quantized::{GgmlDType, QTensor},
+        DType, Device, Tensor,
+    };
+
+    use super::{split_qtensor, Alibi};
+
+    #[test]
+    fn alibi() {
+        // A power of two number of heads gives a geometric sequence.
+        let slopes = Alibi::slopes(8, 8.);
+        let expected = (1..=8).map(|h| 2f32.powi(-h)).collect::<Vec<_>>();
+        assert_eq!(slopes, expected);
+
+        // Other head counts interleave the slopes of twice the largest power of two below.
+        let slopes = Alibi::slopes(12, 8.);
+        assert_eq!(&slopes[..8], &expected[..]);
+        let interleaved = [0.5f32, 1.5, 2.5, 3.5].map(|e| 2f32.powf(-e));
+        for (slope, expected) in slopes[8..].iter().zip(interleaved) {
+            assert!((slope - expected).abs() < 1e-6);
+        }
+
+        // The bias grows linearly with the distance to the query, including past tokens.
+        let alibi = Alibi::new(2, 2., &Device::Cpu).unwrap();
+        let bias = alibi.bias(2, 1, DType::F32).unwrap();
+        assert_eq!(bias.dims(), [1, 2, 2, 3]);
+        let bias = bias.squeeze(0).unwrap().to_vec3::<f32>().unwrap();
+        assert_eq!(bias[0], [[-0.5, 0., 0.5], [-1., -0.5, 0.]]);
+        assert_eq!(bias[1][1], [-0.5, -0.25, 0.]);
+    }
+
+    #[test]
+    fn split_quantized() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let w = Tensor::randn(0f32, 1f32, (96, 32), &dev)?;
+        let qw = QTensor::quantize(&w, GgmlDType::Q8_0)?;
+        let fused = qw.dequantize(&dev)?;
+        for (i, part) in split_qtensor(&qw, 3, &dev)?.iter().enumerate() {
+            assert_eq!(part.dtype(), GgmlDType::Q8_0);
+            let part = part.dequantize(&dev)?;
+            let diff = (part - fused.narrow(0, i * 32, 32)?)?
+                .abs()?
+                .max_all()?
+                .to_scalar::<f32>()?;
+            assert_eq!(diff, 0.);
+        }
+        assert!(split_qtensor(&qw, 5, &dev).is_err());
+
+        // A stack of matrices is split into matrices.
+        let w = Tensor::randn(0f32, 1f32, (4, 8, 32), &dev)?;
+        let qw = QTensor::quantize(&w, GgmlDType::Q8_0)?;
+        let parts = split_qtensor(&qw, 4, &dev)?;
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[3].shape().dims(), [8, 32]);
+        let diff = (parts[3].dequantize(&dev)? - qw.dequantize(&dev)?.get(3)?)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert_eq!(diff, 0.);
+        Ok(())
+    }
+}
@@ -1,9 +1,11 @@
 #![deny(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 use candle_core::Device;
-use engine::Engine;
 pub use engine::{
-    BertEmbeddingModel, EngineInstruction, ENGINE_INSTRUCTIONS, TERMINATE_ALL_NEXT_STEP,
+    BertEmbeddingModel, EngineEvent, EngineEventBus, EngineEventSubscriber, EngineEventUsage,
+    EngineInstruction, MemoryPressureLevel, PreprocessingBenchmark, PreprocessingBenchmarkResults,
+    DEFAULT_ENGINE_EVENT_CAPACITY, ENGINE_INSTRUCTIONS, TERMINATE_ALL_NEXT_STEP,
 };
+use engine::{Engine, PreprocessingPool};
 use hf_hub::Cache;
 pub use lora::Ordering;
 pub use pipeline::ModelCategory;
@@ -13,7 +15,7 @@ use pyo3::exceptions::PyValueError;
 use std::io::BufRead;
 use std::io::BufReader;
 use std::sync::OnceLock;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::{
     cell::RefCell,
     error::Error,
@@ -30,9 +32,14 @@ use tokio::sync::mpsc::{channel, Sender};
 use tracing::info;
 use tracing::warn;
 
+mod beam_search;
+mod continue_word;
 mod cuda;
 mod device_map;
+mod early_exit;
 mod engine;
+mod fingerprint;
+mod flash_decoding;
 mod lora;
 mod model_loader;
 mod ops;
@@ -40,6 +47,10 @@ pub use model_loader::{
     get_auto_device_map_params, get_model_dtype, get_tgt_non_granular_index, LoaderBuilder,
 };
 mod search;
+pub use search::{
+    Document, DocumentPacking, InMemoryVectorStore, RequestDocuments, RetrievedDocument,
+    VectorStore,
+};
 
 mod model_selected;
 pub use model_selected::ModelSelected;
@@ -49,10 +60,12 @@ mod amoe;
 #[cfg(not(any(all(feature = "cuda", target_family = "unix"), feature = "metal")))]
 mod dummy_paged_attention;
 mod embedding;
+pub use embedding::bert::BertPipeline;
 mod gguf;
 pub mod layers;
 mod layers_masker;
 mod layers_utils;
+mod logits_verify;
 mod models;
 #[cfg(any(all(feature = "cuda", target_family = "unix"), feature = "metal"))]
 mod paged_attention;
@@ -65,6 +78,7 @@ mod pipeline;
 mod prefix_cacher;
 mod request;
 mod response;
+mod routing;
 mod sampler;
 mod scheduler;
 mod sequence;
@@ -76,34 +90,56 @@ mod vision_models;
 mod xlora_models;
 
 pub use amoe::{AnyMoeConfig, AnyMoeExpertType};
+pub use beam_search::BeamSearchConfig;
 pub use device_map::{
     DeviceLayerMapMetadata, DeviceMapMetadata, DeviceMapSetting, LayerDeviceMapper,
 };
-pub use gguf::{GGUFArchitecture, GGUF_MULTI_FILE_DELIMITER};
+pub use early_exit::{EarlyExitConfig, EarlyExitStats};
+pub use gguf::{
+    GGUFArchitecture, GgufBaseModel, GgufQuantBreakdown, GgufVarBuilder, Provenance,
+    QuantTypeCounts, GGUF_MULTI_FILE_DELIMITER,
+};
+pub use logits_verify::{ReferenceLogits, VerifyReport};
 pub use mistralrs_quant::{IsqType, MULTI_LORA_DELIMITER};
 pub use paged_attention::{MemoryGpuConfig, PagedAttentionConfig};
 pub use pipeline::{
-    chat_template::ChatTemplate, parse_isq_value, AnyMoeLoader, AnyMoePipeline,
-    AutoDeviceMapParams, DiffusionGenerationParams, DiffusionLoader, DiffusionLoaderBuilder,
-    DiffusionLoaderType, DiffusionSpecificConfig, GGMLLoader, GGMLLoaderBuilder,
-    GGMLSpecificConfig, GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig, GemmaLoader,
-    Idefics2Loader, IsqOrganization, LLaVALoader, LLaVANextLoader, LlamaLoader, Loader,
-    LocalModelPaths, MistralLoader, MixtralLoader, ModelKind, ModelPaths, NormalLoader,
+    chat_template::{ChatTemplate, ChatWarning},
+    parse_isq_value, AnyMoeLoader, AnyMoePipeline, AutoDeviceMapParams, BenchmarkConfig,
+    BenchmarkResults, BenchmarkRun, DiffusionGenerationParams, DiffusionLoader,
+    DiffusionLoaderBuilder, DiffusionLoaderType, DiffusionSpecificConfig, GGMLLoader,
+    GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoader, GGUFLoaderBuilder, GGUFPipeline,
+    GGUFSpecificConfig, GemmaLoader, HubAccessError, HubAccessKind, Idefics2Loader,
+    IsqOrganization, LLaVALoader, LLaVANextLoader, LlamaLoader, Loader, LocalModelPaths,
+    MistralLoader, MixtralLoader, ModelBenchmark, ModelKind, ModelPaths, NormalLoader,
     NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, Phi2Loader, Phi3Loader,
-    Phi3VLoader, Qwen2Loader, SpeculativeConfig, SpeculativeLoader, SpeculativePipeline,
-    Starcoder2Loader, TokenSource, VisionLoader, VisionLoaderBuilder, VisionLoaderType,
+    Phi3VLoader, PromptFormat, PromptFormatDetection, Qwen2Loader, SpeculativeConfig,
+    SpeculativeLoader, SpeculativePipeline, Starcoder2Loader, ThroughputEstimate,
+    ThroughputTracker, TokenSource, VisionLoader, VisionLoaderBuilder, VisionLoaderType,
     VisionPromptPrefixer, VisionSpecificConfig,
 };
+#[cfg(feature = "onnx")]
+pub use pipeline::{OnnxLoader, OnnxLoaderBuilder, OnnxPipeline, OnnxSpecificConfig};
+pub use prefix_cacher::{
+    EvictionPolicy, EvictionPolicyConfig, EvictionPolicyMetrics, PrefixCacheEvictionConfig,
+    PrefixCacheMetrics, PrefixCachePersistence,
+};
 pub use request::{
-    ApproximateUserLocation, Constraint, DetokenizationRequest, ImageGenerationResponseFormat,
-    LlguidanceGrammar, MessageContent, NormalRequest, Request, RequestMessage, TokenizationRequest,
+    ApproximateUserLocation, Constraint, ContextCheckRequest, DetokenizationRequest,
+    DynamicGrammar, GrammarProvider, GrammarUpdate, ImageGenerationResponseFormat,
+    LlguidanceGrammar, MessageContent, NormalRequest, PrefixCacheOp, PrefixCacheRequest, Request,
+    RequestMessage, SchedulerLimitsRequest, SchedulerMetricsRequest, TokenizationRequest,
     WebSearchOptions, WebSearchUserLocation,
 };
 pub use response::*;
+pub use routing::{Overflow, RouteTags, RoutingDecision, RoutingPolicy, RoutingRule};
 pub use sampler::{
-    CustomLogitsProcessor, DrySamplingParams, SamplingParams, StopTokens, TopLogprob,
+    AdaptiveTemperature, CustomLogitsProcessor, DrySamplingParams, HybridConfig, SamplerBackend,
+    SamplingDefaults, SamplingParams, StopTokens, TopLogprob,
+};
+pub use scheduler::{
+    AdapterWeights, DefaultSchedulerMethod, LoraAdapterCache, LoraAdapterCacheStats,
+    SchedulerConfig, SchedulerLimits, SchedulerMetrics,
 };
-pub use scheduler::{DefaultSchedulerMethod, SchedulerConfig};
 use serde::Serialize;
 use tokio::runtime::Runtime;
 use toml_selector::{TomlLoaderArgs, TomlSelector};
@@ -111,13 +147,14 @@ pub use tools::{
     CalledFunction, Function, Tool, ToolCallResponse, ToolCallType, ToolChoice, ToolType,
 };
 pub use topology::{LayerTopology, Topology};
-pub use utils::debug::initialize_logging;
+pub use utils::debug::{initialize_logging, DebugTensors};
 pub use utils::memory_usage::MemoryUsage;
 pub use utils::normal::{ModelDType, TryIntoDType};
 pub use utils::{paged_attn_supported, using_flash_attn};
 
 // re-export llguidance for easier LlguidanceGrammar construction
 pub use llguidance;
+pub use pipeline::llg::build_tok_env;
 
 /// `true` if `MISTRALRS_DEBUG=1`
 pub(crate) static DEBUG: AtomicBool = AtomicBool::new(false);
@@ -145,6 +182,15 @@ pub struct MistralRs {
     engine_id: usize,
     category: ModelCategory,
     config: MistralRsConfig,
+    lora_adapter_cache: Option<Arc<Mutex<LoraAdapterCache>>>,
+    events: EngineEventBus,
+    throughput: Arc<ThroughputTracker>,
+    speculative_stats: Option<Arc<Mutex<SpeculativeStats>>>,
+    early_exit_stats: Option<Arc<Mutex<EarlyExitStats>>>,
+    prompt_format: Option<PromptFormatDetection>,
+    system_fingerprint: String,
+    commit_hash: Option<String>,
+    prefix_cache_persistence: Option<PrefixCachePersistence>,
 }
 
 #[derive(Clone)]
@@ -155,9 +201,14 @@ struct RebootState {
     no_kv_cache: bool,
     no_prefix_cache: bool,
     prefix_cache_n: usize,
+    prefix_cache_persistence: Option<PrefixCachePersistence>,
+    prefix_cache_eviction: PrefixCacheEvictionConfig,
+    preprocessing_threads: usize,
+    sampling_defaults: SamplingDefaults,
     disable_eos_stop: bool,
     throughput_logging_enabled: bool,
     search_embedding_model: Option<BertEmbeddingModel>,
+    events: EngineEventBus,
 }
 
 #[derive(Debug)]
@@ -192,9 +243,15 @@ pub struct MistralRsBuilder {
     no_kv_cache: Option<bool>,
     no_prefix_cache: Option<bool>,
     prefix_cache_n: Option<usize>,
+    prefix_cache_persistence: Option<PrefixCachePersistence>,
+    prefix_cache_eviction: Option<PrefixCacheEvictionConfig>,
+    preprocessing_threads: Option<usize>,
+    sampling_defaults: Option<SamplingDefaults>,
     disable_eos_stop: Option<bool>,
     throughput_logging_enabled: bool,
     search_embedding_model: Option<BertEmbeddingModel>,
+    lora_adapter_cache_size: Option<usize>,
+    events: Option<EngineEventBus>,
 }
 
 impl MistralRsBuilder {
@@ -212,9 +269,15 @@ impl MistralRsBuilder {
             no_kv_cache: None,
             no_prefix_cache: None,
             prefix_cache_n: None,
+            prefix_cache_persistence: None,
+            prefix_cache_eviction: None,
+            preprocessing_threads: None,
+            sampling_defaults: None,
             disable_eos_stop: None,
             throughput_logging_enabled: throughput_logging,
             search_embedding_model,
+            lora_adapter_cache_size: None,
+            events: None,
         }
     }
     pub fn with_log(mut self, log: String) -> Self {
@@ -241,10 +304,45 @@ impl MistralRsBuilder {
         self.prefix_cache_n = Some(prefix_cache_n);
         self
     }
+    /// Persist the prefix cache on shutdown and load it in the background on startup.
+    pub fn with_prefix_cache_persistence(mut self, persistence: PrefixCachePersistence) -> Self {
+        self.prefix_cache_persistence = Some(persistence);
+        self
+    }
+    /// How the prefix cache evicts its entries. Defaults to LRU without a memory limit.
+    pub fn with_prefix_cache_eviction(mut self, eviction: PrefixCacheEvictionConfig) -> Self {
+        self.prefix_cache_eviction = Some(eviction);
+        self
+    }
+    /// Number of threads rendering and tokenizing chat requests. Defaults to the available
+    /// parallelism, up to 4.
+    pub fn with_preprocessing_threads(mut self, threads: usize) -> Self {
+        self.preprocessing_threads = Some(threads);
+        self
+    }
+    /// Sampling parameters for requests which do not set them. These take precedence over the
+    /// defaults in the `generation_config.json` of the model.
+    pub fn with_sampling_defaults(mut self, defaults: SamplingDefaults) -> Self {
+        self.sampling_defaults = Some(defaults);
+        self
+    }
     pub fn with_disable_eos_stop(mut self, disable_eos_stop: bool) -> Self {
         self.disable_eos_stop = Some(disable_eos_stop);
         self
     }
+    /// Keep up to `max_adapters` LoRA adapters loaded in the LRU cache of [`LoraAdapterCache::global`], which
+    /// pipelines load their preloaded adapters through, and report its hit rate. To also cache the adapters
+    /// of the pipeline being built, resize the cache before loading it.
+    pub fn with_lora_adapter_cache(mut self, max_adapters: usize) -> Self {
+        self.lora_adapter_cache_size = Some(max_adapters);
+        self
+    }
+    /// Use an existing event bus, so that subscribers created before building also receive the
+    /// initial [`EngineEvent::ModelLoaded`].
+    pub fn with_engine_events(mut self, events: EngineEventBus) -> Self {
+        self.events = Some(events);
+        self
+    }
 
     pub fn build(self) -> Arc<MistralRs> {
         MistralRs::new(self)
@@ -270,12 +368,45 @@ impl MistralRs {
             no_kv_cache,
             no_prefix_cache,
             prefix_cache_n,
+            prefix_cache_persistence,
+            prefix_cache_eviction,
+            preprocessing_threads,
+            sampling_defaults,
             disable_eos_stop,
             throughput_logging_enabled,
             search_embedding_model,
+            lora_adapter_cache_size,
+            events,
         } = config;
+        let events = events.unwrap_or_default();
 
         let category = pipeline.try_lock().unwrap().category();
+        let throughput = pipeline
+            .try_lock()
+            .unwrap()
+            .get_metadata()
+            .throughput
+            .clone();
+        let speculative_stats = pipeline.try_lock().unwrap().speculative_stats();
+        let early_exit_stats = pipeline.try_lock().unwrap().early_exit_stats();
+        let prompt_format = pipeline
+            .try_lock()
+            .unwrap()
+            .get_metadata()
+            .prompt_format
+            .clone();
+        let system_fingerprint = pipeline
+            .try_lock()
+            .unwrap()
+            .get_metadata()
+            .system_fingerprint
+            .clone();
+        let commit_hash = pipeline
+            .try_lock()
+            .unwrap()
+            .get_metadata()
+            .commit_hash
+            .clone();
         mistralrs_quant::cublaslt::maybe_init_cublas_lt_wrapper(
             get_mut_arcmutex!(pipeline).device(),
         );
@@ -284,6 +415,10 @@ impl MistralRs {
         let no_kv_cache = no_kv_cache.unwrap_or(false);
         let no_prefix_cache = no_prefix_cache.unwrap_or(false);
         let prefix_cache_n = prefix_cache_n.unwrap_or(16);
+        let prefix_cache_eviction = prefix_cache_eviction.unwrap_or_default();
+        let preprocessing_threads =
+            preprocessing_threads.unwrap_or_else(PreprocessingPool::default_threads);
+        let sampling_defaults = sampling_defaults.unwrap_or_default();
         let disable_eos_stop = disable_eos_stop.unwrap_or(false);
 
         let reboot_state = RebootState {
@@ -293,9 +428,14 @@ impl MistralRs {
             no_kv_cache,
             no_prefix_cache,
             prefix_cache_n,
+            prefix_cache_persistence: prefix_cache_persistence.clone(),
+            prefix_cache_eviction: prefix_cache_eviction.clone(),
+            preprocessing_threads,
+            sampling_defaults: sampling_defaults.clone(),
             disable_eos_stop,
             throughput_logging_enabled,
             search_embedding_model: search_embedding_model.clone(),
+            events: events.clone(),
         };
 
         let (tx, rx) = channel(10_000);
@@ -311,6 +451,8 @@ impl MistralRs {
             category: category.clone(),
         };
 
+        let engine_events = events.clone();
+        let engine_persistence = prefix_cache_persistence.clone();
         let engine_handler = thread::spawn(move || {
             let rt = Runtime::new().unwrap();
             rt.block_on(async move {
@@ -322,9 +464,14 @@ impl MistralRs {
                     no_kv_cache,
                     no_prefix_cache,
                     prefix_cache_n,
+                    engine_persistence,
+                    prefix_cache_eviction,
+                    preprocessing_threads,
+                    sampling_defaults,
                     disable_eos_stop,
                     throughput_logging_enabled,
                     search_embedding_model,
+                    engine_events,
                 )
                 .expect("Engine creation failed.");
                 Arc::new(engine).run().await;
@@ -383,6 +530,45 @@ impl MistralRs {
                                     resp.as_result().unwrap();
                                     continue;
                                 }
+                                Request::SchedulerLimits(mut x) => {
+                                    let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+                                    x.response = sender;
+                                    let req = Request::SchedulerLimits(x);
+
+                                    request_sender.send(req).await.unwrap();
+                                    let resp = receiver.recv().await.unwrap();
+                                    resp.unwrap();
+                                    continue;
+                                }
+                                Request::SchedulerMetrics(mut x) => {
+                                    let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+                                    x.response = sender;
+                                    let req = Request::SchedulerMetrics(x);
+
+                                    request_sender.send(req).await.unwrap();
+                                    receiver.recv().await.unwrap();
+                                    continue;
+                                }
+                                Request::PrefixCache(mut x) => {
+                                    let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+                                    x.response = sender;
+                                    let req = Request::PrefixCache(x);
+
+                                    request_sender.send(req).await.unwrap();
+                                    let resp = receiver.recv().await.unwrap();
+                                    resp.unwrap();
+                                    continue;
+                                }
+                                Request::ContextCheck(mut x) => {
+                                    let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+                                    x.response = sender;
+                                    let req = Request::ContextCheck(x);
+
+                                    request_sender.send(req).await.unwrap();
+                                    let resp = receiver.recv().await.unwrap();
+                                    resp.unwrap();
+                                    continue;
+                                }
                                 Request::TerminateAllSeqsNextStep => {
                                     Request::TerminateAllSeqsNextStep
                                 }
@@ -431,6 +617,12 @@ impl MistralRs {
                     logits_processors: None,
                     return_raw_logits: false,
                     web_search_options: None,
+                    enable_thinking: None,
+                    strict_system_role: false,
+                    dynamic_grammar: None,
+                    return_effective_params: false,
+                    documents: None,
+                    adapters: None,
                 });
                 info!("Beginning dummy run.");
                 let start = Instant::now();
@@ -448,6 +640,25 @@ impl MistralRs {
             });
         }
 
+        info!("Model fingerprint: {system_fingerprint}");
+        if let Some(commit_hash) = &commit_hash {
+            info!("Model snapshot: {commit_hash}");
+        }
+        if let Some(file) = &log {
+            // Not a request, so this line is skipped when replaying the log.
+            let mut f = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(file)
+                .expect("Unable to open file");
+            let time = chrono::offset::Local::now();
+            f.write_all(
+                format!("Model {id} loaded at {time} with fingerprint {system_fingerprint}\n\n")
+                    .as_bytes(),
+            )
+            .expect("Unable to write data");
+        }
+
         Arc::new(Self {
             engine_id,
             sender,
@@ -462,6 +673,23 @@ impl MistralRs {
             engine_handler: RwLock::new(engine_handler),
             category,
             config,
+            lora_adapter_cache: lora_adapter_cache_size.map(|n| {
+                let cache = LoraAdapterCache::global();
+                {
+                    let mut cache = cache.lock().expect("Adapter cache was poisoned");
+                    cache.set_max_adapters(n);
+                    cache.set_events(events.clone());
+                }
+                cache
+            }),
+            events,
+            throughput,
+            speculative_stats,
+            early_exit_stats,
+            prompt_format,
+            system_fingerprint,
+            commit_hash,
+            prefix_cache_persistence,
         })
     }
 
@@ -495,9 +723,14 @@ impl MistralRs {
                         reboot_state.no_kv_cache,
                         reboot_state.no_prefix_cache,
                         reboot_state.prefix_cache_n,
+                        reboot_state.prefix_cache_persistence,
+                        reboot_state.prefix_cache_eviction,
+                        reboot_state.preprocessing_threads,
+                        reboot_state.sampling_defaults,
                         reboot_state.disable_eos_stop,
                         reboot_state.throughput_logging_enabled,
                         reboot_state.search_embedding_model,
+                        reboot_state.events,
                     )
                     .expect("Engine creation failed");
                     Arc::new(engine).run().await;
@@ -543,6 +776,86 @@ impl MistralRs {
         self.category.clone()
     }
 
+    /// The LoRA adapter cache, if enabled with [`MistralRsBuilder::with_lora_adapter_cache`].
+    pub fn lora_adapter_cache(&self) -> Option<Arc<Mutex<LoraAdapterCache>>> {
+        self.lora_adapter_cache.clone()
+    }
+
+    pub fn lora_adapter_cache_stats(&self) -> Option<LoraAdapterCacheStats> {
+        self.lora_adapter_cache
+            .as_ref()
+            .map(|cache| cache.lock().expect("Adapter cache was poisoned").stats())
+    }
+
+    /// Subscribe to engine lifecycle events. Slow subscribers never stall the engine, they miss
+    /// the oldest events instead.
+    pub fn subscribe_events(&self) -> EngineEventSubscriber {
+        self.events.subscribe()
+    }
+
+    pub fn engine_events(&self) -> &EngineEventBus {
+        &self.events
+    }
+
+    /// Moving average of the prompt and decode throughput of recent generations, for example to
+    /// predict completion times.
+    pub fn throughput_estimate(&self) -> ThroughputEstimate {
+        self.throughput.estimate()
+    }
+
+    /// Acceptance of the draft tokens of all requests, if the model uses speculative decoding.
+    pub fn speculative_stats(&self) -> Option<SpeculativeStats> {
+        self.speculative_stats
+            .as_ref()
+            .map(|stats| *stats.lock().expect("Speculative stats were poisoned"))
+    }
+
+    /// Depths at which the decode steps exited, if the model runs with early exit.
+    pub fn early_exit_stats(&self) -> Option<EarlyExitStats> {
+        self.early_exit_stats
+            .as_ref()
+            .map(|stats| *stats.lock().expect("Early exit stats were poisoned"))
+    }
+
+    /// The prompt format detected for the model and whether the chat template matches it. This is
+    /// only available for GGUF models.
+    pub fn prompt_format(&self) -> Option<&PromptFormatDetection> {
+        self.prompt_format.as_ref()
+    }
+
+    /// Fingerprint of the loaded weights, quantization and adapters, which is sent as the
+    /// `system_fingerprint` of responses.
+    pub fn system_fingerprint(&self) -> &str {
+        &self.system_fingerprint
+    }
+
+    /// Commit of the Hugging Face hub snapshot serving the model, `None` if it was loaded from
+    /// local files.
+    pub fn commit_hash(&self) -> Option<&str> {
+        self.commit_hash.as_deref()
+    }
+
+    /// Where the prefix cache is persisted, if enabled.
+    pub fn prefix_cache_persistence(&self) -> Option<&PrefixCachePersistence> {
+        self.prefix_cache_persistence.as_ref()
+    }
+
+    /// Stop the engine after its current step and wait for it to finish. This persists the prefix
+    /// cache if enabled.
+    pub async fn shutdown(&self) -> Result<(), MistralRsError> {
+        if self.engine_dead()? {
+            return Ok(());
+        }
+        if let Ok(sender) = self.sender.read() {
+            // The engine is gone if the channel is closed.
+            let _ = sender.try_send(Request::Terminate);
+        }
+        while !self.engine_dead()? {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        Ok(())
+    }
+
     pub fn next_request_id(&self) -> usize {
         let l = self.next_request_id.lock().unwrap();
         let last = &mut *l.borrow_mut();
@@ -0,0 +1,188 @@
+//! Check the logits of a model against reference logits, for example computed by another
+//! implementation, to make reports of broken models actionable.
+
+use std::{fmt, path::Path};
+
+use candle_core::{DType, Device, Result, Tensor};
+use serde::Serialize;
+
+/// Reference logits of the token after a fixed input, stored in a safetensors file with the
+/// tensors `input_ids` (`u32`, shape `(seq_len,)`) and `logits` (shape `(vocab_size,)`).
+#[derive(Clone, Debug)]
+pub struct ReferenceLogits {
+    pub input_ids: Vec<u32>,
+    pub logits: Tensor,
+}
+
+impl ReferenceLogits {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut tensors = candle_core::safetensors::load(path, &Device::Cpu)?;
+        let mut take = |name: &str| {
+            tensors.remove(name).ok_or_else(|| {
+                candle_core::Error::Msg(format!(
+                    "Reference logits file {} has no `{name}` tensor.",
+                    path.display()
+                ))
+            })
+        };
+        let input_ids = take("input_ids")?.to_dtype(DType::U32)?.to_vec1()?;
+        let logits = take("logits")?;
+        Ok(Self { input_ids, logits })
+    }
+}
+
+/// Result of comparing logits against reference logits.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct VerifyReport {
+    pub tolerance: f32,
+    pub max_abs_diff: f32,
+    /// Index of the logit with the largest difference.
+    pub max_abs_diff_index: usize,
+    /// Index of the first logit differing by more than the tolerance.
+    pub first_divergent_index: Option<usize>,
+    /// Number of logits differing by more than the tolerance.
+    pub num_divergent: usize,
+    pub reference_argmax: usize,
+    pub actual_argmax: usize,
+}
+
+impl VerifyReport {
+    /// Compare logits of the same shape elementwise. NaNs always diverge.
+    pub fn compare(actual: &Tensor, reference: &Tensor, tolerance: f32) -> Result<Self> {
+        if actual.dims() != reference.dims() {
+            candle_core::bail!(
+                "Logits have shape {:?} but the reference logits have shape {:?}.",
+                actual.dims(),
+                reference.dims()
+            );
+        }
+        let actual = actual
+            .flatten_all()?
+            .to_dtype(DType::F32)?
+            .to_vec1::<f32>()?;
+        let reference = reference
+            .flatten_all()?
+            .to_dtype(DType::F32)?
+            .to_vec1::<f32>()?;
+
+        let mut max_abs_diff = 0f32;
+        let mut max_abs_diff_index = 0;
+        let mut first_divergent_index = None;
+        let mut num_divergent = 0;
+        for (i, (a, r)) in actual.iter().zip(&reference).enumerate() {
+            let diff = (a - r).abs();
+            let diverges = diff > tolerance || diff.is_nan();
+            if diverges {
+                num_divergent += 1;
+                first_divergent_index.get_or_insert(i);
+            }
+            if diff > max_abs_diff || (diff.is_nan() && !max_abs_diff.is_nan()) {
+                max_abs_diff = diff;
+                max_abs_diff_index = i;
+            }
+        }
+
+        let argmax = |xs: &[f32]| {
+            (0..xs.len())
+                .max_by(|a, b| xs[*a].total_cmp(&xs[*b]))
+                .unwrap_or_default()
+        };
+        Ok(Self {
+            tolerance,
+            max_abs_diff,
+            max_abs_diff_index,
+            first_divergent_index,
+            num_divergent,
+            reference_argmax: argmax(&reference),
+            actual_argmax: argmax(&actual),
+        })
+    }
+
+    pub fn passed(&self) -> bool {
+        self.num_divergent == 0
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.first_divergent_index {
+            None => write!(
+                f,
+                "Logits match the reference within {} (max abs diff {} at index {}).",
+                self.tolerance, self.max_abs_diff, self.max_abs_diff_index
+            ),
+            Some(first) => write!(
+                f,
+                "Logits diverge from the reference: {} logits differ by more than {}, the first at index {first}. Max abs diff {} at index {}. Argmax is {} (reference {}).",
+                self.num_divergent,
+                self.tolerance,
+                self.max_abs_diff,
+                self.max_abs_diff_index,
+                self.actual_argmax,
+                self.reference_argmax
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::{Device, Tensor};
+
+    use super::{ReferenceLogits, VerifyReport};
+
+    #[test]
+    fn verify_logits() {
+        let dev = Device::Cpu;
+        let reference = Tensor::new(&[1.5f32, -2.0, 7.25, 0.0, 3.0], &dev).unwrap();
+
+        // A correct load matches up to numerical noise.
+        let actual = (&reference + 1e-4).unwrap();
+        let report = VerifyReport::compare(&actual, &reference, 1e-3).unwrap();
+        assert!(report.passed());
+        assert_eq!(report.actual_argmax, 2);
+        assert!(report.to_string().starts_with("Logits match"));
+
+        // A perturbed model is reported with the divergent positions.
+        let perturbed = Tensor::new(&[1.5f32, -2.0, 7.25, 9.0, 2.5], &dev).unwrap();
+        let report = VerifyReport::compare(&perturbed, &reference, 1e-3).unwrap();
+        assert!(!report.passed());
+        assert_eq!(report.first_divergent_index, Some(3));
+        assert_eq!(report.num_divergent, 2);
+        assert_eq!(report.max_abs_diff_index, 3);
+        assert_eq!(report.max_abs_diff, 9.0);
+        assert_eq!((report.actual_argmax, report.reference_argmax), (3, 2));
+        assert!(report
+            .to_string()
+            .contains("2 logits differ by more than 0.001, the first at index 3"));
+
+        let nan = Tensor::new(&[1.5f32, f32::NAN, 7.25, 0.0, 3.0], &dev).unwrap();
+        let report = VerifyReport::compare(&nan, &reference, 1e-3).unwrap();
+        assert_eq!(report.first_divergent_index, Some(1));
+
+        let short = reference.narrow(0, 0, 4).unwrap();
+        assert!(VerifyReport::compare(&short, &reference, 1e-3).is_err());
+    }
+
+    #[test]
+    fn load_reference() {
+        let dev = Device::Cpu;
+        let path = std::env::temp_dir().join(format!(
+            "mistralrs_reference_logits_{}.safetensors",
+            std::process::id()
+        ));
+        let tensors = [
+            ("input_ids", Tensor::new(&[1u32, 415, 5565], &dev).unwrap()),
+            ("logits", Tensor::new(&[0.5f32, 1.0], &dev).unwrap()),
+        ];
+        candle_core::safetensors::save(&HashMap::from(tensors), &path).unwrap();
+
+        let reference = ReferenceLogits::load(&path).unwrap();
+        assert_eq!(reference.input_ids, [1, 415, 5565]);
+        assert_eq!(reference.logits.to_vec1::<f32>().unwrap(), [0.5, 1.0]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -34,15 +34,12 @@ impl LoraLinear {
         let mut a_adapters = Vec::with_capacity(config.len());
         let mut b_adapters = Vec::with_capacity(config.len());
         let mut scale_adapters = Vec::with_capacity(config.len());
-        let a_vb = vb.pp("lora_A".to_string());
-        let b_vb = vb.pp("lora_B".to_string());
         let mut state = None;
         let mut all_same = true;
         let mut adapters = HashMap::new();
         for ((name_id, adapter_name), cfg) in config.iter() {
-            let a_pp = a_vb.pp(name_id);
-            let b_pp = b_vb.pp(name_id);
-            let adapter = make_adapter(a_pp, b_pp, cfg, linear_config)?;
+            let (a_pp, b_pp) = cfg.adapter_vbs(vb, Some(name_id.as_str()));
+            let adapter = make_adapter(a_pp, b_pp, Some(name_id.as_str()), cfg, linear_config)?;
             a_adapters.push(adapter.a.clone());
             b_adapters.push(adapter.b.clone());
             scale_adapters.push(adapter.scale);
@@ -71,10 +68,11 @@ impl LoraLinear {
 
         if let Some(preload_adapters) = preload_adapters {
             all_same = false;
-            for (name, (vb, cfg)) in preload_adapters {
-                let a_vb = vb.set_prefix(a_vb.prefix());
-                let b_vb = vb.set_prefix(b_vb.prefix());
-                let adapter = make_adapter(a_vb, b_vb, cfg, linear_config)?;
+            for (name, (preload_vb, cfg)) in preload_adapters {
+                let (a_vb, b_vb) = cfg.adapter_vbs(vb, None);
+                let a_vb = preload_vb.set_prefix(a_vb.prefix());
+                let b_vb = preload_vb.set_prefix(b_vb.prefix());
+                let adapter = make_adapter(a_vb, b_vb, None, cfg, linear_config)?;
                 adapters.insert(name.clone(), adapter);
             }
         }
@@ -128,6 +126,43 @@ impl LoraLinear {
     }
 }
 
+impl LoraLinear {
+    /// Add the output of each adapter for `input` to `result`, weighted by its scalings if any.
+    fn add_adapters(
+        &self,
+        input: &Tensor,
+        mut result: Tensor,
+        scalings: Option<&Tensor>,
+        global_scaling_weight: f64,
+    ) -> Result<Tensor> {
+        let (a_adapters, b_adapters) = match (&self.a_adapters, &self.b_adapters) {
+            (Either::Left(a), Either::Left(b)) | (Either::Right((_, a)), Either::Right((_, b))) => {
+                (a, b)
+            }
+            _ => unreachable!("Both adapters must be Either::Left or Either::Right."),
+        };
+        //No fan_in_fan_out so no weight.transpose(0,1)
+        for (i, (adapter_a, (adapter_b, adapter_scale))) in
+            zip(a_adapters, zip(b_adapters, &self.scale_adapters)).enumerate()
+        {
+            let input_new = input.to_dtype(adapter_a.weight().dtype())?;
+            let input_new = if let Some(scalings) = scalings {
+                let scalings = scalings.to_dtype(input_new.dtype())?;
+                apply_scalings_to_x(input_new, &scalings, i)?
+            } else {
+                input_new
+            };
+
+            let res = adapter_b
+                .forward(&adapter_a.forward(&input_new)?)?
+                .mul(*adapter_scale)?
+                .mul(global_scaling_weight)?;
+            result = (result + res.to_dtype(result.dtype())?)?;
+        }
+        Ok(result)
+    }
+}
+
 impl Merge for LoraLinear {
     fn get_delta_weight(&self, adapter: usize) -> Result<Tensor> {
         match (&self.a_adapters, &self.b_adapters) {
@@ -150,8 +185,10 @@ impl Merge for LoraLinear {
                 w_base_layer = Some(self.get_delta_weight(adapter)?)
             }
         }
-        self.old
-            .add_delta_w(w_base_layer.as_ref().expect("Found no adapters to merge."))?;
+        let w_base_layer = w_base_layer
+            .expect("Found no adapters to merge.")
+            .to_dtype(self.old.dtype_and_device().0)?;
+        self.old = self.old.add_delta_w(&w_base_layer)?;
         self.merged = true;
         Ok(())
     }
@@ -177,10 +214,16 @@ impl LinearLayerLike for LoraLinear {
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
     ) -> Result<Tensor> {
-        let mut result = self.old.forward(input)?;
+        let result = self.old.forward(input)?;
 
         if self.merged {
-            return Ok(result);
+            // The adapters are merged with a weight of 1, so a blend of the adapters only adds
+            // the difference of its weights.
+            let Some(scalings) = scalings else {
+                return Ok(result);
+            };
+            let scalings = (get_maybe_topk_scalings(scalings, self.layer_n)? - 1.)?;
+            return self.add_adapters(input, result, Some(&scalings), global_scaling_weight);
         }
 
         if is_scaling_pass.is_some_and(|x| x == 0.) {
@@ -194,34 +237,7 @@ impl LinearLayerLike for LoraLinear {
                 .as_ref()
                 .is_some_and(|scalings| scalings.dims3().unwrap().1 != 1)
         {
-            let a_adapters = if self.a_adapters.is_right() {
-                self.a_adapters.as_ref().unwrap_right().1.clone()
-            } else {
-                self.a_adapters.as_ref().unwrap_left().clone()
-            };
-            let b_adapters = if self.b_adapters.is_right() {
-                self.b_adapters.as_ref().unwrap_right().1.clone()
-            } else {
-                self.b_adapters.as_ref().unwrap_left().clone()
-            };
-            //No fan_in_fan_out so no weight.transpose(0,1)
-            for (i, (adapter_a, (adapter_b, adapter_scale))) in
-                zip(a_adapters, zip(b_adapters, &self.scale_adapters)).enumerate()
-            {
-                let input_new = input.to_dtype(adapter_a.weight().dtype())?;
-                let input_new = if let Some(scalings) = &scalings {
-                    apply_scalings_to_x(input_new, scalings, i)?
-                } else {
-                    input_new
-                };
-
-                let res = adapter_b
-                    .forward(&adapter_a.forward(&input_new)?)?
-                    .mul(*adapter_scale)?
-                    .mul(global_scaling_weight)?;
-                result = (result + res)?;
-            }
-            Ok(result)
+            self.add_adapters(input, result, scalings.as_ref(), global_scaling_weight)
         } else {
             let adapter_a = &self.a_adapters.as_ref().unwrap_right().0;
             let adapter_b = &self.b_adapters.as_ref().unwrap_right().0;
@@ -232,7 +248,8 @@ impl LinearLayerLike for LoraLinear {
                     .squeeze(0)?
                     .squeeze(0)?
                     .unsqueeze(1)?
-                    .unsqueeze(1)?;
+                    .unsqueeze(1)?
+                    .to_dtype(adapter_a.dtype())?;
                 adapter_a
                     .broadcast_mul(&scalings)?
                     .mul(global_scaling_weight)?
@@ -241,12 +258,12 @@ impl LinearLayerLike for LoraLinear {
             };
 
             let (b, s, h) = input.dims3()?;
-            let input = input.reshape((b * s, h))?;
+            let input = input.reshape((b * s, h))?.to_dtype(adapter_a.dtype())?;
             let out = adapter_a.broadcast_matmul(&input.t()?)?;
             let out = adapter_b.broadcast_matmul(&out)?;
             let o_h = out.dims()[1];
             let out = out.reshape((n_adapters, b, s, o_h))?;
-            let out = out.sum(0)?;
+            let out = out.sum(0)?.to_dtype(result.dtype())?;
             out + result
         }
     }
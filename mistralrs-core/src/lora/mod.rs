@@ -2,11 +2,13 @@
 
 use std::{collections::HashSet, fmt::Debug, sync::Arc};
 
-use candle_core::{quantized::QTensor, DType, IndexOp, Result, Tensor, D};
+use candle_core::{quantized::QTensor, DType, Device, IndexOp, Result, Tensor, D};
 use candle_nn::{Linear, Module};
 use loralinear::LoraLinear;
 use mistralrs_quant::{QuantMethod, ShardedVarBuilder};
 pub use qloralinear::QLoraLinear;
+use rand::{Rng, SeedableRng};
+use rand_isaac::Isaac64Rng;
 use serde::Deserialize;
 
 mod loralinear;
@@ -48,15 +50,134 @@ impl LoraLinearConfig {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AdapterKind {
+    Lora,
+    /// VeRA: frozen random A and B matrices shared by all layers, generated from
+    /// `projection_prng_key`, with only the `vera_lambda_d` and `vera_lambda_b` scaling vectors
+    /// trained per layer.
+    VeRA {
+        projection_prng_key: u64,
+    },
+}
+
+impl AdapterKind {
+    /// Names of the tensors applied before and after the rank reduction.
+    fn tensor_names(&self) -> (&'static str, &'static str) {
+        match self {
+            Self::Lora => ("lora_A", "lora_B"),
+            Self::VeRA { .. } => ("vera_lambda_d", "vera_lambda_b"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct LoraConfigRaw {
+    peft_type: Option<String>,
+    r: usize,
+    lora_alpha: Option<f64>,
+    lora_dropout: Option<f32>,
+    vera_dropout: Option<f32>,
+    #[serde(default)]
+    projection_prng_key: u64,
+    target_modules: HashSet<String>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "LoraConfigRaw")]
 pub struct LoraConfig {
-    #[serde(rename = "r")]
     rank: usize,
-    #[serde(rename = "lora_alpha")]
     alpha: f64,
-    #[serde(rename = "lora_dropout")]
     dropout: Option<f32>,
     target_modules: HashSet<String>,
+    kind: AdapterKind,
+    /// DType of the adapter weights, if different from the base model.
+    dtype: Option<DType>,
+}
+
+impl TryFrom<LoraConfigRaw> for LoraConfig {
+    type Error = String;
+
+    fn try_from(raw: LoraConfigRaw) -> std::result::Result<Self, Self::Error> {
+        match raw.peft_type.as_deref() {
+            None | Some("LORA") => Ok(Self {
+                rank: raw.r,
+                alpha: raw
+                    .lora_alpha
+                    .ok_or("LoRA adapter configs must specify `lora_alpha`.")?,
+                dropout: raw.lora_dropout,
+                target_modules: raw.target_modules,
+                kind: AdapterKind::Lora,
+                dtype: None,
+            }),
+            // VeRA does not scale the adapter output, which is an alpha equal to the rank.
+            Some("VERA") => Ok(Self {
+                rank: raw.r,
+                alpha: raw.r as f64,
+                dropout: raw.vera_dropout,
+                target_modules: raw.target_modules,
+                kind: AdapterKind::VeRA {
+                    projection_prng_key: raw.projection_prng_key,
+                },
+                dtype: None,
+            }),
+            Some(other) => Err(format!(
+                "Unsupported adapter type `{other}`, expected `LORA` or `VERA`."
+            )),
+        }
+    }
+}
+
+impl LoraConfig {
+    /// Load the adapter weights in `dtype` instead of the dtype of the base model.
+    pub(crate) fn with_dtype(mut self, dtype: DType) -> Self {
+        self.dtype = Some(dtype);
+        self
+    }
+
+    /// Var builders of the tensors applied before and after the rank reduction for the adapter
+    /// `name_id`, or for the adapter at the root of `vb` if `None`.
+    fn adapter_vbs(
+        &self,
+        vb: &ShardedVarBuilder,
+        name_id: Option<&str>,
+    ) -> (ShardedVarBuilder, ShardedVarBuilder) {
+        let (a_name, b_name) = self.kind.tensor_names();
+        match name_id {
+            Some(name_id) => (vb.pp(a_name).pp(name_id), vb.pp(b_name).pp(name_id)),
+            None => (vb.pp(a_name), vb.pp(b_name)),
+        }
+    }
+}
+
+/// Weight of each of the LoRA adapters `names` for a request blending `adapters`, in the order of
+/// the scalings. Adapters which are not blended get a weight of 0.
+pub(crate) fn blend_scalings(
+    adapters: &[(String, f32)],
+    names: &[String],
+) -> std::result::Result<Vec<f32>, String> {
+    if names.is_empty() {
+        return Err("The model has no LoRA adapters to blend. Adapters are blended for LoRA models, but not for X-LoRA or GGUF adapter models.".to_string());
+    }
+    let mut scalings = vec![0.; names.len()];
+    let mut blended = HashSet::new();
+    for (name, weight) in adapters {
+        let Some(i) = names.iter().position(|n| n == name) else {
+            return Err(format!(
+                "Unknown LoRA adapter `{name}`, expected one of {names:?}."
+            ));
+        };
+        if !blended.insert(i) {
+            return Err(format!("LoRA adapter `{name}` is blended more than once."));
+        }
+        if !weight.is_finite() {
+            return Err(format!(
+                "The weight of LoRA adapter `{name}` must be finite."
+            ));
+        }
+        scalings[i] = *weight;
+    }
+    Ok(scalings)
 }
 
 fn apply_scalings_to_x(x: Tensor, scalings_layer: &Tensor, adapter: usize) -> Result<Tensor> {
@@ -72,16 +193,106 @@ struct Adapter {
     scale: f64,
 }
 
+/// Generate the frozen VeRA projections A of shape `(rank, in_features)` and B of shape
+/// `(out_features, rank)`, with the Kaiming uniform initialization used by PEFT. The projections
+/// only depend on the seed and the shapes, so layers of the same shape share them.
+fn vera_projections(
+    projection_prng_key: u64,
+    rank: usize,
+    linear_cfg: &LoraLinearConfig,
+    dtype: DType,
+    device: &Device,
+) -> Result<(Tensor, Tensor)> {
+    let mut rng = Isaac64Rng::seed_from_u64(projection_prng_key);
+    let mut kaiming_uniform = |rows: usize, fan_in: usize| {
+        let bound = 1. / (fan_in as f32).sqrt();
+        let data = (0..rows * fan_in)
+            .map(|_| rng.random_range(-bound..bound))
+            .collect::<Vec<f32>>();
+        Tensor::from_vec(data, (rows, fan_in), device)?.to_dtype(dtype)
+    };
+    let a = kaiming_uniform(rank, linear_cfg.in_features)?;
+    let b = kaiming_uniform(linear_cfg.out_features, rank)?;
+    Ok((a, b))
+}
+
+/// Prefix of the VeRA projections shared by all layers in the adapter weights saved by PEFT.
+const VERA_PROJECTIONS_PREFIX: &str = "base_model";
+
+/// The frozen VeRA projections A and B of one layer. PEFT saves a single A of shape
+/// `(rank, max_in_features)` and B of shape `(max_out_features, rank)` for all layers as
+/// `base_model.vera_A` and `base_model.vera_B`, of which each layer uses the top left slice. Adapters
+/// saved without the projections use projections generated from `projection_prng_key`.
+fn load_vera_projections(
+    vb: &ShardedVarBuilder,
+    name_id: Option<&str>,
+    projection_prng_key: u64,
+    rank: usize,
+    linear_cfg: &LoraLinearConfig,
+    dtype: DType,
+    device: &Device,
+) -> Result<(Tensor, Tensor)> {
+    let vb = vb.set_prefix(VERA_PROJECTIONS_PREFIX);
+    let suffix = name_id.map(|id| format!(".{id}")).unwrap_or_default();
+    let (a_name, b_name) = (format!("vera_A{suffix}"), format!("vera_B{suffix}"));
+    if !vb.contains_tensor(&a_name) || !vb.contains_tensor(&b_name) {
+        return vera_projections(projection_prng_key, rank, linear_cfg, dtype, device);
+    }
+    let a = vb.get_unchecked(&a_name)?;
+    let b = vb.get_unchecked(&b_name)?;
+    if a.dim(0)? != rank || b.dim(1)? != rank {
+        candle_core::bail!(
+            "VeRA projections of shapes {:?} and {:?} do not have the rank {rank} of the adapter.",
+            a.dims(),
+            b.dims()
+        );
+    }
+    let a = a.narrow(1, 0, linear_cfg.in_features)?;
+    let b = b.narrow(0, 0, linear_cfg.out_features)?;
+    Ok((a.to_device(device)?, b.to_device(device)?))
+}
+
 fn make_adapter(
     a_vb: ShardedVarBuilder,
     b_vb: ShardedVarBuilder,
+    name_id: Option<&str>,
     cfg: &LoraConfig,
     linear_cfg: &LoraLinearConfig,
 ) -> Result<Adapter> {
-    assert!(a_vb.contains_tensor("weight"));
-    let a = a_vb.get((cfg.rank, linear_cfg.in_features), "weight")?;
-    assert!(b_vb.contains_tensor("weight"));
-    let b = b_vb.get((linear_cfg.out_features, cfg.rank), "weight")?;
+    let (a, b) = match cfg.kind {
+        AdapterKind::Lora => {
+            assert!(a_vb.contains_tensor("weight"));
+            let a = a_vb.get((cfg.rank, linear_cfg.in_features), "weight")?;
+            assert!(b_vb.contains_tensor("weight"));
+            let b = b_vb.get((linear_cfg.out_features, cfg.rank), "weight")?;
+            (a, b)
+        }
+        AdapterKind::VeRA {
+            projection_prng_key,
+        } => {
+            // The scaling vectors are folded into the projections, so that the adapter is applied
+            // and merged like a LoRA adapter.
+            let lambda_d = a_vb.get(cfg.rank, "weight")?;
+            let lambda_b = b_vb.get(linear_cfg.out_features, "weight")?;
+            let (a, b) = load_vera_projections(
+                &a_vb,
+                name_id,
+                projection_prng_key,
+                cfg.rank,
+                linear_cfg,
+                lambda_d.dtype(),
+                lambda_d.device(),
+            )?;
+            (
+                a.broadcast_mul(&lambda_d.unsqueeze(1)?)?,
+                b.broadcast_mul(&lambda_b.unsqueeze(1)?)?,
+            )
+        }
+    };
+    let (a, b) = match cfg.dtype {
+        Some(dtype) => (a.to_dtype(dtype)?, b.to_dtype(dtype)?),
+        None => (a, b),
+    };
     let a = Linear::new(a, None);
     let b = Linear::new(b, None);
     let scale = if cfg.rank > 0 {
@@ -256,6 +467,10 @@ pub fn linear_no_bias(
 }
 
 fn get_maybe_topk_scalings(scalings: Tensor, layer: usize) -> Result<Tensor> {
+    // The scalings of a blend of adapters are the same for all the layers.
+    if scalings.dim(2)? == 1 {
+        return scalings.i((.., .., 0, ..));
+    }
     scalings.i((.., .., layer, ..))
 }
 
@@ -299,3 +514,233 @@ pub fn linear_b(
 pub fn get_lora_cfg(tensor: &QTensor) -> LoraLinearConfig {
     LoraLinearConfig::new(tensor.shape().dims()[1], tensor.shape().dims()[0])
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use candle_core::{DType, Device, Tensor};
+    use candle_nn::Linear;
+    use mistralrs_quant::ShardedSafeTensors;
+
+    use super::{
+        blend_scalings, vera_projections, AdapterKind, LinearLayerLike, LoraConfig, LoraLinear,
+        LoraLinearConfig, Merge,
+    };
+
+    #[test]
+    fn vera_config() {
+        let cfg: LoraConfig = serde_json::from_str(
+            r#"{"peft_type": "VERA", "r": 4, "projection_prng_key": 7, "target_modules": ["q_proj"]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            cfg.kind,
+            AdapterKind::VeRA {
+                projection_prng_key: 7
+            }
+        );
+        assert_eq!(cfg.alpha / cfg.rank as f64, 1.);
+
+        let cfg: LoraConfig = serde_json::from_str(
+            r#"{"r": 8, "lora_alpha": 16, "lora_dropout": 0.1, "target_modules": ["q_proj"]}"#,
+        )
+        .unwrap();
+        assert_eq!(cfg.kind, AdapterKind::Lora);
+        assert!(serde_json::from_str::<LoraConfig>(r#"{"r": 8, "target_modules": []}"#).is_err());
+        assert!(serde_json::from_str::<LoraConfig>(
+            r#"{"peft_type": "IA3", "r": 8, "target_modules": []}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn vera_projections_are_shared() -> candle_core::Result<()> {
+        let linear_cfg = LoraLinearConfig::new(64, 32);
+        let (a, b) = vera_projections(7, 4, &linear_cfg, DType::F32, &Device::Cpu)?;
+        assert_eq!(a.dims(), &[4, 64]);
+        assert_eq!(b.dims(), &[32, 4]);
+        // Kaiming uniform bounds.
+        assert!(a.abs()?.max_all()?.to_scalar::<f32>()? <= 1. / 8.);
+        assert!(b.abs()?.max_all()?.to_scalar::<f32>()? <= 1. / 2.);
+
+        // Layers of the same shape get the same projections for the same seed.
+        let (a2, b2) = vera_projections(7, 4, &linear_cfg, DType::F32, &Device::Cpu)?;
+        assert_eq!(a.to_vec2::<f32>()?, a2.to_vec2::<f32>()?);
+        assert_eq!(b.to_vec2::<f32>()?, b2.to_vec2::<f32>()?);
+        let (a3, _) = vera_projections(8, 4, &linear_cfg, DType::F32, &Device::Cpu)?;
+        assert_ne!(a.to_vec2::<f32>()?, a3.to_vec2::<f32>()?);
+        Ok(())
+    }
+
+    #[test]
+    fn blend_scalings_by_name() {
+        let names = vec![
+            "style".to_string(),
+            "domain".to_string(),
+            "math".to_string(),
+        ];
+        let blend = vec![("domain".to_string(), 1.), ("style".to_string(), 0.3)];
+        assert_eq!(blend_scalings(&blend, &names).unwrap(), vec![0.3, 1., 0.]);
+
+        assert!(blend_scalings(&[("code".to_string(), 1.)], &names).is_err());
+        assert!(blend_scalings(
+            &[("math".to_string(), 1.), ("math".to_string(), 0.5)],
+            &names
+        )
+        .is_err());
+        assert!(blend_scalings(&[("math".to_string(), f32::NAN)], &names).is_err());
+        assert!(blend_scalings(&blend, &[]).is_err());
+    }
+
+    #[test]
+    fn blended_adapters_match_merged_weights() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let (in_features, out_features, rank) = (6, 4, 2);
+        let cfg = LoraConfig {
+            rank,
+            alpha: 4.,
+            dropout: None,
+            target_modules: HashSet::from(["q_proj".to_string()]),
+            kind: AdapterKind::Lora,
+            dtype: None,
+        };
+        let scale = cfg.alpha / rank as f64;
+
+        let weight = Tensor::randn(0f32, 1., (out_features, in_features), &dev)?;
+        let mut tensors = HashMap::new();
+        let mut deltas = Vec::new();
+        for name_id in ["0", "1"] {
+            let a = Tensor::randn(0f32, 1., (rank, in_features), &dev)?;
+            let b = Tensor::randn(0f32, 1., (out_features, rank), &dev)?;
+            deltas.push((b.matmul(&a)? * scale)?);
+            tensors.insert(format!("lora_A.{name_id}.weight"), a);
+            tensors.insert(format!("lora_B.{name_id}.weight"), b);
+        }
+        let vb = ShardedSafeTensors::wrap(Box::new(tensors), DType::F32, dev.clone());
+        let config = [
+            (("0".to_string(), "style".to_string()), cfg.clone()),
+            (("1".to_string(), "domain".to_string()), cfg),
+        ];
+        let mut layer = LoraLinear::new(
+            &Linear::new(weight.clone(), None),
+            &LoraLinearConfig::new(in_features, out_features),
+            &config,
+            &vb,
+            0,
+            &None,
+        )?;
+        layer.merge_weights()?;
+
+        // Two sequences: the first blends the adapters, the second applies both of them.
+        let x = Tensor::randn(0f32, 1., (2, 3, in_features), &dev)?;
+        let scalings = Tensor::new(&[0.3f32, 1., 1., 1.], &dev)?.reshape((2, 1, 1, 2))?;
+        let out = layer.lora_forward(&x, Some(scalings), 1., None)?;
+
+        let blended = ((&weight + (&deltas[0] * 0.3)?)? + &deltas[1])?;
+        let all = ((&weight + &deltas[0])? + &deltas[1])?;
+        for (i, merged) in [blended, all].iter().enumerate() {
+            let expected = x.get(i)?.matmul(&merged.t()?)?;
+            let diff = (out.get(i)? - expected)?
+                .abs()?
+                .max_all()?
+                .to_scalar::<f32>()?;
+            assert!(diff < 1e-4, "sequence {i} differs by {diff}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn adapters_in_another_dtype() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let (in_features, out_features, rank) = (6, 4, 2);
+        let cfg = LoraConfig {
+            rank,
+            alpha: 2.,
+            dropout: None,
+            target_modules: HashSet::from(["q_proj".to_string()]),
+            kind: AdapterKind::Lora,
+            dtype: None,
+        }
+        .with_dtype(DType::F16);
+
+        let weight = Tensor::randn(0f32, 1., (out_features, in_features), &dev)?;
+        let a = Tensor::randn(0f32, 1., (rank, in_features), &dev)?;
+        let b = Tensor::randn(0f32, 1., (out_features, rank), &dev)?;
+        let merged = (&weight + b.matmul(&a)?)?;
+        let tensors = HashMap::from([
+            ("lora_A.0.weight".to_string(), a),
+            ("lora_B.0.weight".to_string(), b),
+        ]);
+        let vb = ShardedSafeTensors::wrap(Box::new(tensors), DType::F32, dev.clone());
+        let config = [(("0".to_string(), "style".to_string()), cfg)];
+        let mut layer = LoraLinear::new(
+            &Linear::new(weight, None),
+            &LoraLinearConfig::new(in_features, out_features),
+            &config,
+            &vb,
+            0,
+            &None,
+        )?;
+        assert_eq!(layer.get_delta_weight(0)?.dtype(), DType::F16);
+
+        // The base model keeps its dtype, before and after merging the adapter.
+        let x = Tensor::randn(0f32, 1., (2, 3, in_features), &dev)?;
+        let expected = x.broadcast_matmul(&merged.t()?)?;
+        for merge in [false, true] {
+            if merge {
+                layer.merge_weights()?;
+            }
+            let out = layer.lora_forward(&x, None, 1., None)?;
+            assert_eq!(out.dtype(), DType::F32);
+            let diff = (out - &expected)?.abs()?.max_all()?.to_scalar::<f32>()?;
+            assert!(diff < 1e-2, "merged {merge}: differs by {diff}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn vera_forward_with_saved_projections() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let cfg: LoraConfig = serde_json::from_str(
+            r#"{"peft_type": "VERA", "r": 2, "projection_prng_key": 7, "target_modules": ["q_proj"]}"#,
+        )
+        .unwrap();
+        // The shared projections are larger than the layer, which uses their top left slice.
+        let tensors = HashMap::from([
+            (
+                "base_model.vera_A.0".to_string(),
+                Tensor::new(&[[1f32, 0., 2., 9.], [0., 1., -1., 9.]], &dev)?,
+            ),
+            (
+                "base_model.vera_B.0".to_string(),
+                Tensor::new(&[[1f32, 2.], [3., -1.], [9., 9.]], &dev)?,
+            ),
+            (
+                "vera_lambda_d.0.weight".to_string(),
+                Tensor::new(&[0.5f32, 2.], &dev)?,
+            ),
+            (
+                "vera_lambda_b.0.weight".to_string(),
+                Tensor::new(&[1f32, -2.], &dev)?,
+            ),
+        ]);
+        let vb = ShardedSafeTensors::wrap(Box::new(tensors), DType::F32, dev.clone());
+        let layer = LoraLinear::new(
+            &Linear::new(Tensor::zeros((2, 3), DType::F32, &dev)?, None),
+            &LoraLinearConfig::new(3, 2),
+            &[(("0".to_string(), "vera".to_string()), cfg)],
+            &vb,
+            0,
+            &None,
+        )?;
+
+        // PEFT's VeRA forward: lambda_b * (B[:out] @ (lambda_d * (A[:, :in] @ x))). With
+        // x = [1, 2, 3], A[:, :3] @ x = [7, -1], scaled by lambda_d to [3.5, -2], then
+        // B[:2] @ [3.5, -2] = [-0.5, 12.5], scaled by lambda_b to [-0.5, -25].
+        let x = Tensor::new(&[[[1f32, 2., 3.]]], &dev)?;
+        let out = layer.lora_forward(&x, None, 1., None)?;
+        assert_eq!(out.flatten_all()?.to_vec1::<f32>()?, [-0.5, -25.]);
+        Ok(())
+    }
+}
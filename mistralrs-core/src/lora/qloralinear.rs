@@ -77,15 +77,12 @@ impl QLoraLinear {
         let mut b_adapters = Vec::with_capacity(config.len());
         let mut scale_adapters = Vec::with_capacity(config.len());
         let vb = vb.pp(prefix.clone());
-        let a_vb = vb.pp("lora_A".to_string());
-        let b_vb = vb.pp("lora_B".to_string());
         let mut state = None;
         let mut all_same = true;
         let mut adapters = HashMap::new();
         for ((name_id, adapter_name), cfg) in config.iter() {
-            let a_pp = a_vb.pp(name_id);
-            let b_pp = b_vb.pp(name_id);
-            let adapter = make_adapter(a_pp, b_pp, cfg, linear_config)?;
+            let (a_pp, b_pp) = cfg.adapter_vbs(&vb, Some(name_id.as_str()));
+            let adapter = make_adapter(a_pp, b_pp, Some(name_id.as_str()), cfg, linear_config)?;
             a_adapters.push(adapter.a.clone());
             b_adapters.push(adapter.b.clone());
             scale_adapters.push(adapter.scale);
@@ -114,10 +111,11 @@ impl QLoraLinear {
 
         if let Some(preload_adapters) = preload_adapters {
             all_same = false;
-            for (name, (vb, cfg)) in preload_adapters {
-                let a_vb = vb.set_prefix(a_vb.prefix());
-                let b_vb = vb.set_prefix(b_vb.prefix());
-                let adapter = make_adapter(a_vb, b_vb, cfg, linear_config)?;
+            for (name, (preload_vb, cfg)) in preload_adapters {
+                let (a_vb, b_vb) = cfg.adapter_vbs(&vb, None);
+                let a_vb = preload_vb.set_prefix(a_vb.prefix());
+                let b_vb = preload_vb.set_prefix(b_vb.prefix());
+                let adapter = make_adapter(a_vb, b_vb, None, cfg, linear_config)?;
                 adapters.insert(name.clone(), adapter);
             }
         }
@@ -195,7 +193,8 @@ impl Merge for QLoraLinear {
                 w_base_layer = Some(self.get_delta_weight(adapter)?)
             }
         }
-        self.old
+        self.old = self
+            .old
             .add_delta_w(w_base_layer.as_ref().expect("Found no adapters to merge."))?;
         self.merged = true;
         Ok(())
@@ -1,17 +1,20 @@
 use std::{
     fs::{self, File},
     num::NonZeroUsize,
+    path::PathBuf,
 };
 
 use mistralrs_quant::MULTI_LORA_DELIMITER;
 
+#[cfg(feature = "onnx")]
+use crate::pipeline::{OnnxLoaderBuilder, OnnxSpecificConfig};
 use crate::{
     get_toml_selected_model_dtype,
     pipeline::{GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder, NormalSpecificConfig},
     toml_selector::get_toml_selected_model_device_map_params,
-    AutoDeviceMapParams, DiffusionLoaderBuilder, DiffusionSpecificConfig, GGUFSpecificConfig,
-    Loader, ModelDType, ModelSelected, NormalLoaderBuilder, TomlLoaderArgs, TomlSelector, Topology,
-    VisionLoaderBuilder, VisionSpecificConfig, GGUF_MULTI_FILE_DELIMITER,
+    AutoDeviceMapParams, DiffusionLoaderBuilder, DiffusionSpecificConfig, GGUFArchitecture,
+    GGUFSpecificConfig, Loader, ModelDType, ModelSelected, NormalLoaderBuilder, TomlLoaderArgs,
+    TomlSelector, Topology, VisionLoaderBuilder, VisionSpecificConfig, GGUF_MULTI_FILE_DELIMITER,
 };
 
 /// A builder for a loader using the selected model.
@@ -22,6 +25,10 @@ pub struct LoaderBuilder {
     jinja_explicit: Option<String>,
     use_flash_attn: bool,
     prompt_chunksize: Option<NonZeroUsize>,
+    auto_correct_chat_template: bool,
+    byte_fallback: Option<bool>,
+    force_arch: Option<GGUFArchitecture>,
+    accuracy_companion: Option<PathBuf>,
 }
 
 impl LoaderBuilder {
@@ -33,6 +40,10 @@ impl LoaderBuilder {
             use_flash_attn: false,
             prompt_chunksize: None,
             jinja_explicit: None,
+            auto_correct_chat_template: false,
+            byte_fallback: None,
+            force_arch: None,
+            accuracy_companion: None,
         }
     }
 
@@ -56,6 +67,27 @@ impl LoaderBuilder {
         self.prompt_chunksize = prompt_chunksize;
         self
     }
+    /// Replace the chat template of GGUF models when it does not match the detected prompt format.
+    pub fn with_auto_correct_chat_template(mut self, auto_correct_chat_template: bool) -> Self {
+        self.auto_correct_chat_template = auto_correct_chat_template;
+        self
+    }
+    /// Force byte fallback of the tokenizer on or off instead of using the tokenizer's default.
+    pub fn with_byte_fallback(mut self, byte_fallback: Option<bool>) -> Self {
+        self.byte_fallback = byte_fallback;
+        self
+    }
+    /// Load GGUF models as this architecture instead of the one declared in their metadata.
+    pub fn with_force_arch(mut self, force_arch: Option<GGUFArchitecture>) -> Self {
+        self.force_arch = force_arch;
+        self
+    }
+    /// Load the tensors of this safetensors file as F16 instead of the quantized GGUF tensors of
+    /// the same name.
+    pub fn with_accuracy_companion(mut self, accuracy_companion: Option<PathBuf>) -> Self {
+        self.accuracy_companion = accuracy_companion;
+        self
+    }
 
     pub fn build(self) -> anyhow::Result<Box<dyn Loader>> {
         loader_from_model_selected(self)
@@ -72,7 +104,8 @@ pub fn get_tgt_non_granular_index(model: &ModelSelected) -> Option<usize> {
         | ModelSelected::LoraGGML { .. }
         | ModelSelected::Toml { .. }
         | ModelSelected::VisionPlain { .. }
-        | ModelSelected::DiffusionPlain { .. } => None,
+        | ModelSelected::DiffusionPlain { .. }
+        | ModelSelected::Onnx { .. } => None,
         ModelSelected::XLora {
             tgt_non_granular_index,
             ..
@@ -101,6 +134,8 @@ pub fn get_model_dtype(model: &ModelSelected) -> anyhow::Result<ModelDType> {
         | ModelSelected::XLoraGGML { dtype, .. }
         | ModelSelected::LoraGGUF { dtype, .. }
         | ModelSelected::LoraGGML { dtype, .. } => Ok(*dtype),
+        // ONNX graphs are exported in F32.
+        ModelSelected::Onnx { .. } => Ok(ModelDType::F32),
         ModelSelected::Toml { file } => {
             let selector: TomlSelector = toml::from_str(
                 &fs::read_to_string(file.clone())
@@ -173,7 +208,9 @@ pub fn get_auto_device_map_params(model: &ModelSelected) -> anyhow::Result<AutoD
             max_image_shape: (*max_image_length, *max_image_length),
             max_num_images: *max_num_images,
         }),
-        ModelSelected::DiffusionPlain { .. } => Ok(AutoDeviceMapParams::default_text()),
+        ModelSelected::DiffusionPlain { .. } | ModelSelected::Onnx { .. } => {
+            Ok(AutoDeviceMapParams::default_text())
+        }
         ModelSelected::Toml { file } => {
             let selector: TomlSelector = toml::from_str(
                 &fs::read_to_string(file.clone())
@@ -198,6 +235,9 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 no_kv_cache: args.no_kv_cache,
                 prompt_chunksize: args.prompt_chunksize,
                 jinja_explicit: args.jinja_explicit,
+                byte_fallback: args.byte_fallback,
+                force_arch: args.force_arch,
+                accuracy_companion: args.accuracy_companion.clone(),
             };
             (selector, args).try_into()?
         }
@@ -226,6 +266,9 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 imatrix,
                 calibration_file,
                 hf_cache_path,
+                early_exit: None,
+                byte_fallback: args.byte_fallback,
+                tok_env: None,
             },
             args.chat_template,
             tokenizer_json,
@@ -259,6 +302,9 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 imatrix: None,
                 calibration_file: None,
                 hf_cache_path,
+                early_exit: None,
+                byte_fallback: args.byte_fallback,
+                tok_env: None,
             },
             args.chat_template,
             tokenizer_json,
@@ -299,6 +345,9 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 imatrix: None,
                 calibration_file: None,
                 hf_cache_path,
+                early_exit: None,
+                byte_fallback: args.byte_fallback,
+                tok_env: None,
             },
             args.chat_template,
             tokenizer_json,
@@ -330,6 +379,11 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             GGUFSpecificConfig {
                 prompt_chunksize: args.prompt_chunksize,
                 topology: Topology::from_option_path(topology)?,
+                auto_correct_chat_template: args.auto_correct_chat_template,
+                byte_fallback: args.byte_fallback,
+                force_arch: args.force_arch,
+                accuracy_companion: args.accuracy_companion.clone(),
+                tok_env: None,
             },
             args.no_kv_cache,
             args.jinja_explicit,
@@ -355,6 +409,11 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             GGUFSpecificConfig {
                 prompt_chunksize: args.prompt_chunksize,
                 topology: Topology::from_option_path(topology)?,
+                auto_correct_chat_template: args.auto_correct_chat_template,
+                byte_fallback: args.byte_fallback,
+                force_arch: args.force_arch,
+                accuracy_companion: args.accuracy_companion.clone(),
+                tok_env: None,
             },
             args.no_kv_cache,
             args.jinja_explicit,
@@ -388,6 +447,11 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             GGUFSpecificConfig {
                 prompt_chunksize: args.prompt_chunksize,
                 topology: Topology::from_option_path(topology)?,
+                auto_correct_chat_template: args.auto_correct_chat_template,
+                byte_fallback: args.byte_fallback,
+                force_arch: args.force_arch,
+                accuracy_companion: args.accuracy_companion.clone(),
+                tok_env: None,
             },
             args.no_kv_cache,
             args.jinja_explicit,
@@ -413,6 +477,8 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 gqa,
                 prompt_chunksize: args.prompt_chunksize,
                 topology: Topology::from_option_path(topology)?,
+                byte_fallback: args.byte_fallback,
+                tok_env: None,
             },
             args.chat_template,
             tokenizer_json,
@@ -439,6 +505,8 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 gqa,
                 prompt_chunksize: args.prompt_chunksize,
                 topology: Topology::from_option_path(topology)?,
+                byte_fallback: args.byte_fallback,
+                tok_env: None,
             },
             args.chat_template,
             tokenizer_json,
@@ -473,6 +541,8 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 gqa,
                 prompt_chunksize: args.prompt_chunksize,
                 topology: Topology::from_option_path(topology)?,
+                byte_fallback: args.byte_fallback,
+                tok_env: None,
             },
             args.chat_template,
             tokenizer_json,
@@ -517,6 +587,8 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 calibration_file,
                 imatrix,
                 hf_cache_path,
+                byte_fallback: args.byte_fallback,
+                tok_env: None,
             },
             args.chat_template,
             tokenizer_json,
@@ -532,6 +604,28 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             DiffusionLoaderBuilder::new(DiffusionSpecificConfig { use_flash_attn }, Some(model_id))
                 .build(arch)
         }
+        #[cfg(feature = "onnx")]
+        ModelSelected::Onnx {
+            model_id,
+            onnx_file,
+            tokenizer_json,
+            intra_threads,
+        } => OnnxLoaderBuilder::new(
+            OnnxSpecificConfig {
+                intra_threads,
+                ..Default::default()
+            },
+            args.chat_template,
+            tokenizer_json,
+            model_id,
+            onnx_file,
+            args.jinja_explicit,
+        )
+        .build(),
+        #[cfg(not(feature = "onnx"))]
+        ModelSelected::Onnx { .. } => {
+            anyhow::bail!("Loading ONNX models requires the `onnx` feature.")
+        }
     };
     Ok(loader)
 }
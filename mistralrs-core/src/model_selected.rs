@@ -539,4 +539,25 @@ pub enum ModelSelected {
         #[arg(short, long, default_value_t = ModelDType::Auto, value_parser = parse_model_dtype)]
         dtype: ModelDType,
     },
+
+    /// Select a Mistral or Llama model exported to ONNX, which runs on the CPU with ONNX Runtime.
+    /// This requires the `onnx` feature.
+    Onnx {
+        /// Model ID to load from. This may be a HF hub repo or a local path.
+        #[arg(short, long)]
+        model_id: String,
+
+        /// Path of the ONNX graph inside the model. Defaults to `onnx/model.onnx`.
+        #[arg(short = 'f', long)]
+        onnx_file: Option<String>,
+
+        /// Path to local tokenizer.json file. If this is specified it is used over any remote file.
+        #[arg(long)]
+        tokenizer_json: Option<String>,
+
+        /// Number of threads used to parallelize execution within nodes. Defaults to the choice of
+        /// ONNX Runtime.
+        #[arg(long)]
+        intra_threads: Option<usize>,
+    },
 }
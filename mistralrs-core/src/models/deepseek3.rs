@@ -1204,6 +1204,7 @@ impl NormalModel for DeepSeekV3 {
         _position_ids: Vec<usize>,
         _flash_params: &FlashParams,
         _flash_params_full: &FlashParams,
+        _adapter_scalings: Option<Tensor>,
     ) -> Result<Tensor> {
         unimplemented!()
     }
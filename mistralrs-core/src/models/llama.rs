@@ -1,18 +1,25 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
-use candle_core::{Device, Result, Tensor};
+use candle_core::{Device, IndexOp, Result, Tensor};
 use candle_nn::{Embedding, Module};
+use indicatif::MultiProgress;
 use mistralrs_quant::{
     ColumnParallelLayer, QuantMethod, QuantizedConfig, ReplicatedLayer, RowParallelLayer,
     ShardedVarBuilder,
 };
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    ops::Range,
+    sync::{Arc, Mutex},
+};
 
 use crate::{
     amoe::{AnyMoeBaseModelMixin, AnyMoeConfig, AnyMoeExpertType, MlpLayer, MoeMlp},
     attention::SdpaParams,
-    device_map::DeviceMapper,
+    device_map::{device_segments, micro_batch_schedule, DeviceMapper, DeviceSegment},
+    distributed::{DistributedRank, PipelineShard},
+    early_exit::{is_confident, EarlyExitConfig, EarlyExitStats},
     get_delta_from_lora_ab,
     layers::{
         embedding, Activation, CausalMasker, Llama3RopeConfig, Llama3RotaryEmbedding, MatMul, Mlp,
@@ -174,6 +181,40 @@ impl CausalSelfAttention {
         Ok(res)
     }
 
+    /// Append the keys and values of `x` to the cache without running attention. This fills the
+    /// cache of the layers skipped by an early exit, using the hidden state at the exit layer.
+    fn append_kv(
+        &self,
+        x: &Tensor,
+        seqlen_offsets: &[usize],
+        kv_cache: &mut KvCache,
+    ) -> Result<()> {
+        let (b_sz, seq_len, _) = x.dims3()?;
+
+        let original_dtype = x.dtype();
+        let mut x = x.clone();
+        if let Some(t) = self.k_proj.quantized_act_type() {
+            x = x.to_dtype(t)?;
+        }
+        let mut k = MatMul.qmethod_matmul(&x, &*self.k_proj)?;
+        let mut v = MatMul.qmethod_matmul(&x, &*self.v_proj)?;
+        if self.k_proj.quantized_act_type().is_some() {
+            k = k.to_dtype(original_dtype)?;
+            v = v.to_dtype(original_dtype)?;
+        }
+        let k = k
+            .reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = v
+            .reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim))?
+            .transpose(1, 2)?;
+
+        // The rotary embedding is applied to queries and keys independently.
+        let (_, k) = self.rotary_emb.forward(&k, &k, seqlen_offsets)?;
+        kv_cache.append(&k, &v)?;
+        Ok(())
+    }
+
     fn load(
         vb: ShardedVarBuilder,
         cfg: &Config,
@@ -327,6 +368,65 @@ impl Block {
     }
 }
 
+/// Load the blocks of the layers in `layers`, sharing the rotary embedding of each device.
+#[allow(clippy::too_many_arguments)]
+fn load_blocks(
+    cfg: &Config,
+    vb_m: &ShardedVarBuilder,
+    layers: Range<usize>,
+    is_gptx: bool,
+    mapper: &dyn DeviceMapper,
+    real_device: &Device,
+    loading_isq: bool,
+    multi_progress: &MultiProgress,
+    attention_mechanism: &AttentionImplementation,
+) -> Result<Vec<Block>> {
+    let head_dim = cfg.hidden_size / cfg.num_attention_heads;
+    let mut ropes = HashMap::new();
+    for i in layers.clone() {
+        let device = mapper.device_for(i, false).unwrap_or(real_device);
+        ropes.insert(
+            device.location(),
+            Arc::new(Llama3RotaryEmbedding::new_llama3(
+                vb_m.dtype(),
+                cfg,
+                device,
+                is_gptx,
+            )?),
+        );
+    }
+    let blocks = NiceProgressBar::<_, 'b'>(layers, "Loading repeating layers", multi_progress)
+        .into_iter()
+        .map(|i| {
+            let device = mapper.device_for(i, false).unwrap_or(real_device);
+            let rotary_emb = ropes
+                .get(&device.location())
+                .expect("No RoPE for device location!")
+                .clone();
+            let paged_attn = match attention_mechanism {
+                AttentionImplementation::Eager => None,
+                AttentionImplementation::PagedAttention => Some(
+                    PagedAttention::new(head_dim, device, None)
+                        .expect("Failed to create PagedAttention"),
+                ),
+            };
+            let comm = mapper.get_comm_for(i).unwrap();
+            Block::load(
+                vb_m.pp(format!("layers.{i}")),
+                cfg,
+                mapper,
+                i,
+                loading_isq,
+                rotary_emb,
+                paged_attn,
+                &comm,
+            )
+            .expect("Failed to load block.")
+        })
+        .collect();
+    Ok(blocks)
+}
+
 pub struct Llama {
     wte: Embedding,
     blocks: Vec<Block>,
@@ -336,6 +436,8 @@ pub struct Llama {
     device: Device,
     mapper: Box<dyn DeviceMapper + Send + Sync>,
     cfg: ModelConfigMetadata,
+    early_exit: Option<EarlyExitConfig>,
+    early_exit_stats: Arc<Mutex<EarlyExitStats>>,
 }
 
 impl Llama {
@@ -400,57 +502,17 @@ impl Llama {
             cfg.rms_norm_eps,
             mapper.set_nm_device(vb_m.pp("norm"), false),
         )?;
-        let head_dim = cfg.hidden_size / cfg.num_attention_heads;
-        let mut ropes = HashMap::new();
-        for i in 0..cfg.num_hidden_layers {
-            let device = mapper
-                .device_for(i, false)
-                .unwrap_or(&normal_loading_metadata.real_device);
-            ropes.insert(
-                device.location(),
-                Arc::new(Llama3RotaryEmbedding::new_llama3(
-                    vb_m.dtype(),
-                    cfg,
-                    device,
-                    is_gptx,
-                )?),
-            );
-        }
-        let blocks: Vec<_> = NiceProgressBar::<_, 'b'>(
+        let blocks = load_blocks(
+            cfg,
+            &vb_m,
             0..cfg.num_hidden_layers,
-            "Loading repeating layers",
+            is_gptx,
+            &*mapper,
+            &normal_loading_metadata.real_device,
+            normal_loading_metadata.loading_isq,
             &normal_loading_metadata.multi_progress,
-        )
-        .into_iter()
-        .map(|i| {
-            let device = mapper
-                .device_for(i, false)
-                .unwrap_or(&normal_loading_metadata.real_device);
-            let rotary_emb = ropes
-                .get(&device.location())
-                .expect("No RoPE for device location!")
-                .clone();
-            let paged_attn = match &attention_mechanism {
-                AttentionImplementation::Eager => None,
-                AttentionImplementation::PagedAttention => Some(
-                    PagedAttention::new(head_dim, device, None)
-                        .expect("Failed to create PagedAttention"),
-                ),
-            };
-            let comm = mapper.get_comm_for(i).unwrap();
-            Block::load(
-                vb_m.pp(format!("layers.{i}")),
-                cfg,
-                &*mapper,
-                i,
-                normal_loading_metadata.loading_isq,
-                rotary_emb,
-                paged_attn,
-                &comm,
-            )
-            .expect("Failed to load block.")
-        })
-        .collect();
+            &attention_mechanism,
+        )?;
 
         Ok(Self {
             wte,
@@ -474,6 +536,8 @@ impl Llama {
                 v_head_dim: cfg.hidden_size / cfg.num_attention_heads,
             },
             mapper,
+            early_exit: None,
+            early_exit_stats: Arc::new(Mutex::new(EarlyExitStats::default())),
         })
     }
 
@@ -527,8 +591,46 @@ impl Llama {
                 .map(|(_, meta)| meta.is_first_prompt_chunk)
                 .unwrap_or(true)
         });
+        // Early exit only applies to decode steps without PagedAttention.
+        let early_exit = self
+            .early_exit
+            .filter(|_| metadata.is_none() && input_ids.dim(1).is_ok_and(|len| len == 1));
+
+        let segments = device_segments(&*self.mapper, self.blocks.len());
+        let segments_cover_all_layers =
+            segments.iter().map(|s| s.layers.len()).sum::<usize>() == self.blocks.len();
+        if segments.len() > 1
+            && segments_cover_all_layers
+            && x.dim(0)? > 1
+            && metadata.is_none()
+            && early_exit.is_none()
+            && !crate::using_flash_attn()
+            && self.mapper.pipelining()
+        {
+            let x = self.forward_micro_batches(
+                x,
+                &mask,
+                seqlen_offsets,
+                cache,
+                &segments,
+                flash_params,
+            )?;
+            let xs = self.logit_lens(&x)?;
+            return extract_logits(&xs, context_lens);
+        }
+
+        let mut exited = false;
+        let mut depth = self.blocks.len();
         for (block_idx, block) in self.blocks.iter().enumerate() {
             x = self.mapper.map(x, block_idx)?;
+            if exited {
+                block.attn.append_kv(
+                    &block.rms_1.forward(&x)?,
+                    seqlen_offsets,
+                    &mut cache[block_idx],
+                )?;
+                continue;
+            }
             x = block.forward(
                 &x,
                 &mask.clone().map(|m| m.to_device(x.device()).unwrap()),
@@ -539,14 +641,90 @@ impl Llama {
                     .map(|(kv_cache, metadata)| (kv_cache[block_idx].clone(), *metadata)),
                 flash_params,
             )?;
+            if let Some(EarlyExitConfig {
+                min_layer,
+                confidence,
+            }) = early_exit
+            {
+                if block_idx >= min_layer
+                    && block_idx + 1 < self.blocks.len()
+                    && is_confident(&self.logit_lens(&x)?.squeeze(1)?, confidence)?
+                {
+                    exited = true;
+                    depth = block_idx + 1;
+                }
+            }
+        }
+        if early_exit.is_some() {
+            self.early_exit_stats
+                .lock()
+                .expect("Early exit stats were poisoned")
+                .record(depth, self.blocks.len());
+        }
+        let xs = self.logit_lens(&x)?;
+        extract_logits(&xs, context_lens)
+    }
+
+    /// Run the layers as a two-stage pipeline across the device boundaries: the batch is split
+    /// into two micro-batches so that while one runs the layers on a device, the other can run
+    /// the layers on the previous device instead of waiting for the whole batch.
+    fn forward_micro_batches(
+        &self,
+        x: Tensor,
+        mask: &Option<Tensor>,
+        seqlen_offsets: &[usize],
+        cache: &mut [KvCache],
+        segments: &[DeviceSegment],
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let b_sz = x.dim(0)?;
+        let sizes = [b_sz.div_ceil(2), b_sz / 2];
+        let offsets = [&seqlen_offsets[..sizes[0]], &seqlen_offsets[sizes[0]..]];
+        let mut xs = vec![x.narrow(0, 0, sizes[0])?, x.narrow(0, sizes[0], sizes[1])?];
+        let mut micro_caches = cache
+            .iter()
+            .map(|c| c.split_batch(&sizes))
+            .collect::<Result<Vec<_>>>()?;
+
+        for step in micro_batch_schedule(sizes.len(), segments.len()) {
+            for &(micro_batch, segment) in &step {
+                let DeviceSegment { layers, device } = &segments[segment];
+                let mask = mask.as_ref().map(|m| m.to_device(device)).transpose()?;
+                let mut h = xs[micro_batch].to_device(device)?;
+                for layer in layers.clone() {
+                    h = self.blocks[layer].forward(
+                        &h,
+                        &mask,
+                        offsets[micro_batch],
+                        &mut micro_caches[layer][micro_batch],
+                        None,
+                        flash_params,
+                    )?;
+                }
+                xs[micro_batch] = h;
+            }
+            // Only wait for the activations once the work of every device has been issued.
+            for &(micro_batch, segment) in &step {
+                if let Some(next) = segments.get(segment + 1) {
+                    xs[micro_batch] = xs[micro_batch].to_device(&next.device)?;
+                }
+            }
+        }
+
+        for (layer_cache, parts) in cache.iter_mut().zip(micro_caches) {
+            layer_cache.merge_batch(&parts)?;
         }
+        Tensor::cat(&xs, 0)
+    }
+
+    /// Apply the final norm and LM head to a hidden state.
+    fn logit_lens(&self, x: &Tensor) -> Result<Tensor> {
         let x = x.to_device(&self.device)?;
         let mut x = self.ln_f.forward(&x)?;
         if let Some(t) = self.lm_head.quantized_act_type() {
             x = x.to_dtype(t)?;
         }
-        let xs = MatMul.qmethod_matmul(&x, &*self.lm_head)?;
-        extract_logits(&xs, context_lens)
+        MatMul.qmethod_matmul(&x, &*self.lm_head)
     }
 
     pub fn residual_tensors_m(&self, uvb_m: UnVarBuilder) -> Vec<(String, Tensor)> {
@@ -642,6 +820,7 @@ impl NormalModel for Llama {
         _position_ids: Vec<usize>,
         _flash_params: &FlashParams,
         _flash_params_full: &FlashParams,
+        _adapter_scalings: Option<Tensor>,
     ) -> Result<Tensor> {
         unimplemented!()
     }
@@ -663,6 +842,13 @@ impl NormalModel for Llama {
     fn config(&self) -> &ModelConfigMetadata {
         &self.cfg
     }
+    fn set_early_exit(&mut self, early_exit: Option<EarlyExitConfig>) -> Result<()> {
+        self.early_exit = early_exit;
+        Ok(())
+    }
+    fn early_exit_stats(&self) -> Option<Arc<Mutex<EarlyExitStats>>> {
+        self.early_exit.map(|_| self.early_exit_stats.clone())
+    }
 }
 
 impl AnyMoeBaseModelMixin for Llama {
@@ -784,3 +970,479 @@ impl AnyMoeBaseModelMixin for Llama {
         true
     }
 }
+
+/// The layers of a Llama model run by one rank with pipeline parallelism across machines, see
+/// [`DistributedRank::layers`]. Only the weights of these layers are loaded. The first rank
+/// embeds the input ids, and the last rank applies the final norm and LM head, so that rank 0
+/// receives the logits of every position.
+pub struct LlamaShard {
+    wte: Option<Embedding>,
+    blocks: Vec<Block>,
+    head: Option<(RmsNorm, Arc<dyn QuantMethod>)>,
+    kv_cache: EitherCache,
+    device: Device,
+}
+
+impl LlamaShard {
+    pub fn new(
+        cfg: &Config,
+        vb: ShardedVarBuilder,
+        is_gptx: bool,
+        rank: DistributedRank,
+        normal_loading_metadata: NormalLoadingMetadata,
+    ) -> Result<Self> {
+        let vb_m = vb.pp("model");
+        let mapper = normal_loading_metadata.mapper;
+        let is_first = rank.rank() == 0;
+        let is_last = rank.rank() + 1 == rank.world_size();
+
+        let wte = if is_first || (is_last && cfg.tie_word_embeddings) {
+            Some(embedding(
+                cfg.vocab_size,
+                cfg.hidden_size,
+                mapper.set_nm_device(vb_m.pp("embed_tokens"), false),
+                &cfg.quantization_config,
+            )?)
+        } else {
+            None
+        };
+        let head = if is_last {
+            let lm_head = match &wte {
+                Some(wte) if cfg.tie_word_embeddings => ReplicatedLayer::from_linear(
+                    candle_nn::Linear::new(mapper.cast_nm_device(wte.embeddings(), false)?, None),
+                )?,
+                _ => ReplicatedLayer::new(
+                    cfg.hidden_size,
+                    cfg.vocab_size,
+                    &None,
+                    false,
+                    mapper.set_nm_device(vb.pp("lm_head"), false),
+                )?,
+            };
+            let ln_f = RmsNorm::new(
+                cfg.hidden_size,
+                cfg.rms_norm_eps,
+                mapper.set_nm_device(vb_m.pp("norm"), false),
+            )?;
+            Some((ln_f, lm_head))
+        } else {
+            None
+        };
+
+        let layers = rank.layers(cfg.num_hidden_layers);
+        if layers.is_empty() {
+            candle_core::bail!(
+                "Rank {} has no layer, the world size {} is above the {} layers.",
+                rank.rank(),
+                rank.world_size(),
+                cfg.num_hidden_layers
+            );
+        }
+        let blocks = load_blocks(
+            cfg,
+            &vb_m,
+            layers.clone(),
+            is_gptx,
+            &*mapper,
+            &normal_loading_metadata.real_device,
+            false,
+            &normal_loading_metadata.multi_progress,
+            &AttentionImplementation::Eager,
+        )?;
+        Ok(Self {
+            wte: wte.filter(|_| is_first),
+            blocks,
+            head,
+            kv_cache: EitherCache::Normal(NormalCache::new(
+                layers.len(),
+                cfg.max_position_embeddings,
+            )),
+            device: normal_loading_metadata.real_device,
+        })
+    }
+}
+
+impl PipelineShard for LlamaShard {
+    fn forward(&self, xs: &Tensor, seqlen_offsets: &[usize]) -> Result<Tensor> {
+        let mut x = match &self.wte {
+            Some(wte) => wte.forward(xs)?,
+            None => xs.to_device(&self.device)?,
+        };
+        let cache = &mut self.kv_cache.normal().0;
+        // The mask only depends on the batch size and the sequence length.
+        let mask = CausalMasker.make_causal_mask_matrix(
+            &x.i((.., .., 0))?,
+            &*cache as &dyn PastKvLenCache,
+            x.dtype(),
+            self.blocks[0].attn.num_attention_heads,
+        )?;
+        let flash_params = FlashParams {
+            max_q: 0,
+            max_k: 0,
+            cumulative_seqlens_q: HashMap::new(),
+            cumulative_seqlens_k: HashMap::new(),
+        };
+        for (block, kv_cache) in self.blocks.iter().zip(cache.iter_mut()) {
+            x = block.forward(&x, &mask, seqlen_offsets, kv_cache, None, &flash_params)?;
+        }
+        let Some((ln_f, lm_head)) = &self.head else {
+            return Ok(x);
+        };
+        let mut x = ln_f.forward(&x)?;
+        if let Some(t) = lm_head.quantized_act_type() {
+            x = x.to_dtype(t)?;
+        }
+        MatMul.qmethod_matmul(&x, &**lm_head)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use candle_core::{DType, Device, Result, Tensor};
+    use indicatif::MultiProgress;
+    use mistralrs_quant::{ShardedSafeTensors, ShardedVarBuilder};
+    use tokio::net::TcpListener;
+
+    use super::{Config, Llama, LlamaShard};
+    use crate::{
+        device_map::DeviceSegment,
+        distributed::{serve_shard, DistributedCoordinator, DistributedRank},
+        early_exit::{EarlyExitConfig, EarlyExitStats},
+        layers::CausalMasker,
+        layers_masker::PastKvLenCache,
+        paged_attention::AttentionImplementation,
+        pipeline::{
+            extract_logits, text_models_inputs_processor::FlashParams, NormalLoadingMetadata,
+            NormalModel,
+        },
+        DeviceMapSetting,
+    };
+
+    const LAYERS: usize = 3;
+    const VOCAB: usize = 16;
+    const HIDDEN: usize = 32;
+
+    fn weights(dev: &Device) -> Result<HashMap<String, Tensor>> {
+        let mut tensors = HashMap::new();
+        let mut linear = |name: String, out_dim: usize, in_dim: usize| -> Result<()> {
+            let w = (Tensor::randn(0f32, 1f32, (out_dim, in_dim), dev)? * 0.1)?;
+            tensors.insert(name, w);
+            Ok(())
+        };
+        linear("model.embed_tokens.weight".to_string(), VOCAB, HIDDEN)?;
+        for layer in 0..LAYERS {
+            let prefix = format!("model.layers.{layer}");
+            // 4 heads of dimension 8, and 2 key-value heads.
+            for (proj, out_dim) in [("q", HIDDEN), ("k", 16), ("v", 16), ("o", HIDDEN)] {
+                linear(
+                    format!("{prefix}.self_attn.{proj}_proj.weight"),
+                    out_dim,
+                    HIDDEN,
+                )?;
+            }
+            linear(format!("{prefix}.mlp.gate_proj.weight"), 64, HIDDEN)?;
+            linear(format!("{prefix}.mlp.up_proj.weight"), 64, HIDDEN)?;
+            linear(format!("{prefix}.mlp.down_proj.weight"), HIDDEN, 64)?;
+        }
+        let ones = Tensor::ones(HIDDEN, DType::F32, dev)?;
+        for layer in 0..LAYERS {
+            let prefix = format!("model.layers.{layer}");
+            tensors.insert(format!("{prefix}.input_layernorm.weight"), ones.clone());
+            tensors.insert(
+                format!("{prefix}.post_attention_layernorm.weight"),
+                ones.clone(),
+            );
+        }
+        tensors.insert("model.norm.weight".to_string(), ones);
+        Ok(tensors)
+    }
+
+    fn config() -> Config {
+        serde_json::from_value(serde_json::json!({
+            "hidden_act": "silu",
+            "hidden_size": HIDDEN,
+            "intermediate_size": 64,
+            "vocab_size": VOCAB,
+            "num_hidden_layers": LAYERS,
+            "num_attention_heads": 4,
+            "num_key_value_heads": 2,
+            "rms_norm_eps": 1e-5,
+            "rope_theta": 10000.0,
+            "max_position_embeddings": 64,
+            "tie_word_embeddings": true,
+        }))
+        .unwrap()
+    }
+
+    fn vb_and_metadata(
+        weights: &HashMap<String, Tensor>,
+    ) -> Result<(ShardedVarBuilder, NormalLoadingMetadata)> {
+        let dev = Device::Cpu;
+        let vb = ShardedSafeTensors::wrap(Box::new(weights.clone()), DType::F32, dev.clone());
+        let metadata = NormalLoadingMetadata {
+            mapper: DeviceMapSetting::dummy().into_mapper(LAYERS, &dev, None)?,
+            loading_isq: false,
+            real_device: dev,
+            multi_progress: Arc::new(MultiProgress::new()),
+        };
+        Ok((vb, metadata))
+    }
+
+    fn load(
+        weights: &HashMap<String, Tensor>,
+        early_exit: Option<EarlyExitConfig>,
+    ) -> Result<Llama> {
+        let (vb, metadata) = vb_and_metadata(weights)?;
+        let mut model = Llama::new(
+            &config(),
+            vb,
+            false,
+            metadata,
+            AttentionImplementation::Eager,
+        )?;
+        model.set_early_exit(early_exit)?;
+        Ok(model)
+    }
+
+    /// Run the prompt then one decode step per token of `decoded`, and return the logits of the
+    /// last token of each step.
+    fn generate(model: &Llama, prompt: &[u32], decoded: &[u32]) -> Result<Vec<Vec<f32>>> {
+        let flash_params = FlashParams {
+            max_q: 0,
+            max_k: 0,
+            cumulative_seqlens_q: HashMap::new(),
+            cumulative_seqlens_k: HashMap::new(),
+        };
+        let mut steps = vec![prompt];
+        steps.extend(decoded.chunks(1));
+        let mut offset = 0;
+        let mut logits = Vec::new();
+        for ids in steps {
+            let input = Tensor::new(ids, &Device::Cpu)?.unsqueeze(0)?;
+            let out = model.forward(
+                &input,
+                &[offset],
+                vec![(ids.len() - 1, 1)],
+                None,
+                &flash_params,
+            )?;
+            logits.push(out.flatten_all()?.to_vec1::<f32>()?);
+            offset += ids.len();
+        }
+        Ok(logits)
+    }
+
+    /// Run a step of a batch as two micro-batches through the first layer and then the other
+    /// layers, and return the logits of the last token of each sequence.
+    fn micro_batch_step(model: &Llama, input: &Tensor, offsets: &[usize]) -> Result<Tensor> {
+        let flash_params = FlashParams {
+            max_q: 0,
+            max_k: 0,
+            cumulative_seqlens_q: HashMap::new(),
+            cumulative_seqlens_k: HashMap::new(),
+        };
+        let segments = [
+            DeviceSegment {
+                layers: 0..1,
+                device: Device::Cpu,
+            },
+            DeviceSegment {
+                layers: 1..LAYERS,
+                device: Device::Cpu,
+            },
+        ];
+        let x = model.get_input_embeddings(input)?;
+        let cache = &mut model.kv_cache.normal().0;
+        let mask = CausalMasker.make_causal_mask_matrix(
+            input,
+            cache as &dyn PastKvLenCache,
+            x.dtype(),
+            model.blocks[0].attn.num_attention_heads,
+        )?;
+        let x = model.forward_micro_batches(x, &mask, offsets, cache, &segments, &flash_params)?;
+        let (b_sz, seq_len) = input.dims2()?;
+        extract_logits(&model.logit_lens(&x)?, vec![(seq_len - 1, 1); b_sz])
+    }
+
+    fn cached_tokens(model: &Llama, layer: usize) -> Result<usize> {
+        let k = model.kv_cache.normal().0[layer].k()?;
+        k.map_or(Ok(0), |k| k.dim(2))
+    }
+
+    #[test]
+    fn unconfident_model_runs_every_layer() -> Result<()> {
+        let weights = weights(&Device::Cpu)?;
+        let (prompt, decoded) = ([1u32, 5, 9, 2], [3u32, 7, 11]);
+
+        let baseline = load(&weights, None)?;
+        let expected = generate(&baseline, &prompt, &decoded)?;
+        assert!(baseline.early_exit_stats().is_none());
+
+        // No token can reach a probability above 1.
+        let model = load(
+            &weights,
+            Some(EarlyExitConfig {
+                min_layer: 0,
+                confidence: 1.1,
+            }),
+        )?;
+        assert_eq!(generate(&model, &prompt, &decoded)?, expected);
+
+        let stats = *model.early_exit_stats().unwrap().lock().unwrap();
+        // The prompt is not counted, only the decode steps.
+        assert_eq!(
+            stats,
+            EarlyExitStats {
+                steps: decoded.len(),
+                early_exits: 0,
+                total_depth: LAYERS * decoded.len(),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn confident_model_exits_after_min_layer() -> Result<()> {
+        let weights = weights(&Device::Cpu)?;
+        let (prompt, decoded) = ([1u32, 5, 9, 2], [3u32, 7, 11]);
+
+        let model = load(
+            &weights,
+            Some(EarlyExitConfig {
+                min_layer: 1,
+                confidence: 0.,
+            }),
+        )?;
+        let logits = generate(&model, &prompt, &decoded)?;
+        assert!(logits.iter().flatten().all(|x| x.is_finite()));
+
+        let stats = *model.early_exit_stats().unwrap().lock().unwrap();
+        assert_eq!(stats.steps, decoded.len());
+        assert_eq!(stats.early_exits, decoded.len());
+        assert_eq!(stats.average_exit_depth(), Some(2.));
+
+        // The layers skipped by the exit still cache the keys and values of the decoded tokens.
+        for layer in 0..LAYERS {
+            assert_eq!(cached_tokens(&model, layer)?, prompt.len() + decoded.len());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn micro_batches_match_the_plain_forward() -> Result<()> {
+        let weights = weights(&Device::Cpu)?;
+        let flash_params = FlashParams {
+            max_q: 0,
+            max_k: 0,
+            cumulative_seqlens_q: HashMap::new(),
+            cumulative_seqlens_k: HashMap::new(),
+        };
+        let plain = load(&weights, None)?;
+        let pipelined = load(&weights, None)?;
+
+        // A prompt step then a decode step, for a batch of two sequences.
+        let steps = [
+            Tensor::new(&[[1u32, 5, 9, 2], [4, 8, 12, 6]], &Device::Cpu)?,
+            Tensor::new(&[[3u32], [7]], &Device::Cpu)?,
+        ];
+        let mut offset = 0;
+        for input in steps {
+            let (b_sz, seq_len) = input.dims2()?;
+            let offsets = vec![offset; b_sz];
+            let expected = plain
+                .forward(
+                    &input,
+                    &offsets,
+                    vec![(seq_len - 1, 1); b_sz],
+                    None,
+                    &flash_params,
+                )?
+                .flatten_all()?
+                .to_vec1::<f32>()?;
+            let logits = micro_batch_step(&pipelined, &input, &offsets)?
+                .flatten_all()?
+                .to_vec1::<f32>()?;
+            assert_eq!(logits.len(), expected.len());
+            for (a, b) in logits.iter().zip(&expected) {
+                assert!((a - b).abs() < 1e-5, "{logits:?} != {expected:?}");
+            }
+            offset += seq_len;
+        }
+
+        // The micro-batches are merged back into the cache of the whole batch.
+        for layer in 0..LAYERS {
+            assert_eq!(cached_tokens(&pipelined, layer)?, 5);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shards_across_ranks_match_the_model() -> anyhow::Result<()> {
+        let weights = weights(&Device::Cpu)?;
+        let (prompt, decoded) = ([1u32, 5, 9, 2], [3u32, 7, 11]);
+        let expected = generate(&load(&weights, None)?, &prompt, &decoded)?;
+
+        let mut listeners = Vec::new();
+        for _ in 0..LAYERS {
+            listeners.push(TcpListener::bind("127.0.0.1:0").await?);
+        }
+        let addrs = listeners
+            .iter()
+            .map(|listener| listener.local_addr())
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let mut shards = Vec::new();
+        for rank in 0..LAYERS {
+            let (vb, metadata) = vb_and_metadata(&weights)?;
+            let rank = DistributedRank::new(rank, LAYERS)?;
+            shards.push(Arc::new(LlamaShard::new(
+                &config(),
+                vb,
+                false,
+                rank,
+                metadata,
+            )?));
+        }
+
+        let mut listeners = listeners.into_iter();
+        let listener = listeners.next().unwrap();
+        let mut workers = Vec::new();
+        for (rank, worker_listener) in (1..LAYERS).zip(listeners) {
+            workers.push(tokio::spawn(serve_shard(
+                DistributedRank::new(rank, LAYERS)?,
+                shards[rank].clone(),
+                Device::Cpu,
+                worker_listener,
+                addrs[(rank + 1) % LAYERS],
+            )));
+        }
+        let coordinator = DistributedCoordinator::connect(
+            LAYERS,
+            shards[0].clone(),
+            Device::Cpu,
+            listener,
+            addrs[1],
+        )
+        .await?;
+
+        let mut steps = vec![&prompt[..]];
+        steps.extend(decoded.chunks(1));
+        let mut offset = 0;
+        for (ids, expected) in steps.into_iter().zip(expected) {
+            let input = Tensor::new(ids, &Device::Cpu)?.unsqueeze(0)?;
+            let logits = coordinator.forward(&input, &[offset]).await?;
+            let logits = logits.squeeze(0)?.get(ids.len() - 1)?.to_vec1::<f32>()?;
+            for (a, b) in logits.iter().zip(&expected) {
+                assert!((a - b).abs() < 1e-5, "{logits:?} != {expected:?}");
+            }
+            offset += ids.len();
+        }
+
+        drop(coordinator);
+        for worker in workers {
+            worker.await??;
+        }
+        Ok(())
+    }
+}
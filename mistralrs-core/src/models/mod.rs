@@ -8,10 +8,25 @@ pub(crate) mod mixtral;
 pub(crate) mod phi2;
 pub(crate) mod phi3;
 pub(crate) mod phi3_5_moe;
+pub(crate) mod quantized_bloom;
+pub(crate) mod quantized_chatglm;
+pub(crate) mod quantized_common;
+pub(crate) mod quantized_falcon;
+pub(crate) mod quantized_gemma;
+pub(crate) mod quantized_gemma3;
+pub(crate) mod quantized_gpt2;
+pub(crate) mod quantized_gptneox;
+pub(crate) mod quantized_granite;
+pub(crate) mod quantized_jais;
 pub(crate) mod quantized_llama;
+pub(crate) mod quantized_mamba;
+pub(crate) mod quantized_mpt;
 pub(crate) mod quantized_phi2;
 pub(crate) mod quantized_phi3;
 pub(crate) mod quantized_qwen2;
+pub(crate) mod quantized_rwkv;
 pub(crate) mod quantized_starcoder2;
+#[cfg(test)]
+pub(crate) mod quantized_test_utils;
 pub(crate) mod qwen2;
 pub(crate) mod starcoder2;
@@ -0,0 +1,384 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::sync::Arc;
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_nn::{Embedding, LayerNorm};
+use indicatif::MultiProgress;
+use mistralrs_quant::QuantMethod;
+
+use crate::attention::SdpaParams;
+use crate::device_map::DeviceMapper;
+use crate::gguf::Content;
+use crate::layers::{split_qtensor, Alibi, CausalMasker, MatMul, Sdpa};
+use crate::layers_masker::PastKvLenCache;
+use crate::models::quantized_common::{gguf_linear, layer_norm};
+use crate::paged_attention::AttentionImplementation;
+use crate::pipeline::{extract_logits, EitherCache, KvCache, NormalCache};
+use crate::utils::gguf_metadata::ContentMetadata;
+use crate::utils::model_config as ModelConfig;
+use crate::utils::progress::NiceProgressBar;
+const MAX_SEQ_LEN: u32 = 2048;
+/// BLOOM uses the standard ALiBi slopes.
+const MAX_ALIBI_BIAS: f32 = 8.;
+
+struct Mlp {
+    ffn_up: Arc<dyn QuantMethod>,
+    ffn_down: Arc<dyn QuantMethod>,
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        MatMul.qmethod_matmul(
+            &MatMul
+                .qmethod_matmul(xs, &*self.ffn_up)?
+                .apply(&candle_nn::Activation::NewGelu)?,
+            &*self.ffn_down,
+        )
+    }
+}
+
+struct LayerWeights {
+    attention_wq: Arc<dyn QuantMethod>,
+    attention_wk: Arc<dyn QuantMethod>,
+    attention_wv: Arc<dyn QuantMethod>,
+    attention_wo: Arc<dyn QuantMethod>,
+    attention_norm: LayerNorm,
+    mlp: Mlp,
+    ffn_norm: LayerNorm,
+    n_head: usize,
+    head_dim: usize,
+    sdpa_params: SdpaParams,
+    dtype: DType,
+}
+
+impl LayerWeights {
+    fn forward_attn(&self, x: &Tensor, mask: &Tensor, kv_cache: &mut KvCache) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = x.dims3()?;
+
+        let proj = |w: &Arc<dyn QuantMethod>| -> Result<Tensor> {
+            MatMul
+                .qmethod_matmul(x, &**w)?
+                .to_dtype(self.dtype)?
+                .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()
+        };
+        let q = proj(&self.attention_wq)?;
+        let k = proj(&self.attention_wk)?;
+        let v = proj(&self.attention_wv)?;
+
+        let (k, v) = kv_cache.append(&k, &v)?;
+        let y = Sdpa.run_attention(&q, &k, &v, Some(mask), None, &self.sdpa_params)?;
+
+        let y = y.transpose(1, 2)?.reshape((b_sz, seq_len, ()))?;
+
+        MatMul.qmethod_matmul(&y.to_dtype(x.dtype())?, &*self.attention_wo)
+    }
+}
+
+/// BLOOM (BigScience), a GPT-style model with ALiBi positions, a layer norm of the word
+/// embeddings, biases everywhere and a GELU MLP. The fused query, key and value projection is
+/// split at load time, and the output projection is tied to the token embeddings.
+///
+/// PagedAttention is not supported, so the pipeline runs BLOOM without it.
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    tok_embeddings_norm: LayerNorm,
+    layers: Vec<LayerWeights>,
+    norm: LayerNorm,
+    output: Arc<dyn QuantMethod>,
+    alibi: Alibi,
+    pub device: Device,
+    pub cache: EitherCache,
+    pub max_seq_len: usize,
+    mapper: Option<Box<dyn DeviceMapper + Send + Sync>>,
+    dtype: DType,
+}
+
+// bloom `llm` fields:
+// https://github.com/ggerganov/ggml/blob/master/docs/gguf.md#llm
+// NOTE: Types here do not match spec
+pub(crate) struct PropsGGUF {
+    pub head_count: usize,
+    pub block_count: usize,
+    pub embedding_length: usize,
+    pub layer_norm_epsilon: f64,
+    pub max_seq_len: usize,
+}
+
+impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
+    type Error = anyhow::Error;
+
+    fn try_from(c: ContentMetadata) -> std::result::Result<Self, Self::Error> {
+        c.verify_arch("bloom")?;
+
+        let required = [
+            "attention.head_count",
+            "block_count",
+            "embedding_length",
+            "attention.layer_norm_epsilon",
+        ];
+        c.has_required_keys(&required)?;
+
+        // NOTE: Values are not aligned with GGUFv3 types
+        // TODO: Normalize value types to spec
+        let props = Self {
+            head_count: c.get_value::<u32>("attention.head_count")? as usize,
+            block_count: c.get_value::<u32>("block_count")? as usize,
+            embedding_length: c.get_value::<u32>("embedding_length")? as usize,
+            layer_norm_epsilon: c.get_value::<f32>("attention.layer_norm_epsilon")? as f64,
+            max_seq_len: c
+                .get_value::<u64>("context_length")
+                .ok()
+                .unwrap_or(MAX_SEQ_LEN as u64) as usize,
+        };
+
+        Ok(props)
+    }
+}
+
+impl ModelConfig::FromGGUF for ModelWeights {
+    fn from_gguf<R: std::io::Seek + std::io::Read>(
+        mut ct: Content<'_, R>,
+        device: &Device,
+        mapper: Box<dyn DeviceMapper + Send + Sync>,
+        attention_mechanism: AttentionImplementation,
+        dtype: DType,
+    ) -> Result<Self> {
+        if matches!(attention_mechanism, AttentionImplementation::PagedAttention) {
+            candle_core::bail!("BLOOM does not support PagedAttention.");
+        }
+
+        // Parameter extraction from metadata.
+        let metadata = ContentMetadata {
+            path_prefix: "bloom",
+            metadata: ct.get_metadata(),
+        };
+        let PropsGGUF {
+            head_count,
+            block_count,
+            embedding_length,
+            layer_norm_epsilon,
+            max_seq_len,
+        } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
+
+        let qtok_embeddings = ct.tensor("token_embd.weight", device)?;
+        let tok_embeddings = qtok_embeddings.dequantize(device)?;
+        let tok_embeddings_norm = layer_norm(
+            ct.tensor("token_embd_norm.weight", device)?,
+            ct.tensor("token_embd_norm.bias", device)?,
+            layer_norm_epsilon,
+        )?;
+        let norm = layer_norm(
+            ct.tensor("output_norm.weight", device)?,
+            ct.tensor("output_norm.bias", device)?,
+            layer_norm_epsilon,
+        )?;
+        // The output projection is tied to the token embeddings.
+        let output = if !ct.has_tensor("output.weight") {
+            ct.tensor("token_embd.weight", device)?
+        } else {
+            ct.tensor("output.weight", device)?
+        };
+        let mut layers = Vec::with_capacity(block_count);
+
+        let head_dim = embedding_length / head_count;
+
+        for layer_idx in NiceProgressBar::<_, 'b'>(
+            0..block_count,
+            "Loading repeating layers",
+            &MultiProgress::new(),
+        ) {
+            let prefix = format!("blk.{layer_idx}");
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+
+            // The converters write the fused projection as the queries, then the keys, then the
+            // values.
+            let qkv = ct.tensor(&format!("{prefix}.attn_qkv.weight"), device)?;
+            let qkv_bias = ct
+                .tensor(&format!("{prefix}.attn_qkv.bias"), device)?
+                .dequantize(device)?;
+            let mut attention_qkv =
+                split_qtensor(&qkv, 3, device)?
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, weight)| {
+                        let bias = qkv_bias.narrow(0, i * embedding_length, embedding_length)?;
+                        gguf_linear(weight, Some(bias))
+                    });
+            let attention_wq = attention_qkv.next().unwrap()?;
+            let attention_wk = attention_qkv.next().unwrap()?;
+            let attention_wv = attention_qkv.next().unwrap()?;
+
+            let mut proj = |name: &str| -> Result<Arc<dyn QuantMethod>> {
+                let weight = ct.tensor(&format!("{prefix}.{name}.weight"), device)?;
+                let bias = ct
+                    .tensor(&format!("{prefix}.{name}.bias"), device)?
+                    .dequantize(device)?;
+                gguf_linear(weight, Some(bias))
+            };
+            let attention_wo = proj("attn_output")?;
+            let mlp = Mlp {
+                ffn_up: proj("ffn_up")?,
+                ffn_down: proj("ffn_down")?,
+            };
+
+            let mut norm = |name: &str| -> Result<LayerNorm> {
+                layer_norm(
+                    ct.tensor(&format!("{prefix}.{name}.weight"), device)?,
+                    ct.tensor(&format!("{prefix}.{name}.bias"), device)?,
+                    layer_norm_epsilon,
+                )
+            };
+            let attention_norm = norm("attn_norm")?;
+            let ffn_norm = norm("ffn_norm")?;
+            layers.push(LayerWeights {
+                attention_wq,
+                attention_wk,
+                attention_wv,
+                attention_wo,
+                attention_norm,
+                mlp,
+                ffn_norm,
+                n_head: head_count,
+                head_dim,
+                sdpa_params: SdpaParams {
+                    n_kv_groups: 1,
+                    use_flash_attn: false,
+                    softcap: None,
+                    softmax_scale: 1.0 / (head_dim as f32).sqrt(),
+                    sliding_window: None,
+                },
+                dtype,
+            })
+        }
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            tok_embeddings_norm,
+            layers,
+            norm,
+            output: gguf_linear(output, None)?,
+            alibi: Alibi::new(head_count, MAX_ALIBI_BIAS, device)?,
+            device: device.clone(),
+            cache: EitherCache::Normal(NormalCache::new(block_count, max_seq_len)),
+            max_seq_len,
+            mapper: Some(mapper),
+            dtype,
+        })
+    }
+}
+
+impl ModelWeights {
+    pub fn forward(&self, x: &Tensor, context_lens: Vec<(usize, usize)>) -> Result<Tensor> {
+        let (b_sz, seq_len) = x.dims2()?;
+        let mut layer_in = self
+            .tok_embeddings
+            .forward(x)?
+            .apply(&self.tok_embeddings_norm)?;
+        let cache = &mut self.cache.normal().0;
+        let past_kv_len_cache = &*cache as &dyn PastKvLenCache;
+        let past_kv_len = past_kv_len_cache.get_past_kv_len()?;
+        let causal_mask = CausalMasker.make_causal_mask_matrix(
+            x,
+            past_kv_len_cache,
+            self.dtype,
+            self.layers[0].n_head,
+        )?;
+        // The ALiBi bias is added to the causal mask, so a mask is needed even for one token.
+        let alibi = self.alibi.bias(seq_len, past_kv_len, self.dtype)?;
+        let mask = match causal_mask {
+            Some(causal_mask) => alibi.broadcast_add(&causal_mask)?,
+            None => alibi,
+        };
+        let mask = mask
+            .broadcast_as((b_sz, self.layers[0].n_head, seq_len, past_kv_len + seq_len))?
+            .contiguous()?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            if let Some(ref mapper) = self.mapper {
+                layer_in = mapper.map(layer_in, i)?;
+            }
+            let x = layer_in;
+            let residual = &x;
+            let x = x.apply(&layer.attention_norm)?;
+            let attn = layer.forward_attn(&x, &mask.to_device(x.device())?, &mut cache[i])?;
+            let x = (attn + residual)?;
+
+            // MLP
+            let residual = &x;
+            let x = x.apply(&layer.ffn_norm)?;
+            let x = layer.mlp.forward(&x)?;
+            let x = (x + residual)?;
+            layer_in = x;
+        }
+        let x = layer_in.apply(&self.norm)?;
+        extract_logits(
+            &MatMul.qmethod_matmul(&x.contiguous()?, &*self.output)?,
+            context_lens,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::ModelWeights;
+    use crate::models::quantized_test_utils::{
+        assert_decoding_matches_prompt, assert_finite, last_logits, load, TestGguf,
+    };
+
+    /// Write a GGUF file of a BLOOM model with two layers and tied embeddings.
+    fn bloom_gguf() -> candle_core::Result<Vec<u8>> {
+        let (vocab, hidden) = (16, 32);
+        let mut gguf = TestGguf::new("bloom");
+        gguf.u32("block_count", 2)
+            .u32("context_length", 64)
+            .u32("embedding_length", hidden)
+            .u32("attention.head_count", 4)
+            .f32("attention.layer_norm_epsilon", 1e-5);
+        gguf.linear("token_embd.weight", vocab, hidden)?
+            .layer_norm("token_embd_norm", hidden)?
+            .layer_norm("output_norm", hidden)?;
+        for layer in 0..2 {
+            gguf.layer_norm(&format!("blk.{layer}.attn_norm"), hidden)?
+                .layer_norm(&format!("blk.{layer}.ffn_norm"), hidden)?;
+            for (name, out_dim, in_dim) in [
+                ("attn_qkv", 3 * hidden, hidden),
+                ("attn_output", hidden, hidden),
+                ("ffn_up", 4 * hidden, hidden),
+                ("ffn_down", hidden, 4 * hidden),
+            ] {
+                gguf.linear(format!("blk.{layer}.{name}.weight"), out_dim, in_dim)?
+                    .vector(format!("blk.{layer}.{name}.bias"), out_dim, 0.01)?;
+            }
+        }
+        gguf.build()
+    }
+
+    fn logits(model: &ModelWeights, ids: &[u32], _offset: usize) -> candle_core::Result<Vec<f32>> {
+        let input_ids = Tensor::new(ids, &Device::Cpu)?.unsqueeze(0)?;
+        last_logits(model.forward(&input_ids, vec![(ids.len() - 1, 1)])?)
+    }
+
+    #[test]
+    fn forward() -> candle_core::Result<()> {
+        let model = load::<ModelWeights>(&bloom_gguf()?)?;
+        assert_eq!(model.max_seq_len, 64);
+
+        let out = logits(&model, &[1, 5, 7], 0)?;
+        assert_eq!(out.len(), 16);
+        assert_finite(&out);
+
+        // Decoding a token reuses the KV cache of the prompt.
+        assert_eq!(logits(&model, &[3], 3)?.len(), 16);
+        assert_eq!(model.cache.normal().0[0].current_seq_len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn decoding_matches_prompt() -> candle_core::Result<()> {
+        // The ALiBi bias of the decoded token depends on its distance to the cached tokens.
+        assert_decoding_matches_prompt(&bloom_gguf()?, &[&[1, 5, 7], &[3]], logits)
+    }
+}
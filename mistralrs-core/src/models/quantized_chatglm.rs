@@ -0,0 +1,535 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use candle_core::{DType, Device, Result, Tensor, D};
+use candle_nn::{Embedding, Module};
+use indicatif::MultiProgress;
+use mistralrs_quant::QuantMethod;
+
+use crate::attention::SdpaParams;
+use crate::device_map::DeviceMapper;
+use crate::gguf::Content;
+use crate::layers::{CausalMasker, MatMul, QRmsNorm, RotaryEmbedding, Sdpa};
+use crate::layers_masker::PastKvLenCache;
+use crate::models::quantized_common::gguf_linear;
+use crate::paged_attention::{AttentionImplementation, PagedAttention};
+use crate::pipeline::text_models_inputs_processor::PagedAttentionInputMetadata;
+use crate::pipeline::{extract_logits, EitherCache, KvCache, NormalCache};
+use crate::utils::gguf_metadata::ContentMetadata;
+use crate::utils::model_config as ModelConfig;
+use crate::utils::progress::NiceProgressBar;
+const MAX_SEQ_LEN: u32 = 8192;
+
+/// SwiGLU MLP. ChatGLM GGUFs store the gate and up projections fused in `ffn_up`, with the gate
+/// in the first half.
+struct Mlp {
+    ffn_gate: Option<Arc<dyn QuantMethod>>,
+    ffn_up: Arc<dyn QuantMethod>,
+    ffn_down: Arc<dyn QuantMethod>,
+}
+
+impl Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let up = MatMul.qmethod_matmul(xs, &*self.ffn_up)?;
+        let (gate, up) = match &self.ffn_gate {
+            Some(ffn_gate) => (MatMul.qmethod_matmul(xs, &**ffn_gate)?, up),
+            None => {
+                let half = up.dim(D::Minus1)? / 2;
+                (
+                    up.narrow(D::Minus1, 0, half)?,
+                    up.narrow(D::Minus1, half, half)?,
+                )
+            }
+        };
+        let y = (candle_nn::ops::silu(&gate)? * up)?;
+        MatMul.qmethod_matmul(&y, &*self.ffn_down)
+    }
+}
+
+/// The query, key and value projections, fused in `attn_qkv` or separate.
+enum QkvProj {
+    Fused(Arc<dyn QuantMethod>),
+    Split {
+        q: Arc<dyn QuantMethod>,
+        k: Arc<dyn QuantMethod>,
+        v: Arc<dyn QuantMethod>,
+    },
+}
+
+struct LayerWeights {
+    qkv: QkvProj,
+    attention_wo: Arc<dyn QuantMethod>,
+    attention_norm: QRmsNorm,
+    mlp: Mlp,
+    ffn_norm: QRmsNorm,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    rope_dim: usize,
+    rotary: Arc<RotaryEmbedding>,
+    paged_attn: Option<PagedAttention>,
+    sdpa_params: SdpaParams,
+    dtype: DType,
+}
+
+impl LayerWeights {
+    /// Apply the rotary embedding to the first `rope_dim` dimensions of each head only. This is
+    /// what remains of the 2D positions of GLM, which encoded the position in the first half of
+    /// each head and the position in the generated span in the second half.
+    fn apply_rotary(
+        &self,
+        q: &Tensor,
+        k: &Tensor,
+        start_offsets: &[usize],
+    ) -> Result<(Tensor, Tensor)> {
+        let pass_dim = self.head_dim - self.rope_dim;
+        if pass_dim == 0 {
+            return self.rotary.forward(q, k, start_offsets);
+        }
+        let (q_rot, k_rot) = self.rotary.forward(
+            &q.narrow(D::Minus1, 0, self.rope_dim)?.contiguous()?,
+            &k.narrow(D::Minus1, 0, self.rope_dim)?.contiguous()?,
+            start_offsets,
+        )?;
+        let q = Tensor::cat(
+            &[&q_rot, &q.narrow(D::Minus1, self.rope_dim, pass_dim)?],
+            D::Minus1,
+        )?;
+        let k = Tensor::cat(
+            &[&k_rot, &k.narrow(D::Minus1, self.rope_dim, pass_dim)?],
+            D::Minus1,
+        )?;
+        Ok((q.contiguous()?, k.contiguous()?))
+    }
+
+    fn forward_attn(
+        &self,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        start_offsets: &[usize],
+        kv_cache: &mut KvCache,
+        metadata: Option<((Tensor, Tensor), &PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = x.dims3()?;
+
+        let (q, k, v) = match &self.qkv {
+            QkvProj::Fused(qkv) => {
+                let qkv = MatMul.qmethod_matmul(x, &**qkv)?.to_dtype(self.dtype)?;
+                let q_size = self.n_head * self.head_dim;
+                let kv_size = self.n_kv_head * self.head_dim;
+                (
+                    qkv.narrow(D::Minus1, 0, q_size)?,
+                    qkv.narrow(D::Minus1, q_size, kv_size)?,
+                    qkv.narrow(D::Minus1, q_size + kv_size, kv_size)?,
+                )
+            }
+            QkvProj::Split { q, k, v } => (
+                MatMul.qmethod_matmul(x, &**q)?.to_dtype(self.dtype)?,
+                MatMul.qmethod_matmul(x, &**k)?.to_dtype(self.dtype)?,
+                MatMul.qmethod_matmul(x, &**v)?.to_dtype(self.dtype)?,
+            ),
+        };
+
+        let q = q
+            .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = k
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = v
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let (q, k) = self.apply_rotary(&q, &k, start_offsets)?;
+
+        let y = match &self.paged_attn {
+            Some(paged_attn) => {
+                let ((key_cache, value_cache), input_metadata) = metadata.unwrap();
+                paged_attn.forward(
+                    &q,
+                    &k,
+                    &v,
+                    mask,
+                    Some(key_cache),
+                    Some(value_cache),
+                    input_metadata,
+                    &self.sdpa_params,
+                    None,
+                )?
+            }
+            None => {
+                let (k, v) = kv_cache.append(&k, &v)?;
+
+                Sdpa.run_attention(&q, &k, &v, mask, None, &self.sdpa_params)?
+            }
+        };
+
+        let y = y.transpose(1, 2)?.reshape((b_sz, seq_len, ()))?;
+
+        MatMul.qmethod_matmul(&y.to_dtype(x.dtype())?, &*self.attention_wo)
+    }
+}
+
+/// ChatGLM (ChatGLM2 and later, including GLM-4) with multi-query attention: a few key-value
+/// heads shared by all query heads.
+///
+/// ChatGLM-6B let the prompt tokens attend to each other bidirectionally, but the later models
+/// are trained with causal attention everywhere. The `[gMASK]<sop>` prefix tokens are inserted
+/// by the chat template and are attended to causally like the rest of the prompt.
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    layers: Vec<LayerWeights>,
+    norm: QRmsNorm,
+    output: Arc<dyn QuantMethod>,
+    pub device: Device,
+    pub cache: EitherCache,
+    pub max_seq_len: usize,
+    mapper: Option<Box<dyn DeviceMapper + Send + Sync>>,
+    dtype: DType,
+}
+
+// chatglm `llm` fields:
+// https://github.com/ggerganov/ggml/blob/master/docs/gguf.md#llm
+// NOTE: Types here do not match spec
+pub(crate) struct PropsGGUF {
+    pub head_count: usize,
+    pub head_count_kv: usize,
+    pub block_count: usize,
+    pub embedding_length: usize,
+    pub rms_norm_eps: f32,
+    pub max_seq_len: usize,
+    pub rope_freq_base: f32,
+    pub rope_dim: usize,
+}
+
+impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
+    type Error = anyhow::Error;
+
+    fn try_from(c: ContentMetadata) -> std::result::Result<Self, Self::Error> {
+        c.verify_arch("chatglm")?;
+
+        let required = [
+            "attention.head_count",
+            "attention.head_count_kv",
+            "block_count",
+            "embedding_length",
+            "attention.layer_norm_rms_epsilon",
+        ];
+        c.has_required_keys(&required)?;
+
+        let embed_len = c.get_value::<u32>("embedding_length")? as usize;
+        let head_count = c.get_value::<u32>("attention.head_count")? as usize;
+
+        // NOTE: Values are not aligned with GGUFv3 types
+        // TODO: Normalize value types to spec
+        let props = Self {
+            head_count,
+            head_count_kv: c.get_value::<u32>("attention.head_count_kv")? as usize,
+            block_count: c.get_value::<u32>("block_count")? as usize,
+            embedding_length: embed_len,
+            rms_norm_eps: c.get_value("attention.layer_norm_rms_epsilon")?,
+            max_seq_len: c
+                .get_value::<u64>("context_length")
+                .ok()
+                .unwrap_or(MAX_SEQ_LEN as u64) as usize,
+            rope_freq_base: c.get_value("rope.freq_base").ok().unwrap_or(10_000_f32),
+            // Rotary embeddings cover half of each head by default.
+            rope_dim: c
+                .get_value::<u32>("rope.dimension_count")
+                .ok()
+                .map(|x| x as usize)
+                .unwrap_or(embed_len / head_count / 2),
+        };
+
+        Ok(props)
+    }
+}
+
+impl ModelConfig::FromGGUF for ModelWeights {
+    fn from_gguf<R: std::io::Seek + std::io::Read>(
+        mut ct: Content<'_, R>,
+        device: &Device,
+        mapper: Box<dyn DeviceMapper + Send + Sync>,
+        attention_mechanism: AttentionImplementation,
+        dtype: DType,
+    ) -> Result<Self> {
+        // Parameter extraction from metadata.
+        let metadata = ContentMetadata {
+            path_prefix: "chatglm",
+            metadata: ct.get_metadata(),
+        };
+        let PropsGGUF {
+            head_count,
+            head_count_kv,
+            block_count,
+            embedding_length,
+            rms_norm_eps,
+            max_seq_len,
+            rope_freq_base,
+            rope_dim,
+        } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
+
+        let qtok_embeddings = ct.tensor("token_embd.weight", device)?;
+        let tok_embeddings = qtok_embeddings.dequantize(device)?;
+        let norm = QRmsNorm::new(ct.tensor("output_norm.weight", device)?, rms_norm_eps)?;
+        let output = if !ct.has_tensor("output.weight") {
+            ct.tensor("token_embd.weight", device)?
+        } else {
+            ct.tensor("output.weight", device)?
+        };
+        let mut layers = Vec::with_capacity(block_count);
+
+        let head_dim = embedding_length / head_count;
+
+        let mut ropes = HashMap::new();
+        for layer_idx in 0..block_count {
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+            ropes.insert(
+                device.location(),
+                Arc::new(RotaryEmbedding::new_partial(
+                    rope_freq_base,
+                    rope_dim,
+                    max_seq_len,
+                    device,
+                    false,
+                    dtype,
+                )?),
+            );
+        }
+
+        for layer_idx in NiceProgressBar::<_, 'b'>(
+            0..block_count,
+            "Loading repeating layers",
+            &MultiProgress::new(),
+        ) {
+            let prefix = format!("blk.{layer_idx}");
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+            let rotary = ropes
+                .get(&device.location())
+                .expect("No RoPE for device location!")
+                .clone();
+
+            let fused_qkv = ct.has_tensor(&format!("{prefix}.attn_qkv.weight"));
+            // The projection biases are optional.
+            let mut proj = |name: &str| -> Result<Arc<dyn QuantMethod>> {
+                let weight = ct.tensor(&format!("{prefix}.{name}.weight"), device)?;
+                let bias_name = format!("{prefix}.{name}.bias");
+                let bias = if ct.has_tensor(&bias_name) {
+                    Some(ct.tensor(&bias_name, device)?.dequantize(device)?)
+                } else {
+                    None
+                };
+                gguf_linear(weight, bias)
+            };
+            let qkv = if fused_qkv {
+                QkvProj::Fused(proj("attn_qkv")?)
+            } else {
+                QkvProj::Split {
+                    q: proj("attn_q")?,
+                    k: proj("attn_k")?,
+                    v: proj("attn_v")?,
+                }
+            };
+            let attention_wo = proj("attn_output")?;
+
+            let ffn_gate = if ct.has_tensor(&format!("{prefix}.ffn_gate.weight")) {
+                Some(gguf_linear(
+                    ct.tensor(&format!("{prefix}.ffn_gate.weight"), device)?,
+                    None,
+                )?)
+            } else {
+                None
+            };
+            let mlp = Mlp {
+                ffn_gate,
+                ffn_up: gguf_linear(ct.tensor(&format!("{prefix}.ffn_up.weight"), device)?, None)?,
+                ffn_down: gguf_linear(
+                    ct.tensor(&format!("{prefix}.ffn_down.weight"), device)?,
+                    None,
+                )?,
+            };
+
+            let attention_norm = ct.tensor(&format!("{prefix}.attn_norm.weight"), device)?;
+            let ffn_norm = ct.tensor(&format!("{prefix}.ffn_norm.weight"), device)?;
+            let paged_attn = match &attention_mechanism {
+                AttentionImplementation::Eager => None,
+                AttentionImplementation::PagedAttention => {
+                    Some(PagedAttention::new(head_dim, device, None)?)
+                }
+            };
+            layers.push(LayerWeights {
+                qkv,
+                attention_wo,
+                attention_norm: QRmsNorm::new(attention_norm, rms_norm_eps)?,
+                mlp,
+                ffn_norm: QRmsNorm::new(ffn_norm, rms_norm_eps)?,
+                n_head: head_count,
+                n_kv_head: head_count_kv,
+                head_dim,
+                rope_dim,
+                rotary: rotary.clone(),
+                paged_attn,
+                sdpa_params: SdpaParams {
+                    n_kv_groups: head_count / head_count_kv,
+                    use_flash_attn: false,
+                    softcap: None,
+                    softmax_scale: 1.0 / (head_dim as f32).sqrt(),
+                    sliding_window: None,
+                },
+                dtype,
+            })
+        }
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            layers,
+            norm,
+            output: gguf_linear(output, None)?,
+            device: device.clone(),
+            cache: EitherCache::Normal(NormalCache::new(block_count, max_seq_len)),
+            max_seq_len,
+            mapper: Some(mapper),
+            dtype,
+        })
+    }
+}
+
+impl ModelWeights {
+    pub fn forward(
+        &self,
+        x: &Tensor,
+        start_offsets: &[usize],
+        context_lens: Vec<(usize, usize)>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        let mut layer_in = self.tok_embeddings.forward(x)?;
+        let cache = &mut self.cache.normal().0;
+        let mask = CausalMasker.make_causal_mask_matrix(
+            x,
+            metadata
+                .as_ref()
+                .map(|(_, _)| &start_offsets as &dyn PastKvLenCache)
+                .unwrap_or(cache as &dyn PastKvLenCache),
+            self.dtype,
+            self.layers[0].n_head,
+        )?;
+        let mask = mask.filter(|_| {
+            metadata
+                .as_ref()
+                .map(|(_, meta)| meta.is_first_prompt_chunk)
+                .unwrap_or(true)
+        });
+        for (i, layer) in self.layers.iter().enumerate() {
+            if let Some(ref mapper) = self.mapper {
+                layer_in = mapper.map(layer_in, i)?;
+            }
+            let x = layer_in;
+            let residual = &x;
+            let x = layer.attention_norm.forward(&x)?;
+            let attn = layer.forward_attn(
+                &x,
+                mask.as_ref()
+                    .map(|m| m.to_device(x.device()).unwrap())
+                    .as_ref(),
+                start_offsets,
+                &mut cache[i],
+                metadata
+                    .as_ref()
+                    .map(|(kv_cache, metadata)| (kv_cache[i].clone(), *metadata)),
+            )?;
+            let x = (attn + residual)?;
+
+            // MLP
+            let residual = &x;
+            let x = layer.ffn_norm.forward(&x)?;
+            let x = layer.mlp.forward(&x)?;
+            let x = (x + residual)?;
+            layer_in = x;
+        }
+        let x = self.norm.forward(&layer_in)?;
+        extract_logits(
+            &MatMul.qmethod_matmul(&x.contiguous()?, &*self.output)?,
+            context_lens,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::ModelWeights;
+    use crate::models::quantized_test_utils::{
+        assert_decoding_matches_prompt, assert_finite, last_logits, load, TestGguf,
+    };
+
+    /// Write a GGUF file of a ChatGLM model with two layers and grouped-query attention. GLM-4
+    /// GGUFs fuse the query, key and value projections with their biases in `attn_qkv` and the
+    /// gate and up projections in `ffn_up`, other converters keep them separate.
+    fn chatglm_gguf(fused: bool) -> candle_core::Result<Vec<u8>> {
+        let (vocab, hidden, n_head, n_kv_head, ff) = (16, 32, 4, 2, 64);
+        let kv_dim = hidden / n_head * n_kv_head;
+        let mut gguf = TestGguf::new("chatglm");
+        gguf.u32("block_count", 2)
+            .u32("context_length", 64)
+            .u32("embedding_length", hidden)
+            .u32("attention.head_count", n_head)
+            .u32("attention.head_count_kv", n_kv_head)
+            .f32("attention.layer_norm_rms_epsilon", 1e-5);
+        gguf.linear("token_embd.weight", vocab, hidden)?
+            .vector("output_norm.weight", hidden, 1.)?
+            .linear("output.weight", vocab, hidden)?;
+        for layer in 0..2 {
+            let name = |name: &str| format!("blk.{layer}.{name}");
+            gguf.vector(name("attn_norm.weight"), hidden, 1.)?
+                .vector(name("ffn_norm.weight"), hidden, 1.)?
+                .linear(name("attn_output.weight"), hidden, hidden)?
+                .linear(name("ffn_down.weight"), hidden, ff)?;
+            if fused {
+                let bias = (Tensor::randn(0f32, 1f32, hidden + 2 * kv_dim, &Device::Cpu)? * 0.1)?;
+                gguf.linear(name("attn_qkv.weight"), hidden + 2 * kv_dim, hidden)?
+                    .dense(name("attn_qkv.bias"), &bias)?
+                    .linear(name("ffn_up.weight"), 2 * ff, hidden)?;
+            } else {
+                gguf.linear(name("attn_q.weight"), hidden, hidden)?
+                    .linear(name("attn_k.weight"), kv_dim, hidden)?
+                    .linear(name("attn_v.weight"), kv_dim, hidden)?
+                    .linear(name("ffn_gate.weight"), ff, hidden)?
+                    .linear(name("ffn_up.weight"), ff, hidden)?;
+            }
+        }
+        gguf.build()
+    }
+
+    fn logits(model: &ModelWeights, ids: &[u32], offset: usize) -> candle_core::Result<Vec<f32>> {
+        let input_ids = Tensor::new(ids, &Device::Cpu)?.unsqueeze(0)?;
+        last_logits(model.forward(&input_ids, &[offset], vec![(ids.len() - 1, 1)], None)?)
+    }
+
+    #[test]
+    fn forward() -> candle_core::Result<()> {
+        let model = load::<ModelWeights>(&chatglm_gguf(true)?)?;
+        assert_eq!(model.max_seq_len, 64);
+
+        let out = logits(&model, &[1, 5, 7], 0)?;
+        assert_eq!(out.len(), 16);
+        assert_finite(&out);
+
+        // Decoding a token reuses the KV cache of the prompt.
+        assert_eq!(logits(&model, &[3], 3)?.len(), 16);
+        assert_eq!(model.cache.normal().0[0].current_seq_len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn decoding_matches_prompt() -> candle_core::Result<()> {
+        // Only half of each head is rotated, the position of the decoded token comes from its
+        // offset.
+        for fused in [true, false] {
+            assert_decoding_matches_prompt(&chatglm_gguf(fused)?, &[&[1, 5, 7], &[3]], logits)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,22 @@
+//! Layers shared by the quantized architectures loaded from GGUF files.
+
+use std::sync::Arc;
+
+use candle_core::{quantized::QTensor, Result, Tensor};
+use candle_nn::LayerNorm;
+use mistralrs_quant::{GgufMatMul, QuantMethod, QuantMethodConfig};
+
+/// A linear layer running the matmul on the quantized weight, with an optional bias.
+pub(crate) fn gguf_linear(q_weight: QTensor, b: Option<Tensor>) -> Result<Arc<dyn QuantMethod>> {
+    Ok(Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+        q_weight: Arc::new(q_weight),
+        b,
+    })?))
+}
+
+/// A layer norm with the dequantized weight and bias.
+pub(crate) fn layer_norm(w: QTensor, b: QTensor, eps: f64) -> Result<LayerNorm> {
+    let w = w.dequantize(&w.device())?;
+    let b = b.dequantize(&b.device())?;
+    Ok(LayerNorm::new(w, b, eps))
+}
@@ -0,0 +1,480 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use candle_core::{DType, Device, Module, Result, Tensor, D};
+use candle_nn::{Embedding, LayerNorm};
+use indicatif::MultiProgress;
+use mistralrs_quant::QuantMethod;
+
+use crate::attention::SdpaParams;
+use crate::device_map::DeviceMapper;
+use crate::gguf::Content;
+use crate::layers::{CausalMasker, MatMul, RotaryEmbedding, Sdpa};
+use crate::layers_masker::PastKvLenCache;
+use crate::models::quantized_common::{gguf_linear, layer_norm};
+use crate::paged_attention::{AttentionImplementation, PagedAttention};
+use crate::pipeline::text_models_inputs_processor::PagedAttentionInputMetadata;
+use crate::pipeline::{extract_logits, EitherCache, KvCache, NormalCache};
+use crate::utils::gguf_metadata::ContentMetadata;
+use crate::utils::model_config as ModelConfig;
+use crate::utils::progress::NiceProgressBar;
+const MAX_SEQ_LEN: u32 = 2048;
+
+struct Mlp {
+    ffn_up: Arc<dyn QuantMethod>,
+    ffn_down: Arc<dyn QuantMethod>,
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        MatMul.qmethod_matmul(
+            &MatMul
+                .qmethod_matmul(xs, &*self.ffn_up)?
+                .apply(&candle_nn::Activation::Gelu)?,
+            &*self.ffn_down,
+        )
+    }
+}
+
+struct LayerWeights {
+    attention_qkv: Arc<dyn QuantMethod>,
+    attention_wo: Arc<dyn QuantMethod>,
+    attention_norm: LayerNorm,
+    /// Separate norm of the MLP input, in the architecture of Falcon-40B and larger. Otherwise
+    /// the attention and the MLP share `attention_norm`.
+    ffn_norm: Option<LayerNorm>,
+    mlp: Mlp,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    rotary: Arc<RotaryEmbedding>,
+    paged_attn: Option<PagedAttention>,
+    sdpa_params: SdpaParams,
+    dtype: DType,
+}
+
+impl LayerWeights {
+    fn forward_attn(
+        &self,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        start_offsets: &[usize],
+        kv_cache: &mut KvCache,
+        metadata: Option<((Tensor, Tensor), &PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = x.dims3()?;
+
+        // The fused projection is laid out as `[q, k, v]`, with a single key and value head for
+        // the multi-query attention of Falcon-7B.
+        let qkv = MatMul
+            .qmethod_matmul(x, &*self.attention_qkv)?
+            .to_dtype(self.dtype)?;
+        let q_size = self.n_head * self.head_dim;
+        let kv_size = self.n_kv_head * self.head_dim;
+        let q = qkv
+            .narrow(D::Minus1, 0, q_size)?
+            .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let k = qkv
+            .narrow(D::Minus1, q_size, kv_size)?
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let v = qkv
+            .narrow(D::Minus1, q_size + kv_size, kv_size)?
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let (q, k) = self.rotary.forward(&q, &k, start_offsets)?;
+
+        let y = match &self.paged_attn {
+            Some(paged_attn) => {
+                let ((key_cache, value_cache), input_metadata) = metadata.unwrap();
+                paged_attn.forward(
+                    &q,
+                    &k,
+                    &v,
+                    mask,
+                    Some(key_cache),
+                    Some(value_cache),
+                    input_metadata,
+                    &self.sdpa_params,
+                    None,
+                )?
+            }
+            None => {
+                let (k, v) = kv_cache.append(&k, &v)?;
+
+                Sdpa.run_attention(&q, &k, &v, mask, None, &self.sdpa_params)?
+            }
+        };
+
+        let y = if mask.is_some() {
+            y.transpose(1, 2)?.reshape((b_sz, seq_len, ()))?
+        } else {
+            y.reshape((b_sz, seq_len, ()))?
+        };
+
+        MatMul.qmethod_matmul(&y.to_dtype(x.dtype())?, &*self.attention_wo)
+    }
+}
+
+/// Falcon, with the attention and the GELU MLP computed in parallel from the same residual stream,
+/// rotary position embeddings and multi-query (7B) or grouped-query (40B and larger) attention.
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    layers: Vec<LayerWeights>,
+    norm: LayerNorm,
+    output: Arc<dyn QuantMethod>,
+    pub device: Device,
+    pub cache: EitherCache,
+    pub max_seq_len: usize,
+    mapper: Option<Box<dyn DeviceMapper + Send + Sync>>,
+    dtype: DType,
+}
+
+// falcon `llm` fields:
+// https://github.com/ggerganov/ggml/blob/master/docs/gguf.md#llm
+// NOTE: Types here do not match spec
+pub(crate) struct PropsGGUF {
+    pub head_count: usize,
+    pub head_count_kv: usize,
+    pub block_count: usize,
+    pub embedding_length: usize,
+    pub layer_norm_epsilon: f64,
+    pub max_seq_len: usize,
+    pub rope_freq_base: f32,
+}
+
+impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
+    type Error = anyhow::Error;
+
+    fn try_from(c: ContentMetadata) -> std::result::Result<Self, Self::Error> {
+        c.verify_arch("falcon")?;
+
+        let required = [
+            "attention.head_count",
+            "block_count",
+            "embedding_length",
+            "attention.layer_norm_epsilon",
+        ];
+        c.has_required_keys(&required)?;
+
+        let head_count = c.get_value::<u32>("attention.head_count")? as usize;
+
+        // NOTE: Values are not aligned with GGUFv3 types
+        // TODO: Normalize value types to spec
+        let props = Self {
+            head_count,
+            head_count_kv: c
+                .get_value::<u32>("attention.head_count_kv")
+                .ok()
+                .map(|x| x as usize)
+                .unwrap_or(head_count),
+            block_count: c.get_value::<u32>("block_count")? as usize,
+            embedding_length: c.get_value::<u32>("embedding_length")? as usize,
+            layer_norm_epsilon: c.get_value::<f32>("attention.layer_norm_epsilon")? as f64,
+            max_seq_len: c
+                .get_value::<u64>("context_length")
+                .ok()
+                .unwrap_or(MAX_SEQ_LEN as u64) as usize,
+            rope_freq_base: c.get_value("rope.freq_base").ok().unwrap_or(10_000_f32),
+        };
+
+        Ok(props)
+    }
+}
+
+impl ModelConfig::FromGGUF for ModelWeights {
+    fn from_gguf<R: std::io::Seek + std::io::Read>(
+        mut ct: Content<'_, R>,
+        device: &Device,
+        mapper: Box<dyn DeviceMapper + Send + Sync>,
+        attention_mechanism: AttentionImplementation,
+        dtype: DType,
+    ) -> Result<Self> {
+        // Parameter extraction from metadata.
+        let metadata = ContentMetadata {
+            path_prefix: "falcon",
+            metadata: ct.get_metadata(),
+        };
+        let PropsGGUF {
+            head_count,
+            head_count_kv,
+            block_count,
+            embedding_length,
+            layer_norm_epsilon,
+            max_seq_len,
+            rope_freq_base,
+        } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
+
+        let qtok_embeddings = ct.tensor("token_embd.weight", device)?;
+        let tok_embeddings = qtok_embeddings.dequantize(device)?;
+        let norm = layer_norm(
+            ct.tensor("output_norm.weight", device)?,
+            ct.tensor("output_norm.bias", device)?,
+            layer_norm_epsilon,
+        )?;
+        let output = if !ct.has_tensor("output.weight") {
+            ct.tensor("token_embd.weight", device)?
+        } else {
+            ct.tensor("output.weight", device)?
+        };
+        let mut layers = Vec::with_capacity(block_count);
+
+        // Falcon-7B has multi-query attention, with one KV head, and Falcon-40B grouped-query
+        // attention. The fused QKV projection is laid out by the head counts of the metadata.
+        if head_count_kv == 0 || head_count % head_count_kv != 0 {
+            candle_core::bail!(
+                "Falcon has {head_count} attention heads, which are not grouped evenly over {head_count_kv} KV heads."
+            );
+        }
+        let head_dim = embedding_length / head_count;
+        let qkv_dim = (head_count + 2 * head_count_kv) * head_dim;
+
+        let mut ropes = HashMap::new();
+        for layer_idx in 0..block_count {
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+            ropes.insert(
+                device.location(),
+                Arc::new(RotaryEmbedding::new(
+                    rope_freq_base,
+                    head_dim,
+                    max_seq_len,
+                    device,
+                    true,
+                    dtype,
+                )?),
+            );
+        }
+
+        for layer_idx in NiceProgressBar::<_, 'b'>(
+            0..block_count,
+            "Loading repeating layers",
+            &MultiProgress::new(),
+        ) {
+            let prefix = format!("blk.{layer_idx}");
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+            let rotary = ropes
+                .get(&device.location())
+                .expect("No RoPE for device location!")
+                .clone();
+
+            let qkv_dims = ct
+                .tensor_info(&format!("{prefix}.attn_qkv.weight"))?
+                .shape
+                .dims()
+                .to_vec();
+            if qkv_dims.first() != Some(&qkv_dim) {
+                candle_core::bail!(
+                    "Falcon layer {layer_idx} has a fused QKV projection of shape {qkv_dims:?}, expected {qkv_dim} outputs for {head_count} heads and {head_count_kv} KV heads."
+                );
+            }
+
+            // Falcon has no biases in the projections, but some converters write zero biases.
+            let mut proj = |name: &str| -> Result<Arc<dyn QuantMethod>> {
+                let weight = ct.tensor(&format!("{prefix}.{name}.weight"), device)?;
+                let bias_name = format!("{prefix}.{name}.bias");
+                let bias = if ct.has_tensor(&bias_name) {
+                    Some(ct.tensor(&bias_name, device)?.dequantize(device)?)
+                } else {
+                    None
+                };
+                gguf_linear(weight, bias)
+            };
+            let attention_qkv = proj("attn_qkv")?;
+            let attention_wo = proj("attn_output")?;
+            let mlp = Mlp {
+                ffn_up: proj("ffn_up")?,
+                ffn_down: proj("ffn_down")?,
+            };
+
+            let mut norm = |name: &str| -> Result<LayerNorm> {
+                layer_norm(
+                    ct.tensor(&format!("{prefix}.{name}.weight"), device)?,
+                    ct.tensor(&format!("{prefix}.{name}.bias"), device)?,
+                    layer_norm_epsilon,
+                )
+            };
+            let attention_norm = norm("attn_norm")?;
+            let ffn_norm = if ct.has_tensor(&format!("{prefix}.attn_norm_2.weight")) {
+                Some(norm("attn_norm_2")?)
+            } else {
+                None
+            };
+            let paged_attn = match &attention_mechanism {
+                AttentionImplementation::Eager => None,
+                AttentionImplementation::PagedAttention => {
+                    Some(PagedAttention::new(head_dim, device, None)?)
+                }
+            };
+            layers.push(LayerWeights {
+                attention_qkv,
+                attention_wo,
+                attention_norm,
+                ffn_norm,
+                mlp,
+                n_head: head_count,
+                n_kv_head: head_count_kv,
+                head_dim,
+                rotary,
+                paged_attn,
+                sdpa_params: SdpaParams {
+                    n_kv_groups: head_count / head_count_kv,
+                    use_flash_attn: false,
+                    softcap: None,
+                    softmax_scale: 1.0 / (head_dim as f32).sqrt(),
+                    sliding_window: None,
+                },
+                dtype,
+            })
+        }
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            layers,
+            norm,
+            output: gguf_linear(output, None)?,
+            device: device.clone(),
+            cache: EitherCache::Normal(NormalCache::new(block_count, max_seq_len)),
+            max_seq_len,
+            mapper: Some(mapper),
+            dtype,
+        })
+    }
+}
+
+impl ModelWeights {
+    pub fn forward(
+        &self,
+        x: &Tensor,
+        start_offsets: &[usize],
+        context_lens: Vec<(usize, usize)>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        let mut layer_in = self.tok_embeddings.forward(x)?;
+        let cache = &mut self.cache.normal().0;
+        let mask = CausalMasker.make_causal_mask_matrix(
+            x,
+            metadata
+                .as_ref()
+                .map(|(_, _)| &start_offsets as &dyn PastKvLenCache)
+                .unwrap_or(cache as &dyn PastKvLenCache),
+            self.dtype,
+            self.layers[0].n_head,
+        )?;
+        let mask = mask.filter(|_| {
+            metadata
+                .as_ref()
+                .map(|(_, meta)| meta.is_first_prompt_chunk)
+                .unwrap_or(true)
+        });
+        for (i, layer) in self.layers.iter().enumerate() {
+            if let Some(ref mapper) = self.mapper {
+                layer_in = mapper.map(layer_in, i)?;
+            }
+            let residual = &layer_in;
+            let attn_in = layer_in.apply(&layer.attention_norm)?;
+            let mlp_in = match &layer.ffn_norm {
+                Some(ffn_norm) => layer_in.apply(ffn_norm)?,
+                None => attn_in.clone(),
+            };
+            let attn = layer.forward_attn(
+                &attn_in,
+                mask.as_ref()
+                    .map(|m| m.to_device(attn_in.device()).unwrap())
+                    .as_ref(),
+                start_offsets,
+                &mut cache[i],
+                metadata
+                    .as_ref()
+                    .map(|(kv_cache, metadata)| (kv_cache[i].clone(), *metadata)),
+            )?;
+            let mlp = layer.mlp.forward(&mlp_in)?;
+            layer_in = ((attn + mlp)? + residual)?;
+        }
+        let x = layer_in.apply(&self.norm)?;
+        extract_logits(
+            &MatMul.qmethod_matmul(&x.contiguous()?, &*self.output)?,
+            context_lens,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::ModelWeights;
+    use crate::models::quantized_test_utils::{
+        assert_decoding_matches_prompt, assert_finite, last_logits, load, TestGguf,
+    };
+
+    /// Write a GGUF file with two layers and tied embeddings. Falcon-7B has multi-query attention
+    /// and shares the layer norm of the attention and the MLP, Falcon-40B has grouped-query
+    /// attention and a separate `attn_norm_2` for the MLP.
+    fn falcon_gguf(n_kv_head: usize, separate_ffn_norm: bool) -> candle_core::Result<Vec<u8>> {
+        let (vocab, hidden, n_head, head_dim) = (16, 32, 4, 8);
+        let mut gguf = TestGguf::new("falcon");
+        gguf.u32("block_count", 2)
+            .u32("context_length", 64)
+            .u32("embedding_length", hidden)
+            .u32("attention.head_count", n_head)
+            .u32("attention.head_count_kv", n_kv_head)
+            .f32("attention.layer_norm_epsilon", 1e-5);
+        gguf.linear("token_embd.weight", vocab, hidden)?
+            .layer_norm("output_norm", hidden)?;
+        for layer in 0..2 {
+            gguf.layer_norm(&format!("blk.{layer}.attn_norm"), hidden)?;
+            if separate_ffn_norm {
+                gguf.layer_norm(&format!("blk.{layer}.attn_norm_2"), hidden)?;
+            }
+            let qkv_dim = (n_head + 2 * n_kv_head) * head_dim;
+            gguf.linear(format!("blk.{layer}.attn_qkv.weight"), qkv_dim, hidden)?
+                .linear(format!("blk.{layer}.attn_output.weight"), hidden, hidden)?
+                .linear(format!("blk.{layer}.ffn_up.weight"), 4 * hidden, hidden)?
+                .linear(format!("blk.{layer}.ffn_down.weight"), hidden, 4 * hidden)?;
+        }
+        gguf.build()
+    }
+
+    fn logits(model: &ModelWeights, ids: &[u32], offset: usize) -> candle_core::Result<Vec<f32>> {
+        let input_ids = Tensor::new(ids, &Device::Cpu)?.unsqueeze(0)?;
+        last_logits(model.forward(&input_ids, &[offset], vec![(ids.len() - 1, 1)], None)?)
+    }
+
+    #[test]
+    fn forward() -> candle_core::Result<()> {
+        let model = load::<ModelWeights>(&falcon_gguf(1, false)?)?;
+        assert_eq!(model.max_seq_len, 64);
+
+        let out = logits(&model, &[1, 5, 7], 0)?;
+        assert_eq!(out.len(), 16);
+        assert_finite(&out);
+
+        // Decoding a token reuses the KV cache of the prompt.
+        assert_eq!(logits(&model, &[3], 3)?.len(), 16);
+        assert_eq!(model.cache.normal().0[0].current_seq_len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn decoding_matches_prompt() -> candle_core::Result<()> {
+        for (n_kv_head, separate_ffn_norm) in [(1, false), (2, true)] {
+            let file = falcon_gguf(n_kv_head, separate_ffn_norm)?;
+            assert_decoding_matches_prompt(&file, &[&[1, 5, 7], &[3]], logits)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_uneven_kv_heads() -> candle_core::Result<()> {
+        // 4 attention heads cannot share 3 KV heads.
+        let err = load::<ModelWeights>(&falcon_gguf(3, false)?).err().unwrap();
+        assert!(err.to_string().contains("3 KV heads"), "{err}");
+        Ok(())
+    }
+}
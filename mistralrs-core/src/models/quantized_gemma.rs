@@ -0,0 +1,509 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::{Embedding, Module};
+use indicatif::MultiProgress;
+use mistralrs_quant::{GgufMatMul, QuantMethod, QuantMethodConfig};
+
+use crate::attention::SdpaParams;
+use crate::device_map::DeviceMapper;
+use crate::gguf::Content;
+use crate::layers::{CausalMasker, MatMul, QRmsNorm, RotaryEmbedding, ScaledEmbedding, Sdpa};
+use crate::layers_masker::PastKvLenCache;
+use crate::paged_attention::{AttentionImplementation, PagedAttention};
+use crate::pipeline::text_models_inputs_processor::PagedAttentionInputMetadata;
+use crate::pipeline::{extract_logits, EitherCache, KvCache, NormalCache};
+use crate::utils::gguf_metadata::ContentMetadata;
+use crate::utils::model_config as ModelConfig;
+use crate::utils::progress::NiceProgressBar;
+const MAX_SEQ_LEN: u32 = 8192;
+
+/// GeGLU feed forward: the tanh approximation of GELU on the gate, times the up projection.
+struct Mlp {
+    feed_forward_w1: Arc<dyn QuantMethod>,
+    feed_forward_w2: Arc<dyn QuantMethod>,
+    feed_forward_w3: Arc<dyn QuantMethod>,
+}
+
+impl Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let w1 = MatMul.qmethod_matmul(xs, &*self.feed_forward_w1)?;
+        let w3 = MatMul.qmethod_matmul(xs, &*self.feed_forward_w3)?;
+        let y = &(w1.gelu()? * w3)?;
+        MatMul.qmethod_matmul(y, &*self.feed_forward_w2)
+    }
+}
+
+struct LayerWeights {
+    attention_wq: Arc<dyn QuantMethod>,
+    attention_wk: Arc<dyn QuantMethod>,
+    attention_wv: Arc<dyn QuantMethod>,
+    attention_wo: Arc<dyn QuantMethod>,
+    attention_norm: QRmsNorm,
+    mlp: Mlp,
+    ffn_norm: QRmsNorm,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    rotary: Arc<RotaryEmbedding>,
+    paged_attn: Option<PagedAttention>,
+    sdpa_params: SdpaParams,
+    dtype: DType,
+}
+
+impl LayerWeights {
+    fn forward_attn(
+        &self,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        start_offsets: &[usize],
+        kv_cache: &mut KvCache,
+        metadata: Option<((Tensor, Tensor), &PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = x.dims3()?;
+
+        let q = MatMul
+            .qmethod_matmul(x, &*self.attention_wq)?
+            .to_dtype(self.dtype)?;
+        let k = MatMul
+            .qmethod_matmul(x, &*self.attention_wk)?
+            .to_dtype(self.dtype)?;
+        let v = MatMul
+            .qmethod_matmul(x, &*self.attention_wv)?
+            .to_dtype(self.dtype)?;
+
+        let (q, k, v) = if seq_len != 1 {
+            let q = q
+                .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+                .transpose(1, 2)?;
+            let k = k
+                .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+                .transpose(1, 2)?;
+            let v = v
+                .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+                .transpose(1, 2)?;
+            (q, k, v)
+        } else {
+            let q = q.reshape((b_sz, self.n_head, seq_len, self.head_dim))?;
+            let k = k.reshape((b_sz, self.n_kv_head, seq_len, self.head_dim))?;
+            let v = v.reshape((b_sz, self.n_kv_head, seq_len, self.head_dim))?;
+            (q, k, v)
+        };
+
+        let (q, k) = self.rotary.forward(&q, &k, start_offsets)?;
+
+        let y = match &self.paged_attn {
+            Some(paged_attn) => {
+                let ((key_cache, value_cache), input_metadata) = metadata.unwrap();
+                paged_attn.forward(
+                    &q,
+                    &k,
+                    &v,
+                    mask,
+                    Some(key_cache),
+                    Some(value_cache),
+                    input_metadata,
+                    &self.sdpa_params,
+                    None,
+                )?
+            }
+            None => {
+                let (k, v) = kv_cache.append(&k, &v)?;
+
+                Sdpa.run_attention(&q, &k, &v, mask, None, &self.sdpa_params)?
+            }
+        };
+
+        let y = if mask.is_some() {
+            y.transpose(1, 2)?.reshape((b_sz, seq_len, ()))?
+        } else {
+            y.reshape((b_sz, seq_len, ()))?
+        };
+
+        let y = MatMul.qmethod_matmul(&y.to_dtype(x.dtype())?, &*self.attention_wo)?;
+        Ok(y)
+    }
+}
+
+pub struct ModelWeights {
+    tok_embeddings: ScaledEmbedding,
+    layers: Vec<LayerWeights>,
+    norm: QRmsNorm,
+    output: Arc<dyn QuantMethod>,
+    pub device: Device,
+    pub cache: EitherCache,
+    pub max_seq_len: usize,
+    mapper: Option<Box<dyn DeviceMapper + Send + Sync>>,
+    dtype: DType,
+}
+
+// gemma `llm` fields:
+// https://github.com/ggerganov/ggml/blob/master/docs/gguf.md#llm
+// NOTE: Types here do not match spec
+pub(crate) struct PropsGGUF {
+    pub head_count: usize,
+    pub head_count_kv: usize,
+    pub block_count: usize,
+    pub embedding_length: usize,
+    pub rms_norm_eps: f32,
+    pub max_seq_len: usize,
+    pub rope_freq_base: f32,
+    pub key_length: usize,
+    pub value_length: usize,
+}
+
+impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
+    type Error = anyhow::Error;
+
+    fn try_from(c: ContentMetadata) -> std::result::Result<Self, Self::Error> {
+        c.verify_arch("gemma")?;
+
+        let required = [
+            "attention.head_count",
+            "attention.head_count_kv",
+            "block_count",
+            "embedding_length",
+            "attention.layer_norm_rms_epsilon",
+        ];
+        c.has_required_keys(&required)?;
+
+        let embed_len = c.get_value::<u32>("embedding_length")? as usize;
+        let head_count = c.get_value::<u32>("attention.head_count")? as usize;
+
+        // NOTE: Values are not aligned with GGUFv3 types
+        // TODO: Normalize value types to spec
+        let props = Self {
+            head_count,
+            head_count_kv: c.get_value::<u32>("attention.head_count_kv")? as usize,
+            block_count: c.get_value::<u32>("block_count")? as usize,
+            embedding_length: embed_len,
+            rms_norm_eps: c.get_value("attention.layer_norm_rms_epsilon")?,
+            max_seq_len: c
+                .get_value::<u64>("context_length")
+                .ok()
+                .unwrap_or(MAX_SEQ_LEN as u64) as usize,
+            rope_freq_base: c.get_value("rope.freq_base").ok().unwrap_or(10_000_f32),
+            // The head dim of Gemma 7b is not the embedding length divided by the head count.
+            key_length: c
+                .get_value::<u32>("attention.key_length")
+                .ok()
+                .map(|x| x as usize)
+                .unwrap_or(embed_len / head_count),
+            value_length: c
+                .get_value::<u32>("attention.value_length")
+                .ok()
+                .map(|x| x as usize)
+                .unwrap_or(embed_len / head_count),
+        };
+
+        Ok(props)
+    }
+}
+
+impl ModelConfig::FromGGUF for ModelWeights {
+    fn from_gguf<R: std::io::Seek + std::io::Read>(
+        mut ct: Content<'_, R>,
+        device: &Device,
+        mapper: Box<dyn DeviceMapper + Send + Sync>,
+        attention_mechanism: AttentionImplementation,
+        dtype: DType,
+    ) -> Result<Self> {
+        // Parameter extraction from metadata.
+        let metadata = ContentMetadata {
+            path_prefix: "gemma",
+            metadata: ct.get_metadata(),
+        };
+        let PropsGGUF {
+            head_count,
+            head_count_kv,
+            block_count,
+            embedding_length,
+            rms_norm_eps,
+            max_seq_len,
+            rope_freq_base,
+            key_length,
+            value_length,
+        } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
+
+        let qtok_embeddings = ct.tensor("token_embd.weight", device)?;
+        let tok_embeddings = qtok_embeddings.dequantize(device)?;
+        let norm = QRmsNorm::new(ct.tensor("output_norm.weight", device)?, rms_norm_eps)?;
+        let output = if !ct.has_tensor("output.weight") {
+            ct.tensor("token_embd.weight", device)?
+        } else {
+            ct.tensor("output.weight", device)?
+        };
+        let mut layers = Vec::with_capacity(block_count);
+
+        let head_dim = key_length;
+        if key_length != value_length {
+            candle_core::bail!(
+                "Expected key_length == value_length, got {key_length} != {value_length}"
+            );
+        }
+
+        let mut ropes = HashMap::new();
+        for layer_idx in 0..block_count {
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+            ropes.insert(
+                device.location(),
+                Arc::new(RotaryEmbedding::new(
+                    rope_freq_base,
+                    head_dim,
+                    max_seq_len,
+                    device,
+                    true,
+                    dtype,
+                )?),
+            );
+        }
+
+        for layer_idx in NiceProgressBar::<_, 'b'>(
+            0..block_count,
+            "Loading repeating layers",
+            &MultiProgress::new(),
+        ) {
+            let prefix = format!("blk.{layer_idx}");
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+            let rotary = ropes
+                .get(&device.location())
+                .expect("No RoPE for device location!")
+                .clone();
+
+            let attention_wq = ct.tensor(&format!("{prefix}.attn_q.weight"), device)?;
+            let attention_wk = ct.tensor(&format!("{prefix}.attn_k.weight"), device)?;
+            let attention_wv = ct.tensor(&format!("{prefix}.attn_v.weight"), device)?;
+            let attention_wo = ct.tensor(&format!("{prefix}.attn_output.weight"), device)?;
+
+            let feed_forward_w1 = ct.tensor(&format!("{prefix}.ffn_gate.weight"), device)?;
+            let feed_forward_w2 = ct.tensor(&format!("{prefix}.ffn_down.weight"), device)?;
+            let feed_forward_w3 = ct.tensor(&format!("{prefix}.ffn_up.weight"), device)?;
+            let mlp = Mlp {
+                feed_forward_w1: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                    q_weight: Arc::new(feed_forward_w1),
+                    b: None,
+                })?),
+                feed_forward_w2: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                    q_weight: Arc::new(feed_forward_w2),
+                    b: None,
+                })?),
+                feed_forward_w3: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                    q_weight: Arc::new(feed_forward_w3),
+                    b: None,
+                })?),
+            };
+
+            // The Gemma norms have an offset of 1, which is already added to the GGUF weights.
+            let attention_norm = QRmsNorm::new(
+                ct.tensor(&format!("{prefix}.attn_norm.weight"), device)?,
+                rms_norm_eps,
+            )?;
+            let ffn_norm = QRmsNorm::new(
+                ct.tensor(&format!("{prefix}.ffn_norm.weight"), device)?,
+                rms_norm_eps,
+            )?;
+            let paged_attn = match &attention_mechanism {
+                AttentionImplementation::Eager => None,
+                AttentionImplementation::PagedAttention => {
+                    Some(PagedAttention::new(head_dim, device, None)?)
+                }
+            };
+            layers.push(LayerWeights {
+                attention_wq: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                    q_weight: Arc::new(attention_wq),
+                    b: None,
+                })?),
+                attention_wk: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                    q_weight: Arc::new(attention_wk),
+                    b: None,
+                })?),
+                attention_wv: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                    q_weight: Arc::new(attention_wv),
+                    b: None,
+                })?),
+                attention_wo: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                    q_weight: Arc::new(attention_wo),
+                    b: None,
+                })?),
+                attention_norm,
+                mlp,
+                ffn_norm,
+                n_head: head_count,
+                n_kv_head: head_count_kv,
+                head_dim,
+                rotary,
+                paged_attn,
+                sdpa_params: SdpaParams {
+                    n_kv_groups: head_count / head_count_kv,
+                    use_flash_attn: false,
+                    softcap: None,
+                    softmax_scale: 1.0 / (head_dim as f32).sqrt(),
+                    sliding_window: None,
+                },
+                dtype,
+            })
+        }
+
+        Ok(Self {
+            tok_embeddings: ScaledEmbedding::new(
+                (embedding_length as f64).sqrt(),
+                Embedding::new(tok_embeddings, embedding_length),
+            ),
+            layers,
+            norm,
+            output: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                q_weight: Arc::new(output),
+                b: None,
+            })?),
+            device: device.clone(),
+            cache: EitherCache::Normal(NormalCache::new(block_count, max_seq_len)),
+            max_seq_len,
+            mapper: Some(mapper),
+            dtype,
+        })
+    }
+}
+
+impl ModelWeights {
+    pub fn forward(
+        &self,
+        x: &Tensor,
+        start_offsets: &[usize],
+        context_lens: Vec<(usize, usize)>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        let mut layer_in = self.tok_embeddings.forward(x)?;
+        let cache = &mut self.cache.normal().0;
+        let mask = CausalMasker.make_causal_mask_matrix(
+            x,
+            metadata
+                .as_ref()
+                .map(|(_, _)| &start_offsets as &dyn PastKvLenCache)
+                .unwrap_or(cache as &dyn PastKvLenCache),
+            self.dtype,
+            self.layers[0].n_head,
+        )?;
+        // PagedAttention prompt chunking
+        let mask = mask.filter(|_| {
+            metadata
+                .as_ref()
+                .map(|(_, meta)| meta.is_first_prompt_chunk)
+                .unwrap_or(true)
+        });
+        for (i, layer) in self.layers.iter().enumerate() {
+            if let Some(ref mapper) = self.mapper {
+                layer_in = mapper.map(layer_in, i)?;
+            }
+            let x = layer_in;
+            let residual = &x;
+            let x = layer.attention_norm.forward(&x)?;
+            let attn = layer.forward_attn(
+                &x,
+                mask.as_ref()
+                    .map(|m| m.to_device(x.device()).unwrap())
+                    .as_ref(),
+                start_offsets,
+                &mut cache[i],
+                metadata
+                    .as_ref()
+                    .map(|(kv_cache, metadata)| (kv_cache[i].clone(), *metadata)),
+            )?;
+            let x = (attn + residual)?;
+
+            // MLP
+            let residual = &x;
+            let x = layer.ffn_norm.forward(&x)?;
+            let x = layer.mlp.forward(&x)?;
+            let x = (x + residual)?;
+            layer_in = x;
+        }
+        let x = self.norm.forward(&layer_in)?;
+        extract_logits(
+            &MatMul.qmethod_matmul(&x.contiguous()?, &*self.output)?,
+            context_lens,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::ModelWeights;
+    use crate::models::quantized_test_utils::{
+        assert_decoding_matches_prompt, assert_finite, last_logits, load, TestGguf,
+    };
+
+    /// Write a GGUF file of a Gemma model with two layers, a head dim larger than the embedding
+    /// length divided by the head count as in Gemma 7b, and tied embeddings.
+    fn gemma_gguf() -> candle_core::Result<Vec<u8>> {
+        let (vocab, hidden, n_head, n_kv_head, head_dim) = (16, 32, 4, 2, 16);
+        let mut gguf = TestGguf::new("gemma");
+        gguf.u32("context_length", 512)
+            .u32("block_count", 2)
+            .u32("embedding_length", hidden)
+            .u32("attention.head_count", n_head)
+            .u32("attention.head_count_kv", n_kv_head)
+            .u32("attention.key_length", head_dim)
+            .u32("attention.value_length", head_dim)
+            .f32("attention.layer_norm_rms_epsilon", 1e-6);
+        gguf.linear("token_embd.weight", vocab, hidden)?;
+        gguf.vector("output_norm.weight", hidden, 1.)?;
+        for layer in 0..2 {
+            gguf.vector(format!("blk.{layer}.attn_norm.weight"), hidden, 1.)?
+                .vector(format!("blk.{layer}.ffn_norm.weight"), hidden, 1.)?
+                .linear(
+                    format!("blk.{layer}.attn_q.weight"),
+                    n_head * head_dim,
+                    hidden,
+                )?
+                .linear(
+                    format!("blk.{layer}.attn_k.weight"),
+                    n_kv_head * head_dim,
+                    hidden,
+                )?
+                .linear(
+                    format!("blk.{layer}.attn_v.weight"),
+                    n_kv_head * head_dim,
+                    hidden,
+                )?
+                .linear(
+                    format!("blk.{layer}.attn_output.weight"),
+                    hidden,
+                    n_head * head_dim,
+                )?
+                .linear(format!("blk.{layer}.ffn_gate.weight"), 2 * hidden, hidden)?
+                .linear(format!("blk.{layer}.ffn_up.weight"), 2 * hidden, hidden)?
+                .linear(format!("blk.{layer}.ffn_down.weight"), hidden, 2 * hidden)?;
+        }
+        gguf.build()
+    }
+
+    fn logits(model: &ModelWeights, ids: &[u32], offset: usize) -> candle_core::Result<Vec<f32>> {
+        let input_ids = Tensor::new(ids, &Device::Cpu)?.unsqueeze(0)?;
+        last_logits(model.forward(&input_ids, &[offset], vec![(ids.len() - 1, 1)], None)?)
+    }
+
+    #[test]
+    fn forward() -> candle_core::Result<()> {
+        let model = load::<ModelWeights>(&gemma_gguf()?)?;
+        assert_eq!(model.max_seq_len, 512);
+
+        let out = logits(&model, &[1, 5, 7], 0)?;
+        assert_eq!(out.len(), 16);
+        assert_finite(&out);
+
+        // Decoding a token reuses the KV cache of the prompt.
+        assert_eq!(logits(&model, &[3], 3)?.len(), 16);
+        assert_eq!(model.cache.normal().0[0].current_seq_len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn decoding_matches_prompt() -> candle_core::Result<()> {
+        assert_decoding_matches_prompt(&gemma_gguf()?, &[&[1, 5, 7], &[3]], logits)
+    }
+}
@@ -0,0 +1,593 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::{Embedding, Module};
+use indicatif::MultiProgress;
+use mistralrs_quant::{GgufMatMul, QuantMethod, QuantMethodConfig};
+
+use crate::attention::SdpaParams;
+use crate::device_map::DeviceMapper;
+use crate::gguf::Content;
+use crate::layers::{CausalMasker, MatMul, QRmsNorm, RotaryEmbedding, ScaledEmbedding, Sdpa};
+use crate::layers_masker::PastKvLenCache;
+use crate::paged_attention::{AttentionImplementation, PagedAttention};
+use crate::pipeline::text_models_inputs_processor::PagedAttentionInputMetadata;
+use crate::pipeline::{extract_logits, EitherCache, KvCache, NormalCache, NormalCacheType};
+use crate::utils::gguf_metadata::ContentMetadata;
+use crate::utils::model_config as ModelConfig;
+use crate::utils::progress::NiceProgressBar;
+const MAX_SEQ_LEN: u32 = 4096;
+
+/// Every `pattern`-th layer attends to the whole context, the others only to the last
+/// `sliding_window` tokens.
+fn layer_sliding_window(layer_idx: usize, pattern: usize, sliding_window: usize) -> Option<usize> {
+    ((layer_idx + 1) % pattern != 0).then_some(sliding_window)
+}
+
+struct Mlp {
+    feed_forward_w1: Arc<dyn QuantMethod>,
+    feed_forward_w2: Arc<dyn QuantMethod>,
+    feed_forward_w3: Arc<dyn QuantMethod>,
+}
+
+impl Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let w1 = MatMul.qmethod_matmul(xs, &*self.feed_forward_w1)?;
+        let w3 = MatMul.qmethod_matmul(xs, &*self.feed_forward_w3)?;
+        let y = &(w1.gelu()? * w3)?;
+        MatMul.qmethod_matmul(y, &*self.feed_forward_w2)
+    }
+}
+
+struct LayerWeights {
+    attention_wq: Arc<dyn QuantMethod>,
+    attention_wk: Arc<dyn QuantMethod>,
+    attention_wv: Arc<dyn QuantMethod>,
+    attention_wo: Arc<dyn QuantMethod>,
+    attention_q_norm: QRmsNorm,
+    attention_k_norm: QRmsNorm,
+    attention_norm: QRmsNorm,
+    post_attention_norm: QRmsNorm,
+    mlp: Mlp,
+    ffn_norm: QRmsNorm,
+    post_ffn_norm: QRmsNorm,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    /// The local RoPE for sliding window layers, otherwise the global RoPE.
+    rotary: Arc<RotaryEmbedding>,
+    sliding_window: Option<usize>,
+    paged_attn: Option<PagedAttention>,
+    sdpa_params: SdpaParams,
+    dtype: DType,
+}
+
+impl LayerWeights {
+    fn forward_attn(
+        &self,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        start_offsets: &[usize],
+        kv_cache: &mut KvCache,
+        metadata: Option<((Tensor, Tensor), &PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = x.dims3()?;
+
+        let q = MatMul.qmethod_matmul(x, &*self.attention_wq)?;
+        let k = MatMul.qmethod_matmul(x, &*self.attention_wk)?;
+        let v = MatMul.qmethod_matmul(x, &*self.attention_wv)?;
+
+        let (q, k, v) = if seq_len != 1 {
+            let q = q
+                .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+                .transpose(1, 2)?;
+            let k = k
+                .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+                .transpose(1, 2)?;
+            let v = v
+                .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+                .transpose(1, 2)?;
+            (q, k, v)
+        } else {
+            let q = q.reshape((b_sz, self.n_head, seq_len, self.head_dim))?;
+            let k = k.reshape((b_sz, self.n_kv_head, seq_len, self.head_dim))?;
+            let v = v.reshape((b_sz, self.n_kv_head, seq_len, self.head_dim))?;
+            (q, k, v)
+        };
+
+        let q = self.attention_q_norm.forward(&q)?.to_dtype(self.dtype)?;
+        let k = self.attention_k_norm.forward(&k)?.to_dtype(self.dtype)?;
+        let v = v.to_dtype(self.dtype)?;
+
+        let (q, k) = self.rotary.forward(&q, &k, start_offsets)?;
+
+        let y = match &self.paged_attn {
+            Some(paged_attn) => {
+                let ((key_cache, value_cache), input_metadata) = metadata.unwrap();
+                paged_attn.forward(
+                    &q,
+                    &k,
+                    &v,
+                    mask,
+                    Some(key_cache),
+                    Some(value_cache),
+                    input_metadata,
+                    &self.sdpa_params,
+                    None,
+                )?
+            }
+            None => {
+                let (k, v) = kv_cache.append(&k, &v)?;
+
+                Sdpa.run_attention(&q, &k, &v, mask, None, &self.sdpa_params)?
+            }
+        };
+
+        let y = if mask.is_some() {
+            y.transpose(1, 2)?.reshape((b_sz, seq_len, ()))?
+        } else {
+            y.reshape((b_sz, seq_len, ()))?
+        };
+
+        let y = MatMul.qmethod_matmul(&y.to_dtype(x.dtype())?, &*self.attention_wo)?;
+        Ok(y)
+    }
+}
+
+pub struct ModelWeights {
+    tok_embeddings: ScaledEmbedding,
+    layers: Vec<LayerWeights>,
+    norm: QRmsNorm,
+    output: Arc<dyn QuantMethod>,
+    sliding_window: usize,
+    final_logit_softcapping: Option<f32>,
+    pub device: Device,
+    pub cache: EitherCache,
+    pub max_seq_len: usize,
+    mapper: Option<Box<dyn DeviceMapper + Send + Sync>>,
+    dtype: DType,
+}
+
+// gemma3 `llm` fields:
+// https://github.com/ggerganov/ggml/blob/master/docs/gguf.md#llm
+// NOTE: Types here do not match spec
+pub(crate) struct PropsGGUF {
+    pub head_count: usize,
+    pub head_count_kv: usize,
+    pub block_count: usize,
+    pub embedding_length: usize,
+    pub rms_norm_eps: f32,
+    pub max_seq_len: usize,
+    pub rope_freq_base: f32,
+    pub rope_local_freq_base: f32,
+    pub rope_linear_scaling: f32,
+    pub key_length: usize,
+    pub value_length: usize,
+    pub sliding_window: usize,
+    pub sliding_window_pattern: usize,
+    pub query_pre_attn_scalar: usize,
+    pub attn_logit_softcapping: Option<f32>,
+    pub final_logit_softcapping: Option<f32>,
+}
+
+impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
+    type Error = anyhow::Error;
+
+    fn try_from(c: ContentMetadata) -> std::result::Result<Self, Self::Error> {
+        c.verify_arch("gemma3")?;
+
+        let required = [
+            "attention.head_count",
+            "attention.head_count_kv",
+            "block_count",
+            "embedding_length",
+            "attention.layer_norm_rms_epsilon",
+            "attention.sliding_window",
+        ];
+        c.has_required_keys(&required)?;
+
+        let embed_len = c.get_value::<u32>("embedding_length")? as usize;
+        let head_count = c.get_value::<u32>("attention.head_count")? as usize;
+        let block_count = c.get_value::<u32>("block_count")? as usize;
+        let key_length = c
+            .get_value::<u32>("attention.key_length")
+            .ok()
+            .map(|x| x as usize)
+            .unwrap_or(embed_len / head_count);
+
+        // Only linear RoPE scaling is used by Gemma 3, and only for the global layers.
+        let rope_linear_scaling = match c.get_value::<String>("rope.scaling.type").ok() {
+            None => 1.,
+            Some(ty) if ty == "none" => 1.,
+            Some(ty) if ty == "linear" => c.get_value("rope.scaling.factor").ok().unwrap_or(1.),
+            Some(ty) => anyhow::bail!("Unsupported RoPE scaling type `{ty}` for Gemma 3"),
+        };
+
+        // NOTE: Values are not aligned with GGUFv3 types
+        // TODO: Normalize value types to spec
+        let props = Self {
+            head_count,
+            head_count_kv: c.get_value::<u32>("attention.head_count_kv")? as usize,
+            block_count,
+            embedding_length: embed_len,
+            rms_norm_eps: c.get_value("attention.layer_norm_rms_epsilon")?,
+            max_seq_len: c
+                .get_value::<u64>("context_length")
+                .ok()
+                .unwrap_or(MAX_SEQ_LEN as u64) as usize,
+            rope_freq_base: c.get_value("rope.freq_base").ok().unwrap_or(1_000_000_f32),
+            // Not stored in the GGUF files, the value of all Gemma 3 models.
+            rope_local_freq_base: 10_000.,
+            rope_linear_scaling,
+            key_length,
+            value_length: c
+                .get_value::<u32>("attention.value_length")
+                .ok()
+                .map(|x| x as usize)
+                .unwrap_or(embed_len / head_count),
+            sliding_window: c.get_value::<u32>("attention.sliding_window")? as usize,
+            sliding_window_pattern: c
+                .get_value::<u32>("attention.sliding_window_pattern")
+                .ok()
+                .map(|x| x as usize)
+                .unwrap_or(6),
+            // `query_pre_attn_scalar` is not stored in the GGUF files. It is the head dim except
+            // for the 62 layer 27b model.
+            query_pre_attn_scalar: if block_count == 62 {
+                embed_len / head_count
+            } else {
+                key_length
+            },
+            attn_logit_softcapping: c.get_value("attn_logit_softcapping").ok(),
+            final_logit_softcapping: c.get_value("final_logit_softcapping").ok(),
+        };
+
+        Ok(props)
+    }
+}
+
+impl ModelConfig::FromGGUF for ModelWeights {
+    fn from_gguf<R: std::io::Seek + std::io::Read>(
+        mut ct: Content<'_, R>,
+        device: &Device,
+        mapper: Box<dyn DeviceMapper + Send + Sync>,
+        attention_mechanism: AttentionImplementation,
+        dtype: DType,
+    ) -> Result<Self> {
+        // Parameter extraction from metadata.
+        let metadata = ContentMetadata {
+            path_prefix: "gemma3",
+            metadata: ct.get_metadata(),
+        };
+        let PropsGGUF {
+            head_count,
+            head_count_kv,
+            block_count,
+            embedding_length,
+            rms_norm_eps,
+            max_seq_len,
+            rope_freq_base,
+            rope_local_freq_base,
+            rope_linear_scaling,
+            key_length,
+            value_length,
+            sliding_window,
+            sliding_window_pattern,
+            query_pre_attn_scalar,
+            attn_logit_softcapping,
+            final_logit_softcapping,
+        } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
+
+        let qtok_embeddings = ct.tensor("token_embd.weight", device)?;
+        let tok_embeddings = qtok_embeddings.dequantize(device)?;
+        let norm = QRmsNorm::new(ct.tensor("output_norm.weight", device)?, rms_norm_eps)?;
+        let output = if !ct.has_tensor("output.weight") {
+            ct.tensor("token_embd.weight", device)?
+        } else {
+            ct.tensor("output.weight", device)?
+        };
+        let mut layers = Vec::with_capacity(block_count);
+
+        let head_dim = key_length;
+        if key_length != value_length {
+            candle_core::bail!(
+                "Expected key_length == value_length, got {key_length} != {value_length}"
+            );
+        }
+
+        let mut global_ropes = HashMap::new();
+        let mut local_ropes = HashMap::new();
+        for layer_idx in 0..block_count {
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+            global_ropes.insert(
+                device.location(),
+                Arc::new(RotaryEmbedding::new_linear_scaled(
+                    rope_freq_base,
+                    head_dim,
+                    max_seq_len,
+                    rope_linear_scaling,
+                    device,
+                    true,
+                    dtype,
+                )?),
+            );
+            local_ropes.insert(
+                device.location(),
+                Arc::new(RotaryEmbedding::new(
+                    rope_local_freq_base,
+                    head_dim,
+                    max_seq_len,
+                    device,
+                    true,
+                    dtype,
+                )?),
+            );
+        }
+
+        for layer_idx in NiceProgressBar::<_, 'b'>(
+            0..block_count,
+            "Loading repeating layers",
+            &MultiProgress::new(),
+        ) {
+            let prefix = format!("blk.{layer_idx}");
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+            let layer_sliding_window =
+                layer_sliding_window(layer_idx, sliding_window_pattern, sliding_window);
+            let ropes = if layer_sliding_window.is_some() {
+                &local_ropes
+            } else {
+                &global_ropes
+            };
+            let rotary = ropes
+                .get(&device.location())
+                .expect("No RoPE for device location!")
+                .clone();
+
+            let attention_wq = ct.tensor(&format!("{prefix}.attn_q.weight"), device)?;
+            let attention_wk = ct.tensor(&format!("{prefix}.attn_k.weight"), device)?;
+            let attention_wv = ct.tensor(&format!("{prefix}.attn_v.weight"), device)?;
+            let attention_wo = ct.tensor(&format!("{prefix}.attn_output.weight"), device)?;
+
+            let feed_forward_w1 = ct.tensor(&format!("{prefix}.ffn_gate.weight"), device)?;
+            let feed_forward_w2 = ct.tensor(&format!("{prefix}.ffn_down.weight"), device)?;
+            let feed_forward_w3 = ct.tensor(&format!("{prefix}.ffn_up.weight"), device)?;
+            let mlp = Mlp {
+                feed_forward_w1: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                    q_weight: Arc::new(feed_forward_w1),
+                    b: None,
+                })?),
+                feed_forward_w2: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                    q_weight: Arc::new(feed_forward_w2),
+                    b: None,
+                })?),
+                feed_forward_w3: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                    q_weight: Arc::new(feed_forward_w3),
+                    b: None,
+                })?),
+            };
+
+            // The Gemma norms have an offset of 1, which is already added to the GGUF weights.
+            let mut norm = |name: &str| -> Result<QRmsNorm> {
+                QRmsNorm::new(
+                    ct.tensor(&format!("{prefix}.{name}.weight"), device)?,
+                    rms_norm_eps,
+                )
+            };
+            let attention_q_norm = norm("attn_q_norm")?;
+            let attention_k_norm = norm("attn_k_norm")?;
+            let attention_norm = norm("attn_norm")?;
+            let post_attention_norm = norm("post_attention_norm")?;
+            let ffn_norm = norm("ffn_norm")?;
+            let post_ffn_norm = norm("post_ffw_norm")?;
+            let paged_attn = match &attention_mechanism {
+                AttentionImplementation::Eager => None,
+                AttentionImplementation::PagedAttention => {
+                    Some(PagedAttention::new(head_dim, device, None)?)
+                }
+            };
+            layers.push(LayerWeights {
+                attention_wq: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                    q_weight: Arc::new(attention_wq),
+                    b: None,
+                })?),
+                attention_wk: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                    q_weight: Arc::new(attention_wk),
+                    b: None,
+                })?),
+                attention_wv: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                    q_weight: Arc::new(attention_wv),
+                    b: None,
+                })?),
+                attention_wo: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                    q_weight: Arc::new(attention_wo),
+                    b: None,
+                })?),
+                attention_q_norm,
+                attention_k_norm,
+                attention_norm,
+                post_attention_norm,
+                mlp,
+                ffn_norm,
+                post_ffn_norm,
+                n_head: head_count,
+                n_kv_head: head_count_kv,
+                head_dim,
+                rotary,
+                sliding_window: layer_sliding_window,
+                paged_attn,
+                sdpa_params: SdpaParams {
+                    n_kv_groups: head_count / head_count_kv,
+                    use_flash_attn: false,
+                    softcap: attn_logit_softcapping,
+                    softmax_scale: 1.0 / (query_pre_attn_scalar as f32).sqrt(),
+                    sliding_window: layer_sliding_window,
+                },
+                dtype,
+            })
+        }
+
+        let cache_types = layers
+            .iter()
+            .map(|layer| match layer.sliding_window {
+                Some(window) => NormalCacheType::SlidingWindow { window },
+                None => NormalCacheType::Normal { max_seq_len },
+            })
+            .collect();
+        Ok(Self {
+            tok_embeddings: ScaledEmbedding::new(
+                (embedding_length as f64).sqrt(),
+                Embedding::new(tok_embeddings, embedding_length),
+            ),
+            layers,
+            norm,
+            output: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                q_weight: Arc::new(output),
+                b: None,
+            })?),
+            sliding_window,
+            final_logit_softcapping,
+            device: device.clone(),
+            cache: EitherCache::Normal(NormalCache::from_types(cache_types)),
+            max_seq_len,
+            mapper: Some(mapper),
+            dtype,
+        })
+    }
+}
+
+impl ModelWeights {
+    pub fn forward(
+        &self,
+        x: &Tensor,
+        start_offsets: &[usize],
+        context_lens: Vec<(usize, usize)>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        let mut layer_in = self.tok_embeddings.forward(x)?;
+        let cache = &mut self.cache.normal().0;
+        let past_kv_len_cache = metadata
+            .as_ref()
+            .map(|(_, _)| &start_offsets as &dyn PastKvLenCache)
+            .unwrap_or(cache as &dyn PastKvLenCache);
+        let mask = CausalMasker.make_causal_mask_matrix(
+            x,
+            past_kv_len_cache,
+            self.dtype,
+            self.layers[0].n_head,
+        )?;
+        let sliding_mask = CausalMasker.make_sliding_window_causal_mask_matrix(
+            x,
+            past_kv_len_cache,
+            Some(self.sliding_window),
+            self.dtype,
+            self.layers[0].n_head,
+        )?;
+        // PagedAttention prompt chunking
+        let is_first_prompt_chunk = metadata
+            .as_ref()
+            .map(|(_, meta)| meta.is_first_prompt_chunk)
+            .unwrap_or(true);
+        let mask = mask.filter(|_| is_first_prompt_chunk);
+        let sliding_mask = sliding_mask.filter(|_| is_first_prompt_chunk);
+        for (i, layer) in self.layers.iter().enumerate() {
+            if let Some(ref mapper) = self.mapper {
+                layer_in = mapper.map(layer_in, i)?;
+            }
+            let layer_mask = if layer.sliding_window.is_some() {
+                &sliding_mask
+            } else {
+                &mask
+            };
+            let x = layer_in;
+            let residual = &x;
+            let x = layer.attention_norm.forward(&x)?;
+            let attn = layer.forward_attn(
+                &x,
+                layer_mask
+                    .as_ref()
+                    .map(|m| m.to_device(x.device()).unwrap())
+                    .as_ref(),
+                start_offsets,
+                &mut cache[i],
+                metadata
+                    .as_ref()
+                    .map(|(kv_cache, metadata)| (kv_cache[i].clone(), *metadata)),
+            )?;
+            let attn = layer.post_attention_norm.forward(&attn)?;
+            let x = (attn + residual)?;
+
+            // MLP
+            let residual = &x;
+            let x = layer.ffn_norm.forward(&x)?;
+            let x = layer.mlp.forward(&x)?;
+            let x = layer.post_ffn_norm.forward(&x)?;
+            let x = (x + residual)?;
+            layer_in = x;
+        }
+        let x = self.norm.forward(&layer_in)?;
+        let mut logits = MatMul.qmethod_matmul(&x.contiguous()?, &*self.output)?;
+        if let Some(softcap) = self.final_logit_softcapping {
+            logits = ((logits / softcap as f64)?.tanh()? * softcap as f64)?;
+        }
+        extract_logits(&logits, context_lens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::layer_sliding_window;
+    use crate::layers::CausalMasker;
+
+    #[test]
+    fn local_and_global_layers() {
+        // Gemma 3 1b: 26 layers, 5 local layers for each global layer.
+        let windows = (0..26)
+            .map(|i| layer_sliding_window(i, 6, 512))
+            .collect::<Vec<_>>();
+        let global = (0..26)
+            .filter(|i| windows[*i].is_none())
+            .collect::<Vec<_>>();
+        assert_eq!(global, [5, 11, 17, 23]);
+        assert!(windows.iter().flatten().all(|w| *w == 512));
+
+        // A local layer cannot attend past the window, a global layer attends to the whole prompt.
+        let dev = Device::Cpu;
+        let input_ids = Tensor::zeros((1, 8), DType::U32, &dev).unwrap();
+        let past_kv_len: &[usize] = &[0];
+        let masked_last_row = |sliding_window: Option<usize>| {
+            let mask = CausalMasker
+                .make_sliding_window_causal_mask_matrix(
+                    &input_ids,
+                    &past_kv_len,
+                    sliding_window,
+                    DType::F32,
+                    1,
+                )
+                .unwrap()
+                .unwrap();
+            mask.get(7)
+                .unwrap()
+                .to_vec1::<f32>()
+                .unwrap()
+                .into_iter()
+                .map(|x| x.is_infinite())
+                .collect::<Vec<_>>()
+        };
+        for window in windows.iter().take(6) {
+            let masked = masked_last_row(window.map(|_| 4));
+            if window.is_some() {
+                assert_eq!(
+                    masked,
+                    [true, true, true, false, false, false, false, false]
+                );
+            } else {
+                assert_eq!(masked, [false; 8]);
+            }
+        }
+    }
+}
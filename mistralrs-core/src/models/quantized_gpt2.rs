@@ -0,0 +1,411 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::sync::Arc;
+
+use candle_core::{DType, Device, Module, Result, Tensor, D};
+use candle_nn::{Embedding, LayerNorm};
+use indicatif::MultiProgress;
+use mistralrs_quant::QuantMethod;
+
+use crate::attention::SdpaParams;
+use crate::device_map::DeviceMapper;
+use crate::gguf::Content;
+use crate::layers::{CausalMasker, MatMul, Sdpa};
+use crate::layers_masker::PastKvLenCache;
+use crate::models::quantized_common::{gguf_linear, layer_norm};
+use crate::paged_attention::{AttentionImplementation, PagedAttention};
+use crate::pipeline::text_models_inputs_processor::PagedAttentionInputMetadata;
+use crate::pipeline::{extract_logits, EitherCache, KvCache, NormalCache};
+use crate::utils::gguf_metadata::ContentMetadata;
+use crate::utils::model_config as ModelConfig;
+use crate::utils::progress::NiceProgressBar;
+
+struct Mlp {
+    ffn_up: Arc<dyn QuantMethod>,
+    ffn_down: Arc<dyn QuantMethod>,
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        MatMul.qmethod_matmul(
+            &MatMul
+                .qmethod_matmul(xs, &*self.ffn_up)?
+                .apply(&candle_nn::Activation::NewGelu)?,
+            &*self.ffn_down,
+        )
+    }
+}
+
+struct LayerWeights {
+    attention_qkv: Arc<dyn QuantMethod>,
+    attention_wo: Arc<dyn QuantMethod>,
+    attention_norm: LayerNorm,
+    ffn_norm: LayerNorm,
+    mlp: Mlp,
+    n_head: usize,
+    head_dim: usize,
+    paged_attn: Option<PagedAttention>,
+    sdpa_params: SdpaParams,
+    dtype: DType,
+}
+
+impl LayerWeights {
+    fn forward_attn(
+        &self,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        kv_cache: &mut KvCache,
+        metadata: Option<((Tensor, Tensor), &PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, n_embd) = x.dims3()?;
+
+        let qkv = MatMul
+            .qmethod_matmul(x, &*self.attention_qkv)?
+            .to_dtype(self.dtype)?;
+        let split = |i: usize| -> Result<Tensor> {
+            qkv.narrow(D::Minus1, i * n_embd, n_embd)?
+                .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()
+        };
+        let (q, k, v) = (split(0)?, split(1)?, split(2)?);
+
+        let y = match &self.paged_attn {
+            Some(paged_attn) => {
+                let ((key_cache, value_cache), input_metadata) = metadata.unwrap();
+                paged_attn.forward(
+                    &q,
+                    &k,
+                    &v,
+                    mask,
+                    Some(key_cache),
+                    Some(value_cache),
+                    input_metadata,
+                    &self.sdpa_params,
+                    None,
+                )?
+            }
+            None => {
+                let (k, v) = kv_cache.append(&k, &v)?;
+                Sdpa.run_attention(&q, &k, &v, mask, None, &self.sdpa_params)?
+            }
+        };
+
+        let y = if mask.is_some() {
+            y.transpose(1, 2)?.reshape((b_sz, seq_len, ()))?
+        } else {
+            y.reshape((b_sz, seq_len, ()))?
+        };
+
+        MatMul.qmethod_matmul(&y.to_dtype(x.dtype())?, &*self.attention_wo)
+    }
+}
+
+/// GPT-2: learned absolute position embeddings added to the token embeddings, pre-norm layers
+/// with biases, a fused query, key and value projection and a GELU MLP. The output projection is
+/// tied to the token embeddings, which the converters may not write again as `output.weight`.
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    pos_embeddings: Embedding,
+    layers: Vec<LayerWeights>,
+    norm: LayerNorm,
+    output: Arc<dyn QuantMethod>,
+    pub device: Device,
+    pub cache: EitherCache,
+    pub max_seq_len: usize,
+    mapper: Option<Box<dyn DeviceMapper + Send + Sync>>,
+    dtype: DType,
+}
+
+// gpt2 `llm` fields:
+// https://github.com/ggerganov/ggml/blob/master/docs/gguf.md#llm
+// NOTE: Types here do not match spec
+pub(crate) struct PropsGGUF {
+    pub head_count: usize,
+    pub block_count: usize,
+    pub embedding_length: usize,
+    pub layer_norm_epsilon: f64,
+    /// Number of learned positions, which bounds the sequence length.
+    pub max_seq_len: usize,
+}
+
+impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
+    type Error = anyhow::Error;
+
+    fn try_from(c: ContentMetadata) -> std::result::Result<Self, Self::Error> {
+        c.verify_arch("gpt2")?;
+
+        let required = [
+            "attention.head_count",
+            "block_count",
+            "embedding_length",
+            "context_length",
+            "attention.layer_norm_epsilon",
+        ];
+        c.has_required_keys(&required)?;
+
+        // NOTE: Values are not aligned with GGUFv3 types
+        // TODO: Normalize value types to spec
+        let props = Self {
+            head_count: c.get_value::<u32>("attention.head_count")? as usize,
+            block_count: c.get_value::<u32>("block_count")? as usize,
+            embedding_length: c.get_value::<u32>("embedding_length")? as usize,
+            layer_norm_epsilon: c.get_value::<f32>("attention.layer_norm_epsilon")? as f64,
+            max_seq_len: c.get_value::<u64>("context_length")? as usize,
+        };
+
+        Ok(props)
+    }
+}
+
+impl ModelConfig::FromGGUF for ModelWeights {
+    fn from_gguf<R: std::io::Seek + std::io::Read>(
+        mut ct: Content<'_, R>,
+        device: &Device,
+        mapper: Box<dyn DeviceMapper + Send + Sync>,
+        attention_mechanism: AttentionImplementation,
+        dtype: DType,
+    ) -> Result<Self> {
+        // Parameter extraction from metadata.
+        let metadata = ContentMetadata {
+            path_prefix: "gpt2",
+            metadata: ct.get_metadata(),
+        };
+        let PropsGGUF {
+            head_count,
+            block_count,
+            embedding_length,
+            layer_norm_epsilon,
+            max_seq_len,
+        } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
+
+        let qtok_embeddings = ct.tensor("token_embd.weight", device)?;
+        let tok_embeddings = qtok_embeddings.dequantize(device)?;
+        let pos_embeddings = ct
+            .tensor("position_embd.weight", device)?
+            .dequantize(device)?;
+        if pos_embeddings.dim(0)? < max_seq_len {
+            candle_core::bail!(
+                "`position_embd.weight` has {} positions, but `gpt2.context_length` is {max_seq_len}.",
+                pos_embeddings.dim(0)?
+            );
+        }
+        let norm = layer_norm(
+            ct.tensor("output_norm.weight", device)?,
+            ct.tensor("output_norm.bias", device)?,
+            layer_norm_epsilon,
+        )?;
+        // The output projection is tied to the token embeddings.
+        let output = if !ct.has_tensor("output.weight") {
+            ct.tensor("token_embd.weight", device)?
+        } else {
+            ct.tensor("output.weight", device)?
+        };
+        let mut layers = Vec::with_capacity(block_count);
+
+        let head_dim = embedding_length / head_count;
+
+        for layer_idx in NiceProgressBar::<_, 'b'>(
+            0..block_count,
+            "Loading repeating layers",
+            &MultiProgress::new(),
+        ) {
+            let prefix = format!("blk.{layer_idx}");
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+
+            let mut proj = |name: &str| -> Result<Arc<dyn QuantMethod>> {
+                let weight = ct.tensor(&format!("{prefix}.{name}.weight"), device)?;
+                let bias = ct
+                    .tensor(&format!("{prefix}.{name}.bias"), device)?
+                    .dequantize(device)?;
+                gguf_linear(weight, Some(bias))
+            };
+            let attention_qkv = proj("attn_qkv")?;
+            let attention_wo = proj("attn_output")?;
+            let mlp = Mlp {
+                ffn_up: proj("ffn_up")?,
+                ffn_down: proj("ffn_down")?,
+            };
+
+            let mut norm = |name: &str| -> Result<LayerNorm> {
+                layer_norm(
+                    ct.tensor(&format!("{prefix}.{name}.weight"), device)?,
+                    ct.tensor(&format!("{prefix}.{name}.bias"), device)?,
+                    layer_norm_epsilon,
+                )
+            };
+            let attention_norm = norm("attn_norm")?;
+            let ffn_norm = norm("ffn_norm")?;
+            let paged_attn = match &attention_mechanism {
+                AttentionImplementation::Eager => None,
+                AttentionImplementation::PagedAttention => {
+                    Some(PagedAttention::new(head_dim, device, None)?)
+                }
+            };
+            layers.push(LayerWeights {
+                attention_qkv,
+                attention_wo,
+                attention_norm,
+                ffn_norm,
+                mlp,
+                n_head: head_count,
+                head_dim,
+                paged_attn,
+                sdpa_params: SdpaParams {
+                    n_kv_groups: 1,
+                    use_flash_attn: false,
+                    softcap: None,
+                    softmax_scale: 1.0 / (head_dim as f32).sqrt(),
+                    sliding_window: None,
+                },
+                dtype,
+            })
+        }
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            pos_embeddings: Embedding::new(pos_embeddings, embedding_length),
+            layers,
+            norm,
+            output: gguf_linear(output, None)?,
+            device: device.clone(),
+            cache: EitherCache::Normal(NormalCache::new(block_count, max_seq_len)),
+            max_seq_len,
+            mapper: Some(mapper),
+            dtype,
+        })
+    }
+}
+
+impl ModelWeights {
+    pub fn forward(
+        &self,
+        x: &Tensor,
+        start_offsets: &[usize],
+        context_lens: Vec<(usize, usize)>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len) = x.dims2()?;
+        let positions = start_offsets
+            .iter()
+            .flat_map(|offset| (*offset..offset + seq_len).map(|pos| pos as u32))
+            .collect::<Vec<_>>();
+        let positions = Tensor::from_vec(positions, (b_sz, seq_len), x.device())?;
+        let mut layer_in =
+            (self.tok_embeddings.forward(x)? + self.pos_embeddings.forward(&positions)?)?;
+        let cache = &mut self.cache.normal().0;
+        let mask = CausalMasker.make_causal_mask_matrix(
+            x,
+            metadata
+                .as_ref()
+                .map(|(_, _)| &start_offsets as &dyn PastKvLenCache)
+                .unwrap_or(cache as &dyn PastKvLenCache),
+            self.dtype,
+            self.layers[0].n_head,
+        )?;
+        let mask = mask.filter(|_| {
+            metadata
+                .as_ref()
+                .map(|(_, meta)| meta.is_first_prompt_chunk)
+                .unwrap_or(true)
+        });
+        for (i, layer) in self.layers.iter().enumerate() {
+            if let Some(ref mapper) = self.mapper {
+                layer_in = mapper.map(layer_in, i)?;
+            }
+            let residual = &layer_in;
+            let attn_in = layer_in.apply(&layer.attention_norm)?;
+            let attn = layer.forward_attn(
+                &attn_in,
+                mask.as_ref()
+                    .map(|m| m.to_device(attn_in.device()).unwrap())
+                    .as_ref(),
+                &mut cache[i],
+                metadata
+                    .as_ref()
+                    .map(|(kv_cache, metadata)| (kv_cache[i].clone(), *metadata)),
+            )?;
+            let x = (attn + residual)?;
+            let mlp = layer.mlp.forward(&x.apply(&layer.ffn_norm)?)?;
+            layer_in = (mlp + x)?;
+        }
+        let x = layer_in.apply(&self.norm)?;
+        extract_logits(
+            &MatMul.qmethod_matmul(&x.contiguous()?, &*self.output)?,
+            context_lens,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::ModelWeights;
+    use crate::models::quantized_test_utils::{
+        assert_decoding_matches_prompt, assert_finite, last_logits, load, TestGguf,
+    };
+
+    /// Write a GGUF file with two layers and 64 positions, with or without an `output.weight`.
+    fn gpt2_gguf(tied: bool) -> candle_core::Result<Vec<u8>> {
+        let (vocab, hidden, n_ctx) = (16, 32, 64);
+        let mut gguf = TestGguf::new("gpt2");
+        gguf.u32("block_count", 2)
+            .u32("context_length", n_ctx)
+            .u32("embedding_length", hidden)
+            .u32("attention.head_count", 4)
+            .f32("attention.layer_norm_epsilon", 1e-5);
+        gguf.linear("token_embd.weight", vocab, hidden)?
+            .linear("position_embd.weight", n_ctx, hidden)?
+            .layer_norm("output_norm", hidden)?;
+        if !tied {
+            gguf.linear("output.weight", vocab, hidden)?;
+        }
+        for layer in 0..2 {
+            gguf.layer_norm(&format!("blk.{layer}.attn_norm"), hidden)?
+                .layer_norm(&format!("blk.{layer}.ffn_norm"), hidden)?;
+            for (name, out_dim, in_dim) in [
+                ("attn_qkv", 3 * hidden, hidden),
+                ("attn_output", hidden, hidden),
+                ("ffn_up", 4 * hidden, hidden),
+                ("ffn_down", hidden, 4 * hidden),
+            ] {
+                gguf.linear(format!("blk.{layer}.{name}.weight"), out_dim, in_dim)?
+                    .vector(format!("blk.{layer}.{name}.bias"), out_dim, 0.01)?;
+            }
+        }
+        gguf.build()
+    }
+
+    fn logits(model: &ModelWeights, ids: &[u32], offset: usize) -> candle_core::Result<Vec<f32>> {
+        let input_ids = Tensor::new(ids, &Device::Cpu)?.unsqueeze(0)?;
+        last_logits(model.forward(&input_ids, &[offset], vec![(ids.len() - 1, 1)], None)?)
+    }
+
+    #[test]
+    fn forward() -> candle_core::Result<()> {
+        let model = load::<ModelWeights>(&gpt2_gguf(false)?)?;
+        assert_eq!(model.max_seq_len, 64);
+
+        let out = logits(&model, &[1, 5, 7], 0)?;
+        assert_eq!(out.len(), 16);
+        assert_finite(&out);
+
+        // Decoding a token reuses the KV cache of the prompt.
+        assert_eq!(logits(&model, &[3], 3)?.len(), 16);
+        assert_eq!(model.cache.normal().0[0].current_seq_len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn decoding_matches_prompt() -> candle_core::Result<()> {
+        // The position of the decoded token comes from its offset.
+        assert_decoding_matches_prompt(&gpt2_gguf(false)?, &[&[1, 5, 7], &[3]], logits)
+    }
+
+    #[test]
+    fn tied_output_embeddings() -> candle_core::Result<()> {
+        let model = load::<ModelWeights>(&gpt2_gguf(true)?)?;
+        assert_eq!(logits(&model, &[1, 5, 7], 0)?.len(), 16);
+        Ok(())
+    }
+}
@@ -0,0 +1,424 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::sync::Arc;
+
+use candle_core::{DType, Device, Module, Result, Tensor, D};
+use candle_nn::{Embedding, LayerNorm};
+use indicatif::MultiProgress;
+use mistralrs_quant::QuantMethod;
+
+use crate::attention::SdpaParams;
+use crate::device_map::DeviceMapper;
+use crate::gguf::Content;
+use crate::layers::{Alibi, CausalMasker, MatMul, Sdpa};
+use crate::layers_masker::PastKvLenCache;
+use crate::models::quantized_common::{gguf_linear, layer_norm};
+use crate::paged_attention::AttentionImplementation;
+use crate::pipeline::{extract_logits, EitherCache, KvCache, NormalCache};
+use crate::utils::gguf_metadata::ContentMetadata;
+use crate::utils::model_config as ModelConfig;
+use crate::utils::progress::NiceProgressBar;
+const MAX_SEQ_LEN: u32 = 2048;
+
+/// SwiGLU MLP with biases.
+struct Mlp {
+    ffn_gate: Arc<dyn QuantMethod>,
+    ffn_up: Arc<dyn QuantMethod>,
+    ffn_down: Arc<dyn QuantMethod>,
+}
+
+impl Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let gate = MatMul.qmethod_matmul(xs, &*self.ffn_gate)?;
+        let up = MatMul.qmethod_matmul(xs, &*self.ffn_up)?;
+        let y = (candle_nn::ops::silu(&gate)? * up)?;
+        MatMul.qmethod_matmul(&y, &*self.ffn_down)
+    }
+}
+
+struct LayerWeights {
+    attention_qkv: Arc<dyn QuantMethod>,
+    attention_wo: Arc<dyn QuantMethod>,
+    attention_norm: LayerNorm,
+    mlp: Mlp,
+    ffn_norm: LayerNorm,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    clip_qkv: Option<f32>,
+    sdpa_params: SdpaParams,
+    dtype: DType,
+}
+
+impl LayerWeights {
+    fn forward_attn(&self, x: &Tensor, mask: &Tensor, kv_cache: &mut KvCache) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = x.dims3()?;
+
+        let mut qkv = MatMul
+            .qmethod_matmul(x, &*self.attention_qkv)?
+            .to_dtype(self.dtype)?;
+        if let Some(clip) = self.clip_qkv {
+            qkv = qkv.clamp(-clip, clip)?;
+        }
+        let q_size = self.n_head * self.head_dim;
+        let kv_size = self.n_kv_head * self.head_dim;
+        let q = qkv
+            .narrow(D::Minus1, 0, q_size)?
+            .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let k = qkv
+            .narrow(D::Minus1, q_size, kv_size)?
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let v = qkv
+            .narrow(D::Minus1, q_size + kv_size, kv_size)?
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let (k, v) = kv_cache.append(&k, &v)?;
+        let y = Sdpa.run_attention(&q, &k, &v, Some(mask), None, &self.sdpa_params)?;
+
+        let y = y.transpose(1, 2)?.reshape((b_sz, seq_len, ()))?;
+
+        MatMul.qmethod_matmul(&y.to_dtype(x.dtype())?, &*self.attention_wo)
+    }
+}
+
+/// Jais, a GPT-style model for Arabic and English with ALiBi positions instead of positional
+/// embeddings, SwiGLU MLPs and the muP attention scale of `1 / head_dim`. The Arabic-optimized
+/// vocabulary is a byte-level BPE, converted like the GPT-2 tokenizers.
+///
+/// PagedAttention is not supported, so the pipeline runs Jais without it.
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    layers: Vec<LayerWeights>,
+    norm: LayerNorm,
+    output: Arc<dyn QuantMethod>,
+    alibi: Alibi,
+    pub device: Device,
+    pub cache: EitherCache,
+    pub max_seq_len: usize,
+    mapper: Option<Box<dyn DeviceMapper + Send + Sync>>,
+    dtype: DType,
+}
+
+// jais `llm` fields:
+// https://github.com/ggerganov/ggml/blob/master/docs/gguf.md#llm
+// NOTE: Types here do not match spec
+pub(crate) struct PropsGGUF {
+    pub head_count: usize,
+    pub head_count_kv: usize,
+    pub block_count: usize,
+    pub embedding_length: usize,
+    pub layer_norm_epsilon: f64,
+    pub max_seq_len: usize,
+    pub max_alibi_bias: f32,
+    pub clip_qkv: Option<f32>,
+}
+
+impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
+    type Error = anyhow::Error;
+
+    fn try_from(c: ContentMetadata) -> std::result::Result<Self, Self::Error> {
+        c.verify_arch("jais")?;
+
+        let required = [
+            "attention.head_count",
+            "block_count",
+            "embedding_length",
+            "attention.layer_norm_epsilon",
+        ];
+        c.has_required_keys(&required)?;
+
+        let head_count = c.get_value::<u32>("attention.head_count")? as usize;
+
+        // NOTE: Values are not aligned with GGUFv3 types
+        // TODO: Normalize value types to spec
+        let props = Self {
+            head_count,
+            head_count_kv: c
+                .get_value::<u32>("attention.head_count_kv")
+                .ok()
+                .map(|x| x as usize)
+                .unwrap_or(head_count),
+            block_count: c.get_value::<u32>("block_count")? as usize,
+            embedding_length: c.get_value::<u32>("embedding_length")? as usize,
+            layer_norm_epsilon: c.get_value::<f32>("attention.layer_norm_epsilon")? as f64,
+            max_seq_len: c
+                .get_value::<u64>("context_length")
+                .ok()
+                .unwrap_or(MAX_SEQ_LEN as u64) as usize,
+            max_alibi_bias: c
+                .get_value("attention.max_alibi_bias")
+                .ok()
+                .unwrap_or(8_f32),
+            // llama.cpp names this `clamp_kqv`.
+            clip_qkv: c
+                .get_value("attention.clip_qkv")
+                .or_else(|_| c.get_value("attention.clamp_kqv"))
+                .ok(),
+        };
+
+        Ok(props)
+    }
+}
+
+impl ModelConfig::FromGGUF for ModelWeights {
+    fn from_gguf<R: std::io::Seek + std::io::Read>(
+        mut ct: Content<'_, R>,
+        device: &Device,
+        mapper: Box<dyn DeviceMapper + Send + Sync>,
+        attention_mechanism: AttentionImplementation,
+        dtype: DType,
+    ) -> Result<Self> {
+        if matches!(attention_mechanism, AttentionImplementation::PagedAttention) {
+            candle_core::bail!("Jais does not support PagedAttention.");
+        }
+
+        // Parameter extraction from metadata.
+        let metadata = ContentMetadata {
+            path_prefix: "jais",
+            metadata: ct.get_metadata(),
+        };
+        let PropsGGUF {
+            head_count,
+            head_count_kv,
+            block_count,
+            embedding_length,
+            layer_norm_epsilon,
+            max_seq_len,
+            max_alibi_bias,
+            clip_qkv,
+        } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
+
+        let qtok_embeddings = ct.tensor("token_embd.weight", device)?;
+        let tok_embeddings = qtok_embeddings.dequantize(device)?;
+        let norm = layer_norm(
+            ct.tensor("output_norm.weight", device)?,
+            ct.tensor("output_norm.bias", device)?,
+            layer_norm_epsilon,
+        )?;
+        let output = if !ct.has_tensor("output.weight") {
+            ct.tensor("token_embd.weight", device)?
+        } else {
+            ct.tensor("output.weight", device)?
+        };
+        let mut layers = Vec::with_capacity(block_count);
+
+        let head_dim = embedding_length / head_count;
+
+        for layer_idx in NiceProgressBar::<_, 'b'>(
+            0..block_count,
+            "Loading repeating layers",
+            &MultiProgress::new(),
+        ) {
+            let prefix = format!("blk.{layer_idx}");
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+
+            let mut proj = |name: &str| -> Result<Arc<dyn QuantMethod>> {
+                let weight = ct.tensor(&format!("{prefix}.{name}.weight"), device)?;
+                let bias = ct
+                    .tensor(&format!("{prefix}.{name}.bias"), device)?
+                    .dequantize(device)?;
+                gguf_linear(weight, Some(bias))
+            };
+            let attention_qkv = proj("attn_qkv")?;
+            let attention_wo = proj("attn_output")?;
+            let mlp = Mlp {
+                ffn_gate: proj("ffn_gate")?,
+                ffn_up: proj("ffn_up")?,
+                ffn_down: proj("ffn_down")?,
+            };
+
+            let mut norm = |name: &str| -> Result<LayerNorm> {
+                layer_norm(
+                    ct.tensor(&format!("{prefix}.{name}.weight"), device)?,
+                    ct.tensor(&format!("{prefix}.{name}.bias"), device)?,
+                    layer_norm_epsilon,
+                )
+            };
+            let attention_norm = norm("attn_norm")?;
+            let ffn_norm = norm("ffn_norm")?;
+            layers.push(LayerWeights {
+                attention_qkv,
+                attention_wo,
+                attention_norm,
+                mlp,
+                ffn_norm,
+                n_head: head_count,
+                n_kv_head: head_count_kv,
+                head_dim,
+                clip_qkv,
+                sdpa_params: SdpaParams {
+                    n_kv_groups: head_count / head_count_kv,
+                    use_flash_attn: false,
+                    softcap: None,
+                    // muP scales the attention scores by `1 / head_dim`.
+                    softmax_scale: 1.0 / head_dim as f32,
+                    sliding_window: None,
+                },
+                dtype,
+            })
+        }
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            layers,
+            norm,
+            output: gguf_linear(output, None)?,
+            alibi: Alibi::new(head_count, max_alibi_bias, device)?,
+            device: device.clone(),
+            cache: EitherCache::Normal(NormalCache::new(block_count, max_seq_len)),
+            max_seq_len,
+            mapper: Some(mapper),
+            dtype,
+        })
+    }
+}
+
+impl ModelWeights {
+    pub fn forward(&self, x: &Tensor, context_lens: Vec<(usize, usize)>) -> Result<Tensor> {
+        let (b_sz, seq_len) = x.dims2()?;
+        let mut layer_in = self.tok_embeddings.forward(x)?;
+        let cache = &mut self.cache.normal().0;
+        let past_kv_len_cache = &*cache as &dyn PastKvLenCache;
+        let past_kv_len = past_kv_len_cache.get_past_kv_len()?;
+        let causal_mask = CausalMasker.make_causal_mask_matrix(
+            x,
+            past_kv_len_cache,
+            self.dtype,
+            self.layers[0].n_head,
+        )?;
+        // The ALiBi bias is added to the causal mask, so a mask is needed even for one token.
+        let alibi = self.alibi.bias(seq_len, past_kv_len, self.dtype)?;
+        let mask = match causal_mask {
+            Some(causal_mask) => alibi.broadcast_add(&causal_mask)?,
+            None => alibi,
+        };
+        let mask = mask
+            .broadcast_as((b_sz, self.layers[0].n_head, seq_len, past_kv_len + seq_len))?
+            .contiguous()?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            if let Some(ref mapper) = self.mapper {
+                layer_in = mapper.map(layer_in, i)?;
+            }
+            let x = layer_in;
+            let residual = &x;
+            let x = x.apply(&layer.attention_norm)?;
+            let attn = layer.forward_attn(&x, &mask.to_device(x.device())?, &mut cache[i])?;
+            let x = (attn + residual)?;
+
+            // MLP
+            let residual = &x;
+            let x = x.apply(&layer.ffn_norm)?;
+            let x = layer.mlp.forward(&x)?;
+            let x = (x + residual)?;
+            layer_in = x;
+        }
+        let x = layer_in.apply(&self.norm)?;
+        extract_logits(
+            &MatMul.qmethod_matmul(&x.contiguous()?, &*self.output)?,
+            context_lens,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::ModelWeights;
+    use crate::models::quantized_test_utils::{
+        assert_decoding_matches_prompt, assert_finite, last_logits, load, TestGguf,
+    };
+
+    /// Write a GGUF file of a Jais model with two layers, multi-head attention and tied
+    /// embeddings, clipping the QKV activations with the `clip_key` metadata if given.
+    fn jais_gguf(arch: &str, name: &str, clip_key: Option<&str>) -> candle_core::Result<Vec<u8>> {
+        let (vocab, hidden, ff) = (16, 32, 64);
+        let mut gguf = TestGguf::new(arch);
+        gguf.general("name", name)
+            .u32("block_count", 2)
+            .u32("context_length", 64)
+            .u32("embedding_length", hidden)
+            .u32("attention.head_count", 4)
+            .f32("attention.layer_norm_epsilon", 1e-5);
+        if let Some(clip_key) = clip_key {
+            gguf.f32(clip_key, 0.5);
+        }
+        gguf.linear("token_embd.weight", vocab, hidden)?
+            .layer_norm("output_norm", hidden)?;
+        for layer in 0..2 {
+            gguf.layer_norm(&format!("blk.{layer}.attn_norm"), hidden)?
+                .layer_norm(&format!("blk.{layer}.ffn_norm"), hidden)?;
+            for (name, out_dim, in_dim) in [
+                ("attn_qkv", 3 * hidden, hidden),
+                ("attn_output", hidden, hidden),
+                ("ffn_gate", ff, hidden),
+                ("ffn_up", ff, hidden),
+                ("ffn_down", hidden, ff),
+            ] {
+                gguf.linear(format!("blk.{layer}.{name}.weight"), out_dim, in_dim)?
+                    .vector(format!("blk.{layer}.{name}.bias"), out_dim, 0.01)?;
+            }
+        }
+        gguf.build()
+    }
+
+    fn logits(model: &ModelWeights, ids: &[u32], _offset: usize) -> candle_core::Result<Vec<f32>> {
+        let input_ids = Tensor::new(ids, &Device::Cpu)?.unsqueeze(0)?;
+        last_logits(model.forward(&input_ids, vec![(ids.len() - 1, 1)])?)
+    }
+
+    #[test]
+    fn forward() -> candle_core::Result<()> {
+        let model = load::<ModelWeights>(&jais_gguf("jais", "Jais 13b Chat", None)?)?;
+        assert_eq!(model.max_seq_len, 64);
+
+        let out = logits(&model, &[1, 5, 7], 0)?;
+        assert_eq!(out.len(), 16);
+        assert_finite(&out);
+
+        // Decoding a token reuses the KV cache of the prompt.
+        assert_eq!(logits(&model, &[3], 3)?.len(), 16);
+        assert_eq!(model.cache.normal().0[0].current_seq_len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn decoding_matches_prompt() -> candle_core::Result<()> {
+        // The ALiBi bias of the decoded token depends on its distance to the cached tokens.
+        for clip_key in [
+            None,
+            Some("attention.clip_qkv"),
+            Some("attention.clamp_kqv"),
+        ] {
+            let file = jais_gguf("jais", "Jais 13b Chat", clip_key)?;
+            assert_decoding_matches_prompt(&file, &[&[1, 5, 7], &[3]], logits)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn clip_qkv_key() -> candle_core::Result<()> {
+        for clip_key in ["attention.clip_qkv", "attention.clamp_kqv"] {
+            let model = load::<ModelWeights>(&jais_gguf("jais", "Jais", Some(clip_key))?)?;
+            assert_eq!(model.layers[0].clip_qkv, Some(0.5));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn declared_as_gpt2() -> candle_core::Result<()> {
+        // The Jais metadata is read from the `gpt2` prefix.
+        let model = load::<ModelWeights>(&jais_gguf("gpt2", "jais-13b-chat", None)?)?;
+        assert_eq!(model.max_seq_len, 64);
+        assert_finite(&logits(&model, &[1, 5, 7], 0)?);
+
+        // Other GPT-2 models are not Jais.
+        assert!(load::<ModelWeights>(&jais_gguf("gpt2", "gpt2-medium", None)?).is_err());
+        Ok(())
+    }
+}
@@ -4,16 +4,18 @@ use std::collections::HashMap;
 use std::sync::Arc;
 
 use candle_core::quantized::ggml_file;
-use candle_core::quantized::QTensor;
+use candle_core::quantized::{GgmlDType, QTensor};
 use candle_core::{DType, Device, Result, Tensor};
-use candle_nn::{Embedding, Module};
+use candle_nn::{Embedding, Module, Var, VarMap};
 use indicatif::MultiProgress;
 use mistralrs_quant::{GgufMatMul, QuantMethod, QuantMethodConfig};
 
 use crate::attention::SdpaParams;
 use crate::device_map::DeviceMapper;
 use crate::gguf::Content;
-use crate::layers::{CausalMasker, MatMul, QRmsNorm, RotaryEmbedding, Sdpa};
+use crate::layers::{
+    repeat_kv, split_qtensor, CausalMasker, MatMul, QRmsNorm, RotaryEmbedding, Sdpa,
+};
 use crate::layers_masker::PastKvLenCache;
 use crate::paged_attention::{AttentionImplementation, PagedAttention};
 use crate::pipeline::extract_logits;
@@ -21,11 +23,39 @@ use crate::pipeline::text_models_inputs_processor::PagedAttentionInputMetadata;
 use crate::pipeline::EitherCache;
 use crate::pipeline::KvCache;
 use crate::pipeline::NormalCache;
+use crate::pipeline::NormalCacheType;
+use crate::utils::debug::DebugTensors;
 use crate::utils::gguf_metadata::ContentMetadata;
 use crate::utils::model_config as ModelConfig;
 use crate::utils::progress::NiceProgressBar;
 const MAX_SEQ_LEN: u32 = 4096;
 
+/// Which layers attend to the whole context when the model has a sliding window.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum AttentionPattern {
+    /// All layers use the sliding window, if any.
+    Uniform,
+    /// Grouped sliding window attention, as in Mistral v0.3: the listed layers attend to the
+    /// whole context and the other layers use the sliding window.
+    Interleaved { full_attn_layers: Vec<usize> },
+}
+
+impl AttentionPattern {
+    /// The sliding window of a layer, `None` if it attends to the whole context.
+    fn layer_sliding_window(
+        &self,
+        layer_idx: usize,
+        sliding_window: Option<usize>,
+    ) -> Option<usize> {
+        match self {
+            Self::Uniform => sliding_window,
+            Self::Interleaved { full_attn_layers } => {
+                sliding_window.filter(|_| !full_attn_layers.contains(&layer_idx))
+            }
+        }
+    }
+}
+
 struct Mlp {
     feed_forward_w1: Arc<dyn QuantMethod>,
     feed_forward_w2: Arc<dyn QuantMethod>,
@@ -33,10 +63,15 @@ struct Mlp {
 }
 
 impl Mlp {
-    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+    fn forward(&self, xs: &Tensor, debug: Option<&DebugTensors>) -> Result<Tensor> {
         let w1 = MatMul.qmethod_matmul(xs, &*self.feed_forward_w1)?;
         let w3 = MatMul.qmethod_matmul(xs, &*self.feed_forward_w3)?;
-        let y = &(candle_nn::ops::silu(&w1)? * w3)?;
+        let y = &(candle_nn::ops::silu(&w1)? * &w3)?;
+        if let Some(debug) = debug {
+            debug.insert("ffn_gate", &w1);
+            debug.insert("ffn_up", &w3);
+            debug.insert("ffn_act", y);
+        }
         MatMul.qmethod_matmul(y, &*self.feed_forward_w2)
     }
 }
@@ -51,7 +86,7 @@ enum MlpOrMoe {
 }
 
 impl MlpOrMoe {
-    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+    fn forward(&self, xs: &Tensor, debug: Option<&DebugTensors>) -> Result<Tensor> {
         match self {
             Self::MoE {
                 feed_forward_gate_inp,
@@ -61,6 +96,9 @@ impl MlpOrMoe {
                 let (b_size, seq_len, hidden_dim) = xs.dims3()?;
                 let xs = xs.reshape(((), hidden_dim))?;
                 let router_logits = MatMul.qmethod_matmul(&xs, &**feed_forward_gate_inp)?;
+                if let Some(debug) = debug {
+                    debug.insert("ffn_router_logits", &router_logits);
+                }
                 let routing_weights = candle_nn::ops::softmax_last_dim(&router_logits)?;
 
                 // In order to extract topk, we extract the data from the tensor and manipulate it
@@ -106,7 +144,7 @@ impl MlpOrMoe {
                     // states by `routing_weights` on the corresponding tokens (top-1 and top-2)
                     let current_state = xs.index_select(&top_x, 0)?.reshape(((), hidden_dim))?;
                     // current_hidden_states = expert_layer(current_state, routing_weights[top_x_list, idx_list, None])
-                    let current_hidden_states = expert_layer.forward(&current_state)?;
+                    let current_hidden_states = expert_layer.forward(&current_state, None)?;
                     let current_hidden_states =
                         current_hidden_states.broadcast_mul(&selected_rws)?;
                     ys = ys.index_add(&top_x, &current_hidden_states, 0)?;
@@ -115,7 +153,7 @@ impl MlpOrMoe {
                 let ys = ys.reshape((b_size, seq_len, hidden_dim))?;
                 Ok(ys)
             }
-            Self::Mlp(mlp) => mlp.forward(xs),
+            Self::Mlp(mlp) => mlp.forward(xs, debug),
         }
     }
 }
@@ -132,6 +170,7 @@ struct LayerWeights {
     n_kv_head: usize,
     head_dim: usize,
     rotary: Arc<RotaryEmbedding>,
+    sliding_window: Option<usize>,
     paged_attn: Option<PagedAttention>,
     sdpa_params: SdpaParams,
     dtype: DType,
@@ -145,6 +184,7 @@ impl LayerWeights {
         start_offsets: &[usize],
         kv_cache: &mut KvCache,
         metadata: Option<((Tensor, Tensor), &PagedAttentionInputMetadata)>,
+        debug: Option<&DebugTensors>,
     ) -> Result<Tensor> {
         let (b_sz, seq_len, _) = x.dims3()?;
 
@@ -177,6 +217,11 @@ impl LayerWeights {
         };
 
         let (q, k) = self.rotary.forward(&q, &k, start_offsets)?;
+        if let Some(debug) = debug {
+            debug.insert("attn_q", &q);
+            debug.insert("attn_k", &k);
+            debug.insert("attn_v", &v);
+        }
 
         let y = match &self.paged_attn {
             Some(paged_attn) => {
@@ -195,6 +240,9 @@ impl LayerWeights {
             }
             None => {
                 let (k, v) = kv_cache.append(&k, &v)?;
+                if let Some(debug) = debug {
+                    debug.insert("attn_scores", &self.attention_scores(&q, &k, mask)?);
+                }
 
                 Sdpa.run_attention(&q, &k, &v, mask, None, &self.sdpa_params)?
             }
@@ -209,6 +257,21 @@ impl LayerWeights {
         let y = MatMul.qmethod_matmul(&y.to_dtype(x.dtype())?, &*self.attention_wo)?;
         Ok(y)
     }
+
+    /// The attention probabilities over the cached keys, which the fused attention does not
+    /// expose, recomputed for debugging.
+    fn attention_scores(&self, q: &Tensor, k: &Tensor, mask: Option<&Tensor>) -> Result<Tensor> {
+        let k = repeat_kv(k.clone(), self.sdpa_params.n_kv_groups)?;
+        let scores = (q
+            .to_dtype(DType::F32)?
+            .matmul(&k.to_dtype(DType::F32)?.t()?)?
+            * f64::from(self.sdpa_params.softmax_scale))?;
+        let scores = match mask {
+            Some(mask) => scores.broadcast_add(&mask.to_dtype(DType::F32)?)?,
+            None => scores,
+        };
+        candle_nn::ops::softmax_last_dim(&scores)
+    }
 }
 
 pub struct ModelWeights {
@@ -216,11 +279,13 @@ pub struct ModelWeights {
     layers: Vec<LayerWeights>,
     norm: QRmsNorm,
     output: Arc<dyn QuantMethod>,
+    sliding_window: Option<usize>,
     pub device: Device,
     pub cache: EitherCache,
     pub max_seq_len: usize,
     mapper: Option<Box<dyn DeviceMapper + Send + Sync>>,
     dtype: DType,
+    pub debug: DebugTensors,
 }
 
 impl ModelConfig::FromGGML for ModelWeights {
@@ -295,6 +360,7 @@ impl ModelConfig::FromGGML for ModelWeights {
                 n_kv_head: ct.hparams.n_head as usize / gqa,
                 head_dim: (ct.hparams.n_embd / ct.hparams.n_head) as usize,
                 rotary: rotary.clone().into(),
+                sliding_window: None,
                 paged_attn: None, // TODO
                 sdpa_params: SdpaParams {
                     n_kv_groups: ct.hparams.n_head as usize / n_kv_head,
@@ -314,6 +380,7 @@ impl ModelConfig::FromGGML for ModelWeights {
                 q_weight: Arc::new(output),
                 b: None,
             })?),
+            sliding_window: None,
             device: ct.device.clone(),
             cache: EitherCache::Normal(NormalCache::new(
                 ct.hparams.n_layer as usize,
@@ -322,6 +389,7 @@ impl ModelConfig::FromGGML for ModelWeights {
             max_seq_len: MAX_SEQ_LEN as usize, // Cannot determine from ggml.
             mapper: None,
             dtype,
+            debug: DebugTensors::default(),
         })
     }
 }
@@ -342,6 +410,8 @@ pub(crate) struct PropsGGUF {
     pub rope_freq_base: f32,
     pub key_length: usize,
     pub value_length: usize,
+    pub sliding_window: Option<usize>,
+    pub attention_pattern: AttentionPattern,
 }
 
 impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
@@ -362,15 +432,65 @@ impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
 
         let embed_len = c.get_value::<u32>("embedding_length")? as usize;
         let head_count = c.get_value::<u32>("attention.head_count")? as usize;
+        let block_count = c.get_value::<u32>("block_count")? as usize;
+
+        let sliding_window = c
+            .get_option_value::<u32>("attention.sliding_window")?
+            .map(|x| x as usize);
+        // Models with grouped sliding window attention give the attention type of every layer.
+        let attn_types = (0..block_count)
+            .map(|layer_idx| {
+                let key = format!("blk.{layer_idx}.attn_type");
+                c.metadata
+                    .get(&key)
+                    .map(|ty| {
+                        ty.to_string()
+                            .cloned()
+                            .map_err(|e| anyhow::anyhow!("`{key}` `{e}`"))
+                    })
+                    .transpose()
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let attention_pattern = if attn_types.iter().all(Option::is_none) {
+            AttentionPattern::Uniform
+        } else {
+            if sliding_window.is_none() {
+                anyhow::bail!("Per-layer attention types require `attention.sliding_window`.");
+            }
+            let mut full_attn_layers = Vec::new();
+            for (layer_idx, ty) in attn_types.iter().enumerate() {
+                match ty.as_deref() {
+                    Some("full") => full_attn_layers.push(layer_idx),
+                    Some("sliding") => (),
+                    Some(ty) => {
+                        anyhow::bail!("Unknown attention type `{ty}` of layer {layer_idx}.")
+                    }
+                    None => anyhow::bail!("Missing the attention type of layer {layer_idx}."),
+                }
+            }
+            AttentionPattern::Interleaved { full_attn_layers }
+        };
+
+        // Mixtral and other MoE models are stored as Llama with expert tensors.
+        let n_expert = c.get_value::<u32>("expert_count").ok().unwrap_or(0) as usize;
+        let n_expert_used = if n_expert > 1 {
+            let n_expert_used = c.get_value::<u32>("expert_used_count")? as usize;
+            if n_expert_used == 0 || n_expert_used > n_expert {
+                anyhow::bail!("Cannot route each token to {n_expert_used} of {n_expert} experts.");
+            }
+            n_expert_used
+        } else {
+            0
+        };
 
         // NOTE: Values are not aligned with GGUFv3 types
         // TODO: Normalize value types to spec
         let props = Self {
-            n_expert: c.get_value::<u32>("expert_count").ok().unwrap_or(0) as usize,
-            n_expert_used: c.get_value::<u32>("expert_used_count").ok().unwrap_or(0) as usize,
+            n_expert,
+            n_expert_used,
             head_count,
             head_count_kv: c.get_value::<u32>("attention.head_count_kv")? as usize,
-            block_count: c.get_value::<u32>("block_count")? as usize,
+            block_count,
             embedding_length: embed_len,
             rope_dim: c.get_value::<u32>("rope.dimension_count")? as usize,
             // Strangely this value is generally 1e-6 in GGUF file but used to be 1e-5 by default.
@@ -390,6 +510,8 @@ impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
                 .ok()
                 .map(|x| x as usize)
                 .unwrap_or(embed_len / head_count),
+            sliding_window,
+            attention_pattern,
         };
 
         Ok(props)
@@ -422,6 +544,8 @@ impl ModelConfig::FromGGUF for ModelWeights {
             rope_freq_base,
             key_length,
             value_length,
+            sliding_window,
+            attention_pattern,
         } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
 
         let qtok_embeddings = ct.tensor("token_embd.weight", device)?;
@@ -464,6 +588,8 @@ impl ModelConfig::FromGGUF for ModelWeights {
         ) {
             let prefix = format!("blk.{layer_idx}");
             let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+            let layer_sliding_window =
+                attention_pattern.layer_sliding_window(layer_idx, sliding_window);
             let rotary = ropes
                 .get(&device.location())
                 .expect("No RoPE for device location!")
@@ -502,44 +628,31 @@ impl ModelConfig::FromGGUF for ModelWeights {
                         let feed_forward_up_exps =
                             ct.tensor(&format!("{prefix}.ffn_up_exps.weight"), device)?;
 
-                        let dequant_ffn_gate = feed_forward_gate_exps
-                            .dequantize(device)?
-                            .chunk(n_expert, 0)?;
-                        let dequant_ffn_down = feed_forward_down_exps
-                            .dequantize(device)?
-                            .chunk(n_expert, 0)?;
-                        let dequant_ffn_up = feed_forward_up_exps
-                            .dequantize(device)?
-                            .chunk(n_expert, 0)?;
-
-                        assert_eq!(dequant_ffn_up.len(), dequant_ffn_down.len());
-                        assert_eq!(dequant_ffn_gate.len(), dequant_ffn_down.len());
-                        assert_eq!(dequant_ffn_gate.len(), n_expert);
-
-                        let gate_type = feed_forward_gate_exps.dtype();
-                        let down_type = feed_forward_down_exps.dtype();
-                        let up_type = feed_forward_up_exps.dtype();
-
-                        for (ff_w1, (ff_w2, ff_w3)) in dequant_ffn_gate
-                            .into_iter()
-                            .zip(dequant_ffn_down.into_iter().zip(dequant_ffn_up))
+                        // The experts are stacked along the first dimension, and are split
+                        // without dequantizing them.
+                        let ffn_gate = split_qtensor(&feed_forward_gate_exps, n_expert, device)?;
+                        let ffn_down = split_qtensor(&feed_forward_down_exps, n_expert, device)?;
+                        let ffn_up = split_qtensor(&feed_forward_up_exps, n_expert, device)?;
+
+                        for (ff_w1, (ff_w2, ff_w3)) in
+                            ffn_gate.into_iter().zip(ffn_down.into_iter().zip(ffn_up))
                         {
                             experts.push(Mlp {
                                 feed_forward_w1: Arc::new(GgufMatMul::new(
                                     QuantMethodConfig::Gguf {
-                                        q_weight: Arc::new(QTensor::quantize(&ff_w1, gate_type)?),
+                                        q_weight: Arc::new(ff_w1),
                                         b: None,
                                     },
                                 )?),
                                 feed_forward_w2: Arc::new(GgufMatMul::new(
                                     QuantMethodConfig::Gguf {
-                                        q_weight: Arc::new(QTensor::quantize(&ff_w2, down_type)?),
+                                        q_weight: Arc::new(ff_w2),
                                         b: None,
                                     },
                                 )?),
                                 feed_forward_w3: Arc::new(GgufMatMul::new(
                                     QuantMethodConfig::Gguf {
-                                        q_weight: Arc::new(QTensor::quantize(&ff_w3, up_type)?),
+                                        q_weight: Arc::new(ff_w3),
                                         b: None,
                                     },
                                 )?),
@@ -618,17 +731,26 @@ impl ModelConfig::FromGGUF for ModelWeights {
                 n_kv_head: head_count_kv,
                 head_dim,
                 rotary: rotary.clone(),
+                sliding_window: layer_sliding_window,
                 paged_attn,
                 sdpa_params: SdpaParams {
                     n_kv_groups: head_count / head_count_kv,
                     use_flash_attn: false,
                     softcap: None,
                     softmax_scale: 1.0 / (head_dim as f32).sqrt(),
-                    sliding_window: None,
+                    sliding_window: layer_sliding_window,
                 },
                 dtype,
             })
         }
+
+        let cache_types = layers
+            .iter()
+            .map(|layer| match layer.sliding_window {
+                Some(window) => NormalCacheType::SlidingWindow { window },
+                None => NormalCacheType::Normal { max_seq_len },
+            })
+            .collect();
         Ok(Self {
             tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
             layers,
@@ -637,11 +759,13 @@ impl ModelConfig::FromGGUF for ModelWeights {
                 q_weight: Arc::new(output),
                 b: None,
             })?),
+            sliding_window,
             device: device.clone(),
-            cache: EitherCache::Normal(NormalCache::new(block_count, max_seq_len)),
+            cache: EitherCache::Normal(NormalCache::from_types(cache_types)),
             max_seq_len,
             mapper: Some(mapper),
             dtype,
+            debug: DebugTensors::default(),
         })
     }
 }
@@ -656,32 +780,54 @@ impl ModelWeights {
     ) -> Result<Tensor> {
         let mut layer_in = self.tok_embeddings.forward(x)?;
         let cache = &mut self.cache.normal().0;
+        let past_kv_len_cache = metadata
+            .as_ref()
+            .map(|(_, _)| &start_offsets as &dyn PastKvLenCache)
+            .unwrap_or(cache as &dyn PastKvLenCache);
         let mask = CausalMasker.make_causal_mask_matrix(
             x,
-            metadata
-                .as_ref()
-                .map(|(_, _)| &start_offsets as &dyn PastKvLenCache)
-                .unwrap_or(cache as &dyn PastKvLenCache),
+            past_kv_len_cache,
             self.dtype,
             self.layers[0].n_head,
         )?;
+        let sliding_mask = match self.sliding_window {
+            Some(sliding_window) => CausalMasker.make_sliding_window_causal_mask_matrix(
+                x,
+                past_kv_len_cache,
+                Some(sliding_window),
+                self.dtype,
+                self.layers[0].n_head,
+            )?,
+            None => None,
+        };
         // PagedAttention prompt chunking
-        let mask = mask.filter(|_| {
-            metadata
-                .as_ref()
-                .map(|(_, meta)| meta.is_first_prompt_chunk)
-                .unwrap_or(true)
-        });
+        let is_first_prompt_chunk = metadata
+            .as_ref()
+            .map(|(_, meta)| meta.is_first_prompt_chunk)
+            .unwrap_or(true);
+        let mask = mask.filter(|_| is_first_prompt_chunk);
+        let sliding_mask = sliding_mask.filter(|_| is_first_prompt_chunk);
         for (i, layer) in self.layers.iter().enumerate() {
             if let Some(ref mapper) = self.mapper {
                 layer_in = mapper.map(layer_in, i)?;
             }
+            let layer_mask = if layer.sliding_window.is_some() {
+                &sliding_mask
+            } else {
+                &mask
+            };
+            let debug = self.debug.for_layer(i);
             let x = layer_in;
             let residual = &x;
             let x = layer.attention_norm.forward(&x)?;
+            if let Some(debug) = debug {
+                debug.insert("hidden_states_in", residual);
+                debug.insert("attn_norm", &x);
+            }
             let attn = layer.forward_attn(
                 &x,
-                mask.as_ref()
+                layer_mask
+                    .as_ref()
                     .map(|m| m.to_device(x.device()).unwrap())
                     .as_ref(),
                 start_offsets,
@@ -689,14 +835,26 @@ impl ModelWeights {
                 metadata
                     .as_ref()
                     .map(|(kv_cache, metadata)| (kv_cache[i].clone(), *metadata)),
+                debug,
             )?;
+            if let Some(debug) = debug {
+                debug.insert("attn_output", &attn);
+            }
             let x = (attn + residual)?;
 
             // MLP
             let residual = &x;
             let x = layer.ffn_norm.forward(&x)?;
-            let x = layer.mlp_or_moe.forward(&x)?;
+            if let Some(debug) = debug {
+                debug.insert("attn_residual", residual);
+                debug.insert("ffn_norm", &x);
+            }
+            let x = layer.mlp_or_moe.forward(&x, debug)?;
             let x = (x + residual)?;
+            if let Some(debug) = debug {
+                debug.insert("hidden_states_out", &x);
+                debug.finish();
+            }
             layer_in = x;
         }
         let layer_in = layer_in.to_device(&self.device)?;
@@ -707,3 +865,609 @@ impl ModelWeights {
         )
     }
 }
+
+enum NamedWeight<'a> {
+    Linear(&'a mut Arc<dyn QuantMethod>),
+    Norm(&'a mut QRmsNorm),
+}
+
+impl ModelWeights {
+    /// All weights except the token embeddings, using the GGUF tensor names.
+    fn named_weights_mut(&mut self) -> Vec<(String, NamedWeight<'_>)> {
+        let mut weights = Vec::new();
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            let prefix = format!("blk.{i}");
+            weights.push((
+                format!("{prefix}.attn_q.weight"),
+                NamedWeight::Linear(&mut layer.attention_wq),
+            ));
+            weights.push((
+                format!("{prefix}.attn_k.weight"),
+                NamedWeight::Linear(&mut layer.attention_wk),
+            ));
+            weights.push((
+                format!("{prefix}.attn_v.weight"),
+                NamedWeight::Linear(&mut layer.attention_wv),
+            ));
+            weights.push((
+                format!("{prefix}.attn_output.weight"),
+                NamedWeight::Linear(&mut layer.attention_wo),
+            ));
+            weights.push((
+                format!("{prefix}.attn_norm.weight"),
+                NamedWeight::Norm(&mut layer.attention_norm),
+            ));
+            weights.push((
+                format!("{prefix}.ffn_norm.weight"),
+                NamedWeight::Norm(&mut layer.ffn_norm),
+            ));
+            match &mut layer.mlp_or_moe {
+                MlpOrMoe::Mlp(mlp) => {
+                    weights.push((
+                        format!("{prefix}.ffn_gate.weight"),
+                        NamedWeight::Linear(&mut mlp.feed_forward_w1),
+                    ));
+                    weights.push((
+                        format!("{prefix}.ffn_down.weight"),
+                        NamedWeight::Linear(&mut mlp.feed_forward_w2),
+                    ));
+                    weights.push((
+                        format!("{prefix}.ffn_up.weight"),
+                        NamedWeight::Linear(&mut mlp.feed_forward_w3),
+                    ));
+                }
+                MlpOrMoe::MoE {
+                    feed_forward_gate_inp,
+                    experts,
+                    ..
+                } => {
+                    weights.push((
+                        format!("{prefix}.ffn_gate_inp.weight"),
+                        NamedWeight::Linear(feed_forward_gate_inp),
+                    ));
+                    for (j, mlp) in experts.iter_mut().enumerate() {
+                        weights.push((
+                            format!("{prefix}.ffn_gate.{j}.weight"),
+                            NamedWeight::Linear(&mut mlp.feed_forward_w1),
+                        ));
+                        weights.push((
+                            format!("{prefix}.ffn_down.{j}.weight"),
+                            NamedWeight::Linear(&mut mlp.feed_forward_w2),
+                        ));
+                        weights.push((
+                            format!("{prefix}.ffn_up.{j}.weight"),
+                            NamedWeight::Linear(&mut mlp.feed_forward_w3),
+                        ));
+                    }
+                }
+            }
+        }
+        weights.push((
+            "output_norm.weight".to_string(),
+            NamedWeight::Norm(&mut self.norm),
+        ));
+        weights.push((
+            "output.weight".to_string(),
+            NamedWeight::Linear(&mut self.output),
+        ));
+        weights
+    }
+
+    /// Dequantize all weights into a `VarMap`, keyed by the GGUF tensor names.
+    pub fn export_varmap(&mut self, dtype: DType) -> Result<VarMap> {
+        let varmap = VarMap::new();
+        {
+            let mut data = varmap.data().lock().unwrap();
+            data.insert(
+                "token_embd.weight".to_string(),
+                Var::from_tensor(&self.tok_embeddings.embeddings().to_dtype(dtype)?)?,
+            );
+            for (name, weight) in self.named_weights_mut() {
+                let tensor = match weight {
+                    NamedWeight::Linear(w) => w.dequantize_w()?,
+                    NamedWeight::Norm(norm) => norm.weight().clone(),
+                };
+                data.insert(name, Var::from_tensor(&tensor.to_dtype(dtype)?)?);
+            }
+        }
+        Ok(varmap)
+    }
+
+    /// Replace the weights with those in `varmap`, quantizing the linear layers to `quant`.
+    /// Weights missing from `varmap` are left unchanged.
+    pub fn load_from_varmap(&mut self, varmap: &VarMap, quant: GgmlDType) -> Result<()> {
+        let data = varmap.data().lock().unwrap();
+        if let Some(var) = data.get("token_embd.weight") {
+            let embeddings = self.tok_embeddings.embeddings();
+            let (dtype, device) = (embeddings.dtype(), embeddings.device().clone());
+            let hidden_size = embeddings.dim(1)?;
+            self.tok_embeddings = Embedding::new(
+                var.as_tensor().to_device(&device)?.to_dtype(dtype)?,
+                hidden_size,
+            );
+        }
+        for (name, weight) in self.named_weights_mut() {
+            let Some(var) = data.get(&name) else {
+                continue;
+            };
+            match weight {
+                NamedWeight::Linear(w) => {
+                    let (_, device) = w.dtype_and_device();
+                    let tensor = var.as_tensor().to_device(&device)?.to_dtype(DType::F32)?;
+                    *w = Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                        q_weight: Arc::new(QTensor::quantize(&tensor, quant)?),
+                        b: None,
+                    })?);
+                }
+                NamedWeight::Norm(norm) => {
+                    let old = norm.weight();
+                    let tensor = var
+                        .as_tensor()
+                        .to_device(old.device())?
+                        .to_dtype(old.dtype())?;
+                    norm.set_weight(tensor);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, io::Cursor};
+
+    use candle_core::{
+        quantized::{gguf_file, GgmlDType, QTensor},
+        DType, Device, Tensor,
+    };
+
+    use super::{AttentionPattern, MlpOrMoe, ModelWeights, PropsGGUF};
+    use crate::{
+        gguf::Content,
+        paged_attention::AttentionImplementation,
+        pipeline::KvCache,
+        utils::{gguf_metadata::ContentMetadata, model_config::FromGGUF},
+        DeviceMapSetting,
+    };
+
+    /// Metadata of a Llama model with four layers, a sliding window of 2 tokens and the given
+    /// attention types.
+    fn metadata(attn_types: &[&str]) -> Vec<(String, gguf_file::Value)> {
+        let mut metadata = vec![
+            (
+                "general.architecture".to_string(),
+                gguf_file::Value::String("llama".to_string()),
+            ),
+            ("llama.block_count".to_string(), gguf_file::Value::U32(4)),
+            (
+                "llama.embedding_length".to_string(),
+                gguf_file::Value::U32(32),
+            ),
+            (
+                "llama.rope.dimension_count".to_string(),
+                gguf_file::Value::U32(8),
+            ),
+            (
+                "llama.attention.head_count".to_string(),
+                gguf_file::Value::U32(4),
+            ),
+            (
+                "llama.attention.head_count_kv".to_string(),
+                gguf_file::Value::U32(2),
+            ),
+            (
+                "llama.attention.layer_norm_rms_epsilon".to_string(),
+                gguf_file::Value::F32(1e-5),
+            ),
+            (
+                "llama.attention.sliding_window".to_string(),
+                gguf_file::Value::U32(2),
+            ),
+        ];
+        for (layer_idx, ty) in attn_types.iter().enumerate() {
+            metadata.push((
+                format!("blk.{layer_idx}.attn_type"),
+                gguf_file::Value::String(ty.to_string()),
+            ));
+        }
+        metadata
+    }
+
+    fn props(attn_types: &[&str]) -> anyhow::Result<PropsGGUF> {
+        let metadata = metadata(attn_types).into_iter().collect::<HashMap<_, _>>();
+        PropsGGUF::try_from(ContentMetadata {
+            path_prefix: "llama",
+            metadata: &metadata,
+        })
+    }
+
+    #[test]
+    fn attention_pattern() {
+        let gswa = props(&["sliding", "sliding", "sliding", "full"]).unwrap();
+        assert_eq!(
+            gswa.attention_pattern,
+            AttentionPattern::Interleaved {
+                full_attn_layers: vec![3]
+            }
+        );
+        let windows = (0..4)
+            .map(|i| {
+                gswa.attention_pattern
+                    .layer_sliding_window(i, gswa.sliding_window)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(windows, [Some(2), Some(2), Some(2), None]);
+
+        // Without attention types, all layers use the sliding window.
+        let uniform = props(&[]).unwrap();
+        assert_eq!(uniform.attention_pattern, AttentionPattern::Uniform);
+        assert_eq!(
+            uniform.attention_pattern.layer_sliding_window(3, Some(2)),
+            Some(2)
+        );
+
+        assert!(props(&["sliding", "full"]).is_err());
+        assert!(props(&["sliding", "global", "sliding", "full"]).is_err());
+    }
+
+    /// Write a GGUF file of a random model with four layers, with a dense FFN, or with stacked
+    /// experts if `n_expert` is not 0.
+    fn llama_gguf(
+        dev: &Device,
+        metadata: &[(String, gguf_file::Value)],
+        n_expert: usize,
+    ) -> candle_core::Result<Vec<u8>> {
+        let (vocab, hidden, kv_dim) = (16, 32, 16);
+        let linear = |out_dim: usize, in_dim: usize| -> candle_core::Result<QTensor> {
+            let w = (Tensor::randn(0f32, 1f32, (out_dim, in_dim), dev)? * 0.1)?;
+            QTensor::quantize(&w, GgmlDType::Q8_0)
+        };
+        let norm = || -> candle_core::Result<QTensor> {
+            QTensor::quantize(&Tensor::ones(hidden, DType::F32, dev)?, GgmlDType::F32)
+        };
+        let mut tensors = vec![
+            ("token_embd.weight".to_string(), linear(vocab, hidden)?),
+            ("output_norm.weight".to_string(), norm()?),
+        ];
+        for layer in 0..4 {
+            tensors.extend([
+                (format!("blk.{layer}.attn_norm.weight"), norm()?),
+                (format!("blk.{layer}.ffn_norm.weight"), norm()?),
+                (
+                    format!("blk.{layer}.attn_q.weight"),
+                    linear(hidden, hidden)?,
+                ),
+                (
+                    format!("blk.{layer}.attn_k.weight"),
+                    linear(kv_dim, hidden)?,
+                ),
+                (
+                    format!("blk.{layer}.attn_v.weight"),
+                    linear(kv_dim, hidden)?,
+                ),
+                (
+                    format!("blk.{layer}.attn_output.weight"),
+                    linear(hidden, hidden)?,
+                ),
+            ]);
+            if n_expert == 0 {
+                tensors.extend([
+                    (
+                        format!("blk.{layer}.ffn_gate.weight"),
+                        linear(2 * hidden, hidden)?,
+                    ),
+                    (
+                        format!("blk.{layer}.ffn_up.weight"),
+                        linear(2 * hidden, hidden)?,
+                    ),
+                    (
+                        format!("blk.{layer}.ffn_down.weight"),
+                        linear(hidden, 2 * hidden)?,
+                    ),
+                ]);
+            } else {
+                let experts = |out_dim: usize, in_dim: usize| -> candle_core::Result<QTensor> {
+                    let w = (Tensor::randn(0f32, 1f32, (n_expert, out_dim, in_dim), dev)? * 0.1)?;
+                    QTensor::quantize(&w, GgmlDType::Q8_0)
+                };
+                tensors.extend([
+                    (
+                        format!("blk.{layer}.ffn_gate_inp.weight"),
+                        linear(n_expert, hidden)?,
+                    ),
+                    (
+                        format!("blk.{layer}.ffn_gate_exps.weight"),
+                        experts(2 * hidden, hidden)?,
+                    ),
+                    (
+                        format!("blk.{layer}.ffn_up_exps.weight"),
+                        experts(2 * hidden, hidden)?,
+                    ),
+                    (
+                        format!("blk.{layer}.ffn_down_exps.weight"),
+                        experts(hidden, 2 * hidden)?,
+                    ),
+                ]);
+            }
+        }
+        let mut buf = Cursor::new(Vec::new());
+        gguf_file::write(
+            &mut buf,
+            &metadata
+                .iter()
+                .map(|(k, v)| (k.as_str(), v))
+                .collect::<Vec<_>>(),
+            &tensors
+                .iter()
+                .map(|(n, t)| (n.as_str(), t))
+                .collect::<Vec<_>>(),
+        )?;
+        Ok(buf.into_inner())
+    }
+
+    fn load(dev: &Device, file: &[u8]) -> candle_core::Result<ModelWeights> {
+        let mut reader = Cursor::new(file);
+        let mut readers = [&mut reader];
+        let content = Content::from_readers(&mut readers)?;
+        let mapper = DeviceMapSetting::dummy().into_mapper(4, dev, None)?;
+        ModelWeights::from_gguf(
+            content,
+            dev,
+            mapper,
+            AttentionImplementation::Eager,
+            DType::F32,
+        )
+    }
+
+    /// A random model with the interleaved attention pattern.
+    fn model(dev: &Device) -> candle_core::Result<ModelWeights> {
+        let metadata = metadata(&["sliding", "full", "sliding", "full"]);
+        load(dev, &llama_gguf(dev, &metadata, 0)?)
+    }
+
+    #[test]
+    fn interleaved_forward() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let model = model(&dev)?;
+
+        // The sliding window layers keep a rotating KV cache, the other layers the whole context.
+        let input_ids = Tensor::new(&[[1u32, 5, 7, 3]], &dev)?;
+        let logits = model.forward(&input_ids, &[0], vec![(3, 1)], None)?;
+        assert_eq!(logits.dims(), [1, 1, 16]);
+        let cache = &model.cache.normal().0;
+        assert!(matches!(cache[0], KvCache::Rotating { .. }));
+        assert!(matches!(cache[1], KvCache::Normal { .. }));
+        assert!(matches!(cache[2], KvCache::Rotating { .. }));
+        assert!(matches!(cache[3], KvCache::Normal { .. }));
+
+        let input_ids = Tensor::new(&[[2u32]], &dev)?;
+        let logits = model.forward(&input_ids, &[4], vec![(0, 1)], None)?;
+        assert!(logits
+            .flatten_all()?
+            .to_vec1::<f32>()?
+            .iter()
+            .all(|x| x.is_finite()));
+        Ok(())
+    }
+
+    #[test]
+    fn debug_tensors() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let model = model(&dev)?;
+        model.debug.set_layer(1);
+
+        let input_ids = Tensor::new(&[[1u32, 5, 7]], &dev)?;
+        model.forward(&input_ids, &[0], vec![(2, 1)], None)?;
+        let tensors = model.debug.tensors();
+        assert_eq!(tensors["hidden_states_in"].dims(), [1, 3, 32]);
+        assert_eq!(tensors["hidden_states_out"].dims(), [1, 3, 32]);
+        assert_eq!(tensors["ffn_act"].dims(), [1, 3, 64]);
+        // One probability per head, query and key.
+        let scores = &tensors["attn_scores"];
+        assert_eq!(scores.dims(), [1, 4, 3, 3]);
+        let sums = scores.sum(3)?.flatten_all()?.to_vec1::<f32>()?;
+        assert!(sums.iter().all(|s| (s - 1.).abs() < 1e-4));
+
+        // The capture is disarmed after the layer.
+        let input_ids = Tensor::new(&[[2u32]], &dev)?;
+        model.forward(&input_ids, &[3], vec![(0, 1)], None)?;
+        assert_eq!(model.debug.tensors()["attn_scores"].dims(), [1, 4, 3, 3]);
+        Ok(())
+    }
+
+    /// The weights of a random Llama model with two layers and an embedding length of 256, the
+    /// block size of the k-quants.
+    fn coherence_weights(dev: &Device) -> candle_core::Result<Vec<(String, Tensor)>> {
+        let (vocab, hidden, kv_dim) = (16, 256, 128);
+        let linear = |out_dim: usize, in_dim: usize| -> candle_core::Result<Tensor> {
+            Tensor::randn(0f32, 1f32, (out_dim, in_dim), dev)? * 0.1
+        };
+        let norm = || Tensor::ones(hidden, DType::F32, dev);
+        let mut weights = vec![
+            ("token_embd.weight".to_string(), linear(vocab, hidden)?),
+            ("output_norm.weight".to_string(), norm()?),
+        ];
+        for layer in 0..2 {
+            weights.extend([
+                (format!("blk.{layer}.attn_norm.weight"), norm()?),
+                (format!("blk.{layer}.ffn_norm.weight"), norm()?),
+                (
+                    format!("blk.{layer}.attn_q.weight"),
+                    linear(hidden, hidden)?,
+                ),
+                (
+                    format!("blk.{layer}.attn_k.weight"),
+                    linear(kv_dim, hidden)?,
+                ),
+                (
+                    format!("blk.{layer}.attn_v.weight"),
+                    linear(kv_dim, hidden)?,
+                ),
+                (
+                    format!("blk.{layer}.attn_output.weight"),
+                    linear(hidden, hidden)?,
+                ),
+                (
+                    format!("blk.{layer}.ffn_gate.weight"),
+                    linear(2 * hidden, hidden)?,
+                ),
+                (
+                    format!("blk.{layer}.ffn_up.weight"),
+                    linear(2 * hidden, hidden)?,
+                ),
+                (
+                    format!("blk.{layer}.ffn_down.weight"),
+                    linear(hidden, 2 * hidden)?,
+                ),
+            ]);
+        }
+        Ok(weights)
+    }
+
+    /// Write `weights` to a GGUF file, with the attention of layer `i` quantized to `attn[i]`,
+    /// its FFN to `ffn[i]` and the norms kept in F32.
+    fn quantized_llama_gguf(
+        weights: &[(String, Tensor)],
+        attn: [GgmlDType; 2],
+        ffn: [GgmlDType; 2],
+        embd: GgmlDType,
+    ) -> candle_core::Result<Vec<u8>> {
+        let u32 = |x: u32| gguf_file::Value::U32(x);
+        let metadata = [
+            (
+                "general.architecture",
+                gguf_file::Value::String("llama".to_string()),
+            ),
+            ("llama.block_count", u32(2)),
+            ("llama.embedding_length", u32(256)),
+            ("llama.rope.dimension_count", u32(64)),
+            ("llama.attention.head_count", u32(4)),
+            ("llama.attention.head_count_kv", u32(2)),
+            (
+                "llama.attention.layer_norm_rms_epsilon",
+                gguf_file::Value::F32(1e-5),
+            ),
+        ];
+        let tensors = weights
+            .iter()
+            .map(|(name, w)| {
+                let dtype = match name.split('.').collect::<Vec<_>>()[..] {
+                    [_, _, n, _] if n.ends_with("norm") => GgmlDType::F32,
+                    ["blk", layer, n, _] => {
+                        let layer = layer.parse::<usize>().unwrap();
+                        if n.starts_with("attn") {
+                            attn[layer]
+                        } else {
+                            ffn[layer]
+                        }
+                    }
+                    ["token_embd", _] => embd,
+                    _ => GgmlDType::F32,
+                };
+                Ok((name.as_str(), QTensor::quantize(w, dtype)?))
+            })
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let mut buf = Cursor::new(Vec::new());
+        gguf_file::write(
+            &mut buf,
+            &metadata.iter().map(|(k, v)| (*k, v)).collect::<Vec<_>>(),
+            &tensors.iter().map(|(n, t)| (*n, t)).collect::<Vec<_>>(),
+        )?;
+        Ok(buf.into_inner())
+    }
+
+    #[test]
+    fn mixed_quant_forward_is_coherent() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let weights = coherence_weights(&dev)?;
+        let logits = |model: &ModelWeights| -> candle_core::Result<Vec<f32>> {
+            let input_ids = Tensor::new(&[[1u32, 5, 7, 3]], &dev)?;
+            model
+                .forward(&input_ids, &[0], vec![(3, 1)], None)?
+                .flatten_all()?
+                .to_vec1::<f32>()
+        };
+        let cosine = |a: &[f32], b: &[f32]| {
+            let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+            let norm = |x: &[f32]| x.iter().map(|x| x * x).sum::<f32>().sqrt();
+            dot / (norm(a) * norm(b))
+        };
+
+        let f32 = [GgmlDType::F32; 2];
+        let reference = logits(&load(
+            &dev,
+            &quantized_llama_gguf(&weights, f32, f32, GgmlDType::F32)?,
+        )?)?;
+
+        // Each layer and sublayer uses a different quantization type, as in the `*_K_M` mixes.
+        let mixed = load(
+            &dev,
+            &quantized_llama_gguf(
+                &weights,
+                [GgmlDType::Q4K, GgmlDType::Q6K],
+                [GgmlDType::Q6K, GgmlDType::Q8_0],
+                GgmlDType::Q8_0,
+            )?,
+        )?;
+        let mixed_logits = logits(&mixed)?;
+        assert!(mixed_logits.iter().all(|x| x.is_finite()));
+        let similarity = cosine(&mixed_logits, &reference);
+        assert!(similarity > 0.95, "{similarity}");
+
+        // Decoding through the mixed layers continues from their KV cache.
+        let input_ids = Tensor::new(&[[2u32]], &dev)?;
+        let decoded = mixed
+            .forward(&input_ids, &[4], vec![(0, 1)], None)?
+            .flatten_all()?
+            .to_vec1::<f32>()?;
+        let f32_model = load(
+            &dev,
+            &quantized_llama_gguf(&weights, f32, f32, GgmlDType::F32)?,
+        )?;
+        logits(&f32_model)?;
+        let expected = f32_model
+            .forward(&input_ids, &[4], vec![(0, 1)], None)?
+            .flatten_all()?
+            .to_vec1::<f32>()?;
+        let similarity = cosine(&decoded, &expected);
+        assert!(similarity > 0.95, "{similarity}");
+        Ok(())
+    }
+
+    #[test]
+    fn moe_forward() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let mut metadata = metadata(&[]);
+        metadata.push(("llama.expert_count".to_string(), gguf_file::Value::U32(4)));
+        metadata.push((
+            "llama.expert_used_count".to_string(),
+            gguf_file::Value::U32(2),
+        ));
+        let model = load(&dev, &llama_gguf(&dev, &metadata, 4)?)?;
+        let MlpOrMoe::MoE {
+            n_expert_used,
+            experts,
+            ..
+        } = &model.layers[0].mlp_or_moe
+        else {
+            panic!("Expected a MoE layer.");
+        };
+        assert_eq!(*n_expert_used, 2);
+        assert_eq!(experts.len(), 4);
+
+        let input_ids = Tensor::new(&[[1u32, 5, 7]], &dev)?;
+        let logits = model.forward(&input_ids, &[0], vec![(2, 1)], None)?;
+        assert_eq!(logits.dims(), [1, 1, 16]);
+        assert!(logits
+            .flatten_all()?
+            .to_vec1::<f32>()?
+            .iter()
+            .all(|x| x.is_finite()));
+
+        // The number of experts per token comes from the metadata.
+        metadata.pop();
+        assert!(load(&dev, &llama_gguf(&dev, &metadata, 4)?).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,434 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::sync::Arc;
+
+use candle_core::{DType, Device, IndexOp, Module, Result, Tensor, D};
+use candle_nn::Embedding;
+use indicatif::MultiProgress;
+use mistralrs_quant::QuantMethod;
+
+use crate::device_map::DeviceMapper;
+use crate::gguf::Content;
+use crate::layers::{MatMul, QRmsNorm};
+use crate::models::quantized_common::gguf_linear;
+use crate::paged_attention::AttentionImplementation;
+use crate::pipeline::{extract_logits, Cache, EitherCache};
+use crate::utils::gguf_metadata::ContentMetadata;
+use crate::utils::model_config as ModelConfig;
+use crate::utils::progress::NiceProgressBar;
+/// Mamba has no positions, so the context length is only a limit of the scheduler.
+const MAX_SEQ_LEN: u32 = 4096;
+
+fn softplus(xs: &Tensor) -> Result<Tensor> {
+    // log(1 + exp(x)) = max(x, 0) + log(1 + exp(-|x|)), which does not overflow.
+    xs.relu()? + (xs.abs()?.neg()?.exp()? + 1.)?.log()?
+}
+
+fn rms_norm_unweighted(xs: &Tensor, eps: f32) -> Result<Tensor> {
+    let norm = (xs.sqr()?.mean_keepdim(D::Minus1)? + eps as f64)?.sqrt()?;
+    xs.broadcast_div(&norm)
+}
+
+/// A Mamba block: a gated causal convolution followed by the selective scan.
+struct LayerWeights {
+    norm: QRmsNorm,
+    ssm_in: Arc<dyn QuantMethod>,
+    /// Depthwise convolution weights, (d_inner, d_conv).
+    conv_weight: Tensor,
+    conv_bias: Tensor,
+    ssm_x: Arc<dyn QuantMethod>,
+    ssm_dt: Arc<dyn QuantMethod>,
+    /// The state matrix, (d_inner, d_state). The converters store `-exp(A_log)`.
+    ssm_a: Tensor,
+    ssm_d: Tensor,
+    ssm_out: Arc<dyn QuantMethod>,
+    d_inner: usize,
+    d_state: usize,
+    d_conv: usize,
+    dt_rank: usize,
+    /// Falcon Mamba normalizes the time step, B and C with this epsilon.
+    dt_b_c_rms_eps: Option<f32>,
+}
+
+impl LayerWeights {
+    /// Run the block on `x` of shape (b, seq_len, hidden), advancing the convolution and SSM
+    /// states. The states are created for the first tokens of the sequences.
+    fn forward(&self, x: &Tensor, state: &mut Option<(Tensor, Tensor)>) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = x.dims3()?;
+        let xz = MatMul.qmethod_matmul(&self.norm.forward(x)?, &*self.ssm_in)?;
+        let xs = xz.narrow(D::Minus1, 0, self.d_inner)?;
+        let z = xz.narrow(D::Minus1, self.d_inner, self.d_inner)?;
+
+        let (conv_state, ssm_state) = match state.take() {
+            Some(state) => state,
+            None => (
+                Tensor::zeros(
+                    (b_sz, self.d_inner, self.d_conv - 1),
+                    DType::F32,
+                    x.device(),
+                )?,
+                Tensor::zeros((b_sz, self.d_inner, self.d_state), DType::F32, x.device())?,
+            ),
+        };
+
+        // Causal depthwise convolution over the last inputs of the previous steps and these.
+        let conv_in = Tensor::cat(&[&conv_state, &xs.transpose(1, 2)?.contiguous()?], 2)?;
+        let mut conv = self.conv_bias.reshape((1, self.d_inner, 1))?;
+        for k in 0..self.d_conv {
+            let w = self
+                .conv_weight
+                .narrow(1, k, 1)?
+                .reshape((1, self.d_inner, 1))?;
+            conv = conv_in
+                .narrow(2, k, seq_len)?
+                .broadcast_mul(&w)?
+                .broadcast_add(&conv)?;
+        }
+        let conv_state = conv_in.narrow(2, seq_len, self.d_conv - 1)?.contiguous()?;
+        let xs = candle_nn::ops::silu(&conv.transpose(1, 2)?.contiguous()?)?;
+
+        let x_dbl = MatMul.qmethod_matmul(&xs, &*self.ssm_x)?;
+        let mut dt = x_dbl.narrow(D::Minus1, 0, self.dt_rank)?;
+        let mut b = x_dbl.narrow(D::Minus1, self.dt_rank, self.d_state)?;
+        let mut c = x_dbl.narrow(D::Minus1, self.dt_rank + self.d_state, self.d_state)?;
+        if let Some(eps) = self.dt_b_c_rms_eps {
+            dt = rms_norm_unweighted(&dt, eps)?;
+            b = rms_norm_unweighted(&b, eps)?;
+            c = rms_norm_unweighted(&c, eps)?;
+        }
+        let delta = softplus(&MatMul.qmethod_matmul(&dt.contiguous()?, &*self.ssm_dt)?)?;
+
+        // Selective scan: h = exp(delta * A) * h + delta * B * x, y = C h + D x.
+        let mut h = ssm_state;
+        let mut ys = Vec::with_capacity(seq_len);
+        for t in 0..seq_len {
+            let delta_t = delta.i((.., t))?.unsqueeze(2)?;
+            let x_t = xs.i((.., t))?;
+            let b_t = b.i((.., t))?.unsqueeze(1)?;
+            let c_t = c.i((.., t))?.unsqueeze(2)?.contiguous()?;
+            let da = delta_t.broadcast_mul(&self.ssm_a)?.exp()?;
+            let dbx = delta_t
+                .broadcast_mul(&x_t.unsqueeze(2)?)?
+                .broadcast_mul(&b_t)?;
+            h = ((da * &h)? + dbx)?;
+            let y = h.matmul(&c_t)?.squeeze(2)?;
+            ys.push((y + x_t.broadcast_mul(&self.ssm_d)?)?);
+        }
+        let y = (Tensor::stack(&ys, 1)? * candle_nn::ops::silu(&z)?)?;
+        *state = Some((conv_state, h));
+
+        MatMul.qmethod_matmul(&y, &*self.ssm_out)
+    }
+}
+
+/// Mamba, a state space model. Instead of a KV cache growing with the sequence, each layer keeps
+/// a fixed-size state: the last inputs of its convolution and the state of its selective scan.
+/// These are stored as the pair of tensors of the layer in the full cache, batched on the first
+/// dimension like KV caches, so the scheduler can clone them in and out of the sequences.
+///
+/// PagedAttention and prefix caching are not supported, so the pipeline runs Mamba without them.
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    layers: Vec<LayerWeights>,
+    norm: QRmsNorm,
+    output: Arc<dyn QuantMethod>,
+    pub device: Device,
+    pub cache: EitherCache,
+    pub max_seq_len: usize,
+    mapper: Option<Box<dyn DeviceMapper + Send + Sync>>,
+}
+
+// mamba `llm` fields:
+// https://github.com/ggerganov/ggml/blob/master/docs/gguf.md#llm
+// NOTE: Types here do not match spec
+pub(crate) struct PropsGGUF {
+    pub block_count: usize,
+    pub embedding_length: usize,
+    pub conv_kernel: usize,
+    pub inner_size: usize,
+    pub state_size: usize,
+    pub time_step_rank: usize,
+    pub rms_norm_eps: f32,
+    pub dt_b_c_rms: bool,
+    pub max_seq_len: usize,
+}
+
+impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
+    type Error = anyhow::Error;
+
+    fn try_from(c: ContentMetadata) -> std::result::Result<Self, Self::Error> {
+        c.verify_arch("mamba")?;
+
+        let required = [
+            "block_count",
+            "embedding_length",
+            "ssm.conv_kernel",
+            "ssm.inner_size",
+            "ssm.state_size",
+            "ssm.time_step_rank",
+            "attention.layer_norm_rms_epsilon",
+        ];
+        c.has_required_keys(&required)?;
+
+        // NOTE: Values are not aligned with GGUFv3 types
+        // TODO: Normalize value types to spec
+        let props = Self {
+            block_count: c.get_value::<u32>("block_count")? as usize,
+            embedding_length: c.get_value::<u32>("embedding_length")? as usize,
+            conv_kernel: c.get_value::<u32>("ssm.conv_kernel")? as usize,
+            inner_size: c.get_value::<u32>("ssm.inner_size")? as usize,
+            state_size: c.get_value::<u32>("ssm.state_size")? as usize,
+            time_step_rank: c.get_value::<u32>("ssm.time_step_rank")? as usize,
+            rms_norm_eps: c.get_value("attention.layer_norm_rms_epsilon")?,
+            dt_b_c_rms: c.get_option_value("ssm.dt_b_c_rms")?.unwrap_or(false),
+            max_seq_len: c
+                .get_value::<u64>("context_length")
+                .ok()
+                .unwrap_or(MAX_SEQ_LEN as u64) as usize,
+        };
+
+        Ok(props)
+    }
+}
+
+impl ModelConfig::FromGGUF for ModelWeights {
+    fn from_gguf<R: std::io::Seek + std::io::Read>(
+        mut ct: Content<'_, R>,
+        device: &Device,
+        mapper: Box<dyn DeviceMapper + Send + Sync>,
+        attention_mechanism: AttentionImplementation,
+        _dtype: DType,
+    ) -> Result<Self> {
+        if matches!(attention_mechanism, AttentionImplementation::PagedAttention) {
+            candle_core::bail!("Mamba does not support PagedAttention.");
+        }
+
+        // Parameter extraction from metadata.
+        let metadata = ContentMetadata {
+            path_prefix: "mamba",
+            metadata: ct.get_metadata(),
+        };
+        let PropsGGUF {
+            block_count,
+            embedding_length,
+            conv_kernel,
+            inner_size,
+            state_size,
+            time_step_rank,
+            rms_norm_eps,
+            dt_b_c_rms,
+            max_seq_len,
+        } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
+
+        let qtok_embeddings = ct.tensor("token_embd.weight", device)?;
+        let tok_embeddings = qtok_embeddings.dequantize(device)?;
+        let norm = QRmsNorm::new(ct.tensor("output_norm.weight", device)?, rms_norm_eps)?;
+        // The output projection is tied to the token embeddings.
+        let output = if !ct.has_tensor("output.weight") {
+            ct.tensor("token_embd.weight", device)?
+        } else {
+            ct.tensor("output.weight", device)?
+        };
+        let mut layers = Vec::with_capacity(block_count);
+
+        for layer_idx in NiceProgressBar::<_, 'b'>(
+            0..block_count,
+            "Loading repeating layers",
+            &MultiProgress::new(),
+        ) {
+            let prefix = format!("blk.{layer_idx}");
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+
+            let mut dense = |name: &str| -> Result<Tensor> {
+                ct.tensor(&format!("{prefix}.{name}"), device)?
+                    .dequantize(device)
+            };
+            let conv_weight = dense("ssm_conv1d.weight")?;
+            let conv_bias = dense("ssm_conv1d.bias")?;
+            let ssm_dt_bias = dense("ssm_dt.bias")?;
+            let ssm_a = dense("ssm_a")?;
+            let ssm_d = dense("ssm_d")?;
+            if conv_weight.dims() != [inner_size, conv_kernel]
+                || ssm_a.dims() != [inner_size, state_size]
+            {
+                candle_core::bail!(
+                    "Mamba layer {layer_idx} has a convolution of shape {:?} and a state matrix of shape {:?}, expected {:?} and {:?}.",
+                    conv_weight.dims(),
+                    ssm_a.dims(),
+                    [inner_size, conv_kernel],
+                    [inner_size, state_size]
+                );
+            }
+
+            let mut proj = |name: &str, bias: Option<Tensor>| -> Result<Arc<dyn QuantMethod>> {
+                gguf_linear(ct.tensor(&format!("{prefix}.{name}"), device)?, bias)
+            };
+            let ssm_in = proj("ssm_in.weight", None)?;
+            let ssm_x = proj("ssm_x.weight", None)?;
+            let ssm_dt = proj("ssm_dt.weight", Some(ssm_dt_bias))?;
+            let ssm_out = proj("ssm_out.weight", None)?;
+            let norm = QRmsNorm::new(
+                ct.tensor(&format!("{prefix}.attn_norm.weight"), device)?,
+                rms_norm_eps,
+            )?;
+            layers.push(LayerWeights {
+                norm,
+                ssm_in,
+                conv_weight,
+                conv_bias,
+                ssm_x,
+                ssm_dt,
+                ssm_a,
+                ssm_d,
+                ssm_out,
+                d_inner: inner_size,
+                d_state: state_size,
+                d_conv: conv_kernel,
+                dt_rank: time_step_rank,
+                dt_b_c_rms_eps: dt_b_c_rms.then_some(rms_norm_eps),
+            })
+        }
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            layers,
+            norm,
+            output: gguf_linear(output, None)?,
+            device: device.clone(),
+            cache: EitherCache::Full(Cache::new(block_count, false)),
+            max_seq_len,
+            mapper: Some(mapper),
+        })
+    }
+}
+
+impl ModelWeights {
+    /// Run the new tokens of the sequences, which all have the same length, continuing from the
+    /// states in the cache. There are no positions, so a decoding step is a prompt of one token.
+    pub fn forward(&self, x: &Tensor, context_lens: Vec<(usize, usize)>) -> Result<Tensor> {
+        let mut layer_in = self.tok_embeddings.forward(x)?;
+        let mut cache = self.cache.full().lock();
+        for (i, layer) in self.layers.iter().enumerate() {
+            if let Some(ref mapper) = self.mapper {
+                layer_in = mapper.map(layer_in, i)?;
+            }
+            let x = layer_in;
+            let residual = &x;
+            let x = layer.forward(&x, &mut cache[i])?;
+            layer_in = (x + residual)?;
+        }
+        let x = self.norm.forward(&layer_in)?;
+        extract_logits(
+            &MatMul.qmethod_matmul(&x.contiguous()?, &*self.output)?,
+            context_lens,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::ModelWeights;
+    use crate::models::quantized_test_utils::{
+        assert_close, assert_decoding_matches_prompt, assert_finite, last_logits, load, TestGguf,
+    };
+
+    /// Write a GGUF file of a Mamba model with two layers and tied embeddings.
+    fn mamba_gguf() -> candle_core::Result<Vec<u8>> {
+        let dev = Device::Cpu;
+        let (vocab, hidden, d_inner, d_state, d_conv, dt_rank) = (16, 32, 64, 8, 4, 2);
+        let mut gguf = TestGguf::new("mamba");
+        gguf.u32("block_count", 2)
+            .u32("context_length", 64)
+            .u32("embedding_length", hidden)
+            .u32("ssm.conv_kernel", d_conv)
+            .u32("ssm.inner_size", d_inner)
+            .u32("ssm.state_size", d_state)
+            .u32("ssm.time_step_rank", dt_rank)
+            .f32("attention.layer_norm_rms_epsilon", 1e-5);
+        gguf.linear("token_embd.weight", vocab, hidden)?;
+        gguf.vector("output_norm.weight", hidden, 1.)?;
+        for layer in 0..2 {
+            let a = Tensor::arange(1f32, (d_state + 1) as f32, &dev)?
+                .neg()?
+                .broadcast_as((d_inner, d_state))?
+                .contiguous()?;
+            let name = |name: &str| format!("blk.{layer}.{name}");
+            gguf.vector(name("attn_norm.weight"), hidden, 1.)?
+                .linear(name("ssm_in.weight"), 2 * d_inner, hidden)?
+                .dense(
+                    name("ssm_conv1d.weight"),
+                    &Tensor::randn(0f32, 0.5, (d_inner, d_conv), &dev)?,
+                )?
+                .vector(name("ssm_conv1d.bias"), d_inner, 0.)?
+                .linear(name("ssm_x.weight"), dt_rank + 2 * d_state, d_inner)?
+                .dense(
+                    name("ssm_dt.weight"),
+                    &Tensor::randn(0f32, 0.5, (d_inner, dt_rank), &dev)?,
+                )?
+                .vector(name("ssm_dt.bias"), d_inner, -2.)?
+                .dense(name("ssm_a"), &a)?
+                .vector(name("ssm_d"), d_inner, 1.)?
+                .linear(name("ssm_out.weight"), hidden, d_inner)?;
+        }
+        gguf.build()
+    }
+
+    fn logits(model: &ModelWeights, ids: &[u32], _offset: usize) -> candle_core::Result<Vec<f32>> {
+        let input_ids = Tensor::new(ids, &Device::Cpu)?.unsqueeze(0)?;
+        last_logits(model.forward(&input_ids, vec![(ids.len() - 1, 1)])?)
+    }
+
+    #[test]
+    fn forward_keeps_fixed_size_state() -> candle_core::Result<()> {
+        let model = load::<ModelWeights>(&mamba_gguf()?)?;
+        assert_eq!(model.max_seq_len, 64);
+
+        let out = logits(&model, &[1, 5, 7], 0)?;
+        assert_eq!(out.len(), 16);
+        assert_finite(&out);
+
+        // Decoding a token updates the states in place, which do not grow with the sequence.
+        let shapes = |model: &ModelWeights| {
+            let cache = model.cache.full().lock();
+            let (conv, ssm) = cache[0].as_ref().unwrap();
+            (conv.dims().to_vec(), ssm.dims().to_vec())
+        };
+        let before = shapes(&model);
+        assert_eq!(before, (vec![1, 64, 3], vec![1, 64, 8]));
+        logits(&model, &[3], 3)?;
+        assert_eq!(shapes(&model), before);
+        Ok(())
+    }
+
+    #[test]
+    fn decoding_matches_prompt() -> candle_core::Result<()> {
+        // Running the prompt in chunks and then decoding continues from the cached states.
+        assert_decoding_matches_prompt(&mamba_gguf()?, &[&[1, 5], &[7, 3], &[9]], logits)
+    }
+
+    #[test]
+    fn states_are_batched_per_sequence() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let file = mamba_gguf()?;
+
+        let model = load::<ModelWeights>(&file)?;
+        logits(&model, &[2, 4, 6], 0)?;
+        let expected = logits(&model, &[8], 3)?;
+
+        // Run two sequences as a batch, then keep only the state of the second, as the scheduler
+        // does when the sequences are no longer scheduled together.
+        let model = load::<ModelWeights>(&file)?;
+        let input_ids = Tensor::new(&[[1u32, 5, 7], [2, 4, 6]], &dev)?;
+        model.forward(&input_ids, vec![(2, 1), (2, 1)])?;
+        {
+            let mut cache = model.cache.full().lock();
+            for layer in cache.iter_mut() {
+                let (conv, ssm) = layer.take().unwrap();
+                *layer = Some((conv.chunk(2, 0)?[1].clone(), ssm.chunk(2, 0)?[1].clone()));
+            }
+        }
+        assert_close(&logits(&model, &[8], 3)?, &expected);
+        Ok(())
+    }
+}
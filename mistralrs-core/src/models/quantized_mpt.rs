@@ -0,0 +1,424 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::sync::Arc;
+
+use candle_core::{DType, Device, Module, Result, Tensor, D};
+use candle_nn::{Embedding, LayerNorm};
+use indicatif::MultiProgress;
+use mistralrs_quant::QuantMethod;
+
+use crate::attention::SdpaParams;
+use crate::device_map::DeviceMapper;
+use crate::gguf::Content;
+use crate::layers::{Alibi, CausalMasker, MatMul, Sdpa};
+use crate::layers_masker::PastKvLenCache;
+use crate::models::quantized_common::gguf_linear;
+use crate::paged_attention::AttentionImplementation;
+use crate::pipeline::{extract_logits, EitherCache, KvCache, NormalCache};
+use crate::utils::gguf_metadata::ContentMetadata;
+use crate::utils::model_config as ModelConfig;
+use crate::utils::progress::NiceProgressBar;
+const MAX_SEQ_LEN: u32 = 2048;
+
+/// MPT is trained without biases, but the converters write them if the checkpoint has any.
+fn optional_bias<R: std::io::Seek + std::io::Read>(
+    ct: &mut Content<'_, R>,
+    name: &str,
+    device: &Device,
+) -> Result<Option<Tensor>> {
+    if ct.has_tensor(name) {
+        Ok(Some(ct.tensor(name, device)?.dequantize(device)?))
+    } else {
+        Ok(None)
+    }
+}
+
+fn layer_norm<R: std::io::Seek + std::io::Read>(
+    ct: &mut Content<'_, R>,
+    name: &str,
+    device: &Device,
+    eps: f64,
+) -> Result<LayerNorm> {
+    let w = ct
+        .tensor(&format!("{name}.weight"), device)?
+        .dequantize(device)?;
+    match optional_bias(ct, &format!("{name}.bias"), device)? {
+        Some(b) => Ok(LayerNorm::new(w, b, eps)),
+        None => Ok(LayerNorm::new_no_bias(w, eps)),
+    }
+}
+
+struct Mlp {
+    ffn_up: Arc<dyn QuantMethod>,
+    ffn_down: Arc<dyn QuantMethod>,
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        MatMul.qmethod_matmul(
+            &MatMul
+                .qmethod_matmul(xs, &*self.ffn_up)?
+                .apply(&candle_nn::Activation::Gelu)?,
+            &*self.ffn_down,
+        )
+    }
+}
+
+struct LayerWeights {
+    attention_qkv: Arc<dyn QuantMethod>,
+    attention_wo: Arc<dyn QuantMethod>,
+    attention_norm: LayerNorm,
+    mlp: Mlp,
+    ffn_norm: LayerNorm,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    clip_qkv: Option<f32>,
+    sdpa_params: SdpaParams,
+    dtype: DType,
+}
+
+impl LayerWeights {
+    fn forward_attn(&self, x: &Tensor, mask: &Tensor, kv_cache: &mut KvCache) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = x.dims3()?;
+
+        let mut qkv = MatMul
+            .qmethod_matmul(x, &*self.attention_qkv)?
+            .to_dtype(self.dtype)?;
+        if let Some(clip) = self.clip_qkv {
+            qkv = qkv.clamp(-clip, clip)?;
+        }
+        let q_size = self.n_head * self.head_dim;
+        let kv_size = self.n_kv_head * self.head_dim;
+        let q = qkv
+            .narrow(D::Minus1, 0, q_size)?
+            .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let k = qkv
+            .narrow(D::Minus1, q_size, kv_size)?
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let v = qkv
+            .narrow(D::Minus1, q_size + kv_size, kv_size)?
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let (k, v) = kv_cache.append(&k, &v)?;
+        let y = Sdpa.run_attention(&q, &k, &v, Some(mask), None, &self.sdpa_params)?;
+
+        let y = y.transpose(1, 2)?.reshape((b_sz, seq_len, ()))?;
+
+        MatMul.qmethod_matmul(&y.to_dtype(x.dtype())?, &*self.attention_wo)
+    }
+}
+
+/// MPT (MosaicML), a GPT-style model with ALiBi positions instead of position embeddings, a GELU
+/// MLP and no biases. The ALiBi slopes are computed once at load time.
+///
+/// PagedAttention is not supported, so the pipeline runs MPT without it.
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    layers: Vec<LayerWeights>,
+    norm: LayerNorm,
+    output: Arc<dyn QuantMethod>,
+    alibi: Alibi,
+    pub device: Device,
+    pub cache: EitherCache,
+    pub max_seq_len: usize,
+    mapper: Option<Box<dyn DeviceMapper + Send + Sync>>,
+    dtype: DType,
+}
+
+// mpt `llm` fields:
+// https://github.com/ggerganov/ggml/blob/master/docs/gguf.md#llm
+// NOTE: Types here do not match spec
+pub(crate) struct PropsGGUF {
+    pub head_count: usize,
+    pub head_count_kv: usize,
+    pub block_count: usize,
+    pub embedding_length: usize,
+    pub layer_norm_epsilon: f64,
+    pub max_seq_len: usize,
+    pub max_alibi_bias: f32,
+    pub clip_qkv: Option<f32>,
+}
+
+impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
+    type Error = anyhow::Error;
+
+    fn try_from(c: ContentMetadata) -> std::result::Result<Self, Self::Error> {
+        c.verify_arch("mpt")?;
+
+        let required = [
+            "attention.head_count",
+            "block_count",
+            "embedding_length",
+            "attention.layer_norm_epsilon",
+        ];
+        c.has_required_keys(&required)?;
+
+        let head_count = c.get_value::<u32>("attention.head_count")? as usize;
+
+        // NOTE: Values are not aligned with GGUFv3 types
+        // TODO: Normalize value types to spec
+        let props = Self {
+            head_count,
+            head_count_kv: c
+                .get_value::<u32>("attention.head_count_kv")
+                .ok()
+                .map(|x| x as usize)
+                .unwrap_or(head_count),
+            block_count: c.get_value::<u32>("block_count")? as usize,
+            embedding_length: c.get_value::<u32>("embedding_length")? as usize,
+            layer_norm_epsilon: c.get_value::<f32>("attention.layer_norm_epsilon")? as f64,
+            max_seq_len: c
+                .get_value::<u64>("context_length")
+                .ok()
+                .unwrap_or(MAX_SEQ_LEN as u64) as usize,
+            // llama.cpp writes `max_alibi_bias`, other converters keep the name of the MPT config.
+            max_alibi_bias: c
+                .get_value("attention.max_alibi_bias")
+                .or_else(|_| c.get_value("attention.alibi_bias_max"))
+                .ok()
+                .unwrap_or(8_f32),
+            clip_qkv: c.get_value("attention.clamp_kqv").ok(),
+        };
+
+        Ok(props)
+    }
+}
+
+impl ModelConfig::FromGGUF for ModelWeights {
+    fn from_gguf<R: std::io::Seek + std::io::Read>(
+        mut ct: Content<'_, R>,
+        device: &Device,
+        mapper: Box<dyn DeviceMapper + Send + Sync>,
+        attention_mechanism: AttentionImplementation,
+        dtype: DType,
+    ) -> Result<Self> {
+        if matches!(attention_mechanism, AttentionImplementation::PagedAttention) {
+            candle_core::bail!("MPT does not support PagedAttention.");
+        }
+
+        // Parameter extraction from metadata.
+        let metadata = ContentMetadata {
+            path_prefix: "mpt",
+            metadata: ct.get_metadata(),
+        };
+        let PropsGGUF {
+            head_count,
+            head_count_kv,
+            block_count,
+            embedding_length,
+            layer_norm_epsilon,
+            max_seq_len,
+            max_alibi_bias,
+            clip_qkv,
+        } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
+
+        if ct.has_tensor("blk.0.attn_q_norm.weight") {
+            candle_core::bail!("MPT with layer norms of the queries and keys is not supported.");
+        }
+
+        let qtok_embeddings = ct.tensor("token_embd.weight", device)?;
+        let tok_embeddings = qtok_embeddings.dequantize(device)?;
+        let norm = layer_norm(&mut ct, "output_norm", device, layer_norm_epsilon)?;
+        let output = if !ct.has_tensor("output.weight") {
+            ct.tensor("token_embd.weight", device)?
+        } else {
+            ct.tensor("output.weight", device)?
+        };
+        let mut layers = Vec::with_capacity(block_count);
+
+        let head_dim = embedding_length / head_count;
+
+        for layer_idx in NiceProgressBar::<_, 'b'>(
+            0..block_count,
+            "Loading repeating layers",
+            &MultiProgress::new(),
+        ) {
+            let prefix = format!("blk.{layer_idx}");
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+
+            let mut proj = |name: &str| -> Result<Arc<dyn QuantMethod>> {
+                let weight = ct.tensor(&format!("{prefix}.{name}.weight"), device)?;
+                let bias = optional_bias(&mut ct, &format!("{prefix}.{name}.bias"), device)?;
+                gguf_linear(weight, bias)
+            };
+            let attention_qkv = proj("attn_qkv")?;
+            let attention_wo = proj("attn_output")?;
+            let mlp = Mlp {
+                ffn_up: proj("ffn_up")?,
+                ffn_down: proj("ffn_down")?,
+            };
+
+            let attention_norm = layer_norm(
+                &mut ct,
+                &format!("{prefix}.attn_norm"),
+                device,
+                layer_norm_epsilon,
+            )?;
+            let ffn_norm = layer_norm(
+                &mut ct,
+                &format!("{prefix}.ffn_norm"),
+                device,
+                layer_norm_epsilon,
+            )?;
+            layers.push(LayerWeights {
+                attention_qkv,
+                attention_wo,
+                attention_norm,
+                mlp,
+                ffn_norm,
+                n_head: head_count,
+                n_kv_head: head_count_kv,
+                head_dim,
+                clip_qkv,
+                sdpa_params: SdpaParams {
+                    n_kv_groups: head_count / head_count_kv,
+                    use_flash_attn: false,
+                    softcap: None,
+                    softmax_scale: 1.0 / (head_dim as f32).sqrt(),
+                    sliding_window: None,
+                },
+                dtype,
+            })
+        }
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            layers,
+            norm,
+            output: gguf_linear(output, None)?,
+            alibi: Alibi::new(head_count, max_alibi_bias, device)?,
+            device: device.clone(),
+            cache: EitherCache::Normal(NormalCache::new(block_count, max_seq_len)),
+            max_seq_len,
+            mapper: Some(mapper),
+            dtype,
+        })
+    }
+}
+
+impl ModelWeights {
+    pub fn forward(&self, x: &Tensor, context_lens: Vec<(usize, usize)>) -> Result<Tensor> {
+        let (b_sz, seq_len) = x.dims2()?;
+        let mut layer_in = self.tok_embeddings.forward(x)?;
+        let cache = &mut self.cache.normal().0;
+        let past_kv_len_cache = &*cache as &dyn PastKvLenCache;
+        let past_kv_len = past_kv_len_cache.get_past_kv_len()?;
+        let causal_mask = CausalMasker.make_causal_mask_matrix(
+            x,
+            past_kv_len_cache,
+            self.dtype,
+            self.layers[0].n_head,
+        )?;
+        // The ALiBi bias is added to the causal mask, so a mask is needed even for one token.
+        let alibi = self.alibi.bias(seq_len, past_kv_len, self.dtype)?;
+        let mask = match causal_mask {
+            Some(causal_mask) => alibi.broadcast_add(&causal_mask)?,
+            None => alibi,
+        };
+        let mask = mask
+            .broadcast_as((b_sz, self.layers[0].n_head, seq_len, past_kv_len + seq_len))?
+            .contiguous()?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            if let Some(ref mapper) = self.mapper {
+                layer_in = mapper.map(layer_in, i)?;
+            }
+            let x = layer_in;
+            let residual = &x;
+            let x = x.apply(&layer.attention_norm)?;
+            let attn = layer.forward_attn(&x, &mask.to_device(x.device())?, &mut cache[i])?;
+            let x = (attn + residual)?;
+
+            // MLP
+            let residual = &x;
+            let x = x.apply(&layer.ffn_norm)?;
+            let x = layer.mlp.forward(&x)?;
+            let x = (x + residual)?;
+            layer_in = x;
+        }
+        let x = layer_in.apply(&self.norm)?;
+        extract_logits(
+            &MatMul.qmethod_matmul(&x.contiguous()?, &*self.output)?,
+            context_lens,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::ModelWeights;
+    use crate::models::quantized_test_utils::{
+        assert_decoding_matches_prompt, assert_finite, last_logits, load, TestGguf,
+    };
+
+    /// Write a GGUF file of an MPT model with two layers, tied embeddings and no biases, as
+    /// converted by llama.cpp unless `alibi_bias_key` names the maximum ALiBi bias differently.
+    fn mpt_gguf(alibi_bias_key: &str, max_alibi_bias: f32) -> candle_core::Result<Vec<u8>> {
+        let (vocab, hidden) = (16, 32);
+        let mut gguf = TestGguf::new("mpt");
+        gguf.u32("block_count", 2)
+            .u32("context_length", 64)
+            .u32("embedding_length", hidden)
+            .u32("attention.head_count", 4)
+            .f32("attention.layer_norm_epsilon", 1e-5)
+            .f32(alibi_bias_key, max_alibi_bias);
+        gguf.linear("token_embd.weight", vocab, hidden)?;
+        gguf.vector("output_norm.weight", hidden, 1.)?;
+        for layer in 0..2 {
+            gguf.vector(format!("blk.{layer}.attn_norm.weight"), hidden, 1.)?
+                .vector(format!("blk.{layer}.ffn_norm.weight"), hidden, 1.)?
+                .linear(format!("blk.{layer}.attn_qkv.weight"), 3 * hidden, hidden)?
+                .linear(format!("blk.{layer}.attn_output.weight"), hidden, hidden)?
+                .linear(format!("blk.{layer}.ffn_up.weight"), 4 * hidden, hidden)?
+                .linear(format!("blk.{layer}.ffn_down.weight"), hidden, 4 * hidden)?;
+        }
+        gguf.build()
+    }
+
+    fn logits(model: &ModelWeights, ids: &[u32], _offset: usize) -> candle_core::Result<Vec<f32>> {
+        let input_ids = Tensor::new(ids, &Device::Cpu)?.unsqueeze(0)?;
+        last_logits(model.forward(&input_ids, vec![(ids.len() - 1, 1)])?)
+    }
+
+    #[test]
+    fn forward() -> candle_core::Result<()> {
+        let model = load::<ModelWeights>(&mpt_gguf("attention.max_alibi_bias", 8.)?)?;
+        assert_eq!(model.max_seq_len, 64);
+
+        let out = logits(&model, &[1, 5, 7], 0)?;
+        assert_eq!(out.len(), 16);
+        assert_finite(&out);
+
+        // Decoding a token reuses the KV cache of the prompt.
+        assert_eq!(logits(&model, &[3], 3)?.len(), 16);
+        assert_eq!(model.cache.normal().0[0].current_seq_len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn decoding_matches_prompt() -> candle_core::Result<()> {
+        // The ALiBi bias of the decoded token depends on its distance to the cached tokens.
+        for key in ["attention.max_alibi_bias", "attention.alibi_bias_max"] {
+            assert_decoding_matches_prompt(&mpt_gguf(key, 4.)?, &[&[1, 5, 7], &[3]], logits)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn max_alibi_bias_key() -> candle_core::Result<()> {
+        for key in ["attention.max_alibi_bias", "attention.alibi_bias_max"] {
+            let model = load::<ModelWeights>(&mpt_gguf(key, 4.)?)?;
+            let bias = model.alibi.bias(1, 1, DType::F32)?.flatten_all()?;
+            // The first of 4 heads has the slope `2^(-max_bias / 4)`.
+            assert_eq!(bias.to_vec1::<f32>()?[0], -0.5);
+        }
+        Ok(())
+    }
+}
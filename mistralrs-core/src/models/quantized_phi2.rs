@@ -175,14 +175,14 @@ fn layer_norm(w: QTensor, b: QTensor, eps: f64) -> Result<LayerNorm> {
 // phi2 `llm` fields:
 // https://github.com/ggerganov/ggml/blob/master/docs/gguf.md#llm
 // NOTE: Types here do not match spec
-struct PropsGGUF {
-    head_count: usize,
-    head_count_kv: usize,
-    block_count: usize,
-    embedding_length: usize,
-    rope_dim: usize,
-    ln_eps: f64,
-    max_seq_len: usize,
+pub(crate) struct PropsGGUF {
+    pub head_count: usize,
+    pub head_count_kv: usize,
+    pub block_count: usize,
+    pub embedding_length: usize,
+    pub rope_dim: usize,
+    pub ln_eps: f64,
+    pub max_seq_len: usize,
 }
 
 impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
@@ -423,3 +423,67 @@ impl ModelWeights {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::ModelWeights;
+    use crate::models::quantized_test_utils::{
+        assert_decoding_matches_prompt, assert_finite, last_logits, load, TestGguf,
+    };
+
+    /// Write a GGUF file of a Qwen2 model with two layers, grouped-query attention with biases on
+    /// the query, key and value projections, and tied embeddings.
+    fn qwen2_gguf() -> candle_core::Result<Vec<u8>> {
+        let (vocab, hidden, n_head, n_kv_head) = (16, 32, 4, 2);
+        let kv_dim = hidden / n_head * n_kv_head;
+        let mut gguf = TestGguf::new("qwen2");
+        gguf.u32("block_count", 2)
+            .u32("embedding_length", hidden)
+            .u32("attention.head_count", n_head)
+            .u32("attention.head_count_kv", n_kv_head)
+            .f32("attention.layer_norm_rms_epsilon", 1e-6)
+            .f32("rope.freq_base", 1_000_000.);
+        gguf.linear("token_embd.weight", vocab, hidden)?;
+        gguf.vector("output_norm.weight", hidden, 1.)?;
+        for layer in 0..2 {
+            gguf.vector(format!("blk.{layer}.attn_norm.weight"), hidden, 1.)?
+                .vector(format!("blk.{layer}.ffn_norm.weight"), hidden, 1.)?;
+            for (name, out_dim) in [("attn_q", hidden), ("attn_k", kv_dim), ("attn_v", kv_dim)] {
+                let bias = (Tensor::randn(0f32, 1f32, out_dim, &Device::Cpu)? * 0.1)?;
+                gguf.linear(format!("blk.{layer}.{name}.weight"), out_dim, hidden)?
+                    .dense(format!("blk.{layer}.{name}.bias"), &bias)?;
+            }
+            gguf.linear(format!("blk.{layer}.attn_output.weight"), hidden, hidden)?
+                .linear(format!("blk.{layer}.ffn_gate.weight"), 2 * hidden, hidden)?
+                .linear(format!("blk.{layer}.ffn_up.weight"), 2 * hidden, hidden)?
+                .linear(format!("blk.{layer}.ffn_down.weight"), hidden, 2 * hidden)?;
+        }
+        gguf.build()
+    }
+
+    fn logits(model: &ModelWeights, ids: &[u32], offset: usize) -> candle_core::Result<Vec<f32>> {
+        let input_ids = Tensor::new(ids, &Device::Cpu)?.unsqueeze(0)?;
+        last_logits(model.forward(&input_ids, &[offset], vec![(ids.len() - 1, 1)], None)?)
+    }
+
+    #[test]
+    fn forward() -> candle_core::Result<()> {
+        let model = load::<ModelWeights>(&qwen2_gguf()?)?;
+
+        let out = logits(&model, &[1, 5, 7], 0)?;
+        assert_eq!(out.len(), 16);
+        assert_finite(&out);
+
+        // Decoding a token reuses the KV cache of the prompt.
+        assert_eq!(logits(&model, &[3], 3)?.len(), 16);
+        assert_eq!(model.cache.normal().0[0].current_seq_len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn decoding_matches_prompt() -> candle_core::Result<()> {
+        assert_decoding_matches_prompt(&qwen2_gguf()?, &[&[1, 5, 7], &[3]], logits)
+    }
+}
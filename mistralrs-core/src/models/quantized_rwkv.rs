@@ -0,0 +1,577 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::sync::Arc;
+
+use candle_core::{DType, Device, IndexOp, Module, Result, Tensor, D};
+use candle_nn::{Embedding, LayerNorm};
+use indicatif::MultiProgress;
+use mistralrs_quant::QuantMethod;
+
+use crate::device_map::DeviceMapper;
+use crate::gguf::Content;
+use crate::layers::MatMul;
+use crate::models::quantized_common::{gguf_linear, layer_norm};
+use crate::paged_attention::AttentionImplementation;
+use crate::pipeline::{extract_logits, Cache, EitherCache};
+use crate::utils::gguf_metadata::ContentMetadata;
+use crate::utils::model_config as ModelConfig;
+use crate::utils::progress::NiceProgressBar;
+/// RWKV keeps a fixed-size state whatever the context length, so this only bounds the scheduler.
+const MAX_SEQ_LEN: usize = 1 << 20;
+/// Epsilon of the group norm of the time mixing output, as in the reference implementation.
+const TIME_MIX_GROUP_NORM_EPS: f64 = 64e-5;
+
+/// Normalize each head of `xs` (b, seq_len, n_head * head_size) separately.
+fn group_norm(xs: &Tensor, weight: &Tensor, bias: &Tensor, n_head: usize) -> Result<Tensor> {
+    let (b_sz, seq_len, hidden) = xs.dims3()?;
+    let xs = xs.reshape((b_sz, seq_len, n_head, hidden / n_head))?;
+    let xs = xs.broadcast_sub(&xs.mean_keepdim(D::Minus1)?)?;
+    let var = xs.sqr()?.mean_keepdim(D::Minus1)?;
+    xs.broadcast_div(&(var + TIME_MIX_GROUP_NORM_EPS)?.sqrt()?)?
+        .reshape((b_sz, seq_len, hidden))?
+        .broadcast_mul(weight)?
+        .broadcast_add(bias)
+}
+
+/// The inputs of the previous positions: `last` (b, hidden) for the first one, then `xs` shifted
+/// by one. Returns them with the last input of `xs`, which is the next `last`.
+fn token_shift(xs: &Tensor, last: &Tensor) -> Result<(Tensor, Tensor)> {
+    let seq_len = xs.dim(1)?;
+    let shifted = if seq_len == 1 {
+        last.unsqueeze(1)?
+    } else {
+        Tensor::cat(&[&last.unsqueeze(1)?, &xs.narrow(1, 0, seq_len - 1)?], 1)?
+    };
+    Ok((shifted, xs.i((.., seq_len - 1))?.contiguous()?))
+}
+
+/// The time mixing of RWKV6, its attention replacement: a linear recurrence over a per-head
+/// (head_size, head_size) state with data-dependent decay.
+struct TimeMix {
+    lerp_x: Tensor,
+    /// Interpolation of w, k, v, r and g, (5, hidden).
+    lerp: Tensor,
+    w1: Arc<dyn QuantMethod>,
+    /// (5, hidden, extra_dim)
+    w2: Tensor,
+    decay: Tensor,
+    decay_w1: Arc<dyn QuantMethod>,
+    decay_w2: Arc<dyn QuantMethod>,
+    /// Bonus of the current token, (n_head, head_size).
+    first: Tensor,
+    key: Arc<dyn QuantMethod>,
+    value: Arc<dyn QuantMethod>,
+    receptance: Arc<dyn QuantMethod>,
+    gate: Arc<dyn QuantMethod>,
+    output: Arc<dyn QuantMethod>,
+    ln_x_weight: Tensor,
+    ln_x_bias: Tensor,
+    n_head: usize,
+    head_size: usize,
+    extra_dim: usize,
+}
+
+impl TimeMix {
+    fn forward(&self, xs: &Tensor, shift: &Tensor, wkv: Tensor) -> Result<(Tensor, Tensor)> {
+        let (b_sz, seq_len, hidden) = xs.dims3()?;
+        let sx = (shift - xs)?;
+
+        // Data-dependent interpolation between each input and the previous one.
+        let xxx = xs.add(&sx.broadcast_mul(&self.lerp_x)?)?;
+        let xxx = MatMul.qmethod_matmul(&xxx, &*self.w1)?.tanh()?;
+        let xxx = xxx
+            .reshape((b_sz * seq_len, 5, self.extra_dim))?
+            .transpose(0, 1)?
+            .contiguous()?
+            .matmul(&self.w2.transpose(1, 2)?.contiguous()?)?
+            .reshape((5, b_sz, seq_len, hidden))?;
+        let mix = |i: usize| -> Result<Tensor> {
+            let m = xxx.i(i)?.broadcast_add(&self.lerp.i(i)?)?;
+            xs + (&sx * m)?
+        };
+        let (xw, xk, xv, xr, xg) = (mix(0)?, mix(1)?, mix(2)?, mix(3)?, mix(4)?);
+
+        let r = MatMul.qmethod_matmul(&xr, &*self.receptance)?;
+        let k = MatMul.qmethod_matmul(&xk, &*self.key)?;
+        let v = MatMul.qmethod_matmul(&xv, &*self.value)?;
+        let g = candle_nn::ops::silu(&MatMul.qmethod_matmul(&xg, &*self.gate)?)?;
+        let w = MatMul.qmethod_matmul(
+            &MatMul.qmethod_matmul(&xw, &*self.decay_w1)?.tanh()?,
+            &*self.decay_w2,
+        )?;
+        let w = w.broadcast_add(&self.decay)?.exp()?.neg()?.exp()?;
+
+        // out = r (u * k^T v + state), state = k^T v + w * state, per head.
+        let heads = |t: &Tensor, i: usize| -> Result<Tensor> {
+            t.i((.., i))?.reshape((b_sz, self.n_head, self.head_size))
+        };
+        let u = self.first.unsqueeze(2)?;
+        let mut state = wkv;
+        let mut ys = Vec::with_capacity(seq_len);
+        for t in 0..seq_len {
+            let (r_t, k_t, v_t, w_t) = (heads(&r, t)?, heads(&k, t)?, heads(&v, t)?, heads(&w, t)?);
+            let kv = k_t.unsqueeze(3)?.broadcast_mul(&v_t.unsqueeze(2)?)?;
+            let y = r_t
+                .unsqueeze(2)?
+                .matmul(&kv.broadcast_mul(&u)?.add(&state)?)?
+                .reshape((b_sz, hidden))?;
+            state = (kv + w_t.unsqueeze(3)?.broadcast_mul(&state)?)?;
+            ys.push(y);
+        }
+        let y = group_norm(
+            &Tensor::stack(&ys, 1)?,
+            &self.ln_x_weight,
+            &self.ln_x_bias,
+            self.n_head,
+        )?;
+        let y = MatMul.qmethod_matmul(&(y * g)?, &*self.output)?;
+        Ok((y, state))
+    }
+}
+
+/// The channel mixing of RWKV, its MLP replacement.
+struct ChannelMix {
+    lerp_k: Tensor,
+    lerp_r: Tensor,
+    key: Arc<dyn QuantMethod>,
+    value: Arc<dyn QuantMethod>,
+    receptance: Arc<dyn QuantMethod>,
+}
+
+impl ChannelMix {
+    fn forward(&self, xs: &Tensor, shift: &Tensor) -> Result<Tensor> {
+        let sx = (shift - xs)?;
+        let xk = (xs + sx.broadcast_mul(&self.lerp_k)?)?;
+        let xr = (xs + sx.broadcast_mul(&self.lerp_r)?)?;
+        let k = MatMul.qmethod_matmul(&xk, &*self.key)?.relu()?.sqr()?;
+        let kv = MatMul.qmethod_matmul(&k, &*self.value)?;
+        candle_nn::ops::sigmoid(&MatMul.qmethod_matmul(&xr, &*self.receptance)?)? * kv
+    }
+}
+
+struct LayerWeights {
+    ln1: LayerNorm,
+    time_mix: TimeMix,
+    ln2: LayerNorm,
+    channel_mix: ChannelMix,
+}
+
+impl LayerWeights {
+    /// Run the block on `x` of shape (b, seq_len, hidden), advancing its state: the last inputs
+    /// of the time and channel mixing, (b, 2, hidden), and the time mixing state,
+    /// (b, n_head, head_size, head_size). The state is created for the first tokens of the
+    /// sequences.
+    fn forward(&self, x: &Tensor, state: &mut Option<(Tensor, Tensor)>) -> Result<Tensor> {
+        let (b_sz, _, hidden) = x.dims3()?;
+        let tm = &self.time_mix;
+        let (shift, wkv) = match state.take() {
+            Some(state) => state,
+            None => (
+                Tensor::zeros((b_sz, 2, hidden), DType::F32, x.device())?,
+                Tensor::zeros(
+                    (b_sz, tm.n_head, tm.head_size, tm.head_size),
+                    DType::F32,
+                    x.device(),
+                )?,
+            ),
+        };
+
+        let xs = self.ln1.forward(x)?;
+        let (prev, tm_last) = token_shift(&xs, &shift.i((.., 0))?)?;
+        let (y, wkv) = tm.forward(&xs, &prev, wkv)?;
+        let x = (x + y)?;
+
+        let xs = self.ln2.forward(&x)?;
+        let (prev, cm_last) = token_shift(&xs, &shift.i((.., 1))?)?;
+        let x = (&x + self.channel_mix.forward(&xs, &prev)?)?;
+
+        *state = Some((Tensor::stack(&[tm_last, cm_last], 1)?, wkv));
+        Ok(x)
+    }
+}
+
+/// RWKV6 (Finch), a recurrent model with the parallel training of a transformer. Each layer keeps
+/// a fixed-size state instead of a KV cache: the last inputs of its token shifts and the state of
+/// its time mixing. These are stored as the pair of tensors of the layer in the full cache,
+/// batched on the first dimension like KV caches, so the scheduler can clone them in and out of
+/// the sequences.
+///
+/// PagedAttention and prefix caching are not supported, so the pipeline runs RWKV without them.
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    tok_embeddings_norm: LayerNorm,
+    layers: Vec<LayerWeights>,
+    norm: LayerNorm,
+    output: Arc<dyn QuantMethod>,
+    rescale_every_n_layers: usize,
+    pub device: Device,
+    pub cache: EitherCache,
+    pub max_seq_len: usize,
+    mapper: Option<Box<dyn DeviceMapper + Send + Sync>>,
+}
+
+// rwkv `llm` fields, written as `rwkv6` by llama.cpp:
+// https://github.com/ggerganov/ggml/blob/master/docs/gguf.md#llm
+// NOTE: Types here do not match spec
+pub(crate) struct PropsGGUF {
+    pub block_count: usize,
+    pub embedding_length: usize,
+    pub head_size: usize,
+    pub time_mix_extra_dim: usize,
+    pub layer_norm_epsilon: f64,
+    pub rescale_every_n_layers: usize,
+}
+
+impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
+    type Error = anyhow::Error;
+
+    fn try_from(c: ContentMetadata) -> std::result::Result<Self, Self::Error> {
+        c.verify_arch("rwkv")?;
+
+        let required = [
+            "block_count",
+            "embedding_length",
+            "wkv.head_size",
+            "time_mix_extra_dim",
+            "attention.layer_norm_epsilon",
+        ];
+        c.has_required_keys(&required)?;
+
+        // NOTE: Values are not aligned with GGUFv3 types
+        // TODO: Normalize value types to spec
+        let props = Self {
+            block_count: c.get_value::<u32>("block_count")? as usize,
+            embedding_length: c.get_value::<u32>("embedding_length")? as usize,
+            head_size: c.get_value::<u32>("wkv.head_size")? as usize,
+            time_mix_extra_dim: c.get_value::<u32>("time_mix_extra_dim")? as usize,
+            layer_norm_epsilon: c.get_value::<f32>("attention.layer_norm_epsilon")? as f64,
+            rescale_every_n_layers: c
+                .get_option_value::<u32>("rescale_every_n_layers")?
+                .unwrap_or(0) as usize,
+        };
+
+        Ok(props)
+    }
+}
+
+impl ModelConfig::FromGGUF for ModelWeights {
+    fn from_gguf<R: std::io::Seek + std::io::Read>(
+        mut ct: Content<'_, R>,
+        device: &Device,
+        mapper: Box<dyn DeviceMapper + Send + Sync>,
+        attention_mechanism: AttentionImplementation,
+        _dtype: DType,
+    ) -> Result<Self> {
+        if matches!(attention_mechanism, AttentionImplementation::PagedAttention) {
+            candle_core::bail!("RWKV does not support PagedAttention.");
+        }
+
+        // Parameter extraction from metadata.
+        let metadata = ContentMetadata {
+            path_prefix: "rwkv",
+            metadata: ct.get_metadata(),
+        };
+        let PropsGGUF {
+            block_count,
+            embedding_length,
+            head_size,
+            time_mix_extra_dim,
+            layer_norm_epsilon,
+            rescale_every_n_layers,
+        } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
+        if head_size == 0 || embedding_length % head_size != 0 {
+            candle_core::bail!(
+                "RWKV head size {head_size} does not divide the hidden size {embedding_length}."
+            );
+        }
+        let n_head = embedding_length / head_size;
+
+        let qtok_embeddings = ct.tensor("token_embd.weight", device)?;
+        let tok_embeddings = qtok_embeddings.dequantize(device)?;
+        let tok_embeddings_norm = layer_norm(
+            ct.tensor("token_embd_norm.weight", device)?,
+            ct.tensor("token_embd_norm.bias", device)?,
+            layer_norm_epsilon,
+        )?;
+        let norm = layer_norm(
+            ct.tensor("output_norm.weight", device)?,
+            ct.tensor("output_norm.bias", device)?,
+            layer_norm_epsilon,
+        )?;
+        let output = if !ct.has_tensor("output.weight") {
+            ct.tensor("token_embd.weight", device)?
+        } else {
+            ct.tensor("output.weight", device)?
+        };
+        let mut layers = Vec::with_capacity(block_count);
+
+        for layer_idx in NiceProgressBar::<_, 'b'>(
+            0..block_count,
+            "Loading repeating layers",
+            &MultiProgress::new(),
+        ) {
+            let prefix = format!("blk.{layer_idx}");
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+
+            // Recent converters fuse the interpolations of w, k, v, r and g.
+            let lerp = if ct.has_tensor(&format!("{prefix}.time_mix_lerp_fused.weight")) {
+                ct.tensor(&format!("{prefix}.time_mix_lerp_fused.weight"), device)?
+                    .dequantize(device)?
+                    .reshape((5, embedding_length))?
+            } else {
+                let mut lerps = Vec::with_capacity(5);
+                for name in ["w", "k", "v", "r", "g"] {
+                    lerps.push(
+                        ct.tensor(&format!("{prefix}.time_mix_lerp_{name}.weight"), device)?
+                            .dequantize(device)?
+                            .flatten_all()?,
+                    );
+                }
+                Tensor::stack(&lerps, 0)?
+            };
+            // The interpolations are stored as (1, 1, hidden), flattened here.
+            let mut dense = |name: &str| -> Result<Tensor> {
+                ct.tensor(&format!("{prefix}.{name}"), device)?
+                    .dequantize(device)
+            };
+            let lerp_x = dense("time_mix_lerp_x.weight")?.flatten_all()?;
+            let w2 = dense("time_mix_w2.weight")?;
+            if w2.dims() != [5, embedding_length, time_mix_extra_dim] {
+                candle_core::bail!(
+                    "RWKV layer {layer_idx} has a time mixing projection of shape {:?}, expected {:?}.",
+                    w2.dims(),
+                    [5, embedding_length, time_mix_extra_dim]
+                );
+            }
+            let decay = dense("time_mix_decay.weight")?.flatten_all()?;
+            let first = dense("time_mix_first.weight")?.reshape((n_head, head_size))?;
+            let ln_x_weight = dense("time_mix_ln.weight")?;
+            let ln_x_bias = dense("time_mix_ln.bias")?;
+            let lerp_k = dense("channel_mix_lerp_k.weight")?.flatten_all()?;
+            let lerp_r = dense("channel_mix_lerp_r.weight")?.flatten_all()?;
+
+            let mut proj = |name: &str| -> Result<Arc<dyn QuantMethod>> {
+                gguf_linear(ct.tensor(&format!("{prefix}.{name}.weight"), device)?, None)
+            };
+            let time_mix = TimeMix {
+                lerp_x,
+                lerp,
+                w1: proj("time_mix_w1")?,
+                w2,
+                decay,
+                decay_w1: proj("time_mix_decay_w1")?,
+                decay_w2: proj("time_mix_decay_w2")?,
+                first,
+                key: proj("time_mix_key")?,
+                value: proj("time_mix_value")?,
+                receptance: proj("time_mix_receptance")?,
+                gate: proj("time_mix_gate")?,
+                output: proj("time_mix_output")?,
+                ln_x_weight,
+                ln_x_bias,
+                n_head,
+                head_size,
+                extra_dim: time_mix_extra_dim,
+            };
+            let channel_mix = ChannelMix {
+                lerp_k,
+                lerp_r,
+                key: proj("channel_mix_key")?,
+                value: proj("channel_mix_value")?,
+                receptance: proj("channel_mix_receptance")?,
+            };
+
+            let mut norm = |name: &str| -> Result<LayerNorm> {
+                layer_norm(
+                    ct.tensor(&format!("{prefix}.{name}.weight"), device)?,
+                    ct.tensor(&format!("{prefix}.{name}.bias"), device)?,
+                    layer_norm_epsilon,
+                )
+            };
+            layers.push(LayerWeights {
+                ln1: norm("attn_norm")?,
+                time_mix,
+                ln2: norm("attn_norm_2")?,
+                channel_mix,
+            })
+        }
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            tok_embeddings_norm,
+            layers,
+            norm,
+            output: gguf_linear(output, None)?,
+            rescale_every_n_layers,
+            device: device.clone(),
+            cache: EitherCache::Full(Cache::new(block_count, false)),
+            max_seq_len: MAX_SEQ_LEN,
+            mapper: Some(mapper),
+        })
+    }
+}
+
+impl ModelWeights {
+    /// Run the new tokens of the sequences, which all have the same length, continuing from the
+    /// states in the cache. There are no positions, so a decoding step is a prompt of one token.
+    pub fn forward(&self, x: &Tensor, context_lens: Vec<(usize, usize)>) -> Result<Tensor> {
+        let mut layer_in = self
+            .tok_embeddings_norm
+            .forward(&self.tok_embeddings.forward(x)?)?;
+        let mut cache = self.cache.full().lock();
+        for (i, layer) in self.layers.iter().enumerate() {
+            if let Some(ref mapper) = self.mapper {
+                layer_in = mapper.map(layer_in, i)?;
+            }
+            layer_in = layer.forward(&layer_in, &mut cache[i])?;
+            // The converters divide the output projections by the same factor to stay in the
+            // range of F16.
+            if self.rescale_every_n_layers != 0 && (i + 1) % self.rescale_every_n_layers == 0 {
+                layer_in = (layer_in / 2.)?;
+            }
+        }
+        let x = self.norm.forward(&layer_in)?;
+        extract_logits(
+            &MatMul.qmethod_matmul(&x.contiguous()?, &*self.output)?,
+            context_lens,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::ModelWeights;
+    use crate::models::quantized_test_utils::{
+        assert_close, assert_decoding_matches_prompt, assert_finite, last_logits, load, TestGguf,
+    };
+
+    /// Write a GGUF file of an RWKV6 model with two layers and tied embeddings. The first layer
+    /// has fused interpolations, the second separate ones.
+    fn rwkv_gguf() -> candle_core::Result<Vec<u8>> {
+        let dev = Device::Cpu;
+        let (vocab, hidden, head_size, extra, decay_extra, ff) = (16, 64, 16, 32, 32, 128);
+        let n_head = hidden / head_size;
+        let uniform = |shape: &[usize]| Tensor::rand(0f32, 1f32, shape, &dev);
+
+        let mut gguf = TestGguf::new("rwkv6");
+        gguf.u32("block_count", 2)
+            .u32("context_length", 1048576)
+            .u32("embedding_length", hidden)
+            .u32("feed_forward_length", ff)
+            .u32("attention.head_count", 0)
+            .u32("wkv.head_size", head_size)
+            .u32("time_mix_extra_dim", extra)
+            .u32("time_decay_extra_dim", decay_extra)
+            .u32("rescale_every_n_layers", 1)
+            .f32("attention.layer_norm_epsilon", 1e-5);
+        gguf.linear("token_embd.weight", vocab, hidden)?
+            .layer_norm("token_embd_norm", hidden)?
+            .layer_norm("output_norm", hidden)?;
+        for layer in 0..2 {
+            let name = |name: &str| format!("blk.{layer}.{name}");
+            gguf.layer_norm(&name("attn_norm"), hidden)?
+                .layer_norm(&name("attn_norm_2"), hidden)?
+                .dense(name("time_mix_lerp_x.weight"), &uniform(&[1, 1, hidden])?)?
+                .linear(name("time_mix_w1.weight"), 5 * extra, hidden)?
+                .dense(
+                    name("time_mix_w2.weight"),
+                    &(Tensor::randn(0f32, 1f32, (5, hidden, extra), &dev)? * 0.1)?,
+                )?
+                .dense(
+                    name("time_mix_decay.weight"),
+                    &(Tensor::rand(-1f32, 0f32, hidden, &dev)? - 1.)?,
+                )?
+                .linear(name("time_mix_decay_w1.weight"), decay_extra, hidden)?
+                .linear(name("time_mix_decay_w2.weight"), hidden, decay_extra)?
+                .dense(
+                    name("time_mix_first.weight"),
+                    &uniform(&[n_head, head_size])?,
+                )?;
+            for proj in ["key", "value", "receptance", "gate", "output"] {
+                gguf.linear(name(&format!("time_mix_{proj}.weight")), hidden, hidden)?;
+            }
+            gguf.layer_norm(&name("time_mix_ln"), hidden)?
+                .dense(
+                    name("channel_mix_lerp_k.weight"),
+                    &uniform(&[1, 1, hidden])?,
+                )?
+                .dense(
+                    name("channel_mix_lerp_r.weight"),
+                    &uniform(&[1, 1, hidden])?,
+                )?
+                .linear(name("channel_mix_key.weight"), ff, hidden)?
+                .linear(name("channel_mix_value.weight"), hidden, ff)?
+                .linear(name("channel_mix_receptance.weight"), hidden, hidden)?;
+            if layer == 0 {
+                gguf.dense(
+                    name("time_mix_lerp_fused.weight"),
+                    &uniform(&[5, 1, 1, hidden])?,
+                )?;
+            } else {
+                for lerp in ["w", "k", "v", "r", "g"] {
+                    gguf.dense(
+                        name(&format!("time_mix_lerp_{lerp}.weight")),
+                        &uniform(&[1, 1, hidden])?,
+                    )?;
+                }
+            }
+        }
+        gguf.build()
+    }
+
+    fn logits(model: &ModelWeights, ids: &[u32], _offset: usize) -> candle_core::Result<Vec<f32>> {
+        let input_ids = Tensor::new(ids, &Device::Cpu)?.unsqueeze(0)?;
+        last_logits(model.forward(&input_ids, vec![(ids.len() - 1, 1)])?)
+    }
+
+    #[test]
+    fn forward_keeps_fixed_size_state() -> candle_core::Result<()> {
+        let model = load::<ModelWeights>(&rwkv_gguf()?)?;
+        assert_eq!(model.max_seq_len, 1 << 20);
+
+        let out = logits(&model, &[1, 5, 7], 0)?;
+        assert_eq!(out.len(), 16);
+        assert_finite(&out);
+
+        let shapes = |model: &ModelWeights| {
+            let cache = model.cache.full().lock();
+            let (shift, wkv) = cache[1].as_ref().unwrap();
+            (shift.dims().to_vec(), wkv.dims().to_vec())
+        };
+        let before = shapes(&model);
+        assert_eq!(before, (vec![1, 2, 64], vec![1, 4, 16, 16]));
+        logits(&model, &[3], 3)?;
+        assert_eq!(shapes(&model), before);
+        Ok(())
+    }
+
+    #[test]
+    fn decoding_matches_prompt() -> candle_core::Result<()> {
+        // Running the prompt in chunks and then decoding continues from the cached states.
+        assert_decoding_matches_prompt(&rwkv_gguf()?, &[&[1, 5], &[7, 3], &[9]], logits)
+    }
+
+    #[test]
+    fn states_are_batched_per_sequence() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let file = rwkv_gguf()?;
+
+        let model = load::<ModelWeights>(&file)?;
+        logits(&model, &[2, 4, 6], 0)?;
+        let expected = logits(&model, &[8], 3)?;
+
+        let model = load::<ModelWeights>(&file)?;
+        let input_ids = Tensor::new(&[[1u32, 5, 7], [2, 4, 6]], &dev)?;
+        model.forward(&input_ids, vec![(2, 1), (2, 1)])?;
+        {
+            let mut cache = model.cache.full().lock();
+            for layer in cache.iter_mut() {
+                let (shift, wkv) = layer.take().unwrap();
+                *layer = Some((shift.chunk(2, 0)?[1].clone(), wkv.chunk(2, 0)?[1].clone()));
+            }
+        }
+        assert_close(&logits(&model, &[8], 3)?, &expected);
+        Ok(())
+    }
+}
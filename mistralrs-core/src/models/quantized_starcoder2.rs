@@ -144,6 +144,7 @@ pub struct ModelWeights {
     pub device: Device,
     pub cache: EitherCache,
     pub max_seq_len: usize,
+    sliding_window: Option<usize>,
     dtype: DType,
 }
 
@@ -157,6 +158,7 @@ pub(crate) struct PropsGGUF {
     pub layer_norm_epsilon: f64,
     pub context_window: usize,
     pub rope_freq_base: f32,
+    pub sliding_window: Option<usize>,
 }
 
 impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
@@ -185,6 +187,9 @@ impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
             layer_norm_epsilon: c.get_value::<f32>("attention.layer_norm_epsilon")? as f64,
             context_window: c.get_value::<u32>("context_length")? as usize,
             rope_freq_base: c.get_value("rope.freq_base").ok().unwrap_or(100_000_f32),
+            sliding_window: c
+                .get_option_value::<u32>("attention.sliding_window")?
+                .map(|x| x as usize),
         };
 
         Ok(props)
@@ -212,6 +217,7 @@ impl ModelConfig::FromGGUF for ModelWeights {
             layer_norm_epsilon,
             context_window,
             rope_freq_base,
+            sliding_window,
         } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
 
         let tok_embeddings = ct.tensor("token_embd.weight", device)?;
@@ -333,7 +339,7 @@ impl ModelConfig::FromGGUF for ModelWeights {
                     use_flash_attn: false,
                     softcap: None,
                     softmax_scale: 1.0 / (head_dim as f32).sqrt(),
-                    sliding_window: None,
+                    sliding_window,
                 },
                 dtype,
             })
@@ -345,8 +351,13 @@ impl ModelConfig::FromGGUF for ModelWeights {
             output,
             mapper: Some(mapper),
             device: device.clone(),
-            cache: EitherCache::Normal(NormalCache::new(block_count, context_window)),
+            cache: EitherCache::Normal(NormalCache::new_sliding(
+                block_count,
+                context_window,
+                sliding_window,
+            )),
             max_seq_len: context_window,
+            sliding_window,
             dtype,
         })
     }
@@ -362,12 +373,13 @@ impl ModelWeights {
         let (_b_sz, seq_len) = input_ids.dims2()?;
         let mut xs = self.tok_embeddings.forward(input_ids)?;
         let cache = &mut self.cache.normal().0;
-        let mask = CausalMasker.make_causal_mask_matrix(
+        let mask = CausalMasker.make_sliding_window_causal_mask_matrix(
             input_ids,
             metadata
                 .as_ref()
                 .map(|(_, _)| &seqlen_offsets as &dyn PastKvLenCache)
                 .unwrap_or(cache as &dyn PastKvLenCache),
+            self.sliding_window,
             self.dtype,
             self.layers[0].n_head,
         )?;
@@ -0,0 +1,176 @@
+//! Small random GGUF models for the tests of the quantized architectures.
+
+use std::io::Cursor;
+
+use candle_core::{
+    quantized::{gguf_file, GgmlDType, QTensor},
+    DType, Device, Result, Tensor,
+};
+
+use crate::{
+    gguf::Content, paged_attention::AttentionImplementation, utils::model_config::FromGGUF,
+    DeviceMapSetting,
+};
+
+/// Builder of an in-memory GGUF file. Metadata keys are relative to the architecture, as in
+/// `block_count` for `llama.block_count`.
+pub(crate) struct TestGguf {
+    arch: String,
+    metadata: Vec<(String, gguf_file::Value)>,
+    tensors: Vec<(String, QTensor)>,
+}
+
+impl TestGguf {
+    pub(crate) fn new(arch: &str) -> Self {
+        Self {
+            arch: arch.to_string(),
+            metadata: vec![(
+                "general.architecture".to_string(),
+                gguf_file::Value::String(arch.to_string()),
+            )],
+            tensors: Vec::new(),
+        }
+    }
+
+    pub(crate) fn metadata(&mut self, key: &str, value: gguf_file::Value) -> &mut Self {
+        self.metadata.push((format!("{}.{key}", self.arch), value));
+        self
+    }
+
+    pub(crate) fn u32(&mut self, key: &str, value: usize) -> &mut Self {
+        self.metadata(key, gguf_file::Value::U32(value as u32))
+    }
+
+    pub(crate) fn f32(&mut self, key: &str, value: f32) -> &mut Self {
+        self.metadata(key, gguf_file::Value::F32(value))
+    }
+
+    /// Set a `general.*` key.
+    pub(crate) fn general(&mut self, key: &str, value: &str) -> &mut Self {
+        self.metadata.push((
+            format!("general.{key}"),
+            gguf_file::Value::String(value.to_string()),
+        ));
+        self
+    }
+
+    pub(crate) fn tensor(&mut self, name: impl Into<String>, tensor: QTensor) -> &mut Self {
+        self.tensors.push((name.into(), tensor));
+        self
+    }
+
+    /// Add an unquantized tensor.
+    pub(crate) fn dense(&mut self, name: impl Into<String>, tensor: &Tensor) -> Result<&mut Self> {
+        let tensor = QTensor::quantize(tensor, GgmlDType::F32)?;
+        Ok(self.tensor(name, tensor))
+    }
+
+    /// Add a random Q8_0 weight of shape `(out_dim, in_dim)`, small enough to keep the
+    /// activations of a few layers in range.
+    pub(crate) fn linear(
+        &mut self,
+        name: impl Into<String>,
+        out_dim: usize,
+        in_dim: usize,
+    ) -> Result<&mut Self> {
+        let w = (Tensor::randn(0f32, 1f32, (out_dim, in_dim), &Device::Cpu)? * 0.1)?;
+        let tensor = QTensor::quantize(&w, GgmlDType::Q8_0)?;
+        Ok(self.tensor(name, tensor))
+    }
+
+    /// Add an unquantized vector filled with `value`, as a norm weight or bias.
+    pub(crate) fn vector(
+        &mut self,
+        name: impl Into<String>,
+        len: usize,
+        value: f64,
+    ) -> Result<&mut Self> {
+        let t = (Tensor::ones(len, DType::F32, &Device::Cpu)? * value)?;
+        self.dense(name, &t)
+    }
+
+    /// Add a layer norm weight of ones and bias of zeros.
+    pub(crate) fn layer_norm(&mut self, name: &str, len: usize) -> Result<&mut Self> {
+        self.vector(format!("{name}.weight"), len, 1.)?
+            .vector(format!("{name}.bias"), len, 0.)
+    }
+
+    pub(crate) fn build(&self) -> Result<Vec<u8>> {
+        let mut buf = Cursor::new(Vec::new());
+        gguf_file::write(
+            &mut buf,
+            &self
+                .metadata
+                .iter()
+                .map(|(k, v)| (k.as_str(), v))
+                .collect::<Vec<_>>(),
+            &self
+                .tensors
+                .iter()
+                .map(|(n, t)| (n.as_str(), t))
+                .collect::<Vec<_>>(),
+        )?;
+        Ok(buf.into_inner())
+    }
+}
+
+/// Load a model from a GGUF file on the CPU, with eager attention.
+pub(crate) fn load<M: FromGGUF>(file: &[u8]) -> Result<M> {
+    let dev = Device::Cpu;
+    let mut reader = Cursor::new(file);
+    let mut readers = [&mut reader];
+    let content = Content::from_readers(&mut readers)?;
+    let metadata = content.get_metadata();
+    let arch = metadata["general.architecture"].to_string()?;
+    let layers = metadata[&format!("{arch}.block_count")].to_u32()? as usize;
+    let mapper = DeviceMapSetting::dummy().into_mapper(layers, &dev, None)?;
+    M::from_gguf(
+        content,
+        &dev,
+        mapper,
+        AttentionImplementation::Eager,
+        DType::F32,
+    )
+}
+
+/// Flatten the logits of the last token of a single sequence.
+pub(crate) fn last_logits(logits: Tensor) -> Result<Vec<f32>> {
+    let (batch, seq_len, _) = logits.dims3()?;
+    assert_eq!((batch, seq_len), (1, 1), "{:?}", logits.shape());
+    logits.flatten_all()?.to_vec1::<f32>()
+}
+
+pub(crate) fn assert_finite(logits: &[f32]) {
+    assert!(logits.iter().all(|x| x.is_finite()), "{logits:?}");
+}
+
+pub(crate) fn assert_close(a: &[f32], b: &[f32]) {
+    assert_eq!(a.len(), b.len());
+    for (a, b) in a.iter().zip(b) {
+        assert!((a - b).abs() < 1e-4, "{a} != {b}");
+    }
+}
+
+/// Check that running `chunks` one after the other, each continuing from the cache of the
+/// previous ones, gives the same logits for the last token as running them as a single prompt.
+///
+/// `logits(model, ids, offset)` runs `ids` at position `offset` and returns the logits of the
+/// last token.
+pub(crate) fn assert_decoding_matches_prompt<M: FromGGUF>(
+    file: &[u8],
+    chunks: &[&[u32]],
+    logits: impl Fn(&M, &[u32], usize) -> Result<Vec<f32>>,
+) -> Result<()> {
+    let model = load::<M>(file)?;
+    let expected = logits(&model, &chunks.concat(), 0)?;
+
+    let model = load::<M>(file)?;
+    let mut offset = 0;
+    let mut decoded = Vec::new();
+    for chunk in chunks {
+        decoded = logits(&model, chunk, offset)?;
+        offset += chunk.len();
+    }
+    assert_close(&decoded, &expected);
+    Ok(())
+}
@@ -42,6 +42,19 @@ impl LogicalTokenBlock {
         self.tokens.pop();
         self.num_tokens -= 1;
     }
+
+    pub fn toks(&self) -> &[usize] {
+        &self.tokens[..self.num_tokens]
+    }
+}
+
+/// The tokens of the leading full blocks of `blocks`, stopping at the first block which is not full.
+pub fn full_blocks(blocks: &[LogicalTokenBlock]) -> Vec<&[usize]> {
+    blocks
+        .iter()
+        .take_while(|block| block.is_full())
+        .map(|block| block.toks())
+        .collect()
 }
 
 #[derive(Hash, PartialEq, Eq)]
@@ -171,6 +184,70 @@ impl Allocator<CPUAllocator> {
     }
 }
 
+/// The key of a cached block: the id of the block before it in the prefix, if any, and its
+/// tokens. Following the keys from the first block identifies the whole token prefix exactly.
+type PrefixBlockKey = (Option<usize>, Vec<usize>);
+
+/// Maps full token blocks, in the context of the prefix before them, to the GPU blocks holding
+/// their KV cache, so that sequences with a common prefix (such as a system prompt) share those
+/// blocks.
+///
+/// Shared blocks are immutable: they are full, so new tokens are always written to a private
+/// block, and [`BlockEngine::append_token_slot_to_seq`] copies a block on write if it is shared.
+/// The map does not hold a reference to the blocks, a block is forgotten once it is freed. A
+/// sequence holding a cached block also holds the block before it, so that block is only freed,
+/// and its id reused, once the blocks keyed on it are freed too.
+#[derive(Default)]
+pub struct SharedPrefixBlockAllocator {
+    blocks: HashMap<PrefixBlockKey, Arc<PhysicalTokenBlock>>,
+    keys: HashMap<usize, PrefixBlockKey>,
+    hits: usize,
+}
+
+impl SharedPrefixBlockAllocator {
+    /// Number of leading full blocks which are already cached.
+    fn num_shared(&self, full_blocks: &[&[usize]]) -> usize {
+        let mut parent = None;
+        for (i, tokens) in full_blocks.iter().enumerate() {
+            match self.blocks.get(&(parent, tokens.to_vec())) {
+                Some(block) => parent = Some(block.deref_mut().block_id),
+                None => return i,
+            }
+        }
+        full_blocks.len()
+    }
+
+    /// Take a reference to the block cached for these tokens after the block `parent`.
+    fn get(&mut self, parent: Option<usize>, tokens: &[usize]) -> Option<Arc<PhysicalTokenBlock>> {
+        let block = self.blocks.get(&(parent, tokens.to_vec()))?.clone();
+        block.deref_mut().refcount += 1;
+        self.hits += 1;
+        Some(block)
+    }
+
+    fn insert(&mut self, parent: Option<usize>, tokens: &[usize], block: Arc<PhysicalTokenBlock>) {
+        let key = (parent, tokens.to_vec());
+        self.keys.insert(block.deref_mut().block_id, key.clone());
+        self.blocks.insert(key, block);
+    }
+
+    /// Forget a block which has been freed and may be reused for other tokens.
+    fn evict(&mut self, block_id: usize) {
+        if let Some(key) = self.keys.remove(&block_id) {
+            self.blocks.remove(&key);
+        }
+    }
+
+    pub fn num_cached_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Number of blocks which were shared instead of allocated.
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+}
+
 pub enum AllocStatus {
     Ok,
     Later,
@@ -187,6 +264,7 @@ pub struct BlockEngine {
     num_gpu_blocks: usize,
     gpu_allocator: Allocator<GPUAllocator>,
     cpu_allocator: Allocator<CPUAllocator>,
+    prefix_allocator: SharedPrefixBlockAllocator,
     pub block_tables: HashMap<SeqID, BlockTable>,
 }
 
@@ -199,12 +277,18 @@ impl BlockEngine {
             num_gpu_blocks,
             gpu_allocator: Allocator::<GPUAllocator>::new(block_size, num_gpu_blocks),
             cpu_allocator: Allocator::<CPUAllocator>::new(block_size, num_cpu_blocks),
+            prefix_allocator: SharedPrefixBlockAllocator::default(),
             block_tables: HashMap::new(),
         }
     }
 
+    pub fn prefix_allocator(&self) -> &SharedPrefixBlockAllocator {
+        &self.prefix_allocator
+    }
+
     pub fn can_allocate(&self, seq: &impl BlockEngineSequence) -> AllocStatus {
-        let num_required_blocks = seq.get_logical_token_blocks();
+        let num_shared = self.prefix_allocator.num_shared(&seq.full_blocks());
+        let num_required_blocks = seq.get_logical_token_blocks() - num_shared;
         let num_free_gpu_blocks = self.gpu_allocator.get_num_free_blocks();
 
         if *num_free_gpu_blocks < num_required_blocks {
@@ -216,14 +300,37 @@ impl BlockEngine {
         }
     }
 
+    /// Allocate the blocks of a sequence. Blocks for a prefix which is already cached are shared,
+    /// private blocks are allocated for the rest, and new full blocks are cached for sharing.
     pub fn allocate(&mut self, seq: &impl BlockEngineSequence) {
-        let mut block_table = Vec::new();
-        for _logcical_idx in 0..seq.get_logical_token_blocks() {
-            block_table.push(self.gpu_allocator.allocate());
+        let full_blocks = seq.full_blocks();
+        let mut block_table: BlockTable = Vec::new();
+        for logical_idx in 0..seq.get_logical_token_blocks() {
+            let parent = block_table.last().map(|block| block.deref_mut().block_id);
+            let tokens = full_blocks.get(logical_idx);
+            let block = match tokens.and_then(|tokens| self.prefix_allocator.get(parent, tokens)) {
+                Some(block) => block,
+                None => {
+                    let block = self.gpu_allocator.allocate();
+                    if let Some(tokens) = tokens {
+                        self.prefix_allocator.insert(parent, tokens, block.clone());
+                    }
+                    block
+                }
+            };
+            block_table.push(block);
         }
         self.block_tables.insert(seq.get_id(), block_table.clone());
     }
 
+    fn free_gpu_block(&mut self, block: Arc<PhysicalTokenBlock>) {
+        let block_id = block.deref_mut().block_id;
+        self.gpu_allocator.free_block(block.clone());
+        if block.deref_mut().refcount == 0 {
+            self.prefix_allocator.evict(block_id);
+        }
+    }
+
     pub fn can_append_token_to_seq(&self, seq: &impl BlockEngineSequence) -> bool {
         let free_blocks = self.gpu_allocator.get_num_free_blocks();
         // Physical blocks = logical blocks
@@ -232,17 +339,15 @@ impl BlockEngine {
 
     pub fn free_sequence(&mut self, id: usize) {
         // Handle double free if run out of tokens
-        if let Some(block_table) = self.block_tables.get(&id) {
+        if let Some(block_table) = self.block_tables.remove(&id) {
             // Free from block table
             for block in block_table {
                 if block.deref_mut().is_gpu {
-                    self.gpu_allocator.free_block(block.clone())
+                    self.free_gpu_block(block)
                 } else {
-                    self.cpu_allocator.free_block(block.clone())
+                    self.cpu_allocator.free_block(block)
                 }
             }
-
-            self.block_tables.remove(&id);
         }
     }
 
@@ -266,9 +371,9 @@ impl BlockEngine {
         let seq_id = seq.get_id();
 
         let mut new_block_table = Vec::new();
-        let block_table = self.block_tables.get(&seq_id).unwrap();
+        let block_table = self.block_tables.get(&seq_id).unwrap().clone();
 
-        for gpu_block in block_table {
+        for gpu_block in &block_table {
             let cpu_block =
                 if let Entry::Vacant(e) = new_mapping.entry(gpu_block.deref_mut().block_id) {
                     // Create a new block
@@ -285,7 +390,7 @@ impl BlockEngine {
                     cpu_block
                 };
             new_block_table.push(cpu_block);
-            self.gpu_allocator.free_block(gpu_block.clone());
+            self.free_gpu_block(gpu_block.clone());
         }
         self.block_tables.insert(seq_id, new_block_table);
 
@@ -314,7 +419,8 @@ impl BlockEngine {
                 if last_block.deref_mut().refcount == 1 {
                     None
                 } else {
-                    // We would be writing into shared, so COW.
+                    // We would be writing into shared, so COW. The block is still referenced by
+                    // other sequences, so it stays cached for sharing.
                     let new_block = self.gpu_allocator.allocate();
                     self.gpu_allocator.free_block(last_block.clone());
                     let old_number = last_block.deref_mut().block_id;
@@ -376,3 +482,135 @@ impl BlockEngine {
             .collect::<HashMap<_, _>>()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{full_blocks, BlockEngine, BlockEngineSequence, LogicalTokenBlock};
+
+    struct TestSeq {
+        id: usize,
+        blocks: Vec<LogicalTokenBlock>,
+    }
+
+    impl TestSeq {
+        fn new(id: usize, toks: &[usize], block_size: usize) -> Self {
+            let mut blocks = vec![LogicalTokenBlock::new(block_size)];
+            for tok in toks {
+                if blocks.last().unwrap().is_full() {
+                    blocks.push(LogicalTokenBlock::new(block_size));
+                }
+                blocks.last_mut().unwrap().append_token_id(*tok);
+            }
+            Self { id, blocks }
+        }
+    }
+
+    impl BlockEngineSequence for TestSeq {
+        fn blocks_to_add_new_tok(&self) -> usize {
+            usize::from(self.blocks.last().unwrap().is_full())
+        }
+        fn get_id(&self) -> usize {
+            self.id
+        }
+        fn get_logical_token_blocks(&self) -> usize {
+            self.blocks.len()
+        }
+        fn full_blocks(&self) -> Vec<&[usize]> {
+            full_blocks(&self.blocks)
+        }
+    }
+
+    fn block_ids(engine: &BlockEngine, id: usize) -> Vec<usize> {
+        engine.block_tables[&id]
+            .iter()
+            .map(|block| block.deref_mut().block_id)
+            .collect()
+    }
+
+    #[test]
+    fn shares_prefix_blocks() {
+        let mut engine = BlockEngine::new(2, 16, 16);
+        // Two full blocks of common prefix, then a different suffix.
+        let a = TestSeq::new(0, &[1, 2, 3, 4, 5], 2);
+        let b = TestSeq::new(1, &[1, 2, 3, 4, 6, 7, 8], 2);
+        let c = TestSeq::new(2, &[1, 9, 3, 4], 2);
+        engine.allocate(&a);
+        engine.allocate(&b);
+        engine.allocate(&c);
+
+        let (a_ids, b_ids, c_ids) = (
+            block_ids(&engine, 0),
+            block_ids(&engine, 1),
+            block_ids(&engine, 2),
+        );
+        assert_eq!(a_ids[..2], b_ids[..2]);
+        assert_ne!(a_ids[2], b_ids[2]);
+        // The second block has the same tokens but a different prefix.
+        assert_ne!(a_ids[0], c_ids[0]);
+        assert_ne!(a_ids[1], c_ids[1]);
+        assert_eq!(engine.prefix_allocator().hits(), 2);
+        assert_eq!(engine.gpu_allocator.free_blocks.len(), 16 - 3 - 2 - 2);
+
+        // Shared blocks stay allocated and cached until every sequence using them is freed.
+        engine.free_sequence(0);
+        assert_eq!(engine.gpu_allocator.free_blocks.len(), 16 - 2 - 2 - 2);
+        let d = TestSeq::new(3, &[1, 2, 3, 4], 2);
+        engine.allocate(&d);
+        assert_eq!(block_ids(&engine, 3), b_ids[..2]);
+        engine.free_sequence(1);
+        engine.free_sequence(3);
+        assert_eq!(engine.gpu_allocator.free_blocks.len(), 16 - 2);
+        assert_eq!(engine.prefix_allocator().num_cached_blocks(), 2);
+    }
+
+    #[test]
+    fn shares_only_the_common_prefix() {
+        let mut engine = BlockEngine::new(2, 16, 16);
+        // The third blocks have the same tokens, after different second blocks.
+        let a = TestSeq::new(0, &[1, 2, 3, 4, 5, 6], 2);
+        let b = TestSeq::new(1, &[1, 2, 7, 8, 5, 6], 2);
+        engine.allocate(&a);
+        engine.allocate(&b);
+
+        let (a_ids, b_ids) = (block_ids(&engine, 0), block_ids(&engine, 1));
+        assert_eq!(a_ids[0], b_ids[0]);
+        assert_ne!(a_ids[1], b_ids[1]);
+        assert_ne!(a_ids[2], b_ids[2]);
+        assert_eq!(engine.prefix_allocator().hits(), 1);
+
+        // Once the prefix is freed, its blocks are reused for other tokens and not shared.
+        engine.free_sequence(0);
+        engine.free_sequence(1);
+        assert_eq!(engine.prefix_allocator().num_cached_blocks(), 0);
+        let c = TestSeq::new(2, &[9, 9, 9, 9, 9, 9], 2);
+        let d = TestSeq::new(3, &[1, 2, 3, 4, 5, 6], 2);
+        engine.allocate(&c);
+        engine.allocate(&d);
+        assert_eq!(engine.prefix_allocator().hits(), 1);
+        assert!(block_ids(&engine, 2)
+            .iter()
+            .all(|id| !block_ids(&engine, 3).contains(id)));
+    }
+
+    #[test]
+    fn new_tokens_go_to_private_blocks() {
+        let mut engine = BlockEngine::new(2, 16, 16);
+        let mut a = TestSeq::new(0, &[1, 2], 2);
+        let b = TestSeq::new(1, &[1, 2], 2);
+        engine.allocate(&a);
+        engine.allocate(&b);
+        assert_eq!(block_ids(&engine, 0), block_ids(&engine, 1));
+
+        // The shared block is full, so the next token gets a new private block.
+        assert!(engine.append_token_slot_to_seq(&a).is_none());
+        let a_ids = block_ids(&engine, 0);
+        assert_eq!(a_ids[0], block_ids(&engine, 1)[0]);
+        assert_eq!(a_ids.len(), 2);
+
+        // Writing into the private block does not need a copy.
+        a.blocks.push(LogicalTokenBlock::new(2));
+        a.blocks.last_mut().unwrap().append_token_id(3);
+        assert!(engine.append_token_slot_to_seq(&a).is_none());
+        assert_eq!(block_ids(&engine, 0), a_ids);
+    }
+}
@@ -2,4 +2,6 @@ pub trait BlockEngineSequence {
     fn blocks_to_add_new_tok(&self) -> usize;
     fn get_id(&self) -> usize;
     fn get_logical_token_blocks(&self) -> usize;
+    /// Tokens of the leading full logical blocks, see [`super::block_engine::full_blocks`].
+    fn full_blocks(&self) -> Vec<&[usize]>;
 }
@@ -11,7 +11,9 @@ mod layers;
 mod scheduler;
 pub const _PAD_SLOT_ID: i64 = -1;
 
-pub use block_engine::{BlockEngine, BlockTables, LogicalTokenBlock};
+pub use block_engine::{
+    full_blocks, BlockEngine, BlockTables, LogicalTokenBlock, SharedPrefixBlockAllocator,
+};
 pub use block_engine_sequence::BlockEngineSequence;
 pub use cache_engine::{CacheConfig, CacheEngine};
 use candle_core::{DType, Device};
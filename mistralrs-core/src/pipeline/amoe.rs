@@ -23,7 +23,7 @@ use crate::{
     device_map::DeviceMapper,
     get_mut_arcmutex,
     prefix_cacher::PrefixCacheManagerV2,
-    sampler::Sampler,
+    sampler::{Sampler, SamplerBackend},
     sequence::{SeqStepType, Sequence, SequenceGroup, SequenceRecognizer},
     utils::progress::NiceProgressBar,
     DeviceMapSetting, Loader, ModelCategory, ModelKind, ModelPaths, PagedAttentionConfig, Pipeline,
@@ -372,15 +372,20 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
             None,
             None,
             None,
+            None,
             -1,
             0.0,
             0.0,
+            None,
+            SamplerBackend::Native,
+            None,
+            None,
             vec![],
         )
         .map_err(candle_core::Error::msg)?;
 
         let dummy_group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
-            1, false, false, None,
+            1, false, false, None, None,
         )));
 
         let mut latest_loss = vec![0.0; optimizers.len()];
@@ -411,6 +416,7 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
                             true,
                             true,
                             Vec::new(),
+                            None,
                         )
                         .map_err(candle_core::Error::msg)?;
                     let images = image_urls.as_ref().map(|urls| {
@@ -555,7 +561,7 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
 
 /// Create a dummy sequence containing just the prompt. This is OK because we just want a sequence that
 /// has no information other than the input tokens (and maybe images).
-fn new_dummy_seq(
+pub(crate) fn new_dummy_seq(
     (tokens, prompt): (Vec<u32>, String),
     dummy_sender: tokio::sync::mpsc::Sender<Response>,
     dummy_sampler: Sampler,
@@ -573,6 +579,10 @@ fn new_dummy_seq(
         dummy_sampler,
         vec![],
         vec![],
+        false,
+        None,
+        None,
+        None,
         None,
         false,
         false,
@@ -0,0 +1,228 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use candle_core::{Device, Result};
+use serde::Serialize;
+use tracing::info;
+
+use crate::{
+    sampler::{Sampler, SamplerBackend},
+    sequence::{Sequence, SequenceGroup},
+    MemoryUsage, ModelCategory, Pipeline,
+};
+
+use super::{amoe::new_dummy_seq, inputs_processor::InputProcessorOutput, ForwardInputsResult};
+
+/// Shapes to benchmark. Every combination of prompt length, batch size and decode length is run.
+#[derive(Clone, Debug)]
+pub struct BenchmarkConfig {
+    pub prompt_lengths: Vec<usize>,
+    pub batch_sizes: Vec<usize>,
+    /// Number of tokens decoded after the first token.
+    pub decode_lengths: Vec<usize>,
+    /// Unmeasured runs of each combination before the measured one.
+    pub warmup_runs: usize,
+}
+
+/// Measurements of one combination of a [`BenchmarkConfig`].
+#[derive(Clone, Debug, Serialize)]
+pub struct BenchmarkRun {
+    pub prompt_len: usize,
+    pub batch_size: usize,
+    pub decode_len: usize,
+    /// Time to process the prompts of the batch and compute the logits of the first token.
+    pub time_to_first_token: Duration,
+    /// Time to decode the tokens after the first.
+    pub decode_time: Duration,
+    /// Prompt tokens of the whole batch per second.
+    pub prompt_throughput_tps: f64,
+    /// Decoded tokens of the whole batch per second.
+    pub decode_throughput_tps: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BenchmarkResults {
+    /// Prompt tokens per second over all runs.
+    pub prompt_throughput_tps: f64,
+    /// Decoded tokens per second over all runs.
+    pub decode_throughput_tps: f64,
+    /// Peak memory used on the device of the pipeline, including by other processes.
+    pub memory_used_mb: u64,
+    pub runs: Vec<BenchmarkRun>,
+}
+
+/// Measures the prompt and decode throughput of a pipeline by running the model directly on
+/// synthetic inputs, without the scheduler or sampling.
+pub struct ModelBenchmark;
+
+impl ModelBenchmark {
+    pub fn run(pipeline: &mut dyn Pipeline, config: BenchmarkConfig) -> Result<BenchmarkResults> {
+        if pipeline.category() != ModelCategory::Text {
+            candle_core::bail!("Only text models can be benchmarked.");
+        }
+        if pipeline.get_metadata().cache_config.is_some() {
+            candle_core::bail!("Benchmarking a pipeline with PagedAttention is not supported.");
+        }
+
+        let mut memory_used = memory_used(&pipeline.device())?;
+        let mut runs = Vec::new();
+        for &prompt_len in &config.prompt_lengths {
+            for &batch_size in &config.batch_sizes {
+                for &decode_len in &config.decode_lengths {
+                    for _ in 0..config.warmup_runs {
+                        Self::run_once(pipeline, prompt_len, batch_size, decode_len)?;
+                    }
+                    let (time_to_first_token, decode_time, run_memory_used) =
+                        Self::run_once(pipeline, prompt_len, batch_size, decode_len)?;
+                    memory_used = memory_used.max(run_memory_used);
+
+                    let run = BenchmarkRun {
+                        prompt_len,
+                        batch_size,
+                        decode_len,
+                        time_to_first_token,
+                        decode_time,
+                        prompt_throughput_tps: tps(batch_size * prompt_len, time_to_first_token),
+                        decode_throughput_tps: tps(batch_size * decode_len, decode_time),
+                    };
+                    info!(
+                        "pp {prompt_len}, tg {decode_len}, batch size {batch_size}: time to first token {:.2}ms, {:.2} prompt T/s, {:.2} decode T/s",
+                        time_to_first_token.as_secs_f64() * 1000.,
+                        run.prompt_throughput_tps,
+                        run.decode_throughput_tps
+                    );
+                    runs.push(run);
+                }
+            }
+        }
+
+        let prompt_toks = runs.iter().map(|r| r.batch_size * r.prompt_len).sum();
+        let prompt_time = runs.iter().map(|r| r.time_to_first_token).sum();
+        let decode_toks = runs.iter().map(|r| r.batch_size * r.decode_len).sum();
+        let decode_time = runs.iter().map(|r| r.decode_time).sum();
+        Ok(BenchmarkResults {
+            prompt_throughput_tps: tps(prompt_toks, prompt_time),
+            decode_throughput_tps: tps(decode_toks, decode_time),
+            memory_used_mb: (memory_used / (1024 * 1024)) as u64,
+            runs,
+        })
+    }
+
+    /// Returns the time to first token, the decode time and the memory used before the KV cache
+    /// is freed.
+    fn run_once(
+        pipeline: &mut dyn Pipeline,
+        prompt_len: usize,
+        batch_size: usize,
+        decode_len: usize,
+    ) -> Result<(Duration, Duration, usize)> {
+        let (sender, _) = tokio::sync::mpsc::channel(1);
+        let sampler = Sampler::new(
+            None,
+            0,
+            pipeline.tokenizer(),
+            None,
+            None,
+            None,
+            None,
+            -1,
+            0.0,
+            0.0,
+            None,
+            SamplerBackend::Native,
+            None,
+            None,
+            vec![],
+        )
+        .map_err(candle_core::Error::msg)?;
+        let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
+            1, false, false, None, None,
+        )));
+        // The same synthetic prompt as `mistralrs-bench`.
+        let tokens = (1000..).take(prompt_len).collect::<Vec<u32>>();
+        let eos_toks = pipeline.get_metadata().eos_tok.clone();
+        let mut seqs = (0..batch_size)
+            .map(|_| {
+                new_dummy_seq(
+                    (tokens.clone(), String::new()),
+                    sender.clone(),
+                    sampler.clone(),
+                    group.clone(),
+                    None,
+                    eos_toks.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut seqs = seqs.iter_mut().collect::<Vec<_>>();
+
+        pipeline.set_none_cache(&mut seqs, true, false, false);
+
+        let start = Instant::now();
+        Self::forward(pipeline, &mut seqs, true)?;
+        let time_to_first_token = start.elapsed();
+
+        let start = Instant::now();
+        for _ in 0..decode_len {
+            for seq in seqs.iter_mut() {
+                seq.add_tmp_tok(1000);
+            }
+            Self::forward(pipeline, &mut seqs, false)?;
+        }
+        let decode_time = start.elapsed();
+
+        let memory_used = memory_used(&pipeline.device())?;
+        pipeline.set_none_cache(&mut seqs, true, false, false);
+        Ok((time_to_first_token, decode_time, memory_used))
+    }
+
+    /// Run the model on the sequences and wait for the logits.
+    fn forward(
+        pipeline: &mut dyn Pipeline,
+        seqs: &mut [&mut Sequence],
+        is_prompt: bool,
+    ) -> Result<()> {
+        let metadata = pipeline.get_metadata();
+        let inputs = pipeline.get_processor().inputs_processor().process_inputs(
+            pipeline.tokenizer(),
+            seqs,
+            is_prompt,
+            metadata.is_xlora,
+            &pipeline.device(),
+            metadata.no_kv_cache,
+            None,
+            false,
+            pipeline.get_input_processor_config(),
+            None,
+            metadata.prompt_chunksize,
+            pipeline.device_mapper(),
+        );
+        for inputs in inputs {
+            let InputProcessorOutput { inputs, .. } = inputs.map_err(candle_core::Error::msg)?;
+            if let ForwardInputsResult::CausalGeneration { logits } =
+                pipeline.forward_inputs(inputs, false)?
+            {
+                // Copying the logits waits for the device to finish.
+                logits.to_device(&Device::Cpu)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn tps(toks: usize, time: Duration) -> f64 {
+    if time.is_zero() {
+        0.
+    } else {
+        toks as f64 / time.as_secs_f64()
+    }
+}
+
+/// Memory used on the device in bytes.
+fn memory_used(device: &Device) -> Result<usize> {
+    Ok(MemoryUsage
+        .get_total_memory(device)?
+        .saturating_sub(MemoryUsage.get_memory_available(device)?))
+}
@@ -1,6 +1,6 @@
 use std::sync::{Arc, Mutex, MutexGuard};
 
-use candle_core::{Result, Tensor, D};
+use candle_core::{Result, Storage, Tensor, D};
 
 use crate::{get_mut_arcmutex, sequence::Sequence};
 
@@ -407,6 +407,125 @@ impl KvCache {
     pub fn is_rotating(&self) -> bool {
         matches!(self, Self::Rotating { .. })
     }
+
+    fn map_data(&self, f: impl Fn(&Tensor) -> Result<Tensor>) -> Result<Self> {
+        let mut new = self.clone();
+        match &mut new {
+            Self::Normal { k, v } => {
+                k.all_data = k.all_data.as_ref().map(&f).transpose()?;
+                v.all_data = v.all_data.as_ref().map(&f).transpose()?;
+            }
+            Self::Rotating { k, v } => {
+                k.all_data = k.all_data.as_ref().map(&f).transpose()?;
+                v.all_data = v.all_data.as_ref().map(&f).transpose()?;
+            }
+        }
+        Ok(new)
+    }
+
+    fn all_data(&self) -> (&Option<Tensor>, &Option<Tensor>) {
+        match self {
+            Self::Normal { k, v } => (k.all_data(), v.all_data()),
+            Self::Rotating { k, v } => (k.all_data(), v.all_data()),
+        }
+    }
+
+    /// Copy the cache into new storage, so appending to either cache does not modify the other.
+    pub fn deep_copy(&self) -> Result<Self> {
+        self.map_data(Tensor::copy)
+    }
+
+    /// Split the cache along the batch dimension into caches of `sizes` sequences each. The
+    /// parts are views into the storage of this cache, so appending to them writes to this cache
+    /// in place until they need to grow.
+    pub fn split_batch(&self, sizes: &[usize]) -> Result<Vec<Self>> {
+        let mut start = 0;
+        sizes
+            .iter()
+            .map(|size| {
+                let part = self.map_data(|t| t.narrow(0, start, *size))?;
+                start += size;
+                Ok(part)
+            })
+            .collect()
+    }
+
+    /// Merge caches split with [`Self::split_batch`] back into this cache. The parts must have
+    /// been appended to identically since they were split. If they still write to the storage of
+    /// this cache only the length is updated, otherwise they are concatenated.
+    pub fn merge_batch(&mut self, parts: &[Self]) -> Result<()> {
+        let Some(first) = parts.first() else {
+            candle_core::bail!("Expected at least one cache to merge.");
+        };
+        let (k, v) = self.all_data();
+        let (k, v) = (k.clone(), v.clone());
+        let in_place = parts.iter().all(|part| {
+            let (part_k, part_v) = part.all_data();
+            shares_storage(part_k, &k) && shares_storage(part_v, &v)
+        });
+        if !in_place {
+            *self = Self::cat_batch(parts)?;
+            return Ok(());
+        }
+        *self = first.clone();
+        match self {
+            Self::Normal { k: kc, v: vc } => {
+                kc.all_data = k;
+                vc.all_data = v;
+            }
+            Self::Rotating { k: kc, v: vc } => {
+                kc.all_data = k;
+                vc.all_data = v;
+            }
+        }
+        Ok(())
+    }
+
+    /// Concatenate caches along the batch dimension.
+    fn cat_batch(caches: &[Self]) -> Result<Self> {
+        let Some(first) = caches.first() else {
+            candle_core::bail!("Expected at least one cache to concatenate.");
+        };
+        let num_empty = caches.iter().filter(|c| c.all_data().0.is_none()).count();
+        if num_empty == caches.len() {
+            return Ok(first.clone());
+        } else if num_empty > 0 {
+            candle_core::bail!("Cannot concatenate empty and non-empty caches.");
+        }
+        let (ks, vs): (Vec<_>, Vec<_>) = caches
+            .iter()
+            .map(|c| {
+                let (k, v) = c.all_data();
+                (k.clone().unwrap(), v.clone().unwrap())
+            })
+            .unzip();
+        let k = Tensor::cat(&ks, 0)?;
+        let v = Tensor::cat(&vs, 0)?;
+        let mut new = first.clone();
+        match &mut new {
+            Self::Normal { k: kc, v: vc } => {
+                kc.all_data = Some(k);
+                vc.all_data = Some(v);
+            }
+            Self::Rotating { k: kc, v: vc } => {
+                kc.all_data = Some(k);
+                vc.all_data = Some(v);
+            }
+        }
+        Ok(new)
+    }
+}
+
+/// Whether `part` is a view into the storage of `whole`.
+fn shares_storage(part: &Option<Tensor>, whole: &Option<Tensor>) -> bool {
+    match (part, whole) {
+        (Some(part), Some(whole)) => {
+            let part = &*part.storage_and_layout().0 as *const Storage;
+            let whole = &*whole.storage_and_layout().0 as *const Storage;
+            std::ptr::eq(part, whole)
+        }
+        _ => false,
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -1106,3 +1225,82 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for FullCach
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::KvCache;
+
+    fn kv(start: f32, batch: usize) -> Tensor {
+        Tensor::arange(start, start + (batch * 8) as f32, &Device::Cpu)
+            .unwrap()
+            .reshape((batch, 2, 2, 2))
+            .unwrap()
+    }
+
+    /// Append `(k, v)` to the parts of a cache split in batches of 2 and 1 sequences.
+    fn append_split(parts: &mut [KvCache], k: &Tensor, v: &Tensor) {
+        parts[0]
+            .append(&k.narrow(0, 0, 2).unwrap(), &v.narrow(0, 0, 2).unwrap())
+            .unwrap();
+        parts[1]
+            .append(&k.narrow(0, 2, 1).unwrap(), &v.narrow(0, 2, 1).unwrap())
+            .unwrap();
+    }
+
+    fn assert_same_data(a: &KvCache, b: &KvCache) {
+        assert_eq!(a.current_seq_len(), b.current_seq_len());
+        for (a, b) in [
+            (a.k().unwrap().unwrap(), b.k().unwrap().unwrap()),
+            (a.v().unwrap().unwrap(), b.v().unwrap().unwrap()),
+        ] {
+            assert_eq!(
+                a.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+                b.flatten_all().unwrap().to_vec1::<f32>().unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn split_and_merge_batch_in_place() {
+        let mut expected = KvCache::new_normal(2, 16, 4);
+        expected.append(&kv(0., 3), &kv(100., 3)).unwrap();
+        let mut whole = expected.deep_copy().unwrap();
+        let storage = whole.k().unwrap().unwrap();
+
+        let mut parts = whole.split_batch(&[2, 1]).unwrap();
+        assert_eq!(parts[0].k().unwrap().unwrap().dims(), &[2, 2, 2, 2]);
+        assert_eq!(parts[1].k().unwrap().unwrap().dims(), &[1, 2, 2, 2]);
+
+        // Appending to the parts and merging them is the same as appending to the whole.
+        let (k, v) = (kv(200., 3), kv(300., 3));
+        expected.append(&k, &v).unwrap();
+        append_split(&mut parts, &k, &v);
+        whole.merge_batch(&parts).unwrap();
+        assert_same_data(&whole, &expected);
+        // The parts wrote to the storage of the whole cache instead of being concatenated.
+        assert!(super::shares_storage(
+            &Some(whole.k().unwrap().unwrap()),
+            &Some(storage)
+        ));
+    }
+
+    #[test]
+    fn split_and_merge_batch_after_growing() {
+        let mut expected = KvCache::new_normal(2, 1024, 4);
+        expected.append(&kv(0., 3), &kv(100., 3)).unwrap();
+        let mut whole = expected.deep_copy().unwrap();
+        let mut parts = whole.split_batch(&[2, 1]).unwrap();
+
+        // Three more tokens do not fit in the capacity of 4, so the parts grow.
+        let (k, v) = (
+            kv(200., 3).repeat((1, 1, 3, 1)).unwrap(),
+            kv(300., 3).repeat((1, 1, 3, 1)).unwrap(),
+        );
+        expected.append(&k, &v).unwrap();
+        append_split(&mut parts, &k, &v);
+        whole.merge_batch(&parts).unwrap();
+        assert_same_data(&whole, &expected);
+    }
+}
@@ -9,7 +9,7 @@ use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
 use tracing::info;
 
-use crate::{MessageContent, Tool};
+use crate::{MessageContent, SamplingDefaults, Tool};
 
 const SUPPORTED_ALTERNATE_EOS: &[&str] = &[
     "<|im_end|>",      // Handle ChatML case
@@ -17,6 +17,11 @@ const SUPPORTED_ALTERNATE_EOS: &[&str] = &[
     "<|end_of_text|>", // Hermes
 ];
 
+/// Tokens which ChatGLM tokenizers put at the start of every prompt.
+const CHATGLM_PREFIX_TOKENS: &[&str] = &["[gMASK]", "<sop>", "[BOS]"];
+/// ChatGLM ends its turn with the role token of the next turn.
+const CHATGLM_ALTERNATE_EOS: &[&str] = &["<|user|>", "<|observation|>", "<|endoftext|>"];
+
 #[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 pub struct AddedTokensDecoder {
@@ -43,6 +48,71 @@ pub struct ChatTemplateValue(
     #[serde(with = "either::serde_untagged")] pub Either<String, Vec<HashMap<String, String>>>,
 );
 
+/// How a chat template handles messages with the system role, detected at load by rendering it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SystemRoleSupport {
+    /// The system message is rendered, or support was not detected.
+    #[default]
+    Supported,
+    /// The template renders, but drops the system message.
+    Ignored,
+    /// The template raises an error on the system role, as the Gemma templates do.
+    Rejected,
+}
+
+/// Separates the folded system message from the content of the user message.
+const SYSTEM_MESSAGE_SEPARATOR: &str = "\n\n";
+
+/// A likely mistake in the messages of a conversation, found by
+/// [`ChatTemplate::validate_messages`]. Such messages can still be rendered, but many templates
+/// produce garbled prompts or raise an error for them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChatWarning {
+    /// The conversation starts with a message which is neither a system nor a user message.
+    UnexpectedFirstRole { role: String },
+    /// A system message after the start of the conversation.
+    MisplacedSystem { index: usize },
+    /// The message has the same role as the previous one, or is a tool message which does not
+    /// follow an assistant message, instead of alternating between user and assistant.
+    NotAlternating { index: usize, role: String },
+    /// The message has no content, or only whitespace, and no tool calls.
+    EmptyContent { index: usize },
+    /// The message alone has more tokens than the context of the model.
+    TooLong {
+        index: usize,
+        tokens: usize,
+        max_seq_len: usize,
+    },
+}
+
+impl std::fmt::Display for ChatWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnexpectedFirstRole { role } => write!(
+                f,
+                "The conversation starts with a `{role}` message instead of a system or user message."
+            ),
+            Self::MisplacedSystem { index } => write!(
+                f,
+                "Message {index} is a system message which is not at the start of the conversation."
+            ),
+            Self::NotAlternating { index, role } => write!(
+                f,
+                "Message {index} has the role `{role}`, but roles should alternate between user and assistant."
+            ),
+            Self::EmptyContent { index } => write!(f, "Message {index} has no content."),
+            Self::TooLong {
+                index,
+                tokens,
+                max_seq_len,
+            } => write!(
+                f,
+                "Message {index} has {tokens} tokens, more than the maximum sequence length of {max_seq_len}."
+            ),
+        }
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Deserialize, Default)]
 /// Template for chat models including bos/eos/unk as well as the chat template.
@@ -69,6 +139,9 @@ pub struct ChatTemplate {
     truncation_size: Option<String>,
     pub unk_token: Option<BeginEndUnkPadTok>,
     use_default_system_prompt: Option<bool>,
+
+    #[serde(skip)]
+    pub system_role: SystemRoleSupport,
 }
 
 impl ChatTemplate {
@@ -76,6 +149,17 @@ impl ChatTemplate {
         self.chat_template.is_some()
     }
 
+    /// Whether the chat template renders a `documents` variable itself, such as Command R.
+    pub fn has_documents_slot(&self) -> bool {
+        match &self.chat_template {
+            Some(ChatTemplateValue(Either::Left(template))) => template.contains("documents"),
+            Some(ChatTemplateValue(Either::Right(templates))) => templates
+                .iter()
+                .any(|t| t.values().any(|template| template.contains("documents"))),
+            None => false,
+        }
+    }
+
     pub fn eos_tok(&self) -> Option<String> {
         match self.eos_token.as_ref()?.0 {
             Either::Left(ref lit) => Some(lit.clone()),
@@ -96,6 +180,203 @@ impl ChatTemplate {
             Either::Right(ref added) => Some(added.content.clone()),
         }
     }
+
+    /// Detect whether the chat template supports the system role by rendering a system and a user
+    /// message, compared to a user message alone.
+    pub fn with_detected_system_role(mut self) -> Self {
+        let Some(template) = &self.chat_template else {
+            return self;
+        };
+        const PROBE: &str = "mistral.rs system role probe";
+        let message = |role: &str, content: &str| -> IndexMap<String, MessageContent> {
+            IndexMap::from([
+                ("role".to_string(), Either::Left(role.to_string())),
+                ("content".to_string(), Either::Left(content.to_string())),
+            ])
+        };
+        let render = |messages| {
+            apply_chat_template_to(
+                messages,
+                true,
+                template,
+                self.bos_tok(),
+                self.eos_tok(),
+                self.unk_tok(),
+                Vec::new(),
+                None,
+                Vec::new(),
+            )
+        };
+        // Templates which cannot render a plain text user message, such as some vision templates,
+        // are left as they are.
+        if !render(vec![message("user", "Hello")]).is_ok_and(|prompt| prompt.contains("Hello")) {
+            return self;
+        }
+        self.system_role = match render(vec![message("system", PROBE), message("user", "Hello")]) {
+            Ok(prompt) if prompt.contains(PROBE) => SystemRoleSupport::Supported,
+            Ok(_) => SystemRoleSupport::Ignored,
+            Err(_) => SystemRoleSupport::Rejected,
+        };
+        if self.system_role != SystemRoleSupport::Supported {
+            info!("The chat template does not support the system role, system messages will be folded into the next user message.");
+        }
+        self
+    }
+
+    /// Adapt the messages to the system role support of the template. If the system role is not
+    /// supported, the content of each system message is folded into the next user message, or
+    /// rejected with an error if `strict`.
+    pub fn adapt_system_messages(
+        &self,
+        messages: Vec<IndexMap<String, MessageContent>>,
+        strict: bool,
+    ) -> Result<Vec<IndexMap<String, MessageContent>>> {
+        let is_system = |message: &IndexMap<String, MessageContent>| match message.get("role") {
+            Some(Either::Left(role)) => role == "system",
+            _ => false,
+        };
+        if self.system_role == SystemRoleSupport::Supported || !messages.iter().any(is_system) {
+            return Ok(messages);
+        }
+        if strict {
+            anyhow::bail!("The chat template of this model does not support the system role. Remove the system message, or allow it to be folded into the next user message.");
+        }
+
+        let mut adapted = Vec::with_capacity(messages.len());
+        let mut pending: Vec<String> = Vec::new();
+        for mut message in messages {
+            if is_system(&message) {
+                pending.push(message_text(message.get("content")));
+                continue;
+            }
+            let is_user = matches!(message.get("role"), Some(Either::Left(role)) if role == "user");
+            if is_user && !pending.is_empty() {
+                let system = pending.join(SYSTEM_MESSAGE_SEPARATOR);
+                pending.clear();
+                let content = match message.swap_remove("content") {
+                    Some(Either::Left(text)) => {
+                        Either::Left(format!("{system}{SYSTEM_MESSAGE_SEPARATOR}{text}"))
+                    }
+                    Some(Either::Right(mut parts)) => {
+                        let text_part = parts
+                            .iter_mut()
+                            .find_map(|part| part.get_mut("text").filter(|text| text.is_string()));
+                        match text_part {
+                            Some(text) => {
+                                let folded = format!(
+                                    "{system}{SYSTEM_MESSAGE_SEPARATOR}{}",
+                                    text.as_str().unwrap_or_default()
+                                );
+                                *text = serde_json::Value::from(folded);
+                            }
+                            None => parts.insert(
+                                0,
+                                IndexMap::from([
+                                    ("type".to_string(), serde_json::Value::from("text")),
+                                    ("text".to_string(), serde_json::Value::from(system)),
+                                ]),
+                            ),
+                        }
+                        Either::Right(parts)
+                    }
+                    None => Either::Left(system),
+                };
+                message.insert("content".to_string(), content);
+            }
+            adapted.push(message);
+        }
+        // System messages without a following user message become a user message.
+        if !pending.is_empty() {
+            adapted.push(IndexMap::from([
+                ("role".to_string(), Either::Left("user".to_string())),
+                (
+                    "content".to_string(),
+                    Either::Left(pending.join(SYSTEM_MESSAGE_SEPARATOR)),
+                ),
+            ]));
+        }
+        Ok(adapted)
+    }
+
+    /// Check that the messages follow the format expected by chat templates before rendering
+    /// them: the conversation starts with system messages or a user message, then alternates
+    /// between user and assistant messages, with tool messages following assistant messages, and
+    /// no message is empty. With a tokenizer, messages longer than `max_seq_len` tokens are also
+    /// reported.
+    ///
+    /// The issues are returned as warnings, as some templates accept them. Messages without a
+    /// text role are an error.
+    pub fn validate_messages(
+        &self,
+        messages: &[IndexMap<String, MessageContent>],
+        tokenizer: Option<&Tokenizer>,
+        max_seq_len: usize,
+    ) -> Result<Vec<ChatWarning>> {
+        let mut warnings = Vec::new();
+        // The role of the previous message after the leading system messages.
+        let mut previous: Option<&str> = None;
+        for (index, message) in messages.iter().enumerate() {
+            let role = match message.get("role") {
+                Some(Either::Left(role)) => role.as_str(),
+                Some(Either::Right(_)) => anyhow::bail!("Message {index} has a non-text role."),
+                None => anyhow::bail!("Message {index} has no role."),
+            };
+            match (role, previous) {
+                ("system", None) => (),
+                ("system", Some(_)) => warnings.push(ChatWarning::MisplacedSystem { index }),
+                ("user", None) => (),
+                (role, None) => warnings.push(ChatWarning::UnexpectedFirstRole {
+                    role: role.to_string(),
+                }),
+                ("user", Some("user"))
+                | ("assistant", Some("assistant"))
+                | ("tool", Some("user" | "system")) => warnings.push(ChatWarning::NotAlternating {
+                    index,
+                    role: role.to_string(),
+                }),
+                _ => (),
+            }
+            if role != "system" {
+                previous = Some(role);
+            }
+
+            let text = message_text(message.get("content"));
+            // Images and other non-text parts are content.
+            let has_media = match message.get("content") {
+                Some(Either::Right(parts)) => parts.iter().any(|part| !part.contains_key("text")),
+                _ => false,
+            };
+            if text.trim().is_empty() && !has_media && !message.contains_key("tool_calls") {
+                warnings.push(ChatWarning::EmptyContent { index });
+            }
+            if let Some(tokenizer) = tokenizer {
+                let tokens = tokenizer
+                    .encode_fast(text, false)
+                    .map_err(anyhow::Error::msg)?
+                    .len();
+                if tokens > max_seq_len {
+                    warnings.push(ChatWarning::TooLong {
+                        index,
+                        tokens,
+                        max_seq_len,
+                    });
+                }
+            }
+        }
+        Ok(warnings)
+    }
+}
+
+/// The text of a message's content, joining the text parts of multimodal content.
+fn message_text(content: Option<&MessageContent>) -> String {
+    match content {
+        Some(Either::Left(text)) => text.clone(),
+        Some(Either::Right(parts)) => parts
+            .iter()
+            .filter_map(|part| part.get("text").and_then(|text| text.as_str()))
+            .join("\n"),
+        None => String::new(),
+    }
 }
 
 pub fn calculate_eos_tokens(
@@ -140,6 +421,17 @@ pub fn calculate_eos_tokens(
         }
     }
 
+    let vocab = tokenizer.get_vocab(true);
+    if vocab.contains_key("[gMASK]") && vocab.contains_key("<sop>") {
+        // ChatGLM: the prefix tokens start a prompt and must never stop the generation.
+        eos_tok_ids.retain(|tok| !CHATGLM_PREFIX_TOKENS.contains(&tok.as_str()));
+        for alternate in CHATGLM_ALTERNATE_EOS {
+            if vocab.contains_key(*alternate) && !eos_tok_ids.iter().any(|tok| tok == alternate) {
+                eos_tok_ids.push(alternate.to_string())
+            }
+        }
+    }
+
     eos_tok_ids = eos_tok_ids.into_iter().dedup().collect::<Vec<_>>();
     bos_tok_ids = bos_tok_ids.into_iter().dedup().collect::<Vec<_>>();
 
@@ -179,6 +471,22 @@ pub struct GenerationConfig {
     bos_token_id: Either<u32, Vec<u32>>,
     #[serde(with = "either::serde_untagged")]
     eos_token_id: Either<u32, Vec<u32>>,
+    #[serde(default)]
+    do_sample: Option<bool>,
+    #[serde(flatten)]
+    sampling: SamplingDefaults,
+}
+
+impl GenerationConfig {
+    /// The sampling defaults of the model. `do_sample: false` means greedy decoding, which is a
+    /// temperature of 0.
+    pub fn sampling_defaults(&self) -> SamplingDefaults {
+        let mut defaults = self.sampling.clone();
+        if self.do_sample == Some(false) {
+            defaults.temperature = Some(0.0);
+        }
+        defaults
+    }
 }
 
 fn tojson(value: Value, kwargs: Kwargs) -> Result<Value, Error> {
@@ -229,6 +537,8 @@ pub fn apply_chat_template_to(
     eos_tok: Option<String>,
     unk_tok: Option<String>,
     tools: Vec<Tool>,
+    enable_thinking: Option<bool>,
+    documents: Vec<IndexMap<String, String>>,
 ) -> Result<String> {
     let mut env = Environment::new();
 
@@ -300,17 +610,17 @@ pub fn apply_chat_template_to(
     let date = chrono::Utc::now();
     let date_string = date.format("%d, %B, %Y").to_string();
 
-    if tools.is_empty() {
-        Ok(tmpl.render(context! {
+    let ctx = if tools.is_empty() {
+        context! {
             messages => new_messages,
             add_generation_prompt => add_generation_prompt,
             bos_token => bos_tok,
             eos_token => eos_tok,
             unk_token => unk_tok,
             date_string => date_string,
-        })?)
+        }
     } else {
-        Ok(tmpl.render(context! {
+        context! {
             messages => new_messages,
             add_generation_prompt => add_generation_prompt,
             bos_token => bos_tok,
@@ -318,6 +628,342 @@ pub fn apply_chat_template_to(
             unk_token => unk_tok,
             tools => tools,
             date_string => date_string,
-        })?)
+        }
+    };
+    // Leave `enable_thinking` undefined when unset so that the template applies its own default.
+    let ctx = match enable_thinking {
+        Some(enable_thinking) => context! { enable_thinking => enable_thinking, ..ctx },
+        None => ctx,
+    };
+    // Like tools, `documents` is only defined if there are documents.
+    let ctx = if documents.is_empty() {
+        ctx
+    } else {
+        context! { documents => documents, ..ctx }
+    };
+    Ok(tmpl.render(ctx)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use either::Either;
+    use serde_json::json;
+    use tokenizers::Tokenizer;
+
+    use indexmap::IndexMap;
+
+    use super::{
+        apply_chat_template_to, calculate_eos_tokens, BeginEndUnkPadTok, ChatTemplate,
+        ChatTemplateValue, ChatWarning, GenerationConfig, SystemRoleSupport,
+    };
+    use crate::{MessageContent, SamplingDefaults};
+
+    #[test]
+    fn chatglm_eos_tokens() {
+        let vocab = [
+            "[gMASK]",
+            "<sop>",
+            "<|endoftext|>",
+            "<|user|>",
+            "<|observation|>",
+            "a",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(id, tok)| (tok.to_string(), json!(id)))
+        .collect::<serde_json::Map<_, _>>();
+        let raw = json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [],
+            "normalizer": null,
+            "pre_tokenizer": null,
+            "post_processor": null,
+            "decoder": null,
+            "model": { "type": "WordLevel", "vocab": vocab, "unk_token": "a" }
+        });
+        let tokenizer = Tokenizer::from_str(&raw.to_string()).unwrap();
+
+        // GGUF conversions may report the prefix token as EOS.
+        let chat_template = ChatTemplate {
+            eos_token: Some(BeginEndUnkPadTok(Either::Left("[gMASK]".to_string()))),
+            ..Default::default()
+        };
+        let eos = calculate_eos_tokens(&chat_template, None, &tokenizer);
+        assert_eq!(eos, [3, 4, 2]);
+    }
+
+    const GEMMA: &str = "{{ bos_token }}{% if messages[0]['role'] == 'system' %}{{ raise_exception('System role not supported') }}{% endif %}{% for message in messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if (message['role'] == 'assistant') %}{% set role = 'model' %}{% else %}{% set role = message['role'] %}{% endif %}{{ '<start_of_turn>' + role + '\n' + message['content'] | trim + '<end_of_turn>\n' }}{% endfor %}{% if add_generation_prompt %}{{'<start_of_turn>model\n'}}{% endif %}";
+    const MISTRAL_V1: &str = "{{ bos_token }}{% for message in messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if message['role'] == 'user' %}{{ '[INST] ' + message['content'] + ' [/INST]' }}{% elif message['role'] == 'assistant' %}{{ message['content'] + eos_token + ' ' }}{% else %}{{ raise_exception('Only user and assistant roles are supported!') }}{% endif %}{% endfor %}";
+    const CHATML: &str = "{% for message in messages %}{{'<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>' + '\n'}}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}";
+
+    fn chat_template(template: &str, bos: &str, eos: &str) -> ChatTemplate {
+        ChatTemplate {
+            chat_template: Some(ChatTemplateValue(Either::Left(template.to_string()))),
+            bos_token: Some(BeginEndUnkPadTok(Either::Left(bos.to_string()))),
+            eos_token: Some(BeginEndUnkPadTok(Either::Left(eos.to_string()))),
+            ..Default::default()
+        }
+        .with_detected_system_role()
+    }
+
+    fn messages(roles_and_contents: &[(&str, &str)]) -> Vec<IndexMap<String, MessageContent>> {
+        roles_and_contents
+            .iter()
+            .map(|(role, content)| {
+                IndexMap::from([
+                    ("role".to_string(), Either::Left(role.to_string())),
+                    ("content".to_string(), Either::Left(content.to_string())),
+                ])
+            })
+            .collect()
+    }
+
+    fn render(
+        template: &ChatTemplate,
+        messages: Vec<IndexMap<String, MessageContent>>,
+        strict: bool,
+    ) -> anyhow::Result<String> {
+        apply_chat_template_to(
+            template.adapt_system_messages(messages, strict)?,
+            true,
+            template.chat_template.as_ref().unwrap(),
+            template.bos_tok(),
+            template.eos_tok(),
+            template.unk_tok(),
+            Vec::new(),
+            None,
+            Vec::new(),
+        )
+    }
+
+    #[test]
+    fn system_role_support() {
+        let with_system = messages(&[
+            ("system", "Be brief."),
+            ("user", "Hello"),
+            ("assistant", "Hi"),
+            ("user", "Who are you?"),
+        ]);
+        let without_system = messages(&[
+            ("user", "Hello"),
+            ("assistant", "Hi"),
+            ("user", "Who are you?"),
+        ]);
+
+        let gemma = chat_template(GEMMA, "<bos>", "<eos>");
+        assert_eq!(gemma.system_role, SystemRoleSupport::Rejected);
+        assert_eq!(
+            render(&gemma, with_system.clone(), false).unwrap(),
+            "<bos><start_of_turn>user\nBe brief.\n\nHello<end_of_turn>\n<start_of_turn>model\nHi<end_of_turn>\n<start_of_turn>user\nWho are you?<end_of_turn>\n<start_of_turn>model\n"
+        );
+        assert_eq!(
+            render(&gemma, without_system.clone(), false).unwrap(),
+            "<bos><start_of_turn>user\nHello<end_of_turn>\n<start_of_turn>model\nHi<end_of_turn>\n<start_of_turn>user\nWho are you?<end_of_turn>\n<start_of_turn>model\n"
+        );
+        let err = render(&gemma, with_system.clone(), true).unwrap_err();
+        assert!(
+            err.to_string().contains("does not support the system role"),
+            "{err}"
+        );
+        // Strict requests without a system message are unaffected.
+        assert!(render(&gemma, without_system.clone(), true).is_ok());
+
+        let mistral = chat_template(MISTRAL_V1, "<s>", "</s>");
+        assert_eq!(mistral.system_role, SystemRoleSupport::Rejected);
+        assert_eq!(
+            render(&mistral, with_system.clone(), false).unwrap(),
+            "<s>[INST] Be brief.\n\nHello [/INST]Hi</s> [INST] Who are you? [/INST]"
+        );
+        assert_eq!(
+            render(&mistral, without_system.clone(), false).unwrap(),
+            "<s>[INST] Hello [/INST]Hi</s> [INST] Who are you? [/INST]"
+        );
+        assert!(render(&mistral, with_system.clone(), true).is_err());
+
+        // Templates supporting the system role render it as is, even when strict.
+        let chatml = chat_template(CHATML, "<s>", "</s>");
+        assert_eq!(chatml.system_role, SystemRoleSupport::Supported);
+        let expected = "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHello<|im_end|>\n<|im_start|>assistant\nHi<|im_end|>\n<|im_start|>user\nWho are you?<|im_end|>\n<|im_start|>assistant\n";
+        assert_eq!(
+            render(&chatml, with_system.clone(), false).unwrap(),
+            expected
+        );
+        assert_eq!(render(&chatml, with_system, true).unwrap(), expected);
+        assert_eq!(
+            render(&chatml, without_system, false).unwrap(),
+            "<|im_start|>user\nHello<|im_end|>\n<|im_start|>assistant\nHi<|im_end|>\n<|im_start|>user\nWho are you?<|im_end|>\n<|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn fold_system_messages() {
+        // A template which silently drops system messages.
+        let template = chat_template(
+            "{% for message in messages %}{% if message['role'] != 'system' %}{{ message['role'] + ': ' + message['content'] + '\n' }}{% endif %}{% endfor %}",
+            "<s>",
+            "</s>",
+        );
+        assert_eq!(template.system_role, SystemRoleSupport::Ignored);
+
+        // Multimodal content gets the system message prepended to its first text part.
+        let mut input = messages(&[("system", "Be brief.")]);
+        input.push(IndexMap::from([
+            ("role".to_string(), Either::Left("user".to_string())),
+            (
+                "content".to_string(),
+                Either::Right(vec![
+                    IndexMap::from([("type".to_string(), json!("image"))]),
+                    IndexMap::from([
+                        ("type".to_string(), json!("text")),
+                        ("text".to_string(), json!("What is this?")),
+                    ]),
+                ]),
+            ),
+        ]));
+        let folded = template.adapt_system_messages(input, false).unwrap();
+        assert_eq!(folded.len(), 1);
+        let Either::Right(parts) = &folded[0]["content"] else {
+            panic!("Expected multimodal content");
+        };
+        assert_eq!(parts[1]["text"], json!("Be brief.\n\nWhat is this?"));
+
+        // A trailing system message becomes a user message.
+        let folded = template
+            .adapt_system_messages(
+                messages(&[("user", "Hello"), ("system", "Be brief.")]),
+                false,
+            )
+            .unwrap();
+        assert_eq!(folded.len(), 2);
+        assert_eq!(folded[1]["role"], Either::Left("user".to_string()));
+        assert_eq!(folded[1]["content"], Either::Left("Be brief.".to_string()));
+    }
+
+    #[test]
+    fn generation_config_sampling_defaults() {
+        let conf: GenerationConfig = serde_json::from_value(json!({
+            "bos_token_id": 1,
+            "eos_token_id": [2, 3],
+            "do_sample": true,
+            "temperature": 0.6,
+            "top_p": 0.9,
+            "transformers_version": "4.45.0"
+        }))
+        .unwrap();
+        assert_eq!(
+            conf.sampling_defaults(),
+            SamplingDefaults {
+                temperature: Some(0.6),
+                top_p: Some(0.9),
+                ..Default::default()
+            }
+        );
+
+        let conf: GenerationConfig = serde_json::from_value(json!({
+            "bos_token_id": 1,
+            "eos_token_id": 2,
+            "do_sample": false,
+            "temperature": 0.6
+        }))
+        .unwrap();
+        assert_eq!(conf.sampling_defaults().temperature, Some(0.0));
+    }
+
+    #[test]
+    fn validate_messages() {
+        let template = chat_template(CHATML, "<s>", "</s>");
+        let validate = |roles_and_contents: &[(&str, &str)]| {
+            template
+                .validate_messages(&messages(roles_and_contents), None, usize::MAX)
+                .unwrap()
+        };
+
+        assert!(validate(&[
+            ("system", "Be brief."),
+            ("user", "Hello"),
+            ("assistant", "Hi"),
+            ("user", "Who are you?"),
+        ])
+        .is_empty());
+        // Tool results follow the assistant message calling the tool.
+        assert!(validate(&[
+            ("user", "What time is it?"),
+            ("assistant", "{\"name\":\"time\",\"arguments\":{}}"),
+            ("tool", "12:00"),
+            ("assistant", "It is noon."),
+        ])
+        .is_empty());
+
+        assert_eq!(
+            validate(&[("assistant", "Hi"), ("user", "Hello")]),
+            [ChatWarning::UnexpectedFirstRole {
+                role: "assistant".to_string()
+            }]
+        );
+        assert_eq!(
+            validate(&[
+                ("user", "Hello"),
+                ("user", "Are you there?"),
+                ("system", "Be brief."),
+                ("tool", "12:00"),
+            ]),
+            [
+                ChatWarning::NotAlternating {
+                    index: 1,
+                    role: "user".to_string()
+                },
+                ChatWarning::MisplacedSystem { index: 2 },
+                ChatWarning::NotAlternating {
+                    index: 3,
+                    role: "tool".to_string()
+                },
+            ]
+        );
+        assert_eq!(
+            validate(&[("user", " \n"), ("assistant", "")]),
+            [
+                ChatWarning::EmptyContent { index: 0 },
+                ChatWarning::EmptyContent { index: 1 }
+            ]
+        );
+
+        let mut no_role = messages(&[("user", "Hello")]);
+        no_role[0].swap_remove("role");
+        assert!(template
+            .validate_messages(&no_role, None, usize::MAX)
+            .is_err());
+
+        // Message lengths are checked with a tokenizer.
+        let raw = json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [],
+            "normalizer": null,
+            "pre_tokenizer": { "type": "Whitespace" },
+            "post_processor": null,
+            "decoder": null,
+            "model": { "type": "WordLevel", "vocab": { "a": 0 }, "unk_token": "a" }
+        });
+        let tokenizer = Tokenizer::from_str(&raw.to_string()).unwrap();
+        let warnings = template
+            .validate_messages(
+                &messages(&[("user", "one two three"), ("assistant", "four")]),
+                Some(&tokenizer),
+                2,
+            )
+            .unwrap();
+        assert_eq!(
+            warnings,
+            [ChatWarning::TooLong {
+                index: 0,
+                tokens: 3,
+                max_seq_len: 2
+            }]
+        );
     }
 }
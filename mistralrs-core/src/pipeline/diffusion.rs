@@ -1,9 +1,9 @@
 use super::loaders::{DiffusionModelPaths, DiffusionModelPathsInner};
 use super::{
     AnyMoePipelineMixin, Cache, CacheManagerMixin, DiffusionLoaderType, DiffusionModel,
-    DiffusionModelLoader, EitherCache, FluxLoader, ForwardInputsResult, GeneralMetadata,
+    DiffusionModelLoader, EitherCache, FluxLoader, ForwardInputsResult, GeneralMetadata, HubRepo,
     IsqPipelineMixin, Loader, MetadataMixin, ModelCategory, ModelKind, ModelPaths,
-    PreProcessingMixin, Processor, TokenSource,
+    PreProcessingMixin, Processor, ThroughputTracker, TokenSource,
 };
 use crate::device_map::DeviceMapper;
 use crate::diffusion_models::processor::{DiffusionProcessor, ModelInputs};
@@ -13,10 +13,10 @@ use crate::prefix_cacher::PrefixCacheManagerV2;
 use crate::sequence::Sequence;
 use crate::utils::varbuilder_utils::DeviceForLoadTensor;
 use crate::utils::{tokens::get_token, varbuilder_utils::from_mmaped_safetensors};
-use crate::{DeviceMapSetting, PagedAttentionConfig, Pipeline, TryIntoDType};
+use crate::{DeviceMapSetting, PagedAttentionConfig, Pipeline, SamplingDefaults, TryIntoDType};
 use anyhow::Result;
 use candle_core::{DType, Device, Tensor};
-use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
+use hf_hub::api::sync::ApiBuilder;
 use image::{DynamicImage, RgbImage};
 use indicatif::MultiProgress;
 use mistralrs_quant::IsqType;
@@ -99,11 +99,7 @@ impl Loader for DiffusionLoader {
                 .with_token(get_token(&token_source)?)
                 .build()?;
             let revision = revision.unwrap_or("main".to_string());
-            let api = api.repo(Repo::with_revision(
-                self.model_id.clone(),
-                RepoType::Model,
-                revision.clone(),
-            ));
+            let api = HubRepo::new(&api, self.model_id.clone(), revision, &token_source);
             let model_id = std::path::Path::new(&self.model_id);
             let filenames = self.inner.get_model_paths(&api, model_id)?;
             let config_filenames = self.inner.get_config_filenames(&api, model_id)?;
@@ -228,6 +224,13 @@ impl Loader for DiffusionLoader {
                 cache_engine: None,
                 prompt_chunksize: None,
                 model_metadata: None,
+                throughput: Arc::new(ThroughputTracker::default()),
+                prompt_format: None,
+                // Diffusion models are loaded from several directories of weights.
+                system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
+                generation_defaults: SamplingDefaults::default(),
+                lora_adapters: Vec::new(),
+                commit_hash: None,
             }),
             dummy_cache: EitherCache::Full(Cache::new(0, false)),
         })))
@@ -1,14 +1,16 @@
 use super::cache_manager::FullCacheManager;
-use super::llg::build_tok_env;
+use super::llg::build_or_reuse_tok_env;
 use super::{
     get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, QuantizationKind, TokenSource,
+    CacheManager, GeneralMetadata, HubRepo, Loader, ModelKind, ModelPaths, QuantizationKind,
+    ThroughputTracker, TokenSource,
 };
 use super::{
     AnyMoePipelineMixin, CacheManagerMixin, EitherCache, ForwardInputsResult, IsqPipelineMixin,
     MetadataMixin, ModelCategory, PreProcessingMixin,
 };
 use crate::device_map::DeviceMapper;
+use crate::fingerprint::model_fingerprint;
 use crate::lora::Ordering;
 use crate::pipeline::chat_template::{calculate_eos_tokens, GenerationConfig};
 use crate::pipeline::get_chat_template;
@@ -18,8 +20,9 @@ use crate::pipeline::{ChatTemplate, LocalModelPaths};
 use crate::prefix_cacher::PrefixCacheManagerV2;
 use crate::sequence::Sequence;
 use crate::utils::debug::DeviceRepr;
+use crate::utils::hub_proxy::HubProxy;
 use crate::utils::model_config as ModelConfig;
-use crate::utils::tokenizer::get_tokenizer;
+use crate::utils::tokenizer::{get_tokenizer, set_byte_fallback};
 use crate::xlora_models::NonGranularState;
 use crate::{
     get_mut_arcmutex, get_paths, DeviceMapSetting, PagedAttentionConfig, Pipeline, Topology,
@@ -32,7 +35,8 @@ use crate::{
 use anyhow::Result;
 use candle_core::quantized::ggml_file;
 use candle_core::{Device, Tensor};
-use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
+use hf_hub::api::sync::ApiBuilder;
+use llguidance::toktrie::TokEnv;
 use mistralrs_quant::IsqType;
 use rand_isaac::Isaac64Rng;
 use std::any::Any;
@@ -75,6 +79,7 @@ pub struct GGMLLoader {
     tgt_non_granular_index: Option<usize>,
     jinja_explicit: Option<String>,
     lora_adapter_ids: Option<Vec<String>>,
+    proxy: Option<HubProxy>,
 }
 
 #[derive(Clone, Default)]
@@ -83,6 +88,11 @@ pub struct GGMLSpecificConfig {
     pub gqa: usize,
     pub prompt_chunksize: Option<NonZeroUsize>,
     pub topology: Option<Topology>,
+    /// Force byte fallback of the tokenizer on or off, overriding the tokenizer's default.
+    pub byte_fallback: Option<bool>,
+    /// Token environment used instead of building one from the tokenizer, such as one shared by
+    /// the models of a vocabulary. Its tokens must be those of the tokenizer.
+    pub tok_env: Option<TokEnv>,
 }
 
 #[derive(Default)]
@@ -100,6 +110,7 @@ pub struct GGMLLoaderBuilder {
     tokenizer_json: Option<String>,
     tgt_non_granular_index: Option<usize>,
     jinja_explicit: Option<String>,
+    proxy: Option<HubProxy>,
 }
 
 impl GGMLLoaderBuilder {
@@ -178,6 +189,13 @@ impl GGMLLoaderBuilder {
         self.with_adapter(lora_model_id, lora_order, false, None)
     }
 
+    /// Download the model from the Hugging Face Hub through an HTTP proxy, optionally
+    /// authenticating with a user and password, for networks without direct internet access.
+    pub fn with_proxy(mut self, proxy_url: &str, proxy_auth: Option<(&str, &str)>) -> Self {
+        self.proxy = Some(HubProxy::new(proxy_url, proxy_auth));
+        self
+    }
+
     pub fn build(self) -> Box<dyn Loader> {
         Box::new(GGMLLoader {
             model_id: self.model_id.unwrap(),
@@ -193,6 +211,7 @@ impl GGMLLoaderBuilder {
             quantized_model_id: Some(self.quantized_model_id),
             jinja_explicit: self.jinja_explicit,
             lora_adapter_ids: None,
+            proxy: self.proxy,
         })
     }
 }
@@ -236,6 +255,7 @@ impl GGMLLoader {
             tgt_non_granular_index,
             jinja_explicit,
             lora_adapter_ids: None,
+            proxy: None,
         }
     }
 }
@@ -342,7 +362,10 @@ impl Loader for GGMLLoader {
             _ => unreachable!(),
         };
 
-        let tokenizer = get_tokenizer(paths.get_tokenizer_filename(), None)?;
+        let tokenizer = set_byte_fallback(
+            get_tokenizer(paths.get_tokenizer_filename(), None)?,
+            self.config.byte_fallback,
+        )?;
         let gen_conf: Option<GenerationConfig> = paths.get_gen_conf_filename().map(|f| {
             serde_json::from_str(&fs::read_to_string(f).unwrap())
                 .expect("bos_token_id/eos_token_id missing in generation_config.json")
@@ -363,17 +386,22 @@ impl Loader for GGMLLoader {
             Model::Llama(ref l) => l.max_seq_len,
             Model::XLoraLlama(ref xl) => xl.max_seq_len,
         };
-        let tok_env = build_tok_env(tokenizer.clone());
+        let tok_env = build_or_reuse_tok_env(tokenizer.clone(), self.config.tok_env.clone())?;
         let num_hidden_layers = match model {
             Model::Llama(ref model) => model.cache.normal().0.len(),
             Model::XLoraLlama(ref model) => model.cache.full().lock().len(),
         };
+        let generation_defaults = gen_conf
+            .as_ref()
+            .map(GenerationConfig::sampling_defaults)
+            .unwrap_or_default();
         let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
+        let system_fingerprint = model_fingerprint(paths.as_ref(), None);
         Ok(Arc::new(Mutex::new(GGMLPipeline {
             model,
             tokenizer: tokenizer.into(),
             no_kv_cache: self.no_kv_cache,
-            chat_template: Arc::new(chat_template),
+            chat_template: Arc::new(chat_template.with_detected_system_role()),
             model_id: self.model_id.clone(),
             non_granular_state: self.tgt_non_granular_index.map(|tgt_non_granular_index| {
                 NonGranularState {
@@ -396,6 +424,12 @@ impl Loader for GGMLLoader {
                 cache_engine: None,
                 prompt_chunksize: Some(NonZero::new(prompt_chunksize).unwrap()),
                 model_metadata: None,
+                throughput: Arc::new(ThroughputTracker::default()),
+                prompt_format: None,
+                system_fingerprint,
+                generation_defaults,
+                lora_adapters: Vec::new(),
+                commit_hash: paths.get_commit_hash().map(str::to_string),
             }),
         })))
     }
@@ -534,6 +568,7 @@ impl Pipeline for GGMLPipeline {
             paged_attn_meta: _, // NOTE(EricLBuehler): ignore it for ggml
             flash_meta,         // NOTE(EricLBuehler): ignore it for ggml dequant into f32
             flash_meta_full,    // NOTE(EricLBuehler): ignore it for ggml dequant into f32
+            adapter_scalings: _,
         } = *inputs.downcast().expect("Downcast failed.");
         let logits = match self.model {
             Model::Llama(ref model) => {
@@ -1,19 +1,20 @@
 use super::cache_manager::{FullCacheManager, NormalCacheManager};
-use super::llg::build_tok_env;
+use super::llg::build_or_reuse_tok_env;
 use super::{
     get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, PrettyName, QuantizationKind,
-    TokenSource,
+    CacheManager, GeneralMetadata, HubRepo, Loader, ModelKind, ModelPaths, PrettyName,
+    QuantizationKind, ThroughputTracker, TokenSource,
 };
 use super::{
     AnyMoePipelineMixin, CacheManagerMixin, EitherCache, ForwardInputsResult, IsqPipelineMixin,
     MetadataMixin, ModelCategory, PreProcessingMixin,
 };
 use crate::device_map::{self, DeviceMapper};
+use crate::fingerprint::model_fingerprint;
 use crate::gguf::{
-    get_gguf_chat_template, {convert_gguf_to_hf_tokenizer, GgufTokenizerConversion},
+    get_gguf_chat_template, {get_gguf_tokenizer, GgufTokenizerConversion},
 };
-use crate::gguf::{Content, GGUFArchitecture};
+use crate::gguf::{Content, GGUFArchitecture, GgufQuantBreakdown, Provenance};
 use crate::lora::Ordering;
 use crate::paged_attention::{
     calculate_cache_config, AttentionImplementation, CacheEngine, ModelConfigLike,
@@ -22,34 +23,51 @@ use crate::pipeline::chat_template::{calculate_eos_tokens, BeginEndUnkPadTok, Ge
 use crate::pipeline::get_chat_template;
 use crate::pipeline::inputs_processor::DEFAULT_PROMPT_CHUNK_SIZE;
 use crate::pipeline::loaders::DeviceMappedModelLoader;
+use crate::pipeline::prompt_format::check_prompt_format;
 use crate::pipeline::sampling::sample_and_add_toks;
 use crate::pipeline::ChatTemplate;
 use crate::prefix_cacher::PrefixCacheManagerV2;
 use crate::sequence::Sequence;
 use crate::utils::gguf_metadata::{ContentConfig, GgufDeviceMapLoaderInner};
 use crate::utils::model_config as ModelConfig;
-use crate::utils::tokenizer::get_tokenizer;
+use crate::utils::tokenizer::set_byte_fallback;
 use crate::xlora_models::NonGranularState;
 use crate::{
     get_mut_arcmutex, get_paths_gguf, DeviceMapSetting, LocalModelPaths, PagedAttentionConfig,
     Pipeline, Topology, TryIntoDType,
 };
 use crate::{
+    models::quantized_bloom::ModelWeights as QBloom,
+    models::quantized_chatglm::ModelWeights as QChatGlm,
+    models::quantized_falcon::ModelWeights as QFalcon,
+    models::quantized_gemma::ModelWeights as QGemma,
+    models::quantized_gemma3::ModelWeights as QGemma3,
+    models::quantized_gpt2::ModelWeights as QGpt2,
+    models::quantized_gptneox::ModelWeights as QGptNeox,
+    models::quantized_granite::ModelWeights as QGranite,
+    models::quantized_jais::ModelWeights as QJais,
     models::quantized_llama::ModelWeights as QLlama,
+    models::quantized_mamba::ModelWeights as QMamba,
+    models::quantized_mpt::ModelWeights as QMpt,
     models::quantized_phi2::ModelWeights as QPhi,
     models::quantized_phi3::ModelWeights as QPhi3,
     models::quantized_qwen2::ModelWeights as QQwen2,
+    models::quantized_rwkv::ModelWeights as QRwkv,
     models::quantized_starcoder2::ModelWeights as QStarcoder2,
+    utils::hub_proxy::HubProxy,
     utils::tokens::get_token,
-    xlora_models::{XLoraQLlama, XLoraQPhi3},
+    xlora_models::{XLoraQLlama, XLoraQPhi2, XLoraQPhi3},
 };
 use anyhow::{bail, Result};
-use candle_core::{Device, Tensor};
+use candle_core::{quantized::GgmlDType, DType, Device, Tensor};
+use candle_nn::VarMap;
 use either::Either;
-use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
+use hf_hub::api::sync::ApiBuilder;
+use llguidance::toktrie::TokEnv;
 use mistralrs_quant::IsqType;
 use rand_isaac::Isaac64Rng;
 use std::any::Any;
+use std::collections::HashMap;
 use std::fs;
 use std::num::{NonZero, NonZeroUsize};
 use std::path::PathBuf;
@@ -63,10 +81,23 @@ enum Model {
     Llama(QLlama),
     Phi2(QPhi),
     XLoraLlama(XLoraQLlama),
+    XLoraPhi2(XLoraQPhi2),
     XLoraPhi3(XLoraQPhi3),
     Phi3(QPhi3),
     Starcoder2(QStarcoder2),
     Qwen2(QQwen2),
+    Granite(QGranite),
+    ChatGlm(QChatGlm),
+    Gemma(QGemma),
+    Gemma3(QGemma3),
+    Jais(QJais),
+    Falcon(QFalcon),
+    Mpt(QMpt),
+    GptNeox(QGptNeox),
+    Gpt2(QGpt2),
+    Bloom(QBloom),
+    Mamba(QMamba),
+    Rwkv(QRwkv),
 }
 
 pub struct GGUFPipeline {
@@ -78,6 +109,8 @@ pub struct GGUFPipeline {
     non_granular_state: Option<NonGranularState>,
     metadata: Arc<GeneralMetadata>,
     mapper: Box<dyn DeviceMapper + Send + Sync>,
+    quant_breakdown: GgufQuantBreakdown,
+    provenance: Provenance,
 }
 
 /// Loader for a GGUF model.
@@ -94,6 +127,7 @@ pub struct GGUFLoader {
     config: GGUFSpecificConfig,
     jinja_explicit: Option<String>,
     lora_adapter_ids: Option<Vec<String>>,
+    proxy: Option<HubProxy>,
 }
 
 #[derive(Clone, Default)]
@@ -101,6 +135,19 @@ pub struct GGUFLoader {
 pub struct GGUFSpecificConfig {
     pub prompt_chunksize: Option<NonZeroUsize>,
     pub topology: Option<Topology>,
+    /// Replace a missing chat template, or one which does not match the prompt format detected
+    /// from the tokenizer and model name, with a template of the detected format.
+    pub auto_correct_chat_template: bool,
+    /// Force byte fallback of the tokenizer on or off, overriding the tokenizer's default.
+    pub byte_fallback: Option<bool>,
+    /// Load the model as this architecture instead of the one declared in the metadata.
+    pub force_arch: Option<GGUFArchitecture>,
+    /// Safetensors file with F16 copies of some tensors, used instead of the quantized tensors of
+    /// the same name to recover accuracy.
+    pub accuracy_companion: Option<PathBuf>,
+    /// Token environment used instead of building one from the tokenizer, such as one shared by
+    /// the models of a vocabulary. Its tokens must be those of the tokenizer.
+    pub tok_env: Option<TokEnv>,
 }
 
 #[derive(Default)]
@@ -117,6 +164,7 @@ pub struct GGUFLoaderBuilder {
     tgt_non_granular_index: Option<usize>,
     config: GGUFSpecificConfig,
     jinja_explicit: Option<String>,
+    proxy: Option<HubProxy>,
 }
 
 impl GGUFLoaderBuilder {
@@ -195,6 +243,13 @@ impl GGUFLoaderBuilder {
         self.with_adapter(lora_model_id, lora_order, false, None)
     }
 
+    /// Download the model from the Hugging Face Hub through an HTTP proxy, optionally
+    /// authenticating with a user and password. See [`GGUFLoader::with_proxy`].
+    pub fn with_proxy(mut self, proxy_url: &str, proxy_auth: Option<(&str, &str)>) -> Self {
+        self.proxy = Some(HubProxy::new(proxy_url, proxy_auth));
+        self
+    }
+
     pub fn build(self) -> Box<dyn Loader> {
         Box::new(GGUFLoader {
             model_id: self.model_id,
@@ -209,6 +264,7 @@ impl GGUFLoaderBuilder {
             config: self.config,
             jinja_explicit: self.jinja_explicit,
             lora_adapter_ids: None,
+            proxy: self.proxy,
         })
     }
 }
@@ -252,8 +308,16 @@ impl GGUFLoader {
             config,
             jinja_explicit,
             lora_adapter_ids: None,
+            proxy: None,
         }
     }
+
+    /// Download the model from the Hugging Face Hub through an HTTP proxy, optionally
+    /// authenticating with a user and password, for networks without direct internet access.
+    pub fn with_proxy(mut self, proxy_url: &str, proxy_auth: Option<(&str, &str)>) -> Self {
+        self.proxy = Some(HubProxy::new(proxy_url, proxy_auth));
+        self
+    }
 }
 
 impl Loader for GGUFLoader {
@@ -321,9 +385,16 @@ impl Loader for GGUFLoader {
         }
         let mut readers = readers.iter_mut().collect::<Vec<_>>();
 
-        let model = Content::from_readers(&mut readers)?;
+        let mut model = Content::from_readers_with_arch(&mut readers, self.config.force_arch)?;
+        if let Some(companion) = &self.config.accuracy_companion {
+            model = model.with_accuracy_companion(companion)?;
+        }
+        let quant_breakdown = model.quant_breakdown();
+        let provenance = model.provenance();
         if !silent {
             model.print_metadata()?;
+            quant_breakdown.log();
+            provenance.log();
         }
         let arch = model.arch();
 
@@ -378,21 +449,24 @@ impl Loader for GGUFLoader {
             paged_attn_config = None;
         }
 
+        // A SentencePiece model is looked for next to the tokenizer, or else next to the weights.
+        let sentencepiece_filename = if paths.get_tokenizer_filename().as_os_str().is_empty() {
+            paths.get_weight_filenames().first()
+        } else {
+            Some(paths.get_tokenizer_filename())
+        }
+        .map(|path| path.with_file_name("tokenizer.model"));
         let GgufTokenizerConversion {
             tokenizer,
             bos,
             eos,
             unk,
-        } = if paths.get_tokenizer_filename().to_string_lossy().is_empty() {
-            convert_gguf_to_hf_tokenizer(&model)?
-        } else {
-            GgufTokenizerConversion {
-                tokenizer: get_tokenizer(paths.get_tokenizer_filename(), None)?,
-                bos: None,
-                eos: None,
-                unk: None,
-            }
-        };
+        } = get_gguf_tokenizer(
+            paths.get_tokenizer_filename(),
+            &model,
+            sentencepiece_filename.as_deref(),
+        )?;
+        let tokenizer = set_byte_fallback(tokenizer, self.config.byte_fallback)?;
 
         // Only load gguf chat template if there is nothing else
         let gguf_chat_template =
@@ -401,6 +475,13 @@ impl Loader for GGUFLoader {
             } else {
                 None
             };
+        let model_name = model
+            .get_metadata()
+            .get("general.name")
+            .and_then(|name| name.to_string().ok())
+            .cloned()
+            .or_else(|| self.model_id.clone())
+            .unwrap_or(self.quantized_model_id.clone());
 
         let has_adapter = self.kind.is_adapted();
         let is_xlora = self.kind.is_adapted_and(|a| a.is_x_lora());
@@ -408,6 +489,19 @@ impl Loader for GGUFLoader {
         let paged_attn_config = if matches!(self.kind, ModelKind::GgufAdapter { .. }) {
             warn!("Adapter models do not currently support PagedAttention, running without");
             None
+        } else if paged_attn_config.is_some()
+            && matches!(
+                arch,
+                GGUFArchitecture::Jais | GGUFArchitecture::Mpt | GGUFArchitecture::Bloom
+            )
+        {
+            warn!("{arch} uses ALiBi, which is not supported with PagedAttention, running without");
+            None
+        } else if paged_attn_config.is_some()
+            && matches!(arch, GGUFArchitecture::Mamba | GGUFArchitecture::Rwkv)
+        {
+            warn!("{arch} is a recurrent model, which has no KV cache to page, running without");
+            None
         } else {
             paged_attn_config
         };
@@ -449,10 +543,26 @@ impl Loader for GGUFLoader {
                     Model::Starcoder2(QStarcoder2::try_from(model_config)?)
                 }
                 GGUFArchitecture::Qwen2 => Model::Qwen2(QQwen2::try_from(model_config)?),
-                a => bail!("Unsupported architecture `{a:?}` for GGUF"),
+                GGUFArchitecture::Granite => Model::Granite(QGranite::try_from(model_config)?),
+                GGUFArchitecture::ChatGlm => Model::ChatGlm(QChatGlm::try_from(model_config)?),
+                GGUFArchitecture::Gemma => Model::Gemma(QGemma::try_from(model_config)?),
+                GGUFArchitecture::Gemma3 => Model::Gemma3(QGemma3::try_from(model_config)?),
+                GGUFArchitecture::Jais => Model::Jais(QJais::try_from(model_config)?),
+                GGUFArchitecture::Falcon => Model::Falcon(QFalcon::try_from(model_config)?),
+                GGUFArchitecture::Mpt => Model::Mpt(QMpt::try_from(model_config)?),
+                GGUFArchitecture::Gptneox => Model::GptNeox(QGptNeox::try_from(model_config)?),
+                GGUFArchitecture::Gpt2 => Model::Gpt2(QGpt2::try_from(model_config)?),
+                GGUFArchitecture::Bloom => Model::Bloom(QBloom::try_from(model_config)?),
+                GGUFArchitecture::Mamba => Model::Mamba(QMamba::try_from(model_config)?),
+                GGUFArchitecture::Rwkv => Model::Rwkv(QRwkv::try_from(model_config)?),
+                a => bail!(
+                    "Unsupported architecture `{a}` for GGUF, supported architectures are {}.",
+                    GGUFArchitecture::supported_list()
+                ),
             },
             ModelKind::GgufAdapter { adapter, .. } => match arch {
                 GGUFArchitecture::Llama => Model::XLoraLlama(XLoraQLlama::try_from(model_config)?),
+                GGUFArchitecture::Phi2 => Model::XLoraPhi2(XLoraQPhi2::try_from(model_config)?),
                 GGUFArchitecture::Phi3 => Model::XLoraPhi3(XLoraQPhi3::try_from(model_config)?),
                 a => bail!(
                     "Unsupported architecture `{a:?}` for GGUF {kind}",
@@ -507,19 +617,55 @@ impl Loader for GGUFLoader {
             Model::Phi2(ref p) => p.max_seq_len,
             Model::XLoraLlama(ref xl) => xl.max_seq_len,
             Model::Phi3(ref p) => p.max_seq_len,
+            Model::XLoraPhi2(ref p) => p.max_seq_len,
             Model::XLoraPhi3(ref p) => p.max_seq_len,
             Model::Starcoder2(ref p) => p.max_seq_len,
             Model::Qwen2(ref p) => p.max_seq_len,
+            Model::Granite(ref p) => p.max_seq_len,
+            Model::ChatGlm(ref p) => p.max_seq_len,
+            Model::Gemma(ref p) => p.max_seq_len,
+            Model::Gemma3(ref p) => p.max_seq_len,
+            Model::Jais(ref p) => p.max_seq_len,
+            Model::Falcon(ref p) => p.max_seq_len,
+            Model::Mpt(ref p) => p.max_seq_len,
+            Model::GptNeox(ref p) => p.max_seq_len,
+            Model::Gpt2(ref p) => p.max_seq_len,
+            Model::Bloom(ref p) => p.max_seq_len,
+            Model::Mamba(ref p) => p.max_seq_len,
+            Model::Rwkv(ref p) => p.max_seq_len,
+        };
+        let tok_env = build_or_reuse_tok_env(tokenizer.clone(), self.config.tok_env.clone())?;
+        // The state of a recurrent model cannot be cut back to a shared prefix, and running the
+        // whole sequence at each step would continue from the state of the previous step.
+        let is_recurrent = matches!(model, Model::Mamba(_) | Model::Rwkv(_));
+        let no_prefix_cache = is_recurrent;
+        let no_kv_cache = if self.no_kv_cache && is_recurrent {
+            warn!("{arch} is a recurrent model, which keeps a fixed-size state, ignoring `no_kv_cache`");
+            false
+        } else {
+            self.no_kv_cache
         };
-        let tok_env = build_tok_env(tokenizer.clone());
         let num_hidden_layers = match model {
             Model::Llama(ref model) => model.cache.normal().0.len(),
             Model::Phi2(ref model) => model.cache.normal().0.len(),
             Model::XLoraLlama(ref model) => model.cache.full().lock().len(),
             Model::Phi3(ref model) => model.cache.normal().0.len(),
+            Model::XLoraPhi2(ref model) => model.cache.full().lock().len(),
             Model::XLoraPhi3(ref model) => model.cache.full().lock().len(),
             Model::Starcoder2(ref model) => model.cache.normal().0.len(),
             Model::Qwen2(ref model) => model.cache.normal().0.len(),
+            Model::Granite(ref model) => model.cache.normal().0.len(),
+            Model::ChatGlm(ref model) => model.cache.normal().0.len(),
+            Model::Gemma(ref model) => model.cache.normal().0.len(),
+            Model::Gemma3(ref model) => model.cache.normal().0.len(),
+            Model::Jais(ref model) => model.cache.normal().0.len(),
+            Model::Falcon(ref model) => model.cache.normal().0.len(),
+            Model::Mpt(ref model) => model.cache.normal().0.len(),
+            Model::GptNeox(ref model) => model.cache.normal().0.len(),
+            Model::Gpt2(ref model) => model.cache.normal().0.len(),
+            Model::Bloom(ref model) => model.cache.normal().0.len(),
+            Model::Mamba(ref model) => model.cache.full().lock().len(),
+            Model::Rwkv(ref model) => model.cache.full().lock().len(),
         };
 
         if chat_template.bos_token.is_none() && bos.is_some() {
@@ -532,12 +678,25 @@ impl Loader for GGUFLoader {
             chat_template.unk_token = Some(BeginEndUnkPadTok(Either::Left(unk.unwrap())));
         }
 
+        let prompt_format = check_prompt_format(
+            &tokenizer,
+            Some(&model_name),
+            &mut chat_template,
+            self.config.auto_correct_chat_template,
+        );
+
+        let generation_defaults = gen_conf
+            .as_ref()
+            .map(GenerationConfig::sampling_defaults)
+            .unwrap_or_default();
         let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
+        let system_fingerprint =
+            model_fingerprint(paths.as_ref(), quant_breakdown.dominant_type().as_deref());
         Ok(Arc::new(Mutex::new(GGUFPipeline {
             model,
             tokenizer: tokenizer.into(),
-            no_kv_cache: self.no_kv_cache,
-            chat_template: Arc::new(chat_template),
+            no_kv_cache,
+            chat_template: Arc::new(chat_template.with_detected_system_role()),
             model_id: self
                 .model_id
                 .clone()
@@ -551,8 +710,8 @@ impl Loader for GGUFLoader {
             metadata: Arc::new(GeneralMetadata {
                 max_seq_len,
                 tok_env: Some(tok_env),
-                no_kv_cache: self.no_kv_cache,
-                no_prefix_cache: false,
+                no_kv_cache,
+                no_prefix_cache,
                 num_hidden_layers,
                 eos_tok: eos,
                 kind: self.kind.clone(),
@@ -563,8 +722,16 @@ impl Loader for GGUFLoader {
                 cache_engine,
                 prompt_chunksize: Some(NonZero::new(prompt_chunksize).unwrap()),
                 model_metadata: Some(Arc::new(model_config_metadata)),
+                throughput: Arc::new(ThroughputTracker::default()),
+                prompt_format: Some(prompt_format),
+                system_fingerprint,
+                generation_defaults,
+                lora_adapters: Vec::new(),
+                commit_hash: paths.get_commit_hash().map(str::to_string),
             }),
             mapper: pipeline_mapper,
+            quant_breakdown,
+            provenance,
         })))
     }
 
@@ -580,6 +747,123 @@ impl Loader for GGUFLoader {
     }
 }
 
+impl GGUFPipeline {
+    /// The quantization types of the loaded tensors, per layer.
+    pub fn quant_breakdown(&self) -> &GgufQuantBreakdown {
+        &self.quant_breakdown
+    }
+
+    /// The license, finetune and origin of the model from the GGUF metadata.
+    pub fn provenance(&self) -> &Provenance {
+        &self.provenance
+    }
+
+    /// Dequantize all model weights to `dtype` and collect them in a `candle_nn::VarMap`, keyed by
+    /// the GGUF tensor names. This allows continuing training with `candle_nn`.
+    pub fn export_varmap(&mut self, dtype: DType) -> Result<VarMap> {
+        match self.model {
+            Model::Llama(ref mut model) => Ok(model.export_varmap(dtype)?),
+            Model::Phi2(_)
+            | Model::XLoraLlama(_)
+            | Model::XLoraPhi2(_)
+            | Model::XLoraPhi3(_)
+            | Model::Phi3(_)
+            | Model::Starcoder2(_)
+            | Model::Qwen2(_)
+            | Model::Granite(_)
+            | Model::ChatGlm(_)
+            | Model::Gemma(_)
+            | Model::Gemma3(_)
+            | Model::Jais(_)
+            | Model::Falcon(_)
+            | Model::Mpt(_)
+            | Model::GptNeox(_)
+            | Model::Gpt2(_)
+            | Model::Bloom(_)
+            | Model::Mamba(_)
+            | Model::Rwkv(_) => {
+                bail!("VarMap export is only supported for GGUF Llama models.")
+            }
+        }
+    }
+
+    /// Reload weights (for example after fine-tuning) from a `VarMap` produced by
+    /// [`GGUFPipeline::export_varmap`], quantizing the linear layers to `quant`.
+    pub fn load_from_varmap(&mut self, varmap: &VarMap, quant: GgmlDType) -> Result<()> {
+        match self.model {
+            Model::Llama(ref mut model) => Ok(model.load_from_varmap(varmap, quant)?),
+            Model::Phi2(_)
+            | Model::XLoraLlama(_)
+            | Model::XLoraPhi2(_)
+            | Model::XLoraPhi3(_)
+            | Model::Phi3(_)
+            | Model::Starcoder2(_)
+            | Model::Qwen2(_)
+            | Model::Granite(_)
+            | Model::ChatGlm(_)
+            | Model::Gemma(_)
+            | Model::Gemma3(_)
+            | Model::Jais(_)
+            | Model::Falcon(_)
+            | Model::Mpt(_)
+            | Model::GptNeox(_)
+            | Model::Gpt2(_)
+            | Model::Bloom(_)
+            | Model::Mamba(_)
+            | Model::Rwkv(_) => {
+                bail!("Loading from a VarMap is only supported for GGUF Llama models.")
+            }
+        }
+    }
+
+    /// Capture the intermediate tensors of `layer`, such as the attention scores, the hidden
+    /// states and the FFN activations, during the next forward pass. They are then returned by
+    /// [`GGUFPipeline::get_debug_tensors`]. Attention scores are not captured with
+    /// PagedAttention.
+    pub fn set_debug_layer(&mut self, layer: usize) -> Result<()> {
+        match self.model {
+            Model::Llama(ref model) => {
+                let num_layers = model.cache.normal().0.len();
+                if layer >= num_layers {
+                    bail!("Layer {layer} is out of range, the model has {num_layers} layers.");
+                }
+                model.debug.set_layer(layer);
+                Ok(())
+            }
+            Model::Phi2(_)
+            | Model::XLoraLlama(_)
+            | Model::XLoraPhi2(_)
+            | Model::XLoraPhi3(_)
+            | Model::Phi3(_)
+            | Model::Starcoder2(_)
+            | Model::Qwen2(_)
+            | Model::Granite(_)
+            | Model::ChatGlm(_)
+            | Model::Gemma(_)
+            | Model::Gemma3(_)
+            | Model::Jais(_)
+            | Model::Falcon(_)
+            | Model::Mpt(_)
+            | Model::GptNeox(_)
+            | Model::Gpt2(_)
+            | Model::Bloom(_)
+            | Model::Mamba(_)
+            | Model::Rwkv(_) => {
+                bail!("Tensor dumping is only supported for GGUF Llama models.")
+            }
+        }
+    }
+
+    /// The tensors captured for the layer set by [`GGUFPipeline::set_debug_layer`], by name.
+    /// Empty until a forward pass has reached the layer.
+    pub fn get_debug_tensors(&self) -> HashMap<String, Tensor> {
+        match self.model {
+            Model::Llama(ref model) => model.debug.tensors(),
+            _ => HashMap::new(),
+        }
+    }
+}
+
 impl PreProcessingMixin for GGUFPipeline {
     fn get_chat_template(&self) -> Option<Arc<ChatTemplate>> {
         Some(self.chat_template.clone())
@@ -639,9 +923,22 @@ impl CacheManagerMixin for GGUFPipeline {
             Model::Phi2(ref model) => &model.cache,
             Model::XLoraLlama(ref model) => &model.cache,
             Model::Phi3(ref model) => &model.cache,
+            Model::XLoraPhi2(ref model) => &model.cache,
             Model::XLoraPhi3(ref model) => &model.cache,
             Model::Starcoder2(ref model) => &model.cache,
             Model::Qwen2(ref model) => &model.cache,
+            Model::Granite(ref model) => &model.cache,
+            Model::ChatGlm(ref model) => &model.cache,
+            Model::Gemma(ref model) => &model.cache,
+            Model::Gemma3(ref model) => &model.cache,
+            Model::Jais(ref model) => &model.cache,
+            Model::Falcon(ref model) => &model.cache,
+            Model::Mpt(ref model) => &model.cache,
+            Model::GptNeox(ref model) => &model.cache,
+            Model::Gpt2(ref model) => &model.cache,
+            Model::Bloom(ref model) => &model.cache,
+            Model::Mamba(ref model) => &model.cache,
+            Model::Rwkv(ref model) => &model.cache,
         }
     }
 }
@@ -653,9 +950,22 @@ impl MetadataMixin for GGUFPipeline {
             Model::Phi2(ref model) => model.device.clone(),
             Model::XLoraLlama(ref model) => model.device.clone(),
             Model::Phi3(ref model) => model.device.clone(),
+            Model::XLoraPhi2(ref model) => model.device.clone(),
             Model::XLoraPhi3(ref model) => model.device.clone(),
             Model::Starcoder2(ref model) => model.device.clone(),
             Model::Qwen2(ref model) => model.device.clone(),
+            Model::Granite(ref model) => model.device.clone(),
+            Model::ChatGlm(ref model) => model.device.clone(),
+            Model::Gemma(ref model) => model.device.clone(),
+            Model::Gemma3(ref model) => model.device.clone(),
+            Model::Jais(ref model) => model.device.clone(),
+            Model::Falcon(ref model) => model.device.clone(),
+            Model::Mpt(ref model) => model.device.clone(),
+            Model::GptNeox(ref model) => model.device.clone(),
+            Model::Gpt2(ref model) => model.device.clone(),
+            Model::Bloom(ref model) => model.device.clone(),
+            Model::Mamba(ref model) => model.device.clone(),
+            Model::Rwkv(ref model) => model.device.clone(),
         }
     }
     fn tokenizer(&self) -> Option<Arc<Tokenizer>> {
@@ -695,6 +1005,7 @@ impl Pipeline for GGUFPipeline {
             paged_attn_meta,
             flash_meta,
             flash_meta_full,
+            adapter_scalings: _,
         } = *inputs.downcast().expect("Downcast failed.");
         let metadata = self.get_metadata();
         let paged_attn_meta = match (&metadata.cache_engine, &paged_attn_meta) {
@@ -730,6 +1041,17 @@ impl Pipeline for GGUFPipeline {
             Model::Phi3(ref model) => {
                 model.forward(&input_ids, &seqlen_offsets, paged_attn_meta)?
             }
+            Model::XLoraPhi2(ref model) => model.forward(
+                &input_ids,
+                input_ids_full.as_ref().unwrap_or(&input_ids),
+                &seqlen_offsets,
+                seqlen_offsets_full.as_ref().unwrap_or(&seqlen_offsets),
+                self.no_kv_cache,
+                &self.non_granular_state,
+                context_lens,
+                &flash_meta,
+                flash_meta_full.as_ref().unwrap_or(&flash_meta),
+            )?,
             Model::XLoraPhi3(ref model) => model.forward(
                 &input_ids,
                 input_ids_full.as_ref().unwrap_or(&input_ids),
@@ -747,6 +1069,32 @@ impl Pipeline for GGUFPipeline {
             Model::Qwen2(ref model) => {
                 model.forward(&input_ids, &seqlen_offsets, context_lens, paged_attn_meta)?
             }
+            Model::Granite(ref model) => {
+                model.forward(&input_ids, &seqlen_offsets, context_lens, paged_attn_meta)?
+            }
+            Model::ChatGlm(ref model) => {
+                model.forward(&input_ids, &seqlen_offsets, context_lens, paged_attn_meta)?
+            }
+            Model::Gemma(ref model) => {
+                model.forward(&input_ids, &seqlen_offsets, context_lens, paged_attn_meta)?
+            }
+            Model::Gemma3(ref model) => {
+                model.forward(&input_ids, &seqlen_offsets, context_lens, paged_attn_meta)?
+            }
+            Model::Jais(ref model) => model.forward(&input_ids, context_lens)?,
+            Model::Mpt(ref model) => model.forward(&input_ids, context_lens)?,
+            Model::Bloom(ref model) => model.forward(&input_ids, context_lens)?,
+            Model::Mamba(ref model) => model.forward(&input_ids, context_lens)?,
+            Model::Rwkv(ref model) => model.forward(&input_ids, context_lens)?,
+            Model::GptNeox(ref model) => {
+                model.forward(&input_ids, &seqlen_offsets, context_lens, paged_attn_meta)?
+            }
+            Model::Gpt2(ref model) => {
+                model.forward(&input_ids, &seqlen_offsets, context_lens, paged_attn_meta)?
+            }
+            Model::Falcon(ref model) => {
+                model.forward(&input_ids, &seqlen_offsets, context_lens, paged_attn_meta)?
+            }
         };
         if return_raw_logits {
             Ok(ForwardInputsResult::RawLogits { logits })
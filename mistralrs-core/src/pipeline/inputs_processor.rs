@@ -152,7 +152,9 @@ pub mod text_models_inputs_processor {
             .max()
             .expect("No sequences");
         let padding_tok = T::zero();
-        // Pad each sequence by the padding token to the max len.
+        // Pad each sequence by the padding token to the max len. The padding is on the right, so
+        // each sequence starts with its BOS at the first position, the causal mask keeps its tokens
+        // from attending to the padding and the padding is not written to the PagedAttention cache.
         let mut seqs_tensors = Vec::new();
         let mut seqlen_offsets = Vec::new();
         let mut context_lens = Vec::new();
@@ -180,10 +182,9 @@ pub mod text_models_inputs_processor {
 
                 context_lens.push((0, ctxt.len()));
             } else {
-                context_lens.push((
-                    ctxt.len() - last_n_context_len.map(|(a, _)| a).unwrap_or(1),
-                    last_n_context_len.map(|(a, _)| a).unwrap_or(1),
-                ));
+                // The logits are those of the last tokens of the sequence, not of its padding.
+                let n_logits = last_n_context_len.map(|(a, _)| a).unwrap_or(1);
+                context_lens.push((prompt_len.saturating_sub(n_logits), n_logits));
             }
 
             seqlens_q.push(ctxt.len() as u32);
@@ -638,6 +639,37 @@ pub mod text_models_inputs_processor {
         pub paged_attn_meta: Option<PagedAttentionInputMetadata>,
         pub flash_meta: FlashParams,
         pub flash_meta_full: Option<FlashParams>,
+        /// Weights of the LoRA adapters for each sequence, of shape `(batch, 1, 1, n_adapters)`,
+        /// if some of the sequences blend them.
+        pub adapter_scalings: Option<Tensor>,
+    }
+
+    /// Scalings blending the LoRA adapters of the sequences `seq_indices`, if any of them does. The
+    /// other sequences apply each adapter with a weight of 1.
+    fn adapter_scalings_input(
+        adapter_scalings: &[Option<Vec<f32>>],
+        seq_indices: &[usize],
+        device: &Device,
+    ) -> Result<Option<Tensor>> {
+        let Some(n_adapters) = seq_indices
+            .iter()
+            .find_map(|i| adapter_scalings[*i].as_ref().map(Vec::len))
+        else {
+            return Ok(None);
+        };
+        let scalings = seq_indices
+            .iter()
+            .flat_map(|i| {
+                adapter_scalings[*i]
+                    .clone()
+                    .unwrap_or_else(|| vec![1.; n_adapters])
+            })
+            .collect::<Vec<_>>();
+        Ok(Some(Tensor::from_vec(
+            scalings,
+            (seq_indices.len(), 1, 1, n_adapters),
+            device,
+        )?))
     }
 
     pub struct TextInputsProcessor;
@@ -658,6 +690,11 @@ pub mod text_models_inputs_processor {
             prompt_chunksize: Option<NonZeroUsize>,
             mapper: Option<&dyn DeviceMapper>,
         ) -> Box<dyn Iterator<Item = Result<InputProcessorOutput>>> {
+            let adapter_scalings = input_seqs
+                .iter()
+                .map(|seq| seq.adapter_scalings().map(<[f32]>::to_vec))
+                .collect::<Vec<_>>();
+            let scalings_device = device.clone();
             if is_xlora && !is_prompt {
                 Box::new(
                     get_prompt_input(
@@ -687,7 +724,7 @@ pub mod text_models_inputs_processor {
                         prompt_chunksize,
                         mapper,
                     ))
-                    .map(|(prompt, completion)| {
+                    .map(move |(prompt, completion)| {
                         let InnerInputProcessorOutput {
                             inputs:
                                 InputMetadata {
@@ -722,6 +759,11 @@ pub mod text_models_inputs_processor {
                             paged_attn_meta,
                             flash_meta,
                             flash_meta_full: Some(flash_meta_full),
+                            adapter_scalings: adapter_scalings_input(
+                                &adapter_scalings,
+                                &seq_indices,
+                                &scalings_device,
+                            )?,
                         });
                         Ok(InputProcessorOutput {
                             inputs,
@@ -744,7 +786,7 @@ pub mod text_models_inputs_processor {
                         prompt_chunksize,
                         mapper,
                     )
-                    .map(|metadata| {
+                    .map(move |metadata| {
                         let InnerInputProcessorOutput {
                             inputs:
                                 InputMetadata {
@@ -767,6 +809,11 @@ pub mod text_models_inputs_processor {
                             paged_attn_meta,
                             flash_meta: flash_meta.clone(),
                             flash_meta_full: Some(flash_meta),
+                            adapter_scalings: adapter_scalings_input(
+                                &adapter_scalings,
+                                &seq_indices,
+                                &scalings_device,
+                            )?,
                         });
                         Ok(InputProcessorOutput {
                             inputs,
@@ -789,7 +836,7 @@ pub mod text_models_inputs_processor {
                         prompt_chunksize,
                         mapper,
                     )
-                    .map(|metadata| {
+                    .map(move |metadata| {
                         let InnerInputProcessorOutput {
                             inputs:
                                 InputMetadata {
@@ -812,6 +859,11 @@ pub mod text_models_inputs_processor {
                             paged_attn_meta,
                             flash_meta,
                             flash_meta_full: None,
+                            adapter_scalings: adapter_scalings_input(
+                                &adapter_scalings,
+                                &seq_indices,
+                                &scalings_device,
+                            )?,
                         });
                         Ok(InputProcessorOutput {
                             inputs,
@@ -835,7 +887,7 @@ pub mod text_models_inputs_processor {
                         prompt_chunksize,
                         mapper,
                     )
-                    .map(|metadata| {
+                    .map(move |metadata| {
                         let InnerInputProcessorOutput {
                             inputs:
                                 InputMetadata {
@@ -858,6 +910,11 @@ pub mod text_models_inputs_processor {
                             paged_attn_meta,
                             flash_meta,
                             flash_meta_full: None,
+                            adapter_scalings: adapter_scalings_input(
+                                &adapter_scalings,
+                                &seq_indices,
+                                &scalings_device,
+                            )?,
                         });
                         Ok(InputProcessorOutput {
                             inputs,
@@ -873,3 +930,44 @@ pub mod text_models_inputs_processor {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, IndexOp};
+
+    use super::text_models_inputs_processor::make_prompt_chunk;
+    use crate::DeviceMapSetting;
+
+    const BOS: u32 = 1;
+
+    #[test]
+    fn padded_prompts_keep_bos_and_last_token() -> anyhow::Result<()> {
+        let dev = Device::Cpu;
+        let mapper = DeviceMapSetting::dummy().into_mapper(1, &dev, None)?;
+        let toks = vec![vec![BOS, 5, 6, 7], vec![BOS, 9], vec![BOS, 4, 8]];
+        let inputs = make_prompt_chunk(
+            0,
+            toks.clone(),
+            &[0, 1, 2],
+            &dev,
+            None,
+            false,
+            None,
+            Some(&*mapper),
+        )?;
+
+        assert_eq!(inputs.input.dims(), [3, 4]);
+        for (i, seq) in toks.iter().enumerate() {
+            let row = inputs.input.i(i)?.to_vec1::<u32>()?;
+            // Each sequence starts with its BOS at position 0 and is padded after its tokens.
+            assert_eq!(row[0], BOS);
+            assert_eq!(&row[..seq.len()], seq.as_slice());
+            assert!(row[seq.len()..].iter().all(|tok| *tok != BOS));
+            // The logits are taken at the last token of the sequence, not at the padding.
+            assert_eq!(inputs.context_lens[i], (seq.len() - 1, 1));
+            assert_eq!(inputs.position_ids[i], seq.len());
+        }
+        assert_eq!(inputs.positions, [0, 0, 0]);
+        Ok(())
+    }
+}
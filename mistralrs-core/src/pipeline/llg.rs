@@ -8,8 +8,10 @@ use llguidance::{
 };
 use tokenizers::Tokenizer;
 
-use crate::Constraint;
+use crate::{sequence::SequenceRecognizer, Constraint, DynamicGrammar};
 
+/// Build the token environment of a tokenizer, which constrained generation uses to match the
+/// tokens against the grammars. It may be shared by the models of the same vocabulary.
 pub fn build_tok_env(tokenizer: Tokenizer) -> TokEnv {
     let bt = toktrie_hf_tokenizers::ByteTokenizer::from_tokenizer(tokenizer)
         .expect("Failed to create ByteTokenizer from Tokenizer");
@@ -18,6 +20,31 @@ pub fn build_tok_env(tokenizer: Tokenizer) -> TokEnv {
     Arc::new(env)
 }
 
+/// Use the token environment given with the loader, after checking that its tokens are those of
+/// the tokenizer, or build one.
+pub(crate) fn build_or_reuse_tok_env(
+    tokenizer: Tokenizer,
+    prebuilt: Option<TokEnv>,
+) -> Result<TokEnv> {
+    let Some(tok_env) = prebuilt else {
+        return Ok(build_tok_env(tokenizer));
+    };
+    let bt = toktrie_hf_tokenizers::ByteTokenizer::from_tokenizer(tokenizer)?;
+    let tokens = bt.token_bytes();
+    let trie = tok_env.tok_trie();
+    if trie.vocab_size() != tokens.len() {
+        anyhow::bail!(
+            "The token environment has {} tokens but the tokenizer has {}.",
+            trie.vocab_size(),
+            tokens.len()
+        );
+    }
+    if let Some(id) = (0..tokens.len()).find(|&id| trie.token(id as u32) != tokens[id].as_slice()) {
+        anyhow::bail!("Token {id} of the token environment differs from the tokenizer.");
+    }
+    Ok(tok_env)
+}
+
 pub fn llg_grammar_from_constraint(constraint: &Constraint) -> Result<Option<TopLevelGrammar>> {
     let grm = match constraint {
         Constraint::Regex(regex) => TopLevelGrammar::from_regex(regex),
@@ -45,3 +72,184 @@ pub fn constraint_from_llg_grammar(
     )?;
     Ok(llguidance::Constraint::new(parser))
 }
+
+fn build_recognizer(tok_env: &TokEnv, constraint: &Constraint) -> Result<SequenceRecognizer> {
+    match llg_grammar_from_constraint(constraint)? {
+        Some(grm) => Ok(SequenceRecognizer::Llguidance(Box::new(
+            constraint_from_llg_grammar(tok_env.clone(), grm)?,
+        ))),
+        None => Ok(SequenceRecognizer::None),
+    }
+}
+
+/// Rebuilds the recognizer of a sequence when its [`DynamicGrammar`] is updated.
+pub struct DynamicRecognizer {
+    grammar: DynamicGrammar,
+    tok_env: TokEnv,
+    /// Number of generated tokens at the last update.
+    updated_at: usize,
+}
+
+impl DynamicRecognizer {
+    pub fn new(grammar: DynamicGrammar, tok_env: TokEnv) -> Self {
+        Self {
+            grammar,
+            tok_env,
+            updated_at: 0,
+        }
+    }
+
+    /// The recognizer of the initial grammar.
+    pub fn initial(&mut self) -> Result<SequenceRecognizer> {
+        self.updated_at = 0;
+        let constraint = self.grammar.grammar(&[]).unwrap_or(Constraint::None);
+        build_recognizer(&self.tok_env, &constraint)
+    }
+
+    /// The recognizer replacing the current one, if the grammar is updated after the last
+    /// generated token. The grammar is updated at most once per generated token.
+    pub fn update(&mut self, generated: &[u32]) -> Result<Option<SequenceRecognizer>> {
+        if generated.len() <= self.updated_at {
+            return Ok(None);
+        }
+        self.updated_at = generated.len();
+        self.grammar
+            .grammar(generated)
+            .map(|constraint| build_recognizer(&self.tok_env, &constraint))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, sync::Arc};
+
+    use serde_json::json;
+    use tokenizers::Tokenizer;
+
+    use super::{build_or_reuse_tok_env, build_tok_env, DynamicRecognizer};
+    use crate::{sequence::SequenceRecognizer, Constraint, DynamicGrammar, GrammarUpdate};
+
+    const GET: u32 = 0;
+    const SET: u32 = 1;
+    const OPEN: u32 = 2;
+    const CLOSE: u32 = 3;
+    const COMMA: u32 = 4;
+    const KEY: u32 = 5;
+
+    /// A byte-level BPE tokenizer with one token per word of the grammars.
+    fn tokenizer() -> Tokenizer {
+        tokenizer_with_vocab(json!({
+            "get": 0, "set": 1, "(": 2, ")": 3, ",": 4, "key": 5, "value": 6, "</s>": 7
+        }))
+    }
+
+    fn tokenizer_with_vocab(vocab: serde_json::Value) -> Tokenizer {
+        let raw = json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [{
+                "id": 7,
+                "content": "</s>",
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": true
+            }],
+            "normalizer": null,
+            "pre_tokenizer": null,
+            "post_processor": null,
+            "decoder": {
+                "type": "ByteLevel",
+                "add_prefix_space": false,
+                "trim_offsets": true,
+                "use_regex": true
+            },
+            "model": {
+                "type": "BPE",
+                "dropout": null,
+                "unk_token": null,
+                "continuing_subword_prefix": null,
+                "end_of_word_suffix": null,
+                "fuse_unk": false,
+                "byte_fallback": false,
+                "vocab": vocab,
+                "merges": []
+            }
+        });
+        Tokenizer::from_str(&raw.to_string()).unwrap()
+    }
+
+    /// The tokens allowed next.
+    fn allowed(recognizer: &mut SequenceRecognizer) -> Vec<u32> {
+        let SequenceRecognizer::Llguidance(llg) = recognizer else {
+            panic!("Expected a grammar.");
+        };
+        let mut allowed = Vec::new();
+        if let Some(mask) = &llg.compute_mask().unwrap().sample_mask {
+            mask.iter_set_entries(|tok| allowed.push(tok as u32));
+        }
+        allowed
+    }
+
+    fn commit(recognizer: &mut SequenceRecognizer, tok: u32) {
+        let SequenceRecognizer::Llguidance(llg) = recognizer else {
+            panic!("Expected a grammar.");
+        };
+        llg.commit_token(Some(tok)).unwrap();
+    }
+
+    #[test]
+    fn two_stage_grammar() {
+        // A call of `get` only takes a key, and a call of `set` a key and a value.
+        let grammar = DynamicGrammar::new(
+            Box::new(|generated: &[u32]| match generated.last() {
+                None => Constraint::Regex("get|set".to_string()),
+                Some(&GET) => Constraint::Regex(r"\(key\)".to_string()),
+                Some(_) => Constraint::Regex(r"\(key,value\)".to_string()),
+            }),
+            GrammarUpdate::AfterTokens(vec![GET, SET]),
+        );
+        let tok_env = build_tok_env(tokenizer());
+
+        for (function, after_key) in [(GET, CLOSE), (SET, COMMA)] {
+            let mut dynamic = DynamicRecognizer::new(grammar.clone(), tok_env.clone());
+            let mut recognizer = dynamic.initial().unwrap();
+            assert_eq!(allowed(&mut recognizer), [GET, SET]);
+            commit(&mut recognizer, function);
+
+            // Choosing the function updates the grammar to its arguments.
+            let mut generated = vec![function];
+            let mut recognizer = dynamic.update(&generated).unwrap().unwrap();
+            assert!(dynamic.update(&generated).unwrap().is_none());
+            assert_eq!(allowed(&mut recognizer), [OPEN]);
+            commit(&mut recognizer, OPEN);
+
+            // Other tokens do not update the grammar.
+            generated.push(OPEN);
+            assert!(dynamic.update(&generated).unwrap().is_none());
+            assert_eq!(allowed(&mut recognizer), [KEY]);
+            commit(&mut recognizer, KEY);
+            assert_eq!(allowed(&mut recognizer), [after_key]);
+        }
+    }
+
+    #[test]
+    fn reuses_prebuilt_tok_env() {
+        let prebuilt = build_tok_env(tokenizer());
+        let tok_env = build_or_reuse_tok_env(tokenizer(), Some(prebuilt.clone())).unwrap();
+        assert!(Arc::ptr_eq(&tok_env, &prebuilt));
+    }
+
+    #[test]
+    fn rejects_tok_env_of_other_vocab() {
+        // Same size, but `get` and `set` are swapped.
+        let other = tokenizer_with_vocab(json!({
+            "set": 0, "get": 1, "(": 2, ")": 3, ",": 4, "key": 5, "value": 6, "</s>": 7
+        }));
+        let prebuilt = build_tok_env(other);
+        assert!(build_or_reuse_tok_env(tokenizer(), Some(prebuilt)).is_err());
+    }
+}
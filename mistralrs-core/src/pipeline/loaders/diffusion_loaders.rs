@@ -7,7 +7,6 @@ use std::{
 use anyhow::{Context, Result};
 use candle_core::{Device, Tensor};
 
-use hf_hub::api::sync::ApiRepo;
 use mistralrs_quant::ShardedVarBuilder;
 #[cfg(feature = "pyo3_macros")]
 use pyo3::pyclass;
@@ -28,7 +27,7 @@ use crate::{
         DiffusionGenerationParams,
     },
     paged_attention::AttentionImplementation,
-    pipeline::paths::AdapterPaths,
+    pipeline::paths::{AdapterPaths, HubRepo},
 };
 
 pub trait DiffusionModel {
@@ -44,9 +43,9 @@ pub trait DiffusionModel {
 
 pub trait DiffusionModelLoader: Send + Sync {
     /// If the model is being loaded with `load_model_from_hf` (so manual paths not provided), this will be called.
-    fn get_model_paths(&self, api: &ApiRepo, model_id: &Path) -> Result<Vec<PathBuf>>;
+    fn get_model_paths(&self, api: &HubRepo, model_id: &Path) -> Result<Vec<PathBuf>>;
     /// If the model is being loaded with `load_model_from_hf` (so manual paths not provided), this will be called.
-    fn get_config_filenames(&self, api: &ApiRepo, model_id: &Path) -> Result<Vec<PathBuf>>;
+    fn get_config_filenames(&self, api: &HubRepo, model_id: &Path) -> Result<Vec<PathBuf>>;
     fn force_cpu_vb(&self) -> Vec<bool>;
     // `configs` and `vbs` should be corresponding. It is up to the implementer to maintain this invaraint.
     fn load(
@@ -120,6 +119,9 @@ impl ModelPaths for DiffusionModelPaths {
     fn get_adapter_paths(&self) -> &AdapterPaths {
         unreachable!("Use `std::any::Any`.")
     }
+    fn get_commit_hash(&self) -> Option<&str> {
+        unreachable!("Use `std::any::Any`.")
+    }
 }
 
 // ======================== Flux loader
@@ -132,7 +134,7 @@ pub struct FluxLoader {
 }
 
 impl DiffusionModelLoader for FluxLoader {
-    fn get_model_paths(&self, api: &ApiRepo, model_id: &Path) -> Result<Vec<PathBuf>> {
+    fn get_model_paths(&self, api: &HubRepo, model_id: &Path) -> Result<Vec<PathBuf>> {
         let regex = Regex::new(r"^flux\d+-(schnell|dev)\.safetensors$")?;
         let flux_name = api_dir_list!(api, model_id)
             .filter(|x| regex.is_match(x))
@@ -144,7 +146,7 @@ impl DiffusionModelLoader for FluxLoader {
         // NOTE(EricLBuehler): disgusting way of doing this but the 0th path is the flux, 1 is ae
         Ok(vec![flux_file, ae_file])
     }
-    fn get_config_filenames(&self, api: &ApiRepo, model_id: &Path) -> Result<Vec<PathBuf>> {
+    fn get_config_filenames(&self, api: &HubRepo, model_id: &Path) -> Result<Vec<PathBuf>> {
         let flux_file = api_get_file!(api, "transformer/config.json", model_id);
         let ae_file = api_get_file!(api, "vae/config.json", model_id);
 
@@ -4,7 +4,7 @@ mod vision_loaders;
 
 use std::{
     fmt::{self, Debug, Display},
-    path::PathBuf,
+    path::{Path, PathBuf},
     str::FromStr,
     sync::Arc,
 };
@@ -43,7 +43,10 @@ use crate::{
     TryIntoDType,
 };
 
-use super::{paths::AdapterPaths, Pipeline};
+use super::{
+    paths::{AdapterPaths, LoraAdapterPaths},
+    Pipeline,
+};
 
 /// `ModelPaths` abstracts the mechanism to get all necessary files for running a model. For
 /// example `LocalModelPaths` implements `ModelPaths` when all files are in the local file system.
@@ -80,6 +83,9 @@ pub trait ModelPaths: AsAny + Debug + Send + Sync {
 
     /// Get adapter paths.
     fn get_adapter_paths(&self) -> &AdapterPaths;
+
+    /// Commit of the hub snapshot which the files were resolved to, `None` for local files.
+    fn get_commit_hash(&self) -> Option<&str>;
 }
 
 #[derive(Clone, Debug)]
@@ -94,6 +100,7 @@ pub struct LocalModelPaths<P: Debug> {
     pub preprocessor_config: Option<P>,
     pub processor_config: Option<P>,
     pub chat_template_json_filename: Option<P>,
+    pub commit_hash: Option<String>,
 }
 
 impl<P: Debug> LocalModelPaths<P> {
@@ -108,6 +115,7 @@ impl<P: Debug> LocalModelPaths<P> {
         preprocessor_config: Option<P>,
         processor_config: Option<P>,
         chat_template_json_filename: Option<P>,
+        commit_hash: Option<String>,
     ) -> Self {
         Self {
             tokenizer_filename,
@@ -119,10 +127,107 @@ impl<P: Debug> LocalModelPaths<P> {
             preprocessor_config,
             processor_config,
             chat_template_json_filename,
+            commit_hash,
         }
     }
 }
 
+impl LocalModelPaths<PathBuf> {
+    /// Find the files of a model downloaded or extracted to a local directory.
+    ///
+    /// The weights are the `*.gguf` files or, if there are none, the `*.safetensors` files. A
+    /// `tokenizer.json` and `config.json` are required for safetensors weights but optional for
+    /// GGUF weights, which contain the tokenizer and config. `tokenizer_config.json`,
+    /// `generation_config.json`, `preprocessor_config.json`, `processor_config.json` and
+    /// `chat_template.json` are used if present, and an `adapter_config.json` with an
+    /// `adapter_model.safetensors` is loaded as a LoRA adapter.
+    pub fn try_from_directory(dir: &Path) -> Result<Self> {
+        let entries = std::fs::read_dir(dir)
+            .with_context(|| format!("Could not read model directory `{}`", dir.display()))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let find = |name: &str| {
+            entries
+                .iter()
+                .find(|path| path.is_file() && path.file_name().is_some_and(|f| f == name))
+                .cloned()
+        };
+        let with_extension = |ext: &str| {
+            entries
+                .iter()
+                .filter(|path| {
+                    path.is_file()
+                        && path.extension().is_some_and(|e| e == ext)
+                        && path
+                            .file_name()
+                            .is_some_and(|f| f != "adapter_model.safetensors")
+                })
+                .cloned()
+                .sorted()
+                .collect::<Vec<_>>()
+        };
+
+        let gguf = with_extension("gguf");
+        let is_gguf = !gguf.is_empty();
+        let filenames = if is_gguf {
+            gguf
+        } else {
+            with_extension("safetensors")
+        };
+        if filenames.is_empty() {
+            anyhow::bail!(
+                "No `.gguf` or `.safetensors` weights found in `{}`",
+                dir.display()
+            );
+        }
+
+        // GGUF files contain the tokenizer and config, which are then loaded from the weights.
+        let required = |name: &str| match find(name) {
+            Some(path) => Ok(path),
+            None if is_gguf => Ok(PathBuf::new()),
+            None => Err(anyhow::anyhow!(
+                "No `{name}` found in `{}`, which is required for safetensors weights",
+                dir.display()
+            )),
+        };
+        let tokenizer_filename = required("tokenizer.json")?;
+        let config_filename = required("config.json")?;
+
+        let adapter_paths = match (
+            find("adapter_config.json"),
+            find("adapter_model.safetensors"),
+        ) {
+            (Some(config_path), Some(adapter_path)) => {
+                info!("Loading LoRA adapter at `{}`", adapter_path.display());
+                let lora_config = serde_json::from_str(&std::fs::read_to_string(&config_path)?)
+                    .with_context(|| format!("Invalid `{}`", config_path.display()))?;
+                AdapterPaths::Lora(vec![LoraAdapterPaths {
+                    lora_config,
+                    adapter_path,
+                }])
+            }
+            (Some(_), None) => anyhow::bail!(
+                "Found `adapter_config.json` but no `adapter_model.safetensors` in `{}`",
+                dir.display()
+            ),
+            (None, _) => AdapterPaths::None,
+        };
+
+        Ok(Self {
+            tokenizer_filename,
+            config_filename,
+            template_filename: find("tokenizer_config.json"),
+            filenames,
+            adapter_paths,
+            gen_conf: find("generation_config.json"),
+            preprocessor_config: find("preprocessor_config.json"),
+            processor_config: find("processor_config.json"),
+            chat_template_json_filename: find("chat_template.json"),
+            commit_hash: None,
+        })
+    }
+}
+
 impl ModelPaths for LocalModelPaths<PathBuf> {
     fn get_config_filename(&self) -> &PathBuf {
         &self.config_filename
@@ -151,6 +256,9 @@ impl ModelPaths for LocalModelPaths<PathBuf> {
     fn get_adapter_paths(&self) -> &AdapterPaths {
         &self.adapter_paths
     }
+    fn get_commit_hash(&self) -> Option<&str> {
+        self.commit_hash.as_deref()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -768,3 +876,71 @@ pub trait Loader: Send + Sync {
     fn get_id(&self) -> String;
     fn get_kind(&self) -> ModelKind;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{AdapterPaths, LocalModelPaths};
+
+    #[test]
+    fn paths_from_directory() {
+        let dir = std::env::temp_dir().join("mistralrs_local_model_paths");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let touch = |name: &str, contents: &str| fs::write(dir.join(name), contents).unwrap();
+
+        assert!(LocalModelPaths::try_from_directory(&dir).is_err());
+
+        // GGUF weights do not need a tokenizer or config.
+        touch("model-00002-of-00002.gguf", "");
+        touch("model-00001-of-00002.gguf", "");
+        touch("tokenizer_config.json", "{}");
+        let paths = LocalModelPaths::try_from_directory(&dir).unwrap();
+        assert_eq!(
+            paths.filenames,
+            [
+                dir.join("model-00001-of-00002.gguf"),
+                dir.join("model-00002-of-00002.gguf")
+            ]
+        );
+        assert!(paths.tokenizer_filename.as_os_str().is_empty());
+        assert_eq!(
+            paths.template_filename,
+            Some(dir.join("tokenizer_config.json"))
+        );
+        assert!(paths.gen_conf.is_none());
+        assert!(matches!(paths.adapter_paths, AdapterPaths::None));
+
+        touch("tokenizer.json", "{}");
+        touch(
+            "adapter_config.json",
+            r#"{"r": 8, "lora_alpha": 16, "target_modules": ["q_proj"]}"#,
+        );
+        assert!(LocalModelPaths::try_from_directory(&dir).is_err());
+        touch("adapter_model.safetensors", "");
+        let paths = LocalModelPaths::try_from_directory(&dir).unwrap();
+        assert_eq!(paths.tokenizer_filename, dir.join("tokenizer.json"));
+        let AdapterPaths::Lora(adapters) = &paths.adapter_paths else {
+            panic!("Expected a LoRA adapter");
+        };
+        assert_eq!(adapters[0].lora_config.rank, 8);
+        assert_eq!(
+            adapters[0].adapter_path,
+            dir.join("adapter_model.safetensors")
+        );
+
+        // Safetensors weights need a config, and the adapter is not a weight file.
+        for name in ["model-00001-of-00002.gguf", "model-00002-of-00002.gguf"] {
+            fs::remove_file(dir.join(name)).unwrap();
+        }
+        touch("model.safetensors", "");
+        assert!(LocalModelPaths::try_from_directory(&dir).is_err());
+        touch("config.json", "{}");
+        let paths = LocalModelPaths::try_from_directory(&dir).unwrap();
+        assert_eq!(paths.filenames, [dir.join("model.safetensors")]);
+        assert_eq!(paths.config_filename, dir.join("config.json"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,13 +1,16 @@
 use std::{
     collections::HashMap,
     fmt::{Debug, Display},
+    path::Path,
     str::FromStr,
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 
 use crate::{
     amoe::AnyMoeBaseModelMixin,
     device_map::DeviceMapper,
+    distributed::{DistributedRank, PipelineShard},
+    early_exit::{EarlyExitConfig, EarlyExitStats},
     layers::{Activation, Llama3RopeConfig, PhiRopeScalingConfig},
     lora::{LoraConfig, Ordering},
     paged_attention::{AttentionImplementation, ModelConfigLike, ModelConfigMetadata},
@@ -19,12 +22,13 @@ use crate::{
     serde_default_fn,
     utils::{log::once_log_info, varbuilder_utils::DeviceForLoadTensor},
     xlora_models::NonGranularState,
+    DeviceMapSetting,
 };
 use anyhow::Result;
 use candle_core::{DType, Device, Tensor};
 
 use indicatif::MultiProgress;
-use mistralrs_quant::{QuantizedConfig, ShardedVarBuilder};
+use mistralrs_quant::{QuantizedConfig, ShardedSafeTensors, ShardedVarBuilder};
 #[cfg(feature = "pyo3_macros")]
 use pyo3::pyclass;
 
@@ -62,6 +66,7 @@ pub trait NormalModel: IsqModel + AnyMoeBaseModelMixin {
         position_ids: Vec<usize>,
         flash_params: &FlashParams,
         flash_params_full: &FlashParams,
+        adapter_scalings: Option<Tensor>,
     ) -> candle_core::Result<Tensor>;
     fn is_xlora(&self) -> bool;
     fn device(&self) -> &Device;
@@ -69,6 +74,18 @@ pub trait NormalModel: IsqModel + AnyMoeBaseModelMixin {
     fn cache_mut(&mut self) -> &mut EitherCache;
     fn max_seq_len(&self) -> usize;
     fn config(&self) -> &ModelConfigMetadata;
+    /// Exit from the decoder layers early when the logit lens prediction of an intermediate layer
+    /// is confident enough. Only some models support this.
+    fn set_early_exit(&mut self, early_exit: Option<EarlyExitConfig>) -> candle_core::Result<()> {
+        if early_exit.is_some() {
+            candle_core::bail!("Early exit is not supported for this model.");
+        }
+        Ok(())
+    }
+    /// Depths at which decode steps exited, if early exit is enabled.
+    fn early_exit_stats(&self) -> Option<Arc<Mutex<EarlyExitStats>>> {
+        None
+    }
 }
 
 /// Metadata for loading a model with ISQ or device mapping.
@@ -890,6 +907,38 @@ impl LlamaBasicConfig {
 /// [`NormalLoader`]: https://ericlbuehler.github.io/mistral.rs/mistralrs/struct.NormalLoader.html
 pub struct LlamaLoader;
 
+impl LlamaLoader {
+    /// Load the layers of `rank` of a Llama model for pipeline parallelism across machines, from
+    /// its `config.json` and safetensors files. Only the weights of these layers are read. The
+    /// shard runs on `device` with the eager attention.
+    pub fn load_pipeline_shard<P: AsRef<Path>>(
+        &self,
+        config: &str,
+        weights: &[P],
+        rank: DistributedRank,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Arc<dyn PipelineShard>> {
+        let cfg = LlamaBasicConfig::deserialize(config, false)?;
+        // SAFETY: as for the models loaded by the pipelines, the files must not be modified while
+        // they are mapped.
+        let vb = unsafe { ShardedSafeTensors::sharded(weights, dtype, device, None)? };
+        let metadata = NormalLoadingMetadata {
+            mapper: DeviceMapSetting::dummy().into_mapper(cfg.num_hidden_layers, device, None)?,
+            loading_isq: false,
+            real_device: device.clone(),
+            multi_progress: Arc::new(MultiProgress::new()),
+        };
+        Ok(Arc::new(models::llama::LlamaShard::new(
+            &cfg,
+            vb,
+            self.is_gptx(config)?,
+            rank,
+            metadata,
+        )?))
+    }
+}
+
 impl NormalModelLoader for LlamaLoader {
     fn load(
         &self,
@@ -3325,3 +3374,162 @@ impl DeviceMappedModelLoader for DeepSeekV3Loader {
         Ok(Box::new(cfg))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        fs,
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
+
+    use candle_core::{DType, Device, Tensor};
+    use indicatif::MultiProgress;
+    use mistralrs_quant::ShardedSafeTensors;
+
+    use super::{MistralLoader, MixtralLoader, NormalLoadingMetadata, NormalModelLoader};
+    use crate::{
+        paged_attention::AttentionImplementation,
+        pipeline::text_models_inputs_processor::FlashParams, DeviceMapSetting,
+    };
+
+    const VOCAB: usize = 16;
+    const HIDDEN: usize = 32;
+    const INTERMEDIATE: usize = 64;
+    const N_EXPERTS: usize = 4;
+
+    /// Write a model directory with two layers of random safetensors weights, with a sparse MoE
+    /// instead of the MLP if `moe`.
+    fn write_model(name: &str, moe: bool) -> anyhow::Result<PathBuf> {
+        let dev = Device::Cpu;
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+
+        let mut config = serde_json::json!({
+            "vocab_size": VOCAB,
+            "hidden_size": HIDDEN,
+            "intermediate_size": INTERMEDIATE,
+            "num_hidden_layers": 2,
+            "num_attention_heads": 4,
+            "num_key_value_heads": 2,
+            "hidden_act": "silu",
+            "max_position_embeddings": 64,
+            "rms_norm_eps": 1e-5,
+            "rope_theta": 10000.0,
+            "sliding_window": 4,
+        });
+        if moe {
+            config["num_local_experts"] = N_EXPERTS.into();
+            config["num_experts_per_tok"] = 2.into();
+        }
+        fs::write(dir.join("config.json"), config.to_string())?;
+
+        let mut tensors = HashMap::new();
+        let mut linear = |name: String, out_dim: usize, in_dim: usize| -> anyhow::Result<()> {
+            let w = (Tensor::randn(0f32, 1f32, (out_dim, in_dim), &dev)? * 0.1)?;
+            tensors.insert(name, w);
+            Ok(())
+        };
+        linear("model.embed_tokens.weight".to_string(), VOCAB, HIDDEN)?;
+        linear("lm_head.weight".to_string(), VOCAB, HIDDEN)?;
+        for layer in 0..2 {
+            let prefix = format!("model.layers.{layer}");
+            // 4 heads of dimension 8, and 2 key-value heads.
+            for (proj, out_dim) in [("q", HIDDEN), ("k", 16), ("v", 16), ("o", HIDDEN)] {
+                linear(
+                    format!("{prefix}.self_attn.{proj}_proj.weight"),
+                    out_dim,
+                    HIDDEN,
+                )?;
+            }
+            if moe {
+                linear(
+                    format!("{prefix}.block_sparse_moe.gate.weight"),
+                    N_EXPERTS,
+                    HIDDEN,
+                )?;
+                for expert in 0..N_EXPERTS {
+                    let prefix = format!("{prefix}.block_sparse_moe.experts.{expert}");
+                    linear(format!("{prefix}.w1.weight"), INTERMEDIATE, HIDDEN)?;
+                    linear(format!("{prefix}.w2.weight"), HIDDEN, INTERMEDIATE)?;
+                    linear(format!("{prefix}.w3.weight"), INTERMEDIATE, HIDDEN)?;
+                }
+            } else {
+                linear(
+                    format!("{prefix}.mlp.gate_proj.weight"),
+                    INTERMEDIATE,
+                    HIDDEN,
+                )?;
+                linear(format!("{prefix}.mlp.up_proj.weight"), INTERMEDIATE, HIDDEN)?;
+                linear(
+                    format!("{prefix}.mlp.down_proj.weight"),
+                    HIDDEN,
+                    INTERMEDIATE,
+                )?;
+            }
+        }
+        let mut norm = |name: String| -> anyhow::Result<()> {
+            tensors.insert(name, Tensor::ones(HIDDEN, DType::F32, &dev)?);
+            Ok(())
+        };
+        norm("model.norm.weight".to_string())?;
+        for layer in 0..2 {
+            norm(format!("model.layers.{layer}.input_layernorm.weight"))?;
+            norm(format!(
+                "model.layers.{layer}.post_attention_layernorm.weight"
+            ))?;
+        }
+        candle_core::safetensors::save(&tensors, dir.join("model.safetensors"))?;
+        Ok(dir)
+    }
+
+    /// Load the model in `dir` and generate a token greedily after a prompt longer than the
+    /// sliding window.
+    fn generate_token(loader: &dyn NormalModelLoader, dir: &Path) -> anyhow::Result<u32> {
+        let dev = Device::Cpu;
+        let config = fs::read_to_string(dir.join("config.json"))?;
+        let vb = unsafe {
+            ShardedSafeTensors::sharded(&[dir.join("model.safetensors")], DType::F32, &dev, None)?
+        };
+        let metadata = NormalLoadingMetadata {
+            mapper: DeviceMapSetting::dummy().into_mapper(2, &dev, None)?,
+            loading_isq: false,
+            real_device: dev.clone(),
+            multi_progress: Arc::new(MultiProgress::new()),
+        };
+        let model = loader.load(&config, false, vb, metadata, AttentionImplementation::Eager)?;
+
+        let input_ids = Tensor::new(&[[1u32, 5, 7, 3, 9, 2]], &dev)?;
+        let flash_params = FlashParams {
+            max_q: 0,
+            max_k: 0,
+            cumulative_seqlens_q: HashMap::new(),
+            cumulative_seqlens_k: HashMap::new(),
+        };
+        let logits = model.forward(&input_ids, &[0], vec![(5, 1)], vec![0], None, &flash_params)?;
+        assert_eq!(logits.dims(), [1, 1, VOCAB]);
+        let logits = logits.flatten_all()?;
+        assert!(logits.to_vec1::<f32>()?.iter().all(|x| x.is_finite()));
+        Ok(logits.argmax(0)?.to_scalar::<u32>()?)
+    }
+
+    #[test]
+    fn mistral_from_local_safetensors() -> anyhow::Result<()> {
+        let dir = write_model("mistralrs_mistral_safetensors", false)?;
+        let token = generate_token(&MistralLoader, &dir)?;
+        assert!((token as usize) < VOCAB);
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn mixtral_from_local_safetensors() -> anyhow::Result<()> {
+        let dir = write_model("mistralrs_mixtral_safetensors", true)?;
+        let token = generate_token(&MixtralLoader, &dir)?;
+        assert!((token as usize) < VOCAB);
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
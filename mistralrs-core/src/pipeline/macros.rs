@@ -22,13 +22,7 @@ macro_rules! api_dir_list {
                 .collect::<Vec<String>>()
                 .into_iter()
         } else {
-            $api.info()
-                .map(|repo| {
-                    repo.siblings
-                        .iter()
-                        .map(|x| x.rfilename.clone())
-                        .collect::<Vec<String>>()
-                })
+            $api.list_files()
                 .unwrap_or_else(|e| panic!("Could not get directory listing from API: {:?}", e))
                 .into_iter()
         }
@@ -66,6 +60,9 @@ macro_rules! get_paths {
         $silent:expr,
         $loading_uqff:expr
     ) => {{
+        if let Some(proxy) = &$this.proxy {
+            proxy.validate()?;
+        }
         let api = {
             use $crate::GLOBAL_HF_CACHE;
             let cache = GLOBAL_HF_CACHE.get().cloned().unwrap_or_default();
@@ -78,11 +75,13 @@ macro_rules! get_paths {
             api.build()?
         };
         let revision = $revision.unwrap_or("main".to_string());
-        let api = api.repo(Repo::with_revision(
+        let api = HubRepo::new(
+            &api,
             $this.model_id.clone(),
-            RepoType::Model,
             revision.clone(),
-        ));
+            $token_source,
+        )
+        .with_proxy($this.proxy.clone());
         let model_id = std::path::Path::new(&$this.model_id);
         let tokenizer_filename = if let Some(ref p) = $this.tokenizer_json {
             info!("Using tokenizer.json at `{p}`");
@@ -109,6 +108,7 @@ macro_rules! get_paths {
             &$token_source,
             revision.clone(),
             &$this.xlora_order,
+            &$this.proxy,
         )?;
         let gen_conf = if $crate::api_dir_list!(api, model_id)
             .collect::<Vec<_>>()
@@ -179,6 +179,11 @@ macro_rules! get_paths {
             preprocessor_config,
             processor_config,
             chat_template_json_filename,
+            commit_hash: if model_id.exists() {
+                None
+            } else {
+                api.commit_hash()
+            },
         }))
     }};
 }
@@ -187,19 +192,18 @@ macro_rules! get_paths {
 #[macro_export]
 macro_rules! get_uqff_paths {
     ($from_uqff:expr, $this:expr, $silent:expr) => {{
+        let token_source = $this
+            .token_source
+            .read()
+            .expect("Failed to read token source")
+            .clone()
+            .unwrap_or(TokenSource::None);
         let api = {
             use $crate::GLOBAL_HF_CACHE;
             let cache = GLOBAL_HF_CACHE.get().cloned().unwrap_or_default();
             let mut api = ApiBuilder::from_cache(cache)
                 .with_progress(!$silent)
-                .with_token(get_token(
-                    &$this
-                        .token_source
-                        .read()
-                        .expect("Failed to read token source")
-                        .clone()
-                        .unwrap_or(TokenSource::None),
-                )?);
+                .with_token(get_token(&token_source)?);
             if let Ok(x) = std::env::var("HF_HUB_CACHE") {
                 api = api.with_cache_dir(x.into());
             }
@@ -211,11 +215,8 @@ macro_rules! get_uqff_paths {
             .expect("Failed to read revision")
             .clone()
             .unwrap_or("main".to_string());
-        let api = api.repo(Repo::with_revision(
-            $this.model_id.to_string(),
-            RepoType::Model,
-            revision.clone(),
-        ));
+        let api = HubRepo::new(&api, $this.model_id.to_string(), revision, &token_source)
+            .with_proxy($this.proxy.clone());
 
         let file = $from_uqff.display().to_string();
 
@@ -235,6 +236,9 @@ macro_rules! get_paths_gguf {
         $quantized_filenames:expr,
         $silent:expr
     ) => {{
+        if let Some(proxy) = &$this.proxy {
+            proxy.validate()?;
+        }
         let api = {
             use $crate::GLOBAL_HF_CACHE;
             let cache = GLOBAL_HF_CACHE.get().cloned().unwrap_or_default();
@@ -248,11 +252,8 @@ macro_rules! get_paths_gguf {
         };
         let revision = $revision.unwrap_or("main".to_string());
         let this_model_id = $this.model_id.clone().unwrap_or($this.quantized_model_id.clone());
-        let api = api.repo(Repo::with_revision(
-            this_model_id.clone(),
-            RepoType::Model,
-            revision.clone(),
-        ));
+        let api = HubRepo::new(&api, this_model_id.clone(), revision.clone(), $token_source)
+            .with_proxy($this.proxy.clone());
         let model_id = std::path::Path::new(&this_model_id);
 
         let chat_template = if let Some(ref p) = $this.chat_template {
@@ -293,6 +294,7 @@ macro_rules! get_paths_gguf {
             &$token_source,
             revision.clone(),
             &$this.xlora_order,
+            &$this.proxy,
         )?;
 
         let gen_conf = if $crate::api_dir_list!(api, model_id)
@@ -368,6 +370,11 @@ macro_rules! get_paths_gguf {
             preprocessor_config,
             processor_config,
             chat_template_json_filename,
+            commit_hash: if model_id.exists() {
+                None
+            } else {
+                api.commit_hash()
+            },
         }))
     }};
 }
@@ -551,6 +558,7 @@ macro_rules! xlora_model_loader {
     (
         $paths:expr,
         $dtype:expr,
+        $adapter_dtype:expr,
         $device:expr,
         $layer_devices:expr,
         $config:expr,
@@ -575,6 +583,12 @@ macro_rules! xlora_model_loader {
             unreachable!()
         };
 
+        let adapter_configs = adapter_configs
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|(name, cfg)| (name.clone(), cfg.clone().with_dtype($adapter_dtype)))
+            .collect::<Vec<_>>();
         let mut safetensors_paths = $paths.get_weight_filenames().iter().collect::<Vec<_>>();
         safetensors_paths.push(classifier_path.as_ref().unwrap());
         let get_device_for_tensor =
@@ -604,7 +618,7 @@ macro_rules! xlora_model_loader {
             &$config,
             $use_flash_attn,
             vb,
-            adapter_configs.as_ref().unwrap(),
+            &adapter_configs,
             Some(xlora_config.as_ref().unwrap().clone()),
             xlora_order.as_ref().unwrap().clone(),
             $crate::pipeline::NormalLoadingMetadata {
@@ -624,6 +638,7 @@ macro_rules! lora_model_loader {
     (
         $paths:expr,
         $dtype:expr,
+        $adapter_dtype:expr,
         $device:expr,
         $layer_devices:expr,
         $config:expr,
@@ -676,7 +691,7 @@ macro_rules! lora_model_loader {
             let lora_vb = from_mmaped_safetensors(
                 vec![adapter_path.clone()],
                 Vec::new(),
-                $dtype,
+                Some($adapter_dtype),
                 $device,
                 $layer_devices,
                 $silent,
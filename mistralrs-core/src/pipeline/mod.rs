@@ -1,4 +1,5 @@
 mod amoe;
+mod benchmark;
 mod cache_manager;
 pub mod chat_template;
 mod diffusion;
@@ -10,11 +11,15 @@ pub(crate) mod llg;
 mod loaders;
 mod macros;
 mod normal;
+#[cfg(feature = "onnx")]
+mod onnx;
 mod paths;
 mod processing;
+mod prompt_format;
 mod response;
 mod sampling;
 mod speculative;
+mod throughput;
 mod vision;
 
 pub use super::diffusion_models::DiffusionGenerationParams;
@@ -22,11 +27,14 @@ use crate::amoe::{AnyMoeConfig, AnyMoeExpertType, AnyMoeTrainingInputs, AnyMoeTr
 use crate::device_map::DeviceMapper;
 use crate::paged_attention::{CacheConfig, CacheEngine, ModelConfigLike};
 use crate::prefix_cacher::PrefixCacheManagerV2;
+#[cfg(test)]
+pub(crate) use amoe::new_dummy_seq;
 pub use amoe::{AnyMoeLoader, AnyMoePipeline};
+pub use benchmark::{BenchmarkConfig, BenchmarkResults, BenchmarkRun, ModelBenchmark};
 use chat_template::ChatTemplate;
 pub use diffusion::{DiffusionLoader, DiffusionLoaderBuilder, DiffusionSpecificConfig};
 pub use ggml::{GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig};
-pub use gguf::{GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig};
+pub use gguf::{GGUFLoader, GGUFLoaderBuilder, GGUFPipeline, GGUFSpecificConfig};
 use image::DynamicImage;
 pub use inputs_processor::InputProcessorOutput;
 pub(crate) use isq::IsqModelLoader;
@@ -43,25 +51,35 @@ pub use loaders::{
 };
 use mistralrs_quant::IsqType;
 pub use normal::{NormalLoader, NormalLoaderBuilder, NormalSpecificConfig};
+#[cfg(feature = "onnx")]
+pub use onnx::{OnnxLoader, OnnxLoaderBuilder, OnnxPipeline, OnnxSpecificConfig};
 pub(crate) use paths::{
-    get_chat_template, get_model_paths, get_xlora_paths, AdapterPaths, LoraAdapterPaths,
+    get_chat_template, get_model_paths, get_xlora_paths, AdapterPaths, HubRepo, LoraAdapterPaths,
 };
+pub use paths::{HubAccessError, HubAccessKind};
 pub(crate) use processing::{
-    apply_chat_template, BasicProcessor, MessagesAction, Processor, ProcessorCreator,
+    apply_chat_template, render_chat_template, BasicProcessor, MessagesAction, Processor,
+    ProcessorCreator,
 };
+pub use prompt_format::{PromptFormat, PromptFormatDetection};
 use rand_isaac::Isaac64Rng;
+pub(crate) use speculative::DraftLength;
 pub use speculative::{SpeculativeConfig, SpeculativeLoader, SpeculativePipeline};
 use std::any::Any;
 use std::collections::HashMap;
 use std::num::NonZeroUsize;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
+pub use throughput::{ThroughputEstimate, ThroughputTracker, DEFAULT_THROUGHPUT_EMA_ALPHA};
 use tokenizers::Tokenizer;
 pub use vision::{VisionLoader, VisionLoaderBuilder, VisionSpecificConfig};
 
 use anyhow::Result;
 use candle_core::{DType, Device, IndexOp, Tensor, Var};
 
+use crate::early_exit::EarlyExitStats;
+use crate::response::SpeculativeStats;
+use crate::sampler::SamplingDefaults;
 use crate::sequence::Sequence;
 
 pub use self::cache_manager::{
@@ -91,8 +109,22 @@ pub struct GeneralMetadata {
     pub cache_engine: Option<CacheEngine>,
     pub prompt_chunksize: Option<NonZeroUsize>,
     pub model_metadata: Option<Arc<dyn ModelConfigLike + Send + Sync>>,
+    /// Throughput of recent generations, updated as sequences finish.
+    pub throughput: Arc<ThroughputTracker>,
+    /// Prompt format detected from the tokenizer, compared with the chat template.
+    pub prompt_format: Option<PromptFormatDetection>,
+    /// Fingerprint of the weights, quantization and adapters, sent as the `system_fingerprint` of
+    /// responses.
+    pub system_fingerprint: String,
+    /// Sampling defaults from the `generation_config.json` of the model.
+    pub generation_defaults: SamplingDefaults,
+    /// Names of the LoRA adapters which requests may blend, in the order of their scalings.
+    pub lora_adapters: Vec<String>,
+    /// Commit of the hub snapshot serving the model, `None` for local files.
+    pub commit_hash: Option<String>,
 }
 
+#[derive(Clone, Copy)]
 pub enum CacheInstruction {
     In,
     Out,
@@ -148,6 +180,18 @@ pub trait MetadataMixin {
     fn reset_non_granular_state(&self);
     fn get_metadata(&self) -> Arc<GeneralMetadata>;
     fn device_mapper(&self) -> Option<&dyn DeviceMapper>;
+    /// Acceptance of the draft tokens of all sequences, for speculative pipelines.
+    fn speculative_stats(&self) -> Option<Arc<Mutex<SpeculativeStats>>> {
+        None
+    }
+    /// Depths at which decode steps exited, if the model runs with early exit.
+    fn early_exit_stats(&self) -> Option<Arc<Mutex<EarlyExitStats>>> {
+        None
+    }
+    /// Moving average of the prompt and decode throughput of recent generations.
+    fn throughput_estimate(&self) -> ThroughputEstimate {
+        self.get_metadata().throughput.estimate()
+    }
 }
 
 /// Implemented by the base model of an AnyMoe.
@@ -690,6 +734,8 @@ mod tests {
                 Some(eos.to_string()),
                 Some(unk.to_string()),
                 Vec::new(),
+                None,
+                Vec::new(),
             ) {
                 Ok(v) => v,
                 Err(e) => {
@@ -884,4 +930,43 @@ mod tests {
 
         test_with_inputs(&templates, &expected_outputs, inputs);
     }
+
+    #[test]
+    fn enable_thinking() {
+        use super::chat_template::{apply_chat_template_to, ChatTemplateValue};
+
+        // Thinking is on by default, as in the Qwen 3 template.
+        let default_on = "{% for message in messages %}{{ '<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>\n' }}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% if enable_thinking is defined and enable_thinking is false %}{{ '<think>\n\n</think>\n\n' }}{% endif %}{% endif %}";
+        // Thinking is off by default.
+        let default_off = "{% for message in messages %}{{ message['content'] }}{% endfor %}{% if enable_thinking is defined and enable_thinking %}{{ ' /think' }}{% else %}{{ ' /no_think' }}{% endif %}";
+
+        let messages = vec![IndexMap::from([
+            ("role".to_string(), Either::Left("user".to_string())),
+            ("content".to_string(), Either::Left("Hello".to_string())),
+        ])];
+        let render = |template: &str, enable_thinking| {
+            apply_chat_template_to(
+                messages.clone(),
+                true,
+                &ChatTemplateValue(Either::Left(template.to_string())),
+                None,
+                None,
+                None,
+                Vec::new(),
+                enable_thinking,
+                Vec::new(),
+            )
+            .unwrap()
+        };
+
+        let thinking = "<|im_start|>user\nHello<|im_end|>\n<|im_start|>assistant\n";
+        let not_thinking = format!("{thinking}<think>\n\n</think>\n\n");
+        assert_eq!(render(default_on, None), thinking);
+        assert_eq!(render(default_on, Some(true)), thinking);
+        assert_eq!(render(default_on, Some(false)), not_thinking);
+
+        assert_eq!(render(default_off, None), "Hello /no_think");
+        assert_eq!(render(default_off, Some(true)), "Hello /think");
+        assert_eq!(render(default_off, Some(false)), "Hello /no_think");
+    }
 }
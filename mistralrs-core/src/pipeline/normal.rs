@@ -1,11 +1,11 @@
 use super::cache_manager::{FullCacheManager, NormalCacheManager};
 use super::inputs_processor::DEFAULT_PROMPT_CHUNK_SIZE;
 use super::isq::ImatrixDataSource;
-use super::llg::build_tok_env;
+use super::llg::build_or_reuse_tok_env;
 use super::{
     get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, NormalModel, NormalModelLoader,
-    TokenSource,
+    CacheManager, GeneralMetadata, HubRepo, Loader, ModelKind, ModelPaths, NormalModel,
+    NormalModelLoader, ThroughputTracker, TokenSource,
 };
 use super::{
     AnyMoePipelineMixin, CacheManagerMixin, EitherCache, ForwardInputsResult, IsqOrganization,
@@ -19,6 +19,8 @@ use super::{
 use crate::amoe::AnyMoeExpertType;
 use crate::device_map::{self, DeviceMapper};
 use crate::distributed::{self, WorkerTransferData};
+use crate::early_exit::{EarlyExitConfig, EarlyExitStats};
+use crate::fingerprint::model_fingerprint;
 use crate::lora::Ordering;
 use crate::paged_attention::{calculate_cache_config, AttentionImplementation, CacheEngine};
 use crate::pipeline::chat_template::{calculate_eos_tokens, GenerationConfig};
@@ -29,7 +31,9 @@ use crate::pipeline::text_models_inputs_processor::make_prompt_chunk;
 use crate::pipeline::{ChatTemplate, LocalModelPaths};
 use crate::prefix_cacher::PrefixCacheManagerV2;
 use crate::sequence::Sequence;
-use crate::utils::tokenizer::get_tokenizer;
+use crate::utils::hub_proxy::HubProxy;
+use crate::utils::normal::adapter_dtype;
+use crate::utils::tokenizer::{get_tokenizer, set_byte_fallback};
 use crate::utils::varbuilder_utils::DeviceForLoadTensor;
 use crate::utils::{tokens::get_token, varbuilder_utils::from_mmaped_safetensors};
 use crate::xlora_models::NonGranularState;
@@ -40,9 +44,10 @@ use crate::{
 };
 use anyhow::Result;
 use candle_core::{Device, Tensor, Var};
+use hf_hub::api::sync::ApiBuilder;
 use hf_hub::Cache;
-use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
 use indicatif::MultiProgress;
+use llguidance::toktrie::TokEnv;
 use mistralrs_quant::{AfqLayer, GgufMatMul, HqqLayer, IsqType, QuantizedSerdeType};
 use rand_isaac::Isaac64Rng;
 use regex_automata::meta::Regex;
@@ -95,6 +100,7 @@ pub struct NormalLoader {
     from_uqff: RwLock<Option<PathBuf>>,
     jinja_explicit: Option<String>,
     hf_cache_path: Option<PathBuf>,
+    proxy: Option<HubProxy>,
 }
 
 #[derive(Default)]
@@ -112,6 +118,7 @@ pub struct NormalLoaderBuilder {
     tgt_non_granular_index: Option<usize>,
     jinja_explicit: Option<String>,
     hf_cache_path: Option<PathBuf>,
+    proxy: Option<HubProxy>,
 }
 
 #[derive(Clone, Default)]
@@ -126,6 +133,13 @@ pub struct NormalSpecificConfig {
     pub imatrix: Option<PathBuf>,
     pub calibration_file: Option<PathBuf>,
     pub hf_cache_path: Option<PathBuf>,
+    /// Exit from the decoder layers early on confident tokens, for models which support it.
+    pub early_exit: Option<EarlyExitConfig>,
+    /// Force byte fallback of the tokenizer on or off, overriding the tokenizer's default.
+    pub byte_fallback: Option<bool>,
+    /// Token environment used instead of building one from the tokenizer, such as one shared by
+    /// the models of a vocabulary. Its tokens must be those of the tokenizer.
+    pub tok_env: Option<TokEnv>,
 }
 
 impl NormalLoaderBuilder {
@@ -203,6 +217,13 @@ impl NormalLoaderBuilder {
         self
     }
 
+    /// Download the model from the Hugging Face Hub through an HTTP proxy, optionally
+    /// authenticating with a user and password, for networks without direct internet access.
+    pub fn with_proxy(mut self, proxy_url: &str, proxy_auth: Option<(&str, &str)>) -> Self {
+        self.proxy = Some(HubProxy::new(proxy_url, proxy_auth));
+        self
+    }
+
     /// If the loader type is not specified, loader type is automatically determined from the
     /// `architectures` array in the config.
     pub fn build(self, loader_tp: Option<NormalLoaderType>) -> anyhow::Result<Box<dyn Loader>> {
@@ -238,6 +259,7 @@ impl NormalLoaderBuilder {
             revision: RwLock::new(None),
             from_uqff: RwLock::new(None),
             hf_cache_path: self.hf_cache_path,
+            proxy: self.proxy,
         }))
     }
 }
@@ -446,6 +468,15 @@ impl Loader for NormalLoader {
             layer_devices.push(device);
         }
         let dtype = mapper.get_min_dtype(dtype)?;
+        // Only the adapter weights are converted if the devices do not support the dtype natively.
+        let adapter_dtype = if self.kind.is_adapted() {
+            adapter_dtype(
+                dtype,
+                &mapper.get_unique_devices().iter().collect::<Vec<_>>(),
+            )?
+        } else {
+            dtype
+        };
 
         // TODO: PagedAttention is not supported with CPU for now.
         // This check is not really necessary because `get_device_layers` should prevent it.
@@ -525,6 +556,7 @@ impl Loader for NormalLoader {
                 } => xlora_model_loader!(
                     paths,
                     Some(dtype),
+                    adapter_dtype,
                     &load_device,
                     layer_devices.clone(),
                     config,
@@ -541,6 +573,7 @@ impl Loader for NormalLoader {
                 } => lora_model_loader!(
                     paths,
                     Some(dtype),
+                    adapter_dtype,
                     &load_device,
                     layer_devices.clone(),
                     config,
@@ -581,6 +614,7 @@ impl Loader for NormalLoader {
                 } => xlora_model_loader!(
                     paths,
                     Some(dtype),
+                    adapter_dtype,
                     &load_device,
                     layer_devices.clone(),
                     config,
@@ -597,6 +631,7 @@ impl Loader for NormalLoader {
                 } => lora_model_loader!(
                     paths,
                     Some(dtype),
+                    adapter_dtype,
                     &load_device,
                     layer_devices.clone(),
                     config,
@@ -615,7 +650,14 @@ impl Loader for NormalLoader {
             }
         };
 
-        let tokenizer = get_tokenizer(paths.get_tokenizer_filename(), None)?;
+        if let Some(early_exit) = self.config.early_exit {
+            model.set_early_exit(Some(early_exit))?;
+        }
+
+        let tokenizer = set_byte_fallback(
+            get_tokenizer(paths.get_tokenizer_filename(), None)?,
+            self.config.byte_fallback,
+        )?;
         let gen_conf: Option<GenerationConfig> = paths.get_gen_conf_filename().map(|f| {
             serde_json::from_str(&fs::read_to_string(f).unwrap())
                 .expect("bos_token_id/eos_token_id missing in generation_config.json")
@@ -805,20 +847,33 @@ impl Loader for NormalLoader {
         };
 
         let max_seq_len = model.max_seq_len();
-        let tok_env = build_tok_env(tokenizer.clone());
+        let tok_env = build_or_reuse_tok_env(tokenizer.clone(), self.config.tok_env.clone())?;
         let num_hidden_layers = match model.cache() {
             EitherCache::Full(full) => full.lock().len(),
             EitherCache::Normal(normal) => normal.lock().unwrap().0.len(),
         };
+        let generation_defaults = gen_conf
+            .as_ref()
+            .map(GenerationConfig::sampling_defaults)
+            .unwrap_or_default();
         let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
         let sliding_window = model.config().sliding_window;
         let model_metadata = Arc::new(model.config().clone());
+        // The adapters of X-LoRA models are weighted by their classifier.
+        let lora_adapters = match &self.xlora_order {
+            Some(order) if !is_xlora => order.adapters.clone().unwrap_or_default(),
+            _ => Vec::new(),
+        };
+        let system_fingerprint = model_fingerprint(
+            paths.as_ref(),
+            in_situ_quant.map(|isq| format!("{isq:?}")).as_deref(),
+        );
 
         Ok(Arc::new(Mutex::new(NormalPipeline {
             model,
             tokenizer: tokenizer.into(),
             no_kv_cache: self.no_kv_cache,
-            chat_template: Arc::new(chat_template),
+            chat_template: Arc::new(chat_template.with_detected_system_role()),
             non_granular_state: self.tgt_non_granular_index.map(|tgt_non_granular_index| {
                 NonGranularState {
                     non_granular_index: Arc::new(Mutex::new(0)),
@@ -841,6 +896,12 @@ impl Loader for NormalLoader {
                 cache_engine,
                 prompt_chunksize: Some(NonZero::new(prompt_chunksize).unwrap()),
                 model_metadata: Some(model_metadata),
+                throughput: Arc::new(ThroughputTracker::default()),
+                prompt_format: None,
+                system_fingerprint,
+                generation_defaults,
+                lora_adapters,
+                commit_hash: paths.get_commit_hash().map(str::to_string),
             }),
             topology: self.config.topology.clone(),
             silent,
@@ -960,6 +1021,9 @@ impl MetadataMixin for NormalPipeline {
     fn device_mapper(&self) -> Option<&dyn DeviceMapper> {
         Some(&*self.mapper)
     }
+    fn early_exit_stats(&self) -> Option<Arc<std::sync::Mutex<EarlyExitStats>>> {
+        self.model.early_exit_stats()
+    }
 }
 
 #[async_trait::async_trait]
@@ -979,6 +1043,7 @@ impl Pipeline for NormalPipeline {
             paged_attn_meta,
             flash_meta,
             flash_meta_full,
+            adapter_scalings,
         } = *inputs.downcast().expect("Downcast failed.");
         let metadata = self.get_metadata();
         let paged_attn_meta = match (&metadata.cache_engine, &paged_attn_meta) {
@@ -1021,6 +1086,7 @@ impl Pipeline for NormalPipeline {
                     position_ids,
                     &flash_meta,
                     flash_meta_full.as_ref().unwrap_or(&flash_meta),
+                    adapter_scalings,
                 ),
             }
         })?;
@@ -1051,6 +1117,7 @@ impl Pipeline for NormalPipeline {
                 position_ids,
                 &flash_meta,
                 flash_meta_full.as_ref().unwrap_or(&flash_meta),
+                adapter_scalings,
             )?,
         };
         if return_raw_logits {
@@ -1120,11 +1187,7 @@ impl AnyMoePipelineMixin for NormalPipeline {
                 api.build().map_err(candle_core::Error::msg)?
             };
             let revision = revision.clone().unwrap_or("main".to_string());
-            let api = api.repo(Repo::with_revision(
-                model_id_str.clone(),
-                RepoType::Model,
-                revision.clone(),
-            ));
+            let api = HubRepo::new(&api, model_id_str.clone(), revision, token);
 
             let mut filenames = vec![];
             for rfilename in api_dir_list!(api, model_id).filter(|x| x.ends_with(".safetensors")) {
@@ -1176,11 +1239,7 @@ impl AnyMoePipelineMixin for NormalPipeline {
                 api.build().map_err(candle_core::Error::msg)?
             };
             let revision = revision.clone().unwrap_or("main".to_string());
-            let api = api.repo(Repo::with_revision(
-                model_id_str.clone(),
-                RepoType::Model,
-                revision.clone(),
-            ));
+            let api = HubRepo::new(&api, model_id_str.clone(), revision, token);
 
             let mut gate_filenames = vec![];
             for rfilename in api_dir_list!(api, model_id).filter(|x| x.ends_with(".safetensors")) {
@@ -0,0 +1,449 @@
+use super::cache_manager::FullCacheManager;
+use super::llg::build_or_reuse_tok_env;
+use super::{
+    text_models_inputs_processor::ModelInputs, AdapterPaths, Cache, CacheManager, GeneralMetadata,
+    LayerCaches, Loader, ModelKind, ModelPaths, ThroughputTracker, TokenSource,
+};
+use super::{
+    AnyMoePipelineMixin, CacheManagerMixin, EitherCache, ForwardInputsResult, IsqPipelineMixin,
+    MetadataMixin, ModelCategory, PreProcessingMixin,
+};
+use crate::device_map::DeviceMapper;
+use crate::fingerprint::model_fingerprint;
+use crate::pipeline::chat_template::{calculate_eos_tokens, GenerationConfig};
+use crate::pipeline::get_chat_template;
+use crate::pipeline::sampling::sample_and_add_toks;
+use crate::pipeline::{ChatTemplate, LocalModelPaths};
+use crate::prefix_cacher::PrefixCacheManagerV2;
+use crate::sequence::Sequence;
+use crate::utils::debug::DeviceRepr;
+use crate::utils::tokens::get_token;
+use crate::{DeviceMapSetting, PagedAttentionConfig, Pipeline, TryIntoDType};
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+pub use mistralrs_onnx::OnnxSpecificConfig;
+use mistralrs_onnx::{OnnxKvCache, OnnxModelConfig, OnnxModelPaths};
+use mistralrs_quant::IsqType;
+use rand_isaac::Isaac64Rng;
+use std::any::Any;
+use std::fs;
+use std::sync::Arc;
+use tokenizers::Tokenizer;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// A pipeline for a model exported to ONNX, run on the CPU by ONNX Runtime.
+pub struct OnnxPipeline {
+    model: mistralrs_onnx::OnnxPipeline,
+    tokenizer: Arc<Tokenizer>,
+    chat_template: Arc<ChatTemplate>,
+    model_id: String,
+    cache: EitherCache,
+    metadata: Arc<GeneralMetadata>,
+}
+
+/// A loader for a Mistral or Llama model exported to ONNX.
+pub struct OnnxLoader {
+    inner: mistralrs_onnx::OnnxLoader,
+    model_id: String,
+    chat_template: Option<String>,
+    jinja_explicit: Option<String>,
+}
+
+/// A builder for an ONNX loader.
+pub struct OnnxLoaderBuilder {
+    model_id: String,
+    config: OnnxSpecificConfig,
+    onnx_file: Option<String>,
+    chat_template: Option<String>,
+    tokenizer_json: Option<String>,
+    jinja_explicit: Option<String>,
+}
+
+impl OnnxLoaderBuilder {
+    /// `onnx_file` is the path of the graph inside the model, which defaults to `onnx/model.onnx`.
+    pub fn new(
+        config: OnnxSpecificConfig,
+        chat_template: Option<String>,
+        tokenizer_json: Option<String>,
+        model_id: String,
+        onnx_file: Option<String>,
+        jinja_explicit: Option<String>,
+    ) -> Self {
+        Self {
+            model_id,
+            config,
+            onnx_file,
+            chat_template,
+            tokenizer_json,
+            jinja_explicit,
+        }
+    }
+
+    pub fn build(self) -> Box<dyn Loader> {
+        let mut inner = mistralrs_onnx::OnnxLoaderBuilder::new(self.config, self.model_id.clone());
+        if let Some(onnx_file) = self.onnx_file {
+            inner = inner.with_onnx_file(onnx_file);
+        }
+        if let Some(tokenizer_json) = self.tokenizer_json {
+            inner = inner.with_tokenizer_json(tokenizer_json);
+        }
+        Box::new(OnnxLoader {
+            inner: inner.build(),
+            model_id: self.model_id,
+            chat_template: self.chat_template,
+            jinja_explicit: self.jinja_explicit,
+        })
+    }
+}
+
+impl Loader for OnnxLoader {
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_hf(
+        &self,
+        revision: Option<String>,
+        token_source: TokenSource,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapSetting,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
+        let paths = self.inner.get_paths(revision, get_token(&token_source)?)?;
+        let paths: Box<dyn ModelPaths> = Box::new(LocalModelPaths {
+            tokenizer_filename: paths.tokenizer_file,
+            config_filename: paths.config_file,
+            template_filename: paths.template_file,
+            filenames: vec![paths.onnx_file],
+            adapter_paths: AdapterPaths::None,
+            gen_conf: paths.gen_conf_file,
+            preprocessor_config: None,
+            processor_config: None,
+            chat_template_json_filename: None,
+            commit_hash: None,
+        });
+        self.load_model_from_path(
+            &paths,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+            paged_attn_config,
+        )
+    }
+
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_path(
+        &self,
+        paths: &Box<dyn ModelPaths>,
+        _dtype: &dyn TryIntoDType,
+        device: &Device,
+        _silent: bool,
+        mapper: DeviceMapSetting,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
+        if matches!(mapper, DeviceMapSetting::Map(_)) {
+            anyhow::bail!("Device mapping is not supported for ONNX models.")
+        }
+        if in_situ_quant.is_some() {
+            anyhow::bail!(
+                "You are trying to in-situ quantize an ONNX model. This is not supported."
+            )
+        }
+        if paged_attn_config.is_some() {
+            warn!("PagedAttention is not supported for ONNX models, disabling it.");
+        }
+        if !device.is_cpu() {
+            warn!(
+                "ONNX models run on the CPU with ONNX Runtime, ignoring {}.",
+                device.device_pretty_repr()
+            );
+        }
+
+        info!("Loading ONNX model `{}`.", self.get_id());
+
+        let model = self.inner.load_model_from_path(&OnnxModelPaths {
+            onnx_file: paths.get_weight_filenames()[0].clone(),
+            config_file: paths.get_config_filename().clone(),
+            tokenizer_file: paths.get_tokenizer_filename().clone(),
+            template_file: paths.get_template_filename().clone(),
+            gen_conf_file: paths.get_gen_conf_filename().cloned(),
+        })?;
+
+        let tokenizer = model.tokenizer().clone();
+        let gen_conf: Option<GenerationConfig> = paths.get_gen_conf_filename().map(|f| {
+            serde_json::from_str(&fs::read_to_string(f).unwrap())
+                .expect("bos_token_id/eos_token_id missing in generation_config.json")
+        });
+        let chat_template = get_chat_template(
+            paths,
+            &self.jinja_explicit,
+            &paths
+                .get_chat_template_explicit()
+                .as_ref()
+                .map(|x| x.to_string_lossy().to_string())
+                .clone(),
+            &self.chat_template,
+            None,
+        );
+
+        let tok_env = build_or_reuse_tok_env(tokenizer.clone(), None)?;
+        let generation_defaults = gen_conf
+            .as_ref()
+            .map(GenerationConfig::sampling_defaults)
+            .unwrap_or_default();
+        let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
+        let num_hidden_layers = model.config().num_hidden_layers;
+        let max_seq_len = model.config().max_position_embeddings;
+        // Without past key values, every step runs the whole sequence.
+        let no_kv_cache = !model.has_past();
+        let system_fingerprint = model_fingerprint(paths.as_ref(), None);
+        Ok(Arc::new(Mutex::new(OnnxPipeline {
+            model,
+            tokenizer: tokenizer.into(),
+            chat_template: Arc::new(chat_template.with_detected_system_role()),
+            model_id: self.model_id.clone(),
+            cache: EitherCache::Full(Cache::new(num_hidden_layers, false)),
+            metadata: Arc::new(GeneralMetadata {
+                max_seq_len,
+                tok_env: Some(tok_env),
+                no_kv_cache,
+                // The prefix cache only restores normal caches.
+                no_prefix_cache: true,
+                num_hidden_layers,
+                eos_tok: eos,
+                kind: ModelKind::Normal,
+                is_xlora: false,
+                activation_dtype: DType::F32,
+                sliding_window: None,
+                cache_config: None,
+                cache_engine: None,
+                prompt_chunksize: None,
+                model_metadata: None,
+                throughput: Arc::new(ThroughputTracker::default()),
+                prompt_format: None,
+                system_fingerprint,
+                generation_defaults,
+                lora_adapters: Vec::new(),
+                commit_hash: paths.get_commit_hash().map(str::to_string),
+            }),
+        })))
+    }
+
+    fn get_id(&self) -> String {
+        self.model_id.clone()
+    }
+
+    fn get_kind(&self) -> ModelKind {
+        ModelKind::Normal
+    }
+}
+
+/// The ONNX cache of row `row` of the batched cache, or an empty cache for a new sequence.
+fn row_cache(cache: &LayerCaches, row: usize, cfg: &OnnxModelConfig) -> Result<OnnxKvCache> {
+    let Some(Some((k, _))) = cache.first() else {
+        return Ok(OnnxKvCache::new(cfg));
+    };
+    let seq_len = k.dim(2)?;
+    let layers = cache
+        .iter()
+        .map(|layer| {
+            let (k, v) = layer
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("Missing the cache of a layer."))?;
+            Ok((
+                k.get(row)?.flatten_all()?.to_vec1::<f32>()?,
+                v.get(row)?.flatten_all()?.to_vec1::<f32>()?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    OnnxKvCache::from_flat(cfg, layers, seq_len)
+}
+
+/// Batch the ONNX caches of the rows into one cache of shape
+/// (batch, num_kv_heads, seq_len, head_dim) per layer.
+fn merge_row_caches(rows: &[OnnxKvCache], cfg: &OnnxModelConfig) -> Result<LayerCaches> {
+    let rows = rows
+        .iter()
+        .map(|row| (row.seq_len(), row.to_flat()))
+        .collect::<Vec<_>>();
+    (0..cfg.num_hidden_layers)
+        .map(|layer| {
+            let mut ks = Vec::new();
+            let mut vs = Vec::new();
+            for (seq_len, layers) in &rows {
+                let shape = (1, cfg.num_kv_heads(), *seq_len, cfg.head_dim());
+                let (k, v) = &layers[layer];
+                ks.push(Tensor::from_slice(k, shape, &Device::Cpu)?);
+                vs.push(Tensor::from_slice(v, shape, &Device::Cpu)?);
+            }
+            Ok(Some((Tensor::cat(&ks, 0)?, Tensor::cat(&vs, 0)?)))
+        })
+        .collect()
+}
+
+impl PreProcessingMixin for OnnxPipeline {
+    fn get_chat_template(&self) -> Option<Arc<ChatTemplate>> {
+        Some(self.chat_template.clone())
+    }
+    fn get_input_processor_config(&self) -> Option<Arc<dyn Any>> {
+        None
+    }
+}
+
+impl IsqPipelineMixin for OnnxPipeline {
+    fn re_isq_model(&mut self, _dtype: IsqType) -> Result<()> {
+        anyhow::bail!("You are trying to in-situ requantize an ONNX model. This is not supported.")
+    }
+}
+
+impl CacheManagerMixin for OnnxPipeline {
+    fn clone_in_cache(&self, seqs: &mut [&mut Sequence]) {
+        FullCacheManager.clone_in_cache(self, seqs, false)
+    }
+    fn clone_out_cache(&self, seqs: &mut [&mut Sequence]) {
+        FullCacheManager.clone_out_cache(self, seqs, false)
+    }
+    fn set_none_cache(
+        &self,
+        seqs: &mut [&mut Sequence],
+        _reset_non_granular: bool,
+        modify_draft_cache: bool,
+        load_preallocated_cache: bool,
+    ) {
+        FullCacheManager.set_none_cache(self, seqs, modify_draft_cache, load_preallocated_cache);
+    }
+    fn cache(&self) -> &EitherCache {
+        &self.cache
+    }
+}
+
+impl MetadataMixin for OnnxPipeline {
+    fn device(&self) -> Device {
+        Device::Cpu
+    }
+    fn tokenizer(&self) -> Option<Arc<Tokenizer>> {
+        Some(self.tokenizer.clone())
+    }
+    fn name(&self) -> String {
+        self.model_id.clone()
+    }
+    fn reset_non_granular_state(&self) {}
+    fn get_metadata(&self) -> Arc<GeneralMetadata> {
+        self.metadata.clone()
+    }
+    fn device_mapper(&self) -> Option<&dyn DeviceMapper> {
+        None
+    }
+}
+
+#[async_trait::async_trait]
+impl Pipeline for OnnxPipeline {
+    fn forward_inputs(
+        &mut self,
+        inputs: Box<dyn Any>,
+        return_raw_logits: bool,
+    ) -> Result<ForwardInputsResult, candle_core::Error> {
+        let ModelInputs {
+            input_ids,
+            context_lens,
+            ..
+        } = *inputs.downcast().expect("Downcast failed.");
+        if return_raw_logits {
+            candle_core::bail!("ONNX models only return the logits of the last position.");
+        }
+        let cfg = self.model.config();
+        let mut cache = self.cache.full().lock();
+        let mut logits = Vec::new();
+        let mut rows = Vec::new();
+        for (row, (ids, (start, len))) in input_ids
+            .to_vec2::<u32>()?
+            .into_iter()
+            .zip(context_lens)
+            .enumerate()
+        {
+            // Prompts are padded on the right, so the last logit is at the end of the tokens.
+            let ids = &ids[..start + len];
+            let mut seq_cache = row_cache(&cache, row, cfg).map_err(candle_core::Error::msg)?;
+            let row_logits = self
+                .model
+                .forward(ids, &mut seq_cache)
+                .map_err(candle_core::Error::msg)?;
+            let vocab_size = row_logits.len();
+            logits.push(Tensor::from_vec(row_logits, (1, vocab_size), &Device::Cpu)?);
+            rows.push(seq_cache);
+        }
+        if self.model.has_past() {
+            *cache = merge_row_caches(&rows, cfg).map_err(candle_core::Error::msg)?;
+        }
+        Ok(ForwardInputsResult::CausalGeneration {
+            logits: Tensor::stack(&logits, 0)?,
+        })
+    }
+    async fn sample_causal_gen(
+        &self,
+        seqs: &mut [&mut Sequence],
+        logits: Vec<Tensor>,
+        prefix_cacher: &mut PrefixCacheManagerV2,
+        disable_eos_stop: bool,
+        rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+    ) -> Result<(), candle_core::Error> {
+        sample_and_add_toks(self, seqs, logits, prefix_cacher, disable_eos_stop, rng).await
+    }
+    fn category(&self) -> ModelCategory {
+        ModelCategory::Text
+    }
+}
+
+impl AnyMoePipelineMixin for OnnxPipeline {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> OnnxModelConfig {
+        serde_json::from_value(serde_json::json!({
+            "num_hidden_layers": 2,
+            "num_attention_heads": 4,
+            "num_key_value_heads": 2,
+            "hidden_size": 8,
+            "max_position_embeddings": 16,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn row_caches_round_trip_through_the_batched_cache() -> Result<()> {
+        let cfg = config();
+        assert_eq!(row_cache(&vec![None; 2], 0, &cfg)?.seq_len(), 0);
+
+        let shape = (3, cfg.num_kv_heads(), 5, cfg.head_dim());
+        let layer = |offset: f32| -> Result<Option<(Tensor, Tensor)>> {
+            let k = (Tensor::arange(0f32, 60., &Device::Cpu)? + offset as f64)?.reshape(shape)?;
+            Ok(Some((k.clone(), (k + 100.)?)))
+        };
+        let cache = vec![layer(0.)?, layer(1000.)?];
+
+        let rows = (0..3)
+            .map(|row| row_cache(&cache, row, &cfg))
+            .collect::<Result<Vec<_>>>()?;
+        assert!(rows.iter().all(|row| row.seq_len() == 5));
+        let merged = merge_row_caches(&rows, &cfg)?;
+        for (merged, original) in merged.iter().zip(&cache) {
+            let ((mk, mv), (k, v)) = (merged.as_ref().unwrap(), original.as_ref().unwrap());
+            assert_eq!(
+                mk.flatten_all()?.to_vec1::<f32>()?,
+                k.flatten_all()?.to_vec1::<f32>()?
+            );
+            assert_eq!(
+                mv.flatten_all()?.to_vec1::<f32>()?,
+                v.flatten_all()?.to_vec1::<f32>()?
+            );
+            assert_eq!(mk.dims(), k.dims());
+        }
+        Ok(())
+    }
+}
@@ -1,14 +1,17 @@
 use std::{
     collections::HashMap,
-    fs,
+    fmt, fs,
     path::{Path, PathBuf},
+    sync::{mpsc, OnceLock},
+    thread,
+    time::Duration,
 };
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use either::Either;
 use hf_hub::{
-    api::sync::{ApiBuilder, ApiRepo},
-    Repo, RepoType,
+    api::sync::{Api, ApiBuilder, ApiError},
+    Cache, Repo, RepoType,
 };
 use regex_automata::meta::Regex;
 use serde_json::Value;
@@ -21,7 +24,10 @@ use crate::{
         chat_template::{ChatTemplate, ChatTemplateValue},
         isq::UQFF_RESIDUAL_SAFETENSORS,
     },
-    utils::tokens::get_token,
+    utils::{
+        hub_proxy::{HubProxy, HubStatusError},
+        tokens::get_token,
+    },
     xlora_models::XLoraConfig,
     ModelPaths, Ordering, TokenSource, GLOBAL_HF_CACHE,
 };
@@ -31,6 +37,431 @@ const SAFETENSOR_MATCH: &str = r"model-\d+-of-\d+\.safetensors\b";
 const QUANT_SAFETENSOR_MATCH: &str = r"model\.safetensors\b";
 const PICKLE_MATCH: &str = r"pytorch_model-\d{5}-of-\d{5}.((pth)|(pt)|(bin))\b";
 
+/// Time to wait for the hub when revalidating the files of a model, which are often all cached.
+const HUB_REVALIDATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Why the hub denied access to a repository.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HubAccessKind {
+    /// The repository is gated, and the token, if any, has not been granted access to it.
+    Gated,
+    /// The repository does not exist, or it is private and the token, if any, can't read it.
+    NotFound,
+    /// The token was rejected, for example because it expired.
+    InvalidToken,
+}
+
+impl HubAccessKind {
+    /// Classify a 401 or 403 response of the hub from its `X-Error-Code` header, which tells
+    /// gated repositories apart from missing ones.
+    fn classify(status: u16, error_code: Option<&str>, has_token: bool) -> Self {
+        match error_code {
+            Some("GatedRepo") => Self::Gated,
+            Some("RepoNotFound") => Self::NotFound,
+            _ if status == 403 => Self::Gated,
+            _ if has_token => Self::InvalidToken,
+            _ => Self::NotFound,
+        }
+    }
+}
+
+/// The hub answered 401 or 403 for a repository, so retrying won't help until the token or its
+/// access to the repository changes.
+#[derive(Clone, Debug)]
+pub struct HubAccessError {
+    pub model_id: String,
+    pub revision: String,
+    /// The file which was requested, `None` for the metadata of the repository.
+    pub file: Option<String>,
+    pub status: u16,
+    pub kind: HubAccessKind,
+    /// Where the token sent to the hub came from, `None` if no token was sent.
+    pub token_source: Option<String>,
+    /// Page of the repository, where the access to a gated repository is requested.
+    pub repo_url: String,
+}
+
+impl HubAccessError {
+    fn for_file(&self, file: &str) -> Self {
+        Self {
+            file: Some(file.to_string()),
+            ..self.clone()
+        }
+    }
+}
+
+impl fmt::Display for HubAccessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The hub denied access to ")?;
+        if let Some(file) = &self.file {
+            write!(f, "`{file}` of ")?;
+        }
+        write!(
+            f,
+            "`{}` at revision `{}` (status {}): ",
+            self.model_id, self.revision, self.status
+        )?;
+        let token = match &self.token_source {
+            Some(source) => format!("the token from {source}"),
+            None => "no token".to_string(),
+        };
+        match (self.kind, &self.token_source) {
+            (HubAccessKind::Gated, None) => write!(
+                f,
+                "the repository is gated and no token was sent. Accept its conditions at {} and pass a token with `--token-source` (for example `env:HF_TOKEN`), or log in with `huggingface-cli login`.",
+                self.repo_url
+            ),
+            (HubAccessKind::Gated, Some(_)) => write!(
+                f,
+                "the repository is gated and {token} has not been granted access. Accept its conditions at {} with the account of the token, and check that the token can read gated repositories.",
+                self.repo_url
+            ),
+            (HubAccessKind::NotFound, _) => write!(
+                f,
+                "the repository does not exist, or it is private and {token} can't read it. Check the model ID and the revision."
+            ),
+            (HubAccessKind::InvalidToken, _) => write!(
+                f,
+                "{token} was rejected. Create a new token at https://huggingface.co/settings/tokens."
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HubAccessError {}
+
+/// Where a token was read from, without the token itself.
+fn describe_token_source(source: &TokenSource) -> String {
+    match source {
+        TokenSource::Literal(_) => "the literal token source".to_string(),
+        TokenSource::EnvVar(var) => format!("the `{var}` environment variable"),
+        TokenSource::Path(path) => format!("`{path}`"),
+        TokenSource::CacheToken => "the cache of `huggingface-cli login`".to_string(),
+        TokenSource::None => "no source".to_string(),
+    }
+}
+
+/// Status of a request to the hub which failed because access was denied.
+fn access_denied_status(e: &ApiError) -> Option<u16> {
+    match e {
+        ApiError::RequestError(e) => match e.as_ref() {
+            ureq::Error::Status(status @ (401 | 403), _) => Some(*status),
+            _ => None,
+        },
+        ApiError::TooManyRetries(e) => access_denied_status(e),
+        _ => None,
+    }
+}
+
+/// Status of a request to the hub, made directly or through a proxy, which failed because access
+/// was denied.
+fn denied_status(e: &anyhow::Error) -> Option<u16> {
+    if let Some(e) = e.downcast_ref::<HubStatusError>() {
+        return [401, 403].contains(&e.status).then_some(e.status);
+    }
+    e.downcast_ref::<ApiError>().and_then(access_denied_status)
+}
+
+/// Request `url` again, through `proxy` if any, to read the `X-Error-Code` header of the hub,
+/// which the errors of hf-hub don't keep. Returns the status and the error code.
+fn probe_access(
+    url: String,
+    token: Option<String>,
+    proxy: Option<reqwest::Proxy>,
+) -> Option<(u16, Option<String>)> {
+    // A blocking client can't be used from within the runtime of the caller.
+    thread::spawn(move || {
+        let mut client = reqwest::blocking::Client::builder().timeout(HUB_REVALIDATION_TIMEOUT);
+        if let Some(proxy) = proxy {
+            client = client.proxy(proxy);
+        }
+        let client = client.build().ok()?;
+        let mut request = client.head(url);
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().ok()?;
+        let error_code = response
+            .headers()
+            .get("x-error-code")
+            .and_then(|code| code.to_str().ok())
+            .map(str::to_string);
+        Some((response.status().as_u16(), error_code))
+    })
+    .join()
+    .ok()
+    .flatten()
+}
+
+/// Files and commit of a revision on the hub.
+struct RemoteRevision {
+    files: Vec<String>,
+    commit: String,
+}
+
+/// A model repository on the Hugging Face hub, at a revision.
+///
+/// The revision is revalidated against the hub once, with a short timeout. If the hub can't be
+/// reached, the files are read from the cached snapshot of the revision and only the files which
+/// aren't cached are an error.
+///
+/// Once the hub denies access to the repository, the files which aren't cached fail with a
+/// [`HubAccessError`] without requesting them again.
+pub(crate) struct HubRepo {
+    api: Api,
+    cache: Cache,
+    repo: Repo,
+    model_id: String,
+    revision: String,
+    token_source: TokenSource,
+    /// Proxy which the requests to the hub go through, instead of the client of `api`.
+    proxy: Option<HubProxy>,
+    /// The revision on the hub, or why it could not be revalidated.
+    remote: OnceLock<std::result::Result<RemoteRevision, String>>,
+    /// The first access denied by the hub.
+    denied: OnceLock<HubAccessError>,
+}
+
+impl HubRepo {
+    pub(crate) fn new(
+        api: &Api,
+        model_id: String,
+        revision: String,
+        token_source: &TokenSource,
+    ) -> Self {
+        let cache = match std::env::var("HF_HUB_CACHE") {
+            Ok(x) => Cache::new(x.into()),
+            Err(_) => GLOBAL_HF_CACHE.get().cloned().unwrap_or_default(),
+        };
+        Self {
+            api: api.clone(),
+            cache,
+            repo: Repo::with_revision(model_id.clone(), RepoType::Model, revision.clone()),
+            model_id,
+            revision,
+            token_source: token_source.clone(),
+            proxy: None,
+            remote: OnceLock::new(),
+            denied: OnceLock::new(),
+        }
+    }
+
+    /// Send the requests to the hub through `proxy`, if any.
+    pub(crate) fn with_proxy(mut self, proxy: Option<HubProxy>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// The proxy which the requests to the hub go through, if any.
+    pub(crate) fn proxy(&self) -> Option<HubProxy> {
+        self.proxy.clone()
+    }
+
+    /// Record that the hub answered `status` to the request of `file` at `url`.
+    fn deny(&self, file: Option<&str>, status: u16, url: String) -> HubAccessError {
+        self.denied
+            .get_or_init(|| {
+                let token = get_token(&self.token_source).ok().flatten();
+                let token_source = token
+                    .as_ref()
+                    .map(|_| describe_token_source(&self.token_source));
+                let proxy = self.proxy.as_ref().and_then(|proxy| proxy.proxy().ok());
+                let (status, error_code) = probe_access(url, token.clone(), proxy)
+                    .filter(|(status, _)| [401, 403].contains(status))
+                    .unwrap_or((status, None));
+                let repo_url = self.api.repo(self.repo.clone()).url("");
+                let repo_url = repo_url
+                    .split("/resolve/")
+                    .next()
+                    .unwrap_or(&repo_url)
+                    .to_string();
+                HubAccessError {
+                    model_id: self.model_id.clone(),
+                    revision: self.revision.clone(),
+                    file: file.map(str::to_string),
+                    status,
+                    kind: HubAccessKind::classify(status, error_code.as_deref(), token.is_some()),
+                    token_source,
+                    repo_url,
+                }
+            })
+            .clone()
+    }
+
+    fn remote(&self) -> std::result::Result<&RemoteRevision, &str> {
+        self.remote
+            .get_or_init(|| {
+                // The request keeps running in the background if it times out.
+                let (tx, rx) = mpsc::channel();
+                let api = self.api.repo(self.repo.clone());
+                let info_url = api.info_request().url().to_string();
+                let proxy = self.proxy.clone();
+                let token = get_token(&self.token_source).ok().flatten();
+                thread::spawn(move || {
+                    let info = match proxy {
+                        Some(proxy) => proxy.info(info_url, token),
+                        None => api.info().map_err(anyhow::Error::from),
+                    };
+                    let _ = tx.send(info);
+                });
+                let remote = match rx.recv_timeout(HUB_REVALIDATION_TIMEOUT) {
+                    Ok(Ok(info)) => Ok(RemoteRevision {
+                        files: info.siblings.into_iter().map(|x| x.rfilename).collect(),
+                        commit: info.sha,
+                    }),
+                    Ok(Err(e)) => match denied_status(&e) {
+                        Some(status) => {
+                            let url = self.api.repo(self.repo.clone()).info_request();
+                            Err(self.deny(None, status, url.url().to_string()).to_string())
+                        }
+                        None => Err(e.to_string()),
+                    },
+                    Err(_) => Err(format!(
+                        "no response within {}s",
+                        HUB_REVALIDATION_TIMEOUT.as_secs()
+                    )),
+                };
+                if let Err(e) = &remote {
+                    warn!(
+                        "Could not revalidate `{}` at revision `{}` with the hub ({e}), using the cached snapshot.",
+                        self.model_id, self.revision
+                    );
+                }
+                remote
+            })
+            .as_ref()
+            .map_err(String::as_str)
+    }
+
+    /// Commit of the cached snapshot of the revision.
+    fn cached_commit(&self) -> Option<String> {
+        let path = self
+            .cache
+            .path()
+            .join(self.repo.folder_name())
+            .join("refs")
+            .join(&self.revision);
+        let commit = fs::read_to_string(path).ok()?;
+        Some(commit.trim().to_string())
+    }
+
+    /// Commit which the revision resolved to, on the hub or else in the cache.
+    pub(crate) fn commit_hash(&self) -> Option<String> {
+        match self.remote() {
+            Ok(remote) => Some(remote.commit.clone()),
+            Err(_) => self.cached_commit(),
+        }
+    }
+
+    /// Names of the files of the repository, relative to its root.
+    pub(crate) fn list_files(&self) -> Result<Vec<String>> {
+        let e = match self.remote() {
+            Ok(remote) => return Ok(remote.files.clone()),
+            Err(e) => e,
+        };
+        if let (None, Some(denied)) = (self.cached_commit(), self.denied.get()) {
+            return Err(denied.clone().into());
+        }
+        let commit = self.cached_commit().with_context(|| {
+            format!(
+                "`{}` at revision `{}` is not cached and the hub could not be reached: {e}",
+                self.model_id, self.revision
+            )
+        })?;
+        let snapshot = self
+            .cache
+            .path()
+            .join(self.repo.folder_name())
+            .join("snapshots")
+            .join(commit);
+        let mut files = Vec::new();
+        list_snapshot(&snapshot, &snapshot, &mut files)?;
+        files.sort();
+        Ok(files)
+    }
+
+    /// Path of a file of the repository, downloaded unless the cached snapshot is current.
+    pub(crate) fn get(&self, file: &str) -> Result<PathBuf> {
+        let cached = self.cache.repo(self.repo.clone()).get(file);
+        let remote = match self.remote() {
+            Ok(remote) => remote,
+            Err(e) => {
+                if let (None, Some(denied)) = (&cached, self.denied.get()) {
+                    return Err(denied.for_file(file).into());
+                }
+                return cached.with_context(|| {
+                    format!(
+                        "`{file}` of `{}` at revision `{}` is not cached and the hub could not be reached: {e}",
+                        self.model_id, self.revision
+                    )
+                });
+            }
+        };
+        if let Some(path) = cached
+            .as_ref()
+            .filter(|_| self.cached_commit().as_ref() == Some(&remote.commit))
+        {
+            return Ok(path.clone());
+        }
+        if let Some(denied) = self.denied.get() {
+            return cached.ok_or_else(|| denied.for_file(file).into());
+        }
+        let api = self.api.repo(self.repo.clone());
+        let downloaded = match &self.proxy {
+            Some(proxy) => self.download_through(proxy, file, &remote.commit),
+            None => api.download(file).map_err(anyhow::Error::from),
+        };
+        match downloaded {
+            Ok(path) => Ok(path),
+            Err(e) => match cached {
+                Some(path) => {
+                    warn!("Could not download `{file}` from the hub ({e}), using the cached file.");
+                    Ok(path)
+                }
+                None => match denied_status(&e) {
+                    Some(status) => Err(self.deny(Some(file), status, api.url(file)).into()),
+                    None => Err(e),
+                },
+            },
+        }
+    }
+
+    /// Download `file` at `commit` through `proxy` into the snapshot of the commit in the cache,
+    /// and point the revision at the commit.
+    fn download_through(&self, proxy: &HubProxy, file: &str, commit: &str) -> Result<PathBuf> {
+        info!("Downloading `{file}` through the proxy.");
+        let repo = Repo::with_revision(self.model_id.clone(), RepoType::Model, commit.to_string());
+        let url = self.api.repo(repo).url(file);
+        let path = self
+            .cache
+            .path()
+            .join(self.repo.folder_name())
+            .join("snapshots")
+            .join(commit)
+            .join(file);
+        proxy.download(url, get_token(&self.token_source)?, &path)?;
+        self.cache.repo(self.repo.clone()).create_ref(commit)?;
+        Ok(path)
+    }
+}
+
+/// Add the files under `dir` of a snapshot to `files`, relative to the snapshot root.
+fn list_snapshot(root: &Path, dir: &Path, files: &mut Vec<String>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            list_snapshot(root, &path, files)?;
+        } else {
+            let name = path.strip_prefix(root)?.components();
+            let name = name
+                .map(|x| x.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>();
+            files.push(name.join("/"));
+        }
+    }
+    Ok(())
+}
+
 #[derive(Clone, Debug)]
 pub struct LoraAdapterPaths {
     pub lora_config: mistralrs_quant::LoraConfig,
@@ -59,6 +490,7 @@ pub fn get_xlora_paths(
     token_source: &TokenSource,
     revision: String,
     xlora_order: &Option<Ordering>,
+    proxy: &Option<HubProxy>,
 ) -> Result<AdapterPaths> {
     match (lora_adapter_ids, xlora_model_id, xlora_order) {
         (None, Some(xlora_id), Some(xlora_order)) => {
@@ -72,11 +504,8 @@ pub fn get_xlora_paths(
                 }
                 api.build().map_err(candle_core::Error::msg)?
             };
-            let api = api.repo(Repo::with_revision(
-                xlora_id.clone(),
-                RepoType::Model,
-                revision,
-            ));
+            let api = HubRepo::new(&api, xlora_id.clone(), revision, token_source)
+                .with_proxy(proxy.clone());
             let model_id = Path::new(&xlora_id);
 
             // Get the path for the xlora classifier
@@ -280,11 +709,8 @@ pub fn get_xlora_paths(
                     }
                     api.build().map_err(candle_core::Error::msg)?
                 };
-                let api = api.repo(Repo::with_revision(
-                    adapter_id.clone(),
-                    RepoType::Model,
-                    revision.clone(),
-                ));
+                let api = HubRepo::new(&api, adapter_id.clone(), revision.clone(), token_source)
+                    .with_proxy(proxy.clone());
 
                 let config_path = api.get("adapter_config.json")?;
                 let adapter_path = api.get("adapter_model.safetensors")?;
@@ -311,7 +737,7 @@ pub fn get_model_paths(
     token_source: &TokenSource,
     quantized_model_id: &Option<String>,
     quantized_filename: &Option<Vec<String>>,
-    api: &ApiRepo,
+    api: &HubRepo,
     model_id: &Path,
     loading_from_uqff: bool,
 ) -> Result<Vec<PathBuf>> {
@@ -331,11 +757,8 @@ pub fn get_model_paths(
                     }
                     api.build().map_err(candle_core::Error::msg)?
                 };
-                let qapi = qapi.repo(Repo::with_revision(
-                    id.to_string(),
-                    RepoType::Model,
-                    revision.clone(),
-                ));
+                let qapi = HubRepo::new(&qapi, id.to_string(), revision.clone(), token_source)
+                    .with_proxy(api.proxy());
                 let model_id = Path::new(&id);
                 files.push(api_get_file!(qapi, name, model_id));
             }
@@ -582,6 +1005,37 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn access_denied_status() -> anyhow::Result<()> {
+        use hf_hub::api::sync::ApiError;
+
+        let status = |code, text| -> anyhow::Result<ApiError> {
+            let response = ureq::Response::new(code, text, "")?;
+            Ok(ApiError::RequestError(Box::new(ureq::Error::Status(
+                code, response,
+            ))))
+        };
+        assert_eq!(
+            super::access_denied_status(&status(401, "Unauthorized")?),
+            Some(401)
+        );
+        assert_eq!(
+            super::access_denied_status(&ApiError::TooManyRetries(Box::new(status(
+                403,
+                "Forbidden"
+            )?))),
+            Some(403)
+        );
+        assert_eq!(
+            super::access_denied_status(&status(404, "Not Found")?),
+            None
+        );
+        // A message mentioning the status is not a denied request.
+        let e = std::io::Error::other("status code 403");
+        assert_eq!(super::access_denied_status(&ApiError::IoError(e)), None);
+        Ok(())
+    }
+
     #[test]
     fn match_pickle() -> anyhow::Result<()> {
         use regex_automata::meta::Regex;
@@ -607,4 +1061,195 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn hub_unreachable_uses_cached_snapshot() -> anyhow::Result<()> {
+        use std::{fs, sync::OnceLock};
+
+        use hf_hub::{api::sync::ApiBuilder, Cache, Repo, RepoType};
+
+        use super::HubRepo;
+        use crate::TokenSource;
+
+        let dir = std::env::temp_dir().join("mistralrs_hub_cache_test");
+        let _ = fs::remove_dir_all(&dir);
+        let repo_dir = dir.join("models--org--model");
+        fs::create_dir_all(repo_dir.join("refs"))?;
+        fs::write(repo_dir.join("refs").join("main"), "0123abcd\n")?;
+        let snapshot = repo_dir.join("snapshots").join("0123abcd");
+        fs::create_dir_all(snapshot.join("vae"))?;
+        fs::write(snapshot.join("config.json"), "{}")?;
+        fs::write(snapshot.join("vae").join("config.json"), "{}")?;
+
+        let cache = Cache::new(dir.clone());
+        // Nothing listens on the discard port, so the hub can't be reached.
+        let api = ApiBuilder::from_cache(cache.clone())
+            .with_progress(false)
+            .with_endpoint("http://127.0.0.1:9".to_string())
+            .build()?;
+        let repo = HubRepo {
+            api,
+            cache,
+            repo: Repo::with_revision("org/model".to_string(), RepoType::Model, "main".to_string()),
+            model_id: "org/model".to_string(),
+            revision: "main".to_string(),
+            token_source: TokenSource::None,
+            remote: OnceLock::new(),
+            denied: OnceLock::new(),
+        };
+
+        assert_eq!(repo.list_files()?, ["config.json", "vae/config.json"]);
+        assert_eq!(repo.get("config.json")?, snapshot.join("config.json"));
+        assert!(repo.get("model.safetensors").is_err());
+        assert_eq!(repo.commit_hash().as_deref(), Some("0123abcd"));
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    /// Serve the hub API with `api` and the files with `file`, each a status and an optional
+    /// `X-Error-Code`. Returns the endpoint and the number of requests of files.
+    fn mock_hub(
+        api: (u16, Option<&'static str>),
+        file: (u16, Option<&'static str>),
+    ) -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use std::{
+            io::{BufRead, BufReader, Write},
+            net::TcpListener,
+            sync::{atomic::AtomicUsize, atomic::Ordering, Arc},
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let file_requests = Arc::new(AtomicUsize::new(0));
+        let counter = file_requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                let (status, code) = if request_line.contains(" /api/") {
+                    api
+                } else {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    file
+                };
+                let body = if status == 200 {
+                    r#"{"sha": "0123abcd", "siblings": [{"rfilename": "config.json"}]}"#
+                } else {
+                    ""
+                };
+                let code = code
+                    .map(|code| format!("X-Error-Code: {code}\r\n"))
+                    .unwrap_or_default();
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {status} Status\r\n{code}Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+            }
+        });
+        (endpoint, file_requests)
+    }
+
+    #[test]
+    fn hub_access_denied() -> anyhow::Result<()> {
+        use std::{
+            fs,
+            sync::{atomic::Ordering, OnceLock},
+        };
+
+        use hf_hub::{api::sync::ApiBuilder, Cache, Repo, RepoType};
+
+        use super::{HubAccessError, HubAccessKind, HubRepo};
+        use crate::TokenSource;
+
+        let dir = std::env::temp_dir().join("mistralrs_hub_access_test");
+        let _ = fs::remove_dir_all(&dir);
+        let repo = |endpoint: &str, token_source: TokenSource| -> anyhow::Result<HubRepo> {
+            let cache = Cache::new(dir.clone());
+            let token = match &token_source {
+                TokenSource::Literal(token) => Some(token.clone()),
+                _ => None,
+            };
+            let api = ApiBuilder::from_cache(cache.clone())
+                .with_progress(false)
+                .with_endpoint(endpoint.to_string())
+                .with_token(token)
+                .build()?;
+            Ok(HubRepo {
+                api,
+                cache,
+                repo: Repo::with_revision(
+                    "org/model".to_string(),
+                    RepoType::Model,
+                    "main".to_string(),
+                ),
+                model_id: "org/model".to_string(),
+                revision: "main".to_string(),
+                token_source,
+                remote: OnceLock::new(),
+                denied: OnceLock::new(),
+            })
+        };
+        let denied = |result: anyhow::Result<std::path::PathBuf>| {
+            result.unwrap_err().downcast::<HubAccessError>().unwrap()
+        };
+
+        // A gated repository without a token.
+        let (endpoint, file_requests) = mock_hub((200, None), (401, Some("GatedRepo")));
+        let gated = repo(&endpoint, TokenSource::None)?;
+        let e = denied(gated.get("config.json"));
+        assert_eq!(e.kind, HubAccessKind::Gated);
+        assert_eq!((e.status, e.file.as_deref()), (401, Some("config.json")));
+        assert_eq!(e.token_source, None);
+        assert_eq!(e.repo_url, format!("{endpoint}/org/model"));
+        assert!(e
+            .to_string()
+            .contains(&format!("Accept its conditions at {endpoint}/org/model")));
+        // The other files are not requested again.
+        let requests = file_requests.load(Ordering::SeqCst);
+        let e = denied(gated.get("model.safetensors"));
+        assert_eq!(e.file.as_deref(), Some("model.safetensors"));
+        assert_eq!(file_requests.load(Ordering::SeqCst), requests);
+
+        // A gated repository with a token which has not been granted access.
+        let (endpoint, _) = mock_hub((200, None), (403, Some("GatedRepo")));
+        let e = denied(
+            repo(&endpoint, TokenSource::Literal("hf_test".to_string()))?.get("config.json"),
+        );
+        assert_eq!((e.kind, e.status), (HubAccessKind::Gated, 403));
+        assert_eq!(e.token_source.as_deref(), Some("the literal token source"));
+        assert!(!e.to_string().contains("hf_test"));
+
+        // A missing repository, which the hub API already denies.
+        let (endpoint, _) = mock_hub((401, Some("RepoNotFound")), (401, Some("RepoNotFound")));
+        let missing = repo(&endpoint, TokenSource::None)?;
+        let e = denied(missing.get("config.json"));
+        assert_eq!(e.kind, HubAccessKind::NotFound);
+        assert_eq!(e.file.as_deref(), Some("config.json"));
+        let e = missing
+            .list_files()
+            .unwrap_err()
+            .downcast::<HubAccessError>()?;
+        assert_eq!((e.kind, e.file), (HubAccessKind::NotFound, None));
+
+        // Without an error code, a rejected token is told apart from a missing repository.
+        assert_eq!(
+            HubAccessKind::classify(401, None, true),
+            HubAccessKind::InvalidToken
+        );
+        assert_eq!(
+            HubAccessKind::classify(401, None, false),
+            HubAccessKind::NotFound
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+        Ok(())
+    }
 }
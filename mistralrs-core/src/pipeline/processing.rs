@@ -3,13 +3,17 @@ use std::sync::Arc;
 use anyhow::{Context, Result};
 use either::Either;
 use indexmap::IndexMap;
+use tracing::warn;
 
 use crate::{
     vision_models::{preprocessor_config::PreProcessorConfig, processor_config::ProcessorConfig},
     MessageContent, Pipeline, Tool,
 };
 
-use super::{chat_template::apply_chat_template_to, text_models_inputs_processor, InputsProcessor};
+use super::{
+    chat_template::{apply_chat_template_to, ChatTemplate},
+    text_models_inputs_processor, InputsProcessor,
+};
 
 /// Trait to create processors.
 pub trait ProcessorCreator {
@@ -38,6 +42,7 @@ pub trait Processor {
         add_generation_prompt: bool,
         add_special_tokens: bool,
         tools: Vec<Tool>,
+        enable_thinking: Option<bool>,
     ) -> Result<(Vec<u32>, String)> {
         // for message in messages.iter_mut() {
         //     if message["role"].as_ref().left().is_some_and(|x| x == "tool") {
@@ -55,6 +60,7 @@ pub trait Processor {
             add_generation_prompt,
             self.template_action(),
             tools,
+            enable_thinking,
         )?;
         let encoding = pipeline
             .tokenizer()
@@ -76,6 +82,7 @@ pub(crate) fn apply_chat_template(
     add_generation_prompt: bool,
     action: MessagesAction,
     tools: Vec<Tool>,
+    enable_thinking: Option<bool>,
 ) -> Result<String> {
     let messages = match action {
         MessagesAction::Keep => messages,
@@ -119,39 +126,40 @@ pub(crate) fn apply_chat_template(
     let chat_template = pipeline
         .get_chat_template()
         .with_context(|| "`apply_chat_template` expects the pipeline to have a chat template.")?;
+    render_chat_template(
+        &chat_template,
+        messages,
+        add_generation_prompt,
+        tools,
+        enable_thinking,
+        Vec::new(),
+    )
+}
+
+/// Render the messages with the chat template, which must be present. Likely mistakes in the
+/// messages are logged. `documents` are given to templates with a documents slot.
+pub(crate) fn render_chat_template(
+    chat_template: &ChatTemplate,
+    messages: Vec<IndexMap<String, MessageContent>>,
+    add_generation_prompt: bool,
+    tools: Vec<Tool>,
+    enable_thinking: Option<bool>,
+    documents: Vec<IndexMap<String, String>>,
+) -> Result<String> {
+    for warning in chat_template.validate_messages(&messages, None, usize::MAX)? {
+        warn!("{warning}");
+    }
     let template = chat_template.chat_template.as_ref().unwrap();
-    let bos_tok = if let Some(ref bos) = chat_template.bos_token {
-        match bos.0 {
-            Either::Left(ref lit) => Some(lit.to_string()),
-            Either::Right(ref added) => Some(added.content.to_string()),
-        }
-    } else {
-        None
-    };
-    let eos_tok = if let Some(ref eos) = chat_template.eos_token {
-        match eos.0 {
-            Either::Left(ref lit) => Some(lit.to_string()),
-            Either::Right(ref added) => Some(added.content.to_string()),
-        }
-    } else {
-        None
-    };
-    let unk_tok = if let Some(ref unk) = chat_template.unk_token {
-        match unk.0 {
-            Either::Left(ref lit) => Some(lit.to_string()),
-            Either::Right(ref added) => Some(added.content.to_string()),
-        }
-    } else {
-        None
-    };
     apply_chat_template_to(
         messages,
         add_generation_prompt,
         template,
-        bos_tok,
-        eos_tok,
-        unk_tok,
+        chat_template.bos_tok(),
+        chat_template.eos_tok(),
+        chat_template.unk_tok(),
         tools,
+        enable_thinking,
+        documents,
     )
 }
 
@@ -0,0 +1,309 @@
+//! Detection of the prompt format a model expects, to catch missing or mismatched chat templates,
+//! which are common in GGUF files.
+
+use either::Either;
+use serde::Serialize;
+use tokenizers::Tokenizer;
+use tracing::{info, warn};
+
+use super::chat_template::{ChatTemplate, ChatTemplateValue};
+
+/// A family of chat templates sharing the same turn markers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptFormat {
+    /// `<|im_start|>role ... <|im_end|>`, used by Qwen, Hermes, Yi and many fine tunes.
+    ChatMl,
+    /// `<|start_header_id|>role<|end_header_id|> ... <|eot_id|>`.
+    Llama3,
+    /// `<start_of_turn>role ... <end_of_turn>`.
+    Gemma,
+    /// `<|user|> ... <|end|><|assistant|>`.
+    Phi3,
+    /// `[INST] ... [/INST]`, used by Llama 2 and Mistral.
+    Inst,
+}
+
+struct FormatSignature {
+    format: PromptFormat,
+    /// Tokens which indicate the format if any of them is in the vocabulary.
+    special_tokens: &'static [&'static str],
+    /// Substrings of the lowercase model name which indicate the format.
+    name_hints: &'static [&'static str],
+    /// Substrings which identify the format in a chat template.
+    template_markers: &'static [&'static str],
+    /// Template used in place of a missing or mismatched one when auto-correcting.
+    template: &'static str,
+}
+
+/// Signatures of the known formats. Where several formats match, the earlier one is preferred.
+const FORMAT_SIGNATURES: &[FormatSignature] = &[
+    FormatSignature {
+        format: PromptFormat::ChatMl,
+        special_tokens: &["<|im_start|>", "<|im_end|>"],
+        name_hints: &["qwen", "hermes", "chatml", "yi-", "openchat", "dolphin"],
+        template_markers: &["<|im_start|>"],
+        template: "{% for message in messages %}{{'<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>' + '\n'}}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}",
+    },
+    FormatSignature {
+        format: PromptFormat::Llama3,
+        special_tokens: &["<|start_header_id|>", "<|eot_id|>"],
+        name_hints: &["llama-3", "llama3", "llama 3"],
+        template_markers: &["<|start_header_id|>"],
+        template: "{% set loop_messages = messages %}{% for message in loop_messages %}{% set content = '<|start_header_id|>' + message['role'] + '<|end_header_id|>\n\n'+ message['content'] | trim + '<|eot_id|>' %}{% if loop.index0 == 0 %}{% set content = bos_token + content %}{% endif %}{{ content }}{% endfor %}{% if add_generation_prompt %}{{ '<|start_header_id|>assistant<|end_header_id|>\n\n' }}{% endif %}",
+    },
+    FormatSignature {
+        format: PromptFormat::Gemma,
+        special_tokens: &["<start_of_turn>", "<end_of_turn>"],
+        name_hints: &["gemma"],
+        template_markers: &["<start_of_turn>"],
+        template: "{{ bos_token }}{% for message in messages %}{% if message['role'] == 'assistant' %}{% set role = 'model' %}{% else %}{% set role = message['role'] %}{% endif %}{{ '<start_of_turn>' + role + '\n' + message['content'] | trim + '<end_of_turn>\n' }}{% endfor %}{% if add_generation_prompt %}{{ '<start_of_turn>model\n' }}{% endif %}",
+    },
+    FormatSignature {
+        format: PromptFormat::Phi3,
+        special_tokens: &["<|user|>", "<|assistant|>", "<|end|>"],
+        name_hints: &["phi-3", "phi3", "phi-4-mini"],
+        template_markers: &["<|user|>", "<|assistant|>"],
+        template: "{{ bos_token }}{% for message in messages %}{% if (message['role'] == 'user') %}{{'<|user|>' + '\n' + message['content'] + '<|end|>' + '\n' + '<|assistant|>' + '\n'}}{% elif (message['role'] == 'assistant') %}{{message['content'] + '<|end|>' + '\n'}}{% endif %}{% endfor %}",
+    },
+    FormatSignature {
+        format: PromptFormat::Inst,
+        // Only Mistral tokenizers since v3 have these as control tokens, Llama 2 is found by name.
+        special_tokens: &["[INST]", "[/INST]"],
+        name_hints: &["llama-2", "llama2", "mistral", "mixtral", "codellama"],
+        template_markers: &["[INST]"],
+        template: "{{ bos_token }}{% for message in messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if message['role'] == 'user' %}{{ '[INST] ' + message['content'] + ' [/INST]' }}{% elif message['role'] == 'assistant' %}{{ message['content'] + eos_token + ' ' }}{% else %}{{ raise_exception('Only user and assistant roles are supported!') }}{% endif %}{% endfor %}",
+    },
+];
+
+fn signature(format: PromptFormat) -> &'static FormatSignature {
+    FORMAT_SIGNATURES
+        .iter()
+        .find(|s| s.format == format)
+        .expect("Every prompt format has a signature")
+}
+
+/// Get the formats suggested by the model name and the vocabulary, most likely first. Name hints
+/// come first because fine tunes often keep the special tokens of their base model.
+pub fn detect_formats(
+    has_token: impl Fn(&str) -> bool,
+    model_name: Option<&str>,
+) -> Vec<PromptFormat> {
+    let mut formats = Vec::new();
+    if let Some(name) = model_name {
+        let name = name.to_lowercase();
+        formats.extend(
+            FORMAT_SIGNATURES
+                .iter()
+                .filter(|s| s.name_hints.iter().any(|hint| name.contains(hint)))
+                .map(|s| s.format),
+        );
+    }
+    for s in FORMAT_SIGNATURES {
+        if !formats.contains(&s.format) && s.special_tokens.iter().any(|tok| has_token(tok)) {
+            formats.push(s.format);
+        }
+    }
+    formats
+}
+
+/// Recognize the format of a chat template by its turn markers.
+pub fn template_format(template: &str) -> Option<PromptFormat> {
+    FORMAT_SIGNATURES
+        .iter()
+        .find(|s| s.template_markers.iter().any(|m| template.contains(m)))
+        .map(|s| s.format)
+}
+
+/// The result of prompt format detection, exposed so that clients can see which format is in use.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PromptFormatDetection {
+    /// Formats suggested by the model name and the tokenizer, most likely first.
+    pub detected: Vec<PromptFormat>,
+    /// Format of the chat template, if there is one and it was recognized.
+    pub template: Option<PromptFormat>,
+    /// Format of the chat template in use, after any correction.
+    pub in_use: Option<PromptFormat>,
+    /// Whether the chat template agrees with the detected formats. An unrecognized template is
+    /// assumed to agree, a missing one does not unless nothing was detected.
+    pub consistent: bool,
+    /// Whether the chat template was replaced with that of the detected format.
+    pub corrected: bool,
+}
+
+impl PromptFormatDetection {
+    /// Compare the detected formats with the format of the chat template. If they disagree, the
+    /// template is replaced with that of the most likely detected format when `auto_correct` is set.
+    pub fn new(detected: Vec<PromptFormat>, template: Option<&str>, auto_correct: bool) -> Self {
+        let template_format = template.and_then(template_format);
+        let consistent = match (template, template_format) {
+            (_, Some(format)) => detected.is_empty() || detected.contains(&format),
+            (Some(_), None) => true,
+            (None, None) => detected.is_empty(),
+        };
+        let corrected = !consistent && auto_correct;
+        Self {
+            in_use: if corrected {
+                detected.first().copied()
+            } else {
+                template_format
+            },
+            detected,
+            template: template_format,
+            consistent,
+            corrected,
+        }
+    }
+}
+
+/// Detect the prompt format of the model and compare it with the chat template, warning on a
+/// mismatch. With `auto_correct`, a missing or mismatched template is replaced.
+pub fn check_prompt_format(
+    tokenizer: &Tokenizer,
+    model_name: Option<&str>,
+    chat_template: &mut ChatTemplate,
+    auto_correct: bool,
+) -> PromptFormatDetection {
+    let detected = detect_formats(|tok| tokenizer.token_to_id(tok).is_some(), model_name);
+    let template = match &chat_template.chat_template {
+        Some(ChatTemplateValue(Either::Left(template))) => Some(template.clone()),
+        Some(ChatTemplateValue(Either::Right(templates))) => templates
+            .iter()
+            .find(|t| t.get("name").is_some_and(|name| name == "default"))
+            .and_then(|t| t.get("template"))
+            .cloned(),
+        None => None,
+    };
+    let detection = PromptFormatDetection::new(detected, template.as_deref(), auto_correct);
+
+    if detection.corrected {
+        let format = detection.in_use.expect("A corrected template has a format");
+        warn!(
+            "Chat template format {:?} does not match the format {format:?} detected from the model, replacing the chat template.",
+            detection.template
+        );
+        chat_template.chat_template = Some(ChatTemplateValue(Either::Left(
+            signature(format).template.to_string(),
+        )));
+    } else if !detection.consistent {
+        warn!(
+            "Chat template format {:?} does not match the formats {:?} detected from the model. Outputs may be degraded; pass a chat template or enable chat template auto-correction.",
+            detection.template, detection.detected
+        );
+    } else if let Some(format) = detection.in_use {
+        info!("Using prompt format {format:?}.");
+    }
+    detection
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::{detect_formats, template_format, PromptFormat, PromptFormatDetection};
+
+    fn vocab(tokens: &[&str]) -> impl Fn(&str) -> bool {
+        let tokens = tokens
+            .iter()
+            .map(ToString::to_string)
+            .collect::<HashSet<_>>();
+        move |tok| tokens.contains(tok)
+    }
+
+    #[test]
+    fn detect_from_vocab() {
+        let cases: &[(&[&str], &[PromptFormat])] = &[
+            (&["<|im_start|>", "<|im_end|>"], &[PromptFormat::ChatMl]),
+            (
+                &["<|start_header_id|>", "<|eot_id|>"],
+                &[PromptFormat::Llama3],
+            ),
+            (
+                &["<start_of_turn>", "<end_of_turn>"],
+                &[PromptFormat::Gemma],
+            ),
+            (&["<|user|>", "<|end|>"], &[PromptFormat::Phi3]),
+            (&["[INST]", "[/INST]"], &[PromptFormat::Inst]),
+            (&["<s>", "</s>"], &[]),
+            // Hermes on Llama 3 has both.
+            (
+                &["<|start_header_id|>", "<|im_start|>"],
+                &[PromptFormat::ChatMl, PromptFormat::Llama3],
+            ),
+        ];
+        for (tokens, expected) in cases {
+            assert_eq!(&detect_formats(vocab(tokens), None), expected, "{tokens:?}");
+        }
+    }
+
+    #[test]
+    fn name_hints_come_first() {
+        assert_eq!(
+            detect_formats(vocab(&["<s>"]), Some("Llama-2-7B-Chat")),
+            [PromptFormat::Inst]
+        );
+        assert_eq!(
+            detect_formats(
+                vocab(&["<|start_header_id|>", "<|im_start|>"]),
+                Some("Hermes-3-Llama-3.1-8B")
+            ),
+            [PromptFormat::ChatMl, PromptFormat::Llama3]
+        );
+        assert_eq!(
+            detect_formats(vocab(&["<|im_start|>"]), Some("gemma-2-9b-it")),
+            [PromptFormat::Gemma, PromptFormat::ChatMl]
+        );
+    }
+
+    #[test]
+    fn recognize_templates() {
+        assert_eq!(
+            template_format("{{ '<|im_start|>' + message['role'] }}"),
+            Some(PromptFormat::ChatMl)
+        );
+        assert_eq!(
+            template_format("{{ '<start_of_turn>' + role }}"),
+            Some(PromptFormat::Gemma)
+        );
+        assert_eq!(
+            template_format("{{ '[INST] ' + message['content'] + ' [/INST]' }}"),
+            Some(PromptFormat::Inst)
+        );
+        assert_eq!(template_format("{{ message['content'] }}"), None);
+        for s in super::FORMAT_SIGNATURES {
+            assert_eq!(template_format(s.template), Some(s.format));
+        }
+    }
+
+    #[test]
+    fn mismatch_and_correction() {
+        let chatml = "{{ '<|im_start|>' }}";
+
+        let matching = PromptFormatDetection::new(vec![PromptFormat::ChatMl], Some(chatml), true);
+        assert!(matching.consistent);
+        assert!(!matching.corrected);
+        assert_eq!(matching.in_use, Some(PromptFormat::ChatMl));
+
+        let mismatched =
+            PromptFormatDetection::new(vec![PromptFormat::Llama3], Some(chatml), false);
+        assert!(!mismatched.consistent);
+        assert!(!mismatched.corrected);
+        assert_eq!(mismatched.in_use, Some(PromptFormat::ChatMl));
+
+        let corrected = PromptFormatDetection::new(vec![PromptFormat::Llama3], Some(chatml), true);
+        assert!(corrected.corrected);
+        assert_eq!(corrected.in_use, Some(PromptFormat::Llama3));
+
+        let missing = PromptFormatDetection::new(vec![PromptFormat::Gemma], None, true);
+        assert!(missing.corrected);
+        assert_eq!(missing.in_use, Some(PromptFormat::Gemma));
+
+        // Unrecognized templates and undetected formats are left alone.
+        let unknown = PromptFormatDetection::new(vec![PromptFormat::Gemma], Some("{{ x }}"), true);
+        assert!(unknown.consistent);
+        assert!(!unknown.corrected);
+        let undetected = PromptFormatDetection::new(vec![], Some(chatml), true);
+        assert!(undetected.consistent);
+        assert!(!undetected.corrected);
+    }
+}
@@ -1,4 +1,7 @@
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use candle_core::{DType, Device, Result, Tensor};
 use rand_isaac::Isaac64Rng;
@@ -24,6 +27,25 @@ macro_rules! fixup_sentencepiece {
     };
 }
 
+/// Add the timings of a finished sequence to the pipeline throughput estimate.
+fn record_throughput(this: &dyn Pipeline, seq: &Sequence) {
+    let Some(prompt_timestamp) = seq.prompt_timestamp() else {
+        return;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time travel has occurred!")
+        .as_millis();
+    #[allow(clippy::cast_possible_truncation)]
+    let ms = |t: u128| Duration::from_millis(t as u64);
+    this.get_metadata().throughput.record(
+        seq.prompt_tokens(),
+        ms(prompt_timestamp.saturating_sub(seq.timestamp())),
+        seq.get_toks().len().saturating_sub(seq.prompt_tokens()),
+        ms(now.saturating_sub(prompt_timestamp)),
+    );
+}
+
 pub(crate) async fn finish_or_add_toks_to_seq(
     this: &dyn Pipeline,
     prefix_cacher: &mut PrefixCacheManagerV2,
@@ -77,26 +99,44 @@ pub(crate) async fn finish_or_add_toks_to_seq(
             }
         };
 
-        let send = seq.get_toks().len() % 2 == 0 || is_done.is_some();
         if !tool_use_still_possible || tool_use_is_done {
-            if send {
-                if let Some(delta) = crate::handle_seq_error_ok!(seq.get_delta(), seq.responder()) {
-                    if seq.get_mut_group().is_chat {
-                        let (text_new, tool_calls) =
-                            parse_text_tools(this, delta.as_str(), seq.tools.clone())
-                                .map_err(candle_core::Error::msg)?;
-
-                        if !tool_calls.is_empty() && is_done.is_none() {
-                            is_done = Some(StopReason::Eos);
-                        };
-                        seq.add_streaming_chunk_choice_to_group(crate::ChunkChoice {
-                            delta: crate::Delta {
-                                content: fixup_sentencepiece!(
-                                    Option text_new.map(ToString::to_string)
-                                ),
-                                role: "assistant".to_string(),
-                                tool_calls: Some(tool_calls).filter(|v| !v.is_empty()),
-                            },
+            if let Some(delta) = crate::handle_seq_error_ok!(seq.get_delta(), seq.responder()) {
+                let tokens = seq.take_stream_tokens();
+                if seq.get_mut_group().is_chat {
+                    let (text_new, tool_calls) =
+                        parse_text_tools(this, delta.as_str(), seq.tools.clone())
+                            .map_err(candle_core::Error::msg)?;
+
+                    if !tool_calls.is_empty() && is_done.is_none() {
+                        is_done = Some(StopReason::Eos);
+                    };
+                    seq.add_streaming_chunk_choice_to_group(crate::ChunkChoice {
+                        delta: crate::Delta {
+                            content: fixup_sentencepiece!(
+                                Option text_new.map(ToString::to_string)
+                            ),
+                            role: "assistant".to_string(),
+                            tool_calls: Some(tool_calls).filter(|v| !v.is_empty()),
+                        },
+                        index: seq.get_response_index(),
+                        finish_reason: is_done.map(|x| x.to_string()),
+                        logprobs: if seq.return_logprobs() {
+                            Some(crate::ResponseLogprob {
+                                token: delta,
+                                bytes: logprobs.bytes.clone().map(|b| b.into_bytes()),
+                                logprob: logprobs.logprob,
+                                top_logprobs: logprobs.top_logprobs.unwrap().clone(),
+                            })
+                        } else {
+                            None
+                        },
+                        stop_reason: is_done.and_then(|x| seq.response_stop_reason(&x)),
+                        tokens,
+                    });
+                } else {
+                    seq.add_streaming_completion_chunk_choice_to_group(
+                        crate::CompletionChunkChoice {
+                            text: fixup_sentencepiece!(delta),
                             index: seq.get_response_index(),
                             finish_reason: is_done.map(|x| x.to_string()),
                             logprobs: if seq.return_logprobs() {
@@ -109,26 +149,10 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                             } else {
                                 None
                             },
-                        });
-                    } else {
-                        seq.add_streaming_completion_chunk_choice_to_group(
-                            crate::CompletionChunkChoice {
-                                text: fixup_sentencepiece!(delta),
-                                index: seq.get_response_index(),
-                                finish_reason: is_done.map(|x| x.to_string()),
-                                logprobs: if seq.return_logprobs() {
-                                    Some(crate::ResponseLogprob {
-                                        token: delta,
-                                        bytes: logprobs.bytes.clone().map(|b| b.into_bytes()),
-                                        logprob: logprobs.logprob,
-                                        top_logprobs: logprobs.top_logprobs.unwrap().clone(),
-                                    })
-                                } else {
-                                    None
-                                },
-                            },
-                        );
-                    }
+                            stop_reason: is_done.and_then(|x| seq.response_stop_reason(&x)),
+                            tokens,
+                        },
+                    );
                 }
             }
 
@@ -138,6 +162,7 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                     prefix_cacher.evict_to_cpu()?;
                 }
                 seq.set_state(crate::sequence::SequenceState::Done(reason));
+                record_throughput(this, seq);
                 this.reset_non_granular_state();
             }
 
@@ -153,7 +178,12 @@ pub(crate) async fn finish_or_add_toks_to_seq(
 
             if seq
                 .get_mut_group()
-                .maybe_send_streaming_response(seq, this.name().clone(), usage_opt)
+                .maybe_send_streaming_response(
+                    seq,
+                    this.name().clone(),
+                    this.get_metadata().system_fingerprint.clone(),
+                    usage_opt,
+                )
                 .await
                 .is_err()
             {
@@ -172,6 +202,7 @@ pub(crate) async fn finish_or_add_toks_to_seq(
         */
         {
             seq.set_state(crate::sequence::SequenceState::Done(reason));
+            record_throughput(this, seq);
             let (tokenizer, pipeline_name) = {
                 let pipeline_name = this.name();
                 let tokenizer = this.tokenizer();
@@ -207,17 +238,16 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                 | crate::sequence::StopReason::ModelLength(_)
                 | crate::sequence::StopReason::Eos
                 | crate::sequence::StopReason::StopTok(_)
-                | crate::sequence::StopReason::Canceled => {
-                    String::from_utf8_lossy(seq.completion_bytes())
-                        .trim_start()
-                        .to_string()
-                }
-                crate::sequence::StopReason::StopString {
-                    completion_bytes_pos,
-                    ..
-                } => {
-                    let txt = String::from_utf8_lossy(seq.completion_bytes());
-                    txt[..completion_bytes_pos].trim_start().to_string()
+                | crate::sequence::StopReason::Canceled => seq
+                    .strip_response_prefix(&String::from_utf8_lossy(seq.completion_bytes()))
+                    .to_string(),
+                crate::sequence::StopReason::StopString { .. }
+                | crate::sequence::StopReason::Balanced { .. } => {
+                    let end = seq.completion_bytes_end(&reason);
+                    seq.strip_response_prefix(&String::from_utf8_lossy(
+                        &seq.completion_bytes()[..end],
+                    ))
+                    .to_string()
                 }
                 crate::sequence::StopReason::GeneratedImage => {
                     candle_core::bail!("Stop reason was `GeneratedImage`.")
@@ -237,6 +267,7 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                         tool_calls: Some(tool_calls).filter(|v| !v.is_empty()),
                     },
                     logprobs: logprobs.map(|l| crate::Logprobs { content: Some(l) }),
+                    stop_reason: seq.response_stop_reason(&reason),
                 };
                 seq.add_choice_to_group(choice);
             } else {
@@ -245,6 +276,7 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                     index: seq.get_response_index(),
                     text,
                     logprobs: None,
+                    stop_reason: seq.response_stop_reason(&reason),
                 };
                 seq.add_completion_choice_to_group(choice);
             }
@@ -263,9 +295,13 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                             choices: group.get_choices().to_vec(),
                             created: seq.creation_time(),
                             model: pipeline_name,
-                            system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
+                            system_fingerprint: this.get_metadata().system_fingerprint.clone(),
                             object: "chat.completion".to_string(),
                             usage: group.get_usage(),
+                            effective_params: group.effective_params.clone(),
+                            documents: group.documents.clone(),
+                            adapters: group.adapters.clone(),
+                            routing: None,
                         },
                         seq.responder(),
                     )
@@ -279,9 +315,12 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                             choices: group.get_completion_choices().to_vec(),
                             created: seq.creation_time(),
                             model: pipeline_name,
-                            system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
+                            system_fingerprint: this.get_metadata().system_fingerprint.clone(),
                             object: "text_completion".to_string(),
                             usage: group.get_usage(),
+                            effective_params: group.effective_params.clone(),
+                            adapters: group.adapters.clone(),
+                            routing: None,
                         },
                         seq.responder(),
                     )
@@ -306,7 +345,37 @@ pub async fn sample_and_add_toks(
     let seqs_len = seqs.len();
     debug_assert_eq!(logits_seq.len(), seqs_len);
 
-    let use_async_pool = seqs_len > 1;
+    let metadata = this.get_metadata();
+    let eos_tok = if disable_eos_stop {
+        None
+    } else {
+        Some(&metadata.eos_tok[..])
+    };
+
+    let (beam_seqs, other_seqs): (Vec<_>, Vec<_>) = std::iter::zip(logits_seq, seqs.iter_mut())
+        .partition(|(_, seq)| seq.beam_search().is_some());
+    let (logits_seq, mut seqs): (Vec<_>, Vec<_>) = other_seqs.into_iter().unzip();
+
+    // The beams of a request are stepped together.
+    let mut beam_groups: Vec<(Vec<Tensor>, Vec<&mut Sequence>)> = Vec::new();
+    for (logits, seq) in beam_seqs {
+        let seq = &mut **seq;
+        match beam_groups
+            .iter_mut()
+            .find(|(_, beams)| beams[0].same_group(seq))
+        {
+            Some((group_logits, beams)) => {
+                group_logits.push(logits);
+                beams.push(seq);
+            }
+            None => beam_groups.push((vec![logits], vec![seq])),
+        }
+    }
+    for (logits, mut beams) in beam_groups {
+        step_beams(this, prefix_cacher, &mut beams, logits, eos_tok).await?;
+    }
+
+    let use_async_pool = seqs.len() > 1;
 
     let sampling_futures: Vec<_> = std::iter::zip(logits_seq, seqs.iter_mut())
         .map(|(logits_per_seq, seq)| {
@@ -327,19 +396,125 @@ pub async fn sample_and_add_toks(
     for (sampled, seq) in std::iter::zip(sampled_vec, seqs.iter_mut()) {
         let next_token = crate::handle_seq_error_stateaware_ok!(sampled, seq);
 
-        let metadata = this.get_metadata();
-        let eos_tok = if disable_eos_stop {
-            None
-        } else {
-            Some(&metadata.eos_tok[..])
-        };
-
         finish_or_add_toks_to_seq(this, prefix_cacher, seq, next_token, eos_tok, true).await?;
     }
 
     Ok(())
 }
 
+/// Advance the live beams of a beam search, the sequences of one group, by a token. Each beam
+/// continues one of the beams of the last step, whose state it takes, and the sequence with the
+/// best hypothesis is finished with it once the search is over.
+async fn step_beams(
+    this: &dyn Pipeline,
+    prefix_cacher: &mut PrefixCacheManagerV2,
+    beams: &mut [&mut Sequence],
+    logits: Vec<Tensor>,
+    eos_tok: Option<&[u32]>,
+) -> Result<()> {
+    let config = *beams[0]
+        .beam_search()
+        .expect("Expected the sequences of a beam search.");
+    // Number of generated tokens including the next one.
+    let len = beams[0].logprobs().len() + 1;
+    // The beams are identical after the prompt, so only the first one is continued.
+    let n_continued = if len == 1 { 1 } else { beams.len() };
+
+    let mut candidates = Vec::new();
+    for (logits, seq) in std::iter::zip(logits, beams.iter_mut()).take(n_continued) {
+        let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+        candidates.push(seq.sampler().beam_candidates(
+            logits,
+            seq.get_toks(),
+            2 * config.num_beams,
+            seq.return_logprobs(),
+        )?);
+    }
+    let cumulative_logprobs = beams
+        .iter()
+        .take(n_continued)
+        .map(|seq| seq.cumulative_logprob())
+        .collect::<Vec<_>>();
+
+    // The beams have the same length, so they reach the length limits together.
+    let at_max_len = matches!(
+        beams[0].is_done(u32::MAX, None, this.get_metadata().max_seq_len),
+        Some(StopReason::Length(_) | StopReason::ModelLength(_))
+    );
+    let is_eos = |tok: u32| {
+        eos_tok.is_some_and(|eos| eos.contains(&tok)) || beams[0].stop_tokens().contains(&tok)
+    };
+    let next = beams[0]
+        .get_mut_group()
+        .beam_hypotheses
+        .as_mut()
+        .expect("Expected the group of a beam search to have hypotheses.")
+        .step(
+            &cumulative_logprobs,
+            &candidates,
+            beams.len(),
+            len,
+            at_max_len,
+            is_eos,
+            |beam, tok| (beams[beam].beam_state(), tok.clone()),
+        );
+
+    let Some(next) = next else {
+        let (state, tok) = beams[0]
+            .get_mut_group()
+            .beam_hypotheses
+            .as_mut()
+            .and_then(|hypotheses| hypotheses.take_best())
+            .ok_or(candle_core::Error::Msg(
+                "Beam search finished without a hypothesis.".to_string(),
+            ))?;
+        beams[0].set_beam_state(state);
+        // The KV cache does not match the hypothesis, so it is not added to the prefix cache.
+        finish_or_add_toks_to_seq(this, prefix_cacher, beams[0], tok, eos_tok, false).await?;
+        let state = beams[0].getstate();
+        for seq in beams.iter().skip(1) {
+            seq.set_state(state);
+        }
+        return Ok(());
+    };
+
+    // There are fewer continuations than beams only for a vocabulary smaller than twice the
+    // number of beams.
+    for seq in beams.iter().skip(next.len()) {
+        seq.set_state(SequenceState::Done(StopReason::Canceled));
+    }
+
+    // Copy the states of the beams which are continued by another beam before any is replaced.
+    let states = next
+        .iter()
+        .enumerate()
+        .map(|(i, (beam, _))| {
+            if *beam == i {
+                Ok(None)
+            } else {
+                beams[*beam].beam_state_with_caches().map(Some)
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let metadata = this.get_metadata();
+    let tok_trie = metadata
+        .tok_env
+        .as_ref()
+        .ok_or(candle_core::Error::Msg(
+            "Beam search requires the pipeline to have a token trie".to_string(),
+        ))?
+        .tok_trie();
+    for ((seq, (_, tok)), state) in beams.iter_mut().zip(next).zip(states) {
+        if let Some(state) = state {
+            seq.set_beam_state(state);
+        }
+        let completion_bytes = tok_trie.decode(&[tok.token]);
+        seq.add_token(tok, completion_bytes, &None);
+    }
+
+    Ok(())
+}
+
 /// Async sample optionally adding to trie.
 #[allow(clippy::too_many_arguments)]
 pub async fn sample_sequence(
@@ -378,6 +553,9 @@ pub async fn sample_sequence(
         )?
     };
 
+    if add_to_trie {
+        seq.update_grammar().map_err(candle_core::Error::msg)?;
+    }
     let bias_if_not_allowed = match &mut seq.recognizer {
         SequenceRecognizer::Llguidance(ref mut llg) => {
             let step_res = llg.compute_mask().map_err(candle_core::Error::msg)?;
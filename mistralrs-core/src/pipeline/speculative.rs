@@ -6,7 +6,7 @@ use std::{
 };
 
 use anyhow::Result as anyhowResult;
-use candle_core::{Device, IndexOp, Result, Tensor};
+use candle_core::{DType, Device, IndexOp, Result, Tensor};
 use mistralrs_quant::IsqType;
 use rand_isaac::Isaac64Rng;
 use tokenizers::Tokenizer;
@@ -19,6 +19,7 @@ use crate::{
         finish_or_add_toks_to_seq, sample_sequence, sample_target_sequence_speculative,
     },
     prefix_cacher::PrefixCacheManagerV2,
+    response::SpeculativeStats,
     sequence::{Sequence, SequenceRecognizer},
     DeviceMapSetting, Loader, ModelKind, PagedAttentionConfig, Pipeline, TokenSource, TryIntoDType,
 };
@@ -156,11 +157,19 @@ impl Loader for SpeculativeLoader {
 ///     - This means the target model agrees
 /// - Else (q_i(x) > p_i(x)) accept that token with prob p_i(x)/q_i(x)
 ///     - If rejected, sample token from from p'_i(x) = norm(max(0, p(x) − q(x))) and do not take any more'
+/// - If all are kept, sample a bonus token from p_{γ+1}(x), the distribution after the last draft token
 ///
+/// With argmax sampling or a grammar, the draft tokens are instead kept while the target model
+/// samples the same tokens.
+///
+/// The number of draft tokens γ is adapted to the acceptance of the draft tokens of each
+/// sequence if [`SpeculativeConfig::gamma_bounds`] is set.
 pub struct SpeculativePipeline {
     target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
     draft: Arc<tokio::sync::Mutex<dyn Pipeline>>,
-    gamma: usize,
+    config: SpeculativeConfig,
+    /// Acceptance of the draft tokens of all sequences.
+    stats: Arc<Mutex<SpeculativeStats>>,
     metadata: Arc<GeneralMetadata>,
     category: ModelCategory,
 }
@@ -168,8 +177,55 @@ pub struct SpeculativePipeline {
 #[derive(Copy, Clone)]
 /// Metadata for a speculative pipeline
 pub struct SpeculativeConfig {
-    /// γ completions to run of the draft model
+    /// γ completions to run of the draft model, initially if it is adapted.
     pub gamma: usize,
+    /// Minimum and maximum γ, between which γ is adapted to the acceptance of the draft tokens of
+    /// each sequence. If `None`, γ is locked, for example for benchmarking.
+    pub gamma_bounds: Option<(usize, usize)>,
+}
+
+/// Share of the draft tokens of a step above which γ is doubled.
+const HIGH_ACCEPTANCE_RATE: f64 = 0.8;
+/// Share of the draft tokens of a step below which γ is halved.
+const LOW_ACCEPTANCE_RATE: f64 = 0.4;
+
+/// Draft length γ of a sequence, which is increased multiplicatively while most draft tokens are
+/// accepted and decreased while few are, within the bounds of the [`SpeculativeConfig`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct DraftLength {
+    gamma: usize,
+    bounds: Option<(usize, usize)>,
+}
+
+impl DraftLength {
+    pub(crate) fn new(config: &SpeculativeConfig) -> Self {
+        let gamma = match config.gamma_bounds {
+            Some((min, max)) => config.gamma.clamp(min, max),
+            None => config.gamma,
+        };
+        Self {
+            gamma,
+            bounds: config.gamma_bounds,
+        }
+    }
+
+    pub(crate) fn gamma(&self) -> usize {
+        self.gamma
+    }
+
+    /// Adapt γ after a step in which `accepted` of the γ draft tokens were accepted.
+    #[allow(clippy::cast_precision_loss)]
+    pub(crate) fn update(&mut self, accepted: usize) {
+        let Some((min, max)) = self.bounds else {
+            return;
+        };
+        let rate = accepted as f64 / self.gamma as f64;
+        if rate >= HIGH_ACCEPTANCE_RATE {
+            self.gamma = (self.gamma * 2).min(max);
+        } else if rate < LOW_ACCEPTANCE_RATE {
+            self.gamma = (self.gamma / 2).max(min);
+        }
+    }
 }
 
 impl SpeculativePipeline {
@@ -178,6 +234,16 @@ impl SpeculativePipeline {
         draft: Arc<tokio::sync::Mutex<dyn Pipeline>>,
         config: SpeculativeConfig,
     ) -> Result<Self> {
+        if config.gamma == 0 {
+            candle_core::bail!("Speculative decoding requires `gamma` to be at least 1.");
+        }
+        if let Some((min, max)) = config.gamma_bounds {
+            if min == 0 || min > max {
+                candle_core::bail!(
+                    "The bounds of `gamma` must satisfy 1 <= min <= max, got ({min}, {max})."
+                );
+            }
+        }
         if get_mut_arcmutex!(target)
             .tokenizer()
             .as_ref()
@@ -217,7 +283,8 @@ impl SpeculativePipeline {
         Ok(Self {
             target,
             draft,
-            gamma: config.gamma,
+            config,
+            stats: Arc::new(Mutex::new(SpeculativeStats::default())),
             metadata,
             category,
         })
@@ -293,7 +360,7 @@ impl MetadataMixin for SpeculativePipeline {
             "Speculative: tgt = `{}`, draft = `{}`, gamma = `{}`",
             get_mut_arcmutex!(self.target).name(),
             get_mut_arcmutex!(self.draft).name(),
-            self.gamma,
+            self.config.gamma,
         )
     }
     fn reset_non_granular_state(&self) {
@@ -306,6 +373,9 @@ impl MetadataMixin for SpeculativePipeline {
     fn device_mapper(&self) -> Option<&dyn DeviceMapper> {
         None
     }
+    fn speculative_stats(&self) -> Option<Arc<Mutex<SpeculativeStats>>> {
+        Some(self.stats.clone())
+    }
 }
 
 #[async_trait::async_trait]
@@ -358,11 +428,26 @@ impl Pipeline for SpeculativePipeline {
                 assert_eq!(input_seqs.len(), 1);
 
                 let seq = &mut input_seqs[0];
+                let gamma = seq.draft_length(&self.config).gamma();
 
                 // ======================= Run draft model gamma times producing tokens ============================
                 // ======================= Sample the `gamma` logits. ============================
+                // Without a grammar, the draft tokens are drawn from the distribution of the sampler
+                // for rejection sampling. Otherwise, and with argmax sampling, the draft tokens are
+                // kept while the target model samples the same tokens.
+                let sampler = seq.sampler();
+                let use_distributions = matches!(seq.recognizer, SequenceRecognizer::None);
                 let mut draft_samples = Vec::new();
-                for i in 0..self.gamma {
+                let mut draft_probs = Vec::new();
+                // After a bonus token, the draft model has not seen the last draft token, which it
+                // runs with the bonus token at the first step.
+                let draft_cache_len = cache_len(get_mut_arcmutex!(self.draft).cache());
+                let n_draft_uncached = seq.get_toks().len().saturating_sub(draft_cache_len);
+                for i in 0..gamma {
+                    let catch_up = !is_prompt && i == 0 && n_draft_uncached > 1;
+                    if catch_up {
+                        seq.set_prefill_toks(seq.get_toks()[draft_cache_len..].to_vec());
+                    }
                     let is_xlora = get_mut_arcmutex!(self.draft).get_metadata().is_xlora;
                     let device = get_mut_arcmutex!(self.draft).device();
                     let no_kv_cache = get_mut_arcmutex!(self.draft).get_metadata().no_kv_cache;
@@ -372,11 +457,11 @@ impl Pipeline for SpeculativePipeline {
                         .process_inputs(
                             self.tokenizer(),
                             &mut [seq],
-                            is_prompt && i == 0, // Only prompt (no kv cache) if first
+                            (is_prompt || catch_up) && i == 0, // Only prompt (no kv cache) if first
                             is_xlora,
                             &device,
                             no_kv_cache,
-                            None,
+                            catch_up.then_some((1, draft_cache_len)),
                             false,
                             None,
                             None, // TODO: get block tables/handle it
@@ -395,45 +480,54 @@ impl Pipeline for SpeculativePipeline {
                             "Speculative decoding requires `CausalGeneration` forward results"
                         );
                     };
+                    if catch_up {
+                        seq.reset_prefill_toks();
+                    }
 
-                    let sample = sample_sequence(
-                        logits.clone(),
-                        seq,
-                        seq.return_logprobs(),
-                        rng.clone(),
-                        false, // todo tune
-                        false, // do not add to tok trie yet
-                        true,
-                    )
-                    .await?;
+                    let probs = if use_distributions {
+                        let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+                        sampler.distribution(logits, seq.get_toks())?
+                    } else {
+                        None
+                    };
+                    let sample = match &probs {
+                        Some(probs) => sampler.sample_distribution(
+                            probs,
+                            seq.return_logprobs(),
+                            &mut rng.lock().expect("could not lock rng mutex"),
+                        )?,
+                        None => {
+                            sample_sequence(
+                                logits.clone(),
+                                seq,
+                                seq.return_logprobs(),
+                                rng.clone(),
+                                false, // todo tune
+                                false, // do not add to tok trie yet
+                                true,
+                            )
+                            .await?
+                        }
+                    };
                     seq.add_tmp_tok(sample.token);
                     draft_samples.push(SpeculativeSample { sample });
+                    draft_probs.push(probs);
                 }
-                seq.remove_tmp_tok(self.gamma);
+                seq.remove_tmp_tok(gamma);
 
-                // ======================= Add all draft tokens but the last one. Add the last from the seq. ============================
+                // ======================= Add all draft tokens and the last token of the seq. ============================
+                // The logits after the last draft token give a bonus token when all are accepted.
                 let mut draft_prefill_tokens = if is_prompt {
                     seq.get_toks().to_vec()
                 } else {
                     vec![*seq.get_toks().last().unwrap()]
                 };
-                for (i, sample) in draft_samples.iter().enumerate() {
-                    if i == draft_samples.len() - 1 {
-                        continue;
-                    }
-                    draft_prefill_tokens.push(sample.sample.token);
-                }
+                draft_prefill_tokens.extend(draft_samples.iter().map(|sample| sample.sample.token));
                 seq.set_prefill_toks(draft_prefill_tokens);
 
                 // ======================= Run the model with all draft tokens. ============================
 
-                let initial_cache_len = match get_mut_arcmutex!(self.target).cache() {
-                    EitherCache::Full(full) => full.lock()[0]
-                        .as_ref()
-                        .map(|(k, _)| k.dims()[2])
-                        .unwrap_or(0),
-                    EitherCache::Normal(normal) => normal.lock().unwrap().0[0].current_seq_len(),
-                };
+                let initial_cache_len = cache_len(get_mut_arcmutex!(self.target).cache());
 
                 // ========= Run the model ============
                 let is_xlora = get_mut_arcmutex!(self.target).get_metadata().is_xlora;
@@ -449,7 +543,7 @@ impl Pipeline for SpeculativePipeline {
                         is_xlora,
                         &device,
                         no_kv_cache,
-                        Some((self.gamma, initial_cache_len)), // Get the last gamma, see above
+                        Some((gamma + 1, initial_cache_len)), // Get the last gamma + 1, see above
                         false,
                         None,
                         None, // TODO: get block tables/handle it
@@ -474,38 +568,93 @@ impl Pipeline for SpeculativePipeline {
                 seq.reset_prefill_toks();
 
                 // ======================= Rejection sampling. ============================
-                // Map from each target sample to corresponding in draft sample
-                let samples = sample_target_sequence_speculative(
-                    logits.clone(),
-                    seq,
-                    seq.return_logprobs(),
-                    rng.clone(),
-                    self.gamma,
-                )
-                .await?;
-
-                let mut accepted_tokens = Vec::new();
-                for (target_sample, draft_sample) in zip(samples, draft_samples) {
-                    let tok = target_sample.sample.token;
-                    accepted_tokens.push(target_sample.sample);
-                    if draft_sample.sample.token != tok {
-                        break;
+                let accepted_tokens = match draft_probs.into_iter().collect::<Option<Vec<_>>>() {
+                    Some(draft_probs) => {
+                        let draft_tokens = draft_samples
+                            .iter()
+                            .map(|draft_sample| draft_sample.sample.token)
+                            .collect::<Vec<_>>();
+                        // The target distribution of each draft token, given the previous ones.
+                        let mut context = seq.get_toks().to_vec();
+                        let mut target_probs = Vec::new();
+                        for (i, chunk) in logits.chunk(gamma + 1, 1)?.into_iter().enumerate() {
+                            let logits = chunk.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+                            target_probs.push(
+                                sampler
+                                    .distribution(logits, &context)?
+                                    .expect("The draft tokens were drawn from a distribution."),
+                            );
+                            context.extend(draft_tokens.get(i));
+                        }
+                        let tokens = sampler.speculative_rejection_sample(
+                            &draft_tokens,
+                            &draft_probs,
+                            &target_probs,
+                            &mut rng.lock().expect("could not lock rng mutex"),
+                        )?;
+                        zip(tokens, &target_probs)
+                            .map(|(token, probs)| {
+                                sampler.distribution_logprobs(probs, token, seq.return_logprobs())
+                            })
+                            .collect::<Result<Vec<_>>>()?
                     }
-                }
+                    None => {
+                        // Map from each target sample to corresponding in draft sample
+                        let samples = sample_target_sequence_speculative(
+                            logits.clone(),
+                            seq,
+                            seq.return_logprobs(),
+                            rng.clone(),
+                            gamma + 1,
+                        )
+                        .await?;
+
+                        // The target token after the last draft token is a bonus token.
+                        let mut accepted_tokens = Vec::new();
+                        for (i, target_sample) in samples.into_iter().enumerate() {
+                            let tok = target_sample.sample.token;
+                            accepted_tokens.push(target_sample.sample);
+                            if draft_samples
+                                .get(i)
+                                .is_none_or(|draft_sample| draft_sample.sample.token != tok)
+                            {
+                                break;
+                            }
+                        }
+                        accepted_tokens
+                    }
+                };
+
+                // ======================= Adapt gamma to the accepted draft tokens ============================
+                // A token sampled from the target model after a rejection differs from the draft
+                // token.
+                let n_accepted_draft = zip(&accepted_tokens, &draft_samples)
+                    .take_while(|(accepted, draft)| accepted.token == draft.sample.token)
+                    .count();
+                seq.draft_length(&self.config).update(n_accepted_draft);
+                seq.record_speculative_step(gamma, n_accepted_draft, accepted_tokens.len());
+                self.stats
+                    .lock()
+                    .expect("Speculative stats were poisoned")
+                    .record(gamma, n_accepted_draft, accepted_tokens.len());
 
                 // ======================= Narrow caches to account for rejections ============================
-                let n_not_accepted = self.gamma - accepted_tokens.len();
+                // The caches keep the last token of the seq and the accepted tokens but the last
+                // one. The draft model did not run the last draft token, so a bonus token leaves
+                // it one token behind.
+                let n_not_accepted_draft = gamma - accepted_tokens.len().min(gamma);
+                let n_not_accepted_target = gamma + 1 - accepted_tokens.len();
                 match get_mut_arcmutex!(self.draft).cache() {
                     EitherCache::Full(full) => {
                         for (k, v) in full.lock().iter_mut().flatten() {
-                            *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                            *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+                            *k = k.i((.., .., ..k.dims()[2] - n_not_accepted_draft, ..))?;
+                            *v = v.i((.., .., ..v.dims()[2] - n_not_accepted_draft, ..))?;
                         }
                     }
                     EitherCache::Normal(normal) => {
                         for cache in &mut *normal.lock().unwrap().0 {
                             cache
-                                .set_len(cache.current_seq_len() - n_not_accepted)
+                                .set_len(cache.current_seq_len() - n_not_accepted_draft)
                                 .map_err(|_| candle_core::Error::msg("KV cache set_len failed."))?;
                         }
                     }
@@ -514,8 +663,8 @@ impl Pipeline for SpeculativePipeline {
                     match get_mut_arcmutex!(self.draft).cache() {
                         EitherCache::Full(full) => {
                             for (k, v) in full.xlora_lock().iter_mut().flatten() {
-                                *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                                *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+                                *k = k.i((.., .., ..k.dims()[2] - n_not_accepted_draft, ..))?;
+                                *v = v.i((.., .., ..v.dims()[2] - n_not_accepted_draft, ..))?;
                             }
                         }
                         EitherCache::Normal(_) => {
@@ -526,14 +675,14 @@ impl Pipeline for SpeculativePipeline {
                 match get_mut_arcmutex!(self.target).cache() {
                     EitherCache::Full(full) => {
                         for (k, v) in full.lock().iter_mut().flatten() {
-                            *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                            *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+                            *k = k.i((.., .., ..k.dims()[2] - n_not_accepted_target, ..))?;
+                            *v = v.i((.., .., ..v.dims()[2] - n_not_accepted_target, ..))?;
                         }
                     }
                     EitherCache::Normal(normal) => {
                         for cache in &mut *normal.lock().unwrap().0 {
                             cache
-                                .set_len(cache.current_seq_len() - n_not_accepted)
+                                .set_len(cache.current_seq_len() - n_not_accepted_target)
                                 .map_err(|_| candle_core::Error::msg("KV cache set_len failed."))?;
                         }
                     }
@@ -542,8 +691,8 @@ impl Pipeline for SpeculativePipeline {
                     match get_mut_arcmutex!(self.target).cache() {
                         EitherCache::Full(full) => {
                             for (k, v) in full.xlora_lock().iter_mut().flatten() {
-                                *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                                *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+                                *k = k.i((.., .., ..k.dims()[2] - n_not_accepted_target, ..))?;
+                                *v = v.i((.., .., ..v.dims()[2] - n_not_accepted_target, ..))?;
                             }
                         }
                         EitherCache::Normal(_) => {
@@ -563,6 +712,7 @@ impl Pipeline for SpeculativePipeline {
                 };
                 // Add the tokens to the seq and the trie
                 for accepted in accepted_tokens {
+                    seq.update_grammar().map_err(candle_core::Error::msg)?;
                     // Do not use the prefix cacher
                     finish_or_add_toks_to_seq(
                         self,
@@ -641,3 +791,83 @@ impl Pipeline for SpeculativePipeline {
 }
 
 impl AnyMoePipelineMixin for SpeculativePipeline {}
+
+/// Number of tokens in the KV cache of the first layer.
+fn cache_len(cache: &EitherCache) -> usize {
+    match cache {
+        EitherCache::Full(full) => full.lock()[0]
+            .as_ref()
+            .map(|(k, _)| k.dims()[2])
+            .unwrap_or(0),
+        EitherCache::Normal(normal) => normal.lock().unwrap().0[0].current_seq_len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DraftLength, SpeculativeConfig};
+    use crate::response::SpeculativeStats;
+
+    /// γ after each step, accepting the draft tokens while `accept(step, i)` holds.
+    fn simulate(config: SpeculativeConfig, accept: impl Fn(usize, usize) -> bool) -> Vec<usize> {
+        let mut draft_length = DraftLength::new(&config);
+        (0..8)
+            .map(|step| {
+                let gamma = draft_length.gamma();
+                let accepted = (0..gamma).take_while(|i| accept(step, *i)).count();
+                draft_length.update(accepted);
+                draft_length.gamma()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn adapts_gamma_within_bounds() {
+        let config = SpeculativeConfig {
+            gamma: 4,
+            gamma_bounds: Some((2, 16)),
+        };
+        // Easy at first, then the first draft token is always rejected.
+        assert_eq!(
+            simulate(config, |step, _| step < 4),
+            vec![8, 16, 16, 16, 8, 4, 2, 2]
+        );
+        // Accepting 3 draft tokens keeps γ 4, but is too few for γ 8.
+        assert_eq!(
+            simulate(config, |step, i| step == 0 || i < 3),
+            vec![8, 4, 4, 4, 4, 4, 4, 4]
+        );
+    }
+
+    #[test]
+    fn locked_gamma() {
+        let config = SpeculativeConfig {
+            gamma: 4,
+            gamma_bounds: None,
+        };
+        assert_eq!(simulate(config, |_, _| true), vec![4; 8]);
+        assert_eq!(simulate(config, |_, _| false), vec![4; 8]);
+    }
+
+    #[test]
+    fn initial_gamma_is_clamped() {
+        let config = SpeculativeConfig {
+            gamma: 32,
+            gamma_bounds: Some((1, 8)),
+        };
+        assert_eq!(DraftLength::new(&config).gamma(), 8);
+    }
+
+    #[test]
+    fn stats() {
+        let mut stats = SpeculativeStats::default();
+        // All 4 draft tokens accepted, then 1 accepted and a token sampled after the rejection.
+        stats.record(4, 4, 4);
+        stats.record(4, 1, 2);
+        assert_eq!(stats.draft_tokens, 8);
+        assert_eq!(stats.accepted_draft_tokens, 5);
+        assert_eq!(stats.wasted_draft_tokens, 3);
+        assert_eq!(stats.acceptance_rate, 5. / 8.);
+        assert_eq!(stats.effective_speedup, 3.);
+    }
+}
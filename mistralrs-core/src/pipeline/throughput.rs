@@ -0,0 +1,116 @@
+use std::{sync::Mutex, time::Duration};
+
+use serde::Serialize;
+
+/// Weight of the most recent generation in the moving average.
+pub const DEFAULT_THROUGHPUT_EMA_ALPHA: f64 = 0.2;
+
+/// Estimated throughput of a pipeline, in tokens per second.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct ThroughputEstimate {
+    /// Prompt processing throughput, including the time spent waiting to be scheduled.
+    pub prompt_tps: f64,
+    /// Decode throughput of a single sequence.
+    pub decode_tps: f64,
+    /// Number of generations which contributed to the estimate.
+    pub samples: usize,
+}
+
+/// Maintains an exponential moving average of the throughput of finished generations.
+pub struct ThroughputTracker {
+    alpha: f64,
+    estimate: Mutex<ThroughputEstimate>,
+}
+
+impl ThroughputTracker {
+    /// `alpha` is the weight, in `(0, 1]`, of each new generation.
+    pub fn new(alpha: f64) -> Self {
+        assert!(alpha > 0. && alpha <= 1., "`alpha` must be in (0, 1]");
+        Self {
+            alpha,
+            estimate: Mutex::new(ThroughputEstimate::default()),
+        }
+    }
+
+    /// Record a finished generation. Phases with no tokens or no elapsed time do not change
+    /// their part of the estimate.
+    pub fn record(
+        &self,
+        prompt_toks: usize,
+        prompt_time: Duration,
+        decode_toks: usize,
+        decode_time: Duration,
+    ) {
+        let mut estimate = self
+            .estimate
+            .lock()
+            .expect("Throughput estimate was poisoned");
+        let update = |current: f64, toks: usize, time: Duration| {
+            #[allow(clippy::cast_precision_loss)]
+            let tps = toks as f64 / time.as_secs_f64();
+            if current == 0. {
+                tps
+            } else {
+                self.alpha * tps + (1. - self.alpha) * current
+            }
+        };
+        if prompt_toks > 0 && !prompt_time.is_zero() {
+            estimate.prompt_tps = update(estimate.prompt_tps, prompt_toks, prompt_time);
+        }
+        if decode_toks > 0 && !decode_time.is_zero() {
+            estimate.decode_tps = update(estimate.decode_tps, decode_toks, decode_time);
+        }
+        estimate.samples += 1;
+    }
+
+    pub fn estimate(&self) -> ThroughputEstimate {
+        *self
+            .estimate
+            .lock()
+            .expect("Throughput estimate was poisoned")
+    }
+}
+
+impl Default for ThroughputTracker {
+    fn default() -> Self {
+        Self::new(DEFAULT_THROUGHPUT_EMA_ALPHA)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::ThroughputTracker;
+
+    #[test]
+    fn estimate_stabilizes_near_measured_rate() {
+        let tracker = ThroughputTracker::default();
+        // Decode at about 50 T/s with some jitter, prompt at 1000 T/s.
+        for i in 0..40 {
+            let decode_ms = if i % 2 == 0 { 1900 } else { 2100 };
+            tracker.record(
+                500,
+                Duration::from_millis(500),
+                100,
+                Duration::from_millis(decode_ms),
+            );
+        }
+        let estimate = tracker.estimate();
+        assert_eq!(estimate.samples, 40);
+        assert!((estimate.prompt_tps - 1000.).abs() < 1e-6);
+        assert!((estimate.decode_tps - 50.).abs() < 2.);
+    }
+
+    #[test]
+    fn estimate_follows_rate_change() {
+        let tracker = ThroughputTracker::new(0.5);
+        tracker.record(0, Duration::ZERO, 10, Duration::from_secs(1));
+        assert_eq!(tracker.estimate().decode_tps, 10.);
+        assert_eq!(tracker.estimate().prompt_tps, 0.);
+        for _ in 0..20 {
+            tracker.record(0, Duration::ZERO, 40, Duration::from_secs(1));
+        }
+        assert!((tracker.estimate().decode_tps - 40.).abs() < 1e-3);
+    }
+}
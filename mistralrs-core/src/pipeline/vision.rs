@@ -3,10 +3,10 @@ use super::isq::ImatrixDataSource;
 use super::isq::UqffFullSer;
 use super::{
     get_model_paths, get_xlora_paths, AdapterKind, AnyMoePipelineMixin, CacheManager,
-    CacheManagerMixin, EitherCache, ForwardInputsResult, Gemma3Loader, GeneralMetadata,
+    CacheManagerMixin, EitherCache, ForwardInputsResult, Gemma3Loader, GeneralMetadata, HubRepo,
     IsqPipelineMixin, Loader, MetadataMixin, MiniCpmOLoader, ModelCategory, ModelKind, ModelPaths,
-    Phi4MMLoader, PreProcessingMixin, Processor, Qwen2VLLoader, TokenSource, VLlamaLoader,
-    VisionModel, VisionModelLoader, VisionPromptPrefixer,
+    Phi4MMLoader, PreProcessingMixin, Processor, Qwen2VLLoader, ThroughputTracker, TokenSource,
+    VLlamaLoader, VisionModel, VisionModelLoader, VisionPromptPrefixer,
 };
 use super::{
     Idefics2Loader, Idefics3Loader, LLaVALoader, LLaVANextLoader, Mistral3Loader, Phi3VLoader,
@@ -14,15 +14,17 @@ use super::{
 };
 use crate::device_map::{self, DeviceMapper};
 use crate::distributed::{self, WorkerTransferData};
+use crate::fingerprint::model_fingerprint;
 use crate::paged_attention::{calculate_cache_config, AttentionImplementation, CacheEngine};
 use crate::pipeline::chat_template::{calculate_eos_tokens, GenerationConfig};
-use crate::pipeline::llg::build_tok_env;
+use crate::pipeline::llg::build_or_reuse_tok_env;
 use crate::pipeline::sampling::sample_and_add_toks;
 use crate::pipeline::text_models_inputs_processor::make_prompt_chunk;
 use crate::pipeline::{get_chat_template, ChatTemplate, IsqOrganization, LocalModelPaths};
 use crate::prefix_cacher::PrefixCacheManagerV2;
 use crate::sequence::Sequence;
-use crate::utils::tokenizer::get_tokenizer;
+use crate::utils::hub_proxy::HubProxy;
+use crate::utils::tokenizer::{get_tokenizer, set_byte_fallback};
 use crate::utils::varbuilder_utils::DeviceForLoadTensor;
 use crate::utils::{tokens::get_token, varbuilder_utils::from_mmaped_safetensors};
 use crate::vision_models::preprocessor_config::PreProcessorConfig;
@@ -35,9 +37,10 @@ use crate::{
 };
 use anyhow::Result;
 use candle_core::{Device, Tensor, Var};
+use hf_hub::api::sync::ApiBuilder;
 use hf_hub::Cache;
-use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
 use indicatif::MultiProgress;
+use llguidance::toktrie::TokEnv;
 use mistralrs_quant::{AfqLayer, GgufMatMul, HqqLayer, IsqType, QuantizedSerdeType};
 use rand_isaac::Isaac64Rng;
 use regex_automata::meta::Regex;
@@ -91,6 +94,7 @@ pub struct VisionLoader {
     jinja_explicit: Option<String>,
     hf_cache_path: Option<PathBuf>,
     lora_adapter_ids: Option<Vec<String>>,
+    proxy: Option<HubProxy>,
 }
 
 #[derive(Default)]
@@ -104,6 +108,7 @@ pub struct VisionLoaderBuilder {
     jinja_explicit: Option<String>,
     hf_cache_path: Option<PathBuf>,
     lora_adapter_ids: Option<Vec<String>>,
+    proxy: Option<HubProxy>,
 }
 
 #[derive(Clone, Default)]
@@ -118,6 +123,11 @@ pub struct VisionSpecificConfig {
     pub imatrix: Option<PathBuf>,
     pub calibration_file: Option<PathBuf>,
     pub hf_cache_path: Option<PathBuf>,
+    /// Force byte fallback of the tokenizer on or off, overriding the tokenizer's default.
+    pub byte_fallback: Option<bool>,
+    /// Token environment used instead of building one from the tokenizer, such as one shared by
+    /// the models of a vocabulary. Its tokens must be those of the tokenizer.
+    pub tok_env: Option<TokEnv>,
 }
 
 impl VisionLoaderBuilder {
@@ -145,6 +155,13 @@ impl VisionLoaderBuilder {
         self
     }
 
+    /// Download the model from the Hugging Face Hub through an HTTP proxy, optionally
+    /// authenticating with a user and password, for networks without direct internet access.
+    pub fn with_proxy(mut self, proxy_url: &str, proxy_auth: Option<(&str, &str)>) -> Self {
+        self.proxy = Some(HubProxy::new(proxy_url, proxy_auth));
+        self
+    }
+
     pub fn with_lora(mut self, lora_adapter_ids: Vec<String>) -> Self {
         self.kind = ModelKind::Adapter {
             adapter: AdapterKind::Lora,
@@ -183,6 +200,7 @@ impl VisionLoaderBuilder {
             from_uqff: RwLock::new(None),
             hf_cache_path: self.hf_cache_path,
             lora_adapter_ids: self.lora_adapter_ids,
+            proxy: self.proxy,
         })
     }
 }
@@ -247,6 +265,9 @@ impl Loader for VisionLoader {
         in_situ_quant: Option<IsqType>,
         mut paged_attn_config: Option<PagedAttentionConfig>,
     ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
+        if matches!(self.kind, ModelKind::Adapter { .. }) {
+            anyhow::bail!("LoRA adapters are not supported for vision models.");
+        }
         let config = std::fs::read_to_string(paths.get_config_filename())?;
 
         if !self.inner.supports_paged_attention() {
@@ -502,9 +523,12 @@ impl Loader for VisionLoader {
             self.config.max_edge,
         ); //There are always some repos that don't properly handle config position, for example... LLaVA
 
-        let tokenizer = get_tokenizer(
-            paths.get_tokenizer_filename(),
-            Some(processor.get_special_tokens()),
+        let tokenizer = set_byte_fallback(
+            get_tokenizer(
+                paths.get_tokenizer_filename(),
+                Some(processor.get_special_tokens()),
+            )?,
+            self.config.byte_fallback,
         )?;
 
         let gen_conf: Option<GenerationConfig> = paths.get_gen_conf_filename().map(|f| {
@@ -654,18 +678,26 @@ impl Loader for VisionLoader {
         };
 
         let max_seq_len = model.max_seq_len();
-        let tok_env = build_tok_env(tokenizer.clone());
+        let tok_env = build_or_reuse_tok_env(tokenizer.clone(), self.config.tok_env.clone())?;
         let num_hidden_layers = match model.cache() {
             EitherCache::Full(full) => full.lock().len(),
             EitherCache::Normal(normal) => normal.lock().unwrap().0.len(),
         };
+        let generation_defaults = gen_conf
+            .as_ref()
+            .map(GenerationConfig::sampling_defaults)
+            .unwrap_or_default();
         let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
         let sliding_window = model.config().sliding_window;
         let model_metadata = Arc::new(model.config().clone());
+        let system_fingerprint = model_fingerprint(
+            paths.as_ref(),
+            in_situ_quant.map(|isq| format!("{isq:?}")).as_deref(),
+        );
         Ok(Arc::new(Mutex::new(VisionPipeline {
             model,
             tokenizer: tokenizer.into(),
-            chat_template: Arc::new(chat_template),
+            chat_template: Arc::new(chat_template.with_detected_system_role()),
             model_id: self.model_id.clone(),
             metadata: Arc::new(GeneralMetadata {
                 max_seq_len,
@@ -682,6 +714,12 @@ impl Loader for VisionLoader {
                 cache_engine,
                 prompt_chunksize: self.config.prompt_chunksize,
                 model_metadata: Some(model_metadata),
+                throughput: Arc::new(ThroughputTracker::default()),
+                prompt_format: None,
+                system_fingerprint,
+                generation_defaults,
+                lora_adapters: Vec::new(),
+                commit_hash: paths.get_commit_hash().map(str::to_string),
             }),
             processor,
             prefixer: self.inner.prefixer(),
@@ -931,11 +969,7 @@ impl AnyMoePipelineMixin for VisionPipeline {
                 api.build().map_err(candle_core::Error::msg)?
             };
             let revision = revision.clone().unwrap_or("main".to_string());
-            let api = api.repo(Repo::with_revision(
-                model_id_str.clone(),
-                RepoType::Model,
-                revision.clone(),
-            ));
+            let api = HubRepo::new(&api, model_id_str.clone(), revision, token);
 
             let mut filenames = vec![];
             for rfilename in api_dir_list!(api, model_id).filter(|x| x.ends_with(".safetensors")) {
@@ -987,11 +1021,7 @@ impl AnyMoePipelineMixin for VisionPipeline {
                 api.build().map_err(candle_core::Error::msg)?
             };
             let revision = revision.clone().unwrap_or("main".to_string());
-            let api = api.repo(Repo::with_revision(
-                model_id_str.clone(),
-                RepoType::Model,
-                revision.clone(),
-            ));
+            let api = HubRepo::new(&api, model_id_str.clone(), revision, token);
 
             let mut gate_filenames = vec![];
             for rfilename in api_dir_list!(api, model_id).filter(|x| x.ends_with(".safetensors")) {
@@ -0,0 +1,286 @@
+//! Policies choosing which prefix caches are offloaded to the CPU and dropped.
+//!
+//! Policies are combined: an entry protected by any policy is never dropped, an entry expired by
+//! any policy is dropped at the next eviction, and when the cache is over its memory budget the
+//! remaining entries are dropped in the order given by the ranking policies, the first one taking
+//! precedence.
+
+use std::{
+    fmt::Debug,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// What an eviction policy sees of a prefix cache.
+#[derive(Clone, Debug)]
+pub struct PrefixCacheEntry<'a> {
+    pub toks: &'a [u32],
+    /// Memory held by the KV cache of the prefix.
+    pub bytes: usize,
+    /// Number of times the prefix was matched.
+    pub hits: usize,
+    /// Logical time of the last insertion or match, increasing with every access.
+    pub last_access: u64,
+    pub last_access_time: Instant,
+}
+
+/// Strategy to evict prefix caches. See the module documentation for how policies are combined.
+pub trait EvictionPolicy: Debug + Send + Sync {
+    /// Name of the policy in the metrics.
+    fn name(&self) -> String;
+
+    /// Entries which must never be dropped.
+    fn protects(&self, _entry: &PrefixCacheEntry) -> bool {
+        false
+    }
+
+    /// Entries to drop regardless of the memory used.
+    fn expired(&self, _entry: &PrefixCacheEntry, _now: Instant) -> bool {
+        false
+    }
+
+    /// Rank of the entry under memory pressure, the lowest ranks are evicted first. `None` if the
+    /// policy does not rank entries.
+    fn rank(&self, _entry: &PrefixCacheEntry) -> Option<u64> {
+        None
+    }
+
+    /// The pin list of this policy, to pin and unpin prefixes at runtime.
+    fn pin_list(&mut self) -> Option<&mut PinList> {
+        None
+    }
+}
+
+/// Evict the least recently used prefixes first.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Lru;
+
+impl EvictionPolicy for Lru {
+    fn name(&self) -> String {
+        "lru".to_string()
+    }
+
+    fn rank(&self, entry: &PrefixCacheEntry) -> Option<u64> {
+        Some(entry.last_access)
+    }
+}
+
+/// Evict the least frequently matched prefixes first.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Lfu;
+
+impl EvictionPolicy for Lfu {
+    fn name(&self) -> String {
+        "lfu".to_string()
+    }
+
+    fn rank(&self, entry: &PrefixCacheEntry) -> Option<u64> {
+        Some(entry.hits as u64)
+    }
+}
+
+/// Drop prefixes which were not used for a time-to-live.
+#[derive(Clone, Copy, Debug)]
+pub struct Ttl {
+    pub ttl: Duration,
+}
+
+impl EvictionPolicy for Ttl {
+    fn name(&self) -> String {
+        format!("ttl={}", self.ttl.as_secs())
+    }
+
+    fn expired(&self, entry: &PrefixCacheEntry, now: Instant) -> bool {
+        now.saturating_duration_since(entry.last_access_time) > self.ttl
+    }
+}
+
+/// Keep the prefixes of the list, for example system prompts, forever.
+///
+/// Only the exact pinned prefixes are protected: when a cached sequence starts with a pinned
+/// prefix, a copy of its KV cache truncated to the prefix is kept.
+#[derive(Clone, Debug, Default)]
+pub struct PinList {
+    prefixes: Vec<Vec<u32>>,
+}
+
+impl PinList {
+    pub fn new(prefixes: Vec<Vec<u32>>) -> Self {
+        Self { prefixes }
+    }
+
+    pub fn prefixes(&self) -> &[Vec<u32>] {
+        &self.prefixes
+    }
+
+    /// Returns whether the prefix was not pinned yet.
+    pub fn pin(&mut self, toks: Vec<u32>) -> bool {
+        if toks.is_empty() || self.prefixes.contains(&toks) {
+            return false;
+        }
+        self.prefixes.push(toks);
+        true
+    }
+
+    /// Returns whether the prefix was pinned.
+    pub fn unpin(&mut self, toks: &[u32]) -> bool {
+        let n = self.prefixes.len();
+        self.prefixes.retain(|p| p != toks);
+        self.prefixes.len() != n
+    }
+}
+
+impl EvictionPolicy for PinList {
+    fn name(&self) -> String {
+        "pinned".to_string()
+    }
+
+    fn protects(&self, entry: &PrefixCacheEntry) -> bool {
+        self.prefixes.iter().any(|p| p == entry.toks)
+    }
+
+    fn pin_list(&mut self) -> Option<&mut PinList> {
+        Some(self)
+    }
+}
+
+/// A policy of a [`PrefixCacheEvictionConfig`], parsed from `lru`, `lfu`, `ttl=<seconds>` or
+/// `pinned`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "policy", rename_all = "lowercase")]
+pub enum EvictionPolicyConfig {
+    Lru,
+    Lfu,
+    Ttl {
+        ttl_secs: u64,
+    },
+    Pinned {
+        #[serde(default)]
+        prefixes: Vec<Vec<u32>>,
+    },
+}
+
+impl EvictionPolicyConfig {
+    pub fn build(&self) -> Box<dyn EvictionPolicy> {
+        match self {
+            Self::Lru => Box::new(Lru),
+            Self::Lfu => Box::new(Lfu),
+            Self::Ttl { ttl_secs } => Box::new(Ttl {
+                ttl: Duration::from_secs(*ttl_secs),
+            }),
+            Self::Pinned { prefixes } => Box::new(PinList::new(prefixes.clone())),
+        }
+    }
+}
+
+impl FromStr for EvictionPolicyConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().split_once('=') {
+            None if s.trim() == "lru" => Ok(Self::Lru),
+            None if s.trim() == "lfu" => Ok(Self::Lfu),
+            None if s.trim() == "pinned" => Ok(Self::Pinned { prefixes: vec![] }),
+            Some(("ttl", secs)) => secs
+                .trim()
+                .parse()
+                .map(|ttl_secs| Self::Ttl { ttl_secs })
+                .map_err(|_| format!("Expected a number of seconds in `{s}`.")),
+            _ => Err(format!(
+                "Unknown prefix cache eviction policy `{s}`, expected `lru`, `lfu`, `ttl=<seconds>` or `pinned`."
+            )),
+        }
+    }
+}
+
+/// How the prefix cache evicts its entries.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrefixCacheEvictionConfig {
+    /// Policies combined to evict entries. LRU is added if no policy ranks entries.
+    pub policies: Vec<EvictionPolicyConfig>,
+    /// Maximum memory held by the prefix cache, on the device and the CPU. Unlimited if `None`.
+    pub max_bytes: Option<usize>,
+}
+
+impl Default for PrefixCacheEvictionConfig {
+    fn default() -> Self {
+        Self {
+            policies: vec![EvictionPolicyConfig::Lru],
+            max_bytes: None,
+        }
+    }
+}
+
+impl PrefixCacheEvictionConfig {
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+}
+
+impl FromStr for PrefixCacheEvictionConfig {
+    type Err = String;
+
+    /// Parse a comma separated list of policies, for example `pinned,ttl=600,lru`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            policies: s
+                .split(',')
+                .map(EvictionPolicyConfig::from_str)
+                .collect::<Result<_, _>>()?,
+            max_bytes: None,
+        })
+    }
+}
+
+/// Metrics of one eviction policy.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct EvictionPolicyMetrics {
+    pub policy: String,
+    /// Entries dropped because of this policy.
+    pub evictions: usize,
+    /// Memory of the entries governed by this policy: the protected entries for a pin list, and
+    /// the unprotected entries otherwise.
+    pub bytes_held: usize,
+    /// Matches of entries governed by this policy.
+    pub hits: usize,
+    /// Matches of entries governed by this policy over all lookups.
+    pub hit_ratio: f64,
+}
+
+/// Metrics of the prefix cache.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PrefixCacheMetrics {
+    pub entries: usize,
+    pub bytes: usize,
+    pub lookups: usize,
+    pub hits: usize,
+    /// Entries moved from the device to the CPU.
+    pub offloads: usize,
+    pub pinned: Vec<Vec<u32>>,
+    pub policies: Vec<EvictionPolicyMetrics>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::{EvictionPolicyConfig, PrefixCacheEvictionConfig};
+
+    #[test]
+    fn parse_config() {
+        let config = PrefixCacheEvictionConfig::from_str("pinned, ttl=600,lfu").unwrap();
+        assert_eq!(
+            config.policies,
+            [
+                EvictionPolicyConfig::Pinned { prefixes: vec![] },
+                EvictionPolicyConfig::Ttl { ttl_secs: 600 },
+                EvictionPolicyConfig::Lfu,
+            ]
+        );
+        assert!(PrefixCacheEvictionConfig::from_str("lru,ttl=ten").is_err());
+        assert!(PrefixCacheEvictionConfig::from_str("fifo").is_err());
+    }
+}
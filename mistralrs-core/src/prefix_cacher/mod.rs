@@ -0,0 +1,811 @@
+use std::{collections::HashMap, time::Instant};
+
+use candle_core::{Device, Result};
+use either::Either;
+use itertools::Itertools;
+use tracing::info;
+
+use crate::{
+    pipeline::{KvCache, RotatingCache, SingleCache},
+    sequence::Sequence,
+};
+
+mod eviction;
+mod persistence;
+
+pub use eviction::{
+    EvictionPolicy, EvictionPolicyConfig, EvictionPolicyMetrics, Lfu, Lru, PinList,
+    PrefixCacheEntry, PrefixCacheEvictionConfig, PrefixCacheMetrics, Ttl,
+};
+pub use persistence::PrefixCachePersistence;
+use persistence::PrefixRef;
+pub(crate) use persistence::{persistence_fingerprint, PersistedPrefix};
+
+#[derive(PartialEq, Eq, Debug, Hash)]
+struct Tokens(Vec<u32>);
+
+impl Tokens {
+    /// Maximum index to where the two sets of tokens match
+    fn find_max_index(&self, x: &Self) -> Option<usize> {
+        self.0
+            .iter()
+            .zip(x.0.iter())
+            .enumerate()
+            .take_while(|(_, (a, b))| a == b)
+            .map(|(index, _)| index)
+            .last()
+    }
+}
+
+impl From<Vec<u32>> for Tokens {
+    fn from(value: Vec<u32>) -> Self {
+        Self(value)
+    }
+}
+
+#[derive(Clone)]
+struct CacheElement {
+    cache: Vec<Option<KvCache>>,
+    devices: Vec<Option<Device>>,
+    /// Number of times this cache was matched, used to choose the caches to persist.
+    hits: usize,
+    bytes: usize,
+    last_access: u64,
+    last_access_time: Instant,
+}
+
+impl CacheElement {
+    fn new(cache: Vec<Option<KvCache>>, devices: Vec<Option<Device>>, hits: usize) -> Self {
+        let bytes = cache_bytes(&cache);
+        Self {
+            cache,
+            devices,
+            hits,
+            bytes,
+            last_access: 0,
+            last_access_time: Instant::now(),
+        }
+    }
+
+    fn entry<'a>(&self, toks: &'a Tokens) -> PrefixCacheEntry<'a> {
+        PrefixCacheEntry {
+            toks: &toks.0,
+            bytes: self.bytes,
+            hits: self.hits,
+            last_access: self.last_access,
+            last_access_time: self.last_access_time,
+        }
+    }
+
+    fn is_on_device(&self) -> bool {
+        let first_non_none = self.cache.iter().find_or_first(|x| x.is_some());
+        let Some(Some(first_non_none)) = first_non_none else {
+            return false;
+        };
+
+        let cache_device = match first_non_none {
+            KvCache::Normal { k, .. } => k.all_data().as_ref().expect("No KV cache data").device(),
+            KvCache::Rotating { k, .. } => {
+                k.all_data().as_ref().expect("No KV cache data").device()
+            }
+        };
+        !matches!(cache_device, Device::Cpu)
+    }
+}
+
+/// Memory held by the K and V tensors of a cache.
+fn cache_bytes(cache: &[Option<KvCache>]) -> usize {
+    let tensor_bytes = |t: &Option<candle_core::Tensor>| {
+        t.as_ref()
+            .map(|t| t.elem_count() * t.dtype().size_in_bytes())
+            .unwrap_or(0)
+    };
+    cache
+        .iter()
+        .flatten()
+        .map(|layer| match layer {
+            KvCache::Normal { k, v } => tensor_bytes(&k.all_data) + tensor_bytes(&v.all_data),
+            KvCache::Rotating { k, v } => tensor_bytes(&k.all_data) + tensor_bytes(&v.all_data),
+        })
+        .sum()
+}
+
+/// A copy of the first `len` tokens of a cache. Normal caches are copied to free the memory of
+/// the rest of the sequence.
+fn truncated_cache(cache: &[Option<KvCache>], len: usize) -> Result<Vec<Option<KvCache>>> {
+    let truncate = |c: &SingleCache| -> Result<SingleCache> {
+        Ok(SingleCache {
+            all_data: match &c.all_data {
+                Some(data) => Some(data.narrow(c.dim, 0, len)?.contiguous()?),
+                None => None,
+            },
+            dim: c.dim,
+            current_seq_len: len,
+            max_seq_len: c.max_seq_len,
+            capacity_seq_len: len,
+        })
+    };
+    cache
+        .iter()
+        .map(|layer| {
+            layer
+                .as_ref()
+                .map(|layer| match layer {
+                    KvCache::Normal { k, v } => Ok(KvCache::Normal {
+                        k: truncate(k)?,
+                        v: truncate(v)?,
+                    }),
+                    KvCache::Rotating { .. } => {
+                        let mut layer = layer.clone();
+                        layer.set_len(len)?;
+                        Ok(layer)
+                    }
+                })
+                .transpose()
+        })
+        .collect()
+}
+
+/// An eviction policy with its metrics.
+struct PolicySlot {
+    policy: Box<dyn EvictionPolicy>,
+    /// Whether the policy protects entries, in which case it governs the entries it protects
+    /// rather than the others.
+    protective: bool,
+    /// Whether the policy ranks entries under memory pressure.
+    ranking: bool,
+    evictions: usize,
+    hits: usize,
+}
+
+impl PolicySlot {
+    fn new(config: &EvictionPolicyConfig) -> Self {
+        Self {
+            policy: config.build(),
+            protective: matches!(config, EvictionPolicyConfig::Pinned { .. }),
+            ranking: matches!(
+                config,
+                EvictionPolicyConfig::Lru | EvictionPolicyConfig::Lfu
+            ),
+            evictions: 0,
+            hits: 0,
+        }
+    }
+}
+
+pub struct PrefixCacheManagerV2 {
+    caches: HashMap<Tokens, CacheElement>,
+    n_on_device: usize,
+    no_prefix_cache: bool,
+    policies: Vec<PolicySlot>,
+    max_bytes: Option<usize>,
+    /// Logical clock of the accesses, for LRU.
+    clock: u64,
+    lookups: usize,
+    hits: usize,
+    offloads: usize,
+}
+
+#[derive(Clone)]
+pub struct MatchingCache {
+    pub normal: Vec<Option<KvCache>>,
+    pub toks: Vec<u32>,
+    pub offset: usize,
+}
+
+impl PrefixCacheManagerV2 {
+    pub fn new(n_on_device: usize, no_prefix_cache: bool) -> Self {
+        if !no_prefix_cache {
+            info!("PrefixCacherV2 is enabled! Expect higher multi-turn prompt throughput.");
+        }
+        PrefixCacheManagerV2 {
+            caches: HashMap::new(),
+            n_on_device,
+            no_prefix_cache,
+            policies: vec![PolicySlot::new(&EvictionPolicyConfig::Lru)],
+            max_bytes: None,
+            clock: 0,
+            lookups: 0,
+            hits: 0,
+            offloads: 0,
+        }
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.no_prefix_cache
+    }
+
+    /// Evict with these policies instead of LRU.
+    pub fn with_eviction(mut self, config: &PrefixCacheEvictionConfig) -> Self {
+        self.policies = config.policies.iter().map(PolicySlot::new).collect();
+        if !self.policies.iter().any(|p| p.ranking) {
+            self.policies
+                .push(PolicySlot::new(&EvictionPolicyConfig::Lru));
+        }
+        self.max_bytes = config.max_bytes;
+        self
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// This always keeps the cache on the device.
+    pub fn add_sequence(&mut self, seq: &mut Sequence) {
+        // The cache of a blend of LoRA adapters only applies to the same blend.
+        if self.no_prefix_cache || seq.has_images() || seq.adapter_scalings().is_some() {
+            return;
+        }
+        let cache = seq.normal_cache().to_vec();
+        self.insert(seq.get_toks().to_vec(), cache, Instant::now());
+    }
+
+    fn insert(&mut self, toks: Vec<u32>, cache: Vec<Option<KvCache>>, now: Instant) {
+        let devices = cache
+            .iter()
+            .map(|x| x.as_ref().map(|x| x.k().unwrap().unwrap().device().clone()))
+            .collect::<Vec<_>>();
+        let mut element = CacheElement::new(cache, devices, 0);
+        element.last_access = self.tick();
+        element.last_access_time = now;
+
+        // Keep a copy of the pinned prefixes of the sequence.
+        let pinned = self
+            .pinned()
+            .into_iter()
+            .filter(|p| p.len() < toks.len() && toks.starts_with(p))
+            .filter(|p| !self.caches.contains_key(&Tokens(p.clone())))
+            .collect::<Vec<_>>();
+        for prefix in pinned {
+            self.insert_truncated(&element, prefix);
+        }
+
+        self.caches.insert(toks.into(), element);
+        self.drop_entries(now);
+    }
+
+    fn insert_truncated(&mut self, element: &CacheElement, prefix: Vec<u32>) {
+        match truncated_cache(&element.cache, prefix.len()) {
+            Ok(cache) => {
+                let mut pinned = CacheElement::new(cache, element.devices.clone(), 0);
+                pinned.last_access = element.last_access;
+                pinned.last_access_time = element.last_access_time;
+                self.caches.insert(prefix.into(), pinned);
+            }
+            Err(e) => tracing::warn!("Could not keep a pinned prefix: {e}"),
+        }
+    }
+
+    fn is_protected(&self, entry: &PrefixCacheEntry) -> bool {
+        self.policies.iter().any(|p| p.policy.protects(entry))
+    }
+
+    /// Eviction order under memory pressure, lowest first.
+    fn rank_key(&self, entry: &PrefixCacheEntry) -> Vec<u64> {
+        self.policies
+            .iter()
+            .filter_map(|p| p.policy.rank(entry))
+            .chain([entry.last_access])
+            .collect()
+    }
+
+    /// Indices of the policies governing an entry, for the metrics.
+    fn governing(&self, entry: &PrefixCacheEntry) -> Vec<usize> {
+        let protected = self.is_protected(entry);
+        self.policies
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| {
+                if protected {
+                    p.policy.protects(entry)
+                } else {
+                    !p.protective
+                }
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Drop the expired entries, then the entries beyond the memory budget. Returns the number
+    /// of dropped entries.
+    fn drop_entries(&mut self, now: Instant) -> usize {
+        let mut dropped = Vec::new();
+        for (toks, element) in &self.caches {
+            let entry = element.entry(toks);
+            if self.is_protected(&entry) {
+                continue;
+            }
+            if let Some(i) = self
+                .policies
+                .iter()
+                .position(|p| p.policy.expired(&entry, now))
+            {
+                dropped.push((toks.0.clone(), i));
+            }
+        }
+        for (toks, _) in &dropped {
+            self.caches.remove(&Tokens(toks.clone()));
+        }
+
+        if let Some(max_bytes) = self.max_bytes {
+            let mut bytes = self.caches.values().map(|e| e.bytes).sum::<usize>();
+            let ranker = self
+                .policies
+                .iter()
+                .position(|p| p.ranking)
+                .expect("A policy ranks entries");
+            let victims = self
+                .caches
+                .iter()
+                .map(|(toks, element)| (toks, element.entry(toks)))
+                .filter(|(_, entry)| !self.is_protected(entry))
+                .sorted_by_key(|(_, entry)| self.rank_key(entry))
+                .map(|(toks, entry)| (toks.0.clone(), entry.bytes))
+                .collect::<Vec<_>>();
+            for (toks, entry_bytes) in victims {
+                if bytes <= max_bytes {
+                    break;
+                }
+                bytes -= entry_bytes;
+                self.caches.remove(&Tokens(toks.clone()));
+                dropped.push((toks, ranker));
+            }
+        }
+
+        for (_, i) in &dropped {
+            self.policies[*i].evictions += 1;
+        }
+        dropped.len()
+    }
+
+    fn pinned(&mut self) -> Vec<Vec<u32>> {
+        self.policies
+            .iter_mut()
+            .filter_map(|p| p.policy.pin_list().map(|l| l.prefixes().to_vec()))
+            .flatten()
+            .collect()
+    }
+
+    /// Keep the cache of these tokens forever. If a cached sequence starts with them, its cache is
+    /// truncated to the tokens right away, otherwise when such a sequence is cached. Returns
+    /// whether the tokens were not pinned yet.
+    pub fn pin(&mut self, toks: Vec<u32>) -> bool {
+        if self.no_prefix_cache {
+            return false;
+        }
+        let list = match self.policies.iter().position(|p| p.protective) {
+            Some(i) => i,
+            None => {
+                self.policies.insert(
+                    0,
+                    PolicySlot::new(&EvictionPolicyConfig::Pinned { prefixes: vec![] }),
+                );
+                0
+            }
+        };
+        let Some(list) = self.policies[list].policy.pin_list() else {
+            return false;
+        };
+        if !list.pin(toks.clone()) {
+            return false;
+        }
+
+        let key = Tokens(toks.clone());
+        if !self.caches.contains_key(&key) {
+            let source = self
+                .caches
+                .iter()
+                .filter(|(k, _)| k.0.starts_with(&toks))
+                .max_by_key(|(_, e)| e.last_access)
+                .map(|(_, e)| e.clone());
+            if let Some(source) = source {
+                self.insert_truncated(&source, toks);
+            }
+        }
+        true
+    }
+
+    /// Returns whether the tokens were pinned. Their cache is kept until it is evicted.
+    pub fn unpin(&mut self, toks: &[u32]) -> bool {
+        let mut unpinned = false;
+        for slot in &mut self.policies {
+            if let Some(list) = slot.policy.pin_list() {
+                unpinned |= list.unpin(toks);
+            }
+        }
+        unpinned
+    }
+
+    /// Drop all caches, except the protected ones unless `include_protected`. Returns the number
+    /// of dropped caches.
+    pub fn flush(&mut self, include_protected: bool) -> usize {
+        let n = self.caches.len();
+        if include_protected {
+            self.caches.clear();
+        } else {
+            let protected = self
+                .caches
+                .iter()
+                .filter(|(toks, element)| self.is_protected(&element.entry(toks)))
+                .map(|(toks, _)| toks.0.clone())
+                .collect::<Vec<_>>();
+            self.caches
+                .retain(|toks, _| protected.iter().any(|p| *p == toks.0));
+        }
+        n - self.caches.len()
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    pub fn metrics(&mut self) -> PrefixCacheMetrics {
+        let mut policies = self
+            .policies
+            .iter()
+            .map(|slot| EvictionPolicyMetrics {
+                policy: slot.policy.name(),
+                evictions: slot.evictions,
+                bytes_held: 0,
+                hits: slot.hits,
+                hit_ratio: if self.lookups == 0 {
+                    0.
+                } else {
+                    slot.hits as f64 / self.lookups as f64
+                },
+            })
+            .collect::<Vec<_>>();
+        for (toks, element) in &self.caches {
+            for i in self.governing(&element.entry(toks)) {
+                policies[i].bytes_held += element.bytes;
+            }
+        }
+        PrefixCacheMetrics {
+            entries: self.caches.len(),
+            bytes: self.caches.values().map(|e| e.bytes).sum(),
+            lookups: self.lookups,
+            hits: self.hits,
+            offloads: self.offloads,
+            pinned: self.pinned(),
+            policies,
+        }
+    }
+
+    /// Add a prefix loaded from disk, on the CPU until it is matched. Caches added since startup
+    /// take precedence.
+    pub(crate) fn add_persisted(&mut self, prefix: PersistedPrefix, layer_devices: &[Device]) {
+        if self.no_prefix_cache {
+            return;
+        }
+        let devices = prefix
+            .cache
+            .iter()
+            .zip(layer_devices)
+            .map(|(layer, device)| layer.as_ref().map(|_| device.clone()))
+            .collect();
+        let key = Tokens(prefix.toks);
+        if !self.caches.contains_key(&key) {
+            let mut element = CacheElement::new(prefix.cache, devices, prefix.hits);
+            element.last_access = self.tick();
+            self.caches.insert(key, element);
+            self.drop_entries(Instant::now());
+        }
+    }
+
+    /// Write the most matched caches to a new segment in the persistence directory. Returns the
+    /// number of persisted caches.
+    pub(crate) fn persist(
+        &self,
+        persistence: &PrefixCachePersistence,
+        fingerprint: &str,
+    ) -> anyhow::Result<usize> {
+        if self.no_prefix_cache {
+            return Ok(0);
+        }
+        let prefixes = self
+            .caches
+            .iter()
+            .sorted_by_key(|(toks, cache)| {
+                (
+                    std::cmp::Reverse(cache.hits),
+                    std::cmp::Reverse(toks.0.len()),
+                )
+            })
+            .map(|(toks, cache)| PrefixRef {
+                toks: &toks.0,
+                cache: &cache.cache,
+                hits: cache.hits,
+            });
+        persistence.write(fingerprint, prefixes)
+    }
+
+    fn cache_to(
+        cache: &mut [Option<KvCache>],
+        devices: Either<&Device, &Vec<Option<Device>>>,
+    ) -> Result<()> {
+        for (i, layer) in cache
+            .iter_mut()
+            .enumerate()
+            .flat_map(|(i, x)| x.as_mut().map(|x| (i, x)))
+        {
+            let device = devices.left_or_else(|layers| layers[i].as_ref().unwrap());
+
+            match layer {
+                KvCache::Normal { k, v } => {
+                    *layer = KvCache::Normal {
+                        k: SingleCache {
+                            all_data: k.all_data.as_ref().map(|x| x.to_device(device).unwrap()),
+                            dim: k.dim,
+                            current_seq_len: k.current_seq_len,
+                            max_seq_len: k.max_seq_len,
+                            capacity_seq_len: k.capacity_seq_len,
+                        },
+                        v: SingleCache {
+                            all_data: v.all_data.as_ref().map(|x| x.to_device(device).unwrap()),
+                            dim: v.dim,
+                            current_seq_len: v.current_seq_len,
+                            max_seq_len: v.max_seq_len,
+                            capacity_seq_len: v.capacity_seq_len,
+                        },
+                    }
+                }
+                KvCache::Rotating { k, v } => {
+                    *layer = KvCache::Rotating {
+                        k: RotatingCache {
+                            all_data: k.all_data.as_ref().map(|x| x.to_device(device).unwrap()),
+                            dim: k.dim,
+                            current_seq_len: k.current_seq_len,
+                            max_seq_len: k.max_seq_len,
+                            offset: k.offset,
+                            capacity_seq_len: k.capacity_seq_len,
+                        },
+                        v: RotatingCache {
+                            all_data: v.all_data.as_ref().map(|x| x.to_device(device).unwrap()),
+                            dim: v.dim,
+                            current_seq_len: v.current_seq_len,
+                            max_seq_len: v.max_seq_len,
+                            offset: v.offset,
+                            capacity_seq_len: v.capacity_seq_len,
+                        },
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Evict the caches to CPU, in the eviction order of the policies with the protected caches
+    /// last, such that the number of caches on the device is the maximum allowed. Returns the
+    /// number of evicted caches.
+    pub fn evict_to_cpu(&mut self) -> Result<usize> {
+        if self.no_prefix_cache {
+            return Ok(0);
+        }
+        let on_device = self
+            .caches
+            .iter()
+            .filter(|(_, element)| element.is_on_device())
+            .map(|(toks, element)| {
+                let entry = element.entry(toks);
+                let key = (self.is_protected(&entry), self.rank_key(&entry));
+                (key, toks.0.clone())
+            })
+            .sorted()
+            .map(|(_, toks)| toks)
+            .collect::<Vec<_>>();
+        let n_evicted = on_device.len().saturating_sub(self.n_on_device);
+        for toks in on_device.into_iter().take(n_evicted) {
+            let element = self
+                .caches
+                .get_mut(&Tokens(toks))
+                .expect("Cache was just found");
+            Self::cache_to(&mut element.cache, Either::Left(&Device::Cpu))?;
+        }
+        self.offloads += n_evicted;
+        Ok(n_evicted)
+    }
+
+    /// Evict all the caches to CPU.
+    pub fn evict_all_to_cpu(&mut self) -> Result<usize> {
+        if self.no_prefix_cache {
+            return Ok(0);
+        }
+        for cache in self.caches.values_mut() {
+            if cache.is_on_device() {
+                Self::cache_to(&mut cache.cache, Either::Left(&Device::Cpu))?;
+                self.offloads += 1;
+            }
+        }
+        Ok(self.caches.len())
+    }
+
+    /// Search for a matching cache given some toks
+    pub fn search_for_matching_cache(
+        &mut self,
+        toks: &[u32],
+        contains_images: bool,
+    ) -> Result<Option<MatchingCache>> {
+        if self.no_prefix_cache || toks.is_empty() || contains_images {
+            return Ok(None);
+        }
+
+        let now = Instant::now();
+        self.drop_entries(now);
+        self.lookups += 1;
+        let toks = Tokens(toks.to_vec());
+
+        let mut longest_match = (0, None);
+        for k in self.caches.keys() {
+            let match_len = toks.find_max_index(k);
+            if let Some(match_len) = match_len {
+                if match_len > longest_match.0 {
+                    longest_match = (match_len, Some(k));
+                }
+            }
+        }
+        if let (match_len, Some(key)) = longest_match {
+            let key = Tokens(key.0.clone());
+            let tick = self.tick();
+            let longest_match = self.caches.get_mut(&key).expect("Cache was just found");
+            longest_match.hits += 1;
+            longest_match.last_access = tick;
+            longest_match.last_access_time = now;
+            let mut cache = longest_match.clone();
+            self.hits += 1;
+            for i in self.governing(&cache.entry(&key)) {
+                self.policies[i].hits += 1;
+            }
+            Self::cache_to(&mut cache.cache, Either::Right(&cache.devices))?;
+            for layer in cache.cache.iter_mut().flatten() {
+                match layer.set_len(match_len) {
+                    Ok(_) => (),
+                    Err(_) => return Ok(None),
+                }
+            }
+            Ok(Some(MatchingCache {
+                normal: cache.cache,
+                toks: toks.0[match_len..].to_vec(),
+                offset: match_len,
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        str::FromStr,
+        time::{Duration, Instant},
+    };
+
+    use candle_core::{Device, Tensor};
+
+    use super::{cache_bytes, PrefixCacheEvictionConfig, PrefixCacheManagerV2};
+    use crate::pipeline::KvCache;
+
+    fn cache(seq_len: usize) -> Vec<Option<KvCache>> {
+        let mut layer = KvCache::new_normal(2, 64, 16);
+        let k = Tensor::randn(0f32, 1., (1, 4, seq_len, 8), &Device::Cpu).unwrap();
+        layer.append(&k, &(k.clone() * 2.).unwrap()).unwrap();
+        vec![Some(layer), None]
+    }
+
+    /// A manager holding at most `n_entries` caches of up to 16 tokens.
+    fn manager(policies: &str, n_entries: usize) -> PrefixCacheManagerV2 {
+        let config = PrefixCacheEvictionConfig::from_str(policies)
+            .unwrap()
+            .with_max_bytes(n_entries * cache_bytes(&cache(1)));
+        PrefixCacheManagerV2::new(16, false).with_eviction(&config)
+    }
+
+    fn insert(manager: &mut PrefixCacheManagerV2, toks: &[u32]) {
+        manager.insert(toks.to_vec(), cache(toks.len()), Instant::now());
+    }
+
+    fn cached(manager: &PrefixCacheManagerV2) -> Vec<Vec<u32>> {
+        let mut toks = manager
+            .caches
+            .keys()
+            .map(|t| t.0.clone())
+            .collect::<Vec<_>>();
+        toks.sort();
+        toks
+    }
+
+    #[test]
+    fn lru_evicts_least_recently_used() {
+        let mut manager = manager("lru", 2);
+        insert(&mut manager, &[1, 2, 3]);
+        insert(&mut manager, &[4, 5, 6]);
+        manager
+            .search_for_matching_cache(&[1, 2, 3, 7], false)
+            .unwrap()
+            .unwrap();
+        insert(&mut manager, &[8, 9]);
+        assert_eq!(cached(&manager), [vec![1, 2, 3], vec![8, 9]]);
+        insert(&mut manager, &[10, 11]);
+        assert_eq!(cached(&manager), [vec![8, 9], vec![10, 11]]);
+
+        let metrics = manager.metrics();
+        assert_eq!(metrics.policies[0].evictions, 2);
+        assert_eq!((metrics.lookups, metrics.hits), (1, 1));
+        assert_eq!(metrics.policies[0].hit_ratio, 1.);
+    }
+
+    #[test]
+    fn lfu_evicts_least_frequently_used() {
+        let mut manager = manager("lfu", 2);
+        insert(&mut manager, &[1, 2, 3]);
+        insert(&mut manager, &[4, 5, 6]);
+        for toks in [[1, 2, 3, 7], [1, 2, 3, 7], [4, 5, 6, 7]] {
+            manager
+                .search_for_matching_cache(&toks, false)
+                .unwrap()
+                .unwrap();
+        }
+        // The least recently used entry is kept as it was matched the most, and the new entry is
+        // the least frequently used.
+        insert(&mut manager, &[8, 9]);
+        assert_eq!(cached(&manager), [vec![1, 2, 3], vec![4, 5, 6]]);
+        assert_eq!(manager.metrics().policies[0].evictions, 1);
+
+        // Ties are broken by recency.
+        let mut tied = self::manager("lfu", 1);
+        insert(&mut tied, &[1, 2, 3]);
+        insert(&mut tied, &[4, 5, 6]);
+        assert_eq!(cached(&tied), [vec![4, 5, 6]]);
+    }
+
+    #[test]
+    fn ttl_drops_expired() {
+        let mut manager = manager("ttl=60", 8);
+        let now = Instant::now();
+        manager.insert(vec![1, 2, 3], cache(3), now);
+        manager.insert(vec![4, 5, 6], cache(3), now + Duration::from_secs(30));
+        assert_eq!(manager.drop_entries(now + Duration::from_secs(60)), 0);
+        assert_eq!(manager.drop_entries(now + Duration::from_secs(61)), 1);
+        assert_eq!(cached(&manager), [vec![4, 5, 6]]);
+
+        let metrics = manager.metrics();
+        assert_eq!(metrics.policies[0].policy, "ttl=60");
+        assert_eq!(metrics.policies[0].evictions, 1);
+        // LRU is added to evict under memory pressure.
+        assert_eq!(metrics.policies[1].policy, "lru");
+        assert_eq!(metrics.policies[1].evictions, 0);
+    }
+
+    #[test]
+    fn pinned_prefixes_are_kept() {
+        let mut manager = manager("pinned,ttl=60,lru", 2);
+        assert!(manager.pin(vec![1, 2]));
+        assert!(!manager.pin(vec![1, 2]));
+
+        // A truncated copy of the pinned prefix is kept.
+        let now = Instant::now();
+        manager.insert(vec![1, 2, 3, 4], cache(4), now);
+        assert_eq!(cached(&manager), [vec![1, 2], vec![1, 2, 3, 4]]);
+        insert(&mut manager, &[5, 6]);
+        assert_eq!(cached(&manager), [vec![1, 2], vec![5, 6]]);
+        assert_eq!(manager.drop_entries(now + Duration::from_secs(3600)), 1);
+        assert_eq!(cached(&manager), [vec![1, 2]]);
+
+        let matching = manager
+            .search_for_matching_cache(&[1, 2, 7], false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(matching.offset, 1);
+        let metrics = manager.metrics();
+        assert_eq!(metrics.pinned, [vec![1, 2]]);
+        assert_eq!(metrics.policies[0].hits, 1);
+        assert_eq!(metrics.policies[0].bytes_held, metrics.bytes);
+        assert_eq!(metrics.policies[1].evictions, 1);
+        assert_eq!(metrics.policies[2].evictions, 1);
+
+        insert(&mut manager, &[5, 6]);
+        assert_eq!(manager.flush(false), 1);
+        assert_eq!(cached(&manager), [vec![1, 2]]);
+        assert!(manager.unpin(&[1, 2]));
+        assert_eq!(manager.flush(false), 1);
+        assert!(cached(&manager).is_empty());
+    }
+}
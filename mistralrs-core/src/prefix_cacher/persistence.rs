@@ -0,0 +1,553 @@
+//! Persistence of the prefix cache across restarts.
+//!
+//! On shutdown, the most used prefixes are appended to the directory as a new segment. Segments
+//! are named by their creation time and the oldest ones are removed once the directory exceeds its
+//! size limit. On startup, segments are read from the newest to the oldest, so the latest version
+//! of a prefix wins.
+//!
+//! A segment is the magic bytes, the length of the model fingerprint as a little endian `u32` and
+//! the fingerprint, followed by records. A record is the length of its payload as a little endian
+//! `u64`, the SHA-256 digest of the payload and the payload: a safetensors file with the KV cache of
+//! each layer and the tokens of the prefix in its metadata.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use candle_core::{Device, Tensor};
+use safetensors::SafeTensors;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::{
+    pipeline::{GeneralMetadata, KvCache, RotatingCache, SingleCache},
+    SYSTEM_FINGERPRINT,
+};
+
+const MAGIC: &[u8; 8] = b"MRPCSEG1";
+const SEGMENT_PREFIX: &str = "prefix-cache-";
+const SEGMENT_EXTENSION: &str = "seg";
+const DIGEST_LEN: u64 = 32;
+
+/// Where and how much of the prefix cache to persist across restarts.
+#[derive(Clone, Debug)]
+pub struct PrefixCachePersistence {
+    pub dir: PathBuf,
+    /// Maximum number of prefixes written on shutdown and loaded on startup.
+    pub max_entries: usize,
+    /// Maximum total size of the segments in the directory, the oldest segments are removed first.
+    pub max_bytes: u64,
+}
+
+impl PrefixCachePersistence {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            max_entries: 16,
+            max_bytes: 8 * 1024 * 1024 * 1024,
+        }
+    }
+
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Remove all persisted segments. Returns the number of removed segments.
+    pub fn clear(&self) -> io::Result<usize> {
+        let segments = segments(&self.dir)?;
+        for (path, _) in &segments {
+            fs::remove_file(path)?;
+        }
+        Ok(segments.len())
+    }
+
+    /// Write the prefixes, most valuable first, to a new segment and remove the oldest segments
+    /// beyond the size limit. Returns the number of written prefixes.
+    pub(crate) fn write<'a>(
+        &self,
+        fingerprint: &str,
+        prefixes: impl IntoIterator<Item = PrefixRef<'a>>,
+    ) -> Result<usize> {
+        fs::create_dir_all(&self.dir)?;
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time travel has occurred!")
+            .as_millis();
+        let path = self
+            .dir
+            .join(format!("{SEGMENT_PREFIX}{millis:020}.{SEGMENT_EXTENSION}"));
+        let tmp_path = path.with_extension("tmp");
+
+        let mut file = BufWriter::new(File::create(&tmp_path)?);
+        file.write_all(MAGIC)?;
+        file.write_all(&u32::try_from(fingerprint.len())?.to_le_bytes())?;
+        file.write_all(fingerprint.as_bytes())?;
+        let mut size = (MAGIC.len() + 4 + fingerprint.len()) as u64;
+
+        let mut n_written = 0;
+        for prefix in prefixes.into_iter().take(self.max_entries) {
+            let payload = match encode_prefix(&prefix) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    warn!(
+                        "Not persisting a prefix of {} tokens: {e}",
+                        prefix.toks.len()
+                    );
+                    continue;
+                }
+            };
+            let record_size = 8 + DIGEST_LEN + payload.len() as u64;
+            if size + record_size > self.max_bytes {
+                break;
+            }
+            file.write_all(&(payload.len() as u64).to_le_bytes())?;
+            file.write_all(&Sha256::digest(&payload))?;
+            file.write_all(&payload)?;
+            size += record_size;
+            n_written += 1;
+        }
+        file.flush()?;
+        drop(file);
+
+        if n_written == 0 {
+            fs::remove_file(&tmp_path)?;
+            return Ok(0);
+        }
+        // Only complete segments get the segment extension.
+        fs::rename(&tmp_path, &path)?;
+
+        let mut total_size = 0;
+        for (path, segment_size) in segments(&self.dir)? {
+            total_size += segment_size;
+            if total_size > self.max_bytes {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(n_written)
+    }
+
+    /// Read the prefixes persisted for the model with this fingerprint, calling `on_prefix` as each
+    /// one is decoded. Corrupt entries and segments of other models are skipped with a warning.
+    /// Returns the number of loaded prefixes.
+    pub(crate) fn load(
+        &self,
+        fingerprint: &str,
+        n_layers: usize,
+        mut on_prefix: impl FnMut(PersistedPrefix),
+    ) -> usize {
+        let segments = match segments(&self.dir) {
+            Ok(segments) => segments,
+            Err(e) => {
+                warn!(
+                    "Could not list the persisted prefix cache in `{}`: {e}",
+                    self.dir.display()
+                );
+                return 0;
+            }
+        };
+
+        let mut seen = HashSet::new();
+        'segments: for (path, _) in segments {
+            let mut reader = match open_segment(&path, fingerprint) {
+                Ok(Some(reader)) => reader,
+                Ok(None) => {
+                    warn!(
+                        "Skipping the persisted prefix cache segment `{}` of a different model.",
+                        path.display()
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    warn!(
+                        "Skipping the corrupt persisted prefix cache segment `{}`: {e}",
+                        path.display()
+                    );
+                    continue;
+                }
+            };
+            loop {
+                if seen.len() >= self.max_entries {
+                    break 'segments;
+                }
+                let payload = match reader.next_record() {
+                    Ok(Some(payload)) => payload,
+                    Ok(None) => break,
+                    // The following records cannot be located.
+                    Err(e) => {
+                        warn!(
+                            "Skipping the rest of the persisted prefix cache segment `{}`: {e}",
+                            path.display()
+                        );
+                        break;
+                    }
+                };
+                match decode_prefix(&payload, n_layers) {
+                    Ok(prefix) => {
+                        if seen.insert(prefix.toks.clone()) {
+                            on_prefix(prefix);
+                        }
+                    }
+                    Err(e) => warn!(
+                        "Skipping a corrupt persisted prefix in `{}`: {e}",
+                        path.display()
+                    ),
+                }
+            }
+        }
+        seen.len()
+    }
+}
+
+/// Fingerprint of the model, matched against the persisted segments. This is `None` if the
+/// weights could not be fingerprinted, as the prefix cache of another model could then be loaded.
+pub(crate) fn persistence_fingerprint(metadata: &GeneralMetadata) -> Option<String> {
+    if metadata.system_fingerprint == SYSTEM_FINGERPRINT {
+        return None;
+    }
+    Some(format!(
+        "{}_{:?}",
+        metadata.system_fingerprint, metadata.activation_dtype
+    ))
+}
+
+pub(crate) struct PrefixRef<'a> {
+    pub toks: &'a [u32],
+    pub cache: &'a [Option<KvCache>],
+    pub hits: usize,
+}
+
+pub(crate) struct PersistedPrefix {
+    pub toks: Vec<u32>,
+    pub cache: Vec<Option<KvCache>>,
+    pub hits: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+enum PersistedLayer {
+    /// The sequence length is the length of the persisted tensors.
+    Normal {
+        dim: usize,
+        max_seq_len: usize,
+    },
+    Rotating {
+        k: RotatingState,
+        v: RotatingState,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct RotatingState {
+    dim: usize,
+    offset: usize,
+    current_seq_len: usize,
+    max_seq_len: usize,
+    capacity_seq_len: usize,
+}
+
+impl RotatingState {
+    fn new(cache: &RotatingCache) -> Self {
+        Self {
+            dim: cache.dim,
+            offset: cache.offset,
+            current_seq_len: cache.current_seq_len,
+            max_seq_len: cache.max_seq_len,
+            capacity_seq_len: cache.capacity_seq_len,
+        }
+    }
+
+    fn into_cache(self, data: Tensor) -> RotatingCache {
+        RotatingCache {
+            all_data: Some(data),
+            dim: self.dim,
+            offset: self.offset,
+            current_seq_len: self.current_seq_len,
+            max_seq_len: self.max_seq_len,
+            capacity_seq_len: self.capacity_seq_len,
+        }
+    }
+}
+
+fn encode_prefix(prefix: &PrefixRef<'_>) -> Result<Vec<u8>> {
+    let mut layers = Vec::with_capacity(prefix.cache.len());
+    let mut tensors = Vec::new();
+    for (i, layer) in prefix.cache.iter().enumerate() {
+        let Some(layer) = layer else {
+            layers.push(None);
+            continue;
+        };
+        let (persisted, k, v) = match layer {
+            KvCache::Normal { k, v } => (
+                PersistedLayer::Normal {
+                    dim: k.dim,
+                    max_seq_len: k.max_seq_len,
+                },
+                k.current_data()?,
+                v.current_data()?,
+            ),
+            KvCache::Rotating { k, v } => (
+                PersistedLayer::Rotating {
+                    k: RotatingState::new(k),
+                    v: RotatingState::new(v),
+                },
+                k.all_data.clone(),
+                v.all_data.clone(),
+            ),
+        };
+        let (Some(k), Some(v)) = (k, v) else {
+            anyhow::bail!("layer {i} has no KV cache data");
+        };
+        // Copying a narrowed tensor would otherwise copy the whole allocation.
+        tensors.push((format!("{i}.k"), k.contiguous()?.to_device(&Device::Cpu)?));
+        tensors.push((format!("{i}.v"), v.contiguous()?.to_device(&Device::Cpu)?));
+        layers.push(Some(persisted));
+    }
+
+    let metadata = HashMap::from([
+        ("toks".to_string(), serde_json::to_string(prefix.toks)?),
+        ("hits".to_string(), prefix.hits.to_string()),
+        ("layers".to_string(), serde_json::to_string(&layers)?),
+    ]);
+    Ok(safetensors::serialize(tensors, &Some(metadata))?)
+}
+
+fn decode_prefix(payload: &[u8], n_layers: usize) -> Result<PersistedPrefix> {
+    let (_, metadata) = SafeTensors::read_metadata(payload)?;
+    let metadata = metadata.metadata().as_ref().context("missing metadata")?;
+    let get = |key: &str| {
+        metadata
+            .get(key)
+            .with_context(|| format!("missing `{key}` in the metadata"))
+    };
+    let toks: Vec<u32> = serde_json::from_str(get("toks")?)?;
+    let hits = get("hits")?.parse()?;
+    let layers: Vec<Option<PersistedLayer>> = serde_json::from_str(get("layers")?)?;
+    if layers.len() != n_layers {
+        anyhow::bail!("expected {n_layers} layers, got {}", layers.len());
+    }
+
+    let mut tensors = candle_core::safetensors::load_buffer(payload, &Device::Cpu)?;
+    let mut cache = Vec::with_capacity(layers.len());
+    for (i, layer) in layers.into_iter().enumerate() {
+        let Some(layer) = layer else {
+            cache.push(None);
+            continue;
+        };
+        let k = tensors
+            .remove(&format!("{i}.k"))
+            .with_context(|| format!("missing the K cache of layer {i}"))?;
+        let v = tensors
+            .remove(&format!("{i}.v"))
+            .with_context(|| format!("missing the V cache of layer {i}"))?;
+        let layer = match layer {
+            PersistedLayer::Normal { dim, max_seq_len } => {
+                let seq_len = k.dim(dim)?;
+                if v.dim(dim)? != seq_len || seq_len > max_seq_len {
+                    anyhow::bail!("invalid KV cache length in layer {i}");
+                }
+                let cache = |data| SingleCache {
+                    all_data: Some(data),
+                    dim,
+                    current_seq_len: seq_len,
+                    capacity_seq_len: seq_len,
+                    max_seq_len,
+                };
+                KvCache::Normal {
+                    k: cache(k),
+                    v: cache(v),
+                }
+            }
+            PersistedLayer::Rotating {
+                k: k_state,
+                v: v_state,
+            } => KvCache::Rotating {
+                k: k_state.into_cache(k),
+                v: v_state.into_cache(v),
+            },
+        };
+        cache.push(Some(layer));
+    }
+
+    Ok(PersistedPrefix { toks, cache, hits })
+}
+
+/// Segments in the directory with their size, from the newest to the oldest.
+fn segments(dir: &Path) -> io::Result<Vec<(PathBuf, u64)>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with(SEGMENT_PREFIX)
+            && name.ends_with(&format!(".{SEGMENT_EXTENSION}"))
+            && entry.file_type()?.is_file()
+        {
+            segments.push((entry.path(), entry.metadata()?.len()));
+        }
+    }
+    // The names hold the zero padded creation time.
+    segments.sort_by(|(a, _), (b, _)| b.cmp(a));
+    Ok(segments)
+}
+
+struct SegmentReader {
+    reader: BufReader<File>,
+    remaining: u64,
+}
+
+/// Open a segment and read its header. Returns `None` if it belongs to another model.
+fn open_segment(path: &Path, fingerprint: &str) -> Result<Option<SegmentReader>> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    let mut magic = [0; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        anyhow::bail!("not a prefix cache segment");
+    }
+    let mut fingerprint_len = [0; 4];
+    reader.read_exact(&mut fingerprint_len)?;
+    let fingerprint_len = u32::from_le_bytes(fingerprint_len);
+    let header_len = (MAGIC.len() + 4) as u64 + u64::from(fingerprint_len);
+    if header_len > len {
+        anyhow::bail!("truncated header");
+    }
+    let mut segment_fingerprint = vec![0; fingerprint_len as usize];
+    reader.read_exact(&mut segment_fingerprint)?;
+    if segment_fingerprint != fingerprint.as_bytes() {
+        return Ok(None);
+    }
+
+    Ok(Some(SegmentReader {
+        reader,
+        remaining: len - header_len,
+    }))
+}
+
+impl SegmentReader {
+    /// Read the payload of the next record, checking its digest.
+    fn next_record(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        if self.remaining < 8 + DIGEST_LEN {
+            anyhow::bail!("truncated record");
+        }
+        let mut len = [0; 8];
+        self.reader.read_exact(&mut len)?;
+        let len = u64::from_le_bytes(len);
+        if len > self.remaining - 8 - DIGEST_LEN {
+            anyhow::bail!("truncated record");
+        }
+        let mut digest = [0; DIGEST_LEN as usize];
+        self.reader.read_exact(&mut digest)?;
+        let mut payload = vec![0; usize::try_from(len)?];
+        self.reader.read_exact(&mut payload)?;
+        self.remaining -= 8 + DIGEST_LEN + len;
+
+        if Sha256::digest(&payload).as_slice() != digest {
+            anyhow::bail!("checksum mismatch");
+        }
+        Ok(Some(payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::{PrefixCachePersistence, PrefixRef};
+    use crate::pipeline::KvCache;
+
+    fn cache(seq_len: usize) -> Vec<Option<KvCache>> {
+        let mut layer = KvCache::new_normal(2, 64, 16);
+        let k = Tensor::randn(0f32, 1., (1, 4, seq_len, 8), &Device::Cpu).unwrap();
+        layer.append(&k, &(k.clone() * 2.).unwrap()).unwrap();
+        vec![Some(layer), None]
+    }
+
+    #[test]
+    fn roundtrip_and_corruption() {
+        let dir = std::env::temp_dir().join("mistralrs_prefix_cache_persistence");
+        let _ = std::fs::remove_dir_all(&dir);
+        let persistence = PrefixCachePersistence::new(&dir);
+
+        let (short, long) = (cache(3), cache(5));
+        let n = persistence
+            .write(
+                "fp_a",
+                [
+                    PrefixRef {
+                        toks: &[1, 2, 3],
+                        cache: &short,
+                        hits: 4,
+                    },
+                    PrefixRef {
+                        toks: &[1, 2, 3, 4, 5],
+                        cache: &long,
+                        hits: 1,
+                    },
+                ],
+            )
+            .unwrap();
+        assert_eq!(n, 2);
+
+        let mut loaded = Vec::new();
+        assert_eq!(persistence.load("fp_a", 2, |p| loaded.push(p)), 2);
+        assert_eq!(loaded[0].toks, vec![1, 2, 3]);
+        assert_eq!(loaded[0].hits, 4);
+        assert!(loaded[1].cache[1].is_none());
+        let (Some(KvCache::Normal { k, v }), Some(KvCache::Normal { k: k_orig, .. })) =
+            (&loaded[1].cache[0], &long[0])
+        else {
+            panic!("Expected a normal cache");
+        };
+        assert_eq!(k.current_seq_len, 5);
+        let diff = (k.current_data().unwrap().unwrap() - k_orig.current_data().unwrap().unwrap())
+            .unwrap()
+            .abs()
+            .unwrap()
+            .sum_all()
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap();
+        assert_eq!(diff, 0.);
+        assert_eq!(v.all_data.as_ref().unwrap().dtype(), DType::F32);
+
+        // Another model, or a different number of layers.
+        assert_eq!(persistence.load("fp_b", 2, |_| ()), 0);
+        assert_eq!(persistence.load("fp_a", 3, |_| ()), 0);
+
+        // Corrupt the last record: the first one is still loaded.
+        let (path, _) = super::segments(&dir).unwrap().remove(0);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+        let mut loaded = Vec::new();
+        assert_eq!(persistence.load("fp_a", 2, |p| loaded.push(p.toks)), 1);
+        assert_eq!(loaded, vec![vec![1, 2, 3]]);
+
+        // Truncated segments are skipped too.
+        std::fs::write(&path, &bytes[..10]).unwrap();
+        assert_eq!(persistence.load("fp_a", 2, |_| ()), 0);
+
+        assert_eq!(persistence.clear().unwrap(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -5,10 +5,12 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use crate::{
-    response::Response,
+    prefix_cacher::PrefixCacheMetrics,
+    response::{ContextCheck, Response},
     sampler::SamplingParams,
     tools::{Tool, ToolChoice},
-    CustomLogitsProcessor, DiffusionGenerationParams,
+    CustomLogitsProcessor, DiffusionGenerationParams, RequestDocuments, SchedulerLimits,
+    SchedulerMetrics,
 };
 use std::{fmt::Debug, sync::Arc};
 use tokio::sync::mpsc::Sender;
@@ -25,6 +27,70 @@ pub enum Constraint {
     None,
 }
 
+impl Constraint {
+    /// Name of the kind of constraint, or `None` if the output is not constrained.
+    pub fn kind(&self) -> Option<&'static str> {
+        match self {
+            Self::Regex(_) => Some("regex"),
+            Self::Lark(_) => Some("lark"),
+            Self::JsonSchema(_) => Some("json_schema"),
+            Self::Llguidance(_) => Some("llguidance"),
+            Self::None => None,
+        }
+    }
+}
+
+/// Provides the grammar of the rest of the generation from the tokens generated so far.
+pub type GrammarProvider = Box<dyn FnMut(&[u32]) -> Constraint + Send>;
+
+/// When the grammar of a [`DynamicGrammar`] is updated.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GrammarUpdate {
+    /// After every generated token.
+    EveryToken,
+    /// After generating one of these tokens, for example the tokens ending a choice.
+    AfterTokens(Vec<u32>),
+}
+
+impl GrammarUpdate {
+    fn is_triggered_by(&self, tok: u32) -> bool {
+        match self {
+            Self::EveryToken => true,
+            Self::AfterTokens(toks) => toks.contains(&tok),
+        }
+    }
+}
+
+/// A grammar updated during the generation, for constraints depending on earlier choices. The
+/// provider is called with no tokens for the initial grammar, then with all the generated tokens
+/// at every update, and the grammar it returns constrains the tokens generated after the update.
+#[derive(Clone)]
+pub struct DynamicGrammar {
+    provider: Arc<std::sync::Mutex<GrammarProvider>>,
+    update: GrammarUpdate,
+}
+
+impl DynamicGrammar {
+    pub fn new(provider: GrammarProvider, update: GrammarUpdate) -> Self {
+        Self {
+            provider: Arc::new(std::sync::Mutex::new(provider)),
+            update,
+        }
+    }
+
+    /// The grammar after the generated tokens, if it must be updated after the last one.
+    pub(crate) fn grammar(&self, generated: &[u32]) -> Option<Constraint> {
+        if generated
+            .last()
+            .is_some_and(|tok| !self.update.is_triggered_by(*tok))
+        {
+            return None;
+        }
+        let mut provider = self.provider.lock().expect("Grammar provider panicked.");
+        Some((*provider)(generated))
+    }
+}
+
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
 #[cfg_attr(feature = "pyo3_macros", pyo3::pyclass(eq, eq_int))]
 /// Image generation response format
@@ -109,6 +175,7 @@ pub struct WebSearchOptions {
 /// - `is_streaming`: Control whether the request is streaming, if so chunk responses will be sent
 /// - `id`: Request ID
 /// - `constraint`: Constraint to use during generation
+/// - `dynamic_grammar`: Grammar updated during generation, replacing `constraint`
 /// - `suffix`: Suffix to add
 /// - `tools`: Tools available in this request
 /// - `tool_choice`: Choice of tools
@@ -118,6 +185,8 @@ pub struct WebSearchOptions {
 ///     3) Apply temperature and softmax
 ///     4) Sample the next token (topk, topp, minp, etc)
 /// - `return_raw_logits`: Return raw logits.
+/// - `enable_thinking`: Passed to the chat template as `enable_thinking` to toggle the thinking
+///     block of reasoning models. If unset, the template's own default is used.
 pub struct NormalRequest {
     pub messages: RequestMessage,
     pub sampling_params: SamplingParams,
@@ -128,6 +197,8 @@ pub struct NormalRequest {
     pub is_streaming: bool,
     pub id: usize,
     pub constraint: Constraint,
+    #[serde(skip)]
+    pub dynamic_grammar: Option<DynamicGrammar>,
     pub suffix: Option<String>,
     pub tools: Option<Vec<Tool>>,
     pub tool_choice: Option<ToolChoice>,
@@ -135,6 +206,24 @@ pub struct NormalRequest {
     pub logits_processors: Option<Vec<Arc<dyn CustomLogitsProcessor>>>,
     pub return_raw_logits: bool,
     pub web_search_options: Option<WebSearchOptions>,
+    #[serde(default)]
+    pub enable_thinking: Option<bool>,
+    /// Reject system messages for chat templates which do not support the system role, instead of
+    /// folding them into the next user message.
+    #[serde(default)]
+    pub strict_system_role: bool,
+    /// Return the sampling parameters used after applying the defaults of the server and of the
+    /// model with the final response.
+    #[serde(default)]
+    pub return_effective_params: bool,
+    /// Retrieved documents packed into the prompt of a chat request by the engine. Which
+    /// documents were included is returned with the final response.
+    #[serde(default)]
+    pub documents: Option<RequestDocuments>,
+    /// Weights of the LoRA adapters applied to this request, as a weighted sum of their deltas.
+    /// Adapters which are not listed are not applied. The blend is returned with the responses.
+    #[serde(default)]
+    pub adapters: Option<Vec<(String, f32)>>,
 }
 
 impl NormalRequest {
@@ -156,10 +245,16 @@ impl NormalRequest {
             return_logprobs: false,
             is_streaming: false,
             constraint: Constraint::None,
+            dynamic_grammar: None,
             suffix: None,
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: None,
+            enable_thinking: None,
+            strict_system_role: false,
+            return_effective_params: false,
+            documents: None,
+            adapters: None,
         }
     }
 }
@@ -187,6 +282,59 @@ pub struct DetokenizationRequest {
     pub response: Sender<anyhow::Result<String>>,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+/// Request to get the scheduler limits, and optionally change them first.
+pub struct SchedulerLimitsRequest {
+    /// New limits, or `None` to only get the current ones.
+    pub limits: Option<SchedulerLimits>,
+    /// Allow lowering `max_num_seqs` below the number of running sequences.
+    pub drain: bool,
+    #[serde(default = "default_responder")]
+    #[serde(skip)]
+    pub response: Sender<anyhow::Result<SchedulerLimits>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// Request to get the load of the prefill and decode phases.
+pub struct SchedulerMetricsRequest {
+    #[serde(default = "default_responder")]
+    #[serde(skip)]
+    pub response: Sender<SchedulerMetrics>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Operation on the prefix cache.
+pub enum PrefixCacheOp {
+    Metrics,
+    /// Keep the cache of these tokens, for example a system prompt, until it is unpinned.
+    Pin(Vec<u32>),
+    Unpin(Vec<u32>),
+    /// Drop the cached prefixes, including the pinned ones if `include_pinned`.
+    Flush {
+        include_pinned: bool,
+    },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// Request to operate on the prefix cache. The response holds the metrics after the operation.
+pub struct PrefixCacheRequest {
+    pub op: PrefixCacheOp,
+    #[serde(default = "default_responder")]
+    #[serde(skip)]
+    pub response: Sender<anyhow::Result<PrefixCacheMetrics>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// Request to measure the prompt of a request against the context, without running it. The
+/// prompt is rendered and tokenized as if `request` was added, including its tools and documents.
+/// The response of `request` is not used.
+pub struct ContextCheckRequest {
+    pub request: NormalRequest,
+    #[serde(default = "default_responder")]
+    #[serde(skip)]
+    pub response: Sender<anyhow::Result<ContextCheck>>,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 /// A request to the Engine, encapsulating the various parameters as well as
 /// the `mpsc` response `Sender` used to return the [`Response`].
@@ -195,6 +343,10 @@ pub enum Request {
     ReIsq(IsqType),
     Tokenize(TokenizationRequest),
     Detokenize(DetokenizationRequest),
+    SchedulerLimits(SchedulerLimitsRequest),
+    SchedulerMetrics(SchedulerMetricsRequest),
+    PrefixCache(PrefixCacheRequest),
+    ContextCheck(ContextCheckRequest),
     // Sending a terminate request causes the `run` function to return to the thread created in `MistralRs::new`,
     // and then Engine will be dropped.
     Terminate,
@@ -225,6 +377,16 @@ impl Debug for Request {
             Request::Detokenize(req) => {
                 write!(f, "Tokenization Request {:?}", req.tokens)
             }
+            Request::SchedulerLimits(req) => {
+                write!(f, "Scheduler Limits Request {:?}", req.limits)
+            }
+            Request::SchedulerMetrics(_) => write!(f, "Scheduler Metrics Request"),
+            Request::PrefixCache(req) => {
+                write!(f, "Prefix Cache Request {:?}", req.op)
+            }
+            Request::ContextCheck(req) => {
+                write!(f, "Context Check Request {}", req.request.id)
+            }
             Request::Terminate => write!(f, "Termination Request"),
             Request::TerminateAllSeqsNextStep => write!(f, "Terminate All Seqs Next Step"),
         }
@@ -8,7 +8,7 @@ use candle_core::Tensor;
 use pyo3::{pyclass, pymethods};
 use serde::Serialize;
 
-use crate::{sampler::TopLogprob, tools::ToolCallResponse};
+use crate::{routing::RoutingDecision, sampler::TopLogprob, tools::ToolCallResponse};
 
 pub const SYSTEM_FINGERPRINT: &str = "local";
 
@@ -71,6 +71,17 @@ pub struct Logprobs {
 
 generate_repr!(Logprobs);
 
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+/// The stop string or stop token which ended a choice.
+pub enum ResponseStopReason {
+    StopString(String),
+    StopToken(u32),
+}
+
+generate_repr!(ResponseStopReason);
+
 #[cfg_attr(feature = "pyo3_macros", pyclass)]
 #[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
 #[derive(Debug, Clone, Serialize)]
@@ -80,6 +91,7 @@ pub struct Choice {
     pub index: usize,
     pub message: ResponseMessage,
     pub logprobs: Option<Logprobs>,
+    pub stop_reason: Option<ResponseStopReason>,
 }
 
 generate_repr!(Choice);
@@ -93,6 +105,10 @@ pub struct ChunkChoice {
     pub index: usize,
     pub delta: Delta,
     pub logprobs: Option<ResponseLogprob>,
+    pub stop_reason: Option<ResponseStopReason>,
+    /// Tokens generated since the previous chunk. Not part of the OpenAI response.
+    #[serde(skip)]
+    pub tokens: Vec<u32>,
 }
 
 generate_repr!(ChunkChoice);
@@ -106,6 +122,10 @@ pub struct CompletionChunkChoice {
     pub index: usize,
     pub logprobs: Option<ResponseLogprob>,
     pub finish_reason: Option<String>,
+    pub stop_reason: Option<ResponseStopReason>,
+    /// Tokens generated since the previous chunk. Not part of the OpenAI response.
+    #[serde(skip)]
+    pub tokens: Vec<u32>,
 }
 
 generate_repr!(CompletionChunkChoice);
@@ -116,6 +136,9 @@ generate_repr!(CompletionChunkChoice);
 /// OpenAI compatible (superset) usage during a request.
 pub struct Usage {
     pub completion_tokens: usize,
+    /// Completion tokens in the think blocks of reasoning models. The other completion tokens are
+    /// those of the answer.
+    pub reasoning_tokens: usize,
     pub prompt_tokens: usize,
     pub total_tokens: usize,
     pub avg_tok_per_sec: f32,
@@ -124,10 +147,96 @@ pub struct Usage {
     pub total_time_sec: f32,
     pub total_prompt_time_sec: f32,
     pub total_completion_time_sec: f32,
+    /// Acceptance of the draft tokens, with speculative decoding.
+    pub speculative: Option<SpeculativeStats>,
 }
 
 generate_repr!(Usage);
 
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+/// Acceptance of the draft tokens of speculative decoding, for a request or for all of them.
+pub struct SpeculativeStats {
+    /// Steps of the target model.
+    pub steps: usize,
+    pub draft_tokens: usize,
+    pub accepted_draft_tokens: usize,
+    /// Draft tokens rejected by the target model.
+    pub wasted_draft_tokens: usize,
+    /// Generated tokens, which are the accepted draft tokens and those sampled from the target
+    /// model after a rejection or after accepting every draft token.
+    pub generated_tokens: usize,
+    pub acceptance_rate: f64,
+    /// Generated tokens per step of the target model, which is the speedup over decoding with the
+    /// target model alone, not counting the steps of the draft model.
+    pub effective_speedup: f64,
+    /// Number of draft tokens of the last step.
+    pub gamma: usize,
+}
+
+generate_repr!(SpeculativeStats);
+
+impl SpeculativeStats {
+    /// Record a step of `gamma` draft tokens, of which `accepted` were accepted, which generated
+    /// `generated` tokens.
+    #[allow(clippy::cast_precision_loss)]
+    pub(crate) fn record(&mut self, gamma: usize, accepted: usize, generated: usize) {
+        self.steps += 1;
+        self.draft_tokens += gamma;
+        self.accepted_draft_tokens += accepted;
+        self.wasted_draft_tokens += gamma - accepted;
+        self.generated_tokens += generated;
+        self.acceptance_rate = self.accepted_draft_tokens as f64 / self.draft_tokens as f64;
+        self.effective_speedup = self.generated_tokens as f64 / self.steps as f64;
+        self.gamma = gamma;
+    }
+}
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+/// Sampling parameters used for a request after the defaults of the server and of the model were
+/// applied, returned if the request sets `return_effective_params`.
+pub struct EffectiveSamplingParams {
+    /// `None` for greedy decoding.
+    pub temperature: Option<f64>,
+    /// `None` if top-k is disabled.
+    pub top_k: Option<usize>,
+    pub top_p: f64,
+    pub min_p: f64,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    /// `None` if the penalties count the whole context.
+    pub repeat_last_n: Option<usize>,
+    pub dry_multiplier: Option<f32>,
+    pub tfs_z: Option<f32>,
+    pub max_len: Option<usize>,
+    pub stop_toks: Vec<u32>,
+    pub stop_strings: Vec<String>,
+    /// Seed of the random number generator of the engine.
+    pub seed: u64,
+    /// Kind of the grammar constraining the output, if any.
+    pub constraint: Option<String>,
+}
+
+generate_repr!(EffectiveSamplingParams);
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+/// A document of the request which was packed into the prompt.
+pub struct DocumentUsage {
+    /// Index of the document in the request.
+    pub index: usize,
+    /// Tokens of the document text in the prompt.
+    pub tokens: usize,
+    /// Whether the document was truncated to `max_tokens_per_document`.
+    pub truncated: bool,
+}
+
+generate_repr!(DocumentUsage);
+
 #[cfg_attr(feature = "pyo3_macros", pyclass)]
 #[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
 #[derive(Debug, Clone, Serialize)]
@@ -140,6 +249,17 @@ pub struct ChatCompletionResponse {
     pub system_fingerprint: String,
     pub object: String,
     pub usage: Usage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub effective_params: Option<EffectiveSamplingParams>,
+    /// Documents of the request included in the prompt, in the order they were packed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub documents: Option<Vec<DocumentUsage>>,
+    /// Weights of the LoRA adapters blended for the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adapters: Option<Vec<(String, f32)>>,
+    /// Pipeline which served the request, if it was sent through a router.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub routing: Option<RoutingDecision>,
 }
 
 generate_repr!(ChatCompletionResponse);
@@ -156,6 +276,18 @@ pub struct ChatCompletionChunkResponse {
     pub system_fingerprint: String,
     pub object: String,
     pub usage: Option<Usage>,
+    /// Tokens of the prompt, so that a client which stops reading the stream early can still be
+    /// accounted for them. Not part of the OpenAI response.
+    #[serde(skip)]
+    pub prompt_tokens: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub effective_params: Option<EffectiveSamplingParams>,
+    /// Documents of the request included in the prompt, in the order they were packed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub documents: Option<Vec<DocumentUsage>>,
+    /// Weights of the LoRA adapters blended for the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adapters: Option<Vec<(String, f32)>>,
 }
 
 generate_repr!(ChatCompletionChunkResponse);
@@ -169,6 +301,7 @@ pub struct CompletionChoice {
     pub index: usize,
     pub text: String,
     pub logprobs: Option<()>,
+    pub stop_reason: Option<ResponseStopReason>,
 }
 
 generate_repr!(CompletionChoice);
@@ -185,6 +318,11 @@ pub struct CompletionResponse {
     pub system_fingerprint: String,
     pub object: String,
     pub usage: Usage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub effective_params: Option<EffectiveSamplingParams>,
+    /// Weights of the LoRA adapters blended for the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adapters: Option<Vec<(String, f32)>>,
 }
 
 generate_repr!(CompletionResponse);
@@ -200,6 +338,18 @@ pub struct CompletionChunkResponse {
     pub model: String,
     pub system_fingerprint: String,
     pub object: String,
+    /// Sent with the final chunk.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    /// Tokens of the prompt, so that a client which stops reading the stream early can still be
+    /// accounted for them. Not part of the OpenAI response.
+    #[serde(skip)]
+    pub prompt_tokens: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub effective_params: Option<EffectiveSamplingParams>,
+    /// Weights of the LoRA adapters blended for the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adapters: Option<Vec<(String, f32)>>,
 }
 
 generate_repr!(CompletionChunkResponse);
@@ -224,6 +374,23 @@ pub struct ImageGenerationResponse {
 
 generate_repr!(ImageGenerationResponse);
 
+#[derive(Debug, Clone, Serialize)]
+/// The prompt of a request measured against the context of the model.
+pub struct ContextCheck {
+    /// Tokens of the rendered prompt, before truncation.
+    pub prompt_tokens: usize,
+    /// Maximum sequence length of the model.
+    pub max_seq_len: usize,
+    /// Tokens which would be truncated from the start of the prompt.
+    pub truncated_tokens: usize,
+    /// Tokens of the context left for the response after the prompt, once truncated.
+    pub remaining_tokens: usize,
+    /// The documents which would be packed into the prompt, if the request has documents.
+    pub documents: Option<Vec<DocumentUsage>>,
+    /// Why the request would be rejected, if the prompt does not fit and is not truncated.
+    pub rejected: Option<String>,
+}
+
 /// The response enum contains 3 types of variants:
 /// - Error (-Error suffix)
 /// - Chat (no prefix)
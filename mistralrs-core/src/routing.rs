@@ -0,0 +1,224 @@
+//! Routing policy for serving several pipelines of the same model, for example a high quality
+//! and a high capacity quantization, and choosing the pipeline of each request.
+
+#[cfg(feature = "pyo3_macros")]
+use pyo3::pyclass;
+use serde::{Deserialize, Serialize};
+
+/// Tags of a request which the routing rules match on.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RouteTags {
+    /// Requested model name.
+    pub model: Option<String>,
+    /// Priority tag, for example `"high"`.
+    pub priority: Option<String>,
+}
+
+/// Serve the requests of a rule with another pipeline when the queue of its target is too long.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Overflow {
+    /// Number of queued requests of the target above which requests overflow.
+    pub max_queue: usize,
+    /// Pipeline serving the requests which overflow.
+    pub fallback: String,
+}
+
+/// A rule matching requests by model name and priority tag. A rule without any condition
+/// matches every request.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RoutingRule {
+    pub model: Option<String>,
+    pub priority: Option<String>,
+    /// Pipeline serving the matching requests.
+    pub target: String,
+    pub overflow: Option<Overflow>,
+}
+
+impl RoutingRule {
+    pub fn new(target: impl ToString) -> Self {
+        Self {
+            model: None,
+            priority: None,
+            target: target.to_string(),
+            overflow: None,
+        }
+    }
+
+    pub fn with_model(mut self, model: impl ToString) -> Self {
+        self.model = Some(model.to_string());
+        self
+    }
+
+    pub fn with_priority(mut self, priority: impl ToString) -> Self {
+        self.priority = Some(priority.to_string());
+        self
+    }
+
+    pub fn with_overflow(mut self, max_queue: usize, fallback: impl ToString) -> Self {
+        self.overflow = Some(Overflow {
+            max_queue,
+            fallback: fallback.to_string(),
+        });
+        self
+    }
+
+    fn matches(&self, tags: &RouteTags) -> bool {
+        let matches = |condition: &Option<String>, tag: &Option<String>| {
+            condition.is_none() || condition == tag
+        };
+        matches(&self.model, &tags.model) && matches(&self.priority, &tags.priority)
+    }
+}
+
+/// Which pipeline serves a request, and why.
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RoutingDecision {
+    /// Pipeline serving the request.
+    pub pipeline: String,
+    /// Index of the matching rule, or `None` if no rule matched and the default pipeline was used.
+    pub rule: Option<usize>,
+    /// Whether the queue of the rule's target was too long and the request overflowed to the
+    /// fallback pipeline.
+    pub overflowed: bool,
+    /// Queue depth of the serving pipeline when the request was routed.
+    pub queue_depth: usize,
+}
+
+/// Rules evaluated in order for each request: the first matching rule selects the pipeline, and
+/// requests matching no rule go to the default pipeline.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RoutingPolicy {
+    pub rules: Vec<RoutingRule>,
+    pub default: String,
+}
+
+impl RoutingPolicy {
+    pub fn new(default: impl ToString) -> Self {
+        Self {
+            rules: Vec::new(),
+            default: default.to_string(),
+        }
+    }
+
+    pub fn with_rule(mut self, rule: RoutingRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Names of all the pipelines the policy can route to.
+    pub fn pipelines(&self) -> impl Iterator<Item = &str> {
+        self.rules
+            .iter()
+            .flat_map(|rule| {
+                std::iter::once(rule.target.as_str())
+                    .chain(rule.overflow.as_ref().map(|o| o.fallback.as_str()))
+            })
+            .chain(std::iter::once(self.default.as_str()))
+    }
+
+    /// Choose the pipeline of a request, given the current queue depth of each pipeline.
+    pub fn route(&self, tags: &RouteTags, queue_depth: impl Fn(&str) -> usize) -> RoutingDecision {
+        let Some((idx, rule)) = self
+            .rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.matches(tags))
+        else {
+            return RoutingDecision {
+                pipeline: self.default.clone(),
+                rule: None,
+                overflowed: false,
+                queue_depth: queue_depth(&self.default),
+            };
+        };
+
+        let target_depth = queue_depth(&rule.target);
+        match &rule.overflow {
+            Some(Overflow {
+                max_queue,
+                fallback,
+            }) if target_depth > *max_queue => RoutingDecision {
+                pipeline: fallback.clone(),
+                rule: Some(idx),
+                overflowed: true,
+                queue_depth: queue_depth(fallback),
+            },
+            _ => RoutingDecision {
+                pipeline: rule.target.clone(),
+                rule: Some(idx),
+                overflowed: false,
+                queue_depth: target_depth,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{RouteTags, RoutingPolicy, RoutingRule};
+
+    #[test]
+    fn quality_routing_with_overflow() {
+        let policy = RoutingPolicy::new("q3").with_rule(
+            RoutingRule::new("q8")
+                .with_priority("high")
+                .with_overflow(2, "q3"),
+        );
+        let high = RouteTags {
+            model: None,
+            priority: Some("high".to_string()),
+        };
+        let mut depths = HashMap::from([("q8", 0), ("q3", 5)]);
+
+        let decision = policy.route(&high, |p| depths[p]);
+        assert_eq!(decision.pipeline, "q8");
+        assert_eq!(decision.rule, Some(0));
+        assert!(!decision.overflowed);
+
+        // Other requests go to the default pipeline.
+        let decision = policy.route(&RouteTags::default(), |p| depths[p]);
+        assert_eq!(decision.pipeline, "q3");
+        assert_eq!(decision.rule, None);
+        assert_eq!(decision.queue_depth, 5);
+
+        // At the threshold the target still serves, above it the request overflows.
+        depths.insert("q8", 2);
+        assert_eq!(policy.route(&high, |p| depths[p]).pipeline, "q8");
+        depths.insert("q8", 3);
+        let decision = policy.route(&high, |p| depths[p]);
+        assert_eq!(decision.pipeline, "q3");
+        assert!(decision.overflowed);
+        assert_eq!(decision.queue_depth, 5);
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let policy = RoutingPolicy::new("default")
+            .with_rule(
+                RoutingRule::new("small-high")
+                    .with_model("small")
+                    .with_priority("high"),
+            )
+            .with_rule(RoutingRule::new("small").with_model("small"))
+            .with_rule(RoutingRule::new("high").with_priority("high"));
+        let route = |model: Option<&str>, priority: Option<&str>| {
+            let tags = RouteTags {
+                model: model.map(ToString::to_string),
+                priority: priority.map(ToString::to_string),
+            };
+            policy.route(&tags, |_| 0).pipeline
+        };
+        assert_eq!(route(Some("small"), Some("high")), "small-high");
+        assert_eq!(route(Some("small"), None), "small");
+        assert_eq!(route(Some("large"), Some("high")), "high");
+        assert_eq!(route(Some("large"), Some("low")), "default");
+        assert_eq!(
+            policy.pipelines().collect::<Vec<_>>(),
+            ["small-high", "small", "high", "default"]
+        );
+    }
+}
@@ -11,12 +11,17 @@ use candle_core::{Device, Error, Result, Tensor, D};
 use pyo3::pyclass;
 
 use once_cell::sync::Lazy;
-use rand::distr::{weighted::WeightedIndex, Distribution};
+use rand::{
+    distr::{weighted::WeightedIndex, Distribution},
+    Rng,
+};
 use rand_isaac::Isaac64Rng;
 use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
 
+use crate::beam_search::BeamSearchConfig;
+
 static DRY_SEQUENCE_BREAKERS: Lazy<Vec<String>> =
     Lazy::new(|| ["\n", ":", "\"", "*"].map(String::from).to_vec());
 
@@ -27,6 +32,24 @@ pub enum StopTokens {
     Ids(Vec<u32>),
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+/// How a token is drawn from the probabilities after temperature, top-k, top-p and min-p.
+pub enum SamplerBackend {
+    /// Inverse transform sampling of the weighted distribution.
+    #[default]
+    Native,
+    /// The argmax of `ln(p) + g` with Gumbel noise `g`. One uniform is drawn for every token of the
+    /// vocabulary from the seeded RNG, so the noise does not depend on the device. The result only
+    /// changes if a difference in the logits changes the argmax, unlike the cumulative sum of the
+    /// native sampling which every difference shifts.
+    GumbelMax,
+    /// Beam search: keep the `num_beams` most likely sequences at each step and return the one
+    /// with the best length-normalized score. The temperature, top-k, top-p and min-p do not
+    /// apply.
+    BeamSearch(BeamSearchConfig),
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 /// Sampling params are used to control sampling.
 pub struct SamplingParams {
@@ -37,11 +60,44 @@ pub struct SamplingParams {
     pub top_n_logprobs: usize,
     pub frequency_penalty: Option<f32>,
     pub presence_penalty: Option<f32>,
+    /// Number of trailing tokens of the context counted by the frequency and presence penalties,
+    /// by default the whole context.
+    pub repeat_last_n: Option<usize>,
     pub stop_toks: Option<StopTokens>,
     pub max_len: Option<usize>,
     pub logits_bias: Option<HashMap<u32, f32>>,
     pub n_choices: usize,
     pub dry_params: Option<DrySamplingParams>,
+    /// Keep the matched stop string at the end of the output instead of trimming it.
+    pub include_stop_str_in_output: bool,
+    /// Number of trailing tokens searched for the stop strings after each token, by default the
+    /// length in bytes of the longest stop string, which finds all of them. A smaller lookback is
+    /// cheaper but misses the stop strings spread over more tokens.
+    pub stop_lookback: Option<usize>,
+    /// Stop once this (open, close) delimiter pair balances after the first open delimiter.
+    pub stop_on_balanced: Option<(char, char)>,
+    /// Number of tokens of context to keep free for the response. A prompt which does not leave
+    /// this much room, even if it fits in the context, is rejected or, with `truncate_sequence`,
+    /// truncated from the start.
+    pub reserved_output_tokens: usize,
+    /// Mask the first generated token to those which continue the last word of the prompt
+    /// instead of starting a new word.
+    pub continue_word: bool,
+    /// Tail free sampling parameter in `(0, 1)`: the tokens after the point where the normalized
+    /// second derivative of the sorted probabilities sums to `tfs_z` are removed.
+    pub tfs_z: Option<f32>,
+    /// GNMT length penalty exponent `alpha` used to rank the candidates of `best_of`: the
+    /// cumulative logprob of each candidate is divided by `((5 + len) / 6)^alpha`. Higher values
+    /// favor longer completions, 0 ranks by the cumulative logprob alone.
+    pub length_penalty: Option<f32>,
+    pub sampler: SamplerBackend,
+    /// Decode greedily except after the trigger tokens of the config.
+    pub hybrid_decode: Option<HybridConfig>,
+    /// Adapt the temperature to the entropy of each token distribution. Replaces `temperature`.
+    pub adaptive_temperature: Option<AdaptiveTemperature>,
+    /// Remove this prefix, for example a role marker echoed by the model, from the start of the
+    /// response if it starts with it, ignoring leading whitespace.
+    pub strip_response_prefix: Option<String>,
 }
 
 impl SamplingParams {
@@ -58,13 +114,67 @@ impl SamplingParams {
             top_n_logprobs: 0,
             frequency_penalty: None,
             presence_penalty: None,
+            repeat_last_n: None,
             stop_toks: None,
             max_len: None,
             logits_bias: None,
             n_choices: 1,
             dry_params: None,
+            include_stop_str_in_output: false,
+            stop_lookback: None,
+            stop_on_balanced: None,
+            reserved_output_tokens: 0,
+            continue_word: false,
+            tfs_z: None,
+            length_penalty: None,
+            sampler: SamplerBackend::Native,
+            hybrid_decode: None,
+            adaptive_temperature: None,
+            strip_response_prefix: None,
         }
     }
+
+    /// Fill the sampling parameters which are not set by the request with the defaults of the
+    /// server, then with those of the model, then with the built-in defaults: a temperature of
+    /// 1, no top-k, a top-p of 1 and a min-p of 0. This is where the defaults of a request are
+    /// resolved, so after it the temperature, top-p and min-p are always set.
+    pub fn with_defaults(mut self, server: &SamplingDefaults, model: &SamplingDefaults) -> Self {
+        self.temperature = self
+            .temperature
+            .or(server.temperature)
+            .or(model.temperature)
+            .or(Some(1.0));
+        self.top_k = self.top_k.or(server.top_k).or(model.top_k);
+        self.top_p = self.top_p.or(server.top_p).or(model.top_p).or(Some(1.0));
+        self.min_p = self.min_p.or(server.min_p).or(model.min_p).or(Some(0.0));
+        self.frequency_penalty = self
+            .frequency_penalty
+            .or(server.frequency_penalty)
+            .or(model.frequency_penalty);
+        self.presence_penalty = self
+            .presence_penalty
+            .or(server.presence_penalty)
+            .or(model.presence_penalty);
+        self.repeat_last_n = self
+            .repeat_last_n
+            .or(server.repeat_last_n)
+            .or(model.repeat_last_n);
+        self
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+/// Sampling parameters used for requests which do not set them, from the server configuration or
+/// the `generation_config.json` of the model.
+pub struct SamplingDefaults {
+    pub temperature: Option<f64>,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f64>,
+    pub min_p: Option<f64>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub repeat_last_n: Option<usize>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -75,6 +185,86 @@ pub struct DrySamplingParams {
     pub allowed_length: usize,
 }
 
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+/// Decode greedily, except for the token following one of `sample_after_tokens`, which is sampled
+/// with the configured temperature, top-k, top-p and min-p. This keeps the structure of the
+/// output deterministic while the parts after the trigger tokens vary.
+pub struct HybridConfig {
+    pub sample_after_tokens: Vec<u32>,
+}
+
+/// Bounds of the temperature with [`AdaptiveTemperature`].
+const ADAPTIVE_TEMPERATURE_RANGE: (f64, f64) = (0.05, 2.0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+/// Adapt the temperature to the entropy, in nats, of the distribution of each generated token.
+/// The temperature starts at `base_temp`, and after each token it moves by `learning_rate` times
+/// the difference between `entropy_target` and the entropy: it increases when the model is
+/// confident and decreases when it is uncertain. This keeps the diversity of the output roughly
+/// constant, avoiding both degenerate repetition and incoherent rambling. The temperature stays
+/// between 0.05 and 2.
+pub struct AdaptiveTemperature {
+    pub base_temp: f32,
+    pub entropy_target: f32,
+    pub learning_rate: f32,
+}
+
+/// Temperature of a sequence with adaptive temperature, updated after each token.
+struct AdaptiveTemperatureState {
+    params: AdaptiveTemperature,
+    temperature: Mutex<f64>,
+}
+
+impl AdaptiveTemperatureState {
+    fn new(params: AdaptiveTemperature) -> anyhow::Result<Self> {
+        let (min, max) = ADAPTIVE_TEMPERATURE_RANGE;
+        let base_temp = f64::from(params.base_temp);
+        if !(min..=max).contains(&base_temp) {
+            anyhow::bail!("The base temperature must be between {min} and {max}, got {base_temp}.");
+        }
+        if params.entropy_target < 0. || params.learning_rate < 0. {
+            anyhow::bail!("The entropy target and the learning rate must not be negative.");
+        }
+        Ok(Self {
+            params,
+            temperature: Mutex::new(base_temp),
+        })
+    }
+
+    fn temperature(&self) -> f64 {
+        *self.temperature.lock().unwrap()
+    }
+
+    /// Move the temperature towards the entropy target, given the entropy of the distribution of
+    /// the last token.
+    fn update(&self, entropy: f64) {
+        let (min, max) = ADAPTIVE_TEMPERATURE_RANGE;
+        let mut temperature = self.temperature.lock().unwrap();
+        let step = f64::from(self.params.learning_rate)
+            * (f64::from(self.params.entropy_target) - entropy);
+        *temperature = (*temperature + step).clamp(min, max);
+    }
+}
+
+impl Clone for AdaptiveTemperatureState {
+    fn clone(&self) -> Self {
+        Self {
+            params: self.params,
+            temperature: Mutex::new(self.temperature()),
+        }
+    }
+}
+
+/// Entropy in nats of the distribution of `logits` at `temperature`.
+fn entropy(logits: &Tensor, temperature: f64) -> Result<f64> {
+    let probs: Vec<f32> = candle_nn::ops::softmax_last_dim(&(logits / temperature)?)?.to_vec1()?;
+    Ok(probs
+        .iter()
+        .filter(|p| **p > 0.)
+        .map(|p| -f64::from(*p) * f64::from(*p).ln())
+        .sum())
+}
+
 impl DrySamplingParams {
     pub fn new_with_defaults(
         multiplier: f32,
@@ -185,10 +375,15 @@ pub struct Sampler {
     tokenizer: Option<Arc<Tokenizer>>,
     frequency_penalty: Option<f32>,
     presence_penalty: Option<f32>,
+    repeat_last_n: Option<usize>,
     dry_params: Option<DrySamplingParamsInner>,
     top_k: i64,
     top_p: f64,
     min_p: f64,
+    tfs_z: Option<f32>,
+    backend: SamplerBackend,
+    hybrid_decode: Option<HybridConfig>,
+    adaptive_temperature: Option<AdaptiveTemperatureState>,
     logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
 }
 
@@ -214,6 +409,73 @@ fn argmax_sample_last_dim(logits: &Tensor) -> Result<Tensor> {
     logits.argmax(D::Minus1)
 }
 
+/// Tail free sampling: mask the tail of the distribution, found with the second derivative of
+/// the sorted probabilities. The absolute second derivatives are normalized to sum to 1 and the
+/// tokens after the point where their cumulative sum exceeds `z` are masked with `-inf`. At
+/// least one token is always kept, and a `z` outside of `(0, 1)` keeps all tokens.
+pub(crate) fn tfs_sample(logits: &Tensor, z: f32) -> Result<Tensor> {
+    let logits_v: Vec<f32> = logits.to_dtype(candle_core::DType::F32)?.to_vec1()?;
+    if z <= 0.0 || z >= 1.0 || logits_v.len() <= 2 {
+        return Ok(logits.clone());
+    }
+
+    let max = logits_v.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let mut probs = logits_v.iter().map(|x| (x - max).exp()).collect::<Vec<_>>();
+    let sum = probs.iter().sum::<f32>();
+    probs.iter_mut().for_each(|p| *p /= sum);
+
+    let mut sorted = (0..probs.len()).collect::<Vec<_>>();
+    sorted.sort_by(|a, b| probs[*b].total_cmp(&probs[*a]));
+
+    let first_derivatives = sorted
+        .windows(2)
+        .map(|w| probs[w[0]] - probs[w[1]])
+        .collect::<Vec<_>>();
+    let mut second_derivatives = first_derivatives
+        .windows(2)
+        .map(|w| (w[0] - w[1]).abs())
+        .collect::<Vec<_>>();
+    let total = second_derivatives.iter().sum::<f32>();
+    if total > 1e-6 {
+        second_derivatives.iter_mut().for_each(|d| *d /= total);
+    }
+
+    let mut keep = sorted.len();
+    let mut cumsum = 0.0;
+    for (i, d) in second_derivatives.iter().enumerate() {
+        cumsum += d;
+        if cumsum > z {
+            keep = i.max(1);
+            break;
+        }
+    }
+
+    let mut masked = logits_v;
+    for &idx in &sorted[keep..] {
+        masked[idx] = f32::NEG_INFINITY;
+    }
+    Tensor::from_vec(masked, logits.shape(), logits.device())?.to_dtype(logits.dtype())
+}
+
+/// Gumbel-max sampling: `argmax(ln(p) + g)` with `g = -ln(-ln(u))` is distributed like a sample of
+/// `p`. A uniform `u` is drawn for every token, including those with a probability of zero, so the
+/// state of the RNG afterwards only depends on the vocabulary size.
+fn gumbel_max_sample(probs: &[f32], rng: &mut Isaac64Rng) -> Result<usize> {
+    let mut best: Option<(usize, f64)> = None;
+    for (i, &p) in probs.iter().enumerate() {
+        let u = rng.random::<f64>();
+        if p <= 0. {
+            continue;
+        }
+        let score = f64::from(p).ln() - (-u.ln()).ln();
+        if best.is_none_or(|(_, best_score)| score > best_score) {
+            best = Some((i, score));
+        }
+    }
+    best.map(|(i, _)| i)
+        .ok_or_else(|| Error::Msg("All token probabilities are zero.".to_string()))
+}
+
 impl Sampler {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
@@ -222,12 +484,20 @@ impl Sampler {
         tokenizer: Option<Arc<Tokenizer>>,
         frequency_penalty: Option<f32>,
         presence_penalty: Option<f32>,
+        repeat_last_n: Option<usize>,
         dry_params: Option<DrySamplingParams>,
         top_k: i64,
         top_p: f64,
         min_p: f64,
+        tfs_z: Option<f32>,
+        backend: SamplerBackend,
+        hybrid_decode: Option<HybridConfig>,
+        adaptive_temperature: Option<AdaptiveTemperature>,
         logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
     ) -> anyhow::Result<Self> {
+        let adaptive_temperature = adaptive_temperature
+            .map(AdaptiveTemperatureState::new)
+            .transpose()?;
         let temperature = if temperature.is_none_or(|v| v < 1e-7) {
             None
         } else {
@@ -248,10 +518,15 @@ impl Sampler {
             tokenizer,
             frequency_penalty,
             presence_penalty,
+            repeat_last_n,
             dry_params,
             top_k,
             top_p,
             min_p,
+            tfs_z,
+            backend,
+            hybrid_decode,
+            adaptive_temperature,
             logits_processors,
         })
     }
@@ -348,44 +623,7 @@ impl Sampler {
         let mut probs: Vec<f32> = logits.to_vec1()?;
         let argsort_indices: Vec<u32> = logits.arg_sort_last_dim(false)?.to_vec1()?;
 
-        if top_k > 0 {
-            // Clamp smaller probabilities to zero.
-            for (index, val) in argsort_indices.iter().enumerate() {
-                if index >= top_k as usize {
-                    probs[*val as usize] = 0.0;
-                }
-            }
-        }
-
-        // TOP P
-
-        // top-p sampling (or "nucleus sampling") samples from the smallest set of
-        // tokens that exceed probability top_p. This way we never sample tokens that
-        // have very low probabilities and are less likely to go "off the rails".
-
-        // Clamp smaller probabilities to zero.
-        let mut cumsum = 0.;
-        for index in &argsort_indices {
-            if cumsum >= top_p {
-                probs[*index as usize] = 0.0;
-            } else {
-                cumsum += probs[*index as usize];
-            }
-        }
-
-        let max_p = probs[argsort_indices[0] as usize];
-
-        // MIN P
-
-        // min-p sampling samples from the tokens whose prob are greater than
-        // (max prob of token in dist) * min_p
-
-        // Clamp smaller probabilities to zero.
-        for index in &argsort_indices {
-            if max_p * min_p >= probs[*index as usize] {
-                probs[*index as usize] = 0.0;
-            }
-        }
+        filter_top_kp_min_p(&mut probs, &argsort_indices, top_k, top_p, min_p);
 
         let logits = Tensor::from_slice(&probs, logits.shape(), &Device::Cpu)?;
 
@@ -417,6 +655,18 @@ impl Sampler {
         })
     }
 
+    /// Draw a token from the weights `probs` with the backend of the sampler.
+    fn draw(&self, probs: &[f32], rng: &mut Isaac64Rng) -> Result<usize> {
+        match self.backend {
+            // Beam search draws its tokens with `beam_candidates`.
+            SamplerBackend::Native | SamplerBackend::BeamSearch(_) => {
+                let distr = WeightedIndex::new(probs).map_err(Error::wrap)?;
+                Ok(distr.sample(rng)) // "Find the first item which has a weight *higher* than the chosen weight."
+            }
+            SamplerBackend::GumbelMax => gumbel_max_sample(probs, rng),
+        }
+    }
+
     fn sample_multinomial(
         &self,
         probs: &mut Vec<f32>,
@@ -424,10 +674,7 @@ impl Sampler {
         return_logprobs: bool,
         rng: Arc<Mutex<Isaac64Rng>>,
     ) -> Result<Logprobs> {
-        let distr = WeightedIndex::new(&*probs).map_err(Error::wrap)?;
-
-        let mut mut_ref_rng = &mut *rng.lock().expect("could not lock rng mutex");
-        let next_token = distr.sample(&mut mut_ref_rng); // "Find the first item which has a weight *higher* than the chosen weight."
+        let next_token = self.draw(probs, &mut rng.lock().expect("could not lock rng mutex"))?;
         let logprob = probs[next_token].log(10.0);
 
         let top_logprobs = if return_logprobs {
@@ -537,6 +784,10 @@ impl Sampler {
         if self.frequency_penalty.is_some() || self.presence_penalty.is_some() {
             let frequency_penalty = self.frequency_penalty.unwrap_or(0.);
             let presence_penalty = self.presence_penalty.unwrap_or(0.);
+            let context = match self.repeat_last_n {
+                Some(n) => &context[context.len().saturating_sub(n)..],
+                None => context,
+            };
 
             //mu[j] -> mu[j] - c[j] * alpha_frequency - float(c[j] > 0) * alpha_presence
 
@@ -631,6 +882,196 @@ impl Sampler {
         Ok(())
     }
 
+    /// The distribution which `sample` draws from after the penalties, the logits processors, the
+    /// temperature, top-k, top-p and min-p, renormalized. `None` with argmax sampling.
+    pub(crate) fn distribution(&self, logits: Tensor, context: &[u32]) -> Result<Option<Vec<f32>>> {
+        let Some(temperature) = self.temperature_for(context) else {
+            return Ok(None);
+        };
+        let logits = self.process_logits(logits, context)?;
+        let probs = candle_nn::ops::softmax_last_dim(&(&logits / temperature)?)?;
+        let argsort_indices: Vec<u32> = probs.arg_sort_last_dim(false)?.to_vec1()?;
+        let mut probs: Vec<f32> = probs.to_vec1()?;
+        filter_top_kp_min_p(
+            &mut probs,
+            &argsort_indices,
+            self.top_k,
+            self.top_p as f32,
+            self.min_p as f32,
+        );
+        let sum = probs.iter().sum::<f32>();
+        if sum > 0. {
+            probs.iter_mut().for_each(|p| *p /= sum);
+        } else {
+            // A top-p of zero keeps no token, then the most likely one is taken as with argmax
+            // sampling.
+            probs.fill(0.);
+            probs[argsort_indices[0] as usize] = 1.;
+        }
+        Ok(Some(probs))
+    }
+
+    /// Draw a token from the distribution `probs` of [`Sampler::distribution`] with the backend of
+    /// the sampler.
+    pub(crate) fn sample_distribution(
+        &self,
+        probs: &[f32],
+        return_logprobs: bool,
+        rng: &mut Isaac64Rng,
+    ) -> Result<Logprobs> {
+        let token = self.draw(probs, rng)?;
+        self.distribution_logprobs(probs, token as u32, return_logprobs)
+    }
+
+    /// Rejection sampling of speculative decoding (<https://arxiv.org/pdf/2211.17192>), which keeps
+    /// the distribution of the target model.
+    ///
+    /// Draft token `i` was drawn from `draft_probs[i]` and is accepted with probability
+    /// `min(1, p(x) / q(x))`, where `p` is `target_probs[i]`. At the first rejection, a token is
+    /// drawn from the residual distribution `norm(max(0, p - q))` instead, and the later draft
+    /// tokens are dropped. If every draft token is accepted and `target_probs` has one more
+    /// distribution, a bonus token is drawn from it. The tokens are drawn with the backend of the
+    /// sampler. Returns the tokens to add to the sequence.
+    pub(crate) fn speculative_rejection_sample(
+        &self,
+        draft_tokens: &[u32],
+        draft_probs: &[Vec<f32>],
+        target_probs: &[Vec<f32>],
+        rng: &mut Isaac64Rng,
+    ) -> Result<Vec<u32>> {
+        let mut tokens = Vec::with_capacity(target_probs.len());
+        for ((&token, q), p) in zip(draft_tokens, draft_probs).zip(target_probs) {
+            let (p_x, q_x) = (p[token as usize], q[token as usize]);
+            if q_x <= p_x || rng.random::<f32>() < p_x / q_x {
+                tokens.push(token);
+                continue;
+            }
+            let residual = zip(p, q).map(|(p, q)| (p - q).max(0.)).collect::<Vec<_>>();
+            // Rounding may leave no residual mass, then the target distribution is used.
+            let weights = if residual.iter().sum::<f32>() > 0. {
+                &residual
+            } else {
+                p
+            };
+            tokens.push(self.draw(weights, rng)? as u32);
+            return Ok(tokens);
+        }
+        if let Some(p) = target_probs.get(draft_tokens.len()) {
+            tokens.push(self.draw(p, rng)? as u32);
+        }
+        Ok(tokens)
+    }
+
+    /// The logprobs of `token` drawn from the distribution `probs`, as returned by `sample`.
+    pub(crate) fn distribution_logprobs(
+        &self,
+        probs: &[f32],
+        token: u32,
+        return_logprobs: bool,
+    ) -> Result<Logprobs> {
+        let top_logprobs = if return_logprobs {
+            let mut argsort_indices = (0..probs.len() as u32).collect::<Vec<_>>();
+            argsort_indices.sort_by(|a, b| probs[*b as usize].total_cmp(&probs[*a as usize]));
+            Some(self.get_top_logprobs(probs, &argsort_indices)?)
+        } else {
+            None
+        };
+        let bytes = match &self.tokenizer {
+            Some(tokenizer) => Some(
+                tokenizer
+                    .decode(&[token], false)
+                    .map_err(|x| Error::Msg(x.to_string()))?,
+            ),
+            None => None,
+        };
+        Ok(Logprobs {
+            token,
+            logprob: probs[token as usize].log(10.0),
+            top_logprobs,
+            bytes,
+        })
+    }
+
+    /// Apply the penalties, the logits processors and tail free sampling.
+    fn process_logits(&self, logits: Tensor, context: &[u32]) -> Result<Tensor> {
+        let logits = logits.to_vec1()?;
+        let mut logits = self.apply_penalties(logits, context)?;
+        for processor in &self.logits_processors {
+            logits = processor.apply(&logits, context)?;
+        }
+        if let Some(z) = self.tfs_z {
+            logits = tfs_sample(&logits, z)?;
+        }
+        Ok(logits)
+    }
+
+    /// The `n` most likely next tokens for beam search, ordered by decreasing logprob, after the
+    /// penalties, the logits processors and tail free sampling. Ties are ordered by token id, so
+    /// the first candidate is the token chosen by greedy sampling.
+    pub(crate) fn beam_candidates(
+        &self,
+        logits: Tensor,
+        context: &[u32],
+        n: usize,
+        return_logprobs: bool,
+    ) -> Result<Vec<Logprobs>> {
+        let logits = self.process_logits(logits, context)?;
+        // Base 10, like the logprobs of the other backends.
+        let logprobs: Vec<f32> = (candle_nn::ops::log_softmax(&logits, D::Minus1)?
+            / std::f64::consts::LN_10)?
+            .to_vec1()?;
+        let mut argsort_indices = (0..logprobs.len() as u32).collect::<Vec<_>>();
+        argsort_indices.sort_by(|a, b| logprobs[*b as usize].total_cmp(&logprobs[*a as usize]));
+
+        let top_logprobs = if return_logprobs {
+            let probs = logprobs
+                .iter()
+                .map(|lp| 10f32.powf(*lp))
+                .collect::<Vec<_>>();
+            Some(self.get_top_logprobs(&probs, &argsort_indices)?)
+        } else {
+            None
+        };
+        argsort_indices
+            .into_iter()
+            .take(n)
+            .map(|token| {
+                let bytes = match &self.tokenizer {
+                    Some(tokenizer) => Some(
+                        tokenizer
+                            .decode(&[token], false)
+                            .map_err(|x| Error::Msg(x.to_string()))?,
+                    ),
+                    None => None,
+                };
+                Ok(Logprobs {
+                    token,
+                    logprob: logprobs[token as usize],
+                    bytes,
+                    top_logprobs: top_logprobs.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// The temperature of the next token, `None` for greedy decoding. With hybrid decoding, it is
+    /// only used if the last token is one of the trigger tokens.
+    fn temperature_for(&self, context: &[u32]) -> Option<f64> {
+        match &self.hybrid_decode {
+            Some(hybrid)
+                if !context
+                    .last()
+                    .is_some_and(|tok| hybrid.sample_after_tokens.contains(tok)) =>
+            {
+                None
+            }
+            _ => match &self.adaptive_temperature {
+                Some(adaptive) => Some(adaptive.temperature()),
+                None => self.temperature,
+            },
+        }
+    }
+
     /// Sample the provided tokens.
     ///
     /// If the temperature is `None`, argmax sampling is used. Otherwise, the selected sampling is used.
@@ -643,13 +1084,14 @@ impl Sampler {
         rng: Arc<Mutex<Isaac64Rng>>,
         sample_speculative: bool,
     ) -> Result<Logprobs> {
-        let logits = logits.to_vec1()?;
-        let mut logits = self.apply_penalties(logits, context)?;
-        for processor in &self.logits_processors {
-            logits = processor.apply(&logits, context)?;
+        let logits = self.process_logits(logits, context)?;
+        let temperature = self.temperature_for(context);
+        // The token is sampled at the current temperature, the next ones at the updated one.
+        if let (Some(adaptive), Some(temperature)) = (&self.adaptive_temperature, temperature) {
+            adaptive.update(entropy(&logits, temperature)?);
         }
         let next_token = if sample_speculative {
-            match self.temperature {
+            match temperature {
                 None => self.sample_speculative_top_kp_min_p(
                     logits,
                     return_logprobs,
@@ -671,7 +1113,7 @@ impl Sampler {
                 }
             }
         } else {
-            match self.temperature {
+            match temperature {
                 None => self.sample_argmax(logits, return_logprobs)?,
                 Some(temperature) => {
                     let logits = (&logits / temperature)?;
@@ -694,18 +1136,127 @@ impl Sampler {
     }
 }
 
+/// Clamp to zero the probabilities outside of the top-k, the top-p and the min-p.
+/// `argsort_indices` sorts the probabilities in descending order.
+fn filter_top_kp_min_p(
+    probs: &mut [f32],
+    argsort_indices: &[u32],
+    top_k: i64,
+    top_p: f32,
+    min_p: f32,
+) {
+    if top_k > 0 {
+        // Clamp smaller probabilities to zero.
+        for (index, val) in argsort_indices.iter().enumerate() {
+            if index >= top_k as usize {
+                probs[*val as usize] = 0.0;
+            }
+        }
+    }
+
+    // TOP P
+
+    // top-p sampling (or "nucleus sampling") samples from the smallest set of
+    // tokens that exceed probability top_p. This way we never sample tokens that
+    // have very low probabilities and are less likely to go "off the rails".
+
+    // Clamp smaller probabilities to zero.
+    let mut cumsum = 0.;
+    for index in argsort_indices {
+        if cumsum >= top_p {
+            probs[*index as usize] = 0.0;
+        } else {
+            cumsum += probs[*index as usize];
+        }
+    }
+
+    let max_p = probs[argsort_indices[0] as usize];
+
+    // MIN P
+
+    // min-p sampling samples from the tokens whose prob are greater than
+    // (max prob of token in dist) * min_p
+
+    // Clamp smaller probabilities to zero.
+    for index in argsort_indices {
+        if max_p * min_p >= probs[*index as usize] {
+            probs[*index as usize] = 0.0;
+        }
+    }
+}
+
 mod tests {
+    #[test]
+    fn test_sampling_defaults_precedence() {
+        use super::{SamplingDefaults, SamplingParams};
+
+        let server = SamplingDefaults {
+            temperature: Some(0.7),
+            top_k: Some(40),
+            repeat_last_n: Some(64),
+            ..Default::default()
+        };
+        let model = SamplingDefaults {
+            temperature: Some(0.6),
+            top_k: Some(20),
+            top_p: Some(0.95),
+            presence_penalty: Some(0.5),
+            ..Default::default()
+        };
+
+        let mut request = SamplingParams::deterministic();
+        request.top_k = None;
+        request.temperature = Some(0.1);
+        let params = request.with_defaults(&server, &model);
+        // The request wins over the server and the model.
+        assert_eq!(params.temperature, Some(0.1));
+        // The server wins over the model.
+        assert_eq!(params.top_k, Some(40));
+        assert_eq!(params.repeat_last_n, Some(64));
+        // The model wins over the built-in defaults.
+        assert_eq!(params.top_p, Some(0.95));
+        assert_eq!(params.presence_penalty, Some(0.5));
+        // The built-in defaults.
+        assert_eq!(params.min_p, Some(0.0));
+        assert_eq!(params.frequency_penalty, None);
+
+        let mut request = SamplingParams::deterministic();
+        request.top_k = None;
+        let params =
+            request.with_defaults(&SamplingDefaults::default(), &SamplingDefaults::default());
+        assert_eq!(params.temperature, Some(1.0));
+        assert_eq!(params.top_k, None);
+        assert_eq!(params.top_p, Some(1.0));
+        assert_eq!(params.min_p, Some(0.0));
+    }
+
     #[test]
     fn test_argmax() {
-        use super::Sampler;
+        use super::{Sampler, SamplerBackend};
         use candle_core::{Device, Tensor};
         use rand::SeedableRng;
         use rand_isaac::Isaac64Rng;
         use std::sync::Arc;
         use std::sync::Mutex;
 
-        let sampler =
-            Sampler::new(None, 10, None, None, None, None, 32, 0.1, 0.05, vec![]).unwrap();
+        let sampler = Sampler::new(
+            None,
+            10,
+            None,
+            None,
+            None,
+            None,
+            None,
+            32,
+            0.1,
+            0.05,
+            None,
+            SamplerBackend::Native,
+            None,
+            None,
+            vec![],
+        )
+        .unwrap();
         let logits = Tensor::arange(0f32, 1024f32, &Device::Cpu).unwrap();
         let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
         let res = sampler
@@ -718,15 +1269,31 @@ mod tests {
 
     #[test]
     fn test_gumbel_speculative() {
-        use super::Sampler;
+        use super::{Sampler, SamplerBackend};
         use candle_core::{Device, Tensor};
         use rand::SeedableRng;
         use rand_isaac::Isaac64Rng;
         use std::sync::Arc;
         use std::sync::Mutex;
 
-        let sampler =
-            Sampler::new(None, 10, None, None, None, None, 32, 0.1, 0.05, vec![]).unwrap();
+        let sampler = Sampler::new(
+            None,
+            10,
+            None,
+            None,
+            None,
+            None,
+            None,
+            32,
+            0.1,
+            0.05,
+            None,
+            SamplerBackend::Native,
+            None,
+            None,
+            vec![],
+        )
+        .unwrap();
         let logits = Tensor::arange(0f32, 1024f32, &Device::Cpu).unwrap();
         let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
         let res = sampler
@@ -736,4 +1303,415 @@ mod tests {
         assert_eq!(res.top_logprobs, None);
         assert_eq!(res.logprob, 1023f64.log(10.) as f32)
     }
+
+    #[test]
+    fn test_gumbel_max_device_independent() {
+        use super::{Sampler, SamplerBackend};
+        use candle_core::{Device, Tensor};
+        use rand::{Rng, SeedableRng};
+        use rand_isaac::Isaac64Rng;
+        use std::sync::{Arc, Mutex};
+
+        let sampler = Sampler::new(
+            Some(0.8),
+            0,
+            None,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            0.95,
+            0.0,
+            None,
+            SamplerBackend::GumbelMax,
+            None,
+            None,
+            vec![],
+        )
+        .unwrap();
+        let mut logits_rng = Isaac64Rng::seed_from_u64(0);
+        let logits = (0..32)
+            .map(|_| (0..512).map(|_| logits_rng.random::<f32>() * 8.).collect())
+            .collect::<Vec<Vec<f32>>>();
+
+        let generate = |device: &Device| {
+            let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+            logits
+                .iter()
+                .map(|logits| {
+                    let logits = Tensor::new(logits.as_slice(), device).unwrap();
+                    sampler
+                        .sample(logits, &[0], false, rng.clone(), false)
+                        .unwrap()
+                        .token
+                })
+                .collect::<Vec<_>>()
+        };
+        let cpu = generate(&Device::Cpu);
+        assert_eq!(cpu, generate(&Device::Cpu));
+        #[cfg(feature = "cuda")]
+        assert_eq!(cpu, generate(&Device::new_cuda(0).unwrap()));
+        #[cfg(feature = "metal")]
+        assert_eq!(cpu, generate(&Device::new_metal(0).unwrap()));
+    }
+
+    #[test]
+    fn test_gumbel_max_distribution() {
+        use super::gumbel_max_sample;
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+
+        let probs = [0.7f32, 0.2, 0.1, 0.0];
+        let mut rng = Isaac64Rng::seed_from_u64(42);
+        let mut counts = [0usize; 4];
+        for _ in 0..10_000 {
+            counts[gumbel_max_sample(&probs, &mut rng).unwrap()] += 1;
+        }
+        assert_eq!(counts[3], 0);
+        for (count, p) in counts.iter().zip(probs) {
+            assert!((*count as f32 / 10_000. - p).abs() < 0.02, "{counts:?}");
+        }
+        assert!(gumbel_max_sample(&[0.0, 0.0], &mut rng).is_err());
+    }
+
+    #[test]
+    fn test_tfs() {
+        use super::tfs_sample;
+        use candle_core::{Device, Tensor};
+
+        // A sharp head of three tokens and a flat tail.
+        let probs = [0.05f32, 0.4, 0.02, 0.3, 0.02, 0.18, 0.02, 0.01];
+        let logits = Tensor::new(&probs.map(f32::ln), &Device::Cpu).unwrap();
+
+        let kept = |z: f32| {
+            tfs_sample(&logits, z)
+                .unwrap()
+                .to_vec1::<f32>()
+                .unwrap()
+                .iter()
+                .enumerate()
+                .filter(|(_, x)| x.is_finite())
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(kept(0.8), vec![1, 3, 5]);
+        assert_eq!(kept(0.5), vec![1, 3]);
+        // A lower threshold cuts more, but never everything.
+        assert_eq!(kept(0.05), vec![1]);
+        // Out of range values disable it.
+        assert_eq!(kept(1.0).len(), probs.len());
+    }
+
+    #[test]
+    fn test_speculative_rejection_sample() {
+        use super::{Sampler, SamplerBackend};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+
+        let sampler = |backend| {
+            Sampler::new(
+                Some(1.0),
+                0,
+                None,
+                None,
+                None,
+                None,
+                None,
+                -1,
+                1.0,
+                0.0,
+                None,
+                backend,
+                None,
+                None,
+                vec![],
+            )
+            .unwrap()
+        };
+        let native = sampler(SamplerBackend::Native);
+        let mut rng = Isaac64Rng::seed_from_u64(42);
+
+        // Greedy decoding: one-hot distributions accept the draft tokens until they disagree with
+        // the target model, whose token replaces the first rejected one.
+        let one_hot = |token: usize| {
+            let mut probs = vec![0f32; 4];
+            probs[token] = 1.;
+            probs
+        };
+        let draft = [1, 2, 3];
+        let draft_probs = draft.map(|t| one_hot(t as usize));
+        let tokens = native
+            .speculative_rejection_sample(&draft, &draft_probs, &draft_probs, &mut rng)
+            .unwrap();
+        assert_eq!(tokens, draft);
+        let target_probs = [one_hot(1), one_hot(0), one_hot(3)];
+        let tokens = native
+            .speculative_rejection_sample(&draft, &draft_probs, &target_probs, &mut rng)
+            .unwrap();
+        assert_eq!(tokens, [1, 0]);
+
+        // Once every draft token is accepted, a bonus token is drawn from the next target
+        // distribution.
+        let target_probs = [one_hot(1), one_hot(2), one_hot(3), one_hot(0)];
+        let tokens = native
+            .speculative_rejection_sample(&draft, &draft_probs, &target_probs, &mut rng)
+            .unwrap();
+        assert_eq!(tokens, [1, 2, 3, 0]);
+        let target_probs = [one_hot(1), one_hot(0), one_hot(3), one_hot(0)];
+        let tokens = native
+            .speculative_rejection_sample(&draft, &draft_probs, &target_probs, &mut rng)
+            .unwrap();
+        assert_eq!(tokens, [1, 0]);
+
+        // The first token follows the target distribution, whatever the draft distribution and
+        // the backend.
+        let p = vec![0.5f32, 0.3, 0.2, 0.0];
+        let q = vec![0.1f32, 0.2, 0.3, 0.4];
+        for sampler in [native, sampler(SamplerBackend::GumbelMax)] {
+            let mut counts = [0usize; 4];
+            let trials = 20_000;
+            for _ in 0..trials {
+                let token = sampler
+                    .sample_distribution(&q, false, &mut rng)
+                    .unwrap()
+                    .token;
+                let tokens = sampler
+                    .speculative_rejection_sample(&[token], &[q.clone()], &[p.clone()], &mut rng)
+                    .unwrap();
+                counts[tokens[0] as usize] += 1;
+            }
+            assert_eq!(counts[3], 0);
+            for (count, p) in counts.iter().zip(&p) {
+                assert!(
+                    (*count as f32 / trials as f32 - p).abs() < 0.02,
+                    "{counts:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_distribution_draws_with_backend() {
+        use super::{Sampler, SamplerBackend};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+
+        let sampler = |top_p, backend| {
+            Sampler::new(
+                Some(1.0),
+                0,
+                None,
+                None,
+                None,
+                None,
+                None,
+                -1,
+                top_p,
+                0.0,
+                None,
+                backend,
+                None,
+                None,
+                vec![],
+            )
+            .unwrap()
+        };
+        let logits = Tensor::new(&[0.5f32, 2.0, 1.0, -1.0], &Device::Cpu).unwrap();
+
+        // A top-p of zero keeps no token, so the distribution falls back to the most likely one.
+        let probs = sampler(0.0, SamplerBackend::Native)
+            .distribution(logits.clone(), &[])
+            .unwrap()
+            .unwrap();
+        assert_eq!(probs, [0., 1., 0., 0.]);
+
+        // The tokens are drawn by the backend: with the same seed, the Gumbel-max draws are those
+        // of `gumbel_max_sample`, not those of the weighted index.
+        let gumbel = sampler(1.0, SamplerBackend::GumbelMax);
+        let probs = gumbel.distribution(logits, &[]).unwrap().unwrap();
+        let mut rng = Isaac64Rng::seed_from_u64(7);
+        let mut expected_rng = Isaac64Rng::seed_from_u64(7);
+        for _ in 0..64 {
+            let token = gumbel
+                .sample_distribution(&probs, false, &mut rng)
+                .unwrap()
+                .token;
+            let expected = super::gumbel_max_sample(&probs, &mut expected_rng).unwrap();
+            assert_eq!(token as usize, expected);
+        }
+    }
+
+    #[test]
+    fn test_repeat_last_n_per_request() {
+        use super::{Sampler, SamplerBackend};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::{Arc, Mutex};
+
+        let sampler = |repeat_last_n| {
+            Sampler::new(
+                None,
+                0,
+                None,
+                None,
+                Some(5.0),
+                repeat_last_n,
+                None,
+                -1,
+                1.0,
+                0.0,
+                None,
+                SamplerBackend::Native,
+                None,
+                None,
+                vec![],
+            )
+            .unwrap()
+        };
+        let logits = Tensor::new(&[3.0f32, 2.5, 2.0, 1.0], &Device::Cpu).unwrap();
+        let context = [0, 1, 3, 3];
+
+        // Two requests sampling at the same time, each with its own window.
+        let (short, long) = std::thread::scope(|s| {
+            let [short, long] = [Some(2), Some(4)].map(|repeat_last_n| {
+                let sampler = sampler(repeat_last_n);
+                let logits = logits.clone();
+                s.spawn(move || {
+                    let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(0)));
+                    sampler
+                        .sample(logits, &context, false, rng, false)
+                        .unwrap()
+                        .token
+                })
+            });
+            (short.join().unwrap(), long.join().unwrap())
+        });
+        // Tokens 0 and 1 are outside of the short window, so only the long one penalizes them.
+        assert_eq!(short, 0);
+        assert_eq!(long, 2);
+        // Without a window, the whole context is penalized.
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(0)));
+        let token = sampler(None)
+            .sample(logits, &context, false, rng, false)
+            .unwrap()
+            .token;
+        assert_eq!(token, 2);
+    }
+
+    #[test]
+    fn test_hybrid_decode() {
+        use super::{HybridConfig, Sampler, SamplerBackend};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::collections::HashSet;
+        use std::sync::{Arc, Mutex};
+
+        const TRIGGER: u32 = 7;
+        let sampler = Sampler::new(
+            Some(1.0),
+            0,
+            None,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            1.0,
+            0.0,
+            None,
+            SamplerBackend::Native,
+            Some(HybridConfig {
+                sample_after_tokens: vec![TRIGGER],
+            }),
+            None,
+            vec![],
+        )
+        .unwrap();
+        // Token 3 is the most likely, but not by much.
+        let logits =
+            Tensor::new(&[1.0f32, 1.2, 1.1, 1.5, 0.9, 1.3, 1.0, 1.4], &Device::Cpu).unwrap();
+
+        let tokens_after = |last: u32| {
+            (0..32)
+                .map(|seed| {
+                    let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(seed)));
+                    sampler
+                        .sample(logits.clone(), &[1, 2, last], false, rng, false)
+                        .unwrap()
+                        .token
+                })
+                .collect::<HashSet<_>>()
+        };
+        // Greedy whatever the seed, except right after the trigger token.
+        for last in [0, 3, 6] {
+            assert_eq!(tokens_after(last), HashSet::from([3]));
+        }
+        assert!(tokens_after(TRIGGER).len() > 1);
+    }
+
+    #[test]
+    fn test_adaptive_temperature() {
+        use super::{AdaptiveTemperature, Sampler, SamplerBackend};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::{Arc, Mutex};
+
+        let sampler = |entropy_target: f32| {
+            Sampler::new(
+                None,
+                0,
+                None,
+                None,
+                None,
+                None,
+                None,
+                -1,
+                1.0,
+                0.0,
+                None,
+                SamplerBackend::Native,
+                None,
+                Some(AdaptiveTemperature {
+                    base_temp: 1.0,
+                    entropy_target,
+                    learning_rate: 0.5,
+                }),
+                vec![],
+            )
+            .unwrap()
+        };
+        let logits = Tensor::new(&[4.0f32, 1.0, 0.5, 0.0], &Device::Cpu).unwrap();
+        let temperatures = |sampler: &Sampler| {
+            (0..5)
+                .map(|seed| {
+                    let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(seed)));
+                    sampler
+                        .sample(logits.clone(), &[1], false, rng, false)
+                        .unwrap();
+                    sampler.temperature_for(&[1]).unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // The distribution is peaked, so a high target raises the temperature up to its bound.
+        let rising = temperatures(&sampler(1.3));
+        assert!(rising.windows(2).all(|w| w[1] >= w[0]));
+        assert!(rising[0] > 1.0 && rising[4] <= 2.0);
+
+        // A low target lowers it.
+        let falling = temperatures(&sampler(0.01));
+        assert!(falling.windows(2).all(|w| w[1] <= w[0]));
+        assert!(falling[0] < 1.0 && falling[4] >= 0.05);
+
+        // Clones of a sampler, one per choice, adapt their temperatures separately.
+        let sampler = sampler(1.3);
+        let clone = sampler.clone();
+        temperatures(&sampler);
+        assert_eq!(clone.temperature_for(&[1]), Some(1.0));
+    }
 }
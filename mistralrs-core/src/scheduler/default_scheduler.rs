@@ -10,7 +10,7 @@ use crate::{
     sequence::{Sequence, SequenceState, StopReason},
 };
 
-use super::{Scheduler, SchedulerOutput};
+use super::{Scheduler, SchedulerLimits, SchedulerOutput};
 
 pub trait FcfsBacker: Default {
     fn new() -> Self;
@@ -175,6 +175,8 @@ pub struct DefaultScheduler<Backer: FcfsBacker> {
     running: Vec<Sequence>,
     method: DefaultSchedulerMethod,
     bucketing_manager: Box<dyn BucketingManager<Backer>>,
+    max_prefill_tokens: Option<usize>,
+    max_decode_seqs: Option<usize>,
 }
 
 impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
@@ -187,6 +189,8 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
             waiting: Backer::new(),
             method,
             bucketing_manager,
+            max_prefill_tokens: None,
+            max_decode_seqs: None,
         }
     }
 
@@ -221,17 +225,9 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
                 };
             }
             (_, 0) => {
-                for seq in waiting.into_iter() {
-                    seq.set_state(SequenceState::RunningPrompt);
-                    self.running.push(seq);
-                }
-                self.waiting = Backer::new();
-                let running = std::mem::take(&mut self.running);
+                self.waiting = self.admit_waiting(&mut running, waiting);
                 self.running = self.bucket_and_waitlist_seqs(running);
-                return DefaultSchedulerOutput {
-                    prompt: self.running.iter_mut().collect::<Vec<_>>().into(),
-                    completion: vec![].into(),
-                };
+                return self.output();
             }
             (0, _) => {
                 self.running = self.bucket_and_waitlist_seqs(running);
@@ -241,6 +237,7 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
                         .for_each(|seq| seq.set_state(SequenceState::Done(StopReason::Canceled)));
                     TERMINATE_ALL_NEXT_STEP.store(false, Ordering::SeqCst);
                 }
+                self.defer_excess_decodes();
                 return DefaultSchedulerOutput {
                     prompt: vec![].into(),
                     completion: self.running.iter_mut().collect::<Vec<_>>().into(),
@@ -252,18 +249,7 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
         // Sort the waiting seqs
         waiting.sort_ascending_ids();
 
-        // If the waiting sequence will fit, add it. Otherwise remove it
-        let mut new_waiting = Backer::new();
-        for seq in waiting.into_iter() {
-            if self.sequence_fits(&running, &seq) {
-                if seq.is_waiting() {
-                    seq.set_state(SequenceState::RunningPrompt);
-                }
-                running.push(seq);
-            } else {
-                new_waiting.add(seq);
-            }
-        }
+        let new_waiting = self.admit_waiting(&mut running, waiting);
 
         let BucketedSeqs {
             running,
@@ -274,7 +260,12 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
 
         self.running = running;
         self.waiting = new_waiting;
+        self.defer_excess_decodes();
 
+        self.output()
+    }
+
+    fn output(&mut self) -> DefaultSchedulerOutput {
         let mut completion = Vec::new();
         let mut prompt = Vec::new();
         for seq in &mut self.running {
@@ -291,6 +282,48 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
         }
     }
 
+    /// Move the waiting sequences which fit into `running`, within the prefill token budget,
+    /// returning the ones which must keep waiting.
+    fn admit_waiting(&self, running: &mut Vec<Sequence>, waiting: Backer) -> Backer {
+        let mut new_waiting = Backer::new();
+        let mut prefill_tokens = 0;
+        for seq in waiting.into_iter() {
+            let prompt_len = if seq.is_waiting() { seq.len() } else { 0 };
+            let within_budget = self
+                .max_prefill_tokens
+                .is_none_or(|max| prefill_tokens == 0 || prefill_tokens + prompt_len <= max);
+            if self.sequence_fits(running, &seq) && within_budget {
+                if seq.is_waiting() {
+                    seq.set_state(SequenceState::RunningPrompt);
+                }
+                prefill_tokens += prompt_len;
+                running.push(seq);
+            } else {
+                new_waiting.add(seq);
+            }
+        }
+        new_waiting
+    }
+
+    /// Decode at most `max_decode_seqs` sequences, moving the others to the waiting list. The
+    /// sequences which generated the fewest tokens are decoded first, so that none starve.
+    fn defer_excess_decodes(&mut self) {
+        let Some(max_decode_seqs) = self.max_decode_seqs else {
+            return;
+        };
+        let (mut completion, other): (Vec<_>, Vec<_>) = std::mem::take(&mut self.running)
+            .into_iter()
+            .partition(|seq| seq.is_completion());
+        if completion.len() > max_decode_seqs {
+            completion.sort_by_key(|seq| seq.get_toks().len().saturating_sub(seq.prompt_tokens()));
+            for seq in completion.split_off(max_decode_seqs) {
+                self.waiting.add(seq.add_urgency());
+            }
+        }
+        self.running = other;
+        self.running.extend(completion);
+    }
+
     fn sequence_fits(&self, running: &[Sequence], _seq: &Sequence) -> bool {
         match &self.method {
             DefaultSchedulerMethod::Fixed(n) => (running.len() + 1) <= (*n).into(),
@@ -310,6 +343,21 @@ impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
     fn running_len(&self) -> usize {
         self.running.len()
     }
+    fn prefill_queue_depth(&self) -> usize {
+        self.running
+            .iter()
+            .chain(self.waiting.iter())
+            .filter(|seq| seq.is_waiting() || seq.is_prompt())
+            .count()
+    }
+    fn decode_queue_depth(&self) -> usize {
+        // Deferred sequences are moved to the waiting list without changing their state.
+        self.running
+            .iter()
+            .chain(self.waiting.iter())
+            .filter(|seq| seq.is_completion())
+            .count()
+    }
     fn add_seq(&mut self, seq: Sequence) {
         if seq.is_running() {
             // prefill case
@@ -328,4 +376,151 @@ impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
     fn block_engine(&mut self) -> Option<&mut BlockEngine> {
         None
     }
+    fn limits(&self) -> SchedulerLimits {
+        let DefaultSchedulerMethod::Fixed(max_num_seqs) = self.method;
+        SchedulerLimits {
+            max_num_seqs: max_num_seqs.get(),
+            max_prefill_tokens: self.max_prefill_tokens,
+            max_decode_seqs: self.max_decode_seqs,
+        }
+    }
+    fn set_limits(&mut self, limits: SchedulerLimits, drain: bool) -> Result<(), String> {
+        limits.validate(self.running.len(), drain)?;
+        // Validated to be nonzero.
+        self.method = DefaultSchedulerMethod::Fixed(limits.max_num_seqs.try_into().unwrap());
+        self.max_prefill_tokens = limits.max_prefill_tokens;
+        self.max_decode_seqs = limits.max_decode_seqs;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{collections::VecDeque, num::NonZeroUsize, sync::Arc};
+
+    use crate::{
+        pipeline::new_dummy_seq,
+        sampler::{Logprobs, Sampler, SamplerBackend},
+        scheduler::{Scheduler, SchedulerLimits},
+        sequence::{Sequence, SequenceGroup, SequenceState},
+    };
+
+    use super::{DefaultScheduler, DefaultSchedulerMethod};
+
+    fn scheduler(limits: SchedulerLimits) -> DefaultScheduler<VecDeque<Sequence>> {
+        let mut scheduler =
+            DefaultScheduler::new(DefaultSchedulerMethod::Fixed(NonZeroUsize::new(1).unwrap()));
+        scheduler.set_limits(limits, false).unwrap();
+        scheduler
+    }
+
+    /// A waiting sequence with a prompt of `prompt_len` tokens, which already generated
+    /// `generated` tokens.
+    pub(crate) fn sequence(prompt_len: usize, generated: usize) -> Sequence {
+        let (sender, _) = tokio::sync::mpsc::channel(1);
+        let sampler = Sampler::new(
+            None,
+            0,
+            None,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            0.0,
+            0.0,
+            None,
+            SamplerBackend::Native,
+            None,
+            None,
+            vec![],
+        )
+        .unwrap();
+        let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
+            1, false, false, None, None,
+        )));
+        let tokens = (0..prompt_len as u32).collect();
+        let mut seq = new_dummy_seq(
+            (tokens, String::new()),
+            sender,
+            sampler,
+            group,
+            None,
+            vec![],
+        );
+        for token in 0..generated as u32 {
+            let logprobs = Logprobs {
+                token,
+                logprob: 0.,
+                bytes: None,
+                top_logprobs: None,
+            };
+            seq.add_token(logprobs, vec![], &None);
+        }
+        seq
+    }
+
+    #[test]
+    fn admission_limits() {
+        // Only the sequences whose prompts fit in the prefill token budget are admitted.
+        let mut s = scheduler(SchedulerLimits {
+            max_num_seqs: 8,
+            max_prefill_tokens: Some(10),
+            max_decode_seqs: None,
+        });
+        for _ in 0..3 {
+            s.add_seq(sequence(4, 0));
+        }
+        let output = s.schedule();
+        assert_eq!((output.prompt.len(), output.completion.len()), (2, 0));
+        assert_eq!(s.waiting_len(), 1);
+        assert_eq!(s.prefill_queue_depth(), 3);
+
+        // A prompt longer than the budget is still admitted alone.
+        let mut s = scheduler(SchedulerLimits {
+            max_num_seqs: 8,
+            max_prefill_tokens: Some(3),
+            max_decode_seqs: None,
+        });
+        s.add_seq(sequence(4, 0));
+        s.add_seq(sequence(4, 0));
+        assert_eq!(s.schedule().prompt.len(), 1);
+        assert_eq!(s.waiting_len(), 1);
+
+        // Without a budget, the number of running sequences is the limit.
+        let mut s = scheduler(SchedulerLimits::new(2));
+        for _ in 0..3 {
+            s.add_seq(sequence(4, 0));
+        }
+        assert_eq!(s.schedule().prompt.len(), 2);
+        assert_eq!(s.waiting_len(), 1);
+    }
+
+    #[test]
+    fn excess_decodes_are_deferred() {
+        let mut s = scheduler(SchedulerLimits {
+            max_num_seqs: 8,
+            max_prefill_tokens: None,
+            max_decode_seqs: Some(2),
+        });
+        // The same length, so that they are scheduled together, and different progress.
+        for (prompt_len, generated) in [(5, 5), (9, 1), (7, 3)] {
+            let seq = sequence(prompt_len, generated);
+            seq.set_state(SequenceState::RunningCompletion);
+            s.add_seq(seq);
+        }
+
+        let output = s.schedule();
+        assert!(output.prompt.is_empty());
+        let mut generated = output
+            .completion
+            .iter()
+            .map(|seq| seq.get_toks().len() - seq.prompt_tokens())
+            .collect::<Vec<_>>();
+        generated.sort();
+        // The sequence which generated the most tokens waits.
+        assert_eq!(generated, [1, 3]);
+        assert_eq!(s.waiting_len(), 1);
+        assert_eq!(s.decode_queue_depth(), 3);
+    }
 }
@@ -0,0 +1,300 @@
+use std::{collections::VecDeque, sync::atomic::Ordering};
+
+use crate::{
+    engine::TERMINATE_ALL_NEXT_STEP,
+    paged_attention::{BlockEngine, BlockTables},
+    sequence::{Sequence, SequenceState, StopReason},
+};
+
+use super::{DefaultSchedulerOutput, Scheduler, SchedulerLimits, SchedulerOutput};
+
+// Same as the buckets of the default scheduler: only sequences with the same length, image
+// prompt and token offset are stepped together.
+type BucketKey = (usize, bool, usize);
+
+fn bucket_key(seq: &Sequence) -> BucketKey {
+    (
+        seq.len(),
+        seq.images().is_some() && seq.is_prompt(),
+        seq.token_offset(),
+    )
+}
+
+/// Scheduler keeping the prefill and decode phases apart. New sequences enter the prefill queue
+/// and move to the decode queue once their prompt is processed. Each step runs one prefill batch
+/// of at most `prefill_seqs` sequences and one decode batch of at most `decode_seqs` sequences,
+/// so that long prompts do not take the slots of the decoding sequences and the other way
+/// around. When a phase has nothing to do, the other one steals its slots.
+///
+/// Both phases run on the pipeline of the engine, one after the other, and not on separate
+/// pipeline instances.
+pub struct DisaggregatedScheduler {
+    /// Sequences waiting for or processing their prompt.
+    prefill: VecDeque<Sequence>,
+    /// Sequences generating tokens.
+    decode: VecDeque<Sequence>,
+    /// Sequences of the last step, which the engine steps in place.
+    running: Vec<Sequence>,
+    prefill_seqs: usize,
+    decode_seqs: usize,
+    max_prefill_tokens: Option<usize>,
+}
+
+impl DisaggregatedScheduler {
+    pub fn new(prefill_seqs: usize, decode_seqs: usize) -> Self {
+        Self {
+            prefill: VecDeque::new(),
+            decode: VecDeque::new(),
+            running: Vec::new(),
+            prefill_seqs,
+            decode_seqs,
+            max_prefill_tokens: None,
+        }
+    }
+
+    /// Slots of the prefill and decode phases for the next step, once an idle phase gave its
+    /// slots to the other one.
+    fn slots(&self) -> (usize, usize) {
+        match (self.prefill.is_empty(), self.decode.is_empty()) {
+            (true, false) => (0, self.prefill_seqs + self.decode_seqs),
+            (false, true) => (self.prefill_seqs + self.decode_seqs, 0),
+            _ => (self.prefill_seqs, self.decode_seqs),
+        }
+    }
+
+    /// Schedule the prefill and decode batches of the next step.
+    pub fn schedule(&mut self) -> DefaultSchedulerOutput<'_> {
+        for seq in std::mem::take(&mut self.running) {
+            if seq.is_completion() {
+                self.decode.push_back(seq);
+            } else if seq.is_waiting() || seq.is_prompt() {
+                self.prefill.push_back(seq);
+            }
+        }
+
+        let (prefill_slots, decode_slots) = self.slots();
+        let decode = self.decode_batch(decode_slots);
+        if TERMINATE_ALL_NEXT_STEP.load(Ordering::SeqCst) {
+            decode
+                .iter()
+                .for_each(|seq| seq.set_state(SequenceState::Done(StopReason::Canceled)));
+            TERMINATE_ALL_NEXT_STEP.store(false, Ordering::SeqCst);
+        }
+        let prefill = self.prefill_batch(prefill_slots);
+        self.running = decode;
+        self.running.extend(prefill);
+
+        let (completion, prompt): (Vec<_>, Vec<_>) =
+            self.running.iter_mut().partition(|seq| seq.is_completion());
+        DefaultSchedulerOutput {
+            completion: completion.into(),
+            prompt: prompt.into(),
+        }
+    }
+
+    /// Take the decode batch: the sequences which generated the fewest tokens go first, so that
+    /// none starve, along with those of the same bucket.
+    fn decode_batch(&mut self, slots: usize) -> Vec<Sequence> {
+        self.decode
+            .make_contiguous()
+            .sort_by_key(|seq| seq.get_toks().len().saturating_sub(seq.prompt_tokens()));
+        let Some(key) = self.decode.front().map(bucket_key) else {
+            return Vec::new();
+        };
+        let mut batch = Vec::new();
+        for seq in std::mem::take(&mut self.decode) {
+            if batch.len() < slots && bucket_key(&seq) == key {
+                batch.push(seq);
+            } else {
+                self.decode.push_back(seq);
+            }
+        }
+        batch
+    }
+
+    /// Take the prefill batch in the order of arrival, within the prefill token budget. At
+    /// least one sequence is taken, even if its prompt is longer.
+    fn prefill_batch(&mut self, slots: usize) -> Vec<Sequence> {
+        self.prefill.make_contiguous().sort_by_key(|seq| *seq.id());
+        let Some(key) = self.prefill.front().map(bucket_key) else {
+            return Vec::new();
+        };
+        let mut batch = Vec::new();
+        let mut prefill_tokens = 0;
+        for seq in std::mem::take(&mut self.prefill) {
+            let within_budget = self
+                .max_prefill_tokens
+                .is_none_or(|max| prefill_tokens == 0 || prefill_tokens + seq.len() <= max);
+            if batch.len() < slots && bucket_key(&seq) == key && within_budget {
+                if seq.is_waiting() {
+                    seq.set_state(SequenceState::RunningPrompt);
+                }
+                prefill_tokens += seq.len();
+                batch.push(seq);
+            } else {
+                self.prefill.push_back(seq);
+            }
+        }
+        batch
+    }
+}
+
+impl Scheduler for DisaggregatedScheduler {
+    fn schedule(&mut self) -> SchedulerOutput<'_> {
+        SchedulerOutput::DefaultScheduler {
+            output: self.schedule(),
+        }
+    }
+    fn waiting_len(&self) -> usize {
+        self.prefill.len() + self.decode.len()
+    }
+    fn running_len(&self) -> usize {
+        self.running.len()
+    }
+    fn prefill_queue_depth(&self) -> usize {
+        self.prefill.len()
+            + self
+                .running
+                .iter()
+                .filter(|seq| seq.is_waiting() || seq.is_prompt())
+                .count()
+    }
+    fn decode_queue_depth(&self) -> usize {
+        self.decode.len()
+            + self
+                .running
+                .iter()
+                .filter(|seq| seq.is_completion())
+                .count()
+    }
+    fn add_seq(&mut self, seq: Sequence) {
+        if seq.is_completion() {
+            self.decode.push_back(seq);
+        } else {
+            self.prefill.push_back(seq);
+        }
+    }
+    fn block_tables(&self) -> Option<&BlockTables> {
+        None
+    }
+    fn block_size(&self) -> Option<usize> {
+        None
+    }
+    fn free_finished_sequence_groups(&mut self) {}
+    fn block_engine(&mut self) -> Option<&mut BlockEngine> {
+        None
+    }
+    /// `max_num_seqs` is the number of slots of both phases, and `max_decode_seqs` those of the
+    /// decode phase.
+    fn limits(&self) -> SchedulerLimits {
+        SchedulerLimits {
+            max_num_seqs: self.prefill_seqs + self.decode_seqs,
+            max_prefill_tokens: self.max_prefill_tokens,
+            max_decode_seqs: Some(self.decode_seqs),
+        }
+    }
+    fn set_limits(&mut self, limits: SchedulerLimits, drain: bool) -> Result<(), String> {
+        limits.validate(self.running.len(), drain)?;
+        let decode_seqs = limits.max_decode_seqs.unwrap_or(self.decode_seqs);
+        if decode_seqs >= limits.max_num_seqs {
+            return Err(format!(
+                "`max_decode_seqs` of {decode_seqs} leaves no slot to the prefill phase out of the {} of `max_num_seqs`.",
+                limits.max_num_seqs
+            ));
+        }
+        self.prefill_seqs = limits.max_num_seqs - decode_seqs;
+        self.decode_seqs = decode_seqs;
+        self.max_prefill_tokens = limits.max_prefill_tokens;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        scheduler::{default_scheduler::tests::sequence, Scheduler, SchedulerLimits},
+        sequence::{Sequence, SequenceState},
+    };
+
+    use super::DisaggregatedScheduler;
+
+    fn decoding(prompt_len: usize, generated: usize) -> Sequence {
+        let seq = sequence(prompt_len, generated);
+        seq.set_state(SequenceState::RunningCompletion);
+        seq
+    }
+
+    #[test]
+    fn phases_have_their_own_slots() {
+        let mut s = DisaggregatedScheduler::new(1, 2);
+        for _ in 0..3 {
+            s.add_seq(sequence(4, 0));
+            s.add_seq(decoding(4, 4));
+        }
+        let output = s.schedule();
+        assert_eq!((output.prompt.len(), output.completion.len()), (1, 2));
+        assert_eq!((s.prefill_queue_depth(), s.decode_queue_depth()), (3, 3));
+
+        // The processed prompt moves to the decode phase.
+        s.running
+            .iter()
+            .filter(|seq| seq.is_prompt())
+            .for_each(|seq| seq.set_state(SequenceState::RunningCompletion));
+        assert_eq!(s.schedule().prompt.len(), 1);
+        assert_eq!((s.prefill_queue_depth(), s.decode_queue_depth()), (2, 4));
+    }
+
+    #[test]
+    fn idle_phase_slots_are_stolen() {
+        let mut s = DisaggregatedScheduler::new(1, 2);
+        for _ in 0..4 {
+            s.add_seq(decoding(4, 4));
+        }
+        assert_eq!(s.schedule().completion.len(), 3);
+
+        let mut s = DisaggregatedScheduler::new(1, 2);
+        for _ in 0..4 {
+            s.add_seq(sequence(4, 0));
+        }
+        assert_eq!(s.schedule().prompt.len(), 3);
+    }
+
+    #[test]
+    fn fewest_generated_tokens_decode_first() {
+        let mut s = DisaggregatedScheduler::new(1, 1);
+        s.add_seq(sequence(4, 0));
+        // The same length, so that they are in the same bucket.
+        for (prompt_len, generated) in [(5, 5), (9, 1), (7, 3)] {
+            s.add_seq(decoding(prompt_len, generated));
+        }
+        let output = s.schedule();
+        let generated = output
+            .completion
+            .iter()
+            .map(|seq| seq.get_toks().len() - seq.prompt_tokens())
+            .collect::<Vec<_>>();
+        assert_eq!(generated, [1]);
+    }
+
+    #[test]
+    fn limits_split_the_slots() {
+        let mut s = DisaggregatedScheduler::new(1, 2);
+        let limits = SchedulerLimits {
+            max_num_seqs: 8,
+            max_prefill_tokens: Some(16),
+            max_decode_seqs: Some(6),
+        };
+        s.set_limits(limits, false).unwrap();
+        assert_eq!((s.prefill_seqs, s.decode_seqs), (2, 6));
+        assert_eq!(s.limits(), limits);
+        assert!(s
+            .set_limits(
+                SchedulerLimits {
+                    max_decode_seqs: Some(8),
+                    ..limits
+                },
+                false
+            )
+            .is_err());
+    }
+}
@@ -0,0 +1,334 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use candle_core::{DType, Device, Result, Tensor};
+use indexmap::IndexMap;
+use mistralrs_quant::{ShardedSafeTensors, ShardedVarBuilder};
+use serde::Serialize;
+
+use crate::{
+    engine::{EngineEvent, EngineEventBus},
+    lora::LoraConfig,
+    utils::varbuilder_utils::load_adapter_tensors,
+};
+
+/// The loaded weights of one LoRA adapter.
+pub struct AdapterWeights {
+    pub name: String,
+    pub path: PathBuf,
+    pub config: LoraConfig,
+    tensors: HashMap<String, Tensor>,
+}
+
+impl AdapterWeights {
+    pub fn tensors(&self) -> &HashMap<String, Tensor> {
+        &self.tensors
+    }
+
+    /// Build a var builder over the cached tensors. The tensors are reference counted, so this does not copy.
+    pub fn var_builder(&self, dtype: DType, device: &Device) -> ShardedVarBuilder {
+        ShardedSafeTensors::wrap(Box::new(self.tensors.clone()), dtype, device.clone())
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LoraAdapterCacheStats {
+    pub max_adapters: usize,
+    pub cached_adapters: Vec<String>,
+    pub hits: usize,
+    pub misses: usize,
+    pub hit_rate: f64,
+}
+
+static LORA_ADAPTER_CACHE: LazyLock<Arc<Mutex<LoraAdapterCache>>> =
+    LazyLock::new(|| Arc::new(Mutex::new(LoraAdapterCache::new(0))));
+
+/// LRU cache of LoRA adapter weights so that hot-swapping between adapters can avoid reloading from disk.
+pub struct LoraAdapterCache {
+    max_adapters: usize,
+    /// Ordered from least to most recently used.
+    cache: IndexMap<String, Arc<AdapterWeights>>,
+    hits: usize,
+    misses: usize,
+    events: Option<EngineEventBus>,
+    /// Adapters loaded before an event bus was attached, reported once one is.
+    pending_events: Vec<String>,
+}
+
+impl LoraAdapterCache {
+    pub fn new(max_adapters: usize) -> Self {
+        Self {
+            max_adapters,
+            cache: IndexMap::new(),
+            hits: 0,
+            misses: 0,
+            events: None,
+            pending_events: Vec::new(),
+        }
+    }
+
+    /// The cache which the pipelines load their preloaded adapters through. It keeps no adapters
+    /// until it is resized with [`LoraAdapterCache::set_max_adapters`].
+    pub fn global() -> Arc<Mutex<LoraAdapterCache>> {
+        LORA_ADAPTER_CACHE.clone()
+    }
+
+    /// Emit [`EngineEvent::AdapterLoaded`] on `events` when an adapter is loaded from disk.
+    pub fn with_events(mut self, events: EngineEventBus) -> Self {
+        self.set_events(events);
+        self
+    }
+
+    /// Emit [`EngineEvent::AdapterLoaded`] on `events` when an adapter is loaded from disk. Adapters which
+    /// were loaded before are reported now.
+    pub fn set_events(&mut self, events: EngineEventBus) {
+        for name in self.pending_events.drain(..) {
+            events.emit(EngineEvent::AdapterLoaded { name });
+        }
+        self.events = Some(events);
+    }
+
+    /// Change the number of adapters kept, evicting the least recently used ones which no longer fit.
+    pub fn set_max_adapters(&mut self, max_adapters: usize) {
+        self.max_adapters = max_adapters;
+        while self.cache.len() > max_adapters {
+            if let Some((evicted, _)) = self.cache.shift_remove_index(0) {
+                tracing::debug!("Evicting LoRA adapter `{evicted}` from the adapter cache.");
+            }
+        }
+    }
+
+    fn load(
+        &mut self,
+        name: &str,
+        path: &PathBuf,
+        config: &LoraConfig,
+        dtype: DType,
+        device: &Device,
+        silent: bool,
+    ) -> Result<Arc<AdapterWeights>> {
+        let weights = Arc::new(AdapterWeights {
+            name: name.to_string(),
+            path: path.clone(),
+            config: config.clone(),
+            tensors: load_adapter_tensors(path, dtype, device, silent)?,
+        });
+        match &self.events {
+            Some(events) => events.emit(EngineEvent::AdapterLoaded {
+                name: name.to_string(),
+            }),
+            None => self.pending_events.push(name.to_string()),
+        }
+        Ok(weights)
+    }
+
+    /// Get a cached adapter, marking it as most recently used. This counts as a hit or miss.
+    pub fn get(&mut self, name: &str) -> Option<Arc<AdapterWeights>> {
+        match self.cache.shift_remove(name) {
+            Some(weights) => {
+                self.hits += 1;
+                self.cache.insert(name.to_string(), weights.clone());
+                Some(weights)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Insert an adapter, evicting the least recently used one if the cache is full.
+    pub fn insert(&mut self, weights: Arc<AdapterWeights>) {
+        if self.max_adapters == 0 {
+            return;
+        }
+        self.cache.shift_remove(&weights.name);
+        while self.cache.len() >= self.max_adapters {
+            if let Some((evicted, _)) = self.cache.shift_remove_index(0) {
+                tracing::debug!("Evicting LoRA adapter `{evicted}` from the adapter cache.");
+            }
+        }
+        self.cache.insert(weights.name.clone(), weights);
+    }
+
+    /// Get the adapter from the cache, or load it from `path` and cache it. A cached adapter of the same name
+    /// which was loaded from another path is replaced.
+    pub fn get_or_load(
+        &mut self,
+        name: &str,
+        path: &PathBuf,
+        config: &LoraConfig,
+        dtype: DType,
+        device: &Device,
+        silent: bool,
+    ) -> Result<Arc<AdapterWeights>> {
+        if let Some(weights) = self.get(name) {
+            if weights.path == *path {
+                return Ok(weights);
+            }
+            self.hits -= 1;
+            self.misses += 1;
+        }
+        let weights = self.load(name, path, config, dtype, device, silent)?;
+        self.insert(weights.clone());
+        Ok(weights)
+    }
+
+    /// Load adapters into the cache ahead of time. This does not affect the hit rate.
+    pub fn preload(
+        &mut self,
+        adapters: &HashMap<String, (PathBuf, LoraConfig)>,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<()> {
+        for (name, (path, config)) in adapters {
+            if self.cache.get(name).is_some_and(|w| w.path == *path) {
+                continue;
+            }
+            let weights = self.load(name, path, config, dtype, device, true)?;
+            self.insert(weights);
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> LoraAdapterCacheStats {
+        let total = self.hits + self.misses;
+        #[allow(clippy::cast_precision_loss)]
+        let hit_rate = if total == 0 {
+            0.
+        } else {
+            self.hits as f64 / total as f64
+        };
+        LoraAdapterCacheStats {
+            max_adapters: self.max_adapters,
+            cached_adapters: self.cache.keys().cloned().collect(),
+            hits: self.hits,
+            misses: self.misses,
+            hit_rate,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use candle_core::{DType, Device, Tensor};
+
+    use super::LoraAdapterCache;
+    use crate::{
+        engine::{EngineEvent, EngineEventBus},
+        lora::LoraConfig,
+    };
+
+    fn write_adapters(names: &[&str]) -> candle_core::Result<(PathBuf, LoraConfig)> {
+        let dir = std::env::temp_dir().join(format!(
+            "mistralrs_lora_adapter_cache_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir)?;
+        for name in names {
+            let tensors = HashMap::from([(
+                "layers.0.q_proj.lora_A.weight".to_string(),
+                Tensor::ones((2, 4), DType::F32, &Device::Cpu)?,
+            )]);
+            candle_core::safetensors::save(&tensors, dir.join(format!("{name}.safetensors")))?;
+        }
+        let config =
+            serde_json::from_str(r#"{"r": 2, "lora_alpha": 4, "target_modules": ["q_proj"]}"#)
+                .unwrap();
+        Ok((dir, config))
+    }
+
+    #[test]
+    fn hits_misses_and_eviction() -> candle_core::Result<()> {
+        let (dir, config) = write_adapters(&["a", "b", "c"])?;
+        let path = |name: &str| dir.join(format!("{name}.safetensors"));
+        let dev = Device::Cpu;
+        let mut cache = LoraAdapterCache::new(2);
+
+        let a = cache.get_or_load("a", &path("a"), &config, DType::F32, &dev, true)?;
+        assert_eq!(a.tensors().len(), 1);
+        cache.get_or_load("b", &path("b"), &config, DType::F32, &dev, true)?;
+        // `a` is cached, and becomes the most recently used.
+        let a_again = cache.get_or_load("a", &path("a"), &config, DType::F32, &dev, true)?;
+        assert!(std::sync::Arc::ptr_eq(&a, &a_again));
+        // Loading `c` evicts the least recently used adapter, `b`.
+        cache.get_or_load("c", &path("c"), &config, DType::F32, &dev, true)?;
+
+        let stats = cache.stats();
+        assert_eq!(
+            stats.cached_adapters,
+            vec!["a".to_string(), "c".to_string()]
+        );
+        assert_eq!((stats.hits, stats.misses), (1, 3));
+        assert_eq!(stats.hit_rate, 0.25);
+
+        cache.get_or_load("b", &path("b"), &config, DType::F32, &dev, true)?;
+        assert_eq!(cache.stats().misses, 4);
+        assert_eq!(
+            cache.stats().cached_adapters,
+            vec!["c".to_string(), "b".to_string()]
+        );
+
+        cache.set_max_adapters(1);
+        assert_eq!(cache.stats().cached_adapters, vec!["b".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn adapter_of_another_path_is_reloaded() -> candle_core::Result<()> {
+        let (dir, config) = write_adapters(&["d", "e"])?;
+        let dev = Device::Cpu;
+        let mut cache = LoraAdapterCache::new(2);
+
+        let d = cache.get_or_load(
+            "adapter",
+            &dir.join("d.safetensors"),
+            &config,
+            DType::F32,
+            &dev,
+            true,
+        )?;
+        let e = cache.get_or_load(
+            "adapter",
+            &dir.join("e.safetensors"),
+            &config,
+            DType::F32,
+            &dev,
+            true,
+        )?;
+        assert!(!std::sync::Arc::ptr_eq(&d, &e));
+        assert_eq!(e.path, dir.join("e.safetensors"));
+        assert_eq!((cache.stats().hits, cache.stats().misses), (0, 2));
+        Ok(())
+    }
+
+    #[test]
+    fn preload_does_not_count_and_reports_loads() -> candle_core::Result<()> {
+        let (dir, config) = write_adapters(&["f", "g"])?;
+        let adapters = HashMap::from([
+            ("f".to_string(), (dir.join("f.safetensors"), config.clone())),
+            ("g".to_string(), (dir.join("g.safetensors"), config.clone())),
+        ]);
+        let mut cache = LoraAdapterCache::new(2);
+        cache.preload(&adapters, DType::F32, &Device::Cpu)?;
+        assert_eq!((cache.stats().hits, cache.stats().misses), (0, 0));
+        assert_eq!(cache.stats().cached_adapters.len(), 2);
+
+        // The loads happened before the event bus was attached.
+        let events = EngineEventBus::default();
+        let mut subscriber = events.subscribe();
+        cache.set_events(events);
+        let mut loaded = Vec::new();
+        while let Some(EngineEvent::AdapterLoaded { name }) = subscriber.try_recv() {
+            loaded.push(name);
+        }
+        loaded.sort();
+        assert_eq!(loaded, vec!["f".to_string(), "g".to_string()]);
+        Ok(())
+    }
+}
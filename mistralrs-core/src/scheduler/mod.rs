@@ -1,8 +1,13 @@
 mod default_scheduler;
+mod disaggregated_scheduler;
+mod lora_adapter_cache;
 
-use std::sync::Arc;
+use std::{num::NonZeroUsize, sync::Arc};
 
 pub use default_scheduler::{DefaultScheduler, DefaultSchedulerMethod, DefaultSchedulerOutput};
+pub use disaggregated_scheduler::DisaggregatedScheduler;
+pub use lora_adapter_cache::{AdapterWeights, LoraAdapterCache, LoraAdapterCacheStats};
+use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 
 use crate::{
@@ -22,6 +27,12 @@ pub enum SchedulerConfig {
         max_num_seqs: usize,
         config: CacheConfig,
     },
+    /// Run the prefill and decode phases as separate batches with their own slots. A phase
+    /// which has nothing to do gives its slots to the other one.
+    Disaggregated {
+        prefill_seqs: NonZeroUsize,
+        decode_seqs: NonZeroUsize,
+    },
 }
 
 impl SchedulerConfig {
@@ -34,13 +45,82 @@ impl SchedulerConfig {
                 max_num_seqs,
                 config,
             } => Arc::new(Mutex::new(PagedAttentionScheduler::new(
-                PagedAttentionSchedulerConfig { max_num_seqs },
+                PagedAttentionSchedulerConfig {
+                    max_num_seqs,
+                    max_prefill_tokens: None,
+                },
                 config,
             ))),
+            Self::Disaggregated {
+                prefill_seqs,
+                decode_seqs,
+            } => Arc::new(Mutex::new(DisaggregatedScheduler::new(
+                prefill_seqs.get(),
+                decode_seqs.get(),
+            ))),
         }
     }
 }
 
+/// Limits of the scheduler, which can be changed while the engine runs. Changes take effect at
+/// the next scheduling step.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SchedulerLimits {
+    /// Maximum number of running sequences.
+    pub max_num_seqs: usize,
+    /// Maximum number of prompt tokens of the sequences admitted in one scheduling step. At
+    /// least one sequence is always admitted, even if its prompt is longer.
+    pub max_prefill_tokens: Option<usize>,
+    /// Maximum number of sequences decoded in one step. The others are deferred, and those
+    /// which generated the fewest tokens are decoded first. Not supported with PagedAttention.
+    pub max_decode_seqs: Option<usize>,
+}
+
+impl SchedulerLimits {
+    pub fn new(max_num_seqs: usize) -> Self {
+        Self {
+            max_num_seqs,
+            max_prefill_tokens: None,
+            max_decode_seqs: None,
+        }
+    }
+
+    /// Check the limits given the number of currently running sequences. Lowering
+    /// `max_num_seqs` below that number requires `drain`: no sequences are then admitted until
+    /// enough of the running ones finish.
+    pub(crate) fn validate(&self, running: usize, drain: bool) -> Result<(), String> {
+        if self.max_num_seqs == 0 {
+            return Err("`max_num_seqs` must be at least 1.".to_string());
+        }
+        if self.max_prefill_tokens == Some(0) {
+            return Err("`max_prefill_tokens` must be at least 1.".to_string());
+        }
+        if self.max_decode_seqs == Some(0) {
+            return Err("`max_decode_seqs` must be at least 1.".to_string());
+        }
+        if self.max_num_seqs < running && !drain {
+            return Err(format!(
+                "{running} sequences are running, more than the new `max_num_seqs` of {}. Set `drain` to stop admitting sequences until enough of them finish.",
+                self.max_num_seqs
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Load of the prefill and decode phases. The prefill queue holds the sequences waiting for or
+/// processing their prompt, and the decode queue those generating tokens, including the ones
+/// deferred or swapped out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct SchedulerMetrics {
+    pub prefill_queue_depth: usize,
+    pub decode_queue_depth: usize,
+    /// Moving average of the prompt throughput, in tokens per second.
+    pub prefill_throughput_tps: f64,
+    /// Moving average of the decode throughput of a single sequence, in tokens per second.
+    pub decode_throughput_tps: f64,
+}
+
 pub enum SchedulerOutput<'a> {
     DefaultScheduler {
         output: DefaultSchedulerOutput<'a>,
@@ -54,6 +134,10 @@ pub trait Scheduler: Send + Sync {
     fn schedule(&mut self) -> SchedulerOutput<'_>;
     fn waiting_len(&self) -> usize;
     fn running_len(&self) -> usize;
+    /// Number of sequences waiting for or processing their prompt.
+    fn prefill_queue_depth(&self) -> usize;
+    /// Number of sequences which processed their prompt and are generating tokens.
+    fn decode_queue_depth(&self) -> usize;
     fn add_seq(&mut self, seq: Sequence);
     /// This may do nothing. It depends on the implementation
     fn free_finished_sequence_groups(&mut self);
@@ -62,4 +146,40 @@ pub trait Scheduler: Send + Sync {
     fn block_tables(&self) -> Option<&BlockTables>;
     fn block_size(&self) -> Option<usize>;
     fn block_engine(&mut self) -> Option<&mut BlockEngine>;
+
+    fn limits(&self) -> SchedulerLimits;
+    /// Validate and apply new limits, see [`SchedulerLimits::validate`].
+    fn set_limits(&mut self, limits: SchedulerLimits, drain: bool) -> Result<(), String>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SchedulerLimits;
+
+    #[test]
+    fn limits_validation() {
+        let limits = SchedulerLimits {
+            max_num_seqs: 4,
+            max_prefill_tokens: Some(2048),
+            max_decode_seqs: Some(2),
+        };
+        assert!(limits.validate(4, false).is_ok());
+        // Going below the running sequences requires draining.
+        assert!(limits.validate(6, false).is_err());
+        assert!(limits.validate(6, true).is_ok());
+
+        for invalid in [
+            SchedulerLimits::new(0),
+            SchedulerLimits {
+                max_prefill_tokens: Some(0),
+                ..limits
+            },
+            SchedulerLimits {
+                max_decode_seqs: Some(0),
+                ..limits
+            },
+        ] {
+            assert!(invalid.validate(0, true).is_err());
+        }
+    }
 }
@@ -0,0 +1,277 @@
+use anyhow::Result;
+use either::Either;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{DocumentUsage, MessageContent};
+
+/// Placeholder of [`DocumentPacking::section_format`] replaced by the packed documents.
+pub const DOCUMENTS_PLACEHOLDER: &str = "{documents}";
+
+const DEFAULT_SECTION_FORMAT: &str = "Answer using the following documents. If they do not contain the answer, say so.\n\n{documents}";
+
+/// A chunk retrieved by the caller, given with a request so that the engine packs it into the
+/// prompt.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RetrievedDocument {
+    pub text: String,
+    /// Relevance of the document to the request, higher is more relevant.
+    pub score: f32,
+    /// Rendered before the text of the document, such as its title or source.
+    #[serde(default)]
+    pub metadata: IndexMap<String, String>,
+}
+
+/// How the documents of a request are packed into the prompt.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DocumentPacking {
+    /// Documents longer than this are truncated to their first tokens.
+    #[serde(default)]
+    pub max_tokens_per_document: Option<usize>,
+    /// Documents section added to the system message, with the documents in place of
+    /// `{documents}`. Unused if the chat template renders the documents itself.
+    #[serde(default = "default_section_format")]
+    pub section_format: String,
+}
+
+fn default_section_format() -> String {
+    DEFAULT_SECTION_FORMAT.to_string()
+}
+
+impl Default for DocumentPacking {
+    fn default() -> Self {
+        Self {
+            max_tokens_per_document: None,
+            section_format: default_section_format(),
+        }
+    }
+}
+
+/// Documents of a chat request. They are packed greedily, by decreasing score, into the context
+/// left after the messages and the generated tokens, stopping at the first document which does
+/// not fit.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RequestDocuments {
+    pub documents: Vec<RetrievedDocument>,
+    #[serde(default)]
+    pub packing: DocumentPacking,
+}
+
+/// A document which fits in the prompt.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct PackedDocument {
+    pub usage: DocumentUsage,
+    pub text: String,
+    pub metadata: IndexMap<String, String>,
+}
+
+/// Select the documents which fit in `budget` tokens, in decreasing order of score.
+/// `token_ends` gives the end offset of each token of a text, and the header of a document counts
+/// against the budget but not against `max_tokens_per_document`.
+pub(crate) fn pack_documents(
+    documents: Vec<RetrievedDocument>,
+    packing: &DocumentPacking,
+    mut budget: usize,
+    mut token_ends: impl FnMut(&str) -> Result<Vec<usize>>,
+) -> Result<Vec<PackedDocument>> {
+    let mut documents = documents.into_iter().enumerate().collect::<Vec<_>>();
+    documents.sort_by(|(_, a), (_, b)| b.score.total_cmp(&a.score));
+
+    let mut packed = Vec::new();
+    for (index, document) in documents {
+        let header_tokens = token_ends(&document_header(packed.len(), &document.metadata))?.len();
+        let ends = token_ends(&document.text)?;
+        let (text, tokens, truncated) = match packing.max_tokens_per_document {
+            Some(max) if ends.len() > max => {
+                let mut end = if max == 0 { 0 } else { ends[max - 1] };
+                // Tokens may end inside a character.
+                while !document.text.is_char_boundary(end) {
+                    end -= 1;
+                }
+                (document.text[..end].to_string(), max, true)
+            }
+            _ => (document.text, ends.len(), false),
+        };
+        let Some(remaining) = budget.checked_sub(header_tokens + tokens) else {
+            break;
+        };
+        budget = remaining;
+        packed.push(PackedDocument {
+            usage: DocumentUsage {
+                index,
+                tokens,
+                truncated,
+            },
+            text,
+            metadata: document.metadata,
+        });
+    }
+    Ok(packed)
+}
+
+fn document_header(position: usize, metadata: &IndexMap<String, String>) -> String {
+    let mut header = format!("[Document {}]\n", position + 1);
+    for (key, value) in metadata {
+        header.push_str(&format!("{key}: {value}\n"));
+    }
+    header
+}
+
+/// Render the documents section, for chat templates without a documents slot.
+pub(crate) fn render_documents_section(
+    packing: &DocumentPacking,
+    packed: &[PackedDocument],
+) -> String {
+    let documents = packed
+        .iter()
+        .enumerate()
+        .map(|(i, document)| {
+            format!(
+                "{}{}",
+                document_header(i, &document.metadata),
+                document.text
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    packing
+        .section_format
+        .replace(DOCUMENTS_PLACEHOLDER, &documents)
+}
+
+/// The `documents` variable of chat templates with a documents slot, such as Command R: the
+/// metadata and the text of each document.
+pub(crate) fn template_documents(packed: &[PackedDocument]) -> Vec<IndexMap<String, String>> {
+    packed
+        .iter()
+        .map(|document| {
+            let mut map = document.metadata.clone();
+            map.insert("text".to_string(), document.text.clone());
+            map
+        })
+        .collect()
+}
+
+/// Add the documents section to the system message, or to the first user message if the system
+/// messages were already folded into it.
+pub(crate) fn add_documents_section(
+    mut messages: Vec<IndexMap<String, MessageContent>>,
+    section: String,
+) -> Vec<IndexMap<String, MessageContent>> {
+    let target = messages.iter().position(|message| {
+        matches!(message.get("role"), Some(Either::Left(role)) if role == "system")
+            && matches!(message.get("content"), Some(Either::Left(_)))
+    });
+    let target = target.or_else(|| {
+        messages.iter().position(|message| {
+            matches!(message.get("role"), Some(Either::Left(role)) if role == "user")
+                && matches!(message.get("content"), Some(Either::Left(_)))
+        })
+    });
+    match target {
+        Some(i) => {
+            if let Some(Either::Left(content)) = messages[i].get_mut("content") {
+                *content = format!("{section}\n\n{content}");
+            }
+        }
+        None => messages.insert(
+            0,
+            IndexMap::from([
+                ("role".to_string(), Either::Left("system".to_string())),
+                ("content".to_string(), Either::Left(section)),
+            ]),
+        ),
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::IndexMap;
+
+    use super::{pack_documents, render_documents_section, DocumentPacking, RetrievedDocument};
+
+    /// One token per word.
+    fn word_ends(text: &str) -> anyhow::Result<Vec<usize>> {
+        let mut ends = Vec::new();
+        let mut in_word = false;
+        for (i, c) in text.char_indices() {
+            if c.is_whitespace() {
+                if in_word {
+                    ends.push(i);
+                }
+                in_word = false;
+            } else {
+                in_word = true;
+            }
+        }
+        if in_word {
+            ends.push(text.len());
+        }
+        Ok(ends)
+    }
+
+    fn document(text: &str, score: f32) -> RetrievedDocument {
+        RetrievedDocument {
+            text: text.to_string(),
+            score,
+            metadata: IndexMap::new(),
+        }
+    }
+
+    #[test]
+    fn packs_by_decreasing_score_until_budget() {
+        let documents = vec![
+            document("low score document", 0.1),
+            document("best", 0.9),
+            document("second best document", 0.5),
+        ];
+        // Each header `[Document n]` is 2 tokens.
+        let packed = pack_documents(documents, &DocumentPacking::default(), 8, word_ends).unwrap();
+        let included = packed.iter().map(|d| d.usage.index).collect::<Vec<_>>();
+        assert_eq!(included, vec![1, 2]);
+        assert_eq!(packed[0].usage.tokens, 1);
+        assert_eq!(packed[1].usage.tokens, 3);
+    }
+
+    #[test]
+    fn stops_at_first_document_which_does_not_fit() {
+        let documents = vec![document("a b c d e f", 0.9), document("short", 0.5)];
+        let packed = pack_documents(documents, &DocumentPacking::default(), 4, word_ends).unwrap();
+        assert!(packed.is_empty());
+    }
+
+    #[test]
+    fn truncates_long_documents() {
+        let packing = DocumentPacking {
+            max_tokens_per_document: Some(2),
+            ..Default::default()
+        };
+        let packed = pack_documents(
+            vec![document("one two three", 1.)],
+            &packing,
+            100,
+            word_ends,
+        )
+        .unwrap();
+        assert_eq!(packed[0].text, "one two");
+        assert_eq!(packed[0].usage.tokens, 2);
+        assert!(packed[0].usage.truncated);
+    }
+
+    #[test]
+    fn renders_section_with_metadata() {
+        let mut doc = document("Paris is the capital.", 1.);
+        doc.metadata
+            .insert("title".to_string(), "France".to_string());
+        let packing = DocumentPacking {
+            max_tokens_per_document: None,
+            section_format: "Context:\n{documents}".to_string(),
+        };
+        let packed = pack_documents(vec![doc], &packing, 100, word_ends).unwrap();
+        assert_eq!(
+            render_documents_section(&packing, &packed),
+            "Context:\n[Document 1]\ntitle: France\nParis is the capital."
+        );
+    }
+}
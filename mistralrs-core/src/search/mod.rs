@@ -1,6 +1,11 @@
 use std::collections::HashMap;
 
+pub(crate) mod documents;
 pub mod rag;
+mod vector_store;
+
+pub use documents::{DocumentPacking, RequestDocuments, RetrievedDocument};
+pub use vector_store::{Document, InMemoryVectorStore, VectorStore};
 
 use anyhow::Result;
 use html2text::{config, render::PlainDecorator};
@@ -0,0 +1,146 @@
+use std::cmp::Ordering;
+
+use anyhow::Result;
+
+/// A document retrieved from a [`VectorStore`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Document {
+    pub id: String,
+    pub text: String,
+    /// Distance between the document embedding and the query embedding, lower is more similar.
+    pub distance: f32,
+}
+
+/// Storage of document embeddings with nearest-neighbor search.
+pub trait VectorStore: Send + Sync {
+    /// Insert a document. A document with the same `id` is replaced.
+    fn insert(&mut self, id: String, embedding: Vec<f32>, text: String) -> Result<()>;
+
+    /// Get the `top_k` documents nearest to the query, in increasing order of distance.
+    fn search(&self, query_embedding: &[f32], top_k: usize) -> Result<Vec<Document>>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+struct Entry {
+    id: String,
+    embedding: Vec<f32>,
+    text: String,
+}
+
+/// A [`VectorStore`] which keeps all embeddings in memory and searches them exhaustively by L2
+/// distance.
+#[derive(Default)]
+pub struct InMemoryVectorStore {
+    entries: Vec<Entry>,
+}
+
+impl InMemoryVectorStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+fn l2_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b)
+        .map(|(x, y)| (x - y) * (x - y))
+        .sum::<f32>()
+        .sqrt()
+}
+
+impl VectorStore for InMemoryVectorStore {
+    fn insert(&mut self, id: String, embedding: Vec<f32>, text: String) -> Result<()> {
+        if let Some(first) = self.entries.first() {
+            anyhow::ensure!(
+                first.embedding.len() == embedding.len(),
+                "Expected an embedding of dimension {}, got {}",
+                first.embedding.len(),
+                embedding.len()
+            );
+        }
+        let entry = Entry {
+            id,
+            embedding,
+            text,
+        };
+        match self.entries.iter_mut().find(|e| e.id == entry.id) {
+            Some(existing) => *existing = entry,
+            None => self.entries.push(entry),
+        }
+        Ok(())
+    }
+
+    fn search(&self, query_embedding: &[f32], top_k: usize) -> Result<Vec<Document>> {
+        if let Some(first) = self.entries.first() {
+            anyhow::ensure!(
+                first.embedding.len() == query_embedding.len(),
+                "Expected a query embedding of dimension {}, got {}",
+                first.embedding.len(),
+                query_embedding.len()
+            );
+        }
+        let mut documents = self
+            .entries
+            .iter()
+            .map(|e| Document {
+                id: e.id.clone(),
+                text: e.text.clone(),
+                distance: l2_distance(&e.embedding, query_embedding),
+            })
+            .collect::<Vec<_>>();
+        documents.sort_by(|a, b| {
+            a.distance
+                .partial_cmp(&b.distance)
+                .unwrap_or(Ordering::Equal)
+        });
+        documents.truncate(top_k);
+        Ok(documents)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InMemoryVectorStore, VectorStore};
+
+    #[test]
+    fn nearest_neighbors() {
+        let mut store = InMemoryVectorStore::new();
+        store
+            .insert("a".to_string(), vec![0., 0.], "origin".to_string())
+            .unwrap();
+        store
+            .insert("b".to_string(), vec![3., 4.], "far".to_string())
+            .unwrap();
+        store
+            .insert("c".to_string(), vec![1., 0.], "near".to_string())
+            .unwrap();
+
+        let docs = store.search(&[0.9, 0.1], 2).unwrap();
+        assert_eq!(
+            docs.iter().map(|d| d.id.as_str()).collect::<Vec<_>>(),
+            ["c", "a"]
+        );
+        assert!(docs[0].distance < docs[1].distance);
+
+        // Replacing a document keeps a single entry for the id.
+        store
+            .insert("b".to_string(), vec![0.9, 0.1], "moved".to_string())
+            .unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.search(&[0.9, 0.1], 1).unwrap()[0].text, "moved");
+
+        assert!(store
+            .insert("d".to_string(), vec![1.], String::new())
+            .is_err());
+        assert!(store.search(&[1., 2., 3.], 1).is_err());
+    }
+}
@@ -1,15 +1,23 @@
 use crate::{
+    beam_search::{length_normalized_score, BeamHypotheses, BeamSearchConfig},
     get_mut_group,
-    pipeline::{text_models_inputs_processor::PagedAttentionMeta, LayerCaches},
-    response::{ChatCompletionChunkResponse, Choice, ChunkChoice, Response, SYSTEM_FINGERPRINT},
+    pipeline::{
+        text_models_inputs_processor::PagedAttentionMeta, DraftLength, LayerCaches,
+        SpeculativeConfig,
+    },
+    response::{
+        ChatCompletionChunkResponse, Choice, ChunkChoice, DocumentUsage, EffectiveSamplingParams,
+        Response, ResponseStopReason, SpeculativeStats,
+    },
     sampler::{Logprobs, Sampler},
     ChatCompletionResponse, Usage,
 };
 use crate::{
-    paged_attention::{BlockEngineSequence, LogicalTokenBlock},
-    pipeline::{DiffusionGenerationParams, KvCache},
+    paged_attention::{full_blocks, BlockEngineSequence, LogicalTokenBlock},
+    pipeline::{llg::DynamicRecognizer, DiffusionGenerationParams, KvCache},
     response::CompletionChoice,
     tools::ToolCallingMatcher,
+    utils::{balanced::BalancedDelimiters, reasoning::ReasoningTokens},
     CompletionChunkChoice, CompletionChunkResponse, CompletionResponse, ImageChoice,
     ImageGenerationResponse, ImageGenerationResponseFormat,
 };
@@ -34,6 +42,9 @@ pub enum StopReason {
         stop_string_idx: usize,
         completion_bytes_pos: usize,
     },
+    Balanced {
+        completion_bytes_pos: usize,
+    },
     Canceled,
     GeneratedImage,
 }
@@ -43,7 +54,9 @@ impl Display for StopReason {
         match self {
             StopReason::Eos => write!(f, "stop"),
             StopReason::Length(_) | StopReason::ModelLength(_) => write!(f, "length"),
-            StopReason::StopTok(_) | StopReason::StopString { .. } => write!(f, "stop"),
+            StopReason::StopTok(_)
+            | StopReason::StopString { .. }
+            | StopReason::Balanced { .. } => write!(f, "stop"),
             StopReason::Canceled => write!(f, "canceled"),
             StopReason::GeneratedImage => write!(f, "generated-image"),
         }
@@ -158,6 +171,16 @@ pub struct Sequence {
     sampler: Arc<Sampler>,
     stop_tokens: Vec<u32>,
     stop_strings: Vec<String>,
+    include_stop_str_in_output: bool,
+    /// Number of trailing tokens searched for the stop strings.
+    stop_lookback: usize,
+    balanced_delimiters: Option<BalancedDelimiters>,
+    /// Generated tokens of the think block of reasoning models.
+    reasoning_tokens: ReasoningTokens,
+    /// Draft length of speculative decoding, adapted to the acceptance of the draft tokens.
+    draft_length: Option<DraftLength>,
+    speculative_stats: Option<SpeculativeStats>,
+    response_prefix: Option<String>,
     return_logprobs: bool,
     responder: Sender<Response>,
     response_index: usize,
@@ -179,6 +202,9 @@ pub struct Sequence {
     // Speculative
     is_tmp: bool,
 
+    // Beam search
+    beam_search: Option<BeamSearchConfig>,
+
     // Prefix caching
     prefill_prompt_toks: Option<Vec<u32>>,
 
@@ -201,8 +227,18 @@ pub struct Sequence {
     last_completion_bytes_len: usize,
     last_is_done: Option<StopReason>,
     completion_bytes: Vec<u8>,
+    /// End of the bytes of each generated token in the completion bytes.
+    completion_token_ends: Vec<usize>,
     stream_idx: usize,
+    /// Number of generated tokens already sent in streaming chunks.
+    stream_token_idx: usize,
     pub recognizer: SequenceRecognizer,
+    dynamic_grammar: Option<DynamicRecognizer>,
+    /// Weight of each LoRA adapter of the model, in the order of their scalings, if the request
+    /// blends them.
+    adapter_scalings: Option<Vec<f32>>,
+    /// Id of the request which created this sequence.
+    request_id: usize,
     scheduling_urgency: usize, // The number of passes since scheduling
     input_images: Option<Vec<image::DynamicImage>>,
     pub cached_pixel_values: Option<Tensor>,
@@ -248,6 +284,16 @@ impl BlockEngineSequence for Sequence {
             SequenceCustomMetadata::None => unreachable!(),
         }
     }
+
+    fn full_blocks(&self) -> Vec<&[usize]> {
+        match &self.custom_metadata {
+            SequenceCustomMetadata::PagedAttention {
+                logical_token_blocks,
+                block_size: _,
+            } => full_blocks(logical_token_blocks),
+            SequenceCustomMetadata::None => unreachable!(),
+        }
+    }
 }
 
 impl Sequence {
@@ -262,6 +308,10 @@ impl Sequence {
         sampler: Sampler,
         stop_tokens: Vec<u32>,
         stop_strings: Vec<String>,
+        include_stop_str_in_output: bool,
+        stop_lookback: Option<usize>,
+        stop_on_balanced: Option<(char, char)>,
+        strip_response_prefix: Option<String>,
         max_len: Option<usize>,
         return_logprobs: bool,
         is_xlora: bool,
@@ -297,6 +347,9 @@ impl Sequence {
         custom_metadata
             .append_tokens_to_blocks(tokens.iter().map(|x| *x as usize).collect::<Vec<_>>());
         Self {
+            reasoning_tokens: ReasoningTokens::new(&prompt),
+            draft_length: None,
+            speculative_stats: None,
             tokens,
             prompt,
             logprobs: Vec::new(),
@@ -317,7 +370,13 @@ impl Sequence {
             responder,
             sampler: sampler.into(),
             stop_tokens,
+            stop_lookback: stop_lookback
+                .unwrap_or_else(|| stop_strings.iter().map(String::len).max().unwrap_or(0)),
             stop_strings,
+            include_stop_str_in_output,
+            balanced_delimiters: stop_on_balanced
+                .map(|(open, close)| BalancedDelimiters::new(open, close)),
+            response_prefix: strip_response_prefix,
             max_len,
             return_logprobs,
             prompt_tok_per_sec: 0.,
@@ -327,16 +386,22 @@ impl Sequence {
             response_index,
             creation_time,
             recognizer,
+            dynamic_grammar: None,
+            adapter_scalings: None,
+            request_id: 0,
             prefill_prompt_toks: None,
             suffix,
             prefix,
             cumulative_logprob: 0.,
             completion_bytes: Vec::new(),
+            completion_token_ends: Vec::new(),
             stream_idx: 0,
+            stream_token_idx: 0,
             last_completion_bytes_len: 0,
             last_logprob: 0.0,
             last_is_done: None,
             is_tmp: false,
+            beam_search: None,
             scheduling_urgency: 0,
             input_images,
             custom_metadata,
@@ -384,6 +449,115 @@ impl Sequence {
         self
     }
 
+    pub(crate) fn with_dynamic_grammar(
+        mut self,
+        dynamic_grammar: Option<DynamicRecognizer>,
+    ) -> Self {
+        self.dynamic_grammar = dynamic_grammar;
+        self
+    }
+
+    /// Make this sequence one of the beams of a beam search. All the sequences of its group must
+    /// be beams of the same search.
+    pub(crate) fn with_beam_search(mut self, beam_search: Option<BeamSearchConfig>) -> Self {
+        self.beam_search = beam_search;
+        self
+    }
+
+    pub(crate) fn with_adapter_scalings(mut self, adapter_scalings: Option<Vec<f32>>) -> Self {
+        self.adapter_scalings = adapter_scalings;
+        self
+    }
+
+    pub(crate) fn adapter_scalings(&self) -> Option<&[f32]> {
+        self.adapter_scalings.as_deref()
+    }
+
+    pub(crate) fn with_request_id(mut self, request_id: usize) -> Self {
+        self.request_id = request_id;
+        self
+    }
+
+    pub fn request_id(&self) -> usize {
+        self.request_id
+    }
+
+    pub(crate) fn beam_search(&self) -> Option<&BeamSearchConfig> {
+        self.beam_search.as_ref()
+    }
+
+    pub(crate) fn same_group(&self, other: &Sequence) -> bool {
+        Arc::ptr_eq(&self.group, &other.group)
+    }
+
+    /// The decoding state of this beam, without the KV cache.
+    pub(crate) fn beam_state(&self) -> BeamState {
+        BeamState {
+            tokens: self.tokens.clone(),
+            logprobs: self.logprobs.clone(),
+            cumulative_logprob: self.cumulative_logprob,
+            last_logprob: self.last_logprob,
+            last_completion_bytes_len: self.last_completion_bytes_len,
+            completion_bytes: self.completion_bytes.clone(),
+            completion_token_ends: self.completion_token_ends.clone(),
+            reasoning_tokens: self.reasoning_tokens.clone(),
+            caches: None,
+        }
+    }
+
+    /// The decoding state of this beam with a copy of the KV cache, which the model appends to
+    /// in place.
+    pub(crate) fn beam_state_with_caches(&self) -> candle_core::Result<BeamState> {
+        let caches = BeamCaches {
+            normal: self
+                .normal_cache
+                .iter()
+                .map(|c| c.as_ref().map(KvCache::deep_copy).transpose())
+                .collect::<candle_core::Result<_>>()?,
+            full: self.cache.clone(),
+            xlora: self.xlora_cache.clone(),
+            scaling: self.scaling_cache.clone(),
+        };
+        Ok(BeamState {
+            caches: Some(caches),
+            ..self.beam_state()
+        })
+    }
+
+    /// Continue another beam. A state without caches clears them, for a finished hypothesis
+    /// which is not decoded further.
+    pub(crate) fn set_beam_state(&mut self, state: BeamState) {
+        self.tokens = state.tokens;
+        self.logprobs = state.logprobs;
+        self.cumulative_logprob = state.cumulative_logprob;
+        self.last_logprob = state.last_logprob;
+        self.last_completion_bytes_len = state.last_completion_bytes_len;
+        self.completion_bytes = state.completion_bytes;
+        self.completion_token_ends = state.completion_token_ends;
+        self.reasoning_tokens = state.reasoning_tokens;
+        let caches = state.caches.unwrap_or_else(|| BeamCaches {
+            normal: vec![None; self.normal_cache.len()],
+            full: vec![None; self.cache.len()],
+            xlora: self.xlora_cache.as_ref().map(|c| vec![None; c.len()]),
+            scaling: None,
+        });
+        self.normal_cache = caches.normal;
+        self.cache = caches.full;
+        self.xlora_cache = caches.xlora;
+        self.scaling_cache = caches.scaling;
+    }
+
+    /// Replace the recognizer if the dynamic grammar is updated after the last generated token.
+    pub(crate) fn update_grammar(&mut self) -> anyhow::Result<()> {
+        let Some(dynamic_grammar) = &mut self.dynamic_grammar else {
+            return Ok(());
+        };
+        if let Some(recognizer) = dynamic_grammar.update(&self.tokens[self.prompt_len..])? {
+            self.recognizer = recognizer;
+        }
+        Ok(())
+    }
+
     pub fn prefill_v2(
         mut self,
         cache: Vec<Option<KvCache>>,
@@ -571,6 +745,25 @@ impl Sequence {
         self.custom_metadata.remove_tokens_from_blocks(n);
     }
 
+    /// Draft length of speculative decoding, starting from the γ of `config`.
+    pub(crate) fn draft_length(&mut self, config: &SpeculativeConfig) -> &mut DraftLength {
+        self.draft_length
+            .get_or_insert_with(|| DraftLength::new(config))
+    }
+
+    /// Record a speculative decoding step of `gamma` draft tokens, of which `accepted` were
+    /// accepted, which generated `generated` tokens.
+    pub(crate) fn record_speculative_step(
+        &mut self,
+        gamma: usize,
+        accepted: usize,
+        generated: usize,
+    ) {
+        self.speculative_stats
+            .get_or_insert_with(SpeculativeStats::default)
+            .record(gamma, accepted, generated);
+    }
+
     pub fn add_token(
         &mut self,
         tok: Logprobs,
@@ -586,7 +779,12 @@ impl Sequence {
             // We don't need to add stop tokens to the completion bytes to check for stop strings.
             // And by not adding it here, we can avoid having to delete these tokens from the output.
             self.completion_bytes.extend_from_slice(&completion_bytes);
+            self.completion_token_ends.push(self.completion_bytes.len());
             self.last_completion_bytes_len = completion_bytes.len();
+            if let Some(balanced) = &mut self.balanced_delimiters {
+                balanced.update(&self.completion_bytes);
+            }
+            self.reasoning_tokens.update(&self.completion_bytes);
         }
         self.last_logprob = tok.logprob;
         self.last_is_done = *is_done;
@@ -646,18 +844,25 @@ impl Sequence {
         } else if self.tokens.len().saturating_sub(self.prompt_len) == max_model_len {
             Some(StopReason::ModelLength(max_model_len))
         } else {
-            if !self.stop_strings.is_empty() {
-                for (idx, s) in self.stop_strings.iter().enumerate() {
-                    if let Some(pos) = galil_seiferas::gs_find(&self.completion_bytes, s.as_bytes())
-                    {
-                        return Some(StopReason::StopString {
-                            stop_string_idx: idx,
-                            completion_bytes_pos: pos,
-                        });
-                    }
-                }
+            if let Some(pos) = self
+                .balanced_delimiters
+                .as_ref()
+                .and_then(|b| b.balanced_at())
+            {
+                return Some(StopReason::Balanced {
+                    completion_bytes_pos: pos,
+                });
             }
-            None
+            find_stop_string(
+                &self.completion_bytes,
+                &self.completion_token_ends,
+                &self.stop_strings,
+                self.stop_lookback,
+            )
+            .map(|(idx, pos)| StopReason::StopString {
+                stop_string_idx: idx,
+                completion_bytes_pos: pos,
+            })
         }
     }
 
@@ -677,21 +882,77 @@ impl Sequence {
         &self.stop_strings
     }
 
+    pub fn stop_tokens(&self) -> &[u32] {
+        &self.stop_tokens
+    }
+
+    pub fn cumulative_logprob(&self) -> f32 {
+        self.cumulative_logprob
+    }
+
+    /// End of the output text in the completion bytes, accounting for a matched stop string.
+    pub fn completion_bytes_end(&self, reason: &StopReason) -> usize {
+        completion_bytes_end(
+            reason,
+            self.completion_bytes.len(),
+            &self.stop_strings,
+            self.include_stop_str_in_output,
+        )
+    }
+
+    /// The stop string or stop token which ended this sequence, if any.
+    pub fn response_stop_reason(&self, reason: &StopReason) -> Option<ResponseStopReason> {
+        response_stop_reason(reason, &self.stop_strings, self.tokens.last().copied())
+    }
+
+    /// Number of trailing completion bytes which may be the start of a stop string and so must not be streamed yet.
+    fn stop_string_holdback(&self) -> usize {
+        if self.include_stop_str_in_output {
+            return 0;
+        }
+        stop_string_holdback(
+            &self.completion_bytes[self.stream_idx..],
+            &self.stop_strings,
+        )
+    }
+
+    /// Trim the leading whitespace of the response, and the response prefix if configured.
+    pub fn strip_response_prefix<'a>(&self, text: &'a str) -> &'a str {
+        strip_response_prefix(text, self.response_prefix.as_deref())
+    }
+
     /// Returns the delta between the last two decoded sequences
     pub fn get_delta(
         &mut self,
     ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
         let new_decoded = self.peek_delta();
-        if matches!(new_decoded, Ok(Some(_))) {
-            self.stream_idx = self.completion_bytes.len();
+        if let Ok(Some(_)) = new_decoded {
+            self.stream_idx = match &self.last_is_done {
+                Some(reason) => self.completion_bytes_end(reason),
+                None => self.completion_bytes.len() - self.stop_string_holdback(),
+            }
+            .max(self.stream_idx);
         }
         new_decoded
     }
 
+    /// Returns the generated tokens which were not yet sent in a streaming chunk.
+    pub fn take_stream_tokens(&mut self) -> Vec<u32> {
+        let generated = &self.tokens[self.prompt_len.min(self.tokens.len())..];
+        let new = generated[self.stream_token_idx.min(generated.len())..].to_vec();
+        self.stream_token_idx = generated.len();
+        new
+    }
+
     /// Peeks at the delta between the last two decoded sequences, but does not advance the stream index.
     pub fn peek_delta(&self) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
         let is_first = self.stream_idx == 0;
-        let new_decoded = String::from_utf8_lossy(&self.completion_bytes[self.stream_idx..]);
+        let end = match &self.last_is_done {
+            Some(reason) => self.completion_bytes_end(reason),
+            None => self.completion_bytes.len() - self.stop_string_holdback(),
+        }
+        .max(self.stream_idx);
+        let new_decoded = String::from_utf8_lossy(&self.completion_bytes[self.stream_idx..end]);
         // Check if the sequence ends with valid utf8, if not skip it as it probably is a multi token sequence
         if new_decoded.ends_with('�') {
             return Ok(None);
@@ -701,7 +962,16 @@ impl Sequence {
         // Since we're using the completion_bytes, we need to take care of that ourselves.
         // Had we used HF's Tokenizer, it would have taken care of that for us.
         if is_first {
-            return Ok(Some(new_decoded.trim_start().to_string()));
+            let delta = self.strip_response_prefix(&new_decoded);
+            // Hold the start of the response back until it can be told apart from the prefix.
+            if let Some(prefix) = &self.response_prefix {
+                if self.last_is_done.is_none()
+                    && (delta.is_empty() || prefix.starts_with(new_decoded.trim_start()))
+                {
+                    return Ok(None);
+                }
+            }
+            return Ok(Some(delta.to_string()));
         }
         Ok(Some(new_decoded.to_string()))
     }
@@ -729,6 +999,8 @@ impl Sequence {
 
         get_mut_group!(self).total_prompt_toks = self.prompt_len;
         get_mut_group!(self).total_toks = self.len();
+        get_mut_group!(self).total_reasoning_toks = self.reasoning_tokens.count();
+        get_mut_group!(self).speculative_stats = self.speculative_stats;
     }
 
     pub fn add_image_choice_to_group(&self, choice: ImageChoice) {
@@ -755,9 +1027,11 @@ impl Sequence {
             choice.text,
             self.suffix.as_deref().unwrap_or("")
         );
-        get_mut_group!(self)
-            .completion_choices
-            .push((self.cumulative_logprob, choice));
+        get_mut_group!(self).add_completion_choice(
+            self.cumulative_logprob,
+            self.tokens.len().saturating_sub(self.prompt_len),
+            choice,
+        );
         self.update_time_info();
     }
 
@@ -814,11 +1088,35 @@ impl Sequence {
     }
 }
 
+/// The decoding state of a beam, moved between the sequences of a beam search.
+pub(crate) struct BeamState {
+    tokens: Vec<u32>,
+    logprobs: Vec<Logprobs>,
+    cumulative_logprob: f32,
+    last_logprob: f32,
+    last_completion_bytes_len: usize,
+    completion_bytes: Vec<u8>,
+    completion_token_ends: Vec<usize>,
+    reasoning_tokens: ReasoningTokens,
+    caches: Option<BeamCaches>,
+}
+
+struct BeamCaches {
+    normal: Vec<Option<KvCache>>,
+    full: LayerCaches,
+    xlora: Option<LayerCaches>,
+    scaling: Option<Tensor>,
+}
+
 pub struct SequenceGroup {
     n_choices: usize, // The target number of choices to return. Can be decreased if an error is thrown.
     best_of: Option<usize>, // Top n seqs based on cumulative logprobs.
+    length_penalty: Option<f32>,
     pub total_prompt_toks: usize,
     pub total_toks: usize,
+    /// Generated tokens in the think blocks of reasoning models, part of the completion tokens.
+    pub total_reasoning_toks: usize,
+    pub speculative_stats: Option<SpeculativeStats>,
     pub total_prompt_time: u128,
     pub total_time: u128,
     pub total_completion_time: u128,
@@ -830,6 +1128,14 @@ pub struct SequenceGroup {
     pub completion_streaming_chunks: Vec<CompletionChunkChoice>,
     pub is_streaming: bool,
     pub is_chat: bool,
+    /// Returned with the final response if the request sets `return_effective_params`.
+    pub effective_params: Option<EffectiveSamplingParams>,
+    /// Documents packed into the prompt, returned with the final chat response.
+    pub documents: Option<Vec<DocumentUsage>>,
+    /// Weights of the LoRA adapters blended for the request, returned with the final response.
+    pub adapters: Option<Vec<(String, f32)>>,
+    /// The finished hypotheses of a beam search, with the last token of each.
+    pub(crate) beam_hypotheses: Option<BeamHypotheses<(BeamState, Logprobs)>>,
 }
 
 impl SequenceGroup {
@@ -838,6 +1144,7 @@ impl SequenceGroup {
         is_streaming: bool,
         is_chat: bool,
         best_of: Option<usize>,
+        length_penalty: Option<f32>,
     ) -> Self {
         Self {
             choices: Vec::new(),
@@ -847,6 +1154,8 @@ impl SequenceGroup {
             n_choices,
             total_prompt_toks: 0,
             total_toks: 0,
+            total_reasoning_toks: 0,
+            speculative_stats: None,
             total_prompt_time: 0,
             total_time: 0,
             total_completion_time: 0,
@@ -855,6 +1164,11 @@ impl SequenceGroup {
             is_streaming,
             is_chat,
             best_of,
+            length_penalty,
+            effective_params: None,
+            documents: None,
+            adapters: None,
+            beam_hypotheses: None,
         }
     }
 
@@ -862,6 +1176,21 @@ impl SequenceGroup {
         &self.choices
     }
 
+    /// Add a completion choice with the score used to rank it for `best_of`: the cumulative
+    /// logprob, length-normalized like beam search hypotheses if a length penalty is set.
+    fn add_completion_choice(
+        &mut self,
+        cumulative_logprob: f32,
+        completion_len: usize,
+        choice: CompletionChoice,
+    ) {
+        let score = match self.length_penalty {
+            Some(alpha) => length_normalized_score(cumulative_logprob, completion_len, alpha),
+            None => cumulative_logprob,
+        };
+        self.completion_choices.push((score, choice));
+    }
+
     /// This may apply the best_of.
     pub fn get_completion_choices(&self) -> Vec<CompletionChoice> {
         if let Some(best_of) = self.best_of {
@@ -890,6 +1219,7 @@ impl SequenceGroup {
         #[allow(clippy::cast_precision_loss)]
         Usage {
             completion_tokens: self.total_toks - self.total_prompt_toks,
+            reasoning_tokens: self.total_reasoning_toks,
             prompt_tokens: self.total_prompt_toks,
             total_tokens: self.total_toks,
             avg_tok_per_sec: (self.total_toks as f32 / self.total_time as f32) * 1000.,
@@ -901,6 +1231,7 @@ impl SequenceGroup {
             total_time_sec: self.total_time as f32 / 1000.,
             total_completion_time_sec: self.total_completion_time as f32 / 1000.,
             total_prompt_time_sec: self.total_prompt_time as f32 / 1000.,
+            speculative: self.speculative_stats,
         }
     }
 
@@ -950,8 +1281,20 @@ impl SequenceGroup {
         &mut self,
         seq: &Sequence,
         model: String,
+        system_fingerprint: String,
         usage_opt: Option<Usage>,
     ) -> Result<(), Box<SendError<Response>>> {
+        // The effective parameters, the documents and the adapters are sent with the final chunk,
+        // like the usage.
+        let (effective_params, documents, adapters) = if usage_opt.is_some() {
+            (
+                self.effective_params.clone(),
+                self.documents.clone(),
+                self.adapters.clone(),
+            )
+        } else {
+            (None, None, None)
+        };
         if self.chat_streaming_chunks.len() == self.n_choices && self.is_streaming {
             let mut swap_streaming_chunks = vec![];
 
@@ -963,9 +1306,13 @@ impl SequenceGroup {
                     choices: swap_streaming_chunks,
                     created: seq.timestamp,
                     model: model.clone(),
-                    system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                    system_fingerprint: system_fingerprint.clone(),
                     object: "chat.completion.chunk".to_string(),
-                    usage: usage_opt,
+                    usage: usage_opt.clone(),
+                    prompt_tokens: seq.prompt_tokens(),
+                    effective_params,
+                    documents,
+                    adapters,
                 }))
                 .await?;
         } else if self.completion_streaming_chunks.len() == self.n_choices && self.is_streaming {
@@ -982,8 +1329,12 @@ impl SequenceGroup {
                     choices: swap_streaming_chunks,
                     created: seq.timestamp,
                     model: model.clone(),
-                    system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                    system_fingerprint,
                     object: "text_completion".to_string(),
+                    usage: usage_opt,
+                    prompt_tokens: seq.prompt_tokens(),
+                    effective_params,
+                    adapters,
                 }))
                 .await?;
         }
@@ -1001,3 +1352,240 @@ impl SequenceGroup {
         Ok(())
     }
 }
+
+/// The index and the position of the first stop string in the bytes of the last `lookback` tokens
+/// of the completion. The stop strings are searched after each token, so a lookback covering the
+/// longest stop string finds all of them, while a smaller one is cheaper for long stop strings
+/// but misses those spread over more than `lookback` tokens.
+fn find_stop_string(
+    completion_bytes: &[u8],
+    token_ends: &[usize],
+    stop_strings: &[String],
+    lookback: usize,
+) -> Option<(usize, usize)> {
+    let start = token_ends
+        .len()
+        .checked_sub(lookback + 1)
+        .map_or(0, |i| token_ends[i]);
+    stop_strings.iter().enumerate().find_map(|(idx, s)| {
+        galil_seiferas::gs_find(&completion_bytes[start..], s.as_bytes())
+            .map(|pos| (idx, start + pos))
+    })
+}
+
+/// End of the output text in `completion_len` completion bytes, accounting for a matched stop string.
+fn completion_bytes_end(
+    reason: &StopReason,
+    completion_len: usize,
+    stop_strings: &[String],
+    include_stop_str_in_output: bool,
+) -> usize {
+    match reason {
+        StopReason::StopString {
+            stop_string_idx,
+            completion_bytes_pos,
+        } => {
+            if include_stop_str_in_output {
+                completion_bytes_pos + stop_strings[*stop_string_idx].len()
+            } else {
+                *completion_bytes_pos
+            }
+        }
+        StopReason::Balanced {
+            completion_bytes_pos,
+        } => *completion_bytes_pos,
+        _ => completion_len,
+    }
+}
+
+/// The stop string or stop token of `reason`. An EOS token is reported as the stop token `last_token`.
+fn response_stop_reason(
+    reason: &StopReason,
+    stop_strings: &[String],
+    last_token: Option<u32>,
+) -> Option<ResponseStopReason> {
+    match reason {
+        StopReason::StopString {
+            stop_string_idx, ..
+        } => Some(ResponseStopReason::StopString(
+            stop_strings[*stop_string_idx].clone(),
+        )),
+        StopReason::StopTok(tok) => Some(ResponseStopReason::StopToken(*tok)),
+        StopReason::Eos => last_token.map(ResponseStopReason::StopToken),
+        _ => None,
+    }
+}
+
+/// Length of the longest suffix of `bytes` which is a proper prefix of one of the stop strings.
+fn stop_string_holdback(bytes: &[u8], stop_strings: &[String]) -> usize {
+    stop_strings
+        .iter()
+        .map(|s| {
+            let s = s.as_bytes();
+            (1..s.len().min(bytes.len() + 1))
+                .rev()
+                .find(|n| bytes.ends_with(&s[..*n]))
+                .unwrap_or(0)
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+fn strip_response_prefix<'a>(text: &'a str, prefix: Option<&str>) -> &'a str {
+    let text = text.trim_start();
+    match prefix.and_then(|prefix| text.strip_prefix(prefix)) {
+        Some(rest) => rest.trim_start(),
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        completion_bytes_end, find_stop_string, response_stop_reason, stop_string_holdback,
+        strip_response_prefix, SequenceGroup, StopReason,
+    };
+    use crate::response::{CompletionChoice, ResponseStopReason};
+
+    fn ranked(length_penalty: Option<f32>) -> Vec<String> {
+        let mut group = SequenceGroup::new(2, false, false, Some(2), length_penalty);
+        for (text, cumulative_logprob, len) in [("short", -3., 2), ("long", -4., 12)] {
+            let choice = CompletionChoice {
+                finish_reason: "stop".to_string(),
+                index: 0,
+                text: text.to_string(),
+                logprobs: None,
+                stop_reason: None,
+            };
+            group.add_completion_choice(cumulative_logprob, len, choice);
+        }
+        group
+            .get_completion_choices()
+            .into_iter()
+            .map(|choice| choice.text)
+            .collect()
+    }
+
+    #[test]
+    fn best_of_length_penalty() {
+        // The cumulative logprob alone favors the short completion.
+        assert_eq!(ranked(None), ["short", "long"]);
+        assert_eq!(ranked(Some(0.)), ranked(None));
+        // -3 / (7 / 6) < -4 / (17 / 6)
+        assert_eq!(ranked(Some(1.)), ["long", "short"]);
+    }
+
+    #[test]
+    fn response_prefix() {
+        let prefix = Some("<|assistant|>");
+        assert_eq!(
+            strip_response_prefix(" <|assistant|>\n Hello there", prefix),
+            "Hello there"
+        );
+        assert_eq!(strip_response_prefix(" Hello there", prefix), "Hello there");
+        // Only a leading prefix is removed.
+        assert_eq!(
+            strip_response_prefix("Hello <|assistant|>", prefix),
+            "Hello <|assistant|>"
+        );
+        assert_eq!(
+            strip_response_prefix(" <|assistant|> Hi", None),
+            "<|assistant|> Hi"
+        );
+    }
+
+    #[test]
+    fn stop_string_lookback() {
+        // One token per word.
+        let tokens = ["The", " answer", " is", " END", " OF", " TEXT", " now"];
+        let mut completion = Vec::new();
+        let mut ends = Vec::new();
+        let stop = vec![" END OF TEXT".to_string()];
+        let mut found = |lookback: usize| {
+            completion.clear();
+            ends.clear();
+            for tok in tokens {
+                completion.extend_from_slice(tok.as_bytes());
+                ends.push(completion.len());
+                let hit = find_stop_string(&completion, &ends, &stop, lookback);
+                if hit.is_some() {
+                    return hit;
+                }
+            }
+            None
+        };
+        // The stop string spans 3 tokens.
+        assert_eq!(found(3), Some((0, 13)));
+        // The default lookback, the length of the stop string in bytes, covers it.
+        assert_eq!(found(stop[0].len()), Some((0, 13)));
+        // A smaller lookback misses it.
+        assert_eq!(found(2), None);
+    }
+
+    #[test]
+    fn stop_string_holdback_keeps_partial_matches() {
+        let stop = vec!["</answer>".to_string(), "STOP".to_string()];
+        // Nothing which could start a stop string.
+        assert_eq!(stop_string_holdback(b"The answer is 4", &stop), 0);
+        assert_eq!(stop_string_holdback(b"The answer is 4 <", &stop), 1);
+        assert_eq!(stop_string_holdback(b"The answer is 4 </ans", &stop), 5);
+        // The longest partial match of any stop string is held back.
+        assert_eq!(stop_string_holdback(b"ST", &stop), 2);
+        // A complete stop string is found by the stop string search, not held back.
+        assert_eq!(stop_string_holdback(b"STOP", &stop), 0);
+        assert_eq!(stop_string_holdback(b"", &stop), 0);
+        assert_eq!(stop_string_holdback(b"abc", &[]), 0);
+    }
+
+    #[test]
+    fn completion_end_with_stop_string() {
+        let stop = vec!["</answer>".to_string()];
+        let reason = StopReason::StopString {
+            stop_string_idx: 0,
+            completion_bytes_pos: 4,
+        };
+        // The completion is `four</answer>`.
+        assert_eq!(completion_bytes_end(&reason, 13, &stop, false), 4);
+        assert_eq!(completion_bytes_end(&reason, 13, &stop, true), 13);
+        let balanced = StopReason::Balanced {
+            completion_bytes_pos: 7,
+        };
+        assert_eq!(completion_bytes_end(&balanced, 9, &stop, true), 7);
+        assert_eq!(completion_bytes_end(&StopReason::Eos, 9, &stop, false), 9);
+        assert_eq!(
+            completion_bytes_end(&StopReason::Length(3), 9, &stop, false),
+            9
+        );
+    }
+
+    #[test]
+    fn stop_reason_of_response() {
+        let stop = vec!["\n\n".to_string(), "###".to_string()];
+        let reason = StopReason::StopString {
+            stop_string_idx: 1,
+            completion_bytes_pos: 0,
+        };
+        assert_eq!(
+            response_stop_reason(&reason, &stop, Some(5)),
+            Some(ResponseStopReason::StopString("###".to_string()))
+        );
+        assert_eq!(
+            response_stop_reason(&StopReason::StopTok(7), &stop, Some(7)),
+            Some(ResponseStopReason::StopToken(7))
+        );
+        // The EOS token is the last token of the sequence.
+        assert_eq!(
+            response_stop_reason(&StopReason::Eos, &stop, Some(2)),
+            Some(ResponseStopReason::StopToken(2))
+        );
+        assert_eq!(response_stop_reason(&StopReason::Eos, &stop, None), None);
+        assert_eq!(
+            response_stop_reason(&StopReason::Length(16), &stop, Some(2)),
+            None
+        );
+        assert_eq!(
+            response_stop_reason(&StopReason::Canceled, &stop, None),
+            None
+        );
+    }
+}
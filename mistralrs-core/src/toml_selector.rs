@@ -5,10 +5,10 @@ use serde::Deserialize;
 
 use crate::{
     amoe::AnyMoeConfig, pipeline::IsqOrganization, AnyMoeLoader, AutoDeviceMapParams,
-    GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, Loader,
-    ModelDType, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, SpeculativeConfig,
-    SpeculativeLoader, Topology, VisionLoaderBuilder, VisionLoaderType, VisionSpecificConfig,
-    GGUF_MULTI_FILE_DELIMITER,
+    GGMLLoaderBuilder, GGMLSpecificConfig, GGUFArchitecture, GGUFLoaderBuilder, GGUFSpecificConfig,
+    Loader, ModelDType, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig,
+    SpeculativeConfig, SpeculativeLoader, Topology, VisionLoaderBuilder, VisionLoaderType,
+    VisionSpecificConfig, GGUF_MULTI_FILE_DELIMITER,
 };
 
 fn default_one() -> usize {
@@ -446,6 +446,9 @@ pub struct SpeculativeTomlModelSelected {
     /// Gamma value for the model
     gamma: usize,
 
+    /// Minimum and maximum gamma, to adapt it to the acceptance of the draft tokens
+    gamma_bounds: Option<(usize, usize)>,
+
     /// Base model
     draft_model: TomlModelSelected,
 }
@@ -503,6 +506,9 @@ pub struct TomlLoaderArgs {
     pub no_kv_cache: bool,
     pub prompt_chunksize: Option<NonZeroUsize>,
     pub jinja_explicit: Option<String>,
+    pub byte_fallback: Option<bool>,
+    pub force_arch: Option<GGUFArchitecture>,
+    pub accuracy_companion: Option<PathBuf>,
 }
 
 pub fn get_toml_selected_model_dtype(model: &TomlSelector) -> ModelDType {
@@ -617,6 +623,9 @@ fn loader_from_selected(
                 imatrix,
                 calibration_file,
                 hf_cache_path,
+                early_exit: None,
+                byte_fallback: args.byte_fallback,
+                tok_env: None,
             },
             args.chat_template,
             args.tokenizer_json,
@@ -649,6 +658,9 @@ fn loader_from_selected(
                 imatrix: None,
                 calibration_file: None,
                 hf_cache_path,
+                early_exit: None,
+                byte_fallback: args.byte_fallback,
+                tok_env: None,
             },
             args.chat_template,
             args.tokenizer_json,
@@ -688,6 +700,9 @@ fn loader_from_selected(
                 imatrix: None,
                 calibration_file: None,
                 hf_cache_path,
+                early_exit: None,
+                byte_fallback: args.byte_fallback,
+                tok_env: None,
             },
             args.chat_template,
             args.tokenizer_json,
@@ -721,6 +736,11 @@ fn loader_from_selected(
             GGUFSpecificConfig {
                 prompt_chunksize: args.prompt_chunksize,
                 topology: Topology::from_option_path(topology)?,
+                auto_correct_chat_template: false,
+                byte_fallback: args.byte_fallback,
+                force_arch: args.force_arch,
+                accuracy_companion: args.accuracy_companion.clone(),
+                tok_env: None,
             },
             args.no_kv_cache,
             args.jinja_explicit,
@@ -748,6 +768,11 @@ fn loader_from_selected(
             GGUFSpecificConfig {
                 prompt_chunksize: args.prompt_chunksize,
                 topology: Topology::from_option_path(topology)?,
+                auto_correct_chat_template: false,
+                byte_fallback: args.byte_fallback,
+                force_arch: args.force_arch,
+                accuracy_companion: args.accuracy_companion.clone(),
+                tok_env: None,
             },
             args.no_kv_cache,
             args.jinja_explicit,
@@ -781,6 +806,11 @@ fn loader_from_selected(
             GGUFSpecificConfig {
                 prompt_chunksize: args.prompt_chunksize,
                 topology: Topology::from_option_path(topology)?,
+                auto_correct_chat_template: false,
+                byte_fallback: args.byte_fallback,
+                force_arch: args.force_arch,
+                accuracy_companion: args.accuracy_companion.clone(),
+                tok_env: None,
             },
             args.no_kv_cache,
             args.jinja_explicit,
@@ -807,6 +837,8 @@ fn loader_from_selected(
                 gqa,
                 prompt_chunksize: args.prompt_chunksize,
                 topology: Topology::from_option_path(topology)?,
+                byte_fallback: args.byte_fallback,
+                tok_env: None,
             },
             args.chat_template,
             args.tokenizer_json,
@@ -834,6 +866,8 @@ fn loader_from_selected(
                 gqa,
                 prompt_chunksize: args.prompt_chunksize,
                 topology: Topology::from_option_path(topology)?,
+                byte_fallback: args.byte_fallback,
+                tok_env: None,
             },
             args.chat_template,
             args.tokenizer_json,
@@ -869,6 +903,8 @@ fn loader_from_selected(
                 gqa,
                 prompt_chunksize: args.prompt_chunksize,
                 topology: Topology::from_option_path(topology)?,
+                byte_fallback: args.byte_fallback,
+                tok_env: None,
             },
             args.chat_template,
             args.tokenizer_json,
@@ -912,6 +948,8 @@ fn loader_from_selected(
                 calibration_file,
                 imatrix,
                 hf_cache_path,
+                byte_fallback: args.byte_fallback,
+                tok_env: None,
             },
             args.chat_template,
             args.tokenizer_json,
@@ -943,6 +981,7 @@ impl TryInto<Box<dyn Loader>> for (TomlSelector, TomlLoaderArgs) {
                 draft: draft_loader,
                 config: SpeculativeConfig {
                     gamma: speculative.gamma,
+                    gamma_bounds: speculative.gamma_bounds,
                 },
             })
         } else {
@@ -0,0 +1,135 @@
+/// Tracks the nesting depth of an open/close delimiter pair over the generated text, to stop
+/// generation as soon as the delimiters balance after the first opening delimiter.
+///
+/// Delimiters inside double-quoted strings (with `\` escapes) are ignored.
+#[derive(Clone, Debug)]
+pub struct BalancedDelimiters {
+    open: char,
+    close: char,
+    depth: usize,
+    opened: bool,
+    in_string: bool,
+    escaped: bool,
+    /// Number of bytes of the output which have been processed.
+    offset: usize,
+    balanced_at: Option<usize>,
+}
+
+impl BalancedDelimiters {
+    pub fn new(open: char, close: char) -> Self {
+        Self {
+            open,
+            close,
+            depth: 0,
+            opened: false,
+            in_string: false,
+            escaped: false,
+            offset: 0,
+            balanced_at: None,
+        }
+    }
+
+    /// Process the output bytes which have not yet been seen. `output` is the entire output so far.
+    /// Returns the byte position just past the closing delimiter once the delimiters are balanced.
+    pub fn update(&mut self, output: &[u8]) -> Option<usize> {
+        if self.balanced_at.is_some() {
+            return self.balanced_at;
+        }
+        let pending = &output[self.offset..];
+        // Leave a trailing partial UTF-8 sequence until the rest of it is generated.
+        let text = match std::str::from_utf8(pending) {
+            Ok(text) => text,
+            Err(e) => std::str::from_utf8(&pending[..e.valid_up_to()]).unwrap(),
+        };
+        for (i, c) in text.char_indices() {
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if c == '\\' {
+                    self.escaped = true;
+                } else if c == '"' {
+                    self.in_string = false;
+                }
+            } else if c == '"' && self.opened {
+                self.in_string = true;
+            } else if c == self.open {
+                self.depth += 1;
+                self.opened = true;
+            } else if c == self.close && self.opened {
+                self.depth -= 1;
+                if self.depth == 0 {
+                    self.balanced_at = Some(self.offset + i + c.len_utf8());
+                    break;
+                }
+            }
+        }
+        self.offset += text.len();
+        self.balanced_at
+    }
+
+    pub fn balanced_at(&self) -> Option<usize> {
+        self.balanced_at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BalancedDelimiters;
+
+    fn run(chunks: &[&str]) -> Option<(usize, String)> {
+        let mut tracker = BalancedDelimiters::new('{', '}');
+        let mut output = Vec::new();
+        for chunk in chunks {
+            output.extend_from_slice(chunk.as_bytes());
+            if let Some(end) = tracker.update(&output) {
+                return Some((end, String::from_utf8(output[..end].to_vec()).unwrap()));
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn stops_at_closing_brace() {
+        let (_, text) = run(&[
+            " Here",
+            " is:",
+            " {\"a\":",
+            " {\"b\": [1, 2]}",
+            "}",
+            " and more",
+        ])
+        .unwrap();
+        assert_eq!(text, " Here is: {\"a\": {\"b\": [1, 2]}}");
+    }
+
+    #[test]
+    fn closing_brace_mid_token() {
+        let (end, text) = run(&["{\"a\": 1", "}\n\nDone"]).unwrap();
+        assert_eq!(text, "{\"a\": 1}");
+        assert_eq!(end, 8);
+    }
+
+    #[test]
+    fn ignores_delimiters_in_strings() {
+        let (_, text) = run(&["{\"a\": \"}\", ", "\"b\": \"\\\"{\"", "}", "}"]).unwrap();
+        assert_eq!(text, "{\"a\": \"}\", \"b\": \"\\\"{\"}");
+    }
+
+    #[test]
+    fn not_balanced_before_opening() {
+        assert!(run(&["} no", " object"]).is_none());
+        assert!(run(&["{\"a\": {", "}"]).is_none());
+    }
+
+    #[test]
+    fn partial_utf8() {
+        let mut tracker = BalancedDelimiters::new('{', '}');
+        let mut output = "{\"é\": ".as_bytes().to_vec();
+        let emoji = "😀".as_bytes();
+        output.extend_from_slice(&emoji[..2]);
+        assert_eq!(tracker.update(&output), None);
+        output.extend_from_slice(&emoji[2..]);
+        output.extend_from_slice(b"}");
+        assert_eq!(tracker.update(&output), Some(output.len()));
+    }
+}
@@ -1,4 +1,9 @@
-use candle_core::{Device, DeviceLocation};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use candle_core::{Device, DeviceLocation, Tensor};
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::EnvFilter;
 
@@ -39,3 +44,48 @@ impl DeviceRepr for Device {
         }
     }
 }
+
+#[derive(Default)]
+struct DebugCapture {
+    layer: Option<usize>,
+    tensors: HashMap<String, Tensor>,
+}
+
+/// Intermediate tensors of one layer of a model, such as the attention scores, the hidden states
+/// and the FFN activations, captured during the next forward pass to debug NaNs, masks or
+/// quantization errors.
+#[derive(Clone, Default)]
+pub struct DebugTensors(Arc<Mutex<DebugCapture>>);
+
+impl DebugTensors {
+    /// Capture the tensors of `layer` during the next forward pass, discarding the tensors
+    /// captured before.
+    pub fn set_layer(&self, layer: usize) {
+        let mut capture = self.0.lock().expect("Poisoned lock");
+        capture.layer = Some(layer);
+        capture.tensors.clear();
+    }
+
+    /// The tensors captured during the last forward pass which reached the layer, by name.
+    pub fn tensors(&self) -> HashMap<String, Tensor> {
+        self.0.lock().expect("Poisoned lock").tensors.clone()
+    }
+
+    /// `Some` if the tensors of `layer` are to be captured.
+    pub(crate) fn for_layer(&self, layer: usize) -> Option<&Self> {
+        (self.0.lock().expect("Poisoned lock").layer == Some(layer)).then_some(self)
+    }
+
+    pub(crate) fn insert(&self, name: &str, tensor: &Tensor) {
+        self.0
+            .lock()
+            .expect("Poisoned lock")
+            .tensors
+            .insert(name.to_string(), tensor.clone());
+    }
+
+    /// Stop capturing once the layer has been computed.
+    pub(crate) fn finish(&self) {
+        self.0.lock().expect("Poisoned lock").layer = None;
+    }
+}
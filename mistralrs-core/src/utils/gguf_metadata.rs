@@ -29,19 +29,27 @@ impl<'a, R: std::io::Seek + std::io::Read> From<&Content<'a, R>> for ContentConf
     fn from(value: &Content<'a, R>) -> Self {
         let metadata = value.get_metadata();
         let arch = metadata["general.architecture"].to_string().unwrap();
+        // Recurrent models such as Mamba and RWKV have no attention heads.
+        let num_attn_heads = metadata
+            .get(&format!("{arch}.attention.head_count"))
+            .map(|x| x.to_u64().unwrap() as usize)
+            .unwrap_or(0);
         Self {
-            max_seq_len: metadata[&format!("{arch}.context_length")]
-                .to_u64()
-                .unwrap() as usize,
+            // Older converters, for example for GPT-NeoX, omit it; the models then use a default
+            // context length of their own.
+            max_seq_len: metadata
+                .get(&format!("{arch}.context_length"))
+                .map(|x| x.to_u64().unwrap() as usize)
+                .unwrap_or_default(),
             hidden_size: metadata[&format!("{arch}.embedding_length")]
                 .to_u64()
                 .unwrap() as usize,
-            num_attn_heads: metadata[&format!("{arch}.attention.head_count")]
-                .to_u64()
-                .unwrap() as usize,
-            num_kv_heads: metadata[&format!("{arch}.attention.head_count_kv")]
-                .to_u64()
-                .unwrap() as usize,
+            num_attn_heads,
+            // Some converters, for example for Jais, omit it for multi-head attention.
+            num_kv_heads: metadata
+                .get(&format!("{arch}.attention.head_count_kv"))
+                .map(|x| x.to_u64().unwrap() as usize)
+                .unwrap_or(num_attn_heads),
             num_layers: metadata[&format!("{arch}.block_count")].to_u64().unwrap() as usize,
             key_length: metadata
                 .get(&format!("{arch}.attention.key_length"))
@@ -70,12 +78,18 @@ impl ModelConfigLike for ContentConfig {
         self.num_layers
     }
     fn k_head_dim(&self) -> usize {
-        self.key_length
-            .unwrap_or(self.hidden_size / self.num_attn_heads)
+        self.key_length.unwrap_or(
+            self.hidden_size
+                .checked_div(self.num_attn_heads)
+                .unwrap_or_default(),
+        )
     }
     fn v_head_dim(&self) -> usize {
-        self.value_length
-            .unwrap_or(self.hidden_size / self.num_attn_heads)
+        self.value_length.unwrap_or(
+            self.hidden_size
+                .checked_div(self.num_attn_heads)
+                .unwrap_or_default(),
+        )
     }
 }
 
@@ -244,6 +258,16 @@ impl DeviceMappedModelLoader for GgufDeviceMapLoaderInner<'_, '_> {
         else {
             anyhow::bail!("Expected text AutoDeviceMapParams for this model!")
         };
+        if self.arch == GGUFArchitecture::Mamba {
+            // The largest activation is the input and gate projection of each token.
+            let inner_size = self.model.get_metadata()["mamba.ssm.inner_size"].to_u32()? as usize;
+            return Ok(max_batch_size * prompt_chunksize * 2 * inner_size);
+        }
+        if self.arch == GGUFArchitecture::Rwkv {
+            // The largest activation is the channel mixing key of each token.
+            let ffn_size = self.model.get_metadata()["rwkv.feed_forward_length"].to_u32()? as usize;
+            return Ok(max_batch_size * prompt_chunksize * ffn_size);
+        }
         let num_heads = self.model.get_metadata()[&format!("{}.attention.head_count", self.arch)]
             .to_u32()? as usize;
         Ok(max_batch_size * num_heads * prompt_chunksize * prompt_chunksize)
@@ -263,7 +287,7 @@ impl DeviceMappedModelLoader for GgufDeviceMapLoaderInner<'_, '_> {
         _weight_pack_factor: usize,
     ) -> Result<usize> {
         let size_in_bytes = match self.arch {
-            GGUFArchitecture::Llama => {
+            GGUFArchitecture::Llama | GGUFArchitecture::Granite => {
                 let token_embd = tensor_info_size_in_bytes!(
                     self.model.tensor_info("token_embd.weight")?,
                     DType::F32
@@ -279,7 +303,7 @@ impl DeviceMappedModelLoader for GgufDeviceMapLoaderInner<'_, '_> {
                 };
                 token_embd + output_norm + output
             }
-            GGUFArchitecture::Phi2 => {
+            GGUFArchitecture::Phi2 | GGUFArchitecture::Falcon => {
                 let token_embd = tensor_info_size_in_bytes!(
                     self.model.tensor_info("token_embd.weight")?,
                     DType::F32
@@ -312,7 +336,14 @@ impl DeviceMappedModelLoader for GgufDeviceMapLoaderInner<'_, '_> {
                 };
                 token_embd + output_norm + output
             }
-            GGUFArchitecture::Qwen2 => {
+            GGUFArchitecture::Qwen2
+            | GGUFArchitecture::ChatGlm
+            | GGUFArchitecture::Gemma
+            | GGUFArchitecture::Gemma3
+            | GGUFArchitecture::Jais
+            | GGUFArchitecture::Mpt
+            | GGUFArchitecture::Gptneox
+            | GGUFArchitecture::Mamba => {
                 let token_embd = tensor_info_size_in_bytes!(
                     self.model.tensor_info("token_embd.weight")?,
                     DType::F32
@@ -328,6 +359,47 @@ impl DeviceMappedModelLoader for GgufDeviceMapLoaderInner<'_, '_> {
                 };
                 token_embd + output_norm + output
             }
+            GGUFArchitecture::Gpt2 => {
+                let token_embd = tensor_info_size_in_bytes!(
+                    self.model.tensor_info("token_embd.weight")?,
+                    DType::F32
+                );
+                let position_embd = tensor_info_size_in_bytes!(
+                    self.model.tensor_info("position_embd.weight")?,
+                    DType::F32
+                );
+                let output_norm = tensor_info_size_in_bytes!(
+                    self.model.tensor_info("output_norm.weight")?,
+                    DType::F32
+                );
+                let output = if !self.model.has_tensor("output.weight") {
+                    tensor_info_size_in_bytes!(self.model.tensor_info("token_embd.weight")?)
+                } else {
+                    tensor_info_size_in_bytes!(self.model.tensor_info("output.weight")?)
+                };
+                token_embd + position_embd + output_norm + output
+            }
+            GGUFArchitecture::Bloom | GGUFArchitecture::Rwkv => {
+                let token_embd = tensor_info_size_in_bytes!(
+                    self.model.tensor_info("token_embd.weight")?,
+                    DType::F32
+                );
+                let mut norms = 0;
+                for name in ["token_embd_norm", "output_norm"] {
+                    for kind in ["weight", "bias"] {
+                        norms += tensor_info_size_in_bytes!(
+                            self.model.tensor_info(&format!("{name}.{kind}"))?,
+                            DType::F32
+                        );
+                    }
+                }
+                let output = if !self.model.has_tensor("output.weight") {
+                    tensor_info_size_in_bytes!(self.model.tensor_info("token_embd.weight")?)
+                } else {
+                    tensor_info_size_in_bytes!(self.model.tensor_info("output.weight")?)
+                };
+                token_embd + norms + output
+            }
             GGUFArchitecture::Starcoder2 => {
                 let token_embd = tensor_info_size_in_bytes!(
                     self.model.tensor_info("token_embd.weight")?,
@@ -384,7 +456,7 @@ impl DeviceMappedModelLoader for GgufDeviceMapLoaderInner<'_, '_> {
                 let n_expert = self
                     .model
                     .get_metadata()
-                    .get("expert_count")
+                    .get(&format!("{}.expert_count", self.arch))
                     .map(|x| x.to_u64().unwrap() as usize)
                     .unwrap_or(0);
                 let moe_or_mlp = if n_expert <= 1 {
@@ -519,6 +591,295 @@ impl DeviceMappedModelLoader for GgufDeviceMapLoaderInner<'_, '_> {
                     + ffn_up
                     + ffn_down
             }
+            GGUFArchitecture::Granite => {
+                let attn_norm = tensor_info_size_in_bytes!(
+                    self.model.tensor_info("blk.0.attn_norm.weight")?,
+                    DType::F32
+                );
+                let ffn_norm = tensor_info_size_in_bytes!(
+                    self.model.tensor_info("blk.0.ffn_norm.weight")?,
+                    DType::F32
+                );
+
+                // The attention projection biases are optional.
+                let mut attn = 0;
+                for name in ["attn_q", "attn_k", "attn_v", "attn_output"] {
+                    attn += tensor_info_size_in_bytes!(self
+                        .model
+                        .tensor_info(&format!("blk.0.{name}.weight"))?);
+                    let bias = format!("blk.0.{name}.bias");
+                    if self.model.has_tensor(&bias) {
+                        attn += tensor_info_size_in_bytes!(self.model.tensor_info(&bias)?);
+                    }
+                }
+
+                let ffn_gate =
+                    tensor_info_size_in_bytes!(self.model.tensor_info("blk.0.ffn_gate.weight")?);
+                let ffn_up =
+                    tensor_info_size_in_bytes!(self.model.tensor_info("blk.0.ffn_up.weight")?);
+                let ffn_down =
+                    tensor_info_size_in_bytes!(self.model.tensor_info("blk.0.ffn_down.weight")?);
+
+                attn_norm + ffn_norm + attn + ffn_gate + ffn_up + ffn_down
+            }
+            GGUFArchitecture::ChatGlm => {
+                let attn_norm = tensor_info_size_in_bytes!(
+                    self.model.tensor_info("blk.0.attn_norm.weight")?,
+                    DType::F32
+                );
+                let ffn_norm = tensor_info_size_in_bytes!(
+                    self.model.tensor_info("blk.0.ffn_norm.weight")?,
+                    DType::F32
+                );
+
+                // The query, key and value projections may be fused, and the gate projection may
+                // be fused with the up projection. The biases are optional.
+                let mut size = 0;
+                for name in [
+                    "attn_qkv",
+                    "attn_q",
+                    "attn_k",
+                    "attn_v",
+                    "attn_output",
+                    "ffn_gate",
+                    "ffn_up",
+                    "ffn_down",
+                ] {
+                    for kind in ["weight", "bias"] {
+                        let tensor = format!("blk.0.{name}.{kind}");
+                        if self.model.has_tensor(&tensor) {
+                            size += tensor_info_size_in_bytes!(self.model.tensor_info(&tensor)?);
+                        }
+                    }
+                }
+
+                attn_norm + ffn_norm + size
+            }
+            GGUFArchitecture::Gemma => {
+                let mut norms = 0;
+                for name in ["attn_norm", "ffn_norm"] {
+                    norms += tensor_info_size_in_bytes!(
+                        self.model.tensor_info(&format!("blk.0.{name}.weight"))?,
+                        DType::F32
+                    );
+                }
+
+                let mut size = 0;
+                for name in [
+                    "attn_q",
+                    "attn_k",
+                    "attn_v",
+                    "attn_output",
+                    "ffn_gate",
+                    "ffn_up",
+                    "ffn_down",
+                ] {
+                    size += tensor_info_size_in_bytes!(self
+                        .model
+                        .tensor_info(&format!("blk.0.{name}.weight"))?);
+                }
+
+                norms + size
+            }
+            GGUFArchitecture::Gemma3 => {
+                let mut norms = 0;
+                for name in [
+                    "attn_norm",
+                    "attn_q_norm",
+                    "attn_k_norm",
+                    "post_attention_norm",
+                    "ffn_norm",
+                    "post_ffw_norm",
+                ] {
+                    norms += tensor_info_size_in_bytes!(
+                        self.model.tensor_info(&format!("blk.0.{name}.weight"))?,
+                        DType::F32
+                    );
+                }
+
+                let mut size = 0;
+                for name in [
+                    "attn_q",
+                    "attn_k",
+                    "attn_v",
+                    "attn_output",
+                    "ffn_gate",
+                    "ffn_up",
+                    "ffn_down",
+                ] {
+                    size += tensor_info_size_in_bytes!(self
+                        .model
+                        .tensor_info(&format!("blk.0.{name}.weight"))?);
+                }
+
+                norms + size
+            }
+            GGUFArchitecture::Jais => {
+                let mut norms = 0;
+                for name in ["attn_norm", "ffn_norm"] {
+                    for kind in ["weight", "bias"] {
+                        norms += tensor_info_size_in_bytes!(
+                            self.model.tensor_info(&format!("blk.0.{name}.{kind}"))?,
+                            DType::F32
+                        );
+                    }
+                }
+
+                let mut size = 0;
+                for name in ["attn_qkv", "attn_output", "ffn_gate", "ffn_up", "ffn_down"] {
+                    for kind in ["weight", "bias"] {
+                        size += tensor_info_size_in_bytes!(self
+                            .model
+                            .tensor_info(&format!("blk.0.{name}.{kind}"))?);
+                    }
+                }
+
+                norms + size
+            }
+            GGUFArchitecture::Gptneox | GGUFArchitecture::Gpt2 | GGUFArchitecture::Bloom => {
+                let mut size = 0;
+                for name in [
+                    "attn_norm",
+                    "ffn_norm",
+                    "attn_qkv",
+                    "attn_output",
+                    "ffn_up",
+                    "ffn_down",
+                ] {
+                    for kind in ["weight", "bias"] {
+                        let tensor = self.model.tensor_info(&format!("blk.0.{name}.{kind}"))?;
+                        size += if name.ends_with("norm") {
+                            tensor_info_size_in_bytes!(tensor, DType::F32)
+                        } else {
+                            tensor_info_size_in_bytes!(tensor)
+                        };
+                    }
+                }
+                size
+            }
+            GGUFArchitecture::Mamba => {
+                let mut size = 0;
+                for name in [
+                    "attn_norm.weight",
+                    "ssm_conv1d.weight",
+                    "ssm_conv1d.bias",
+                    "ssm_dt.bias",
+                    "ssm_a",
+                    "ssm_d",
+                ] {
+                    size += tensor_info_size_in_bytes!(
+                        self.model.tensor_info(&format!("blk.0.{name}"))?,
+                        DType::F32
+                    );
+                }
+                for name in ["ssm_in", "ssm_x", "ssm_dt", "ssm_out"] {
+                    size += tensor_info_size_in_bytes!(self
+                        .model
+                        .tensor_info(&format!("blk.0.{name}.weight"))?);
+                }
+                size
+            }
+            GGUFArchitecture::Rwkv => {
+                let mut size = 0;
+                // The interpolations are fused or separate depending on the converter.
+                for name in [
+                    "attn_norm.weight",
+                    "attn_norm.bias",
+                    "attn_norm_2.weight",
+                    "attn_norm_2.bias",
+                    "time_mix_lerp_x.weight",
+                    "time_mix_lerp_fused.weight",
+                    "time_mix_lerp_w.weight",
+                    "time_mix_lerp_k.weight",
+                    "time_mix_lerp_v.weight",
+                    "time_mix_lerp_r.weight",
+                    "time_mix_lerp_g.weight",
+                    "time_mix_w2.weight",
+                    "time_mix_decay.weight",
+                    "time_mix_first.weight",
+                    "time_mix_ln.weight",
+                    "time_mix_ln.bias",
+                    "channel_mix_lerp_k.weight",
+                    "channel_mix_lerp_r.weight",
+                ] {
+                    let tensor = format!("blk.0.{name}");
+                    if self.model.has_tensor(&tensor) {
+                        size += tensor_info_size_in_bytes!(
+                            self.model.tensor_info(&tensor)?,
+                            DType::F32
+                        );
+                    }
+                }
+                for name in [
+                    "time_mix_w1",
+                    "time_mix_decay_w1",
+                    "time_mix_decay_w2",
+                    "time_mix_key",
+                    "time_mix_value",
+                    "time_mix_receptance",
+                    "time_mix_gate",
+                    "time_mix_output",
+                    "channel_mix_key",
+                    "channel_mix_value",
+                    "channel_mix_receptance",
+                ] {
+                    size += tensor_info_size_in_bytes!(self
+                        .model
+                        .tensor_info(&format!("blk.0.{name}.weight"))?);
+                }
+                size
+            }
+            GGUFArchitecture::Mpt => {
+                // MPT has no biases, but converters write them if the checkpoint has any.
+                let mut size = 0;
+                for name in [
+                    "attn_norm",
+                    "ffn_norm",
+                    "attn_qkv",
+                    "attn_output",
+                    "ffn_up",
+                    "ffn_down",
+                ] {
+                    for kind in ["weight", "bias"] {
+                        let tensor = format!("blk.0.{name}.{kind}");
+                        if self.model.has_tensor(&tensor) {
+                            size += if name.ends_with("norm") {
+                                tensor_info_size_in_bytes!(
+                                    self.model.tensor_info(&tensor)?,
+                                    DType::F32
+                                )
+                            } else {
+                                tensor_info_size_in_bytes!(self.model.tensor_info(&tensor)?)
+                            };
+                        }
+                    }
+                }
+                size
+            }
+            GGUFArchitecture::Falcon => {
+                // Falcon-40B and larger have a separate norm for the MLP input.
+                let mut norms = 0;
+                for name in ["attn_norm", "attn_norm_2"] {
+                    for kind in ["weight", "bias"] {
+                        let tensor = format!("blk.0.{name}.{kind}");
+                        if self.model.has_tensor(&tensor) {
+                            norms += tensor_info_size_in_bytes!(
+                                self.model.tensor_info(&tensor)?,
+                                DType::F32
+                            );
+                        }
+                    }
+                }
+
+                let mut size = 0;
+                for name in ["attn_qkv", "attn_output", "ffn_up", "ffn_down"] {
+                    size += tensor_info_size_in_bytes!(self
+                        .model
+                        .tensor_info(&format!("blk.0.{name}.weight"))?);
+                }
+
+                norms + size
+            }
             GGUFArchitecture::Starcoder2 => {
                 let attn_norm = tensor_info_size_in_bytes!(
                     self.model.tensor_info("blk.0.attn_norm.weight")?,
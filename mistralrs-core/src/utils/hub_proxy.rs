@@ -0,0 +1,268 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use anyhow::{Context, Result};
+use hf_hub::api::RepoInfo;
+use reqwest::blocking::{Client, Response};
+
+/// HTTP proxy for downloads from the Hugging Face Hub.
+///
+/// The blocking hf-hub client only reads its proxy from the environment, so the requests to the
+/// hub go through a client of their own, configured with this proxy.
+#[derive(Clone, Debug)]
+pub(crate) struct HubProxy {
+    url: String,
+    auth: Option<(String, String)>,
+}
+
+/// The hub answered a request made through a proxy with an error status.
+#[derive(Debug)]
+pub(crate) struct HubStatusError {
+    pub(crate) status: u16,
+    url: String,
+}
+
+impl fmt::Display for HubStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The hub answered status code {} for `{}`.",
+            self.status, self.url
+        )
+    }
+}
+
+impl std::error::Error for HubStatusError {}
+
+impl HubProxy {
+    pub(crate) fn new(proxy_url: &str, proxy_auth: Option<(&str, &str)>) -> Self {
+        Self {
+            url: proxy_url.to_string(),
+            auth: proxy_auth.map(|(user, password)| (user.to_string(), password.to_string())),
+        }
+    }
+
+    /// The proxy URL, in the `scheme://host:port` form.
+    fn url(&self) -> Result<String> {
+        let (scheme, host) = match self.url.split_once("://") {
+            Some((scheme, host)) => (scheme, host),
+            None => ("http", self.url.as_str()),
+        };
+        if scheme.starts_with("socks") {
+            anyhow::bail!(
+                "SOCKS proxies are not supported, use an HTTP proxy instead of `{}`.",
+                self.url
+            );
+        }
+        if !matches!(scheme, "http" | "https") {
+            anyhow::bail!("Unsupported proxy scheme `{scheme}` in `{}`.", self.url);
+        }
+        let host = host.trim_end_matches('/');
+        if host.is_empty() || host.contains('/') {
+            anyhow::bail!(
+                "Expected a proxy URL of the form `scheme://host:port`, got `{}`.",
+                self.url
+            );
+        }
+        if self.auth.is_some() && host.contains('@') {
+            anyhow::bail!("Proxy credentials were given both in the URL and separately.");
+        }
+        Ok(format!("{scheme}://{host}"))
+    }
+
+    /// Check the proxy URL, so that an invalid proxy fails before any request.
+    pub(crate) fn validate(&self) -> Result<()> {
+        self.url().map(|_| ())
+    }
+
+    /// The proxy of a client, for all the requests of the client.
+    pub(crate) fn proxy(&self) -> Result<reqwest::Proxy> {
+        let mut proxy = reqwest::Proxy::all(self.url()?)?;
+        if let Some((user, password)) = &self.auth {
+            proxy = proxy.basic_auth(user, password);
+        }
+        Ok(proxy)
+    }
+
+    /// Send a GET request for `url` through the proxy and pass the successful response to `f`.
+    ///
+    /// A blocking client can't be used from within the runtime of the caller, so the request is
+    /// made on a thread of its own.
+    fn get<T: Send + 'static>(
+        &self,
+        url: String,
+        token: Option<String>,
+        f: impl FnOnce(Response) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let proxy = self.proxy()?;
+        thread::spawn(move || {
+            let client = Client::builder().proxy(proxy).build()?;
+            let mut request = client.get(&url);
+            if let Some(token) = token {
+                request = request.bearer_auth(token);
+            }
+            let response = request
+                .send()
+                .with_context(|| format!("Could not request `{url}` through the proxy"))?;
+            let status = response.status();
+            if !status.is_success() {
+                return Err(HubStatusError {
+                    status: status.as_u16(),
+                    url,
+                }
+                .into());
+            }
+            f(response)
+        })
+        .join()
+        .map_err(|_| anyhow::anyhow!("The request through the proxy panicked."))?
+    }
+
+    /// Files and commit of a repository, from the `info_url` of the hub API.
+    pub(crate) fn info(&self, info_url: String, token: Option<String>) -> Result<RepoInfo> {
+        self.get(info_url, token, |response| {
+            Ok(serde_json::from_reader(response)?)
+        })
+    }
+
+    /// Download `url` to `path`, through a temporary file so that an interrupted download does
+    /// not leave a truncated file behind.
+    pub(crate) fn download(&self, url: String, token: Option<String>, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".part");
+        let partial = PathBuf::from(partial);
+        let destination = partial.clone();
+        let downloaded = self.get(url, token, move |mut response| {
+            response.copy_to(&mut fs::File::create(&destination)?)?;
+            Ok(())
+        });
+        if downloaded.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+        downloaded?;
+        fs::rename(&partial, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use super::{HubProxy, HubStatusError};
+
+    #[test]
+    fn proxy_url() {
+        let proxy = HubProxy::new("http://proxy.corp:3128/", None);
+        assert_eq!(proxy.url().unwrap(), "http://proxy.corp:3128");
+
+        // The scheme defaults to HTTP, and credentials are not part of the URL.
+        let proxy = HubProxy::new("proxy.corp:3128", Some(("al:ice", "s3c@ret")));
+        assert_eq!(proxy.url().unwrap(), "http://proxy.corp:3128");
+
+        for (url, auth) in [
+            ("ftp://proxy.corp:21", None),
+            ("socks5://proxy.corp:1080", None),
+            ("socks4a://proxy.corp:1080", None),
+            ("http://proxy.corp:3128/path", None),
+            ("http://bob:pw@proxy.corp:3128", Some(("alice", "s3cret"))),
+        ] {
+            assert!(HubProxy::new(url, auth).validate().is_err(), "{url}");
+        }
+    }
+
+    /// Accept one connection on `listener`, answer it with `status` and `body`, and return the
+    /// request line and the `Proxy-Authorization` header.
+    fn serve_once(
+        listener: TcpListener,
+        status: &'static str,
+        body: &'static str,
+    ) -> thread::JoinHandle<(String, Option<String>)> {
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut auth = None;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(": ") {
+                    if name.eq_ignore_ascii_case("proxy-authorization") {
+                        auth = Some(value.to_string());
+                    }
+                }
+            }
+            let mut stream = reader.into_inner();
+            write!(
+                stream,
+                "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+            (request_line.trim_end().to_string(), auth)
+        })
+    }
+
+    #[test]
+    fn requests_go_through_the_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = serve_once(listener, "200 OK", "weights");
+
+        let dir = std::env::temp_dir().join(format!("mistralrs_hub_proxy_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("snapshots").join("abc").join("model.gguf");
+        let proxy = HubProxy::new(&format!("http://{addr}"), Some(("alice", "s3cret")));
+        proxy
+            .download(
+                "http://hub.invalid/org/model/resolve/abc/model.gguf".to_string(),
+                None,
+                &path,
+            )
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "weights");
+
+        // The proxy receives the request for the hub, with the credentials of the proxy.
+        let (request_line, auth) = server.join().unwrap();
+        assert_eq!(
+            request_line,
+            "GET http://hub.invalid/org/model/resolve/abc/model.gguf HTTP/1.1"
+        );
+        // `alice:s3cret` in base64.
+        assert_eq!(auth.as_deref(), Some("Basic YWxpY2U6czNjcmV0"));
+
+        // An error status of the hub is kept, and no file is left behind.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = serve_once(listener, "403 Forbidden", "");
+        let proxy = HubProxy::new(&format!("http://{addr}"), None);
+        let missing = dir.join("snapshots").join("abc").join("gated.gguf");
+        let err = proxy
+            .download(
+                "http://hub.invalid/org/model/resolve/abc/gated.gguf".to_string(),
+                None,
+                &missing,
+            )
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<HubStatusError>().unwrap().status, 403);
+        assert!(!missing.exists());
+        server.join().unwrap();
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
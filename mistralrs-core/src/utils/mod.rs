@@ -1,10 +1,13 @@
+pub(crate) mod balanced;
 pub(crate) mod debug;
 pub(crate) mod gguf_metadata;
+pub(crate) mod hub_proxy;
 pub(crate) mod log;
 pub(crate) mod memory_usage;
 pub(crate) mod model_config;
 pub(crate) mod normal;
 pub(crate) mod progress;
+pub(crate) mod reasoning;
 pub(crate) mod tokenizer;
 pub(crate) mod tokens;
 pub(crate) mod unvarbuilder;
@@ -85,15 +88,15 @@ macro_rules! handle_pipeline_forward_error {
         match $fallible {
             Ok(v) => v,
             Err(e) => {
-                let (tokenizer, pipeline_name) = {
+                let (tokenizer, pipeline_name, system_fingerprint) = {
                     let pipeline = get_mut_arcmutex!($pipeline);
                     let pipeline_name = pipeline.name();
                     let tokenizer = pipeline.tokenizer();
-                    (tokenizer, pipeline_name)
+                    let system_fingerprint = pipeline.get_metadata().system_fingerprint.clone();
+                    (tokenizer, pipeline_name, system_fingerprint)
                 };
                 use $crate::response::Response;
                 use $crate::sequence::SequenceState;
-                use $crate::response::SYSTEM_FINGERPRINT;
                 use tracing::error;
                 error!("{} - Model failed with error: {:?}", $stage, &e);
                 for seq in $seq_slice.iter_mut() {
@@ -117,6 +120,7 @@ macro_rules! handle_pipeline_forward_error {
                                 tool_calls: None,
                             },
                             logprobs: None,
+                            stop_reason: None,
                         };
                         seq.add_choice_to_group(choice);
                     } else {
@@ -125,6 +129,7 @@ macro_rules! handle_pipeline_forward_error {
                             index: seq.get_response_index(),
                             text: res,
                             logprobs: None,
+                            stop_reason: None,
                         };
                         seq.add_completion_choice_to_group(choice);
                     }
@@ -139,9 +144,13 @@ macro_rules! handle_pipeline_forward_error {
                             choices: group.get_choices().to_vec(),
                             created: seq.creation_time(),
                             model: pipeline_name.clone(),
-                            system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                            system_fingerprint: system_fingerprint.clone(),
                             object: "chat.completion".to_string(),
                             usage: group.get_usage(),
+                            effective_params: group.effective_params.clone(),
+                            documents: group.documents.clone(),
+                            adapters: group.adapters.clone(),
+                            routing: None,
                         };
 
                         seq.responder()
@@ -157,9 +166,12 @@ macro_rules! handle_pipeline_forward_error {
                             choices: group.get_completion_choices().to_vec(),
                             created: seq.creation_time(),
                             model: pipeline_name.clone(),
-                            system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                            system_fingerprint: system_fingerprint.clone(),
                             object: "text_completion".to_string(),
                             usage: group.get_usage(),
+                            effective_params: group.effective_params.clone(),
+                            adapters: group.adapters.clone(),
+                            routing: None,
                         };
 
                         seq.responder()
@@ -295,12 +295,24 @@ impl<R: std::io::Seek + std::io::Read> Config<ParamsGGUF<'_, R>, Adapter<'_>> {
 }
 
 use crate::{
+    models::quantized_bloom::ModelWeights as QBloom,
+    models::quantized_chatglm::ModelWeights as QChatGlm,
+    models::quantized_falcon::ModelWeights as QFalcon,
+    models::quantized_gemma::ModelWeights as QGemma,
+    models::quantized_gemma3::ModelWeights as QGemma3,
+    models::quantized_gpt2::ModelWeights as QGpt2,
+    models::quantized_gptneox::ModelWeights as QGptNeox,
+    models::quantized_granite::ModelWeights as QGranite,
+    models::quantized_jais::ModelWeights as QJais,
     models::quantized_llama::ModelWeights as QLlama,
+    models::quantized_mamba::ModelWeights as QMamba,
+    models::quantized_mpt::ModelWeights as QMpt,
     models::quantized_phi2::ModelWeights as QPhi,
     models::quantized_phi3::ModelWeights as QPhi3,
     models::quantized_qwen2::ModelWeights as QQwen2,
+    models::quantized_rwkv::ModelWeights as QRwkv,
     models::quantized_starcoder2::ModelWeights as QStarcoder2,
-    xlora_models::{XLoraQLlama, XLoraQPhi3},
+    xlora_models::{XLoraQLlama, XLoraQPhi2, XLoraQPhi3},
 };
 use akin::akin;
 
@@ -323,7 +335,7 @@ impl TryFrom<ModelParams<'_, ParamsGGML>> for XLoraQLlama {
 }
 
 akin! {
-    let &models_gguf = [QLlama, QPhi, QPhi3, QStarcoder2, QQwen2];
+    let &models_gguf = [QLlama, QPhi, QPhi3, QStarcoder2, QQwen2, QGranite, QChatGlm, QGemma, QGemma3, QJais, QFalcon, QMpt, QGptNeox, QGpt2, QBloom, QMamba, QRwkv];
 
     impl<R: std::io::Seek + std::io::Read> TryFrom<ModelParams<'_, ParamsGGUF<'_, R>>> for *models_gguf {
         type Error = candle_core::Error;
@@ -336,7 +348,7 @@ akin! {
 }
 
 akin! {
-    let &models_gguf_a = [XLoraQLlama, XLoraQPhi3];
+    let &models_gguf_a = [XLoraQLlama, XLoraQPhi2, XLoraQPhi3];
 
     impl<R: std::io::Seek + std::io::Read> TryFrom<ModelParams<'_, ParamsGGUF<'_, R>>> for *models_gguf_a {
         type Error = candle_core::Error;
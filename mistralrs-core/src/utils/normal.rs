@@ -4,7 +4,7 @@ use std::{fmt::Display, str::FromStr};
 use anyhow::Result;
 use candle_core::{DType, Device, Tensor};
 use serde::Deserialize;
-use tracing::info;
+use tracing::{info, warn};
 
 #[derive(Clone, Copy, Default, Debug, Deserialize, PartialEq)]
 #[cfg_attr(feature = "pyo3_macros", pyo3::pyclass(eq, eq_int))]
@@ -116,49 +116,86 @@ fn get_dtypes() -> Vec<DType> {
     get_dtypes_non_cuda()
 }
 
+/// Whether all devices can run a matmul in `dtype`.
+#[cfg_attr(feature = "accelerate", allow(dead_code))]
+fn devices_support_dtype(dtype: DType, devices: &[&Device]) -> candle_core::Result<bool> {
+    if dtype == DType::F32 {
+        return Ok(true);
+    }
+    if !get_dtypes().contains(&dtype) {
+        return Ok(false);
+    }
+    for device in devices {
+        // Try a matmul
+        let x = Tensor::zeros((2, 2), dtype, device)?;
+        match x.matmul(&x) {
+            Ok(_) => (),
+            // For CUDA
+            Err(candle_core::Error::UnsupportedDTypeForOp(_, _)) => return Ok(false),
+            // Accelerate backend doesn't support f16/bf16
+            // Metal backend doesn't support f16
+            Err(candle_core::Error::Msg(_)) => return Ok(false),
+            // This is when the metal backend doesn't support bf16
+            Err(candle_core::Error::Metal(_)) => return Ok(false),
+            // If running with RUST_BACKTRACE=1
+            Err(candle_core::Error::WithBacktrace { .. }) => return Ok(false),
+            Err(other) => return Err(other),
+        }
+    }
+    Ok(true)
+}
+
 fn determine_auto_dtype_all(devices: &[&Device]) -> candle_core::Result<DType> {
     // We can safely use bf16 for accelerate because we cast up to f32 in all matmuls anyway.
     #[cfg(feature = "accelerate")]
     return Ok(DType::BF16);
     #[cfg(not(feature = "accelerate"))]
     {
-        let dev_dtypes = get_dtypes();
-        for dtype in get_dtypes_non_cuda()
-            .iter()
-            .filter(|x| dev_dtypes.contains(x))
-        {
-            let mut results = Vec::new();
-            for device in devices {
-                // Try a matmul
-                let x = Tensor::zeros((2, 2), *dtype, device)?;
-                results.push(x.matmul(&x));
-            }
-            if results.iter().all(|x| x.is_ok()) {
-                return Ok(*dtype);
-            } else {
-                for result in results {
-                    match result {
-                        Ok(_) => (),
-                        Err(e) => match e {
-                            // For CUDA
-                            candle_core::Error::UnsupportedDTypeForOp(_, _) => continue,
-                            // Accelerate backend doesn't support f16/bf16
-                            // Metal backend doesn't support f16
-                            candle_core::Error::Msg(_) => continue,
-                            // This is when the metal backend doesn't support bf16
-                            candle_core::Error::Metal(_) => continue,
-                            // If running with RUST_BACKTRACE=1
-                            candle_core::Error::WithBacktrace { .. } => continue,
-                            other => return Err(other),
-                        },
-                    }
-                }
+        for dtype in get_dtypes_non_cuda() {
+            if devices_support_dtype(dtype, devices)? {
+                return Ok(dtype);
             }
         }
         Ok(DType::F32)
     }
 }
 
+/// `dtype` if it is supported, otherwise the best supported fallback: F16 for BF16, and F32 for
+/// anything else.
+#[cfg_attr(feature = "accelerate", allow(dead_code))]
+fn fallback_dtype(dtype: DType, mut supported: impl FnMut(DType) -> bool) -> DType {
+    [dtype, DType::F16, DType::F32]
+        .into_iter()
+        .filter(|fallback| fallback.size_in_bytes() >= dtype.size_in_bytes())
+        .find(|fallback| *fallback == DType::F32 || supported(*fallback))
+        .unwrap_or(dtype)
+}
+
+/// DType to load the LoRA adapter weights in on these devices: `dtype` if the devices support it
+/// natively, otherwise it is converted to F16 or F32 with a warning. For example, BF16 runs slowly
+/// emulated or not at all on CUDA devices before Ampere. The base model keeps its dtype.
+pub(crate) fn adapter_dtype(dtype: DType, devices: &[&Device]) -> candle_core::Result<DType> {
+    #[cfg(feature = "accelerate")]
+    return Ok(dtype);
+    #[cfg(not(feature = "accelerate"))]
+    {
+        let mut error = None;
+        let selected = fallback_dtype(dtype, |dtype| {
+            devices_support_dtype(dtype, devices).unwrap_or_else(|e| {
+                error.get_or_insert(e);
+                false
+            })
+        });
+        if let Some(e) = error {
+            return Err(e);
+        }
+        if selected != dtype {
+            warn!("Adapter dtype {dtype:?} is not supported by the device, converting the adapter weights to {selected:?}.");
+        }
+        Ok(selected)
+    }
+}
+
 impl TryIntoDType for ModelDType {
     fn try_into_dtype(&self, devices: &[&Device]) -> Result<DType> {
         let dtype = match self {
@@ -171,3 +208,26 @@ impl TryIntoDType for ModelDType {
         dtype
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::DType;
+
+    use super::fallback_dtype;
+
+    #[test]
+    fn adapter_dtype_fallback() {
+        // A pre-Ampere CUDA device supports F16 but not BF16.
+        let pre_ampere = |dtype| dtype == DType::F16;
+        assert_eq!(fallback_dtype(DType::BF16, pre_ampere), DType::F16);
+        assert_eq!(fallback_dtype(DType::F16, pre_ampere), DType::F16);
+        assert_eq!(fallback_dtype(DType::F32, pre_ampere), DType::F32);
+
+        let no_half = |_| false;
+        assert_eq!(fallback_dtype(DType::BF16, no_half), DType::F32);
+        assert_eq!(fallback_dtype(DType::F16, no_half), DType::F32);
+
+        let all = |_| true;
+        assert_eq!(fallback_dtype(DType::BF16, all), DType::BF16);
+    }
+}
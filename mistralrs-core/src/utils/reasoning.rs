@@ -0,0 +1,101 @@
+const THINK_OPEN: &str = "<think>";
+const THINK_CLOSE: &str = "</think>";
+
+/// Counts the generated tokens of the think blocks of reasoning models, delimited by `<think>`
+/// and `</think>`, so that the reasoning tokens can be reported apart from those of the answer.
+///
+/// A token is a reasoning token if any of its text is inside a think block, including the tokens
+/// of the delimiters.
+#[derive(Clone, Debug)]
+pub struct ReasoningTokens {
+    in_think: bool,
+    /// Number of bytes of the output which have been searched for delimiters.
+    offset: usize,
+    count: usize,
+}
+
+impl ReasoningTokens {
+    /// `prompt` is the rendered prompt, as the chat templates of some models open the think block
+    /// at the end of the prompt.
+    pub fn new(prompt: &str) -> Self {
+        Self {
+            in_think: prompt.trim_end().ends_with(THINK_OPEN),
+            offset: 0,
+            count: 0,
+        }
+    }
+
+    /// Process the text of a new token. `output` is the entire output so far.
+    pub fn update(&mut self, output: &[u8]) {
+        let mut reasoning = self.in_think;
+        loop {
+            let delimiter = if self.in_think {
+                THINK_CLOSE
+            } else {
+                THINK_OPEN
+            };
+            let Some(pos) = output[self.offset..]
+                .windows(delimiter.len())
+                .position(|window| window == delimiter.as_bytes())
+            else {
+                break;
+            };
+            self.offset += pos + delimiter.len();
+            self.in_think = !self.in_think;
+            reasoning = true;
+        }
+        // A delimiter can be split over tokens, so the end of the output is searched again.
+        self.offset = self
+            .offset
+            .max(output.len().saturating_sub(THINK_CLOSE.len() - 1));
+        if reasoning {
+            self.count += 1;
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReasoningTokens;
+
+    fn count(prompt: &str, tokens: &[&str]) -> usize {
+        let mut reasoning = ReasoningTokens::new(prompt);
+        let mut output = Vec::new();
+        for token in tokens {
+            output.extend_from_slice(token.as_bytes());
+            reasoning.update(&output);
+        }
+        reasoning.count()
+    }
+
+    #[test]
+    fn counts_think_block_apart_from_answer() {
+        let mut tokens = vec!["<think>"];
+        tokens.extend([" step"; 48]);
+        tokens.push("</think>");
+        tokens.extend([" answer"; 20]);
+        assert_eq!(tokens.len(), 70);
+
+        let reasoning = count("<|im_start|>assistant\n", &tokens);
+        assert_eq!(reasoning, 50);
+        assert_eq!(tokens.len() - reasoning, 20);
+    }
+
+    #[test]
+    fn think_block_opened_by_prompt() {
+        let mut tokens = vec![" step"; 10];
+        tokens.extend(["</", "think>", "\n\n", "Paris"]);
+        assert_eq!(count("<|Assistant|><think>\n", &tokens), 12);
+    }
+
+    #[test]
+    fn no_think_block() {
+        assert_eq!(count("", &["The", " answer", " is", " 4"]), 0);
+        // A closed think block at the end of the prompt disables thinking.
+        assert_eq!(count("<think>\n\n</think>\n\n", &["Hi", "!"]), 0);
+    }
+}
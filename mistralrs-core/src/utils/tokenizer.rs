@@ -1,9 +1,10 @@
-use std::{collections::HashMap, path::Path};
+use std::{collections::HashMap, path::Path, str::FromStr};
 
 use anyhow::Result;
 use serde::Deserialize;
-use serde_json::Value;
+use serde_json::{json, Value};
 use tokenizers::{tokenizer, Tokenizer};
+use tracing::warn;
 
 #[derive(Deserialize)]
 struct AddedToken {
@@ -18,11 +19,11 @@ pub(crate) fn get_tokenizer<P: AsRef<Path> + Clone>(
 ) -> Result<Tokenizer> {
     let mut tokenizer = {
         let raw = std::fs::read(p.clone()).map_err(anyhow::Error::msg)?;
-        let mut tokenizer: Value = serde_json::from_slice(&raw).unwrap();
+        let mut tokenizer: Value = serde_json::from_slice(&raw)?;
         let added_tokens: Vec<AddedToken> =
-            serde_json::from_value(tokenizer["added_tokens"].clone()).unwrap();
+            serde_json::from_value(tokenizer["added_tokens"].clone())?;
         let vocab: HashMap<String, usize> =
-            serde_json::from_value(tokenizer["model"]["vocab"].clone()).unwrap();
+            serde_json::from_value(tokenizer["model"]["vocab"].clone())?;
         for token in added_tokens {
             if !vocab.contains_key(&token.content) {
                 tokenizer["model"]["vocab"]
@@ -46,3 +47,112 @@ pub(crate) fn get_tokenizer<P: AsRef<Path> + Clone>(
     }
     Ok(tokenizer)
 }
+
+/// Force byte fallback of the tokenizer model on or off, overriding the tokenizer's default. With
+/// byte fallback, characters which are not in the vocabulary are encoded as the tokens of their
+/// UTF-8 bytes (`<0x..>`) instead of the unknown token.
+pub(crate) fn set_byte_fallback(
+    tokenizer: Tokenizer,
+    byte_fallback: Option<bool>,
+) -> Result<Tokenizer> {
+    let Some(byte_fallback) = byte_fallback else {
+        return Ok(tokenizer);
+    };
+    let mut raw: Value =
+        serde_json::from_str(&tokenizer.to_string(false).map_err(anyhow::Error::msg)?)?;
+    let model_type = raw["model"]["type"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+    if !matches!(model_type.as_str(), "BPE" | "Unigram") {
+        warn!("Byte fallback is not supported by {model_type} tokenizers, ignoring the override.");
+        return Ok(tokenizer);
+    }
+    if raw["model"]["byte_fallback"].as_bool() == Some(byte_fallback) {
+        return Ok(tokenizer);
+    }
+    raw["model"]["byte_fallback"] = byte_fallback.into();
+
+    // The byte tokens must also be decoded back into characters.
+    if byte_fallback {
+        let byte_fallback_decoder = json!({ "type": "ByteFallback" });
+        let decoder = &mut raw["decoder"];
+        match decoder["type"].as_str() {
+            None => *decoder = byte_fallback_decoder,
+            Some("ByteFallback") => (),
+            Some("Sequence") => {
+                let decoders = decoder["decoders"]
+                    .as_array_mut()
+                    .ok_or_else(|| anyhow::anyhow!("Sequence decoder without decoders"))?;
+                if !decoders.iter().any(|d| d["type"] == "ByteFallback") {
+                    decoders.insert(0, byte_fallback_decoder);
+                }
+            }
+            Some(_) => {
+                *decoder = json!({
+                    "type": "Sequence",
+                    "decoders": [byte_fallback_decoder, decoder.take()],
+                })
+            }
+        }
+    }
+
+    Tokenizer::from_str(&raw.to_string()).map_err(anyhow::Error::msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use serde_json::json;
+    use tokenizers::Tokenizer;
+
+    use super::set_byte_fallback;
+
+    /// A BPE tokenizer without byte fallback, with the byte tokens of `é` in its vocabulary.
+    fn tokenizer() -> Tokenizer {
+        let raw = json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [],
+            "normalizer": null,
+            "pre_tokenizer": null,
+            "post_processor": null,
+            "decoder": null,
+            "model": {
+                "type": "BPE",
+                "dropout": null,
+                "unk_token": "<unk>",
+                "continuing_subword_prefix": null,
+                "end_of_word_suffix": null,
+                "fuse_unk": false,
+                "byte_fallback": false,
+                "vocab": { "<unk>": 0, "<0xC3>": 1, "<0xA9>": 2, "a": 3 },
+                "merges": []
+            }
+        });
+        Tokenizer::from_str(&raw.to_string()).unwrap()
+    }
+
+    fn encode(tokenizer: &Tokenizer, text: &str) -> Vec<String> {
+        tokenizer.encode(text, false).unwrap().get_tokens().to_vec()
+    }
+
+    #[test]
+    fn byte_fallback_override() {
+        assert_eq!(encode(&tokenizer(), "aé"), ["a", "<unk>"]);
+
+        let forced_on = set_byte_fallback(tokenizer(), Some(true)).unwrap();
+        assert_eq!(encode(&forced_on, "aé"), ["a", "<0xC3>", "<0xA9>"]);
+        let ids = forced_on.encode("aé", false).unwrap().get_ids().to_vec();
+        assert_eq!(forced_on.decode(&ids, false).unwrap(), "aé");
+
+        let forced_off = set_byte_fallback(forced_on, Some(false)).unwrap();
+        assert_eq!(encode(&forced_off, "aé"), ["a", "<unk>"]);
+
+        // Without an override, the tokenizer's default is kept.
+        let unchanged = set_byte_fallback(tokenizer(), None).unwrap();
+        assert_eq!(encode(&unchanged, "aé"), ["a", "<unk>"]);
+    }
+}
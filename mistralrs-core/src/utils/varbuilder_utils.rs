@@ -12,6 +12,7 @@ use mistralrs_quant::{safetensors::MmapedSafetensors, ShardedSafeTensors, Sharde
 use regex::Regex;
 
 use crate::lora::LoraConfig;
+use crate::scheduler::LoraAdapterCache;
 use crate::utils::progress::IterWithProgress;
 use derive_new::new;
 
@@ -179,6 +180,25 @@ pub(crate) fn from_mmaped_safetensors(
     ))
 }
 
+/// Load all tensors of a single LoRA adapter onto `device`.
+pub(crate) fn load_adapter_tensors(
+    path: &PathBuf,
+    dtype: DType,
+    device: &Device,
+    silent: bool,
+) -> Result<HashMap<String, Tensor>> {
+    Common::new().load_tensors_from_path(
+        path,
+        device,
+        vec![None],
+        Arc::new(|_| DeviceForLoadTensor::Base),
+        Some(dtype),
+        silent,
+        |_| true,
+        |_| false,
+    )
+}
+
 pub(crate) fn load_preload_adapters(
     paths: &Option<HashMap<String, (PathBuf, LoraConfig)>>,
     dtype: DType,
@@ -186,25 +206,16 @@ pub(crate) fn load_preload_adapters(
     silent: bool,
 ) -> Result<Option<HashMap<String, (ShardedVarBuilder, LoraConfig)>>> {
     if let Some(paths) = paths {
+        // Adapters which are still cached from an earlier load are not read from disk again.
+        let cache = LoraAdapterCache::global();
+        let mut cache = cache.lock().expect("Adapter cache was poisoned");
         let mut map = HashMap::new();
         for (name, (path, config)) in paths {
-            let loader = Common::new();
-            let loaded_tensors = loader.load_tensors_from_path(
-                path,
-                device,
-                vec![None],
-                Arc::new(|_| DeviceForLoadTensor::Base),
-                Some(dtype),
-                silent,
-                |_| true,
-                |_| false,
-            )?;
-
-            let backend = Box::new(loaded_tensors);
+            let weights = cache.get_or_load(name, path, config, dtype, device, silent)?;
 
             // TODO(EricLBuehler): separation of concerns.
             // This is to have WNA16 for GPTQ which is required. No bf16 for GPTQ
-            let vb = ShardedSafeTensors::wrap(backend, dtype, device.clone());
+            let vb = weights.var_builder(dtype, device);
 
             map.insert(name.clone(), (vb, config.clone()));
         }
@@ -300,15 +311,28 @@ impl LoadTensors for XLora {
         &self,
         tensors: impl Iterator<Item = String>,
     ) -> impl Iterator<Item = (String, String)> {
-        let expectation = "tensor name `{new_name}` should have substring `.lora`";
+        // The index of the adapter follows the name of the adapter tensor, as in
+        // `lora_A.{index}.weight`. The VeRA projections are shared by all layers.
+        const ADAPTER_TENSORS: [&str; 6] = [
+            ".lora_A",
+            ".lora_B",
+            ".vera_lambda_d",
+            ".vera_lambda_b",
+            ".vera_A",
+            ".vera_B",
+        ];
 
         tensors
             .filter(|name| !name.contains("internal_xlora_classifier"))
             .map(|name| {
                 let mut new_name = name.replace("base_model.model.model", "model");
-                // TODO: Add better context to describe intent / requirement:
-                let pos = new_name.find(".lora").expect(expectation);
-                new_name.insert_str(pos + 7, &format!(".{}", self.adapter_index));
+                let end = ADAPTER_TENSORS
+                    .iter()
+                    .find_map(|tensor| new_name.find(tensor).map(|pos| pos + tensor.len()))
+                    .unwrap_or_else(|| {
+                        panic!("Tensor `{name}` is not a tensor of a LoRA or VeRA adapter.")
+                    });
+                new_name.insert_str(end, &format!(".{}", self.adapter_index));
 
                 (name, new_name)
             })
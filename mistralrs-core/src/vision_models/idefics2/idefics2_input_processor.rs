@@ -66,6 +66,7 @@ impl Processor for Idefics2Processor {
         add_generation_prompt: bool,
         add_special_tokens: bool,
         tools: Vec<Tool>,
+        enable_thinking: Option<bool>,
     ) -> anyhow::Result<(Vec<u32>, String)> {
         let mut prompt = apply_chat_template(
             pipeline,
@@ -73,6 +74,7 @@ impl Processor for Idefics2Processor {
             add_generation_prompt,
             self.template_action(),
             tools,
+            enable_thinking,
         )?;
 
         let mut image_str = format!(
@@ -191,6 +191,7 @@ impl InputsProcessor for Phi3InputsProcessor {
                             paged_attn_meta,
                             flash_meta,
                             flash_meta_full: _,
+                            adapter_scalings: _,
                         } = *inputs
                             .downcast::<text_models_inputs_processor::ModelInputs>()
                             .expect("Downcast failed.");
@@ -5,7 +5,7 @@ use mistralrs_quant::ShardedVarBuilder;
 
 use crate::ops::{TopKLastDimOp, TopKOutput};
 
-use super::config::XLoraConfig;
+use super::config::{XLoraConfig, XLoraRegularization};
 
 #[derive(Debug)]
 struct TemperatureScaledSoftmax {
@@ -18,6 +18,22 @@ impl Module for TemperatureScaledSoftmax {
     }
 }
 
+impl XLoraRegularization {
+    /// Penalize the mixing logits over the adapters, on the last dimension.
+    ///
+    /// Minimizing `|z - x|^2 / 2 + l2_coeff * |z|^2 / 2` gives the logits `x / (1 + l2_coeff)`.
+    /// The softmax maximizes `<p, x> + H(p)`, so weighting the entropy by `1 + entropy_coeff`
+    /// gives the weights `softmax(x / (1 + entropy_coeff))`. The logits are scaled around their
+    /// mean, which the softmax does not see, so that without a softmax they are still pulled
+    /// towards a uniform mix.
+    pub(crate) fn forward(&self, logits: &Tensor) -> Result<Tensor> {
+        let logits = (logits / (1. + self.l2_coeff as f64))?;
+        let mean = logits.mean_keepdim(D::Minus1)?;
+        let spread = (logits.broadcast_sub(&mean)? / (1. + self.entropy_coeff as f64))?;
+        spread.broadcast_add(&mean)
+    }
+}
+
 pub struct XLoraClassifier {
     last: Linear,
     inner: Vec<Box<dyn ModuleT + Send + Sync>>,
@@ -39,6 +55,17 @@ impl XLoraClassifier {
         if config.enable_softmax_topk {
             candle_core::bail!("`enable_softmax_topk` is not implemented");
         }
+        if let Some(XLoraRegularization {
+            entropy_coeff,
+            l2_coeff,
+        }) = config.regularization
+        {
+            if entropy_coeff < 0. || l2_coeff < 0. {
+                candle_core::bail!(
+                    "X-LoRA regularization coefficients must be non-negative, got `entropy_coeff` {entropy_coeff} and `l2_coeff` {l2_coeff}."
+                );
+            }
+        }
 
         let (last, inner): (Linear, Vec<Box<dyn ModuleT + Send + Sync>>) = if config.xlora_depth
             == 1
@@ -280,6 +307,9 @@ impl XLoraClassifier {
             self.model_layers,
             self.n_classes,
         ))?;
+        if let Some(ref regularization) = self.config.regularization {
+            scalings = regularization.forward(&scalings)?;
+        }
         if let Some(ref softmax) = self.softmax {
             scalings = softmax.forward(&scalings)?;
         }
@@ -319,3 +349,57 @@ impl XLoraClassifier {
         self.config.global_scaling_weight
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+    use candle_nn::ops::softmax_last_dim;
+
+    use crate::xlora_models::config::XLoraRegularization;
+
+    fn weights(regularization: &XLoraRegularization, logits: &Tensor) -> Vec<f32> {
+        softmax_last_dim(&regularization.forward(logits).unwrap())
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .to_vec1()
+            .unwrap()
+    }
+
+    #[test]
+    fn regularization_flattens_mixing() -> candle_core::Result<()> {
+        let logits = Tensor::new(&[[[[2f32, 0., -1.]]]], &Device::Cpu)?;
+        let none = weights(&XLoraRegularization::default(), &logits);
+        let expected = softmax_last_dim(&logits)?.flatten_all()?.to_vec1::<f32>()?;
+        for (a, b) in none.iter().zip(&expected) {
+            assert!((a - b).abs() < 1e-6);
+        }
+
+        // Entropy regularization is a higher softmax temperature.
+        let entropy = weights(
+            &XLoraRegularization {
+                entropy_coeff: 1.,
+                l2_coeff: 0.,
+            },
+            &logits,
+        );
+        let expected = softmax_last_dim(&(&logits / 2.)?)?
+            .flatten_all()?
+            .to_vec1::<f32>()?;
+        for (a, b) in entropy.iter().zip(&expected) {
+            assert!((a - b).abs() < 1e-6);
+        }
+        assert!(entropy[0] < none[0] && entropy[2] > none[2]);
+
+        // Both move the mix further towards uniform.
+        let both = weights(
+            &XLoraRegularization {
+                entropy_coeff: 1.,
+                l2_coeff: 1.,
+            },
+            &logits,
+        );
+        assert!(both[0] < entropy[0] && both[0] > 1. / 3.);
+        Ok(())
+    }
+}
@@ -59,4 +59,18 @@ pub struct XLoraConfig {
     pub top_k_lora: Option<usize>,
     #[serde(default = "false_default")]
     pub enable_softmax_topk: bool,
+    #[serde(default)]
+    pub regularization: Option<XLoraRegularization>,
+}
+
+/// Soft constraints on the mixing logits of the classifier, applied at inference.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct XLoraRegularization {
+    /// Entropy regularization of the mixing weights, which pulls them towards a uniform mix of
+    /// the adapters. A coefficient `c` divides the spread of the logits by `1 + c`.
+    #[serde(default)]
+    pub entropy_coeff: f32,
+    /// L2 regularization of the mixing logits, which shrinks them by `1 + c`.
+    #[serde(default)]
+    pub l2_coeff: f32,
 }
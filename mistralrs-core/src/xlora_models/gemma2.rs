@@ -720,6 +720,7 @@ impl Model {
         context_lens: Vec<(usize, usize)>,
         flash_params: &FlashParams,
         flash_params_full: &FlashParams,
+        adapter_scalings: Option<Tensor>,
     ) -> Result<Tensor> {
         if self.xlora_classifier.is_some() {
             let scalings = self.get_scalings(
@@ -779,7 +780,7 @@ impl Model {
                 .inner_forward(
                     input_ids,
                     seqlen_offsets,
-                    None,
+                    adapter_scalings,
                     false,
                     no_kv_cache,
                     None,
@@ -880,6 +881,7 @@ impl NormalModel for Model {
         _position_ids: Vec<usize>,
         flash_params: &FlashParams,
         flash_params_full: &FlashParams,
+        adapter_scalings: Option<Tensor>,
     ) -> Result<Tensor> {
         self.forward(
             input_ids,
@@ -891,6 +893,7 @@ impl NormalModel for Model {
             context_lens,
             flash_params,
             flash_params_full,
+            adapter_scalings,
         )
     }
     fn cache(&self) -> &EitherCache {
@@ -483,6 +483,7 @@ impl XLoraLlama {
         context_lens: Vec<(usize, usize)>,
         flash_params: &FlashParams,
         flash_params_full: &FlashParams,
+        adapter_scalings: Option<Tensor>,
     ) -> Result<Tensor> {
         if self.xlora_classifier.is_some() {
             let scalings = self.get_scalings(
@@ -542,7 +543,7 @@ impl XLoraLlama {
                 .inner_forward(
                     input_ids,
                     seqlen_offsets,
-                    None,
+                    adapter_scalings,
                     false,
                     no_kv_cache,
                     None,
@@ -776,6 +777,7 @@ impl NormalModel for XLoraLlama {
         _position_ids: Vec<usize>,
         flash_params: &FlashParams,
         flash_params_full: &FlashParams,
+        adapter_scalings: Option<Tensor>,
     ) -> Result<Tensor> {
         self.forward(
             input_ids,
@@ -787,6 +789,7 @@ impl NormalModel for XLoraLlama {
             context_lens,
             flash_params,
             flash_params_full,
+            adapter_scalings,
         )
     }
     fn cache(&self) -> &super::EitherCache {
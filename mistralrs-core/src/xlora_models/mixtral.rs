@@ -788,6 +788,7 @@ impl XLoraModel {
         context_lens: Vec<(usize, usize)>,
         flash_params: &FlashParams,
         flash_params_full: &FlashParams,
+        adapter_scalings: Option<Tensor>,
     ) -> Result<Tensor> {
         if self.xlora_classifier.is_some() {
             let scalings = self.get_scalings(
@@ -847,7 +848,7 @@ impl XLoraModel {
                 .inner_forward(
                     input_ids,
                     seqlen_offsets,
-                    None,
+                    adapter_scalings,
                     false,
                     no_kv_cache,
                     None,
@@ -943,6 +944,7 @@ impl NormalModel for XLoraModel {
         _position_ids: Vec<usize>,
         flash_params: &FlashParams,
         flash_params_full: &FlashParams,
+        adapter_scalings: Option<Tensor>,
     ) -> Result<Tensor> {
         self.forward(
             input_ids,
@@ -954,6 +956,7 @@ impl NormalModel for XLoraModel {
             context_lens,
             flash_params,
             flash_params_full,
+            adapter_scalings,
         )
     }
     fn cache(&self) -> &EitherCache {
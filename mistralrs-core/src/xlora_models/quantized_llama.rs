@@ -497,7 +497,12 @@ impl ModelConfig::FromAdapterGGUF for ModelWeights {
             rope_freq_base,
             key_length,
             value_length,
+            sliding_window,
+            attention_pattern: _,
         } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
+        if sliding_window.is_some() {
+            candle_core::bail!("Sliding window attention is not supported for X-LoRA GGUF models.");
+        }
 
         let head_dim = key_length;
         if key_length != value_length {
@@ -0,0 +1,599 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::collections::HashMap;
+
+use crate::attention::SdpaParams;
+use crate::device_map::DeviceMapper;
+use crate::gguf::Content;
+use crate::layers::split_qtensor;
+use crate::layers::CausalMasker;
+use crate::layers::Sdpa;
+use crate::lora::get_lora_cfg;
+use crate::lora::LinearLayerLike;
+use crate::lora::LoraConfig;
+use crate::lora::Merge;
+use crate::lora::Ordering;
+use crate::lora::QLoraLinear;
+use crate::pipeline::extract_logits;
+use crate::pipeline::text_models_inputs_processor::FlashParams;
+use crate::pipeline::EitherCache;
+use crate::utils::progress::NiceProgressBar;
+use candle_core::quantized::QMatMul;
+use candle_core::quantized::QTensor;
+use candle_core::{DType, Device, IndexOp, Module, Result, Tensor, D};
+use candle_nn::{Embedding, LayerNorm};
+use indicatif::MultiProgress;
+use mistralrs_quant::ShardedVarBuilder;
+use tqdm::Iter;
+use tracing::info;
+
+use super::classifier::XLoraClassifier;
+use super::verify_sanity_adapters;
+use super::Cache;
+use super::NonGranularState;
+use super::ScalingsMaker;
+use super::XLoraConfig;
+use crate::models::quantized_phi2::PropsGGUF;
+use crate::utils::gguf_metadata::ContentMetadata;
+use crate::utils::model_config as ModelConfig;
+
+const SUPPORTED_LAYERS: [&str; 7] = [
+    "self_attn.q_proj",
+    "self_attn.k_proj",
+    "self_attn.v_proj",
+    "self_attn.dense",
+    "mlp.fc1",
+    "mlp.fc2",
+    "lm_head",
+];
+
+/// The projections of Phi-2 have a bias, which is added after the adapters.
+#[derive(Debug)]
+struct Linear {
+    inner: QLoraLinear,
+    bias: Tensor,
+}
+
+impl Linear {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        weight: QTensor,
+        bias: Tensor,
+        lora_config: &[((String, String), LoraConfig)],
+        vb: &ShardedVarBuilder,
+        ordering: &Ordering,
+        prefix: String,
+        count: &mut usize,
+        preload_adapters: &Option<HashMap<String, (ShardedVarBuilder, LoraConfig)>>,
+    ) -> Result<Self> {
+        let cfg = get_lora_cfg(&weight);
+        let inner = QLoraLinear::new(
+            QMatMul::from_qtensor(weight)?,
+            &cfg,
+            lora_config,
+            vb,
+            ordering,
+            prefix,
+            count,
+            preload_adapters,
+        )?;
+        Ok(Self { inner, bias })
+    }
+
+    fn forward(
+        &self,
+        xs: &Tensor,
+        scalings: Option<Tensor>,
+        global_scaling_weight: f64,
+        is_scaling_pass: Option<f64>,
+    ) -> Result<Tensor> {
+        self.inner
+            .lora_forward(xs, scalings, global_scaling_weight, is_scaling_pass)?
+            .broadcast_add(&self.bias)
+    }
+}
+
+#[derive(Debug)]
+struct Mlp {
+    ffn_up: Linear,
+    ffn_down: Linear,
+}
+
+impl Mlp {
+    fn forward(
+        &self,
+        xs: &Tensor,
+        scalings: Option<Tensor>,
+        global_scaling_weight: f64,
+        is_scaling_pass: Option<f64>,
+    ) -> Result<Tensor> {
+        let xs = self
+            .ffn_up
+            .forward(xs, scalings.clone(), global_scaling_weight, is_scaling_pass)?
+            .gelu()?;
+        self.ffn_down
+            .forward(&xs, scalings, global_scaling_weight, is_scaling_pass)
+    }
+}
+
+fn layer_norm(w: QTensor, b: QTensor, eps: f64) -> Result<LayerNorm> {
+    let w = w.dequantize(&w.device())?;
+    let b = b.dequantize(&b.device())?;
+    let ln = LayerNorm::new(w, b, eps);
+    Ok(ln)
+}
+
+struct LayerWeights {
+    attn_q: Linear,
+    attn_k: Linear,
+    attn_v: Linear,
+    attn_output: Linear,
+    attn_norm: LayerNorm,
+    mlp: Mlp,
+    n_head: usize,
+    head_dim: usize,
+    cos: Tensor,
+    sin: Tensor,
+    rope_dim: usize,
+    sdpa_params: SdpaParams,
+    dtype: DType,
+}
+
+impl LayerWeights {
+    fn apply_rotary_emb(&self, xs: &Tensor, seqlen_offsets: &[usize]) -> Result<Tensor> {
+        let (_b_sz, _n_head, seq_len, _n_embd) = xs.dims4()?;
+        let xs_rot = xs.i((.., .., .., ..self.rope_dim))?;
+        let xs_pass = xs.i((.., .., .., self.rope_dim..))?;
+        let mut chunks = Vec::new();
+        for (b, offset) in (0..xs.dim(0)?).zip(seqlen_offsets) {
+            let cos = self.cos.narrow(0, *offset, seq_len)?;
+            let sin = self.sin.narrow(0, *offset, seq_len)?;
+            let xs_rot =
+                candle_nn::rotary_emb::rope(&xs_rot.i(b)?.unsqueeze(0)?.contiguous()?, &cos, &sin)?;
+            chunks.push(Tensor::cat(
+                &[&xs_rot, &xs_pass.i(b)?.unsqueeze(0)?],
+                D::Minus1,
+            )?);
+        }
+        Tensor::cat(&chunks, 0)?.contiguous()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn forward_attn(
+        &self,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        seqlen_offsets: &[usize],
+        kv_cache: &mut Option<(Tensor, Tensor)>,
+        scalings: Option<Tensor>,
+        global_scaling_weight: f64,
+        is_scaling_pass: Option<f64>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = x.dims3()?;
+        let proj = |linear: &Linear| -> Result<Tensor> {
+            linear
+                .forward(x, scalings.clone(), global_scaling_weight, is_scaling_pass)?
+                .to_dtype(self.dtype)?
+                .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+                .transpose(1, 2)
+        };
+        let q = proj(&self.attn_q)?;
+        let k = proj(&self.attn_k)?;
+        let v = proj(&self.attn_v)?.contiguous()?;
+
+        let q = self.apply_rotary_emb(&q, seqlen_offsets)?;
+        let k = self.apply_rotary_emb(&k, seqlen_offsets)?;
+
+        let (k, v) = Cache::update_kv_cache(kv_cache, k, v, false)?;
+
+        let y = Sdpa.run_attention(&q, &k, &v, mask, Some(flash_params), &self.sdpa_params)?;
+
+        let y = y.transpose(1, 2)?.reshape((b_sz, seq_len, ()))?;
+        self.attn_output.forward(
+            &y.to_dtype(x.dtype())?,
+            scalings,
+            global_scaling_weight,
+            is_scaling_pass,
+        )
+    }
+}
+
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    layers: Vec<LayerWeights>,
+    output_norm: LayerNorm,
+    output: Linear,
+    mapper: Option<Box<dyn DeviceMapper + Send + Sync>>,
+    pub device: Device,
+    pub cache: EitherCache,
+    pub max_seq_len: usize,
+    xlora_classifier: Option<XLoraClassifier>,
+    dtype: DType,
+}
+
+fn precomput_freqs_cis(
+    head_dim: usize,
+    freq_base: f32,
+    device: &Device,
+    max_seq_len: usize,
+    dtype: DType,
+) -> Result<(Tensor, Tensor)> {
+    let theta: Vec<_> = (0..head_dim)
+        .step_by(2)
+        .map(|i| 1f32 / freq_base.powf(i as f32 / head_dim as f32))
+        .collect();
+    let theta = Tensor::new(theta.as_slice(), device)?;
+    let idx_theta = Tensor::arange(0, max_seq_len as u32, device)?
+        .to_dtype(DType::F32)?
+        .reshape((max_seq_len, 1))?
+        .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+    let cos = idx_theta.cos()?.to_dtype(dtype)?;
+    let sin = idx_theta.sin()?.to_dtype(dtype)?;
+    Ok((cos, sin))
+}
+
+impl ModelConfig::FromAdapterGGUF for ModelWeights {
+    #[allow(clippy::too_many_arguments)]
+    fn from_gguf<R: std::io::Seek + std::io::Read>(
+        mut ct: Content<'_, R>,
+        device: &Device,
+        lora_config: &[((String, String), LoraConfig)],
+        vb: &ShardedVarBuilder,
+        ordering: &Ordering,
+        xlora_config: Option<XLoraConfig>,
+        mapper: Box<dyn DeviceMapper + Send + Sync>,
+        preload_adapters: &Option<HashMap<String, (ShardedVarBuilder, LoraConfig)>>,
+        dtype: DType,
+    ) -> Result<Self> {
+        verify_sanity_adapters(ordering, &SUPPORTED_LAYERS)?;
+
+        // Parameter extraction from metadata.
+        let metadata = ContentMetadata {
+            path_prefix: "phi2",
+            metadata: ct.get_metadata(),
+        };
+        let PropsGGUF {
+            head_count,
+            head_count_kv,
+            block_count,
+            embedding_length,
+            rope_dim,
+            ln_eps,
+            max_seq_len,
+        } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
+        if head_count != head_count_kv {
+            candle_core::bail!(
+                "Phi-2 expects as many key-value heads as heads, got {head_count_kv} and {head_count}."
+            );
+        }
+
+        let (cos, sin) = precomput_freqs_cis(rope_dim, 10_000., device, max_seq_len, dtype)?;
+
+        let tok_embeddings = ct.tensor("token_embd.weight", device)?;
+        let tok_embeddings = tok_embeddings.dequantize(device)?;
+        let output_norm = layer_norm(
+            ct.tensor("output_norm.weight", device)?,
+            ct.tensor("output_norm.bias", device)?,
+            ln_eps,
+        )?;
+        let output = ct.tensor("output.weight", device)?;
+        let output_bias = ct.tensor("output.bias", device)?.dequantize(device)?;
+        let mut layers = Vec::with_capacity(block_count);
+        let head_dim = embedding_length / head_count;
+
+        let mut count = 0;
+        for layer_idx in NiceProgressBar::<_, 'b'>(
+            0..block_count,
+            "Loading repeating layers",
+            &MultiProgress::new(),
+        ) {
+            let prefix = format!("blk.{layer_idx}");
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+
+            let mut linear = |name: &str, layer: &str| -> Result<Linear> {
+                Linear::new(
+                    ct.tensor(&format!("{prefix}.{name}.weight"), device)?,
+                    ct.tensor(&format!("{prefix}.{name}.bias"), device)?
+                        .dequantize(device)?,
+                    lora_config,
+                    vb,
+                    ordering,
+                    format!("model.layers.{layer_idx}.{layer}"),
+                    &mut count,
+                    preload_adapters,
+                )
+            };
+            let mlp = Mlp {
+                ffn_up: linear("ffn_up", "mlp.fc1")?,
+                ffn_down: linear("ffn_down", "mlp.fc2")?,
+            };
+            let attn_output = linear("attn_output", "self_attn.dense")?;
+
+            // The adapters of Phi-2 target the separate query, key and value projections, which
+            // the converters fuse in this order.
+            let qkv = ct.tensor(&format!("{prefix}.attn_qkv.weight"), device)?;
+            let qkv_bias = ct
+                .tensor(&format!("{prefix}.attn_qkv.bias"), device)?
+                .dequantize(device)?;
+            let mut attn_qkv = split_qtensor(&qkv, 3, device)?
+                .into_iter()
+                .zip(["q_proj", "k_proj", "v_proj"])
+                .enumerate()
+                .map(|(i, (weight, name))| {
+                    Linear::new(
+                        weight,
+                        qkv_bias.narrow(0, i * embedding_length, embedding_length)?,
+                        lora_config,
+                        vb,
+                        ordering,
+                        format!("model.layers.{layer_idx}.self_attn.{name}"),
+                        &mut count,
+                        preload_adapters,
+                    )
+                })
+                .collect::<Result<Vec<_>>>()?
+                .into_iter();
+
+            let attn_norm = layer_norm(
+                ct.tensor(&format!("{prefix}.attn_norm.weight"), device)?,
+                ct.tensor(&format!("{prefix}.attn_norm.bias"), device)?,
+                ln_eps,
+            )?;
+            layers.push(LayerWeights {
+                attn_q: attn_qkv.next().unwrap(),
+                attn_k: attn_qkv.next().unwrap(),
+                attn_v: attn_qkv.next().unwrap(),
+                attn_output,
+                attn_norm,
+                mlp,
+                n_head: head_count,
+                head_dim,
+                cos: cos.to_device(device)?,
+                sin: sin.to_device(device)?,
+                rope_dim,
+                sdpa_params: SdpaParams {
+                    n_kv_groups: 1,
+                    use_flash_attn: false,
+                    softcap: None,
+                    softmax_scale: 1.0 / (head_dim as f32).sqrt(),
+                    sliding_window: None,
+                },
+                dtype,
+            })
+        }
+        if xlora_config.is_none() {
+            // We are now a LoRA model so we must merge the weights
+            info!("Merging LoRA adapters.");
+            for layer in layers.iter_mut().tqdm() {
+                layer.attn_q.inner.merge_weights()?;
+                layer.attn_k.inner.merge_weights()?;
+                layer.attn_v.inner.merge_weights()?;
+                layer.attn_output.inner.merge_weights()?;
+                layer.mlp.ffn_up.inner.merge_weights()?;
+                layer.mlp.ffn_down.inner.merge_weights()?;
+            }
+        }
+        let output = Linear::new(
+            output,
+            output_bias,
+            lora_config,
+            vb,
+            ordering,
+            "lm_head".to_string(),
+            &mut count,
+            preload_adapters,
+        )?;
+        if xlora_config.is_some() && output.inner.is_lora() {
+            // This is why we can pass dummy values (..., None, 1.0, None)?
+            candle_core::bail!("Got an adapter `lm_head` layer, this is unsupported with X-LoRA.");
+        }
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            layers,
+            output_norm,
+            output,
+            mapper: Some(mapper),
+            device: device.clone(),
+            cache: EitherCache::Full(Cache::new(block_count, true)),
+            max_seq_len,
+            xlora_classifier: xlora_config.map(|xlora_config| {
+                XLoraClassifier::new(xlora_config, count, lora_config.len(), vb.clone(), true)
+                    .unwrap()
+            }),
+            dtype,
+        })
+    }
+}
+
+impl ModelWeights {
+    #[allow(clippy::too_many_arguments)]
+    pub fn inner_forward(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        scalings: Option<Tensor>,
+        is_full_pass: bool,
+        no_kv_cache: bool,
+        is_scaling_pass: Option<f64>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let mut xs = self.tok_embeddings.forward(input_ids)?;
+        let mut cache = if is_full_pass {
+            if no_kv_cache {
+                let mut new_cache = Vec::new();
+                for _ in 0..self.cache.full().xlora_lock().len() {
+                    new_cache.push(None);
+                }
+
+                self.cache.full().xlora_lock().clone_from(&new_cache);
+            }
+            self.cache.full().xlora_lock()
+        } else {
+            self.cache.full().lock()
+        };
+        let mask = CausalMasker.make_causal_mask_matrix(
+            input_ids,
+            &*cache,
+            self.dtype,
+            self.layers[0].n_head,
+        )?;
+        let global_scaling_weight = self
+            .xlora_classifier
+            .as_ref()
+            .map(|classifier| classifier.get_global_scaling_weight())
+            .unwrap_or(1.0);
+        for (i, layer) in self.layers.iter().enumerate() {
+            if let Some(ref mapper) = self.mapper {
+                xs = mapper.map(xs, i)?;
+            }
+            let residual = &xs;
+            let xs_norm = xs.apply(&layer.attn_norm)?;
+            let attn_outputs = layer.forward_attn(
+                &xs_norm,
+                mask.as_ref()
+                    .map(|m| m.to_device(xs.device()).unwrap())
+                    .as_ref(),
+                seqlen_offsets,
+                &mut cache[i],
+                scalings.clone(),
+                global_scaling_weight,
+                is_scaling_pass,
+                flash_params,
+            )?;
+            let feed_forward_hidden_states = layer.mlp.forward(
+                &xs_norm,
+                scalings.clone(),
+                global_scaling_weight,
+                is_scaling_pass,
+            )?;
+            xs = (attn_outputs + feed_forward_hidden_states + residual)?
+        }
+        let xs = xs.to_device(&self.device)?;
+        xs.apply(&self.output_norm)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn forward(
+        &self,
+        input_ids: &Tensor,
+        input_ids_full: &Tensor,
+        seqlen_offsets: &[usize],
+        seqlen_offsets_full: &[usize],
+        no_kv_cache: bool,
+        non_granular_state: &Option<NonGranularState>,
+        context_lens: Vec<(usize, usize)>,
+        flash_params: &FlashParams,
+        flash_params_full: &FlashParams,
+    ) -> Result<Tensor> {
+        if self.xlora_classifier.is_some() {
+            let scalings = self.get_scalings(
+                input_ids,
+                input_ids_full,
+                seqlen_offsets,
+                seqlen_offsets_full,
+                no_kv_cache,
+                non_granular_state,
+                &vec![usize::MAX; context_lens.len()],
+                flash_params,
+                flash_params_full,
+            )?;
+
+            if no_kv_cache {
+                extract_logits(
+                    &self.output.forward(
+                        &self
+                            .inner_forward(
+                                input_ids_full,
+                                seqlen_offsets_full,
+                                Some(scalings),
+                                true,
+                                no_kv_cache,
+                                None,
+                                flash_params_full,
+                            )?
+                            .contiguous()?,
+                        None,
+                        1.0,
+                        None,
+                    )?,
+                    context_lens,
+                )
+            } else {
+                // is_full_pass=true is ok because no_kv_cache=false
+                extract_logits(
+                    &self.output.forward(
+                        &self
+                            .inner_forward(
+                                input_ids,
+                                seqlen_offsets,
+                                Some(scalings),
+                                true,
+                                no_kv_cache,
+                                None,
+                                flash_params,
+                            )?
+                            .contiguous()?,
+                        None,
+                        1.0,
+                        None,
+                    )?,
+                    context_lens,
+                )
+            }
+        } else {
+            extract_logits(
+                &self.output.forward(
+                    &self
+                        .inner_forward(
+                            input_ids,
+                            seqlen_offsets,
+                            None,
+                            false,
+                            no_kv_cache,
+                            None,
+                            flash_params,
+                        )?
+                        .contiguous()?,
+                    None,
+                    1.0,
+                    None,
+                )?,
+                context_lens,
+            )
+        }
+    }
+}
+
+impl ScalingsMaker for ModelWeights {
+    fn dtype(&self) -> DType {
+        DType::F32 // for dummy scalings
+    }
+    fn get_cache(&self) -> &EitherCache {
+        &self.cache
+    }
+    fn get_classifier(&self) -> &XLoraClassifier {
+        self.xlora_classifier.as_ref().unwrap()
+    }
+    fn forward(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        scalings: Tensor,
+        is_full_pass: bool,
+        no_kv_cache: bool,
+        is_scaling_pass: Option<f64>,
+        _context_lens: &[usize],
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        self.inner_forward(
+            input_ids,
+            seqlen_offsets,
+            Some(scalings),
+            is_full_pass,
+            no_kv_cache,
+            is_scaling_pass,
+            flash_params,
+        )
+    }
+}
@@ -0,0 +1,30 @@
+//! This crate provides an ONNX Runtime backend for mistral.rs. It loads Mistral or Llama models
+//! which were exported to ONNX (with past key values, as produced by
+//! `optimum-cli export onnx --task text-generation-with-past`) and runs inference with
+//! ONNX Runtime's optimized CPU kernels instead of `candle_core`.
+//!
+//! `mistralrs-core` serves these models through its engine with the `onnx` feature, which adds the
+//! `onnx` model selector. The pipeline can also generate on its own, as below.
+//!
+//! ## Example
+//! ```no_run
+//! use mistralrs_onnx::{OnnxLoaderBuilder, OnnxSamplingParams, OnnxSpecificConfig};
+//!
+//! let loader = OnnxLoaderBuilder::new(
+//!     OnnxSpecificConfig::default(),
+//!     "onnx-community/Llama-3.2-1B-Instruct".to_string(),
+//! )
+//! .with_onnx_file("onnx/model.onnx".to_string())
+//! .build();
+//! let mut pipeline = loader.load_model_from_hf(None, None).unwrap();
+//! let out = pipeline
+//!     .generate("Hello, my name is", &OnnxSamplingParams::deterministic())
+//!     .unwrap();
+//! println!("{out}");
+//! ```
+
+mod loader;
+mod pipeline;
+
+pub use loader::{OnnxLoader, OnnxLoaderBuilder, OnnxModelPaths, OnnxSpecificConfig};
+pub use pipeline::{OnnxKvCache, OnnxModelConfig, OnnxPipeline, OnnxSamplingParams};
@@ -0,0 +1,188 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use hf_hub::{
+    api::sync::{ApiBuilder, ApiRepo},
+    Repo, RepoType,
+};
+use ort::session::{builder::GraphOptimizationLevel, Session};
+use tokenizers::Tokenizer;
+use tracing::info;
+
+use crate::pipeline::{OnnxModelConfig, OnnxPipeline};
+
+const DEFAULT_ONNX_FILE: &str = "onnx/model.onnx";
+
+/// Configuration for the ONNX Runtime session.
+#[derive(Clone, Debug)]
+pub struct OnnxSpecificConfig {
+    /// Number of threads used to parallelize execution within nodes. `None` lets ONNX Runtime decide.
+    pub intra_threads: Option<usize>,
+    /// Graph optimization level (0-3). Defaults to 3, all optimizations.
+    pub optimization_level: u8,
+}
+
+impl Default for OnnxSpecificConfig {
+    fn default() -> Self {
+        Self {
+            intra_threads: None,
+            optimization_level: 3,
+        }
+    }
+}
+
+/// Local paths of all files required to build an [`OnnxPipeline`].
+#[derive(Clone, Debug)]
+pub struct OnnxModelPaths {
+    pub onnx_file: PathBuf,
+    pub config_file: PathBuf,
+    pub tokenizer_file: PathBuf,
+    /// `tokenizer_config.json`, with the chat template, if the model has one.
+    pub template_file: Option<PathBuf>,
+    /// `generation_config.json`, if the model has one.
+    pub gen_conf_file: Option<PathBuf>,
+}
+
+/// A builder for an [`OnnxLoader`].
+pub struct OnnxLoaderBuilder {
+    model_id: String,
+    config: OnnxSpecificConfig,
+    onnx_file: Option<String>,
+    tokenizer_json: Option<String>,
+}
+
+impl OnnxLoaderBuilder {
+    pub fn new(config: OnnxSpecificConfig, model_id: String) -> Self {
+        Self {
+            model_id,
+            config,
+            onnx_file: None,
+            tokenizer_json: None,
+        }
+    }
+
+    /// Path of the ONNX graph inside the repository. Defaults to `onnx/model.onnx`.
+    pub fn with_onnx_file(mut self, onnx_file: String) -> Self {
+        self.onnx_file = Some(onnx_file);
+        self
+    }
+
+    /// Use a local `tokenizer.json` instead of the one in the repository.
+    pub fn with_tokenizer_json(mut self, tokenizer_json: String) -> Self {
+        self.tokenizer_json = Some(tokenizer_json);
+        self
+    }
+
+    pub fn build(self) -> OnnxLoader {
+        OnnxLoader {
+            model_id: self.model_id,
+            config: self.config,
+            onnx_file: self
+                .onnx_file
+                .unwrap_or_else(|| DEFAULT_ONNX_FILE.to_string()),
+            tokenizer_json: self.tokenizer_json,
+        }
+    }
+}
+
+/// Loader for Mistral and Llama models exported to ONNX.
+pub struct OnnxLoader {
+    model_id: String,
+    config: OnnxSpecificConfig,
+    onnx_file: String,
+    tokenizer_json: Option<String>,
+}
+
+impl OnnxLoader {
+    /// Resolve the model files, either from a local directory (if `model_id` is one) or from the Hugging Face Hub.
+    pub fn get_paths(
+        &self,
+        revision: Option<String>,
+        token: Option<String>,
+    ) -> Result<OnnxModelPaths> {
+        let local = Path::new(&self.model_id);
+        if local.is_dir() {
+            let tokenizer_file = match &self.tokenizer_json {
+                Some(p) => PathBuf::from(p),
+                None => local.join("tokenizer.json"),
+            };
+            let optional = |name: &str| Some(local.join(name)).filter(|p| p.is_file());
+            return Ok(OnnxModelPaths {
+                onnx_file: local.join(&self.onnx_file),
+                config_file: local.join("config.json"),
+                tokenizer_file,
+                template_file: optional("tokenizer_config.json"),
+                gen_conf_file: optional("generation_config.json"),
+            });
+        }
+
+        let api = ApiBuilder::new()
+            .with_progress(true)
+            .with_token(token)
+            .build()?;
+        let revision = revision.unwrap_or_else(|| "main".to_string());
+        let api = api.repo(Repo::with_revision(
+            self.model_id.clone(),
+            RepoType::Model,
+            revision,
+        ));
+
+        let onnx_file = api.get(&self.onnx_file)?;
+        // Large exports store the weights next to the graph as external data.
+        Self::maybe_get_external_data(&api, &self.onnx_file);
+        let tokenizer_file = match &self.tokenizer_json {
+            Some(p) => PathBuf::from(p),
+            None => api.get("tokenizer.json")?,
+        };
+        Ok(OnnxModelPaths {
+            onnx_file,
+            config_file: api.get("config.json")?,
+            tokenizer_file,
+            template_file: api.get("tokenizer_config.json").ok(),
+            gen_conf_file: api.get("generation_config.json").ok(),
+        })
+    }
+
+    fn maybe_get_external_data(api: &ApiRepo, onnx_file: &str) {
+        for suffix in ["_data", ".data"] {
+            let name = format!("{onnx_file}{suffix}");
+            if api.get(&name).is_ok() {
+                info!("Downloaded external ONNX data `{name}`.");
+            }
+        }
+    }
+
+    pub fn load_model_from_hf(
+        &self,
+        revision: Option<String>,
+        token: Option<String>,
+    ) -> Result<OnnxPipeline> {
+        let paths = self.get_paths(revision, token)?;
+        self.load_model_from_path(&paths)
+    }
+
+    pub fn load_model_from_path(&self, paths: &OnnxModelPaths) -> Result<OnnxPipeline> {
+        let config: OnnxModelConfig = serde_json::from_str(
+            &std::fs::read_to_string(&paths.config_file)
+                .with_context(|| format!("Reading {}", paths.config_file.display()))?,
+        )?;
+        let tokenizer = Tokenizer::from_file(&paths.tokenizer_file)
+            .map_err(anyhow::Error::msg)
+            .with_context(|| format!("Loading {}", paths.tokenizer_file.display()))?;
+
+        let level = match self.config.optimization_level {
+            0 => GraphOptimizationLevel::Disable,
+            1 => GraphOptimizationLevel::Level1,
+            2 => GraphOptimizationLevel::Level2,
+            _ => GraphOptimizationLevel::Level3,
+        };
+        let mut builder = Session::builder()?.with_optimization_level(level)?;
+        if let Some(n) = self.config.intra_threads {
+            builder = builder.with_intra_threads(n)?;
+        }
+        info!("Loading ONNX graph from `{}`.", paths.onnx_file.display());
+        let session = builder.commit_from_file(&paths.onnx_file)?;
+
+        OnnxPipeline::new(session, tokenizer, config, self.model_id.clone())
+    }
+}
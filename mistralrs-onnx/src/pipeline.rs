@@ -0,0 +1,445 @@
+use anyhow::{bail, Result};
+use ndarray::{Array2, Array4, ArrayD, Axis, IxDyn};
+use ort::{session::Session, value::Tensor};
+use rand::{
+    distr::{weighted::WeightedIndex, Distribution},
+    SeedableRng,
+};
+use rand_isaac::Isaac64Rng;
+use serde::Deserialize;
+use tokenizers::Tokenizer;
+
+/// Subset of the Hugging Face `config.json` needed to drive the exported graph.
+#[derive(Clone, Debug, Deserialize)]
+pub struct OnnxModelConfig {
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: Option<usize>,
+    pub hidden_size: usize,
+    pub head_dim: Option<usize>,
+    pub max_position_embeddings: usize,
+    pub eos_token_id: Option<serde_json::Value>,
+}
+
+impl OnnxModelConfig {
+    pub fn num_kv_heads(&self) -> usize {
+        self.num_key_value_heads.unwrap_or(self.num_attention_heads)
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.head_dim
+            .unwrap_or(self.hidden_size / self.num_attention_heads)
+    }
+
+    /// Check a step of `seq_len` new tokens after `past_len` cached ones, returning the total length.
+    fn check_step(&self, past_len: usize, seq_len: usize) -> Result<usize> {
+        if seq_len == 0 {
+            bail!("Cannot run the ONNX model on an empty input");
+        }
+        let total_len = past_len + seq_len;
+        if total_len > self.max_position_embeddings {
+            bail!(
+                "Sequence length {total_len} exceeds the model maximum of {}",
+                self.max_position_embeddings
+            );
+        }
+        Ok(total_len)
+    }
+
+    /// Number of tokens which can be generated after a prompt of `prompt_len` tokens.
+    fn remaining_len(&self, prompt_len: usize) -> Result<usize> {
+        match self.max_position_embeddings.checked_sub(prompt_len) {
+            Some(remaining) => Ok(remaining),
+            None => bail!(
+                "Prompt of {prompt_len} tokens exceeds the model maximum of {}",
+                self.max_position_embeddings
+            ),
+        }
+    }
+
+    fn eos_toks(&self) -> Vec<u32> {
+        match &self.eos_token_id {
+            Some(serde_json::Value::Number(n)) => {
+                n.as_u64().map(|n| n as u32).into_iter().collect()
+            }
+            Some(serde_json::Value::Array(a)) => a
+                .iter()
+                .filter_map(|n| n.as_u64().map(|n| n as u32))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Per-sequence KV cache, stored as the `present.*` outputs of the previous step.
+pub struct OnnxKvCache {
+    /// `(k, v)` per layer, each of shape (1, num_kv_heads, seq_len, head_dim).
+    layers: Vec<(ArrayD<f32>, ArrayD<f32>)>,
+    seq_len: usize,
+}
+
+impl OnnxKvCache {
+    pub fn new(cfg: &OnnxModelConfig) -> Self {
+        let shape = (1, cfg.num_kv_heads(), 0, cfg.head_dim());
+        let empty = || Array4::<f32>::zeros(shape).into_dyn();
+        Self {
+            layers: (0..cfg.num_hidden_layers)
+                .map(|_| (empty(), empty()))
+                .collect(),
+            seq_len: 0,
+        }
+    }
+
+    /// Build a cache of `seq_len` tokens from the keys and values of each layer, each flattened
+    /// from shape (1, num_kv_heads, seq_len, head_dim).
+    pub fn from_flat(
+        cfg: &OnnxModelConfig,
+        layers: Vec<(Vec<f32>, Vec<f32>)>,
+        seq_len: usize,
+    ) -> Result<Self> {
+        if layers.len() != cfg.num_hidden_layers {
+            bail!(
+                "Expected the cache of {} layers, got {}",
+                cfg.num_hidden_layers,
+                layers.len()
+            );
+        }
+        let shape = IxDyn(&[1, cfg.num_kv_heads(), seq_len, cfg.head_dim()]);
+        let layers = layers
+            .into_iter()
+            .map(|(k, v)| {
+                Ok((
+                    ArrayD::from_shape_vec(shape.clone(), k)?,
+                    ArrayD::from_shape_vec(shape.clone(), v)?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { layers, seq_len })
+    }
+
+    /// The keys and values of each layer, each flattened from shape
+    /// (1, num_kv_heads, seq_len, head_dim).
+    pub fn to_flat(&self) -> Vec<(Vec<f32>, Vec<f32>)> {
+        self.layers
+            .iter()
+            .map(|(k, v)| (k.iter().copied().collect(), v.iter().copied().collect()))
+            .collect()
+    }
+
+    pub fn seq_len(&self) -> usize {
+        self.seq_len
+    }
+}
+
+/// Sampling parameters for [`OnnxPipeline::generate`].
+#[derive(Clone, Debug)]
+pub struct OnnxSamplingParams {
+    pub temperature: Option<f64>,
+    pub max_len: Option<usize>,
+    pub seed: u64,
+}
+
+impl OnnxSamplingParams {
+    pub fn deterministic() -> Self {
+        Self {
+            temperature: None,
+            max_len: None,
+            seed: 0,
+        }
+    }
+}
+
+/// A Mistral/Llama text model executed by ONNX Runtime.
+pub struct OnnxPipeline {
+    session: Session,
+    tokenizer: Tokenizer,
+    config: OnnxModelConfig,
+    model_id: String,
+    eos_toks: Vec<u32>,
+    has_position_ids: bool,
+    has_past: bool,
+}
+
+impl OnnxPipeline {
+    pub(crate) fn new(
+        session: Session,
+        tokenizer: Tokenizer,
+        config: OnnxModelConfig,
+        model_id: String,
+    ) -> Result<Self> {
+        let input_names = session
+            .inputs
+            .iter()
+            .map(|i| i.name.as_str())
+            .collect::<Vec<_>>();
+        if !input_names.contains(&"input_ids") {
+            bail!("ONNX graph has no `input_ids` input, found {input_names:?}");
+        }
+        let has_position_ids = input_names.contains(&"position_ids");
+        let has_past = input_names
+            .iter()
+            .any(|n| n.starts_with("past_key_values."));
+        if !has_past {
+            tracing::warn!(
+                "ONNX graph was exported without past key values, every step will recompute the full sequence."
+            );
+        }
+        let eos_toks = config.eos_toks();
+        Ok(Self {
+            session,
+            tokenizer,
+            config,
+            model_id,
+            eos_toks,
+            has_position_ids,
+            has_past,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.model_id
+    }
+
+    pub fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    pub fn config(&self) -> &OnnxModelConfig {
+        &self.config
+    }
+
+    /// Whether the graph takes past key values. Otherwise, every step runs the whole sequence and
+    /// the cache is not used.
+    pub fn has_past(&self) -> bool {
+        self.has_past
+    }
+
+    /// Run one step over `input_ids`, returning the logits of the last position. When the graph
+    /// has past key values, only the new tokens should be passed and `cache` is updated in place.
+    pub fn forward(&self, input_ids: &[u32], cache: &mut OnnxKvCache) -> Result<Vec<f32>> {
+        let seq_len = input_ids.len();
+        let past_len = if self.has_past { cache.seq_len } else { 0 };
+        let total_len = self.config.check_step(past_len, seq_len)?;
+
+        let ids =
+            Array2::from_shape_vec((1, seq_len), input_ids.iter().map(|x| *x as i64).collect())?;
+        let mask = Array2::<i64>::ones((1, total_len));
+
+        let mut inputs: Vec<(String, ort::value::DynValue)> = vec![
+            ("input_ids".to_string(), Tensor::from_array(ids)?.into_dyn()),
+            (
+                "attention_mask".to_string(),
+                Tensor::from_array(mask)?.into_dyn(),
+            ),
+        ];
+        if self.has_position_ids {
+            let positions = Array2::from_shape_vec(
+                (1, seq_len),
+                (past_len..total_len).map(|x| x as i64).collect(),
+            )?;
+            inputs.push((
+                "position_ids".to_string(),
+                Tensor::from_array(positions)?.into_dyn(),
+            ));
+        }
+        if self.has_past {
+            for (i, (k, v)) in cache.layers.iter().enumerate() {
+                inputs.push((
+                    format!("past_key_values.{i}.key"),
+                    Tensor::from_array(k.clone())?.into_dyn(),
+                ));
+                inputs.push((
+                    format!("past_key_values.{i}.value"),
+                    Tensor::from_array(v.clone())?.into_dyn(),
+                ));
+            }
+        }
+
+        let outputs = self.session.run(inputs)?;
+
+        let logits = outputs["logits"].try_extract_tensor::<f32>()?;
+        // (1, seq_len, vocab)
+        let last = logits
+            .index_axis(Axis(0), 0)
+            .index_axis(Axis(0), seq_len - 1)
+            .to_owned();
+
+        if self.has_past {
+            for (i, (k, v)) in cache.layers.iter_mut().enumerate() {
+                *k = outputs[format!("present.{i}.key").as_str()]
+                    .try_extract_tensor::<f32>()?
+                    .to_owned()
+                    .into_shape_with_order(IxDyn(&[
+                        1,
+                        self.config.num_kv_heads(),
+                        total_len,
+                        self.config.head_dim(),
+                    ]))?;
+                *v = outputs[format!("present.{i}.value").as_str()]
+                    .try_extract_tensor::<f32>()?
+                    .to_owned()
+                    .into_shape_with_order(IxDyn(&[
+                        1,
+                        self.config.num_kv_heads(),
+                        total_len,
+                        self.config.head_dim(),
+                    ]))?;
+            }
+            cache.seq_len = total_len;
+        }
+
+        Ok(last.into_raw_vec_and_offset().0)
+    }
+
+    /// Generate a completion for `prompt`, stopping at an EOS token or after `max_len` tokens.
+    pub fn generate(&self, prompt: &str, params: &OnnxSamplingParams) -> Result<String> {
+        let prompt_toks = self
+            .tokenizer
+            .encode(prompt, true)
+            .map_err(anyhow::Error::msg)?
+            .get_ids()
+            .to_vec();
+        if prompt_toks.is_empty() {
+            bail!("Prompt is empty after tokenization");
+        }
+        let max_len = match params.max_len {
+            Some(max_len) => max_len,
+            None => self.config.remaining_len(prompt_toks.len())?,
+        };
+
+        let mut rng = Isaac64Rng::seed_from_u64(params.seed);
+        let mut cache = OnnxKvCache::new(&self.config);
+        let mut all_toks = prompt_toks.clone();
+        let mut generated = Vec::new();
+        let mut next_input = prompt_toks;
+
+        while generated.len() < max_len {
+            let logits = self.forward(&next_input, &mut cache)?;
+            let next = Self::sample(&logits, params.temperature, &mut rng)?;
+            if self.eos_toks.contains(&next) {
+                break;
+            }
+            generated.push(next);
+            all_toks.push(next);
+            next_input = if self.has_past {
+                vec![next]
+            } else {
+                all_toks.clone()
+            };
+        }
+
+        self.tokenizer
+            .decode(&generated, true)
+            .map_err(anyhow::Error::msg)
+    }
+
+    fn sample(logits: &[f32], temperature: Option<f64>, rng: &mut Isaac64Rng) -> Result<u32> {
+        match temperature {
+            Some(t) if t > 0. => {
+                let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                let probs = logits
+                    .iter()
+                    .map(|l| ((l - max) as f64 / t).exp())
+                    .collect::<Vec<_>>();
+                let distr = WeightedIndex::new(&probs)?;
+                Ok(distr.sample(rng) as u32)
+            }
+            _ => Ok(logits
+                .iter()
+                .enumerate()
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(i, _)| i as u32)
+                .unwrap_or_default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_isaac::Isaac64Rng;
+
+    use super::{OnnxKvCache, OnnxModelConfig, OnnxPipeline};
+
+    fn config() -> OnnxModelConfig {
+        serde_json::from_str(
+            r#"{
+                "num_hidden_layers": 2,
+                "num_attention_heads": 4,
+                "num_key_value_heads": 2,
+                "hidden_size": 32,
+                "max_position_embeddings": 16,
+                "eos_token_id": [1, 2]
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn config_from_json() {
+        let cfg = config();
+        assert_eq!(cfg.num_kv_heads(), 2);
+        assert_eq!(cfg.head_dim(), 8);
+        assert_eq!(cfg.eos_toks(), vec![1, 2]);
+
+        let cache = OnnxKvCache::new(&cfg);
+        assert_eq!(cache.seq_len(), 0);
+        assert_eq!(cache.layers.len(), 2);
+        assert_eq!(cache.layers[0].0.shape(), &[1, 2, 0, 8]);
+    }
+
+    #[test]
+    fn kv_cache_flat_round_trip() {
+        let cfg = config();
+        // 2 layers of 2 heads, 3 tokens and a head dimension of 8.
+        let layers = (0..2)
+            .map(|layer| {
+                let k = (0..48)
+                    .map(|i| (layer * 100 + i) as f32)
+                    .collect::<Vec<_>>();
+                let v = k.iter().map(|x| -x).collect::<Vec<_>>();
+                (k, v)
+            })
+            .collect::<Vec<_>>();
+        let cache = OnnxKvCache::from_flat(&cfg, layers.clone(), 3).unwrap();
+        assert_eq!(cache.seq_len(), 3);
+        assert_eq!(cache.layers[1].0.shape(), &[1, 2, 3, 8]);
+        assert_eq!(cache.to_flat(), layers);
+
+        assert!(OnnxKvCache::from_flat(&cfg, layers.clone(), 4).is_err());
+        assert!(OnnxKvCache::from_flat(&cfg, layers[..1].to_vec(), 3).is_err());
+    }
+
+    #[test]
+    fn check_step_rejects_empty_and_long_inputs() {
+        let cfg = config();
+        assert_eq!(cfg.check_step(4, 2).unwrap(), 6);
+        assert_eq!(cfg.check_step(15, 1).unwrap(), 16);
+        assert!(cfg.check_step(4, 0).is_err());
+        assert!(cfg.check_step(15, 2).is_err());
+    }
+
+    #[test]
+    fn remaining_len_does_not_underflow() {
+        let cfg = config();
+        assert_eq!(cfg.remaining_len(10).unwrap(), 6);
+        assert_eq!(cfg.remaining_len(16).unwrap(), 0);
+        assert!(cfg.remaining_len(17).is_err());
+    }
+
+    #[test]
+    fn sample_greedy_and_with_temperature() {
+        let mut rng = Isaac64Rng::seed_from_u64(0);
+        let logits = [0.1, 3.0, -1.0, 0.5];
+        assert_eq!(OnnxPipeline::sample(&logits, None, &mut rng).unwrap(), 1);
+        assert_eq!(
+            OnnxPipeline::sample(&logits, Some(0.), &mut rng).unwrap(),
+            1
+        );
+        // A very low temperature puts all of the mass on the most likely token.
+        assert_eq!(
+            OnnxPipeline::sample(&logits, Some(1e-3), &mut rng).unwrap(),
+            1
+        );
+    }
+}
@@ -63,6 +63,9 @@ class ChatCompletionRequest:
     tool_schemas: list[str] | None = None
     tool_choice: ToolChoice | None = None
     web_search_options: WebSearchOptions | None = None
+    enable_thinking: bool | None = None
+    strict_system_role: bool = False
+    include_stop_str_in_output: bool = False
 
 @dataclass
 class CompletionRequest:
@@ -90,6 +93,7 @@ class CompletionRequest:
     min_p: float | None = None
     tool_schemas: list[str] | None = None
     tool_choice: ToolChoice | None = None
+    include_stop_str_in_output: bool = False
 
 @dataclass
 class Architecture(Enum):
@@ -310,6 +314,7 @@ class Runner:
         prefix_cache_n: int = 16,
         token_source: str = "cache",
         speculative_gamma: int = 32,
+        speculative_gamma_bounds: tuple[int, int] | None = None,
         which_draft: Which | None = None,
         chat_template: str | None = None,
         jinja_explicit: str | None = None,
@@ -336,6 +341,8 @@ class Runner:
             The token source follows the following format: "literal:<value>", "env:<value>", "path:<value>", "cache" to use a cached token or "none" to use no token.
         - `speculative_gamma` specifies the `gamma` parameter for specuative decoding, the ratio of draft tokens to generate before calling
             the target model. If `which_draft` is not specified, this is ignored.
+        - `speculative_gamma_bounds` specifies the minimum and maximum `gamma`, between which it is adapted to the acceptance of the draft
+            tokens of each request. By default, `gamma` is locked.
         - `which_draft` specifies which draft model to load. Setting this parameter will cause a speculative decoding model to be loaded,
             with `which` as the target (higher quality) model and `which_draft` as the draft (lower quality) model.
         - `chat_template` specifies an optional JINJA chat template as a JSON file.
@@ -462,6 +469,7 @@ class AnyMoeConfig:
 @dataclass
 class Usage:
     completion_tokens: int
+    reasoning_tokens: int
     prompt_tokens: int
     total_tokens: int
     avg_tok_per_sec: float
@@ -470,6 +478,18 @@ class Usage:
     total_time_sec: float
     total_prompt_time_sec: float
     total_completion_time_sec: float
+    speculative: SpeculativeStats | None
+
+@dataclass
+class SpeculativeStats:
+    steps: int
+    draft_tokens: int
+    accepted_draft_tokens: int
+    wasted_draft_tokens: int
+    generated_tokens: int
+    acceptance_rate: float
+    effective_speedup: float
+    gamma: int
 
 @dataclass
 class ToolCallType(Enum):
@@ -27,8 +27,9 @@ use mistralrs_core::{
     GGUFSpecificConfig, ImageGenerationResponse, ImageGenerationResponseFormat, LlguidanceGrammar,
     Loader, MemoryGpuConfig, MistralRs, MistralRsBuilder, NormalLoaderBuilder, NormalRequest,
     NormalSpecificConfig, PagedAttentionConfig, Request as _Request, RequestMessage, Response,
-    ResponseOk, SamplingParams, SchedulerConfig, SpeculativeConfig, SpeculativeLoader, StopTokens,
-    TokenSource, TokenizationRequest, Tool, Topology, VisionLoaderBuilder, VisionSpecificConfig,
+    ResponseOk, SamplerBackend, SamplingParams, SchedulerConfig, SpeculativeConfig,
+    SpeculativeLoader, StopTokens, TokenSource, TokenizationRequest, Tool, Topology,
+    VisionLoaderBuilder, VisionSpecificConfig,
 };
 use pyo3::prelude::*;
 use std::fs::File;
@@ -109,6 +110,9 @@ fn parse_which(
                 imatrix,
                 calibration_file,
                 hf_cache_path,
+                early_exit: None,
+                byte_fallback: None,
+                tok_env: None,
             },
             chat_template,
             tokenizer_json,
@@ -141,6 +145,9 @@ fn parse_which(
                 imatrix: None,
                 calibration_file: None,
                 hf_cache_path,
+                early_exit: None,
+                byte_fallback: None,
+                tok_env: None,
             },
             chat_template,
             tokenizer_json,
@@ -180,6 +187,9 @@ fn parse_which(
                 imatrix: None,
                 calibration_file: None,
                 hf_cache_path,
+                early_exit: None,
+                byte_fallback: None,
+                tok_env: None,
             },
             chat_template,
             tokenizer_json,
@@ -204,6 +214,11 @@ fn parse_which(
             GGUFSpecificConfig {
                 prompt_chunksize,
                 topology: Topology::from_option_path(topology)?,
+                auto_correct_chat_template: false,
+                byte_fallback: None,
+                force_arch: None,
+                accuracy_companion: None,
+                tok_env: None,
             },
             no_kv_cache,
             jinja_explicit,
@@ -227,6 +242,11 @@ fn parse_which(
             GGUFSpecificConfig {
                 prompt_chunksize,
                 topology: Topology::from_option_path(topology)?,
+                auto_correct_chat_template: false,
+                byte_fallback: None,
+                force_arch: None,
+                accuracy_companion: None,
+                tok_env: None,
             },
             no_kv_cache,
             jinja_explicit,
@@ -258,6 +278,11 @@ fn parse_which(
             GGUFSpecificConfig {
                 prompt_chunksize,
                 topology: Topology::from_option_path(topology)?,
+                auto_correct_chat_template: false,
+                byte_fallback: None,
+                force_arch: None,
+                accuracy_companion: None,
+                tok_env: None,
             },
             no_kv_cache,
             jinja_explicit,
@@ -284,6 +309,8 @@ fn parse_which(
                 gqa,
                 prompt_chunksize,
                 topology: Topology::from_option_path(topology)?,
+                byte_fallback: None,
+                tok_env: None,
             },
             chat_template,
             tokenizer_json,
@@ -311,6 +338,8 @@ fn parse_which(
                 gqa,
                 prompt_chunksize,
                 topology: Topology::from_option_path(topology)?,
+                byte_fallback: None,
+                tok_env: None,
             },
             chat_template,
             tokenizer_json,
@@ -346,6 +375,8 @@ fn parse_which(
                 gqa,
                 prompt_chunksize,
                 topology: Topology::from_option_path(topology)?,
+                byte_fallback: None,
+                tok_env: None,
             },
             chat_template,
             tokenizer_json,
@@ -387,6 +418,8 @@ fn parse_which(
                 calibration_file,
                 imatrix,
                 hf_cache_path,
+                byte_fallback: None,
+                tok_env: None,
             },
             chat_template,
             tokenizer_json,
@@ -449,6 +482,7 @@ impl Runner {
         prefix_cache_n = 16,
         token_source = "cache",
         speculative_gamma = 32,
+        speculative_gamma_bounds = None,
         which_draft = None,
         chat_template = None,
         jinja_explicit = None,
@@ -473,6 +507,7 @@ impl Runner {
         prefix_cache_n: usize,
         token_source: &str,
         speculative_gamma: usize,
+        speculative_gamma_bounds: Option<(usize, usize)>,
         which_draft: Option<Which>,
         chat_template: Option<String>,
         jinja_explicit: Option<String>,
@@ -611,6 +646,7 @@ impl Runner {
                 draft,
                 config: SpeculativeConfig {
                     gamma: speculative_gamma,
+                    gamma_bounds: speculative_gamma_bounds,
                 },
             })
         } else {
@@ -999,12 +1035,24 @@ impl Runner {
                     top_n_logprobs: request.top_logprobs.unwrap_or(1),
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
+                    repeat_last_n: None,
                     max_len: request.max_tokens,
                     stop_toks,
                     logits_bias: request.logit_bias.clone(),
                     n_choices: request.n_choices,
                     min_p: request.min_p,
                     dry_params,
+                    include_stop_str_in_output: request.include_stop_str_in_output,
+                    stop_on_balanced: None,
+                    reserved_output_tokens: 0,
+                    continue_word: false,
+                    tfs_z: None,
+                    length_penalty: None,
+                    sampler: SamplerBackend::Native,
+                    strip_response_prefix: None,
+                    hybrid_decode: None,
+                    adaptive_temperature: None,
+                    stop_lookback: None,
                 },
                 response: tx,
                 return_logprobs: request.logprobs,
@@ -1016,6 +1064,12 @@ impl Runner {
                 logits_processors: None,
                 return_raw_logits: false,
                 web_search_options: request.web_search_options.clone(),
+                enable_thinking: request.enable_thinking,
+                strict_system_role: request.strict_system_role,
+                dynamic_grammar: None,
+                return_effective_params: false,
+                documents: None,
+                adapters: None,
             });
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -1105,12 +1159,24 @@ impl Runner {
                     top_n_logprobs: 1,
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
+                    repeat_last_n: None,
                     max_len: request.max_tokens,
                     stop_toks,
                     logits_bias: request.logit_bias.clone(),
                     n_choices: request.n_choices,
                     min_p: request.min_p,
                     dry_params,
+                    include_stop_str_in_output: request.include_stop_str_in_output,
+                    stop_on_balanced: None,
+                    reserved_output_tokens: 0,
+                    continue_word: false,
+                    tfs_z: None,
+                    length_penalty: None,
+                    sampler: SamplerBackend::Native,
+                    strip_response_prefix: None,
+                    hybrid_decode: None,
+                    adaptive_temperature: None,
+                    stop_lookback: None,
                 },
                 response: tx,
                 return_logprobs: false,
@@ -1122,6 +1188,12 @@ impl Runner {
                 logits_processors: None,
                 return_raw_logits: false,
                 web_search_options: None,
+                enable_thinking: None,
+                strict_system_role: false,
+                dynamic_grammar: None,
+                return_effective_params: false,
+                documents: None,
+                adapters: None,
             });
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -1179,6 +1251,12 @@ impl Runner {
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: None,
+            enable_thinking: None,
+            strict_system_role: false,
+            dynamic_grammar: None,
+            return_effective_params: false,
+            documents: None,
+            adapters: None,
         });
 
         let sender = self.runner.get_sender()?;
@@ -1260,6 +1338,7 @@ fn mistralrs(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<mistralrs_core::Choice>()?;
     m.add_class::<mistralrs_core::ChunkChoice>()?;
     m.add_class::<mistralrs_core::Usage>()?;
+    m.add_class::<mistralrs_core::SpeculativeStats>()?;
     m.add_class::<mistralrs_core::ChatCompletionResponse>()?;
     m.add_class::<mistralrs_core::ChatCompletionChunkResponse>()?;
     m.add_class::<mistralrs_core::CompletionChoice>()?;
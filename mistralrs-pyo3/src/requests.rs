@@ -43,6 +43,7 @@ pub struct CompletionRequest {
     pub(crate) dry_base: Option<f32>,
     pub(crate) dry_allowed_length: Option<usize>,
     pub(crate) dry_sequence_breakers: Option<Vec<String>>,
+    pub(crate) include_stop_str_in_output: bool,
 }
 
 #[pymethods]
@@ -72,6 +73,7 @@ impl CompletionRequest {
         dry_base=None,
         dry_allowed_length=None,
         dry_sequence_breakers=None,
+        include_stop_str_in_output=false,
     ))]
     fn new(
         prompt: String,
@@ -97,6 +99,7 @@ impl CompletionRequest {
         dry_base: Option<f32>,
         dry_allowed_length: Option<usize>,
         dry_sequence_breakers: Option<Vec<String>>,
+        include_stop_str_in_output: bool,
     ) -> PyResult<Self> {
         Ok(Self {
             prompt,
@@ -122,6 +125,7 @@ impl CompletionRequest {
             dry_allowed_length,
             dry_base,
             dry_sequence_breakers,
+            include_stop_str_in_output,
         })
     }
 }
@@ -163,6 +167,9 @@ pub struct ChatCompletionRequest {
     pub(crate) dry_allowed_length: Option<usize>,
     pub(crate) dry_sequence_breakers: Option<Vec<String>>,
     pub(crate) web_search_options: Option<WebSearchOptions>,
+    pub(crate) enable_thinking: Option<bool>,
+    pub(crate) strict_system_role: bool,
+    pub(crate) include_stop_str_in_output: bool,
 }
 
 #[pymethods]
@@ -193,6 +200,9 @@ impl ChatCompletionRequest {
         dry_allowed_length=None,
         dry_sequence_breakers=None,
         web_search_options=None,
+        enable_thinking=None,
+        strict_system_role=false,
+        include_stop_str_in_output=false,
     ))]
     fn new(
         messages: Py<PyAny>,
@@ -219,6 +229,9 @@ impl ChatCompletionRequest {
         dry_allowed_length: Option<usize>,
         dry_sequence_breakers: Option<Vec<String>>,
         web_search_options: Option<WebSearchOptions>,
+        enable_thinking: Option<bool>,
+        strict_system_role: bool,
+        include_stop_str_in_output: bool,
     ) -> PyResult<Self> {
         let messages = Python::with_gil(|py| {
             if let Ok(messages) = messages.bind(py).downcast_exact::<PyList>() {
@@ -293,6 +306,9 @@ impl ChatCompletionRequest {
             dry_base,
             dry_sequence_breakers,
             web_search_options,
+            enable_thinking,
+            strict_system_role,
+            include_stop_str_in_output,
         })
     }
 }
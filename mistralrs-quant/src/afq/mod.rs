@@ -100,6 +100,7 @@ impl QuantMethod for AfqLayer {
             | QuantMethodConfig::FP8 { .. }
             | QuantMethodConfig::Bnb { .. }
             | QuantMethodConfig::BlockwiseFP8 { .. }
+            | QuantMethodConfig::Awq { .. }
             | QuantMethodConfig::Unquantized(_) => unreachable!(),
             QuantMethodConfig::Afq {
                 weight,
@@ -0,0 +1,313 @@
+use std::sync::{atomic::AtomicUsize, Arc};
+
+use candle_core::{DType, Device, Result, Tensor};
+use rayon::{
+    iter::{IndexedParallelIterator, ParallelIterator},
+    slice::ParallelSliceMut,
+};
+
+use crate::{
+    DummyLayer, IsqType, QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedConfig,
+    QuantizedSerde, ShardedVarBuilder,
+};
+
+/// Position in each packed `i32` of the 8 consecutive output columns: AutoAWQ interleaves them
+/// as `[0, 2, 4, 6, 1, 3, 5, 7]` when packing.
+const AWQ_REVERSE_ORDER: [usize; 8] = [0, 4, 1, 5, 2, 6, 3, 7];
+
+/// AWQ layer, as written by AutoAWQ with the GEMM layout. There are no AWQ kernels, so the weight
+/// is dequantized at each forward pass, then multiplied with the input in its dtype.
+#[derive(Debug)]
+pub struct AwqLayer {
+    q_weight: Tensor,
+    qzeros: Option<Tensor>,
+    scales: Tensor,
+    bits: usize,
+    group_size: usize,
+    bias: Option<Tensor>,
+}
+
+/// Dequantize an AWQ weight to `(in_dim, out_dim)`, in the dtype of `scales`.
+///
+/// `q_weight` is `(in_dim, out_dim / 8)` and `qzeros` is `(n_groups, out_dim / 8)`, with 8
+/// values packed in each `i32` in the AutoAWQ order. Each group is `group_size` consecutive
+/// input rows. Without `qzeros`, the quantization is symmetric around `1 << (bits - 1)`.
+pub(crate) fn dequantize_awq(
+    q_weight: &Tensor,
+    qzeros: Option<&Tensor>,
+    scales: &Tensor,
+    bits: usize,
+    group_size: usize,
+) -> Result<Tensor> {
+    if bits != 4 {
+        candle_core::bail!("Only 4-bit AWQ is supported, got {bits} bits.");
+    }
+    if group_size == 0 {
+        candle_core::bail!("The AWQ group size must not be 0.");
+    }
+    let pack = 32 / bits;
+    let mask = (1u32 << bits) - 1;
+
+    let q_weight = q_weight.to_vec2::<i32>()?;
+    let qzeros = qzeros.map(|z| z.to_vec2::<i32>()).transpose()?;
+    let scales_dtype = scales.dtype();
+    let device = scales.device().clone();
+    let scales = scales.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+
+    let in_dim = q_weight.len();
+    let out_dim = q_weight.first().map(|row| row.len()).unwrap_or(0) * pack;
+    let n_groups = in_dim.div_ceil(group_size);
+    if scales.len() < n_groups || qzeros.as_ref().is_some_and(|z| z.len() < n_groups) {
+        candle_core::bail!("Expected {n_groups} AWQ groups for {in_dim} input rows.");
+    }
+
+    let mut w = vec![0f32; in_dim * out_dim];
+    w.par_chunks_mut(out_dim.max(1))
+        .enumerate()
+        .for_each(|(k, row)| {
+            let g = k / group_size;
+            for (n, w) in row.iter_mut().enumerate() {
+                let shift = bits * AWQ_REVERSE_ORDER[n % pack];
+                let q = (q_weight[k][n / pack] as u32 >> shift) & mask;
+                let z = match &qzeros {
+                    Some(qzeros) => (qzeros[g][n / pack] as u32 >> shift) & mask,
+                    None => 1 << (bits - 1),
+                };
+                *w = (q as f32 - z as f32) * scales[g][n];
+            }
+        });
+    Tensor::from_vec(w, (in_dim, out_dim), &device)?.to_dtype(scales_dtype)
+}
+
+impl QuantMethod for AwqLayer {
+    fn new(method: QuantMethodConfig) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        match method {
+            QuantMethodConfig::Awq {
+                bits,
+                group_size,
+                q_weight,
+                qzeros,
+                scales,
+                bias,
+            } => {
+                if bits != 4 {
+                    candle_core::bail!("Only 4-bit AWQ is supported, got {bits} bits.");
+                }
+                Ok(Self {
+                    q_weight,
+                    qzeros,
+                    scales,
+                    bits,
+                    group_size,
+                    bias,
+                })
+            }
+            QuantMethodConfig::Gguf { .. }
+            | QuantMethodConfig::Gptq { .. }
+            | QuantMethodConfig::Unquantized(_)
+            | QuantMethodConfig::Hqq { .. }
+            | QuantMethodConfig::Dummy
+            | QuantMethodConfig::FP8 { .. }
+            | QuantMethodConfig::Bnb { .. }
+            | QuantMethodConfig::BlockwiseFP8 { .. }
+            | QuantMethodConfig::Afq { .. } => {
+                unreachable!()
+            }
+        }
+    }
+
+    fn dequantize_w(&self) -> Result<Tensor> {
+        dequantize_awq(
+            &self.q_weight,
+            self.qzeros.as_ref(),
+            &self.scales,
+            self.bits,
+            self.group_size,
+        )?
+        .t()?
+        .contiguous()
+    }
+
+    fn forward(&self, a: &Tensor) -> Result<Tensor> {
+        let w = dequantize_awq(
+            &self.q_weight,
+            self.qzeros.as_ref(),
+            &self.scales,
+            self.bits,
+            self.group_size,
+        )?
+        .to_device(a.device())?
+        .to_dtype(a.dtype())?;
+        let out = a.broadcast_matmul(&w)?;
+        match &self.bias {
+            Some(bias) => out.broadcast_add(&bias.to_dtype(out.dtype())?),
+            None => Ok(out),
+        }
+    }
+
+    fn quantized_act_type(&self) -> Option<DType> {
+        None
+    }
+
+    fn add_delta_w(&self, _delta: &Tensor) -> Result<Arc<dyn QuantMethod>> {
+        candle_core::bail!("AWQ quantization does not support adding weight delta.")
+    }
+
+    fn dtype_and_device(&self) -> (DType, candle_core::Device) {
+        (self.scales.dtype(), self.scales.device().clone())
+    }
+
+    fn apply_isq(
+        self: Arc<Self>,
+        _dtype: Option<IsqType>,
+        _device: Device,
+        _n_quantized: &AtomicUsize,
+        _imatrix_weight: Option<Vec<f32>>,
+        _guard: QuantizeOntoGuard,
+    ) -> Result<Arc<dyn QuantMethod>> {
+        candle_core::bail!("AWQ quantization does not support ISQ.")
+    }
+}
+
+impl QuantizedSerde for AwqLayer {
+    fn name(&self) -> &'static str {
+        "awq"
+    }
+}
+
+pub fn awq_linear(
+    in_dim: usize,
+    out_dim: usize,
+    config: &QuantizedConfig,
+    vb: ShardedVarBuilder,
+) -> Result<Arc<dyn QuantMethod>> {
+    let QuantizedConfig::Awq {
+        bits,
+        group_size,
+        zero_point,
+    } = config
+    else {
+        candle_core::bail!("Unexpected quantization config.")
+    };
+    if *bits != 4 || *group_size == 0 {
+        candle_core::bail!("Only 4-bit AWQ with a nonzero group size is supported.");
+    }
+
+    // Handle the case where the layer is dummy (no tensors)
+    if !(vb.contains_tensor("qweight")
+        && vb.contains_tensor("scales")
+        && (!zero_point || vb.contains_tensor("qzeros")))
+    {
+        let layer = <DummyLayer as QuantMethod>::new(QuantMethodConfig::Dummy)?;
+        return Ok(Arc::new(layer) as Arc<dyn QuantMethod>);
+    }
+
+    let pack = 32 / bits;
+    let qweight = vb.get_with_hints_dtype(
+        (in_dim, out_dim / pack),
+        "qweight",
+        Default::default(),
+        DType::I32,
+    )?;
+    let n_groups = in_dim / group_size;
+    let qzeros = if *zero_point {
+        Some(vb.get_with_hints_dtype(
+            (n_groups, out_dim / pack),
+            "qzeros",
+            Default::default(),
+            DType::I32,
+        )?)
+    } else {
+        None
+    };
+    let scales = vb.get_with_hints_dtype(
+        (n_groups, out_dim),
+        "scales",
+        Default::default(),
+        DType::F16,
+    )?;
+    let bias = if vb.contains_tensor("bias") {
+        Some(vb.get_with_hints_dtype((out_dim,), "bias", Default::default(), DType::F16)?)
+    } else {
+        None
+    };
+
+    let config = QuantMethodConfig::Awq {
+        bits: *bits,
+        group_size: *group_size,
+        q_weight: qweight,
+        qzeros,
+        scales,
+        bias,
+    };
+    Ok(Arc::new(AwqLayer::new(config)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::dequantize_awq;
+
+    /// Pack 8 4-bit `values`, one per output column, like AutoAWQ.
+    fn pack(values: &[u32; 8]) -> i32 {
+        [0, 2, 4, 6, 1, 3, 5, 7]
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (i, col)| acc | (values[*col] << (4 * i))) as i32
+    }
+
+    #[test]
+    fn dequantizes_asymmetric_groups() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        // 4 input rows of 8 output columns, in groups of 2 rows.
+        let q = [
+            [0u32, 1, 2, 3, 4, 5, 6, 7],
+            [8, 9, 10, 11, 12, 13, 14, 15],
+            [15, 0, 15, 0, 15, 0, 15, 0],
+            [3, 3, 3, 3, 12, 12, 12, 12],
+        ];
+        let z = [[8u32, 8, 8, 8, 0, 0, 0, 0], [1, 2, 3, 4, 5, 6, 7, 9]];
+        let s = [
+            [0.5f32, 0.5, 0.5, 0.5, 1.0, 1.0, 1.0, 1.0],
+            [2.0, 2.0, 0.25, 0.25, 1.0, 1.0, 0.5, 0.5],
+        ];
+        let q_weight = Tensor::new(
+            &[[pack(&q[0])], [pack(&q[1])], [pack(&q[2])], [pack(&q[3])]],
+            &dev,
+        )?;
+        let qzeros = Tensor::new(&[[pack(&z[0])], [pack(&z[1])]], &dev)?;
+        let scales = Tensor::new(&s, &dev)?.to_dtype(DType::F16)?;
+
+        let w = dequantize_awq(&q_weight, Some(&qzeros), &scales, 4, 2)?;
+        assert_eq!(w.dtype(), DType::F16);
+        assert_eq!(
+            w.to_dtype(DType::F32)?.to_vec2::<f32>()?,
+            vec![
+                vec![-4.0, -3.5, -3.0, -2.5, 4.0, 5.0, 6.0, 7.0],
+                vec![0.0, 0.5, 1.0, 1.5, 12.0, 13.0, 14.0, 15.0],
+                vec![28.0, -4.0, 3.0, -1.0, 10.0, -6.0, 4.0, -4.5],
+                vec![4.0, 2.0, 0.0, -0.25, 7.0, 6.0, 2.5, 1.5],
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn dequantizes_symmetric() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let q_weight = Tensor::new(&[[pack(&[0, 8, 15, 7, 1, 9, 4, 12])]], &dev)?;
+        let scales = Tensor::new(&[[0.5f32; 8]], &dev)?;
+
+        let w = dequantize_awq(&q_weight, None, &scales, 4, 1)?;
+        assert_eq!(
+            w.flatten_all()?.to_vec1::<f32>()?,
+            vec![-4.0, 0.0, 3.5, -0.5, -3.5, 0.5, -2.0, 2.0]
+        );
+        assert!(dequantize_awq(&q_weight, None, &scales, 8, 1).is_err());
+        Ok(())
+    }
+}
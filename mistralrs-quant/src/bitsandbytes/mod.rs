@@ -212,7 +212,8 @@ impl QuantMethod for BnbLinear {
             | QuantMethodConfig::Unquantized(_)
             | QuantMethodConfig::FP8 { .. }
             | QuantMethodConfig::BlockwiseFP8 { .. }
-            | QuantMethodConfig::Afq { .. } => unreachable!(),
+            | QuantMethodConfig::Afq { .. }
+            | QuantMethodConfig::Awq { .. } => unreachable!(),
             QuantMethodConfig::Bnb {
                 weight,
                 bias,
@@ -4,7 +4,7 @@ use candle_core::{Context, Result, Tensor};
 use candle_nn::Linear;
 
 use crate::{
-    blockwise_fp8::blockwise_fp8_linear_b, distributed, gptq::gptq_linear,
+    awq::awq_linear, blockwise_fp8::blockwise_fp8_linear_b, distributed, gptq::gptq_linear,
     lora::merge_lora_weights, AfqLayer, BnbLinear, DistributedKind, DummyLayer, FP8Linear,
     GgufMatMul, HqqLayer, QuantMethod, QuantMethodConfig, QuantizeOntoGuard, QuantizedConfig,
     QuantizedSerde, QuantizedSerdeType, Shard, ShardedVarBuilder, UnquantLinear,
@@ -50,10 +50,11 @@ impl RowParallelLayer {
                 QuantizedConfig::Gptq { .. }
                     | QuantizedConfig::Bitsandbytes { .. }
                     | QuantizedConfig::Afq { .. }
+                    | QuantizedConfig::Awq { .. }
             ) && comm.world_size() != 1
             {
                 candle_core::bail!(
-                    "GPTQ and BNB and AFQ quantization types to not support tensor parallelism, but got a world size of {}",
+                    "GPTQ and BNB and AFQ and AWQ quantization types to not support tensor parallelism, but got a world size of {}",
                     comm.world_size()
                 );
             }
@@ -72,6 +73,7 @@ impl RowParallelLayer {
                 QuantizedConfig::Afq { .. } => {
                     AfqLayer::afq_linear_b(in_dim, out_dim, quant_conf, bias, vb.clone())?
                 }
+                QuantizedConfig::Awq { .. } => awq_linear(in_dim, out_dim, quant_conf, vb.clone())?,
             }
         } else {
             // Handle the case where the layer is dummy (no tensors)
@@ -243,10 +245,11 @@ impl ColumnParallelLayer {
                 QuantizedConfig::Gptq { .. }
                     | QuantizedConfig::Bitsandbytes { .. }
                     | QuantizedConfig::Afq { .. }
+                    | QuantizedConfig::Awq { .. }
             ) && comm.world_size() != 1
             {
                 candle_core::bail!(
-                    "GPTQ and BNB and AFQ quantization types to not support tensor parallelism, but got a world size of {}",
+                    "GPTQ and BNB and AFQ and AWQ quantization types to not support tensor parallelism, but got a world size of {}",
                     comm.world_size()
                 );
             }
@@ -265,6 +268,7 @@ impl ColumnParallelLayer {
                 QuantizedConfig::Afq { .. } => {
                     AfqLayer::afq_linear_b(in_dim, out_dim, quant_conf, bias, vb.clone())?
                 }
+                QuantizedConfig::Awq { .. } => awq_linear(in_dim, out_dim, quant_conf, vb.clone())?,
             }
         } else {
             // Handle the case where the layer is dummy (no tensors)
@@ -455,6 +459,7 @@ impl ReplicatedLayer {
                 QuantizedConfig::Afq { .. } => {
                     AfqLayer::afq_linear_b(in_dim, out_dim, quant_conf, bias, vb.clone())?
                 }
+                QuantizedConfig::Awq { .. } => awq_linear(in_dim, out_dim, quant_conf, vb.clone())?,
             }
         } else {
             // Handle the case where the layer is dummy (no tensors)
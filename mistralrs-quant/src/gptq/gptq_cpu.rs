@@ -3,10 +3,76 @@ use crate::{
     QuantizedSerde, ShardedVarBuilder,
 };
 use candle_core::{DType, Device, Result, Tensor};
+use rayon::{
+    iter::{IndexedParallelIterator, ParallelIterator},
+    slice::ParallelSliceMut,
+};
 use std::sync::{atomic::AtomicUsize, Arc};
 
+/// GPTQ layer without the CUDA kernels: the weight is dequantized at each forward pass, then
+/// multiplied with the input in its dtype.
 #[derive(Debug)]
-pub struct GptqLayer;
+pub struct GptqLayer {
+    q_weight: Tensor,
+    gptq_qzeros: Tensor,
+    gptq_scales: Tensor,
+    g_idx: Tensor,
+    bits: usize,
+    bias: Option<Tensor>,
+}
+
+/// Dequantize a GPTQ weight to `(in_dim, out_dim)`, in the dtype of `scales`.
+///
+/// `q_weight` is `(in_dim / pack, out_dim)` and `qzeros` is `(n_groups, out_dim / pack)`, with
+/// `pack = 32 / bits` values packed in each `i32` from the least significant bits. `g_idx` gives
+/// the group of each input row, which is not contiguous for `act_order` (`desc_act`) checkpoints.
+/// The zeros are stored minus one, as in the AutoGPTQ checkpoints.
+pub(crate) fn dequantize_gptq(
+    q_weight: &Tensor,
+    qzeros: &Tensor,
+    scales: &Tensor,
+    g_idx: &Tensor,
+    bits: usize,
+) -> Result<Tensor> {
+    if !matches!(bits, 2 | 4 | 8) {
+        candle_core::bail!("{bits}-bit GPTQ is only supported on CUDA.");
+    }
+    let pack = 32 / bits;
+    let mask = (1u32 << bits) - 1;
+
+    let q_weight = q_weight.to_vec2::<i32>()?;
+    let qzeros = qzeros.to_vec2::<i32>()?;
+    let g_idx = g_idx.to_vec1::<i32>()?;
+    let scales_dtype = scales.dtype();
+    let device = scales.device().clone();
+    let scales = scales.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+
+    let out_dim = q_weight.first().map(|row| row.len()).unwrap_or(0);
+    let in_dim = q_weight.len() * pack;
+    if g_idx.len() != in_dim {
+        candle_core::bail!("Expected {in_dim} GPTQ group indices, got {}.", g_idx.len());
+    }
+    if let Some(g) = g_idx
+        .iter()
+        .find(|g| **g < 0 || **g as usize >= scales.len().min(qzeros.len()))
+    {
+        candle_core::bail!("GPTQ group index {g} is out of range.");
+    }
+
+    let mut w = vec![0f32; in_dim * out_dim];
+    w.par_chunks_mut(out_dim.max(1))
+        .enumerate()
+        .for_each(|(k, row)| {
+            let g = g_idx[k] as usize;
+            let shift = bits * (k % pack);
+            for (n, w) in row.iter_mut().enumerate() {
+                let q = (q_weight[k / pack][n] as u32 >> shift) & mask;
+                let z = ((qzeros[g][n / pack] as u32 >> (bits * (n % pack))) & mask) + 1;
+                *w = (q as f32 - z as f32) * scales[g][n];
+            }
+        });
+    Tensor::from_vec(w, (in_dim, out_dim), &device)?.to_dtype(scales_dtype)
+}
 
 impl QuantMethod for GptqLayer {
     fn new(method: QuantMethodConfig) -> Result<Self>
@@ -14,7 +80,34 @@ impl QuantMethod for GptqLayer {
         Self: Sized,
     {
         match method {
-            QuantMethodConfig::Gptq { .. } => candle_core::bail!("GPTQ is only supported on CUDA."),
+            QuantMethodConfig::Gptq {
+                bits,
+                use_exllama: _,
+                q_weight,
+                gptq_qzeros,
+                gptq_scales,
+                g_idx,
+                bias,
+                workspace: _,
+                is_marlin,
+            } => {
+                let (Some(gptq_qzeros), Some(g_idx), false) = (gptq_qzeros, g_idx, is_marlin)
+                else {
+                    candle_core::bail!("Marlin GPTQ checkpoints are only supported on CUDA.");
+                };
+                let bits = bits as usize;
+                if !matches!(bits, 2 | 4 | 8) {
+                    candle_core::bail!("{bits}-bit GPTQ is only supported on CUDA.");
+                }
+                Ok(Self {
+                    q_weight,
+                    gptq_qzeros,
+                    gptq_scales,
+                    g_idx,
+                    bits,
+                    bias,
+                })
+            }
             QuantMethodConfig::Gguf { .. }
             | QuantMethodConfig::Unquantized(_)
             | QuantMethodConfig::Hqq { .. }
@@ -22,30 +115,52 @@ impl QuantMethod for GptqLayer {
             | QuantMethodConfig::FP8 { .. }
             | QuantMethodConfig::Bnb { .. }
             | QuantMethodConfig::BlockwiseFP8 { .. }
-            | QuantMethodConfig::Afq { .. } => {
+            | QuantMethodConfig::Afq { .. }
+            | QuantMethodConfig::Awq { .. } => {
                 unreachable!()
             }
         }
     }
 
     fn dequantize_w(&self) -> Result<Tensor> {
-        todo!()
+        dequantize_gptq(
+            &self.q_weight,
+            &self.gptq_qzeros,
+            &self.gptq_scales,
+            &self.g_idx,
+            self.bits,
+        )?
+        .t()?
+        .contiguous()
     }
 
-    fn forward(&self, _a: &Tensor) -> Result<Tensor> {
-        todo!()
+    fn forward(&self, a: &Tensor) -> Result<Tensor> {
+        let w = dequantize_gptq(
+            &self.q_weight,
+            &self.gptq_qzeros,
+            &self.gptq_scales,
+            &self.g_idx,
+            self.bits,
+        )?
+        .to_device(a.device())?
+        .to_dtype(a.dtype())?;
+        let out = a.broadcast_matmul(&w)?;
+        match &self.bias {
+            Some(bias) => out.broadcast_add(&bias.to_dtype(out.dtype())?),
+            None => Ok(out),
+        }
     }
 
     fn quantized_act_type(&self) -> Option<DType> {
-        todo!()
+        None
     }
 
     fn add_delta_w(&self, _delta: &Tensor) -> Result<Arc<dyn QuantMethod>> {
-        todo!()
+        candle_core::bail!("GPTQ quantization does not support adding weight delta.")
     }
 
     fn dtype_and_device(&self) -> (DType, candle_core::Device) {
-        todo!()
+        (self.gptq_scales.dtype(), self.gptq_scales.device().clone())
     }
 
     fn apply_isq(
@@ -56,7 +171,7 @@ impl QuantMethod for GptqLayer {
         _imatrix_weight: Option<Vec<f32>>,
         _guard: QuantizeOntoGuard,
     ) -> Result<Arc<dyn QuantMethod>> {
-        todo!()
+        candle_core::bail!("GPTQ quantization does not support ISQ.")
     }
 }
 
@@ -136,3 +251,62 @@ pub fn gptq_linear(
     };
     Ok(Arc::new(GptqLayer::new(config)?))
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::dequantize_gptq;
+
+    /// Pack `values` (each below `1 << bits`) into `i32`s from the least significant bits.
+    fn pack(values: &[u32], bits: usize) -> i32 {
+        values
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (i, v)| acc | (v << (bits * i))) as i32
+    }
+
+    #[test]
+    fn dequantizes_4bit_with_act_order() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        // 8 input rows packed in one i32 per output column, 2 output columns.
+        let w0 = [0u32, 1, 2, 3, 4, 5, 6, 15];
+        let w1 = [15u32, 14, 13, 12, 11, 10, 9, 8];
+        let q_weight = Tensor::new(&[[pack(&w0, 4), pack(&w1, 4)]], &dev)?;
+        // Two groups, zeros stored minus one: group 0 has zeros (8, 1), group 1 has (2, 3).
+        let qzeros = Tensor::new(&[[pack(&[7, 0], 4)], [pack(&[1, 2], 4)]], &dev)?;
+        let scales = Tensor::new(&[[0.5f32, 1.0], [2.0, 0.25]], &dev)?.to_dtype(DType::F16)?;
+        // Rows are assigned to the groups out of order.
+        let g_idx = Tensor::new(&[0i32, 1, 0, 1, 0, 1, 0, 1], &dev)?;
+
+        let w = dequantize_gptq(&q_weight, &qzeros, &scales, &g_idx, 4)?;
+        assert_eq!(w.dtype(), DType::F16);
+        let w = w.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+
+        let zeros = [[8f32, 1.], [2., 3.]];
+        let scales = [[0.5f32, 1.0], [2.0, 0.25]];
+        for k in 0..8 {
+            let g = k % 2;
+            assert_eq!(w[k][0], (w0[k] as f32 - zeros[g][0]) * scales[g][0]);
+            assert_eq!(w[k][1], (w1[k] as f32 - zeros[g][1]) * scales[g][1]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn dequantizes_8bit() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let q_weight = Tensor::new(&[[pack(&[0, 128, 255, 10], 8)]], &dev)?;
+        let qzeros = Tensor::new(&[[pack(&[127], 8)]], &dev)?;
+        let scales = Tensor::new(&[[0.5f32]], &dev)?;
+        let g_idx = Tensor::new(&[0i32, 0, 0, 0], &dev)?;
+
+        let w = dequantize_gptq(&q_weight, &qzeros, &scales, &g_idx, 8)?;
+        assert_eq!(
+            w.flatten_all()?.to_vec1::<f32>()?,
+            vec![-64.0, 0.0, 63.5, -59.0]
+        );
+        assert!(dequantize_gptq(&q_weight, &qzeros, &scales, &g_idx, 3).is_err());
+        Ok(())
+    }
+}
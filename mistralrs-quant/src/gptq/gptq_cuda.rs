@@ -263,7 +263,8 @@ impl QuantMethod for GptqLayer {
             | QuantMethodConfig::FP8 { .. }
             | QuantMethodConfig::Bnb { .. }
             | QuantMethodConfig::BlockwiseFP8 { .. }
-            | QuantMethodConfig::Afq { .. } => {
+            | QuantMethodConfig::Afq { .. }
+            | QuantMethodConfig::Awq { .. } => {
                 unreachable!()
             }
         }
@@ -528,7 +528,8 @@ impl QuantMethod for HqqLayer {
             | QuantMethodConfig::FP8 { .. }
             | QuantMethodConfig::Bnb { .. }
             | QuantMethodConfig::BlockwiseFP8 { .. }
-            | QuantMethodConfig::Afq { .. } => {
+            | QuantMethodConfig::Afq { .. }
+            | QuantMethodConfig::Awq { .. } => {
                 unreachable!()
             }
             QuantMethodConfig::Hqq {
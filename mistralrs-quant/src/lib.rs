@@ -15,6 +15,7 @@ use candle_core::{
 mod metal_kernels;
 
 mod afq;
+mod awq;
 mod bitsandbytes;
 mod blockwise_fp8;
 pub mod cublaslt;
@@ -31,11 +32,13 @@ pub mod safetensors;
 mod unquantized;
 mod utils;
 
+use awq::awq_linear;
 use gptq::gptq_linear;
 use lora::merge_lora_weights;
 pub use safetensors::{Shard, ShardedSafeTensors, ShardedVarBuilder};
 
 pub use afq::{AfqBits, AfqGroupSize, AfqLayer};
+pub use awq::AwqLayer;
 pub use bitsandbytes::{BnbLinear, BnbQuantParmas, BnbQuantType};
 pub use distributed::{
     layers::{
@@ -79,6 +82,11 @@ pub enum QuantizedConfig {
         bits: usize,
         group_size: usize,
     },
+    Awq {
+        bits: usize,
+        group_size: usize,
+        zero_point: bool,
+    },
 }
 
 // Common fields for all variants
@@ -90,6 +98,8 @@ struct RawConfig {
     checkpoint_format: Option<String>,
     weight_block_size: Option<Vec<usize>>,
     bnb_4bit_quant_type: Option<String>,
+    zero_point: Option<bool>,
+    version: Option<String>,
 }
 
 // Custom deserializer implementation
@@ -132,6 +142,24 @@ impl<'de> Deserialize<'de> for QuantizedConfig {
                     .ok_or_else(|| serde::de::Error::missing_field("group_size"))?;
                 Ok(QuantizedConfig::Afq { bits, group_size })
             }
+            Some(m) if m == "awq" => {
+                let bits = raw
+                    .bits
+                    .ok_or_else(|| serde::de::Error::missing_field("bits"))?;
+                let group_size = raw
+                    .group_size
+                    .ok_or_else(|| serde::de::Error::missing_field("group_size"))?;
+                if let Some(version) = raw.version.filter(|v| !v.eq_ignore_ascii_case("gemm")) {
+                    return Err(serde::de::Error::custom(format!(
+                        "Unsupported AWQ version `{version}`, only the GEMM layout is supported."
+                    )));
+                }
+                Ok(QuantizedConfig::Awq {
+                    bits,
+                    group_size,
+                    zero_point: raw.zero_point.unwrap_or(true),
+                })
+            }
             None => {
                 let bits = raw
                     .bits
@@ -143,7 +171,7 @@ impl<'de> Deserialize<'de> for QuantizedConfig {
             }
             Some(unknown_method) => {
                 Err(serde::de::Error::custom(format!(
-                    "Unknown quantization method: {}. Expected one of: gptq, fp8, bitsandbytes, afq, or not specified", 
+                    "Unknown quantization method: {}. Expected one of: gptq, fp8, bitsandbytes, afq, awq, or not specified", 
                     unknown_method
                 )))
             },
@@ -158,6 +186,7 @@ impl QuantizedConfig {
             Self::Fp8 { .. } => "fp8",
             Self::Bitsandbytes { .. } => "bitsandbytes",
             Self::Afq { .. } => "afq",
+            Self::Awq { .. } => "awq",
         }
     }
 
@@ -172,6 +201,7 @@ impl QuantizedConfig {
                 bnb_4bit_quant_type: None,
             } => "8 bits".to_string(),
             Self::Afq { bits, .. } => format!("{bits} bits"),
+            Self::Awq { bits, .. } => format!("{bits} bits"),
         }
     }
 }
@@ -228,6 +258,14 @@ pub enum QuantMethodConfig {
         bits: AfqBits,
         group_size: AfqGroupSize,
     },
+    Awq {
+        bits: usize,
+        group_size: usize,
+        q_weight: Tensor,
+        qzeros: Option<Tensor>,
+        scales: Tensor,
+        bias: Option<Tensor>,
+    },
 }
 
 /// Device/configurable intelligent matrix multiplication
@@ -661,6 +699,7 @@ pub fn linear_no_bias(
             QuantizedConfig::Afq { .. } => {
                 AfqLayer::afq_linear_b(in_dim, out_dim, quant_conf, false, vb)?
             }
+            QuantizedConfig::Awq { .. } => awq_linear(in_dim, out_dim, quant_conf, vb)?,
         }
     } else {
         // Handle the case where the layer is dummy (no tensors)
@@ -698,6 +737,7 @@ pub fn linear(
             QuantizedConfig::Afq { .. } => {
                 AfqLayer::afq_linear_b(in_dim, out_dim, quant_conf, true, vb)?
             }
+            QuantizedConfig::Awq { .. } => awq_linear(in_dim, out_dim, quant_conf, vb)?,
         }
     } else {
         // Handle the case where the layer is dummy (no tensors)
@@ -73,7 +73,7 @@ pub(crate) fn merge_lora_weights(
         };
 
         let delta_weight = (ab * scale)?;
-        weight = (weight + delta_weight.to_dtype(a.dtype())?)?;
+        weight = (&weight + delta_weight.to_dtype(weight.dtype())?)?;
     }
 
     Ok(weight)
@@ -0,0 +1,608 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    path::PathBuf,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use anyhow::Result;
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::{DateTime, Datelike, Months, NaiveDate, Utc};
+use mistralrs_core::Usage;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// An API key allowed to use the server, as given in the `--api-keys` file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKeyConfig {
+    /// Name of the key in the usage and the metrics, so that the secret is never reported.
+    pub id: String,
+    /// Secret sent as `Authorization: Bearer <key>`.
+    pub key: String,
+    /// Tokens (prompt and completion) the key may use per UTC day.
+    #[serde(default)]
+    pub daily_tokens: Option<u64>,
+    /// Tokens (prompt and completion) the key may use per UTC calendar month.
+    #[serde(default)]
+    pub monthly_tokens: Option<u64>,
+}
+
+/// Token usage of an API key.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct KeyUsage {
+    /// UTC day of `daily_tokens`, as `YYYY-MM-DD`.
+    pub day: Option<String>,
+    pub daily_tokens: u64,
+    /// UTC month of `monthly_tokens`, as `YYYY-MM`.
+    pub month: Option<String>,
+    pub monthly_tokens: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// Admitted requests.
+    pub requests: u64,
+    /// Requests rejected because a quota was used.
+    pub rejected_requests: u64,
+}
+
+impl KeyUsage {
+    /// Reset the daily and monthly tokens if `today` is past their window.
+    fn roll(&mut self, today: NaiveDate) {
+        let day = today.format("%Y-%m-%d").to_string();
+        if self.day.as_ref() != Some(&day) {
+            self.day = Some(day);
+            self.daily_tokens = 0;
+        }
+        let month = today.format("%Y-%m").to_string();
+        if self.month.as_ref() != Some(&month) {
+            self.month = Some(month);
+            self.monthly_tokens = 0;
+        }
+    }
+}
+
+fn first_of_month(day: NaiveDate) -> NaiveDate {
+    day.with_day(1).expect("Every month has a first day.")
+}
+
+/// Where the usage of the API keys is kept between restarts.
+pub trait UsageStore: Send + Sync {
+    fn load(&self) -> Result<HashMap<String, KeyUsage>>;
+    /// Called with the usage of all keys after changes, from a thread of its own so that the
+    /// requests do not wait for it.
+    fn save(&self, usage: &HashMap<String, KeyUsage>) -> Result<()>;
+}
+
+/// Keeps the usage in memory only, so that it starts over on restart.
+#[derive(Debug, Default)]
+pub struct InMemoryUsageStore;
+
+impl UsageStore for InMemoryUsageStore {
+    fn load(&self) -> Result<HashMap<String, KeyUsage>> {
+        Ok(HashMap::new())
+    }
+
+    fn save(&self, _usage: &HashMap<String, KeyUsage>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps the usage in a JSON file, mapping the key ids to their usage.
+#[derive(Debug)]
+pub struct JsonFileUsageStore {
+    path: PathBuf,
+}
+
+impl JsonFileUsageStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl UsageStore for JsonFileUsageStore {
+    fn load(&self) -> Result<HashMap<String, KeyUsage>> {
+        if !self.path.exists() {
+            return Ok(HashMap::new());
+        }
+        Ok(serde_json::from_reader(File::open(&self.path)?)?)
+    }
+
+    fn save(&self, usage: &HashMap<String, KeyUsage>) -> Result<()> {
+        // Write then rename so that a crash never leaves a truncated file.
+        let tmp = self.path.with_extension("tmp");
+        serde_json::to_writer(File::create(&tmp)?, usage)?;
+        std::fs::rename(tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+/// A request rejected because its key used one of its quotas.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuotaExceeded {
+    pub id: String,
+    /// `daily` or `monthly`.
+    pub window: &'static str,
+    pub limit: u64,
+    pub resets_at: DateTime<Utc>,
+}
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Quota exceeded: API key `{}` used its {} quota of {} tokens, which resets at {}.",
+            self.id,
+            self.window,
+            self.limit,
+            self.resets_at.to_rfc3339()
+        )
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// The API keys of the server and their token usage.
+pub struct ApiKeys {
+    /// By secret.
+    keys: HashMap<String, ApiKeyConfig>,
+    /// By key id.
+    usage: Arc<Mutex<HashMap<String, KeyUsage>>>,
+    /// Wakes up the thread saving the usage to the store.
+    saves: Option<mpsc::Sender<()>>,
+    saver: Option<thread::JoinHandle<()>>,
+}
+
+impl ApiKeys {
+    pub fn new(configs: Vec<ApiKeyConfig>, store: Box<dyn UsageStore>) -> Result<Self> {
+        let mut ids = HashSet::new();
+        let mut keys = HashMap::new();
+        for config in configs {
+            if !ids.insert(config.id.clone()) {
+                anyhow::bail!("API key id `{}` is given more than once.", config.id);
+            }
+            if keys.contains_key(&config.key) {
+                anyhow::bail!(
+                    "API key `{}` has the same secret as another key.",
+                    config.id
+                );
+            }
+            keys.insert(config.key.clone(), config);
+        }
+        let usage = Arc::new(Mutex::new(store.load()?));
+        let (saves, changes) = mpsc::channel();
+        let saver = {
+            let usage = usage.clone();
+            thread::spawn(move || {
+                while changes.recv().is_ok() {
+                    // The changes made while the previous save ran are saved at once.
+                    while changes.try_recv().is_ok() {}
+                    let usage = usage.lock().expect("Usage lock was poisoned.").clone();
+                    if let Err(e) = store.save(&usage) {
+                        warn!("Failed to save the API key usage: {e}");
+                    }
+                }
+            })
+        };
+        Ok(Self {
+            keys,
+            usage,
+            saves: Some(saves),
+            saver: Some(saver),
+        })
+    }
+
+    /// Load the keys from a JSON file holding a list of [`ApiKeyConfig`].
+    pub fn from_file(path: &str, store: Box<dyn UsageStore>) -> Result<Self> {
+        let configs: Vec<ApiKeyConfig> = serde_json::from_reader(File::open(path)?)?;
+        Self::new(configs, store)
+    }
+
+    pub fn authenticate(&self, secret: &str) -> Option<&ApiKeyConfig> {
+        self.keys.get(secret)
+    }
+
+    fn config(&self, id: &str) -> Option<&ApiKeyConfig> {
+        self.keys.values().find(|config| config.id == id)
+    }
+
+    /// Admit a request of the key `id` if it has not used its quotas yet. The requests admitted
+    /// before a quota is reached run to completion, so a key may go over its quota by their tokens.
+    pub fn admit(&self, id: &str, now: DateTime<Utc>) -> Result<(), QuotaExceeded> {
+        let config = self.config(id).expect("Admitted keys are authenticated.");
+        let today = now.date_naive();
+        let result = {
+            let mut all_usage = self.usage.lock().expect("Usage lock was poisoned.");
+            let usage = all_usage.entry(id.to_string()).or_default();
+            usage.roll(today);
+            let exceeded = match (config.daily_tokens, config.monthly_tokens) {
+                (Some(limit), _) if usage.daily_tokens >= limit => Some(QuotaExceeded {
+                    id: id.to_string(),
+                    window: "daily",
+                    limit,
+                    resets_at: start_of_day(today + chrono::Days::new(1)),
+                }),
+                (_, Some(limit)) if usage.monthly_tokens >= limit => Some(QuotaExceeded {
+                    id: id.to_string(),
+                    window: "monthly",
+                    limit,
+                    resets_at: start_of_day(first_of_month(today) + Months::new(1)),
+                }),
+                _ => None,
+            };
+            match exceeded {
+                Some(exceeded) => {
+                    usage.rejected_requests += 1;
+                    Err(exceeded)
+                }
+                None => {
+                    usage.requests += 1;
+                    Ok(())
+                }
+            }
+        };
+        self.persist();
+        result
+    }
+
+    /// Account the final usage of a request of the key `id`.
+    pub fn record(&self, id: &str, prompt_tokens: u64, completion_tokens: u64, now: DateTime<Utc>) {
+        {
+            let mut all_usage = self.usage.lock().expect("Usage lock was poisoned.");
+            let usage = all_usage.entry(id.to_string()).or_default();
+            usage.roll(now.date_naive());
+            usage.daily_tokens += prompt_tokens + completion_tokens;
+            usage.monthly_tokens += prompt_tokens + completion_tokens;
+            usage.prompt_tokens += prompt_tokens;
+            usage.completion_tokens += completion_tokens;
+        }
+        self.persist();
+    }
+
+    /// The usage of the key `id`, with the windows which ended before `now` reset.
+    pub fn usage(&self, id: &str, now: DateTime<Utc>) -> KeyUsage {
+        let mut usage = self
+            .usage
+            .lock()
+            .expect("Usage lock was poisoned.")
+            .get(id)
+            .cloned()
+            .unwrap_or_default();
+        usage.roll(now.date_naive());
+        usage
+    }
+
+    /// The usage of every key, by key id.
+    pub fn all_usage(&self, now: DateTime<Utc>) -> BTreeMap<String, KeyUsage> {
+        self.keys
+            .values()
+            .map(|config| (config.id.clone(), self.usage(&config.id, now)))
+            .collect()
+    }
+
+    /// Save the usage to the store in the background.
+    fn persist(&self) {
+        if let Some(saves) = &self.saves {
+            // The saver only stops once the keys are dropped.
+            let _ = saves.send(());
+        }
+    }
+}
+
+impl Drop for ApiKeys {
+    /// Wait for the last changes to be saved.
+    fn drop(&mut self) {
+        self.saves.take();
+        if let Some(saver) = self.saver.take() {
+            if saver.join().is_err() {
+                warn!("The thread saving the API key usage panicked.");
+            }
+        }
+    }
+}
+
+fn start_of_day(day: NaiveDate) -> DateTime<Utc> {
+    day.and_hms_opt(0, 0, 0)
+        .expect("Midnight is a valid time.")
+        .and_utc()
+}
+
+/// The authenticated key of a request, used by the handlers to account its usage.
+#[derive(Clone)]
+pub struct ApiKeyUsage {
+    keys: Arc<ApiKeys>,
+    id: String,
+}
+
+impl ApiKeyUsage {
+    pub fn record(&self, usage: &Usage) {
+        self.record_tokens(usage.prompt_tokens, usage.completion_tokens);
+    }
+
+    fn record_tokens(&self, prompt_tokens: usize, completion_tokens: usize) {
+        self.keys.record(
+            &self.id,
+            prompt_tokens as u64,
+            completion_tokens as u64,
+            Utc::now(),
+        );
+    }
+}
+
+/// The usage of a streamed request, accounted with its final chunk or, if the client stops
+/// reading the stream before, with the tokens of the chunks so far when the stream is dropped.
+pub struct StreamedUsage {
+    key: Option<ApiKeyUsage>,
+    prompt_tokens: usize,
+    completion_tokens: usize,
+}
+
+impl StreamedUsage {
+    pub fn new(key: Option<ApiKeyUsage>) -> Self {
+        Self {
+            key,
+            prompt_tokens: 0,
+            completion_tokens: 0,
+        }
+    }
+
+    /// Count a chunk of a request with `prompt_tokens`, with the `completion_tokens` generated
+    /// since the previous chunk.
+    pub fn add_chunk(&mut self, prompt_tokens: usize, completion_tokens: usize) {
+        self.prompt_tokens = prompt_tokens;
+        self.completion_tokens += completion_tokens;
+    }
+
+    /// Account the final usage of the request.
+    pub fn finish(&mut self, usage: &Usage) {
+        if let Some(key) = self.key.take() {
+            key.record(usage);
+        }
+    }
+}
+
+impl Drop for StreamedUsage {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            if self.prompt_tokens + self.completion_tokens > 0 {
+                key.record_tokens(self.prompt_tokens, self.completion_tokens);
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    message: String,
+}
+
+fn error_response(code: StatusCode, message: String) -> Response {
+    (code, Json(JsonError { message })).into_response()
+}
+
+fn authenticate(keys: &Arc<ApiKeys>, request: &Request) -> Result<ApiKeyUsage, Response> {
+    let secret = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match secret.and_then(|secret| keys.authenticate(secret.trim())) {
+        Some(config) => Ok(ApiKeyUsage {
+            keys: keys.clone(),
+            id: config.id.clone(),
+        }),
+        None => Err(error_response(
+            StatusCode::UNAUTHORIZED,
+            "Missing or unknown API key, expected `Authorization: Bearer <key>`.".to_string(),
+        )),
+    }
+}
+
+/// Middleware requiring a known API key.
+pub async fn require_api_key(
+    State(keys): State<Arc<ApiKeys>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    match authenticate(&keys, &request) {
+        Ok(key) => {
+            request.extensions_mut().insert(key);
+            next.run(request).await
+        }
+        Err(response) => response,
+    }
+}
+
+/// Middleware requiring a known API key which has not used its quotas, answering `429` otherwise.
+pub async fn admit_api_key(
+    State(keys): State<Arc<ApiKeys>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let key = match authenticate(&keys, &request) {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+    let now = Utc::now();
+    if let Err(exceeded) = keys.admit(&key.id, now) {
+        let mut response = error_response(StatusCode::TOO_MANY_REQUESTS, exceeded.to_string());
+        let retry_after = (exceeded.resets_at - now).num_seconds().max(0);
+        if let Ok(value) = HeaderValue::from_str(&retry_after.to_string()) {
+            response.headers_mut().insert(header::RETRY_AFTER, value);
+        }
+        return response;
+    }
+    request.extensions_mut().insert(key);
+    next.run(request).await
+}
+
+#[derive(Serialize)]
+pub struct KeyUsageReport {
+    id: String,
+    daily_tokens_limit: Option<u64>,
+    monthly_tokens_limit: Option<u64>,
+    #[serde(flatten)]
+    usage: KeyUsage,
+}
+
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/v1/usage",
+    responses((status = 200, description = "Token usage and quotas of the API key of the request"))
+)]
+pub async fn usage(Extension(key): Extension<ApiKeyUsage>) -> Json<KeyUsageReport> {
+    let config = key.keys.config(&key.id).expect("Key is authenticated.");
+    Json(KeyUsageReport {
+        id: key.id.clone(),
+        daily_tokens_limit: config.daily_tokens,
+        monthly_tokens_limit: config.monthly_tokens,
+        usage: key.keys.usage(&key.id, Utc::now()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, TimeZone, Utc};
+
+    use std::sync::Arc;
+
+    use mistralrs_core::Usage;
+
+    use super::{
+        ApiKeyConfig, ApiKeyUsage, ApiKeys, InMemoryUsageStore, JsonFileUsageStore, StreamedUsage,
+        UsageStore,
+    };
+
+    fn keys(daily_tokens: Option<u64>, monthly_tokens: Option<u64>) -> ApiKeys {
+        let config = ApiKeyConfig {
+            id: "team".to_string(),
+            key: "secret".to_string(),
+            daily_tokens,
+            monthly_tokens,
+        };
+        ApiKeys::new(vec![config], Box::new(InMemoryUsageStore)).unwrap()
+    }
+
+    fn at(y: i32, m: u32, d: u32, h: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn rejects_once_daily_quota_is_used() {
+        let keys = keys(Some(100), None);
+        assert_eq!(keys.authenticate("secret").unwrap().id, "team");
+        assert!(keys.authenticate("other").is_none());
+
+        let now = at(2024, 3, 10, 12);
+        assert!(keys.admit("team", now).is_ok());
+        keys.record("team", 60, 40, now);
+        let exceeded = keys.admit("team", now).unwrap_err();
+        assert_eq!(exceeded.window, "daily");
+        assert_eq!(exceeded.resets_at, at(2024, 3, 11, 0));
+
+        // The next day starts over.
+        assert!(keys.admit("team", at(2024, 3, 11, 1)).is_ok());
+        let usage = keys.usage("team", at(2024, 3, 11, 1));
+        assert_eq!(usage.daily_tokens, 0);
+        assert_eq!(usage.monthly_tokens, 100);
+        assert_eq!(usage.requests, 2);
+        assert_eq!(usage.rejected_requests, 1);
+    }
+
+    #[test]
+    fn monthly_quota_resets_next_month() {
+        let keys = keys(None, Some(50));
+        keys.record("team", 30, 30, at(2024, 1, 31, 23));
+        let exceeded = keys.admit("team", at(2024, 1, 31, 23)).unwrap_err();
+        assert_eq!(exceeded.window, "monthly");
+        assert_eq!(exceeded.resets_at, at(2024, 2, 1, 0));
+        assert!(keys.admit("team", at(2024, 2, 1, 0)).is_ok());
+    }
+
+    #[test]
+    fn usage_survives_restart_in_json_file() {
+        let path = std::env::temp_dir().join(format!("api-key-usage-{}.json", std::process::id()));
+        let config = ApiKeyConfig {
+            id: "team".to_string(),
+            key: "secret".to_string(),
+            daily_tokens: None,
+            monthly_tokens: None,
+        };
+        let now = at(2024, 5, 1, 8);
+        {
+            let keys = ApiKeys::new(
+                vec![config.clone()],
+                Box::new(JsonFileUsageStore::new(&path)),
+            )
+            .unwrap();
+            keys.record("team", 7, 3, now);
+        }
+        let keys = ApiKeys::new(vec![config], Box::new(JsonFileUsageStore::new(&path))).unwrap();
+        let usage = keys.usage("team", now);
+        assert_eq!(usage.prompt_tokens, 7);
+        assert_eq!(usage.completion_tokens, 3);
+        assert_eq!(usage.daily_tokens, 10);
+        assert!(JsonFileUsageStore::new(&path)
+            .load()
+            .unwrap()
+            .contains_key("team"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn streamed_usage_is_recorded_once() {
+        let keys = Arc::new(keys(None, None));
+        let key = ApiKeyUsage {
+            keys: keys.clone(),
+            id: "team".to_string(),
+        };
+        let today = chrono::Utc::now();
+
+        // A client which stops reading is accounted for the chunks it was sent.
+        let mut stream = StreamedUsage::new(Some(key.clone()));
+        stream.add_chunk(12, 1);
+        stream.add_chunk(12, 2);
+        drop(stream);
+        let usage = keys.usage("team", today);
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (12, 3));
+
+        // The final usage replaces the count of the chunks.
+        let mut stream = StreamedUsage::new(Some(key));
+        stream.add_chunk(5, 1);
+        stream.finish(&Usage {
+            completion_tokens: 2,
+            reasoning_tokens: 0,
+            prompt_tokens: 5,
+            total_tokens: 7,
+            avg_tok_per_sec: 0.,
+            avg_prompt_tok_per_sec: 0.,
+            avg_compl_tok_per_sec: 0.,
+            total_time_sec: 0.,
+            total_prompt_time_sec: 0.,
+            total_completion_time_sec: 0.,
+            speculative: None,
+        });
+        drop(stream);
+        let usage = keys.usage("team", today);
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (17, 5));
+    }
+
+    #[test]
+    fn rejects_duplicate_ids() {
+        let config = ApiKeyConfig {
+            id: "team".to_string(),
+            key: "a".to_string(),
+            daily_tokens: None,
+            monthly_tokens: None,
+        };
+        let mut other = config.clone();
+        other.key = "b".to_string();
+        assert!(ApiKeys::new(vec![config, other], Box::new(InMemoryUsageStore)).is_err());
+    }
+}
@@ -3,6 +3,7 @@ use std::{env, error::Error, ops::Deref, pin::Pin, sync::Arc, task::Poll, time::
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
 use crate::{
+    api_keys::{ApiKeyUsage, StreamedUsage},
     openai::{
         ChatCompletionRequest, Grammar, JsonSchemaResponseFormat, MessageInnerContent,
         ResponseFormat, StopTokens,
@@ -18,12 +19,14 @@ use axum::{
         sse::{Event, KeepAlive},
         IntoResponse, Sse,
     },
+    Extension,
 };
 use either::Either;
 use indexmap::IndexMap;
 use itertools::Itertools;
 use mistralrs_core::{
-    ChatCompletionResponse, Constraint, DrySamplingParams, MistralRs, NormalRequest, Request,
+    ChatCompletionResponse, Constraint, ContextCheck, ContextCheckRequest, DocumentPacking,
+    DrySamplingParams, HybridConfig, MistralRs, NormalRequest, Request, RequestDocuments,
     RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
 };
 use serde::Serialize;
@@ -47,6 +50,7 @@ pub struct Streamer {
     rx: Receiver<Response>,
     done_state: DoneState,
     state: Arc<MistralRs>,
+    usage: StreamedUsage,
 }
 
 impl futures::Stream for Streamer {
@@ -71,7 +75,8 @@ impl futures::Stream for Streamer {
 
         match self.rx.poll_recv(cx) {
             Poll::Ready(Some(resp)) => match resp {
-                Response::ModelError(msg, _) => {
+                Response::ModelError(msg, response) => {
+                    self.usage.finish(&response.usage);
                     MistralRs::maybe_log_error(
                         self.state.clone(),
                         &ModelErrorMessage(msg.to_string()),
@@ -91,6 +96,13 @@ impl futures::Stream for Streamer {
                     if response.choices.iter().all(|x| x.finish_reason.is_some()) {
                         self.done_state = DoneState::SendingDone;
                     }
+                    self.usage.add_chunk(
+                        response.prompt_tokens,
+                        response.choices.iter().map(|x| x.tokens.len()).sum(),
+                    );
+                    if let Some(usage) = &response.usage {
+                        self.usage.finish(usage);
+                    }
                     // Done now, just need to send the [DONE]
                     MistralRs::maybe_log_response(self.state.clone(), &response);
                     Poll::Ready(Some(Event::default().json_data(response)))
@@ -171,7 +183,7 @@ impl IntoResponse for ChatCompletionResponder {
     }
 }
 
-async fn parse_request(
+pub(crate) async fn parse_request(
     oairequest: ChatCompletionRequest,
     state: Arc<MistralRs>,
     tx: Sender<Response>,
@@ -386,11 +398,27 @@ async fn parse_request(
                 top_n_logprobs: oairequest.top_logprobs.unwrap_or(1),
                 frequency_penalty: oairequest.frequency_penalty,
                 presence_penalty: oairequest.presence_penalty,
+                repeat_last_n: oairequest.repeat_last_n,
                 max_len: oairequest.max_tokens,
                 stop_toks,
                 logits_bias: oairequest.logit_bias,
                 n_choices: oairequest.n_choices,
                 dry_params,
+                include_stop_str_in_output: oairequest.include_stop_str_in_output,
+                stop_lookback: oairequest.stop_lookback,
+                stop_on_balanced: oairequest.stop_on_balanced,
+                reserved_output_tokens: oairequest.reserved_output_tokens,
+                continue_word: oairequest.continue_word,
+                tfs_z: oairequest.tfs_z,
+                length_penalty: None,
+                sampler: oairequest.sampler,
+                strip_response_prefix: oairequest.strip_response_prefix,
+                hybrid_decode: oairequest.sample_after_tokens.map(|sample_after_tokens| {
+                    HybridConfig {
+                        sample_after_tokens,
+                    }
+                }),
+                adaptive_temperature: oairequest.adaptive_temperature,
             },
             response: tx,
             return_logprobs: oairequest.logprobs,
@@ -402,6 +430,21 @@ async fn parse_request(
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: oairequest.web_search_options,
+            enable_thinking: oairequest.enable_thinking,
+            strict_system_role: oairequest.strict_system_role,
+            dynamic_grammar: None,
+            return_effective_params: oairequest.return_effective_params,
+            documents: oairequest.documents.map(|documents| {
+                let mut packing = DocumentPacking {
+                    max_tokens_per_document: oairequest.max_tokens_per_document,
+                    ..Default::default()
+                };
+                if let Some(section_format) = oairequest.documents_section_format {
+                    packing.section_format = section_format;
+                }
+                RequestDocuments { documents, packing }
+            }),
+            adapters: oairequest.adapters,
         }),
         is_streaming,
     ))
@@ -416,8 +459,10 @@ async fn parse_request(
 )]
 pub async fn chatcompletions(
     State(state): State<Arc<MistralRs>>,
+    api_key: Option<Extension<ApiKeyUsage>>,
     Json(oairequest): Json<ChatCompletionRequest>,
 ) -> ChatCompletionResponder {
+    let api_key = api_key.map(|Extension(api_key)| api_key);
     let (tx, mut rx) = channel(10_000);
     let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx).await {
         Ok(x) => x,
@@ -440,6 +485,7 @@ pub async fn chatcompletions(
             rx,
             done_state: DoneState::Running,
             state,
+            usage: StreamedUsage::new(api_key),
         };
 
         let keep_alive_interval = env::var("KEEP_ALIVE_INTERVAL")
@@ -465,12 +511,18 @@ pub async fn chatcompletions(
                 ChatCompletionResponder::InternalError(e)
             }
             Response::ModelError(msg, response) => {
+                if let Some(api_key) = &api_key {
+                    api_key.record(&response.usage);
+                }
                 MistralRs::maybe_log_error(state.clone(), &ModelErrorMessage(msg.to_string()));
                 MistralRs::maybe_log_response(state, &response);
                 ChatCompletionResponder::ModelError(msg, response)
             }
             Response::ValidationError(e) => ChatCompletionResponder::ValidationError(e),
             Response::Done(response) => {
+                if let Some(api_key) = &api_key {
+                    api_key.record(&response.usage);
+                }
                 MistralRs::maybe_log_response(state, &response);
                 ChatCompletionResponder::Json(response)
             }
@@ -483,3 +535,42 @@ pub async fn chatcompletions(
         }
     }
 }
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/context_check",
+    request_body = ChatCompletionRequest,
+    responses((status = 200, description = "Tokens of the prompt of a chat request and the context left for the response, without running it"))
+)]
+pub async fn context_check(
+    State(state): State<Arc<MistralRs>>,
+    Json(oairequest): Json<ChatCompletionRequest>,
+) -> Result<Json<ContextCheck>, (StatusCode, String)> {
+    let bad_request = |e: anyhow::Error| (StatusCode::BAD_REQUEST, e.to_string());
+    // The request is parsed as for a chat completion, so its prompt is rendered the same way.
+    let (request_tx, _request_rx) = channel(1);
+    let (request, _) = parse_request(oairequest, state.clone(), request_tx)
+        .await
+        .map_err(bad_request)?;
+    let Request::Normal(request) = request else {
+        unreachable!("Chat completion requests are normal requests.")
+    };
+
+    let (tx, mut rx) = channel(1);
+    let internal_error = |e: anyhow::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    state
+        .get_sender()
+        .map_err(|e| internal_error(e.into()))?
+        .send(Request::ContextCheck(ContextCheckRequest {
+            request,
+            response: tx,
+        }))
+        .await
+        .map_err(|e| internal_error(e.into()))?;
+    rx.recv()
+        .await
+        .ok_or_else(|| internal_error(anyhow::anyhow!("Channel was erroneously closed!")))?
+        .map(Json)
+        .map_err(bad_request)
+}
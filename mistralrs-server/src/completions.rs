@@ -9,7 +9,10 @@ use std::{
 };
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
-use crate::openai::{CompletionRequest, Grammar, StopTokens};
+use crate::{
+    api_keys::{ApiKeyUsage, StreamedUsage},
+    openai::{CompletionRequest, Grammar, StopTokens},
+};
 use axum::{
     extract::{Json, State},
     http::{self, StatusCode},
@@ -17,10 +20,11 @@ use axum::{
         sse::{Event, KeepAlive},
         IntoResponse, Sse,
     },
+    Extension,
 };
 use mistralrs_core::{
-    CompletionResponse, Constraint, DrySamplingParams, MistralRs, NormalRequest, Request,
-    RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
+    CompletionResponse, Constraint, DrySamplingParams, HybridConfig, MistralRs, NormalRequest,
+    Request, RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
 };
 use serde::Serialize;
 use tracing::warn;
@@ -44,6 +48,7 @@ pub struct Streamer {
     rx: Receiver<Response>,
     done_state: DoneState,
     state: Arc<MistralRs>,
+    usage: StreamedUsage,
 }
 
 impl futures::Stream for Streamer {
@@ -65,7 +70,8 @@ impl futures::Stream for Streamer {
 
         match self.rx.poll_recv(cx) {
             Poll::Ready(Some(resp)) => match resp {
-                Response::CompletionModelError(msg, _) => {
+                Response::CompletionModelError(msg, response) => {
+                    self.usage.finish(&response.usage);
                     MistralRs::maybe_log_error(
                         self.state.clone(),
                         &ModelErrorMessage(msg.to_string()),
@@ -86,6 +92,13 @@ impl futures::Stream for Streamer {
                         // Done now, just need to send the [DONE]
                         self.done_state = DoneState::SendingDone;
                     }
+                    self.usage.add_chunk(
+                        response.prompt_tokens,
+                        response.choices.iter().map(|x| x.tokens.len()).sum(),
+                    );
+                    if let Some(usage) = &response.usage {
+                        self.usage.finish(usage);
+                    }
                     MistralRs::maybe_log_response(self.state.clone(), &response);
                     Poll::Ready(Some(Event::default().json_data(response)))
                 }
@@ -163,7 +176,7 @@ impl IntoResponse for CompletionResponder {
     }
 }
 
-fn parse_request(
+pub(crate) fn parse_request(
     oairequest: CompletionRequest,
     state: Arc<MistralRs>,
     tx: Sender<Response>,
@@ -209,11 +222,27 @@ fn parse_request(
                 top_n_logprobs: 1,
                 frequency_penalty: oairequest.frequency_penalty,
                 presence_penalty: oairequest.presence_penalty,
+                repeat_last_n: oairequest.repeat_last_n,
                 max_len: oairequest.max_tokens,
                 stop_toks,
                 logits_bias: oairequest.logit_bias,
                 n_choices: oairequest.n_choices,
                 dry_params,
+                include_stop_str_in_output: oairequest.include_stop_str_in_output,
+                stop_lookback: oairequest.stop_lookback,
+                stop_on_balanced: oairequest.stop_on_balanced,
+                reserved_output_tokens: oairequest.reserved_output_tokens,
+                continue_word: oairequest.continue_word,
+                tfs_z: oairequest.tfs_z,
+                length_penalty: oairequest.length_penalty,
+                sampler: oairequest.sampler,
+                strip_response_prefix: oairequest.strip_response_prefix,
+                hybrid_decode: oairequest.sample_after_tokens.map(|sample_after_tokens| {
+                    HybridConfig {
+                        sample_after_tokens,
+                    }
+                }),
+                adaptive_temperature: oairequest.adaptive_temperature,
             },
             response: tx,
             return_logprobs: false,
@@ -231,6 +260,12 @@ fn parse_request(
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: None,
+            enable_thinking: None,
+            strict_system_role: false,
+            dynamic_grammar: None,
+            return_effective_params: oairequest.return_effective_params,
+            documents: None,
+            adapters: oairequest.adapters,
         }),
         is_streaming,
     ))
@@ -246,8 +281,10 @@ fn parse_request(
 
 pub async fn completions(
     State(state): State<Arc<MistralRs>>,
+    api_key: Option<Extension<ApiKeyUsage>>,
     Json(oairequest): Json<CompletionRequest>,
 ) -> CompletionResponder {
+    let api_key = api_key.map(|Extension(api_key)| api_key);
     let (tx, mut rx) = channel(10_000);
     if oairequest.logprobs.is_some() {
         return CompletionResponder::ValidationError(
@@ -276,6 +313,7 @@ pub async fn completions(
             rx,
             done_state: DoneState::Running,
             state,
+            usage: StreamedUsage::new(api_key),
         };
 
         let keep_alive_interval = env::var("KEEP_ALIVE_INTERVAL")
@@ -301,12 +339,18 @@ pub async fn completions(
                 CompletionResponder::InternalError(e)
             }
             Response::CompletionModelError(msg, response) => {
+                if let Some(api_key) = &api_key {
+                    api_key.record(&response.usage);
+                }
                 MistralRs::maybe_log_error(state.clone(), &ModelErrorMessage(msg.to_string()));
                 MistralRs::maybe_log_response(state, &response);
                 CompletionResponder::ModelError(msg, response)
             }
             Response::ValidationError(e) => CompletionResponder::ValidationError(e),
             Response::CompletionDone(response) => {
+                if let Some(api_key) = &api_key {
+                    api_key.record(&response.usage);
+                }
                 MistralRs::maybe_log_response(state, &response);
                 CompletionResponder::Json(response)
             }
@@ -0,0 +1,36 @@
+use mistralrs_core::EngineEventSubscriber;
+use tracing::warn;
+
+/// Forward engine events to `url` as JSON `POST` requests. Events are delivered in order from a
+/// background task, so a slow webhook only causes this subscriber to drop events.
+pub fn spawn_event_webhook(mut subscriber: EngineEventSubscriber, url: String) {
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        let mut reported_dropped = 0;
+        while let Some(event) = subscriber.recv().await {
+            if subscriber.dropped() > reported_dropped {
+                warn!(
+                    "Event webhook fell behind, dropped {} events.",
+                    subscriber.dropped() - reported_dropped
+                );
+                reported_dropped = subscriber.dropped();
+            }
+            let body = match serde_json::to_string(&event) {
+                Ok(body) => body,
+                Err(e) => {
+                    warn!("Failed to serialize engine event: {e}");
+                    continue;
+                }
+            };
+            if let Err(e) = client
+                .post(&url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body)
+                .send()
+                .await
+            {
+                warn!("Failed to send engine event to webhook `{url}`: {e}");
+            }
+        }
+    });
+}
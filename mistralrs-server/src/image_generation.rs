@@ -83,6 +83,12 @@ fn parse_request(
         logits_processors: None,
         return_raw_logits: false,
         web_search_options: None,
+        enable_thinking: None,
+        strict_system_role: false,
+        dynamic_grammar: None,
+        return_effective_params: false,
+        documents: None,
+        adapters: None,
     }))
 }
 
@@ -3,8 +3,8 @@ use indexmap::IndexMap;
 use mistralrs_core::{
     ChunkChoice, Constraint, Delta, DiffusionGenerationParams, DrySamplingParams,
     ImageGenerationResponseFormat, MessageContent, MistralRs, ModelCategory, NormalRequest,
-    Request, RequestMessage, Response, ResponseOk, SamplingParams, WebSearchOptions,
-    TERMINATE_ALL_NEXT_STEP,
+    Request, RequestMessage, Response, ResponseOk, SamplerBackend, SamplingParams,
+    WebSearchOptions, TERMINATE_ALL_NEXT_STEP,
 };
 use once_cell::sync::Lazy;
 use regex::Regex;
@@ -93,11 +93,23 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool, do_s
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repeat_last_n: None,
         max_len: Some(4096),
         stop_toks: None,
         logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        include_stop_str_in_output: false,
+        stop_on_balanced: None,
+        reserved_output_tokens: 0,
+        continue_word: false,
+        tfs_z: None,
+        length_penalty: None,
+        sampler: SamplerBackend::Native,
+        strip_response_prefix: None,
+        hybrid_decode: None,
+        adaptive_temperature: None,
+        stop_lookback: None,
     };
 
     info!("Starting interactive loop with sampling params: {sampling_params:?}");
@@ -180,6 +192,12 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool, do_s
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: do_search.then(WebSearchOptions::default),
+            enable_thinking: None,
+            strict_system_role: false,
+            dynamic_grammar: None,
+            return_effective_params: false,
+            documents: None,
+            adapters: None,
         });
         sender.send(req).await.unwrap();
 
@@ -296,11 +314,23 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool, do
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repeat_last_n: None,
         max_len: Some(4096),
         stop_toks: None,
         logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        include_stop_str_in_output: false,
+        stop_on_balanced: None,
+        reserved_output_tokens: 0,
+        continue_word: false,
+        tfs_z: None,
+        length_penalty: None,
+        sampler: SamplerBackend::Native,
+        strip_response_prefix: None,
+        hybrid_decode: None,
+        adaptive_temperature: None,
+        stop_lookback: None,
     };
 
     info!("Starting interactive loop with sampling params: {sampling_params:?}");
@@ -412,6 +442,12 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool, do
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: do_search.then(WebSearchOptions::default),
+            enable_thinking: None,
+            strict_system_role: false,
+            dynamic_grammar: None,
+            return_effective_params: false,
+            documents: None,
+            adapters: None,
         });
         sender.send(req).await.unwrap();
 
@@ -546,6 +582,12 @@ async fn diffusion_interactive_mode(mistralrs: Arc<MistralRs>, do_search: bool)
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: do_search.then(WebSearchOptions::default),
+            enable_thinking: None,
+            strict_system_role: false,
+            dynamic_grammar: None,
+            return_effective_params: false,
+            documents: None,
+            adapters: None,
         });
 
         let start = Instant::now();
@@ -1,36 +1,47 @@
 use anyhow::Result;
+use api_keys::{ApiKeys, InMemoryUsageStore, JsonFileUsageStore, KeyUsage, UsageStore};
 use axum::{
     extract::{DefaultBodyLimit, Json, State},
     http::{self, Method},
-    routing::{get, post},
-    Router,
+    middleware,
+    routing::{delete, get, post},
+    Extension, Router,
 };
 use candle_core::Device;
 use clap::Parser;
 use mistralrs_core::{
     get_auto_device_map_params, get_model_dtype, get_tgt_non_granular_index, initialize_logging,
     paged_attn_supported, parse_isq_value, BertEmbeddingModel, DefaultSchedulerMethod,
-    DeviceLayerMapMetadata, DeviceMapMetadata, DeviceMapSetting, IsqType, Loader, LoaderBuilder,
-    MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelSelected, PagedAttentionConfig, Request,
-    SchedulerConfig, TokenSource,
+    DeviceLayerMapMetadata, DeviceMapMetadata, DeviceMapSetting, EarlyExitStats, EngineEventBus,
+    GGUFArchitecture, IsqType, Loader, LoaderBuilder, LoraAdapterCache, LoraAdapterCacheStats,
+    MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelSelected, PagedAttentionConfig,
+    PrefixCacheEvictionConfig, PrefixCacheMetrics, PrefixCacheOp, PrefixCachePersistence,
+    PrefixCacheRequest, Request, SamplingDefaults, SchedulerConfig, SchedulerLimits,
+    SchedulerLimitsRequest, SchedulerMetrics, SchedulerMetricsRequest, SpeculativeStats,
+    ThroughputEstimate, TokenSource,
 };
 use openai::{
     ChatCompletionRequest, CompletionRequest, ImageGenerationRequest, Message, ModelObjects,
     StopTokens,
 };
 use serde::{Deserialize, Serialize};
-use std::{num::NonZeroUsize, sync::Arc};
+use std::{collections::BTreeMap, num::NonZeroUsize, path::PathBuf, sync::Arc};
 
+mod api_keys;
 mod chat_completion;
 mod completions;
+mod event_webhook;
 mod image_generation;
 mod interactive_mode;
 mod openai;
+mod replay;
 mod util;
 
 use crate::openai::ModelObject;
 use crate::{
-    chat_completion::{__path_chatcompletions, chatcompletions},
+    chat_completion::{
+        __path_chatcompletions, __path_context_check, chatcompletions, context_check,
+    },
     completions::completions,
     image_generation::image_generation,
 };
@@ -49,6 +60,10 @@ fn parse_token_source(s: &str) -> Result<TokenSource, String> {
     s.parse()
 }
 
+fn parse_prefix_cache_eviction(s: &str) -> Result<PrefixCacheEvictionConfig, String> {
+    s.parse()
+}
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
@@ -82,6 +97,11 @@ struct Args {
     #[arg(long, default_value_t = 16)]
     max_seqs: usize,
 
+    /// Run the prefill and decode phases as separate batches, giving this many of the `max_seqs` slots to the decode phase and the others to the prefill phase.
+    /// A phase which has nothing to do gives its slots to the other one. Not supported with PagedAttention.
+    #[arg(long)]
+    decode_seqs: Option<usize>,
+
     /// Use no KV cache.
     #[arg(long, default_value_t = false)]
     no_kv_cache: bool,
@@ -105,10 +125,77 @@ struct Args {
     #[clap(long, short, action)]
     interactive_mode: bool,
 
-    /// Number of prefix caches to hold on the device. Other caches are evicted to the CPU based on a LRU strategy.
+    /// Replay the requests in this request log instead of serving, then exit. This accepts the file
+    /// written with `--log` or a JSONL file with one chat completion or completion request per line.
+    #[arg(long)]
+    replay: Option<String>,
+
+    /// Submit replayed requests as fast as possible instead of with their original relative timing.
+    #[arg(long, default_value_t = false)]
+    replay_fast: bool,
+
+    /// Write the outputs and latencies of the replay to this file, to be used as a baseline later.
+    #[arg(long)]
+    replay_output: Option<String>,
+
+    /// Compare the replay to a run saved with `--replay-output` and print the report.
+    /// Use the same `--seed` for both runs to compare outputs.
+    #[arg(long)]
+    replay_baseline: Option<String>,
+
+    /// Number of prefix caches to hold on the device. Other caches are offloaded to the CPU in the order of `--prefix-cache-eviction`.
     #[arg(long, default_value_t = 16)]
     prefix_cache_n: usize,
 
+    /// Persist the most used prefix caches to this directory on shutdown (Ctrl-C or SIGTERM), and load them
+    /// in the background on startup if the model is the same.
+    #[arg(long)]
+    prefix_cache_dir: Option<String>,
+
+    /// Maximum number of prefix caches to persist with `--prefix-cache-dir`.
+    #[arg(long, default_value_t = 16)]
+    prefix_cache_persist_n: usize,
+
+    /// Maximum size in MBs of the persisted prefix caches in `--prefix-cache-dir`. The oldest are removed first.
+    #[arg(long, default_value_t = 8192)]
+    prefix_cache_persist_mb: u64,
+
+    /// Comma separated prefix cache eviction policies, combined: `lru`, `lfu`, `ttl=<seconds>` and `pinned`.
+    /// Pinned prefixes are never dropped, expired prefixes are dropped, and the others are dropped in the
+    /// order of the first of `lru` and `lfu` when the cache is over `--prefix-cache-mb`.
+    #[arg(long, default_value = "lru", value_parser = parse_prefix_cache_eviction)]
+    prefix_cache_eviction: PrefixCacheEvictionConfig,
+
+    /// Maximum size in MBs of the prefix cache, on the device and the CPU. Unlimited by default.
+    #[arg(long)]
+    prefix_cache_mb: Option<usize>,
+
+    /// Number of threads rendering the chat template and tokenizing the chat requests, in batches.
+    /// Defaults to the available parallelism, up to 4.
+    #[arg(long)]
+    preprocessing_threads: Option<usize>,
+
+    /// Temperature for requests which do not set one, instead of the default of the model.
+    #[arg(long)]
+    default_temperature: Option<f64>,
+
+    /// Top-k for requests which do not set one, instead of the default of the model.
+    #[arg(long)]
+    default_top_k: Option<usize>,
+
+    /// Top-p for requests which do not set one, instead of the default of the model.
+    #[arg(long)]
+    default_top_p: Option<f64>,
+
+    /// Min-p for requests which do not set one, instead of the default of the model.
+    #[arg(long)]
+    default_min_p: Option<f64>,
+
+    /// Number of trailing tokens counted by the frequency and presence penalties for requests
+    /// which do not set `repeat_last_n`, instead of the whole context.
+    #[arg(long)]
+    default_repeat_last_n: Option<usize>,
+
     /// NOTE: This can be omitted to use automatic device mapping!
     /// Number of device layers to load and run on GPU(s). All others will be on the CPU.
     /// If one GPU is used, then this value should be an integer. Otherwise, it follows the following pattern:
@@ -157,6 +244,26 @@ struct Args {
     #[arg(long = "throughput", default_value_t = false)]
     throughput_log: bool,
 
+    /// Replace the chat template of a GGUF model when it is missing or does not match the prompt
+    /// format detected from the tokenizer and model name. By default, a mismatch only warns.
+    #[arg(long = "auto-correct-chat-template", default_value_t = false)]
+    auto_correct_chat_template: bool,
+
+    /// Force byte fallback of the tokenizer on (`true`) or off (`false`), overriding the tokenizer's default.
+    /// With byte fallback, unknown characters are encoded as byte tokens instead of the unknown token.
+    #[arg(long = "byte-fallback")]
+    byte_fallback: Option<bool>,
+
+    /// Load GGUF models as this architecture (for example `llama`) instead of the one declared in their metadata.
+    /// The tensor names are checked against the architecture before loading.
+    #[arg(long = "force-arch")]
+    force_arch: Option<String>,
+
+    /// Safetensors file with F16 copies of some tensors of a GGUF model, keyed by their GGUF names.
+    /// These tensors are loaded instead of the quantized ones to recover accuracy.
+    #[arg(long = "accuracy-companion")]
+    accuracy_companion: Option<PathBuf>,
+
     /// Number of tokens to batch the prompt step into. This can help with OOM errors when in the prompt step, but reduces performance.
     #[arg(long = "prompt-batchsize")]
     prompt_chunksize: Option<usize>,
@@ -176,6 +283,24 @@ struct Args {
     /// Specify a Hugging Face model ID for a BERT model to assist web searching. Defaults to Snowflake Arctic Embed L.
     #[arg(long = "search-bert-model")]
     search_bert_model: Option<String>,
+
+    /// Number of LoRA adapters to keep loaded for hot-swapping. Least recently used adapters are evicted.
+    #[arg(long = "lora-adapter-cache")]
+    lora_adapter_cache: Option<usize>,
+
+    /// URL to `POST` engine lifecycle events to, as JSON.
+    #[arg(long = "event-webhook")]
+    event_webhook: Option<String>,
+
+    /// JSON file listing the API keys allowed to use the `/v1` endpoints, with their optional daily and monthly
+    /// token quotas. Requests must then send `Authorization: Bearer <key>`.
+    #[arg(long)]
+    api_keys: Option<String>,
+
+    /// Keep the token usage of the API keys in this JSON file, so that the quotas survive restarts.
+    /// Defaults to keeping it in memory.
+    #[arg(long)]
+    api_key_usage: Option<String>,
 }
 
 #[utoipa::path(
@@ -192,6 +317,9 @@ async fn models(State(state): State<Arc<MistralRs>>) -> Json<ModelObjects> {
             object: "model",
             created: state.get_creation_time(),
             owned_by: "local",
+            prompt_format: state.prompt_format().cloned(),
+            system_fingerprint: state.system_fingerprint().to_string(),
+            commit_hash: state.commit_hash().map(str::to_string),
         }],
     })
 }
@@ -206,6 +334,120 @@ async fn health() -> &'static str {
     "OK"
 }
 
+#[derive(Debug, Clone, Serialize)]
+struct Metrics {
+    lora_adapter_cache: Option<LoraAdapterCacheStats>,
+    engine_events_emitted: u64,
+    throughput: ThroughputEstimate,
+    scheduler_limits: Option<SchedulerLimits>,
+    scheduler: Option<SchedulerMetrics>,
+    /// Acceptance of the draft tokens, with speculative decoding.
+    speculative: Option<SpeculativeStats>,
+    /// Depths at which the decode steps exited, with early exit.
+    early_exit: Option<EarlyExitStats>,
+    prefix_cache: Option<PrefixCacheMetrics>,
+    /// Token usage by API key id.
+    api_keys: Option<BTreeMap<String, KeyUsage>>,
+}
+
+/// Get the scheduler limits from the engine, changing them first if `limits` is given.
+async fn send_scheduler_limits_request(
+    state: &MistralRs,
+    limits: Option<SchedulerLimits>,
+    drain: bool,
+) -> Result<SchedulerLimits> {
+    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+    let request = Request::SchedulerLimits(SchedulerLimitsRequest {
+        limits,
+        drain,
+        response: tx,
+    });
+    state.get_sender()?.send(request).await?;
+    rx.recv()
+        .await
+        .ok_or_else(|| anyhow::anyhow!("Channel was erroneously closed!"))?
+}
+
+async fn send_scheduler_metrics_request(state: &MistralRs) -> Result<SchedulerMetrics> {
+    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+    let request = Request::SchedulerMetrics(SchedulerMetricsRequest { response: tx });
+    state.get_sender()?.send(request).await?;
+    rx.recv()
+        .await
+        .ok_or_else(|| anyhow::anyhow!("Channel was erroneously closed!"))
+}
+
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/metrics",
+    responses((status = 200, description = "Engine metrics"))
+)]
+async fn metrics(
+    State(state): State<Arc<MistralRs>>,
+    api_keys: Option<Extension<Arc<ApiKeys>>>,
+) -> Json<Metrics> {
+    Json(Metrics {
+        lora_adapter_cache: state.lora_adapter_cache_stats(),
+        engine_events_emitted: state.engine_events().emitted(),
+        throughput: state.throughput_estimate(),
+        scheduler_limits: send_scheduler_limits_request(&state, None, false)
+            .await
+            .ok(),
+        scheduler: send_scheduler_metrics_request(&state).await.ok(),
+        speculative: state.speculative_stats(),
+        early_exit: state.early_exit_stats(),
+        prefix_cache: send_prefix_cache_request(&state, PrefixCacheOp::Metrics)
+            .await
+            .ok(),
+        api_keys: api_keys.map(|Extension(api_keys)| api_keys.all_usage(chrono::Utc::now())),
+    })
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SchedulerLimitsUpdate {
+    #[serde(flatten)]
+    limits: SchedulerLimits,
+    /// Allow lowering `max_num_seqs` below the number of running sequences.
+    #[serde(default)]
+    drain: bool,
+}
+
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/scheduler/limits",
+    responses((status = 200, description = "Current scheduler limits"))
+)]
+async fn scheduler_limits(
+    State(state): State<Arc<MistralRs>>,
+) -> Result<Json<SchedulerLimits>, (http::StatusCode, String)> {
+    send_scheduler_limits_request(&state, None, false)
+        .await
+        .map(Json)
+        .map_err(|e| (http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/scheduler/limits",
+    responses(
+        (status = 200, description = "Change the scheduler limits, taking effect at the next scheduling step."),
+        (status = 400, description = "The limits are invalid."),
+    )
+)]
+async fn set_scheduler_limits(
+    State(state): State<Arc<MistralRs>>,
+    Json(update): Json<SchedulerLimitsUpdate>,
+) -> Result<Json<SchedulerLimits>, (http::StatusCode, String)> {
+    MistralRs::maybe_log_request(state.clone(), format!("Scheduler limits: {update:?}"));
+    send_scheduler_limits_request(&state, Some(update.limits), update.drain)
+        .await
+        .map(Json)
+        .map_err(|e| (http::StatusCode::BAD_REQUEST, e.to_string()))
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 struct ReIsqRequest {
     #[schema(example = "Q4K")]
@@ -230,10 +472,164 @@ async fn re_isq(
     Ok(repr)
 }
 
-fn get_router(state: Arc<MistralRs>) -> Router {
+#[utoipa::path(
+    delete,
+    tag = "Mistral.rs",
+    path = "/prefix_cache/persisted",
+    responses(
+        (status = 200, description = "Remove the persisted prefix cache. The prefix cache in memory is persisted again on shutdown."),
+        (status = 404, description = "Prefix cache persistence is not enabled."),
+    )
+)]
+async fn drop_persisted_prefix_cache(
+    State(state): State<Arc<MistralRs>>,
+) -> Result<String, (http::StatusCode, String)> {
+    MistralRs::maybe_log_request(state.clone(), "Drop persisted prefix cache".to_string());
+    let persistence = state.prefix_cache_persistence().ok_or((
+        http::StatusCode::NOT_FOUND,
+        "Prefix cache persistence is not enabled.".to_string(),
+    ))?;
+    let n_removed = persistence
+        .clear()
+        .map_err(|e| (http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(format!(
+        "Removed {n_removed} persisted prefix cache segments."
+    ))
+}
+
+async fn send_prefix_cache_request(
+    state: &MistralRs,
+    op: PrefixCacheOp,
+) -> Result<PrefixCacheMetrics> {
+    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+    let request = Request::PrefixCache(PrefixCacheRequest { op, response: tx });
+    state.get_sender()?.send(request).await?;
+    rx.recv()
+        .await
+        .ok_or_else(|| anyhow::anyhow!("Channel was erroneously closed!"))?
+}
+
+async fn prefix_cache_response(
+    state: &MistralRs,
+    op: PrefixCacheOp,
+) -> Result<Json<PrefixCacheMetrics>, (http::StatusCode, String)> {
+    send_prefix_cache_request(state, op)
+        .await
+        .map(Json)
+        .map_err(|e| (http::StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/prefix_cache",
+    responses(
+        (status = 200, description = "Prefix cache metrics, overall and per eviction policy."),
+        (status = 400, description = "Prefix caching is disabled."),
+    )
+)]
+async fn prefix_cache_metrics(
+    State(state): State<Arc<MistralRs>>,
+) -> Result<Json<PrefixCacheMetrics>, (http::StatusCode, String)> {
+    prefix_cache_response(&state, PrefixCacheOp::Metrics).await
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct PrefixCachePin {
+    tokens: Vec<u32>,
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/prefix_cache/pin",
+    responses(
+        (status = 200, description = "Never evict the cache of these prompt tokens, for example a system prompt."),
+        (status = 400, description = "Prefix caching is disabled."),
+    )
+)]
+async fn pin_prefix(
+    State(state): State<Arc<MistralRs>>,
+    Json(pin): Json<PrefixCachePin>,
+) -> Result<Json<PrefixCacheMetrics>, (http::StatusCode, String)> {
+    MistralRs::maybe_log_request(state.clone(), format!("Pin prefix: {:?}", pin.tokens));
+    prefix_cache_response(&state, PrefixCacheOp::Pin(pin.tokens)).await
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/prefix_cache/unpin",
+    responses(
+        (status = 200, description = "Allow evicting the cache of these prompt tokens again."),
+        (status = 400, description = "Prefix caching is disabled."),
+    )
+)]
+async fn unpin_prefix(
+    State(state): State<Arc<MistralRs>>,
+    Json(pin): Json<PrefixCachePin>,
+) -> Result<Json<PrefixCacheMetrics>, (http::StatusCode, String)> {
+    MistralRs::maybe_log_request(state.clone(), format!("Unpin prefix: {:?}", pin.tokens));
+    prefix_cache_response(&state, PrefixCacheOp::Unpin(pin.tokens)).await
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct PrefixCacheFlush {
+    #[serde(default)]
+    include_pinned: bool,
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/prefix_cache/flush",
+    responses(
+        (status = 200, description = "Drop the prefix cache in memory, except the pinned prefixes unless `include_pinned`."),
+        (status = 400, description = "Prefix caching is disabled."),
+    )
+)]
+async fn flush_prefix_cache(
+    State(state): State<Arc<MistralRs>>,
+    flush: Option<Json<PrefixCacheFlush>>,
+) -> Result<Json<PrefixCacheMetrics>, (http::StatusCode, String)> {
+    let Json(flush) = flush.unwrap_or_default();
+    MistralRs::maybe_log_request(state.clone(), format!("Flush prefix cache: {flush:?}"));
+    prefix_cache_response(
+        &state,
+        PrefixCacheOp::Flush {
+            include_pinned: flush.include_pinned,
+        },
+    )
+    .await
+}
+
+/// Resolves on Ctrl-C or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl-C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+fn get_router(state: Arc<MistralRs>, keys: Option<Arc<ApiKeys>>) -> Router {
     #[derive(OpenApi)]
     #[openapi(
-        paths(models, health, chatcompletions),
+        paths(models, health, metrics, chatcompletions, context_check),
         components(
             schemas(ModelObjects, ModelObject, ChatCompletionRequest, CompletionRequest, ImageGenerationRequest, StopTokens, Message)),
         tags(
@@ -252,19 +648,53 @@ fn get_router(state: Arc<MistralRs>) -> Router {
 
     let allow_origin = AllowOrigin::any();
     let cors_layer = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST])
+        .allow_methods([Method::GET, Method::POST, Method::DELETE])
         .allow_headers([http::header::CONTENT_TYPE, http::header::AUTHORIZATION])
         .allow_origin(allow_origin);
 
-    Router::new()
-        .merge(SwaggerUi::new("/docs").url("/api-doc/openapi.json", doc))
+    // The generation routes use the quotas of the API keys, the other `/v1` routes only need a key.
+    let mut generation = Router::new()
         .route("/v1/chat/completions", post(chatcompletions))
         .route("/v1/completions", post(completions))
+        .route("/v1/images/generations", post(image_generation));
+    let mut keyed = Router::new()
         .route("/v1/models", get(models))
+        .route("/v1/context_check", post(context_check));
+    if let Some(keys) = &keys {
+        generation = generation.route_layer(middleware::from_fn_with_state(
+            keys.clone(),
+            api_keys::admit_api_key,
+        ));
+        keyed = keyed.route("/v1/usage", get(api_keys::usage)).route_layer(
+            middleware::from_fn_with_state(keys.clone(), api_keys::require_api_key),
+        );
+    }
+
+    let router = Router::new()
+        .merge(SwaggerUi::new("/docs").url("/api-doc/openapi.json", doc))
+        .merge(generation)
+        .merge(keyed)
         .route("/health", get(health))
+        .route("/metrics", get(metrics))
+        .route(
+            "/scheduler/limits",
+            get(scheduler_limits).post(set_scheduler_limits),
+        )
         .route("/", get(health))
         .route("/re_isq", post(re_isq))
-        .route("/v1/images/generations", post(image_generation))
+        .route("/prefix_cache", get(prefix_cache_metrics))
+        .route("/prefix_cache/pin", post(pin_prefix))
+        .route("/prefix_cache/unpin", post(unpin_prefix))
+        .route("/prefix_cache/flush", post(flush_prefix_cache))
+        .route(
+            "/prefix_cache/persisted",
+            delete(drop_persisted_prefix_cache),
+        );
+    let router = match keys {
+        Some(keys) => router.layer(Extension(keys)),
+        None => router,
+    };
+    router
         .layer(cors_layer)
         .layer(DefaultBodyLimit::max(N_INPUT_SIZE * MB_TO_B))
         .with_state(state)
@@ -301,6 +731,14 @@ async fn main() -> Result<()> {
         .with_use_flash_attn(use_flash_attn)
         .with_prompt_chunksize(prompt_chunksize)
         .with_jinja_explicit(args.jinja_explicit)
+        .with_auto_correct_chat_template(args.auto_correct_chat_template)
+        .with_byte_fallback(args.byte_fallback)
+        .with_force_arch(
+            args.force_arch
+                .map(GGUFArchitecture::from_value)
+                .transpose()?,
+        )
+        .with_accuracy_companion(args.accuracy_companion)
         .build()?;
 
     #[cfg(feature = "metal")]
@@ -436,6 +874,14 @@ async fn main() -> Result<()> {
         (_, _, _, _, _, _) => None,
     };
 
+    if let Some(n) = args.lora_adapter_cache {
+        // Resize before loading, so that the adapters of this model are cached as well.
+        LoraAdapterCache::global()
+            .lock()
+            .expect("Adapter cache was poisoned")
+            .set_max_adapters(n);
+    }
+
     let pipeline = loader.load_model_from_hf(
         None,
         args.token_source,
@@ -460,6 +906,18 @@ async fn main() -> Result<()> {
                 method: DefaultSchedulerMethod::Fixed(args.max_seqs.try_into().unwrap()),
             }
         }
+    } else if let Some(decode_seqs) = args.decode_seqs {
+        let prefill_seqs = args.max_seqs.saturating_sub(decode_seqs);
+        if decode_seqs == 0 || prefill_seqs == 0 {
+            anyhow::bail!(
+                "`decode_seqs` must leave at least one of the {} `max_seqs` slots to each phase, got {decode_seqs}.",
+                args.max_seqs
+            );
+        }
+        SchedulerConfig::Disaggregated {
+            prefill_seqs: prefill_seqs.try_into().unwrap(),
+            decode_seqs: decode_seqs.try_into().unwrap(),
+        }
     } else {
         SchedulerConfig::DefaultScheduler {
             method: DefaultSchedulerMethod::Fixed(args.max_seqs.try_into().unwrap()),
@@ -485,6 +943,42 @@ async fn main() -> Result<()> {
     .with_truncate_sequence(args.truncate_sequence)
     .with_no_kv_cache(args.no_kv_cache)
     .with_prefix_cache_n(args.prefix_cache_n)
+    .with_prefix_cache_eviction(match args.prefix_cache_mb {
+        Some(mb) => args.prefix_cache_eviction.with_max_bytes(mb * MB_TO_B),
+        None => args.prefix_cache_eviction,
+    });
+    let mistralrs = match args.prefix_cache_dir {
+        Some(dir) => mistralrs.with_prefix_cache_persistence(
+            PrefixCachePersistence::new(dir)
+                .with_max_entries(args.prefix_cache_persist_n)
+                .with_max_bytes(args.prefix_cache_persist_mb * MB_TO_B as u64),
+        ),
+        None => mistralrs,
+    };
+    let mistralrs = match args.preprocessing_threads {
+        Some(threads) => mistralrs.with_preprocessing_threads(threads),
+        None => mistralrs,
+    };
+    let mistralrs = mistralrs.with_sampling_defaults(SamplingDefaults {
+        temperature: args.default_temperature,
+        top_k: args.default_top_k,
+        top_p: args.default_top_p,
+        min_p: args.default_min_p,
+        repeat_last_n: args.default_repeat_last_n,
+        ..Default::default()
+    });
+    let mistralrs = match args.lora_adapter_cache {
+        Some(n) => mistralrs.with_lora_adapter_cache(n),
+        None => mistralrs,
+    };
+    let mistralrs = match args.event_webhook {
+        Some(url) => {
+            let events = EngineEventBus::default();
+            event_webhook::spawn_event_webhook(events.subscribe(), url);
+            mistralrs.with_engine_events(events)
+        }
+        None => mistralrs,
+    }
     .build();
 
     if args.interactive_mode {
@@ -492,6 +986,29 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
+    if let Some(log) = args.replay {
+        let requests = replay::parse_request_log(&log)?;
+        info!("Replaying {} requests from `{log}`.", requests.len());
+        let timing = if args.replay_fast {
+            replay::ReplayTiming::AsFastAsPossible
+        } else {
+            replay::ReplayTiming::Original
+        };
+        let run = replay::replay_requests(mistralrs, requests, timing).await?;
+        if let Some(output) = args.replay_output {
+            run.save(output)?;
+        }
+        match args.replay_baseline {
+            Some(baseline) => {
+                let baseline = replay::ReplayRun::load(baseline)?;
+                let report = replay::compare_runs(&baseline, &run);
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            }
+            None => println!("{}", serde_json::to_string_pretty(&run.latency)?),
+        }
+        return Ok(());
+    }
+
     // Needs to be after the .build call as that is where the daemon waits.
     let setting_server = if !args.interactive_mode {
         let port = args.port.expect("Interactive mode was not specified, so expected port to be specified. Perhaps you forgot `-i` or `--port`?");
@@ -504,10 +1021,26 @@ async fn main() -> Result<()> {
         None
     };
 
-    let app = get_router(mistralrs);
+    let api_keys = match args.api_keys {
+        Some(path) => {
+            let store: Box<dyn UsageStore> = match args.api_key_usage {
+                Some(usage_path) => Box::new(JsonFileUsageStore::new(usage_path)),
+                None => Box::new(InMemoryUsageStore),
+            };
+            let api_keys = ApiKeys::from_file(&path, store)?;
+            info!("Requiring one of the API keys of `{path}`.");
+            Some(Arc::new(api_keys))
+        }
+        None => None,
+    };
+    let app = get_router(mistralrs.clone(), api_keys);
     if let Some((listener, ip, port)) = setting_server {
         info!("Serving on http://{ip}:{}.", port);
-        axum::serve(listener, app).await?;
+        axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown_signal())
+            .await?;
+        info!("Shutting down.");
+        mistralrs.shutdown().await?;
     };
 
     Ok(())
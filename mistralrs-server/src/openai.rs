@@ -1,6 +1,7 @@
 use either::Either;
 use mistralrs_core::{
-    ImageGenerationResponseFormat, LlguidanceGrammar, Tool, ToolChoice, ToolType, WebSearchOptions,
+    AdaptiveTemperature, ImageGenerationResponseFormat, LlguidanceGrammar, PromptFormatDetection,
+    RetrievedDocument, SamplerBackend, Tool, ToolChoice, ToolType, WebSearchOptions,
 };
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, ops::Deref};
@@ -138,6 +139,8 @@ pub struct ChatCompletionRequest {
     pub n_choices: usize,
     #[schema(example = json!(Option::None::<f32>))]
     pub presence_penalty: Option<f32>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub repeat_last_n: Option<usize>,
     #[schema(example = json!(Option::None::<f32>))]
     pub frequency_penalty: Option<f32>,
     #[serde(rename = "stop")]
@@ -166,6 +169,15 @@ pub struct ChatCompletionRequest {
     #[schema(example = json!(Option::None::<f64>))]
     pub min_p: Option<f64>,
     #[schema(example = json!(Option::None::<f32>))]
+    pub tfs_z: Option<f32>,
+    #[serde(default)]
+    #[schema(value_type = String, example = "native")]
+    pub sampler: SamplerBackend,
+    #[schema(example = json!(Option::None::<Vec<u32>>))]
+    pub sample_after_tokens: Option<Vec<u32>>,
+    #[schema(value_type = Option<Object>, example = json!(Option::None::<AdaptiveTemperature>))]
+    pub adaptive_temperature: Option<AdaptiveTemperature>,
+    #[schema(example = json!(Option::None::<f32>))]
     pub dry_multiplier: Option<f32>,
     #[schema(example = json!(Option::None::<f32>))]
     pub dry_base: Option<f32>,
@@ -173,6 +185,37 @@ pub struct ChatCompletionRequest {
     pub dry_allowed_length: Option<usize>,
     #[schema(example = json!(Option::None::<String>))]
     pub dry_sequence_breakers: Option<Vec<String>>,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub include_stop_str_in_output: bool,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub stop_lookback: Option<usize>,
+    #[schema(value_type = Option<Vec<String>>, example = json!(Option::None::<Vec<String>>))]
+    pub stop_on_balanced: Option<(char, char)>,
+    #[schema(example = json!(Option::None::<String>))]
+    pub strip_response_prefix: Option<String>,
+    #[serde(default)]
+    #[schema(example = 0)]
+    pub reserved_output_tokens: usize,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub continue_word: bool,
+    #[schema(example = json!(Option::None::<bool>))]
+    pub enable_thinking: Option<bool>,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub strict_system_role: bool,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub return_effective_params: bool,
+    #[schema(value_type = Option<Vec<Object>>, example = json!(Option::None::<Vec<RetrievedDocument>>))]
+    pub documents: Option<Vec<RetrievedDocument>>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub max_tokens_per_document: Option<usize>,
+    #[schema(example = json!(Option::None::<String>))]
+    pub documents_section_format: Option<String>,
+    #[schema(value_type = Option<Vec<Vec<Object>>>, example = json!(Option::None::<Vec<(String, f32)>>))]
+    pub adapters: Option<Vec<(String, f32)>>,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
@@ -181,6 +224,16 @@ pub struct ModelObject {
     pub object: &'static str,
     pub created: u64,
     pub owned_by: &'static str,
+    /// Prompt format detected for the model, and whether the chat template in use matches it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub prompt_format: Option<PromptFormatDetection>,
+    /// Fingerprint of the loaded weights, quantization and adapters, also sent as the
+    /// `system_fingerprint` of completions.
+    pub system_fingerprint: String,
+    /// Commit of the Hugging Face hub snapshot serving the model.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit_hash: Option<String>,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
@@ -198,12 +251,16 @@ pub struct CompletionRequest {
     pub prompt: String,
     #[schema(example = 1)]
     pub best_of: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub length_penalty: Option<f32>,
     #[serde(rename = "echo")]
     #[serde(default = "default_false")]
     #[schema(example = false)]
     pub echo_prompt: bool,
     #[schema(example = json!(Option::None::<f32>))]
     pub presence_penalty: Option<f32>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub repeat_last_n: Option<usize>,
     #[schema(example = json!(Option::None::<f32>))]
     pub frequency_penalty: Option<f32>,
     #[schema(example = json!(Option::None::<HashMap<u32, f32>>))]
@@ -241,6 +298,15 @@ pub struct CompletionRequest {
     #[schema(example = json!(Option::None::<f64>))]
     pub min_p: Option<f64>,
     #[schema(example = json!(Option::None::<f32>))]
+    pub tfs_z: Option<f32>,
+    #[serde(default)]
+    #[schema(value_type = String, example = "native")]
+    pub sampler: SamplerBackend,
+    #[schema(example = json!(Option::None::<Vec<u32>>))]
+    pub sample_after_tokens: Option<Vec<u32>>,
+    #[schema(value_type = Option<Object>, example = json!(Option::None::<AdaptiveTemperature>))]
+    pub adaptive_temperature: Option<AdaptiveTemperature>,
+    #[schema(example = json!(Option::None::<f32>))]
     pub dry_multiplier: Option<f32>,
     #[schema(example = json!(Option::None::<f32>))]
     pub dry_base: Option<f32>,
@@ -248,6 +314,26 @@ pub struct CompletionRequest {
     pub dry_allowed_length: Option<usize>,
     #[schema(example = json!(Option::None::<String>))]
     pub dry_sequence_breakers: Option<Vec<String>>,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub include_stop_str_in_output: bool,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub stop_lookback: Option<usize>,
+    #[schema(value_type = Option<Vec<String>>, example = json!(Option::None::<Vec<String>>))]
+    pub stop_on_balanced: Option<(char, char)>,
+    #[schema(example = json!(Option::None::<String>))]
+    pub strip_response_prefix: Option<String>,
+    #[serde(default)]
+    #[schema(example = 0)]
+    pub reserved_output_tokens: usize,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub continue_word: bool,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub return_effective_params: bool,
+    #[schema(value_type = Option<Vec<Vec<Object>>>, example = json!(Option::None::<Vec<(String, f32)>>))]
+    pub adapters: Option<Vec<(String, f32)>>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
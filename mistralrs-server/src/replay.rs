@@ -0,0 +1,377 @@
+//! Replay a request log against a model and compare the outputs and latencies to a baseline run.
+
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, FixedOffset};
+use mistralrs_core::{MistralRs, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc::channel;
+use tracing::debug;
+
+use crate::{
+    chat_completion, completions,
+    openai::{ChatCompletionRequest, CompletionRequest},
+};
+
+/// Format of the request timestamps written by [`MistralRs::maybe_log_request`].
+const LOG_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f %:z";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplayTiming {
+    /// Submit the requests with the same relative timing as in the log.
+    Original,
+    /// Submit all requests at once.
+    AsFastAsPossible,
+}
+
+#[derive(Clone, Debug)]
+pub enum LoggedRequestKind {
+    Chat(Box<ChatCompletionRequest>),
+    Completion(Box<CompletionRequest>),
+}
+
+#[derive(Clone, Debug)]
+pub struct LoggedRequest {
+    /// Time since the first request in the log.
+    pub offset: Duration,
+    pub kind: LoggedRequestKind,
+}
+
+/// Parse a request log. This accepts the log written with `--log`, where each request is on a
+/// `Request at <time>: <json>` line, as well as JSONL files with one request per line. Requests
+/// without a timestamp are given the offset of the previous request. Logged admin actions, which
+/// are not JSON, are skipped.
+pub fn parse_request_log(path: impl AsRef<Path>) -> Result<Vec<LoggedRequest>> {
+    let path = path.as_ref();
+    let log = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read request log `{}`", path.display()))?;
+
+    let mut first_time: Option<DateTime<FixedOffset>> = None;
+    let mut offset = Duration::ZERO;
+    let mut requests = Vec::new();
+    for (i, line) in log.lines().enumerate() {
+        let line = line.trim();
+        let (time, json) = if let Some(rest) = line.strip_prefix("Request at ") {
+            let (time, json) = rest
+                .split_once(": ")
+                .with_context(|| format!("Malformed request on line {}", i + 1))?;
+            if !json.starts_with('{') {
+                // Admin actions such as re-ISQ, scheduler limit changes and prefix cache pins are
+                // logged as requests too.
+                debug!("Skipping line {} of the request log: {json}", i + 1);
+                continue;
+            }
+            let time = DateTime::parse_from_str(time, LOG_TIME_FORMAT)
+                .with_context(|| format!("Malformed timestamp on line {}", i + 1))?;
+            (Some(time), json)
+        } else if line.starts_with('{') {
+            (None, line)
+        } else {
+            // Responses, errors and blank lines.
+            continue;
+        };
+
+        if let Some(time) = time {
+            let first = *first_time.get_or_insert(time);
+            offset = (time - first).to_std().unwrap_or(Duration::ZERO);
+        }
+        let value: Value = serde_json::from_str(json)
+            .with_context(|| format!("Malformed request JSON on line {}", i + 1))?;
+        let kind = if value.get("messages").is_some() {
+            LoggedRequestKind::Chat(Box::new(serde_json::from_value(value)?))
+        } else if value.get("prompt").is_some() {
+            LoggedRequestKind::Completion(Box::new(serde_json::from_value(value)?))
+        } else {
+            // For example, image generation requests.
+            continue;
+        };
+        requests.push(LoggedRequest { offset, kind });
+    }
+    Ok(requests)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReplayResult {
+    pub index: usize,
+    pub output: Option<String>,
+    pub error: Option<String>,
+    pub latency_ms: f64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct LatencyPercentiles {
+    pub mean_ms: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+impl LatencyPercentiles {
+    pub fn from_latencies(latencies: &[f64]) -> Self {
+        if latencies.is_empty() {
+            return Self::default();
+        }
+        let mut sorted = latencies.to_vec();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        // Nearest-rank percentile.
+        let percentile = |p: f64| {
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let rank = (p / 100. * sorted.len() as f64).ceil() as usize;
+            sorted[rank.clamp(1, sorted.len()) - 1]
+        };
+        #[allow(clippy::cast_precision_loss)]
+        let mean_ms = sorted.iter().sum::<f64>() / sorted.len() as f64;
+        Self {
+            mean_ms,
+            p50_ms: percentile(50.),
+            p90_ms: percentile(90.),
+            p99_ms: percentile(99.),
+            max_ms: *sorted.last().unwrap(),
+        }
+    }
+}
+
+/// Outputs and latencies of a replay, which can be saved and used as the baseline of a later run.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReplayRun {
+    pub results: Vec<ReplayResult>,
+    pub latency: LatencyPercentiles,
+}
+
+impl ReplayRun {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let run = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read replay run `{}`", path.display()))?;
+        Ok(serde_json::from_str(&run)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ReplayReport {
+    /// Number of requests present in both runs.
+    pub compared: usize,
+    pub exact_matches: usize,
+    pub exact_match_rate: f64,
+    /// Indexes of the requests whose outputs differ.
+    pub mismatches: Vec<usize>,
+    pub baseline_latency: LatencyPercentiles,
+    pub candidate_latency: LatencyPercentiles,
+}
+
+/// Compare a run to a baseline run of the same log. Outputs can only be expected to match when
+/// both runs use the same seed and deterministic sampling.
+pub fn compare_runs(baseline: &ReplayRun, candidate: &ReplayRun) -> ReplayReport {
+    let mut compared = 0;
+    let mut mismatches = Vec::new();
+    for result in &candidate.results {
+        let Some(base) = baseline.results.iter().find(|b| b.index == result.index) else {
+            continue;
+        };
+        compared += 1;
+        if base.output != result.output || base.error.is_some() != result.error.is_some() {
+            mismatches.push(result.index);
+        }
+    }
+    let exact_matches = compared - mismatches.len();
+    #[allow(clippy::cast_precision_loss)]
+    let exact_match_rate = if compared == 0 {
+        0.
+    } else {
+        exact_matches as f64 / compared as f64
+    };
+    ReplayReport {
+        compared,
+        exact_matches,
+        exact_match_rate,
+        mismatches,
+        baseline_latency: baseline.latency.clone(),
+        candidate_latency: candidate.latency.clone(),
+    }
+}
+
+async fn run_one(state: Arc<MistralRs>, kind: LoggedRequestKind) -> Result<String> {
+    let (tx, mut rx) = channel(10_000);
+    let request = match kind {
+        LoggedRequestKind::Chat(mut request) => {
+            request.stream = Some(false);
+            chat_completion::parse_request(*request, state.clone(), tx)
+                .await?
+                .0
+        }
+        LoggedRequestKind::Completion(mut request) => {
+            request.stream = Some(false);
+            completions::parse_request(*request, state.clone(), tx)?.0
+        }
+    };
+    state.get_sender()?.send(request).await?;
+    match rx
+        .recv()
+        .await
+        .context("No response received from the model.")?
+    {
+        Response::Done(response) => Ok(response
+            .choices
+            .into_iter()
+            .filter_map(|choice| choice.message.content)
+            .collect::<Vec<_>>()
+            .join("\n")),
+        Response::CompletionDone(response) => Ok(response
+            .choices
+            .into_iter()
+            .map(|choice| choice.text)
+            .collect::<Vec<_>>()
+            .join("\n")),
+        Response::InternalError(e) | Response::ValidationError(e) => anyhow::bail!("{e}"),
+        Response::ModelError(e, _) | Response::CompletionModelError(e, _) => anyhow::bail!("{e}"),
+        _ => anyhow::bail!("Got unexpected response type."),
+    }
+}
+
+/// Submit the logged requests to the model and collect the outputs and latencies.
+pub async fn replay_requests(
+    state: Arc<MistralRs>,
+    requests: Vec<LoggedRequest>,
+    timing: ReplayTiming,
+) -> Result<ReplayRun> {
+    let start = Instant::now();
+    let mut handles = Vec::new();
+    for (index, request) in requests.into_iter().enumerate() {
+        let state = state.clone();
+        handles.push(tokio::spawn(async move {
+            if timing == ReplayTiming::Original {
+                tokio::time::sleep_until((start + request.offset).into()).await;
+            }
+            let submitted = Instant::now();
+            let res = run_one(state, request.kind).await;
+            let latency_ms = submitted.elapsed().as_secs_f64() * 1000.;
+            let (output, error) = match res {
+                Ok(output) => (Some(output), None),
+                Err(e) => (None, Some(e.to_string())),
+            };
+            ReplayResult {
+                index,
+                output,
+                error,
+                latency_ms,
+            }
+        }));
+    }
+    let mut results = Vec::new();
+    for handle in handles {
+        results.push(handle.await?);
+    }
+    let latencies = results.iter().map(|r| r.latency_ms).collect::<Vec<_>>();
+    Ok(ReplayRun {
+        latency: LatencyPercentiles::from_latencies(&latencies),
+        results,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        compare_runs, parse_request_log, LatencyPercentiles, LoggedRequestKind, ReplayResult,
+        ReplayRun,
+    };
+
+    #[test]
+    fn parse_log() {
+        let path = std::env::temp_dir().join("mistralrs_replay_parse_log.txt");
+        std::fs::write(
+            &path,
+            r#"Request at 2025-01-01 10:00:00.000000000 +00:00: {"model":"m","messages":[{"role":"user","content":"hi"}]}
+
+Response at 2025-01-01 10:00:01.000000000 +00:00: {"id":"1"}
+
+Request at 2025-01-01 10:00:02.500000000 +00:00: {"model":"m","prompt":"once upon"}
+
+"#,
+        )
+        .unwrap();
+        let requests = parse_request_log(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(requests.len(), 2);
+        assert!(matches!(requests[0].kind, LoggedRequestKind::Chat(_)));
+        assert_eq!(requests[0].offset, Duration::ZERO);
+        assert!(matches!(requests[1].kind, LoggedRequestKind::Completion(_)));
+        assert_eq!(requests[1].offset, Duration::from_millis(2500));
+    }
+
+    #[test]
+    fn parse_log_with_admin_actions() {
+        let path = std::env::temp_dir().join(format!(
+            "mistralrs_replay_admin_actions_{}.txt",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            r#"Request at 2025-01-01 10:00:00.000000000 +00:00: {"model":"m","prompt":"a"}
+
+Request at 2025-01-01 10:00:01.000000000 +00:00: Re ISQ: Q4K
+
+Request at 2025-01-01 10:00:02.000000000 +00:00: Scheduler limits: SchedulerLimitsUpdate { max_seqs: Some(4) }
+
+Request at 2025-01-01 10:00:03.000000000 +00:00: Pin prefix: [1, 2, 3]
+
+Request at 2025-01-01 10:00:04.000000000 +00:00: Drop persisted prefix cache
+
+Request at 2025-01-01 10:00:05.000000000 +00:00: {"model":"m","messages":[{"role":"user","content":"hi"}]}
+
+"#,
+        )
+        .unwrap();
+        let requests = parse_request_log(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(requests.len(), 2);
+        assert!(matches!(requests[0].kind, LoggedRequestKind::Completion(_)));
+        assert!(matches!(requests[1].kind, LoggedRequestKind::Chat(_)));
+        assert_eq!(requests[1].offset, Duration::from_secs(5));
+    }
+
+    fn run(outputs: &[&str], latencies: &[f64]) -> ReplayRun {
+        ReplayRun {
+            results: outputs
+                .iter()
+                .zip(latencies)
+                .enumerate()
+                .map(|(index, (output, latency_ms))| ReplayResult {
+                    index,
+                    output: Some(output.to_string()),
+                    error: None,
+                    latency_ms: *latency_ms,
+                })
+                .collect(),
+            latency: LatencyPercentiles::from_latencies(latencies),
+        }
+    }
+
+    #[test]
+    fn compare() {
+        let baseline = run(&["a", "b", "c", "d"], &[10., 20., 30., 40.]);
+        let candidate = run(&["a", "x", "c", "d"], &[12., 18., 33., 100.]);
+        let report = compare_runs(&baseline, &candidate);
+        assert_eq!(report.compared, 4);
+        assert_eq!(report.mismatches, vec![1]);
+        assert_eq!(report.exact_match_rate, 0.75);
+        assert_eq!(report.baseline_latency.p50_ms, 20.);
+        assert_eq!(report.candidate_latency.p99_ms, 100.);
+        assert_eq!(report.candidate_latency.mean_ms, 40.75);
+    }
+}
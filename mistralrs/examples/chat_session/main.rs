@@ -0,0 +1,34 @@
+use anyhow::Result;
+use mistralrs::{ChatSession, GgufModelBuilder, SamplingParams};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let model = GgufModelBuilder::new(
+        "bartowski/Meta-Llama-3.1-8B-Instruct-GGUF",
+        vec!["Meta-Llama-3.1-8B-Instruct-Q4_K_M.gguf"],
+    )
+    .with_tok_model_id("meta-llama/Meta-Llama-3.1-8B-Instruct")
+    .with_logging()
+    .build()
+    .await?;
+
+    let mut session = ChatSession::new(&model)
+        .with_system_prompt("You are an AI agent with a specialty in programming.")
+        .with_token_budget(4096);
+    let params = SamplingParams {
+        max_len: Some(256),
+        ..SamplingParams::deterministic()
+    };
+
+    // Later turns reuse the KV cache of the earlier ones and only prefill the new message.
+    for message in [
+        "Please write a generic binary search function in Rust.",
+        "Now make it return the insertion point when the item is not found.",
+        "Write a test for it.",
+    ] {
+        println!("> {message}\n{}\n", session.chat(message, &params).await?);
+    }
+    dbg!(session.prompt_tokens(), session.remaining_budget());
+
+    Ok(())
+}
@@ -21,6 +21,11 @@ async fn setup() -> anyhow::Result<Arc<MistralRs>> {
         GGUFSpecificConfig {
             prompt_chunksize: None,
             topology: None,
+            auto_correct_chat_template: false,
+            byte_fallback: None,
+            force_arch: None,
+            accuracy_companion: None,
+            tok_env: None,
         },
     )
     .build();
@@ -84,6 +89,12 @@ async fn bench_mistralrs(n_requests: usize) -> anyhow::Result<()> {
             tools: None,
             tool_choice: None,
             logits_processors: None,
+            enable_thinking: None,
+            strict_system_role: false,
+            dynamic_grammar: None,
+            return_effective_params: false,
+            documents: None,
+            adapters: None,
         });
         mistralrs.get_sender()?.send(request).await?;
         handles.push(rx);
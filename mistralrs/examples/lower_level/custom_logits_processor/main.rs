@@ -45,6 +45,9 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
             organization: Default::default(),
             write_uqff: None,
             from_uqff: None,
+            early_exit: None,
+            byte_fallback: None,
+            tok_env: None,
         },
         None,
         None,
@@ -98,6 +101,12 @@ fn main() -> anyhow::Result<()> {
             Arc::new(move |logits: &Tensor, _context: &[u32]| logits * random_value),
             Arc::new(ThresholdLogitsProcessor { threshold }),
         ]),
+        enable_thinking: None,
+        strict_system_role: false,
+        dynamic_grammar: None,
+        return_effective_params: false,
+        documents: None,
+        adapters: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
@@ -31,6 +31,9 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
             organization: Default::default(),
             write_uqff: None,
             from_uqff: None,
+            early_exit: None,
+            byte_fallback: None,
+            tok_env: None,
         },
         None,
         None,
@@ -77,6 +80,12 @@ fn main() -> anyhow::Result<()> {
         tool_choice: None,
         tools: None,
         logits_processors: None,
+        enable_thinking: None,
+        strict_system_role: false,
+        dynamic_grammar: None,
+        return_effective_params: false,
+        documents: None,
+        adapters: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
@@ -19,6 +19,8 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
             topology: None,
             write_uqff: None,
             from_uqff: None,
+            byte_fallback: None,
+            tok_env: None,
         },
         None,
         None,
@@ -72,6 +74,12 @@ fn main() -> anyhow::Result<()> {
         tools: None,
         tool_choice: None,
         logits_processors: None,
+        enable_thinking: None,
+        strict_system_role: false,
+        dynamic_grammar: None,
+        return_effective_params: false,
+        documents: None,
+        adapters: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
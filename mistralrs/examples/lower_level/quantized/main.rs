@@ -32,6 +32,11 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
         GGUFSpecificConfig {
             prompt_chunksize: None,
             topology: None,
+            auto_correct_chat_template: false,
+            byte_fallback: None,
+            force_arch: None,
+            accuracy_companion: None,
+            tok_env: None,
         },
     )
     .build();
@@ -75,6 +80,12 @@ fn main() -> anyhow::Result<()> {
         tools: None,
         tool_choice: None,
         logits_processors: None,
+        enable_thinking: None,
+        strict_system_role: false,
+        dynamic_grammar: None,
+        return_effective_params: false,
+        documents: None,
+        adapters: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
@@ -54,6 +54,8 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
             max_edge: None,
             imatrix: None,
             calibration_file: None,
+            byte_fallback: None,
+            tok_env: None,
         },
         None,
         None,
@@ -107,6 +109,12 @@ fn gen_request(id: usize, image: DynamicImage, tx: Sender<Response>) -> Request
         tool_choice: None,
         logits_processors: None,
         return_raw_logits: false,
+        enable_thinking: None,
+        strict_system_role: false,
+        dynamic_grammar: None,
+        return_effective_params: false,
+        documents: None,
+        adapters: None,
     })
 }
 
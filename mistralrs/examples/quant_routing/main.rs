@@ -0,0 +1,40 @@
+use anyhow::Result;
+use mistralrs::{
+    GgufModelBuilder, ModelRouter, RouteTags, RoutingPolicy, RoutingRule, TextMessageRole,
+    TextMessages,
+};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let load = |file: &'static str| {
+        GgufModelBuilder::new("bartowski/Meta-Llama-3.1-8B-Instruct-GGUF", vec![file])
+            .with_tok_model_id("meta-llama/Meta-Llama-3.1-8B-Instruct")
+            .with_logging()
+            .build()
+    };
+    let q8 = load("Meta-Llama-3.1-8B-Instruct-Q8_0.gguf").await?;
+    let q3 = load("Meta-Llama-3.1-8B-Instruct-Q3_K_M.gguf").await?;
+
+    // High quality requests go to the Q8 model, unless more than 4 of them are already queued.
+    let policy = RoutingPolicy::new("q3").with_rule(
+        RoutingRule::new("q8")
+            .with_priority("high")
+            .with_overflow(4, "q3"),
+    );
+    let router = ModelRouter::new(vec![("q8".to_string(), q8), ("q3".to_string(), q3)], policy)?;
+
+    let messages = TextMessages::new().add_message(
+        TextMessageRole::User,
+        "Hello! How are you? Please write generic binary search function in Rust.",
+    );
+    let tags = RouteTags {
+        model: None,
+        priority: Some("high".to_string()),
+    };
+    let response = router.send_chat_request(&tags, messages).await?;
+
+    println!("{}", response.choices[0].message.content.as_ref().unwrap());
+    dbg!(response.routing, router.usage());
+
+    Ok(())
+}
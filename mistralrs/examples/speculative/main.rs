@@ -10,7 +10,10 @@ async fn main() -> Result<()> {
     let draft = TextModelBuilder::new("../hf_models/llama3.2_3b")
         .with_logging()
         .with_isq(IsqType::Q8_0);
-    let spec_cfg = SpeculativeConfig { gamma: 16 };
+    let spec_cfg = SpeculativeConfig {
+        gamma: 16,
+        gamma_bounds: None,
+    };
     let model = TextSpeculativeBuilder::new(target, draft, spec_cfg)?
         .build()
         .await?;
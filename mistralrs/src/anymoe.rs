@@ -51,6 +51,9 @@ impl AnyMoeModelBuilder {
             imatrix: None,
             calibration_file: None,
             hf_cache_path: self.base.hf_cache_path,
+            early_exit: None,
+            byte_fallback: self.base.byte_fallback,
+            tok_env: self.base.tok_env.clone(),
         };
 
         if self.base.with_logging {
@@ -1,5 +1,5 @@
 use mistralrs_core::*;
-use std::num::NonZeroUsize;
+use std::{num::NonZeroUsize, path::PathBuf};
 
 use crate::{best_device, Model};
 
@@ -14,8 +14,14 @@ pub struct GgufModelBuilder {
     pub(crate) chat_template: Option<String>,
     pub(crate) jinja_explicit: Option<String>,
     pub(crate) tokenizer_json: Option<String>,
+    pub(crate) byte_fallback: Option<bool>,
+    pub(crate) tok_env: Option<llguidance::toktrie::TokEnv>,
+    pub(crate) force_arch: Option<GGUFArchitecture>,
+    pub(crate) accuracy_companion: Option<PathBuf>,
+    pub(crate) reference_logits: Option<(PathBuf, f32)>,
     pub(crate) device_mapping: Option<DeviceMapSetting>,
     pub(crate) search_bert_model: Option<BertEmbeddingModel>,
+    pub(crate) auto_correct_chat_template: bool,
 
     // Model running
     pub(crate) prompt_chunksize: Option<NonZeroUsize>,
@@ -58,6 +64,12 @@ impl GgufModelBuilder {
             device_mapping: None,
             jinja_explicit: None,
             throughput_logging: false,
+            auto_correct_chat_template: false,
+            byte_fallback: None,
+            tok_env: None,
+            force_arch: None,
+            accuracy_companion: None,
+            reference_logits: None,
             search_bert_model: None,
         }
     }
@@ -92,6 +104,49 @@ impl GgufModelBuilder {
         self
     }
 
+    /// Replace the chat template when it is missing or does not match the prompt format detected
+    /// from the tokenizer and model name. By default, a mismatch only warns.
+    pub fn with_auto_correct_chat_template(mut self) -> Self {
+        self.auto_correct_chat_template = true;
+        self
+    }
+
+    /// Force byte fallback of the tokenizer on or off, overriding the tokenizer's default. With
+    /// byte fallback, unknown characters are encoded as byte tokens instead of the unknown token.
+    pub fn with_byte_fallback(mut self, byte_fallback: bool) -> Self {
+        self.byte_fallback = Some(byte_fallback);
+        self
+    }
+
+    /// Use a token environment built beforehand, for example with [`build_tok_env`], instead of
+    /// building one from the tokenizer. Models of the same vocabulary may share it to save the
+    /// time to build it. Loading fails if its tokens differ from those of the tokenizer.
+    pub fn with_tok_env(mut self, tok_env: llguidance::toktrie::TokEnv) -> Self {
+        self.tok_env = Some(tok_env);
+        self
+    }
+
+    /// Load the model as this architecture instead of the one declared in the GGUF metadata, for
+    /// files with wrong metadata.
+    pub fn with_force_arch(mut self, force_arch: GGUFArchitecture) -> Self {
+        self.force_arch = Some(force_arch);
+        self
+    }
+
+    /// Load the tensors of this safetensors file as F16 instead of the quantized tensors of the
+    /// same name, to recover the accuracy of the layers most sensitive to quantization.
+    pub fn with_accuracy_companion(mut self, path: impl Into<PathBuf>) -> Self {
+        self.accuracy_companion = Some(path.into());
+        self
+    }
+
+    /// Check the model against reference logits once it is loaded, failing the build if any logit
+    /// differs by more than `tolerance`. See [`ReferenceLogits`] for the file format.
+    pub fn with_reference_logits(mut self, path: impl Into<PathBuf>, tolerance: f32) -> Self {
+        self.reference_logits = Some((path.into(), tolerance));
+        self
+    }
+
     /// Set the model topology for use during loading. If there is an overlap, the topology type is used over the ISQ type.
     pub fn with_topology(mut self, topology: Topology) -> Self {
         self.topology = Some(topology);
@@ -180,6 +235,11 @@ impl GgufModelBuilder {
         let config = GGUFSpecificConfig {
             prompt_chunksize: self.prompt_chunksize,
             topology: self.topology,
+            auto_correct_chat_template: self.auto_correct_chat_template,
+            byte_fallback: self.byte_fallback,
+            force_arch: self.force_arch,
+            accuracy_companion: self.accuracy_companion,
+            tok_env: self.tok_env.clone(),
         };
 
         if self.with_logging {
@@ -244,6 +304,10 @@ impl GgufModelBuilder {
             runner = runner.with_prefix_cache_n(n)
         }
 
-        Ok(Model::new(runner.build()))
+        let model = Model::new(runner.build());
+        if let Some((path, tolerance)) = self.reference_logits {
+            model.verify_reference_logits(&path, tolerance).await?;
+        }
+        Ok(model)
     }
 }
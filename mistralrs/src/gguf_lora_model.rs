@@ -26,6 +26,11 @@ impl GgufLoraModelBuilder {
         let config = GGUFSpecificConfig {
             prompt_chunksize: self.gguf_model.prompt_chunksize,
             topology: self.gguf_model.topology,
+            auto_correct_chat_template: false,
+            byte_fallback: self.gguf_model.byte_fallback,
+            force_arch: self.gguf_model.force_arch,
+            accuracy_companion: self.gguf_model.accuracy_companion.clone(),
+            tok_env: self.gguf_model.tok_env.clone(),
         };
 
         if self.gguf_model.with_logging {
@@ -107,6 +107,9 @@ mod gguf_xlora_model;
 mod lora_model;
 mod messages;
 mod model;
+mod rag;
+mod router;
+mod session;
 mod speculative;
 mod text_model;
 mod vision_model;
@@ -123,7 +126,10 @@ pub mod v0_4_api {
     pub use super::messages::{
         RequestBuilder, RequestLike, TextMessageRole, TextMessages, VisionMessages,
     };
-    pub use super::model::{best_device, Model};
+    pub use super::model::{best_device, Model, StreamingChunk};
+    pub use super::rag::RagPipeline;
+    pub use super::router::{ModelRouter, PipelineUsage};
+    pub use super::session::{BudgetExhausted, ChatSession};
     pub use super::speculative::TextSpeculativeBuilder;
     pub use super::text_model::{
         PagedAttentionMetaBuilder, TextModelBuilder, UqffTextModelBuilder,
@@ -33,6 +33,9 @@ impl LoraModelBuilder {
             imatrix: None,
             calibration_file: None,
             hf_cache_path: self.text_model.hf_cache_path,
+            early_exit: None,
+            byte_fallback: self.text_model.byte_fallback,
+            tok_env: self.text_model.tok_env.clone(),
         };
 
         if self.text_model.with_logging {
@@ -11,12 +11,16 @@ pub trait RequestLike {
     fn messages_ref(&self) -> &[IndexMap<String, MessageContent>];
     fn take_messages(&mut self) -> RequestMessage;
     fn take_logits_processors(&mut self) -> Option<Vec<Arc<dyn CustomLogitsProcessor>>>;
-    fn take_adapters(&mut self) -> Option<Vec<String>>;
+    fn take_adapters(&mut self) -> Option<Vec<(String, f32)>>;
     fn return_logprobs(&self) -> bool;
     fn take_constraint(&mut self) -> Constraint;
+    fn take_dynamic_grammar(&mut self) -> Option<DynamicGrammar>;
     fn take_tools(&mut self) -> Option<(Vec<Tool>, ToolChoice)>;
     fn take_sampling_params(&mut self) -> SamplingParams;
     fn take_web_search_options(&mut self) -> Option<WebSearchOptions>;
+    fn enable_thinking(&self) -> Option<bool>;
+    fn strict_system_role(&self) -> bool;
+    fn take_documents(&mut self) -> Option<RequestDocuments>;
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -91,7 +95,7 @@ impl RequestLike for TextMessages {
     fn take_logits_processors(&mut self) -> Option<Vec<Arc<dyn CustomLogitsProcessor>>> {
         None
     }
-    fn take_adapters(&mut self) -> Option<Vec<String>> {
+    fn take_adapters(&mut self) -> Option<Vec<(String, f32)>> {
         None
     }
     fn return_logprobs(&self) -> bool {
@@ -100,6 +104,10 @@ impl RequestLike for TextMessages {
     fn take_constraint(&mut self) -> Constraint {
         Constraint::None
     }
+
+    fn take_dynamic_grammar(&mut self) -> Option<DynamicGrammar> {
+        None
+    }
     fn take_tools(&mut self) -> Option<(Vec<Tool>, ToolChoice)> {
         None
     }
@@ -109,6 +117,15 @@ impl RequestLike for TextMessages {
     fn take_web_search_options(&mut self) -> Option<WebSearchOptions> {
         None
     }
+    fn enable_thinking(&self) -> Option<bool> {
+        None
+    }
+    fn strict_system_role(&self) -> bool {
+        false
+    }
+    fn take_documents(&mut self) -> Option<RequestDocuments> {
+        None
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -207,7 +224,7 @@ impl RequestLike for VisionMessages {
     fn take_logits_processors(&mut self) -> Option<Vec<Arc<dyn CustomLogitsProcessor>>> {
         None
     }
-    fn take_adapters(&mut self) -> Option<Vec<String>> {
+    fn take_adapters(&mut self) -> Option<Vec<(String, f32)>> {
         None
     }
     fn return_logprobs(&self) -> bool {
@@ -216,6 +233,10 @@ impl RequestLike for VisionMessages {
     fn take_constraint(&mut self) -> Constraint {
         Constraint::None
     }
+
+    fn take_dynamic_grammar(&mut self) -> Option<DynamicGrammar> {
+        None
+    }
     fn take_tools(&mut self) -> Option<(Vec<Tool>, ToolChoice)> {
         None
     }
@@ -225,6 +246,15 @@ impl RequestLike for VisionMessages {
     fn take_web_search_options(&mut self) -> Option<WebSearchOptions> {
         None
     }
+    fn enable_thinking(&self) -> Option<bool> {
+        None
+    }
+    fn strict_system_role(&self) -> bool {
+        false
+    }
+    fn take_documents(&mut self) -> Option<RequestDocuments> {
+        None
+    }
 }
 
 #[derive(Clone)]
@@ -240,13 +270,17 @@ pub struct RequestBuilder {
     messages: Vec<IndexMap<String, MessageContent>>,
     images: Vec<DynamicImage>,
     logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
-    adapters: Vec<String>,
+    adapters: Vec<(String, f32)>,
     return_logprobs: bool,
     constraint: Constraint,
+    dynamic_grammar: Option<DynamicGrammar>,
     tools: Vec<Tool>,
     tool_choice: ToolChoice,
     sampling_params: SamplingParams,
     web_search_options: Option<WebSearchOptions>,
+    enable_thinking: Option<bool>,
+    strict_system_role: bool,
+    documents: Option<RequestDocuments>,
 }
 
 impl Default for RequestBuilder {
@@ -264,10 +298,14 @@ impl From<TextMessages> for RequestBuilder {
             adapters: Vec::new(),
             return_logprobs: false,
             constraint: Constraint::None,
+            dynamic_grammar: None,
             tools: Vec::new(),
             tool_choice: ToolChoice::Auto,
             sampling_params: SamplingParams::deterministic(),
             web_search_options: None,
+            enable_thinking: None,
+            strict_system_role: false,
+            documents: None,
         }
     }
 }
@@ -281,10 +319,14 @@ impl From<VisionMessages> for RequestBuilder {
             adapters: Vec::new(),
             return_logprobs: false,
             constraint: Constraint::None,
+            dynamic_grammar: None,
             tools: Vec::new(),
             tool_choice: ToolChoice::Auto,
             sampling_params: SamplingParams::deterministic(),
             web_search_options: None,
+            enable_thinking: None,
+            strict_system_role: false,
+            documents: None,
         }
     }
 }
@@ -298,10 +340,14 @@ impl RequestBuilder {
             adapters: Vec::new(),
             return_logprobs: false,
             constraint: Constraint::None,
+            dynamic_grammar: None,
             tools: Vec::new(),
             tool_choice: ToolChoice::Auto,
             sampling_params: SamplingParams::deterministic(),
             web_search_options: None,
+            enable_thinking: None,
+            strict_system_role: false,
+            documents: None,
         }
     }
 
@@ -310,6 +356,31 @@ impl RequestBuilder {
         self
     }
 
+    /// Toggle the thinking block of reasoning models which support `enable_thinking` in their chat
+    /// template. If unset, the template's own default is used.
+    pub fn enable_thinking(mut self, enable_thinking: bool) -> Self {
+        self.enable_thinking = Some(enable_thinking);
+        self
+    }
+
+    /// Reject system messages for models whose chat template does not support the system role,
+    /// instead of folding them into the next user message.
+    pub fn with_strict_system_role(mut self) -> Self {
+        self.strict_system_role = true;
+        self
+    }
+
+    /// Retrieved documents which the engine packs into the prompt, by decreasing score, as long as
+    /// they fit in the context.
+    pub fn with_documents(
+        mut self,
+        documents: Vec<RetrievedDocument>,
+        packing: DocumentPacking,
+    ) -> Self {
+        self.documents = Some(RequestDocuments { documents, packing });
+        self
+    }
+
     /// Add a message to the request.
     ///
     /// For messages with tool calls, use [`Self::add_message_with_tool_call`].
@@ -390,7 +461,9 @@ impl RequestBuilder {
         self
     }
 
-    pub fn set_adapters(mut self, adapters: Vec<String>) -> Self {
+    /// Apply the LoRA adapters with these weights, as a weighted sum of their deltas. Adapters
+    /// which are not listed are not applied.
+    pub fn set_adapters(mut self, adapters: Vec<(String, f32)>) -> Self {
         self.adapters = adapters;
         self
     }
@@ -416,6 +489,13 @@ impl RequestBuilder {
         self
     }
 
+    /// Constrain the generation with a grammar updated from the generated tokens, for example to
+    /// only allow the arguments of the function chosen earlier. Replaces the constraint.
+    pub fn set_dynamic_grammar(mut self, grammar: DynamicGrammar) -> Self {
+        self.dynamic_grammar = Some(grammar);
+        self
+    }
+
     /// Set the sampling parameters as given.
     pub fn set_sampling(mut self, params: SamplingParams) -> Self {
         self.sampling_params = params;
@@ -452,6 +532,19 @@ impl RequestBuilder {
         self
     }
 
+    /// Enable tail free sampling with the given `z` in `(0, 1)`.
+    pub fn set_sampler_tfs_z(mut self, tfs_z: f32) -> Self {
+        self.sampling_params.tfs_z = Some(tfs_z);
+        self
+    }
+
+    /// Draw tokens with the given backend, for example [`SamplerBackend::GumbelMax`] for results
+    /// which do not depend on the device.
+    pub fn set_sampler_backend(mut self, backend: SamplerBackend) -> Self {
+        self.sampling_params.sampler = backend;
+        self
+    }
+
     pub fn set_sampler_topn_logprobs(mut self, top_n_logprobs: usize) -> Self {
         self.sampling_params.top_n_logprobs = top_n_logprobs;
         self
@@ -467,6 +560,13 @@ impl RequestBuilder {
         self
     }
 
+    /// Count only the last `repeat_last_n` tokens of the context for the frequency and presence
+    /// penalties.
+    pub fn set_sampler_repeat_last_n(mut self, repeat_last_n: usize) -> Self {
+        self.sampling_params.repeat_last_n = Some(repeat_last_n);
+        self
+    }
+
     pub fn set_sampler_stop_toks(mut self, stop_toks: StopTokens) -> Self {
         self.sampling_params.stop_toks = Some(stop_toks);
         self
@@ -491,6 +591,66 @@ impl RequestBuilder {
         self.sampling_params.dry_params = Some(dry_params);
         self
     }
+
+    /// Keep the matched stop string at the end of the output instead of trimming it.
+    pub fn set_sampler_include_stop_str_in_output(mut self, include: bool) -> Self {
+        self.sampling_params.include_stop_str_in_output = include;
+        self
+    }
+
+    /// Search the stop strings in the last `stop_lookback` tokens only. Too small a lookback misses the longer stop strings.
+    pub fn set_sampler_stop_lookback(mut self, stop_lookback: usize) -> Self {
+        self.sampling_params.stop_lookback = Some(stop_lookback);
+        self
+    }
+
+    /// Stop once the `(open, close)` delimiter pair balances, for example `('{', '}')` to stop after one JSON object.
+    pub fn set_sampler_stop_on_balanced(mut self, open: char, close: char) -> Self {
+        self.sampling_params.stop_on_balanced = Some((open, close));
+        self
+    }
+
+    /// Remove this prefix, such as a role marker echoed by the model, from the start of the response.
+    pub fn set_sampler_strip_response_prefix(mut self, prefix: impl ToString) -> Self {
+        self.sampling_params.strip_response_prefix = Some(prefix.to_string());
+        self
+    }
+
+    /// Keep at least this many tokens of context free for the response. A prompt which does not leave this
+    /// room is rejected, or truncated if the model was built with `truncate_sequence`.
+    pub fn set_sampler_reserved_output_tokens(mut self, reserved_output_tokens: usize) -> Self {
+        self.sampling_params.reserved_output_tokens = reserved_output_tokens;
+        self
+    }
+
+    /// Decode greedily, except for the token following one of `sample_after_tokens`, which is sampled.
+    pub fn set_sampler_hybrid_decode(mut self, sample_after_tokens: Vec<u32>) -> Self {
+        self.sampling_params.hybrid_decode = Some(HybridConfig {
+            sample_after_tokens,
+        });
+        self
+    }
+
+    /// Adapt the temperature, starting at `base_temp`, to keep the entropy of each token distribution near `entropy_target`.
+    pub fn set_sampler_adaptive_temperature(
+        mut self,
+        base_temp: f32,
+        entropy_target: f32,
+        learning_rate: f32,
+    ) -> Self {
+        self.sampling_params.adaptive_temperature = Some(AdaptiveTemperature {
+            base_temp,
+            entropy_target,
+            learning_rate,
+        });
+        self
+    }
+
+    /// Restrict the first generated token to those continuing the last word of the prompt.
+    pub fn set_sampler_continue_word(mut self, continue_word: bool) -> Self {
+        self.sampling_params.continue_word = continue_word;
+        self
+    }
 }
 
 impl RequestLike for RequestBuilder {
@@ -525,7 +685,7 @@ impl RequestLike for RequestBuilder {
         }
     }
 
-    fn take_adapters(&mut self) -> Option<Vec<String>> {
+    fn take_adapters(&mut self) -> Option<Vec<(String, f32)>> {
         if self.adapters.is_empty() {
             None
         } else {
@@ -545,6 +705,10 @@ impl RequestLike for RequestBuilder {
         other
     }
 
+    fn take_dynamic_grammar(&mut self) -> Option<DynamicGrammar> {
+        self.dynamic_grammar.take()
+    }
+
     fn take_tools(&mut self) -> Option<(Vec<Tool>, ToolChoice)> {
         if self.tools.is_empty() {
             None
@@ -568,4 +732,16 @@ impl RequestLike for RequestBuilder {
         std::mem::swap(&mut other, &mut self.web_search_options);
         other
     }
+
+    fn enable_thinking(&self) -> Option<bool> {
+        self.enable_thinking
+    }
+
+    fn strict_system_role(&self) -> bool {
+        self.strict_system_role
+    }
+
+    fn take_documents(&mut self) -> Option<RequestDocuments> {
+        self.documents.take()
+    }
 }
@@ -2,8 +2,8 @@ use anyhow::Context;
 use candle_core::{Device, Result, Tensor};
 use either::Either;
 use mistralrs_core::*;
-use std::sync::Arc;
-use tokio::sync::mpsc::{channel, Receiver};
+use std::{path::Path, sync::Arc};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
 
 use crate::{RequestLike, TextMessages};
 
@@ -58,24 +58,59 @@ impl Stream<'_> {
     }
 }
 
+/// The text generated since the previous chunk of a stream from
+/// [`Model::send_chat_request_streaming`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamingChunk {
+    pub text: String,
+    /// Ids of the tokens generated since the previous chunk. The text of a token may be held
+    /// back until it is known not to start a stop string, so it can come with a later token.
+    pub tokens: Vec<u32>,
+    /// Whether this is the last chunk.
+    pub done: bool,
+}
+
+/// Forward the choice of each chunk in `rx` until the last one, or until `tx` is closed. The
+/// request has a single choice, and chunks without one are skipped.
+async fn forward_streaming_chunks(
+    mut rx: Receiver<Response>,
+    tx: Sender<anyhow::Result<StreamingChunk>>,
+) {
+    while let Some(response) = rx.recv().await {
+        let chunk = match response.as_result() {
+            Ok(ResponseOk::Chunk(chunk)) => {
+                let Some(choice) = chunk.choices.into_iter().next() else {
+                    continue;
+                };
+                Ok(StreamingChunk {
+                    text: choice.delta.content.unwrap_or_default(),
+                    tokens: choice.tokens,
+                    done: choice.finish_reason.is_some(),
+                })
+            }
+            Ok(_) => Err(anyhow::anyhow!("Got unexpected response type.")),
+            Err(e) => Err(e.into()),
+        };
+        let stop = chunk.as_ref().map_or(true, |chunk| chunk.done);
+        if tx.send(chunk).await.is_err() || stop {
+            break;
+        }
+    }
+}
+
 impl Model {
     pub fn new(runner: Arc<MistralRs>) -> Self {
         Self { runner }
     }
 
-    /// Generate with the model.
-    pub async fn stream_chat_request<R: RequestLike>(
-        &self,
-        mut request: R,
-    ) -> anyhow::Result<Stream> {
-        let (tx, rx) = channel(1);
-
+    /// A streaming request which sends its chunks to `tx`.
+    fn streaming_request<R: RequestLike>(mut request: R, tx: Sender<Response>) -> NormalRequest {
         let (tools, tool_choice) = if let Some((a, b)) = request.take_tools() {
             (Some(a), Some(b))
         } else {
             (None, None)
         };
-        let request = Request::Normal(NormalRequest {
+        NormalRequest {
             messages: request.take_messages(),
             sampling_params: request.take_sampling_params(),
             response: tx,
@@ -89,7 +124,19 @@ impl Model {
             logits_processors: request.take_logits_processors(),
             return_raw_logits: false,
             web_search_options: request.take_web_search_options(),
-        });
+            enable_thinking: request.enable_thinking(),
+            strict_system_role: request.strict_system_role(),
+            dynamic_grammar: request.take_dynamic_grammar(),
+            return_effective_params: false,
+            documents: request.take_documents(),
+            adapters: request.take_adapters(),
+        }
+    }
+
+    /// Generate with the model.
+    pub async fn stream_chat_request<R: RequestLike>(&self, request: R) -> anyhow::Result<Stream> {
+        let (tx, rx) = channel(1);
+        let request = Request::Normal(Self::streaming_request(request, tx));
 
         self.runner.get_sender()?.send(request).await?;
 
@@ -98,6 +145,32 @@ impl Model {
         Ok(stream)
     }
 
+    /// Generate with the model, receiving the text and the tokens as soon as they are sampled.
+    /// Errors are sent on the channel and end the stream, and dropping the receiver ends the
+    /// request.
+    ///
+    /// The chunks have the text of a single choice, so requests for several choices are rejected.
+    pub async fn send_chat_request_streaming<R: RequestLike>(
+        &self,
+        request: R,
+    ) -> anyhow::Result<Receiver<anyhow::Result<StreamingChunk>>> {
+        let (tx, rx) = channel(1);
+        let request = Self::streaming_request(request, tx);
+        if request.sampling_params.n_choices > 1 {
+            anyhow::bail!(
+                "Streaming chunks have a single choice, but {} choices were requested.",
+                request.sampling_params.n_choices
+            );
+        }
+        self.runner
+            .get_sender()?
+            .send(Request::Normal(request))
+            .await?;
+        let (tx, chunks) = channel(1);
+        tokio::spawn(forward_streaming_chunks(rx, tx));
+        Ok(chunks)
+    }
+
     /// Generate with the model.
     pub async fn send_chat_request<R: RequestLike>(
         &self,
@@ -124,6 +197,12 @@ impl Model {
             logits_processors: request.take_logits_processors(),
             return_raw_logits: false,
             web_search_options: request.take_web_search_options(),
+            enable_thinking: request.enable_thinking(),
+            strict_system_role: request.strict_system_role(),
+            dynamic_grammar: request.take_dynamic_grammar(),
+            return_effective_params: false,
+            documents: request.take_documents(),
+            adapters: request.take_adapters(),
         });
 
         self.runner.get_sender()?.send(request).await?;
@@ -168,6 +247,12 @@ impl Model {
             logits_processors: request.take_logits_processors(),
             return_raw_logits: true,
             web_search_options: request.take_web_search_options(),
+            enable_thinking: request.enable_thinking(),
+            strict_system_role: request.strict_system_role(),
+            dynamic_grammar: request.take_dynamic_grammar(),
+            return_effective_params: false,
+            documents: request.take_documents(),
+            adapters: request.take_adapters(),
         });
 
         self.runner.get_sender()?.send(request).await?;
@@ -187,6 +272,55 @@ impl Model {
         Ok((logits_chunks, tokens))
     }
 
+    /// Run the prompt tokens through the model and return the raw logits predicting the token
+    /// after the prompt, as a `f32` tensor of shape `(vocab_size,)`.
+    pub async fn prompt_logits(&self, prompt: Vec<u32>) -> anyhow::Result<Tensor> {
+        let (tx, mut rx) = channel(1);
+
+        let request = Request::Normal(NormalRequest {
+            messages: RequestMessage::CompletionTokens(prompt),
+            sampling_params: SamplingParams::deterministic(),
+            response: tx,
+            return_logprobs: false,
+            is_streaming: false,
+            id: 0,
+            constraint: Constraint::None,
+            suffix: None,
+            tools: None,
+            tool_choice: None,
+            logits_processors: None,
+            return_raw_logits: true,
+            web_search_options: None,
+            enable_thinking: None,
+            strict_system_role: false,
+            dynamic_grammar: None,
+            return_effective_params: false,
+            documents: None,
+            adapters: None,
+        });
+
+        self.runner.get_sender()?.send(request).await?;
+
+        let ResponseOk::Raw {
+            mut logits_chunks, ..
+        } = rx
+            .recv()
+            .await
+            .context("Channel was erroneously closed!")?
+            .as_result()?
+        else {
+            anyhow::bail!("Got unexpected response type.")
+        };
+
+        // The last prompt chunk holds the logits of the last prompt position.
+        let logits = logits_chunks.pop().context("No logits were returned")?;
+        let vocab_size = logits.dim(candle_core::D::Minus1)?;
+        let logits = logits.reshape(((), vocab_size))?;
+        Ok(logits
+            .get(logits.dim(0)? - 1)?
+            .to_dtype(candle_core::DType::F32)?)
+    }
+
     pub async fn generate_image(
         &self,
         prompt: impl ToString,
@@ -213,6 +347,12 @@ impl Model {
             logits_processors: None,
             return_raw_logits: false,
             web_search_options: None,
+            enable_thinking: None,
+            strict_system_role: false,
+            dynamic_grammar: None,
+            return_effective_params: false,
+            documents: None,
+            adapters: None,
         });
 
         self.runner.get_sender()?.send(request).await?;
@@ -275,12 +415,177 @@ impl Model {
         rx.recv().await.context("Channel was erroneously closed!")?
     }
 
+    /// Run the input through the model and compare the logits predicting the next token against
+    /// reference logits, for example loaded with [`ReferenceLogits::load`]. The report gives the
+    /// max abs diff and the first logit differing by more than `tol`.
+    pub async fn verify_against(
+        &self,
+        input: &[u32],
+        reference: &Tensor,
+        tol: f32,
+    ) -> anyhow::Result<VerifyReport> {
+        let logits = self.prompt_logits(input.to_vec()).await?;
+        let reference = reference.flatten_all()?;
+        Ok(VerifyReport::compare(&logits, &reference, tol)?)
+    }
+
+    /// Verify the model against the reference logits file at `path`, failing with the report if
+    /// the logits diverge.
+    pub(crate) async fn verify_reference_logits(
+        &self,
+        path: &Path,
+        tol: f32,
+    ) -> anyhow::Result<VerifyReport> {
+        let reference = ReferenceLogits::load(path)?;
+        let report = self
+            .verify_against(&reference.input_ids, &reference.logits, tol)
+            .await?;
+        if !report.passed() {
+            anyhow::bail!("Verification against {} failed. {report}", path.display());
+        }
+        Ok(report)
+    }
+
+    /// Get the current scheduler limits.
+    pub async fn scheduler_limits(&self) -> anyhow::Result<SchedulerLimits> {
+        self.send_scheduler_limits_request(None, false).await
+    }
+
+    /// Change the scheduler limits, which take effect at the next scheduling step. Lowering
+    /// `max_num_seqs` below the number of running sequences fails unless `drain` is set, in which
+    /// case no sequences are admitted until enough of the running ones finish.
+    pub async fn set_scheduler_limits(
+        &self,
+        limits: SchedulerLimits,
+        drain: bool,
+    ) -> anyhow::Result<SchedulerLimits> {
+        self.send_scheduler_limits_request(Some(limits), drain)
+            .await
+    }
+
+    async fn send_scheduler_limits_request(
+        &self,
+        limits: Option<SchedulerLimits>,
+        drain: bool,
+    ) -> anyhow::Result<SchedulerLimits> {
+        let (tx, mut rx) = channel(1);
+        let request = Request::SchedulerLimits(SchedulerLimitsRequest {
+            limits,
+            drain,
+            response: tx,
+        });
+        self.runner.get_sender()?.send(request).await?;
+
+        rx.recv().await.context("Channel was erroneously closed!")?
+    }
+
+    /// Get the queue depths and throughput of the prefill and decode phases.
+    pub async fn scheduler_metrics(&self) -> anyhow::Result<SchedulerMetrics> {
+        let (tx, mut rx) = channel(1);
+        let request = Request::SchedulerMetrics(SchedulerMetricsRequest { response: tx });
+        self.runner.get_sender()?.send(request).await?;
+
+        rx.recv().await.context("Channel was erroneously closed!")
+    }
+
+    /// Get the prefix cache metrics: the cached prefixes, their size and the lookups and hits.
+    /// Fails if prefix caching is disabled.
+    pub async fn prefix_cache_metrics(&self) -> anyhow::Result<PrefixCacheMetrics> {
+        let (tx, mut rx) = channel(1);
+        let request = Request::PrefixCache(PrefixCacheRequest {
+            op: PrefixCacheOp::Metrics,
+            response: tx,
+        });
+        self.runner.get_sender()?.send(request).await?;
+
+        rx.recv().await.context("Channel was erroneously closed!")?
+    }
+
     /// Retrieve some information about this model.
     pub fn config(&self) -> &MistralRsConfig {
         self.runner.config()
     }
 
+    /// Fingerprint of the loaded weights, quantization and adapters, sent as the
+    /// `system_fingerprint` of responses.
+    pub fn system_fingerprint(&self) -> &str {
+        self.runner.system_fingerprint()
+    }
+
     pub fn inner(&self) -> &MistralRs {
         &self.runner
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use mistralrs_core::{ChatCompletionChunkResponse, ChunkChoice, Delta, Response};
+    use tokio::sync::mpsc::channel;
+
+    use super::{forward_streaming_chunks, StreamingChunk};
+
+    fn chunk(choices: Vec<ChunkChoice>) -> Response {
+        Response::Chunk(ChatCompletionChunkResponse {
+            id: "0".to_string(),
+            choices,
+            created: 0,
+            model: "model".to_string(),
+            system_fingerprint: "fp".to_string(),
+            object: "chat.completion.chunk".to_string(),
+            usage: None,
+            prompt_tokens: 4,
+            effective_params: None,
+            documents: None,
+            adapters: None,
+        })
+    }
+
+    fn choice(text: &str, tokens: Vec<u32>, finish_reason: Option<&str>) -> ChunkChoice {
+        ChunkChoice {
+            finish_reason: finish_reason.map(ToString::to_string),
+            index: 0,
+            delta: Delta {
+                content: Some(text.to_string()),
+                role: "assistant".to_string(),
+                tool_calls: None,
+            },
+            logprobs: None,
+            stop_reason: None,
+            tokens,
+        }
+    }
+
+    #[tokio::test]
+    async fn chunks_without_choices_are_skipped() {
+        let (tx, rx) = channel(4);
+        let (chunks_tx, mut chunks) = channel(4);
+        for response in [
+            chunk(vec![choice("Hello", vec![5], None)]),
+            chunk(Vec::new()),
+            chunk(vec![choice(" there", vec![7], Some("stop"))]),
+        ] {
+            tx.send(response).await.unwrap();
+        }
+        forward_streaming_chunks(rx, chunks_tx).await;
+
+        let mut received = Vec::new();
+        while let Some(chunk) = chunks.recv().await {
+            received.push(chunk.unwrap());
+        }
+        assert_eq!(
+            received,
+            [
+                StreamingChunk {
+                    text: "Hello".to_string(),
+                    tokens: vec![5],
+                    done: false,
+                },
+                StreamingChunk {
+                    text: " there".to_string(),
+                    tokens: vec![7],
+                    done: true,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::Context;
+use candle_core::Device;
+use indexmap::IndexMap;
+use mistralrs_core::*;
+
+use crate::{Model, RequestBuilder, TextMessageRole};
+
+/// Number of documents retrieved for each query by default.
+const DEFAULT_TOP_K: usize = 4;
+
+/// Retrieval-augmented generation: documents are embedded with a BERT embedding model and stored in
+/// a [`VectorStore`]. Each query retrieves the nearest documents, which are given to the model as
+/// context for a grounded answer.
+pub struct RagPipeline {
+    llm: Model,
+    embedder: BertPipeline,
+    device: Device,
+    vector_store: Box<dyn VectorStore>,
+    top_k: usize,
+}
+
+impl RagPipeline {
+    /// Create a pipeline with an [`InMemoryVectorStore`].
+    pub fn new(
+        llm: Model,
+        embedding_model: BertEmbeddingModel,
+        device: &Device,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            llm,
+            embedder: BertPipeline::new(embedding_model, device)?,
+            device: device.clone(),
+            vector_store: Box::new(InMemoryVectorStore::new()),
+            top_k: DEFAULT_TOP_K,
+        })
+    }
+
+    pub fn with_vector_store(mut self, vector_store: Box<dyn VectorStore>) -> Self {
+        self.vector_store = vector_store;
+        self
+    }
+
+    /// Number of documents to retrieve for each query.
+    pub fn with_top_k(mut self, top_k: usize) -> Self {
+        self.top_k = top_k;
+        self
+    }
+
+    pub fn llm(&self) -> &Model {
+        &self.llm
+    }
+
+    pub fn vector_store(&self) -> &dyn VectorStore {
+        &*self.vector_store
+    }
+
+    /// Embed the texts with the embedding model.
+    pub fn embed_batch(&mut self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        Ok(self.embedder.embed_batch(texts, &self.device)?)
+    }
+
+    /// Embed and store documents, given as `(id, text)` pairs.
+    pub fn add_documents(&mut self, documents: Vec<(String, String)>) -> anyhow::Result<()> {
+        let (ids, texts): (Vec<_>, Vec<_>) = documents.into_iter().unzip();
+        let embeddings = self.embed_batch(&texts)?;
+        for ((id, text), embedding) in ids.into_iter().zip(texts).zip(embeddings) {
+            self.vector_store.insert(id, embedding, text)?;
+        }
+        Ok(())
+    }
+
+    /// Get the documents most relevant to the query.
+    pub fn retrieve(&mut self, query: &str) -> anyhow::Result<Vec<Document>> {
+        let query_embedding = self
+            .embed_batch(&[query.to_string()])?
+            .pop()
+            .context("Expected a query embedding")?;
+        self.vector_store.search(&query_embedding, self.top_k)
+    }
+
+    /// Answer the query using the retrieved documents as context, generating at most `max_tokens`.
+    /// The engine includes the nearest documents which fit in the context.
+    pub async fn generate_with_retrieval(
+        &mut self,
+        query: &str,
+        max_tokens: usize,
+    ) -> anyhow::Result<String> {
+        let documents = self
+            .retrieve(query)?
+            .into_iter()
+            .map(|document| RetrievedDocument {
+                text: document.text,
+                score: -document.distance,
+                metadata: IndexMap::from([("id".to_string(), document.id)]),
+            })
+            .collect();
+        let request = RequestBuilder::new()
+            .add_message(TextMessageRole::User, query)
+            .with_documents(documents, DocumentPacking::default())
+            .set_sampler_max_len(max_tokens);
+        let response = self.llm.send_chat_request(request).await?;
+        response
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|choice| choice.message.content)
+            .context("Model returned no content")
+    }
+}
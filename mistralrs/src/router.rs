@@ -0,0 +1,113 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use mistralrs_core::*;
+use serde::Serialize;
+
+use crate::{Model, RequestLike};
+
+/// Usage attributed to one pipeline of a [`ModelRouter`].
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct PipelineUsage {
+    /// Number of requests served.
+    pub requests: usize,
+    /// Number of the served requests which overflowed from another pipeline.
+    pub overflow_requests: usize,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+}
+
+struct RoutedPipeline {
+    model: Model,
+    usage: Mutex<PipelineUsage>,
+}
+
+/// Serve several pipelines, for example different quantizations of the same model, and route
+/// each request to one of them with a [`RoutingPolicy`].
+///
+/// The queue depth of a pipeline is the number of sequences its engine is prefilling, decoding
+/// or waiting to schedule, including the requests sent to it without the router.
+pub struct ModelRouter {
+    pipelines: HashMap<String, RoutedPipeline>,
+    policy: RoutingPolicy,
+}
+
+impl ModelRouter {
+    /// Every pipeline which the policy routes to must be given.
+    pub fn new(pipelines: Vec<(String, Model)>, policy: RoutingPolicy) -> anyhow::Result<Self> {
+        let pipelines = pipelines
+            .into_iter()
+            .map(|(name, model)| {
+                (
+                    name,
+                    RoutedPipeline {
+                        model,
+                        usage: Mutex::new(PipelineUsage::default()),
+                    },
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        if let Some(missing) = policy.pipelines().find(|p| !pipelines.contains_key(*p)) {
+            anyhow::bail!("The routing policy uses the unknown pipeline `{missing}`.");
+        }
+        Ok(Self { pipelines, policy })
+    }
+
+    pub fn policy(&self) -> &RoutingPolicy {
+        &self.policy
+    }
+
+    pub fn pipeline(&self, name: &str) -> Option<&Model> {
+        self.pipelines.get(name).map(|p| &p.model)
+    }
+
+    /// Number of sequences which the engine of the pipeline has not finished yet.
+    pub async fn queue_depth(&self, name: &str) -> anyhow::Result<usize> {
+        let Some(pipeline) = self.pipelines.get(name) else {
+            anyhow::bail!("Unknown pipeline `{name}`.");
+        };
+        let metrics = pipeline.model.scheduler_metrics().await?;
+        Ok(metrics.prefill_queue_depth + metrics.decode_queue_depth)
+    }
+
+    /// Usage of each pipeline, attributed to the pipeline which actually served the requests.
+    pub fn usage(&self) -> HashMap<String, PipelineUsage> {
+        self.pipelines
+            .iter()
+            .map(|(name, p)| (name.clone(), p.usage.lock().unwrap().clone()))
+            .collect()
+    }
+
+    /// Choose the pipeline for a request with the given tags without sending it.
+    pub async fn route(&self, tags: &RouteTags) -> anyhow::Result<RoutingDecision> {
+        let mut depths = HashMap::new();
+        for name in self.policy.pipelines() {
+            if !depths.contains_key(name) {
+                depths.insert(name, self.queue_depth(name).await?);
+            }
+        }
+        Ok(self.policy.route(tags, |name| depths[name]))
+    }
+
+    /// Route the request and generate with the chosen pipeline. The routing decision is set as
+    /// the `routing` of the response.
+    pub async fn send_chat_request<R: RequestLike>(
+        &self,
+        tags: &RouteTags,
+        request: R,
+    ) -> anyhow::Result<ChatCompletionResponse> {
+        let routing = self.route(tags).await?;
+        let pipeline = &self.pipelines[&routing.pipeline];
+
+        let mut response = pipeline.model.send_chat_request(request).await?;
+
+        let mut usage = pipeline.usage.lock().unwrap();
+        usage.requests += 1;
+        usage.overflow_requests += usize::from(routing.overflowed);
+        usage.prompt_tokens += response.usage.prompt_tokens;
+        usage.completion_tokens += response.usage.completion_tokens;
+        drop(usage);
+
+        response.routing = Some(routing);
+        Ok(response)
+    }
+}
@@ -0,0 +1,215 @@
+use std::fmt;
+
+use anyhow::Context;
+use either::Either;
+use mistralrs_core::*;
+
+use crate::{Model, RequestBuilder, TextMessageRole, TextMessages};
+
+/// A multi-turn conversation with a model.
+///
+/// Each turn sends the whole conversation, but the prefix cacher keeps the KV cache of the
+/// previous turns, so only the new tokens (the latest user message and the assistant prefix of
+/// the chat template) are prefilled. The prefix cacher is disabled with PagedAttention or by
+/// `with_prefix_cache_n(None)`, in which case every turn prefills the whole conversation.
+pub struct ChatSession<'a> {
+    model: &'a Model,
+    system_prompt: Option<String>,
+    messages: TextMessages,
+    /// Total number of prompt tokens over all turns.
+    prompt_tokens: usize,
+    budget: Option<TokenBudget>,
+}
+
+/// Error of [`ChatSession::chat`] when the turn does not fit in the token budget of the
+/// conversation. Downcast the [`anyhow::Error`] to handle it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BudgetExhausted {
+    pub budget: usize,
+    pub used: usize,
+    /// Prompt tokens of the rejected turn.
+    pub prompt_tokens: usize,
+}
+
+impl fmt::Display for BudgetExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Token budget of {} exhausted: {} tokens used and the next turn has {} prompt tokens.",
+            self.budget, self.used, self.prompt_tokens
+        )
+    }
+}
+
+impl std::error::Error for BudgetExhausted {}
+
+#[derive(Clone, Copy, Debug)]
+struct TokenBudget {
+    budget: usize,
+    used: usize,
+}
+
+impl TokenBudget {
+    fn remaining(&self) -> usize {
+        self.budget.saturating_sub(self.used)
+    }
+
+    /// Maximum number of tokens a turn with `prompt_tokens` may generate, at least one.
+    fn max_completion_tokens(&self, prompt_tokens: usize) -> Result<usize, BudgetExhausted> {
+        match self.remaining().checked_sub(prompt_tokens) {
+            Some(max) if max > 0 => Ok(max),
+            _ => Err(BudgetExhausted {
+                budget: self.budget,
+                used: self.used,
+                prompt_tokens,
+            }),
+        }
+    }
+
+    fn charge(&mut self, usage: &Usage) {
+        self.used += usage.total_tokens;
+    }
+}
+
+impl<'a> ChatSession<'a> {
+    pub fn new(model: &'a Model) -> Self {
+        Self {
+            model,
+            system_prompt: None,
+            messages: TextMessages::new(),
+            prompt_tokens: 0,
+            budget: None,
+        }
+    }
+
+    /// Cap the total number of tokens of the conversation: every turn uses its prompt tokens,
+    /// which is the whole conversation so far, and the generated tokens. The generation of a turn
+    /// is limited to the remaining budget, and a turn is rejected with [`BudgetExhausted`] if its
+    /// prompt does not leave room for at least one generated token.
+    pub fn with_token_budget(mut self, budget: usize) -> Self {
+        self.budget = Some(TokenBudget { budget, used: 0 });
+        self
+    }
+
+    pub fn with_system_prompt(mut self, system_prompt: impl ToString) -> Self {
+        self.system_prompt = Some(system_prompt.to_string());
+        self.clear();
+        self
+    }
+
+    /// The conversation so far.
+    pub fn messages(&self) -> &TextMessages {
+        &self.messages
+    }
+
+    /// Total number of prompt tokens over all turns, including those reused from the cache.
+    pub fn prompt_tokens(&self) -> usize {
+        self.prompt_tokens
+    }
+
+    /// Tokens left in the budget set by [`Self::with_token_budget`], if any.
+    pub fn remaining_budget(&self) -> Option<usize> {
+        self.budget.as_ref().map(TokenBudget::remaining)
+    }
+
+    /// Send the user message and return the reply of the model, which is added to the
+    /// conversation. The conversation is unchanged if generation fails or the turn does not fit
+    /// in the token budget.
+    pub async fn chat(
+        &mut self,
+        user_message: impl ToString,
+        params: &SamplingParams,
+    ) -> anyhow::Result<String> {
+        let messages = self
+            .messages
+            .clone()
+            .add_message(TextMessageRole::User, user_message);
+        let mut params = params.clone();
+        if let Some(budget) = &self.budget {
+            let prompt = self
+                .model
+                .tokenize(Either::Left(messages.clone()), None, true, true)
+                .await?;
+            let max_len = budget.max_completion_tokens(prompt.len())?;
+            params.max_len = Some(params.max_len.map_or(max_len, |len| len.min(max_len)));
+        }
+        let request = RequestBuilder::from(messages.clone()).set_sampling(params);
+        let response = self.model.send_chat_request(request).await?;
+        let reply = response
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|choice| choice.message.content)
+            .context("Model returned no content")?;
+
+        self.prompt_tokens += response.usage.prompt_tokens;
+        if let Some(budget) = &mut self.budget {
+            budget.charge(&response.usage);
+        }
+        self.messages = messages.add_message(TextMessageRole::Assistant, &reply);
+        Ok(reply)
+    }
+
+    /// Forget the conversation, keeping the system prompt if any. The tokens used so far still
+    /// count towards the token budget.
+    pub fn clear(&mut self) {
+        self.messages = match &self.system_prompt {
+            Some(system_prompt) => {
+                TextMessages::new().add_message(TextMessageRole::System, system_prompt)
+            }
+            None => TextMessages::new(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mistralrs_core::Usage;
+
+    use super::{BudgetExhausted, TokenBudget};
+
+    fn usage(prompt_tokens: usize, completion_tokens: usize) -> Usage {
+        Usage {
+            completion_tokens,
+            reasoning_tokens: 0,
+            prompt_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            avg_tok_per_sec: 0.,
+            avg_prompt_tok_per_sec: 0.,
+            avg_compl_tok_per_sec: 0.,
+            total_time_sec: 0.,
+            total_prompt_time_sec: 0.,
+            total_completion_time_sec: 0.,
+            speculative: None,
+        }
+    }
+
+    #[test]
+    fn budget_exhausted() {
+        let mut budget = TokenBudget {
+            budget: 100,
+            used: 0,
+        };
+
+        // Each turn prompts with the whole conversation so far.
+        assert_eq!(budget.max_completion_tokens(10), Ok(90));
+        budget.charge(&usage(10, 20));
+        assert_eq!(budget.remaining(), 70);
+        assert_eq!(budget.max_completion_tokens(35), Ok(35));
+        budget.charge(&usage(35, 30));
+        assert_eq!(budget.remaining(), 5);
+
+        // The next prompt is larger than what is left.
+        assert_eq!(
+            budget.max_completion_tokens(70),
+            Err(BudgetExhausted {
+                budget: 100,
+                used: 95,
+                prompt_tokens: 70,
+            })
+        );
+        // A prompt leaving no room to generate is rejected too.
+        assert!(budget.max_completion_tokens(5).is_err());
+        assert_eq!(budget.max_completion_tokens(4), Ok(1));
+    }
+}
@@ -49,6 +49,9 @@ impl TextSpeculativeBuilder {
             imatrix: builder.imatrix,
             calibration_file: builder.calibration_file,
             hf_cache_path: builder.hf_cache_path,
+            early_exit: None,
+            byte_fallback: builder.byte_fallback,
+            tok_env: builder.tok_env.clone(),
         };
 
         if builder.with_logging {
@@ -21,6 +21,8 @@ pub struct TextModelBuilder {
     pub(crate) chat_template: Option<String>,
     pub(crate) jinja_explicit: Option<String>,
     pub(crate) tokenizer_json: Option<String>,
+    pub(crate) byte_fallback: Option<bool>,
+    pub(crate) tok_env: Option<llguidance::toktrie::TokEnv>,
     pub(crate) device_mapping: Option<DeviceMapSetting>,
     pub(crate) hf_cache_path: Option<PathBuf>,
     pub(crate) search_bert_model: Option<BertEmbeddingModel>,
@@ -35,6 +37,8 @@ pub struct TextModelBuilder {
     pub(crate) force_cpu: bool,
     pub(crate) isq: Option<IsqType>,
     pub(crate) throughput_logging: bool,
+    pub(crate) early_exit: Option<EarlyExitConfig>,
+    pub(crate) reference_logits: Option<(PathBuf, f32)>,
 
     // Other things
     pub(crate) paged_attn_cfg: Option<PagedAttentionConfig>,
@@ -113,6 +117,10 @@ impl TextModelBuilder {
             jinja_explicit: None,
             throughput_logging: false,
             hf_cache_path: None,
+            early_exit: None,
+            reference_logits: None,
+            byte_fallback: None,
+            tok_env: None,
             search_bert_model: None,
         }
     }
@@ -141,6 +149,35 @@ impl TextModelBuilder {
         self
     }
 
+    /// Exit from the decoder layers early when the logit lens prediction of an intermediate layer
+    /// is confident enough. This is only supported for Llama models.
+    pub fn with_early_exit(mut self, early_exit: EarlyExitConfig) -> Self {
+        self.early_exit = Some(early_exit);
+        self
+    }
+
+    /// Check the model against reference logits once it is loaded, failing the build if any logit
+    /// differs by more than `tolerance`. See [`ReferenceLogits`] for the file format.
+    pub fn with_reference_logits(mut self, path: impl Into<PathBuf>, tolerance: f32) -> Self {
+        self.reference_logits = Some((path.into(), tolerance));
+        self
+    }
+
+    /// Force byte fallback of the tokenizer on or off, overriding the tokenizer's default. With
+    /// byte fallback, unknown characters are encoded as byte tokens instead of the unknown token.
+    pub fn with_byte_fallback(mut self, byte_fallback: bool) -> Self {
+        self.byte_fallback = Some(byte_fallback);
+        self
+    }
+
+    /// Use a token environment built beforehand, for example with [`build_tok_env`], instead of
+    /// building one from the tokenizer. Models of the same vocabulary may share it to save the
+    /// time to build it. Loading fails if its tokens differ from those of the tokenizer.
+    pub fn with_tok_env(mut self, tok_env: llguidance::toktrie::TokEnv) -> Self {
+        self.tok_env = Some(tok_env);
+        self
+    }
+
     /// Set the model topology for use during loading. If there is an overlap, the topology type is used over the ISQ type.
     pub fn with_topology(mut self, topology: Topology) -> Self {
         self.topology = Some(topology);
@@ -296,6 +333,9 @@ impl TextModelBuilder {
             imatrix: self.imatrix,
             calibration_file: self.calibration_file,
             hf_cache_path: self.hf_cache_path,
+            early_exit: self.early_exit,
+            byte_fallback: self.byte_fallback,
+            tok_env: self.tok_env.clone(),
         };
 
         if self.with_logging {
@@ -359,7 +399,11 @@ impl TextModelBuilder {
             runner = runner.with_prefix_cache_n(n)
         }
 
-        Ok(Model::new(runner.build()))
+        let model = Model::new(runner.build());
+        if let Some((path, tolerance)) = self.reference_logits {
+            model.verify_reference_logits(&path, tolerance).await?;
+        }
+        Ok(model)
     }
 }
 
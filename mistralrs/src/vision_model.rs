@@ -21,6 +21,8 @@ pub struct VisionModelBuilder {
     pub(crate) chat_template: Option<String>,
     pub(crate) jinja_explicit: Option<String>,
     pub(crate) tokenizer_json: Option<String>,
+    pub(crate) byte_fallback: Option<bool>,
+    pub(crate) tok_env: Option<llguidance::toktrie::TokEnv>,
     pub(crate) device_mapping: Option<DeviceMapSetting>,
     pub(crate) max_edge: Option<u32>,
     pub(crate) hf_cache_path: Option<PathBuf>,
@@ -74,6 +76,8 @@ impl VisionModelBuilder {
             throughput_logging: false,
             paged_attn_cfg: None,
             hf_cache_path: None,
+            byte_fallback: None,
+            tok_env: None,
             search_bert_model: None,
         }
     }
@@ -222,6 +226,21 @@ impl VisionModelBuilder {
         self
     }
 
+    /// Force byte fallback of the tokenizer on or off, overriding the tokenizer's default. With
+    /// byte fallback, unknown characters are encoded as byte tokens instead of the unknown token.
+    pub fn with_byte_fallback(mut self, byte_fallback: bool) -> Self {
+        self.byte_fallback = Some(byte_fallback);
+        self
+    }
+
+    /// Use a token environment built beforehand, for example with [`build_tok_env`], instead of
+    /// building one from the tokenizer. Models of the same vocabulary may share it to save the
+    /// time to build it. Loading fails if its tokens differ from those of the tokenizer.
+    pub fn with_tok_env(mut self, tok_env: llguidance::toktrie::TokEnv) -> Self {
+        self.tok_env = Some(tok_env);
+        self
+    }
+
     pub async fn build(self) -> anyhow::Result<Model> {
         let config = VisionSpecificConfig {
             use_flash_attn: self.use_flash_attn,
@@ -233,6 +252,8 @@ impl VisionModelBuilder {
             calibration_file: self.calibration_file,
             imatrix: self.imatrix,
             hf_cache_path: self.hf_cache_path,
+            byte_fallback: self.byte_fallback,
+            tok_env: self.tok_env.clone(),
         };
 
         if self.with_logging {
@@ -0,0 +1,213 @@
+//! An accuracy companion with F16 copies of the embedding and attention tensors brings the
+//! perplexity of a tiny 2-bit GGUF Llama, written to a temporary directory, back towards the full
+//! precision model.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use candle_core::{
+    quantized::{gguf_file, GgmlDType, QTensor},
+    DType, Device, Tensor,
+};
+use mistralrs::{DeviceMapSetting, GgufModelBuilder, Model, TokenSource};
+
+const VOCAB: usize = 32;
+const HIDDEN: usize = 256;
+const INTERMEDIATE: usize = 256;
+const HEADS: usize = 4;
+const KV_HEADS: usize = 2;
+const HEAD_DIM: usize = HIDDEN / HEADS;
+/// Number of tokens sampled from the full precision model.
+const TEXT_LEN: usize = 48;
+/// Tensors which the accuracy companion keeps in F16.
+const SENSITIVE: [&str; 5] = [
+    "token_embd.weight",
+    "blk.0.attn_q.weight",
+    "blk.0.attn_k.weight",
+    "blk.0.attn_v.weight",
+    "blk.0.attn_output.weight",
+];
+
+/// Deterministic pseudo-random numbers in `[0, 1)`.
+fn uniform(seed: usize, n: usize) -> Vec<f32> {
+    (0..n)
+        .map(|i| {
+            let x = ((seed * 104_729 + i) as f64 * 12.9898).sin() * 43_758.545_3;
+            x.fract().abs() as f32
+        })
+        .collect()
+}
+
+/// The weights of a one-layer Llama, with norms of one and uniform matrices.
+fn weights() -> candle_core::Result<Vec<(&'static str, Tensor)>> {
+    let shapes = [
+        ("token_embd.weight", (VOCAB, HIDDEN)),
+        ("blk.0.attn_q.weight", (HIDDEN, HIDDEN)),
+        ("blk.0.attn_k.weight", (KV_HEADS * HEAD_DIM, HIDDEN)),
+        ("blk.0.attn_v.weight", (KV_HEADS * HEAD_DIM, HIDDEN)),
+        ("blk.0.attn_output.weight", (HIDDEN, HIDDEN)),
+        ("blk.0.ffn_gate.weight", (INTERMEDIATE, HIDDEN)),
+        ("blk.0.ffn_up.weight", (INTERMEDIATE, HIDDEN)),
+        ("blk.0.ffn_down.weight", (HIDDEN, INTERMEDIATE)),
+    ];
+    let mut weights = shapes
+        .into_iter()
+        .enumerate()
+        .map(|(seed, (name, (rows, cols)))| {
+            let values = uniform(seed, rows * cols)
+                .into_iter()
+                .map(|x| 2. * x - 1.)
+                .collect::<Vec<_>>();
+            Ok((name, Tensor::from_vec(values, (rows, cols), &Device::Cpu)?))
+        })
+        .collect::<candle_core::Result<Vec<_>>>()?;
+    for name in [
+        "output_norm.weight",
+        "blk.0.attn_norm.weight",
+        "blk.0.ffn_norm.weight",
+    ] {
+        weights.push((name, Tensor::ones(HIDDEN, DType::F32, &Device::Cpu)?));
+    }
+    Ok(weights)
+}
+
+/// Write the model to `path`, with the matrices quantized to `dtype`.
+fn write_gguf(
+    path: &Path,
+    weights: &[(&str, Tensor)],
+    dtype: GgmlDType,
+) -> candle_core::Result<()> {
+    let tensors = weights
+        .iter()
+        .map(|(name, weight)| {
+            let dtype = if weight.rank() == 2 {
+                dtype
+            } else {
+                GgmlDType::F32
+            };
+            Ok((*name, QTensor::quantize(weight, dtype)?))
+        })
+        .collect::<candle_core::Result<Vec<_>>>()?;
+
+    let string = |s: &str| gguf_file::Value::String(s.to_string());
+    let int = |x: usize| gguf_file::Value::U32(x as u32);
+    let mut tokens = vec![string("<unk>"), string("<s>"), string("</s>")];
+    tokens.extend((3..VOCAB).map(|i| string(&format!("▁t{i}"))));
+    let metadata = [
+        ("general.architecture", string("llama")),
+        ("llama.block_count", int(1)),
+        ("llama.embedding_length", int(HIDDEN)),
+        ("llama.feed_forward_length", int(INTERMEDIATE)),
+        ("llama.attention.head_count", int(HEADS)),
+        ("llama.attention.head_count_kv", int(KV_HEADS)),
+        ("llama.rope.dimension_count", int(HEAD_DIM)),
+        (
+            "llama.attention.layer_norm_rms_epsilon",
+            gguf_file::Value::F32(1e-5),
+        ),
+        ("tokenizer.ggml.model", string("llama")),
+        ("tokenizer.ggml.tokens", gguf_file::Value::Array(tokens)),
+        (
+            "tokenizer.ggml.scores",
+            gguf_file::Value::Array(vec![gguf_file::Value::F32(-1.); VOCAB]),
+        ),
+        ("tokenizer.ggml.unknown_token_id", int(0)),
+        ("tokenizer.ggml.bos_token_id", int(1)),
+        ("tokenizer.ggml.eos_token_id", int(2)),
+        (
+            "tokenizer.chat_template",
+            string("{% for message in messages %}{{ message['content'] }}{% endfor %}"),
+        ),
+    ];
+
+    let mut file = std::fs::File::create(path)?;
+    gguf_file::write(
+        &mut file,
+        &metadata.iter().map(|(k, v)| (*k, v)).collect::<Vec<_>>(),
+        &tensors.iter().map(|(n, t)| (*n, t)).collect::<Vec<_>>(),
+    )
+}
+
+async fn load(dir: &Path, file: &str, companion: Option<PathBuf>) -> anyhow::Result<Model> {
+    let mut builder = GgufModelBuilder::new(dir.display().to_string(), vec![file])
+        .with_force_cpu()
+        .with_token_source(TokenSource::None)
+        .with_prefix_cache_n(None)
+        .with_device_mapping(DeviceMapSetting::dummy());
+    if let Some(companion) = companion {
+        builder = builder.with_accuracy_companion(companion);
+    }
+    builder.build().await
+}
+
+/// Log probabilities of the token following `tokens`.
+async fn next_logprobs(model: &Model, tokens: &[u32]) -> anyhow::Result<Vec<f32>> {
+    let logits = model
+        .prompt_logits(tokens.to_vec())
+        .await?
+        .to_device(&Device::Cpu)?
+        .to_dtype(DType::F32)?
+        .flatten_all()?;
+    Ok(candle_nn::ops::log_softmax(&logits, 0)?.to_vec1()?)
+}
+
+async fn perplexity(model: &Model, text: &[u32]) -> anyhow::Result<f64> {
+    let mut nll = 0.;
+    for i in 1..text.len() {
+        let logprobs = next_logprobs(model, &text[..i]).await?;
+        nll -= f64::from(logprobs[text[i] as usize]);
+    }
+    Ok((nll / (text.len() - 1) as f64).exp())
+}
+
+#[tokio::test]
+async fn companion_improves_perplexity() -> anyhow::Result<()> {
+    let dir = std::env::temp_dir().join(format!(
+        "mistralrs_accuracy_companion_{}",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let weights = weights()?;
+    write_gguf(&dir.join("f32.gguf"), &weights, GgmlDType::F32)?;
+    write_gguf(&dir.join("q2k.gguf"), &weights, GgmlDType::Q2K)?;
+    let companion = dir.join("companion.safetensors");
+    let sensitive = weights
+        .iter()
+        .filter(|(name, _)| SENSITIVE.contains(name))
+        .map(|(name, weight)| (name.to_string(), weight.clone()))
+        .collect::<HashMap<_, _>>();
+    candle_core::safetensors::save(&sensitive, &companion)?;
+
+    let reference = load(&dir, "f32.gguf", None).await?;
+    let low_bit = load(&dir, "q2k.gguf", None).await?;
+    let recovered = load(&dir, "q2k.gguf", Some(companion)).await?;
+
+    // Sample a text from the full precision model, which it has the lowest expected perplexity on.
+    let mut text = vec![1u32];
+    for u in uniform(99, TEXT_LEN) {
+        let logprobs = next_logprobs(&reference, &text).await?;
+        let mut cumulative = 0.;
+        let token = logprobs
+            .iter()
+            .position(|logprob| {
+                cumulative += logprob.exp();
+                cumulative > u
+            })
+            .unwrap_or(VOCAB - 1);
+        text.push(token as u32);
+    }
+
+    let reference = perplexity(&reference, &text).await?;
+    let low_bit = perplexity(&low_bit, &text).await?;
+    let recovered = perplexity(&recovered, &text).await?;
+    assert!(
+        reference < low_bit && recovered < low_bit,
+        "perplexity of the full precision model {reference}, the 2-bit model {low_bit} and the 2-bit model with the companion {recovered}"
+    );
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
@@ -0,0 +1,148 @@
+//! A tiny safetensors Llama, written to a directory, for the integration tests.
+
+use std::{collections::HashMap, path::Path};
+
+use candle_core::{DType, Device, Tensor};
+use mistralrs::{DeviceMapSetting, ModelDType, NormalLoaderType, TextModelBuilder, TokenSource};
+use serde_json::json;
+
+const VOCAB: usize = 32;
+const HIDDEN: usize = 64;
+const INTERMEDIATE: usize = 128;
+const HEADS: usize = 4;
+const KV_HEADS: usize = 2;
+const HEAD_DIM: usize = HIDDEN / HEADS;
+
+/// Deterministic pseudo-random numbers in `[-1, 1)`.
+fn uniform(seed: usize, n: usize) -> Vec<f32> {
+    (0..n)
+        .map(|i| {
+            let x = ((seed * 104_729 + i) as f64 * 12.9898).sin() * 43_758.545_3;
+            2. * x.fract().abs() as f32 - 1.
+        })
+        .collect()
+}
+
+/// Write a one-layer Llama with the weights of `seed` and a word level tokenizer to `dir`.
+pub fn write_tiny_llama(dir: &Path, seed: usize) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let shapes = [
+        ("model.embed_tokens.weight", (VOCAB, HIDDEN)),
+        ("model.layers.0.self_attn.q_proj.weight", (HIDDEN, HIDDEN)),
+        (
+            "model.layers.0.self_attn.k_proj.weight",
+            (KV_HEADS * HEAD_DIM, HIDDEN),
+        ),
+        (
+            "model.layers.0.self_attn.v_proj.weight",
+            (KV_HEADS * HEAD_DIM, HIDDEN),
+        ),
+        ("model.layers.0.self_attn.o_proj.weight", (HIDDEN, HIDDEN)),
+        (
+            "model.layers.0.mlp.gate_proj.weight",
+            (INTERMEDIATE, HIDDEN),
+        ),
+        ("model.layers.0.mlp.up_proj.weight", (INTERMEDIATE, HIDDEN)),
+        (
+            "model.layers.0.mlp.down_proj.weight",
+            (HIDDEN, INTERMEDIATE),
+        ),
+        ("lm_head.weight", (VOCAB, HIDDEN)),
+    ];
+    let mut tensors = HashMap::new();
+    for (i, (name, (rows, cols))) in shapes.into_iter().enumerate() {
+        let values = uniform(seed * shapes.len() + i, rows * cols);
+        tensors.insert(
+            name.to_string(),
+            Tensor::from_vec(values, (rows, cols), &Device::Cpu)?,
+        );
+    }
+    for name in [
+        "model.norm.weight",
+        "model.layers.0.input_layernorm.weight",
+        "model.layers.0.post_attention_layernorm.weight",
+    ] {
+        tensors.insert(
+            name.to_string(),
+            Tensor::ones(HIDDEN, DType::F32, &Device::Cpu)?,
+        );
+    }
+    candle_core::safetensors::save(&tensors, dir.join("model-00001-of-00001.safetensors"))?;
+
+    let config = json!({
+        "architectures": ["LlamaForCausalLM"],
+        "hidden_act": "silu",
+        "hidden_size": HIDDEN,
+        "intermediate_size": INTERMEDIATE,
+        "vocab_size": VOCAB,
+        "num_hidden_layers": 1,
+        "num_attention_heads": HEADS,
+        "num_key_value_heads": KV_HEADS,
+        "rms_norm_eps": 1e-5,
+        "rope_theta": 10000.0,
+        "max_position_embeddings": 256,
+        "tie_word_embeddings": false,
+    });
+    std::fs::write(dir.join("config.json"), config.to_string())?;
+
+    let mut vocab = vec!["<unk>".to_string(), "<s>".to_string(), "</s>".to_string()];
+    vocab.extend((3..VOCAB).map(|i| format!("t{i}")));
+    let added_tokens = vocab[..3]
+        .iter()
+        .enumerate()
+        .map(|(id, content)| {
+            json!({
+                "id": id,
+                "content": content,
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": true,
+            })
+        })
+        .collect::<Vec<_>>();
+    let tokenizer = json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": added_tokens,
+        "normalizer": null,
+        "pre_tokenizer": { "type": "Whitespace" },
+        "post_processor": null,
+        "decoder": null,
+        "model": {
+            "type": "WordLevel",
+            "vocab": vocab
+                .iter()
+                .enumerate()
+                .map(|(id, token)| (token.clone(), json!(id)))
+                .collect::<serde_json::Map<_, _>>(),
+            "unk_token": "<unk>",
+        },
+    });
+    std::fs::write(dir.join("tokenizer.json"), tokenizer.to_string())?;
+
+    let tokenizer_config = json!({
+        "bos_token": "<s>",
+        "eos_token": "</s>",
+        "unk_token": "<unk>",
+        "chat_template": "{% for message in messages %}{{ message['content'] }}{% endfor %}",
+    });
+    std::fs::write(
+        dir.join("tokenizer_config.json"),
+        tokenizer_config.to_string(),
+    )?;
+    Ok(())
+}
+
+/// A builder for the model written by [`write_tiny_llama`] to `dir`, in F32 on the CPU.
+pub fn tiny_llama(dir: &Path) -> TextModelBuilder {
+    TextModelBuilder::new(dir.display().to_string())
+        .with_loader_type(NormalLoaderType::Llama)
+        .with_dtype(ModelDType::F32)
+        .with_force_cpu()
+        .with_token_source(TokenSource::None)
+        .with_prefix_cache_n(None)
+        .with_device_mapping(DeviceMapSetting::dummy())
+}
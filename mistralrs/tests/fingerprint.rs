@@ -0,0 +1,32 @@
+//! The `system_fingerprint` of responses identifies the weights which produced them, so two
+//! quantizations of the same model must have distinct fingerprints.
+//!
+//! Loading the models downloads them, so this is ignored by default. Run it with
+//! `cargo test -p mistralrs --test fingerprint -- --include-ignored`.
+
+use mistralrs::{GgufModelBuilder, TextMessageRole, TextMessages};
+
+const MODEL_ID: &str = "TheBloke/TinyLlama-1.1B-Chat-v1.0-GGUF";
+
+async fn fingerprint(file: &str) -> String {
+    let model = GgufModelBuilder::new(MODEL_ID, vec![file])
+        .with_force_cpu()
+        .build()
+        .await
+        .unwrap();
+
+    let messages = TextMessages::new().add_message(TextMessageRole::User, "Hello!");
+    let response = model.send_chat_request(messages).await.unwrap();
+    assert_eq!(response.system_fingerprint, model.system_fingerprint());
+    response.system_fingerprint
+}
+
+#[tokio::test]
+#[ignore = "downloads the GGUF models"]
+async fn quantizations_have_distinct_fingerprints() {
+    let q4 = fingerprint("tinyllama-1.1b-chat-v1.0.Q4_K_M.gguf").await;
+    let q8 = fingerprint("tinyllama-1.1b-chat-v1.0.Q8_0.gguf").await;
+    assert!(q4.ends_with("_q4k"), "{q4}");
+    assert!(q8.ends_with("_q8_0"), "{q8}");
+    assert_ne!(q4, q8);
+}
@@ -0,0 +1,181 @@
+//! Compare the next token distribution of GGUF models against reference values computed by
+//! llama.cpp, to catch numerical regressions such as rope bugs, wrong norm epsilons or bad
+//! dequantization.
+//!
+//! Each snapshot in `tests/logits_snapshots` describes one GGUF file, a fixed prompt and the top
+//! tokens with their probabilities as computed by llama.cpp. Snapshots are generated with
+//! `scripts/gguf_logits_snapshot.py`; supporting a new architecture only requires adding its
+//! model to that script and committing the new snapshot.
+//!
+//! Loading the models downloads them, so the comparison is ignored by default. Run it with
+//! `cargo test -p mistralrs --test gguf_logits -- --include-ignored`. It fails without any
+//! snapshot, while the check of the snapshot files passes on an empty directory.
+
+use std::path::{Path, PathBuf};
+
+use mistralrs::GgufModelBuilder;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+struct Snapshot {
+    architecture: String,
+    model_id: String,
+    file: String,
+    /// GGML type of the weights, which sets the tolerance.
+    quant: String,
+    prompt_tokens: Vec<u32>,
+    /// Most likely next tokens, in decreasing order of probability.
+    top_tokens: Vec<TopToken>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TopToken {
+    id: u32,
+    prob: f32,
+}
+
+/// Maximum absolute difference in probability allowed for a quantization type. The kernels differ
+/// from llama.cpp in accumulation order and, for quantized types, in the quantization of the
+/// activations.
+fn tolerance(quant: &str) -> f32 {
+    match quant {
+        "F32" => 1e-3,
+        "F16" | "BF16" => 5e-3,
+        "Q8_0" | "Q8_K" => 1e-2,
+        "Q6_K" | "Q5_0" | "Q5_1" | "Q5_K" => 2e-2,
+        _ => 4e-2,
+    }
+}
+
+fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exps = logits.iter().map(|x| (x - max).exp()).collect::<Vec<_>>();
+    let sum = exps.iter().sum::<f32>();
+    exps.into_iter().map(|x| x / sum).collect()
+}
+
+/// Check the probabilities of the reference top tokens, and that the most likely token is one of
+/// them.
+fn compare(snapshot: &Snapshot, logits: &[f32]) -> Result<(), String> {
+    let probs = softmax(logits);
+    let tolerance = tolerance(&snapshot.quant);
+    let mut errors = Vec::new();
+    for TopToken { id, prob } in &snapshot.top_tokens {
+        let actual = probs.get(*id as usize).copied().unwrap_or(0.);
+        if (actual - prob).abs() > tolerance {
+            errors.push(format!(
+                "token {id}: expected p={prob:.4}, got p={actual:.4}"
+            ));
+        }
+    }
+    let argmax = (0..probs.len())
+        .max_by(|a, b| probs[*a].total_cmp(&probs[*b]))
+        .unwrap_or_default() as u32;
+    if !snapshot.top_tokens.iter().any(|t| t.id == argmax) {
+        errors.push(format!(
+            "most likely token {argmax} (p={:.4}) is not in the reference top tokens",
+            probs[argmax as usize]
+        ));
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} ({}, tolerance {tolerance}):\n  {}",
+            snapshot.architecture,
+            snapshot.file,
+            errors.join("\n  ")
+        ))
+    }
+}
+
+fn snapshots_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/logits_snapshots")
+}
+
+fn load_snapshots() -> Vec<Snapshot> {
+    let mut paths = std::fs::read_dir(snapshots_dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let snapshot = std::fs::read_to_string(&path).unwrap();
+            serde_json::from_str(&snapshot)
+                .unwrap_or_else(|e| panic!("Invalid snapshot {}: {e}", path.display()))
+        })
+        .collect()
+}
+
+#[tokio::test]
+#[ignore = "downloads the GGUF models"]
+async fn gguf_logits_match_llama_cpp() {
+    let snapshots = load_snapshots();
+    assert!(
+        !snapshots.is_empty(),
+        "No snapshot in {}, generate them with `scripts/gguf_logits_snapshot.py`.",
+        snapshots_dir().display()
+    );
+    let mut failures = Vec::new();
+    for snapshot in snapshots {
+        let model = GgufModelBuilder::new(&snapshot.model_id, vec![snapshot.file.clone()])
+            .with_force_cpu()
+            .with_prefix_cache_n(None)
+            .build()
+            .await
+            .unwrap();
+        let logits = model
+            .prompt_logits(snapshot.prompt_tokens.clone())
+            .await
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        if let Err(e) = compare(&snapshot, &logits) {
+            failures.push(e);
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn snapshots_are_valid() {
+    for snapshot in load_snapshots() {
+        assert!(!snapshot.prompt_tokens.is_empty(), "{}", snapshot.file);
+        assert!(!snapshot.top_tokens.is_empty(), "{}", snapshot.file);
+        assert!(
+            snapshot
+                .top_tokens
+                .windows(2)
+                .all(|w| w[0].prob >= w[1].prob),
+            "{}: top tokens are not sorted by probability",
+            snapshot.file
+        );
+    }
+}
+
+#[test]
+fn comparison_tolerance() {
+    let snapshot = Snapshot {
+        architecture: "llama".to_string(),
+        model_id: String::new(),
+        file: String::new(),
+        quant: "Q8_0".to_string(),
+        prompt_tokens: vec![1],
+        top_tokens: vec![TopToken { id: 2, prob: 0.6 }, TopToken { id: 0, prob: 0.3 }],
+    };
+    let logits_for = |probs: [f32; 4]| probs.map(f32::ln);
+
+    assert!(compare(&snapshot, &logits_for([0.295, 0.05, 0.605, 0.05])).is_ok());
+    // Outside the Q8_0 tolerance.
+    assert!(compare(&snapshot, &logits_for([0.33, 0.05, 0.57, 0.05])).is_err());
+    // A token outside the reference top tokens became the most likely.
+    let snapshot = Snapshot {
+        quant: "Q4_K".to_string(),
+        top_tokens: vec![TopToken { id: 2, prob: 0.4 }],
+        ..snapshot
+    };
+    assert!(compare(&snapshot, &logits_for([0.1, 0.43, 0.37, 0.1])).is_err());
+}
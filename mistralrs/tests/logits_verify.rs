@@ -0,0 +1,178 @@
+//! Verify a tiny GGUF Llama written to a temporary directory against reference logits, both
+//! directly and when building the model.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use candle_core::{
+    quantized::{gguf_file, GgmlDType, QTensor},
+    DType, Device, Tensor,
+};
+use mistralrs::{DeviceMapSetting, GgufModelBuilder, Model, TokenSource};
+
+const VOCAB: usize = 16;
+const HIDDEN: usize = 32;
+const INTERMEDIATE: usize = 64;
+const HEADS: usize = 4;
+const KV_HEADS: usize = 2;
+const HEAD_DIM: usize = HIDDEN / HEADS;
+const MODEL_FILE: &str = "tiny-llama.gguf";
+const TOLERANCE: f32 = 1e-4;
+
+/// Write a one-layer Llama with random weights and a unigram tokenizer to a new directory.
+fn write_model() -> candle_core::Result<PathBuf> {
+    let dir = std::env::temp_dir().join(format!("mistralrs_logits_verify_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+
+    let dev = Device::Cpu;
+    let weight = |rows: usize, cols: usize| -> candle_core::Result<QTensor> {
+        QTensor::quantize(
+            &Tensor::randn(0f32, 0.2, (rows, cols), &dev)?,
+            GgmlDType::F32,
+        )
+    };
+    let norm = || QTensor::quantize(&Tensor::ones(HIDDEN, DType::F32, &dev)?, GgmlDType::F32);
+    let tensors = [
+        ("token_embd.weight", weight(VOCAB, HIDDEN)?),
+        ("output_norm.weight", norm()?),
+        ("blk.0.attn_norm.weight", norm()?),
+        ("blk.0.ffn_norm.weight", norm()?),
+        ("blk.0.attn_q.weight", weight(HIDDEN, HIDDEN)?),
+        ("blk.0.attn_k.weight", weight(KV_HEADS * HEAD_DIM, HIDDEN)?),
+        ("blk.0.attn_v.weight", weight(KV_HEADS * HEAD_DIM, HIDDEN)?),
+        ("blk.0.attn_output.weight", weight(HIDDEN, HIDDEN)?),
+        ("blk.0.ffn_gate.weight", weight(INTERMEDIATE, HIDDEN)?),
+        ("blk.0.ffn_up.weight", weight(INTERMEDIATE, HIDDEN)?),
+        ("blk.0.ffn_down.weight", weight(HIDDEN, INTERMEDIATE)?),
+    ];
+
+    let string = |s: &str| gguf_file::Value::String(s.to_string());
+    let mut tokens = vec![string("<unk>"), string("<s>"), string("</s>")];
+    tokens.extend(
+        (b'a'..)
+            .take(VOCAB - 3)
+            .map(|c| string(&format!("▁{}", c as char))),
+    );
+    let metadata = [
+        ("general.architecture", string("llama")),
+        ("llama.block_count", gguf_file::Value::U32(1)),
+        (
+            "llama.embedding_length",
+            gguf_file::Value::U32(HIDDEN as u32),
+        ),
+        (
+            "llama.feed_forward_length",
+            gguf_file::Value::U32(INTERMEDIATE as u32),
+        ),
+        (
+            "llama.attention.head_count",
+            gguf_file::Value::U32(HEADS as u32),
+        ),
+        (
+            "llama.attention.head_count_kv",
+            gguf_file::Value::U32(KV_HEADS as u32),
+        ),
+        (
+            "llama.rope.dimension_count",
+            gguf_file::Value::U32(HEAD_DIM as u32),
+        ),
+        (
+            "llama.attention.layer_norm_rms_epsilon",
+            gguf_file::Value::F32(1e-5),
+        ),
+        ("tokenizer.ggml.model", string("llama")),
+        ("tokenizer.ggml.tokens", gguf_file::Value::Array(tokens)),
+        (
+            "tokenizer.ggml.scores",
+            gguf_file::Value::Array(vec![gguf_file::Value::F32(-1.); VOCAB]),
+        ),
+        ("tokenizer.ggml.unknown_token_id", gguf_file::Value::U32(0)),
+        ("tokenizer.ggml.bos_token_id", gguf_file::Value::U32(1)),
+        ("tokenizer.ggml.eos_token_id", gguf_file::Value::U32(2)),
+        (
+            "tokenizer.chat_template",
+            string("{% for message in messages %}{{ message['content'] }}{% endfor %}"),
+        ),
+    ];
+
+    let mut file = std::fs::File::create(dir.join(MODEL_FILE))?;
+    gguf_file::write(
+        &mut file,
+        &metadata.iter().map(|(k, v)| (*k, v)).collect::<Vec<_>>(),
+        &tensors.iter().map(|(n, t)| (*n, t)).collect::<Vec<_>>(),
+    )?;
+    Ok(dir)
+}
+
+async fn load(dir: &Path, reference: Option<&Path>) -> anyhow::Result<Model> {
+    let mut builder = GgufModelBuilder::new(dir.display().to_string(), vec![MODEL_FILE])
+        .with_force_cpu()
+        .with_token_source(TokenSource::None)
+        .with_prefix_cache_n(None)
+        .with_device_mapping(DeviceMapSetting::dummy());
+    if let Some(reference) = reference {
+        builder = builder.with_reference_logits(reference, TOLERANCE);
+    }
+    builder.build().await
+}
+
+fn save_reference(path: &Path, input_ids: &[u32], logits: &Tensor) -> candle_core::Result<()> {
+    let tensors = [
+        ("input_ids", Tensor::new(input_ids, &Device::Cpu)?),
+        ("logits", logits.clone()),
+    ];
+    candle_core::safetensors::save(&HashMap::from(tensors), path)
+}
+
+/// The logits with `offset` added to the logit at `index`.
+fn perturb(logits: &Tensor, index: usize, offset: f32) -> candle_core::Result<Tensor> {
+    let mut values = logits.to_vec1::<f32>()?;
+    values[index] += offset;
+    Tensor::new(values, &Device::Cpu)
+}
+
+#[tokio::test]
+async fn verify_tiny_model() -> anyhow::Result<()> {
+    let dir = write_model()?;
+    let input_ids = [1u32, 5, 9, 3, 12];
+
+    let model = load(&dir, None).await?;
+    let logits = model
+        .prompt_logits(input_ids.to_vec())
+        .await?
+        .to_device(&Device::Cpu)?
+        .to_dtype(DType::F32)?
+        .flatten_all()?;
+    assert_eq!(logits.dims(), [VOCAB]);
+
+    let report = model.verify_against(&input_ids, &logits, TOLERANCE).await?;
+    assert!(report.passed(), "{report}");
+    assert_eq!(report.num_divergent, 0);
+
+    let perturbed = perturb(&logits, 7, 1.)?;
+    let report = model
+        .verify_against(&input_ids, &perturbed, TOLERANCE)
+        .await?;
+    assert!(!report.passed());
+    assert_eq!(report.first_divergent_index, Some(7));
+    assert_eq!(report.max_abs_diff_index, 7);
+    assert_eq!(report.num_divergent, 1);
+
+    // The same checks when building the model.
+    let good = dir.join("reference.safetensors");
+    save_reference(&good, &input_ids, &logits)?;
+    load(&dir, Some(&good)).await?;
+
+    let bad = dir.join("perturbed.safetensors");
+    save_reference(&bad, &input_ids, &perturbed)?;
+    let Err(err) = load(&dir, Some(&bad)).await else {
+        panic!("building against perturbed reference logits succeeded");
+    };
+    assert!(err.to_string().contains("the first at index 7"), "{err}");
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
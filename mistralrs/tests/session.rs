@@ -0,0 +1,33 @@
+//! The second turn of a chat session reuses the KV cache of the first turn from the prefix cache.
+//!
+//! Loading the model downloads it, so this is ignored by default. Run it with
+//! `cargo test -p mistralrs --test session -- --include-ignored`.
+
+use mistralrs::{ChatSession, GgufModelBuilder, SamplingParams};
+
+#[tokio::test]
+#[ignore = "downloads the GGUF model"]
+async fn second_turn_hits_prefix_cache() {
+    let model = GgufModelBuilder::new(
+        "TheBloke/TinyLlama-1.1B-Chat-v1.0-GGUF",
+        vec!["tinyllama-1.1b-chat-v1.0.Q4_K_M.gguf"],
+    )
+    .with_force_cpu()
+    .build()
+    .await
+    .unwrap();
+    let params = SamplingParams {
+        max_len: Some(16),
+        ..SamplingParams::deterministic()
+    };
+
+    let mut session = ChatSession::new(&model).with_system_prompt("You are a helpful assistant.");
+    session.chat("Name a color.", &params).await.unwrap();
+    let first = model.prefix_cache_metrics().await.unwrap();
+    assert!(first.entries > 0, "the first turn was not cached");
+
+    session.chat("Name another one.", &params).await.unwrap();
+    let second = model.prefix_cache_metrics().await.unwrap();
+    assert_eq!(second.lookups, first.lookups + 1);
+    assert_eq!(second.hits, first.hits + 1);
+}
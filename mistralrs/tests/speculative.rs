@@ -0,0 +1,76 @@
+//! Greedy speculative decoding generates the same text as the target model alone, for tiny
+//! safetensors Llamas written to a temporary directory, both with a draft model which is the target
+//! model, whose draft tokens are all accepted, and with another draft model.
+
+mod common;
+
+use std::path::Path;
+
+use common::{tiny_llama, write_tiny_llama};
+use mistralrs::{
+    Model, RequestBuilder, SamplingParams, SpeculativeConfig, TextMessageRole,
+    TextSpeculativeBuilder,
+};
+
+const MAX_LEN: usize = 24;
+const GAMMA: usize = 3;
+
+async fn speculative(target: &Path, draft: &Path) -> anyhow::Result<Model> {
+    TextSpeculativeBuilder::new(
+        tiny_llama(target),
+        tiny_llama(draft),
+        SpeculativeConfig {
+            gamma: GAMMA,
+            gamma_bounds: None,
+        },
+    )?
+    .build()
+    .await
+}
+
+/// The greedy completion of a fixed prompt, with its speculative decoding statistics.
+async fn generate(
+    model: &Model,
+) -> anyhow::Result<(Option<String>, Option<mistralrs::SpeculativeStats>)> {
+    let request = RequestBuilder::new()
+        .add_message(TextMessageRole::User, "t3 t7 t11 t5")
+        .set_sampling(SamplingParams {
+            max_len: Some(MAX_LEN),
+            ..SamplingParams::deterministic()
+        });
+    let mut response = model.send_chat_request(request).await?;
+    let text = response.choices.swap_remove(0).message.content;
+    Ok((text, response.usage.speculative))
+}
+
+#[tokio::test]
+async fn greedy_speculative_decoding_matches_target() -> anyhow::Result<()> {
+    let dir = std::env::temp_dir().join(format!("mistralrs_speculative_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let (target, other) = (dir.join("target"), dir.join("draft"));
+    write_tiny_llama(&target, 0)?;
+    write_tiny_llama(&other, 1)?;
+
+    let (expected, stats) = generate(&tiny_llama(&target).build().await?).await?;
+    assert!(expected.is_some());
+    assert!(stats.is_none());
+
+    // Every draft token is accepted, so each step adds a bonus token from the target model.
+    let (text, stats) = generate(&speculative(&target, &target).await?).await?;
+    assert_eq!(text, expected);
+    let stats = stats.unwrap();
+    assert_eq!(stats.accepted_draft_tokens, stats.draft_tokens);
+    assert_eq!(stats.generated_tokens, stats.draft_tokens + stats.steps);
+
+    // The draft tokens which differ from those of the target model are rejected.
+    let (text, stats) = generate(&speculative(&target, &other).await?).await?;
+    assert_eq!(text, expected);
+    let stats = stats.unwrap();
+    assert!(
+        stats.accepted_draft_tokens < stats.draft_tokens,
+        "{stats:?}"
+    );
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
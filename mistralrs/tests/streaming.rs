@@ -0,0 +1,83 @@
+//! The chunks of a streamed request add up to the response of the same request sent without
+//! streaming, and the last one is marked as done.
+//!
+//! Loading the GGUF model downloads it, so its test is ignored by default. Run it with
+//! `cargo test -p mistralrs --test streaming -- --include-ignored`.
+
+mod common;
+
+use common::{tiny_llama, write_tiny_llama};
+use mistralrs::{GgufModelBuilder, RequestBuilder, SamplingParams, TextMessageRole, TextMessages};
+
+#[tokio::test]
+#[ignore = "downloads the GGUF model"]
+async fn streamed_chunks_match_response() {
+    let model = GgufModelBuilder::new(
+        "TheBloke/TinyLlama-1.1B-Chat-v1.0-GGUF",
+        vec!["tinyllama-1.1b-chat-v1.0.Q4_K_M.gguf"],
+    )
+    .with_force_cpu()
+    .build()
+    .await
+    .unwrap();
+
+    let messages = TextMessages::new().add_message(TextMessageRole::User, "Count from one to ten.");
+    let response = model.send_chat_request(messages.clone()).await.unwrap();
+
+    let mut chunks = model.send_chat_request_streaming(messages).await.unwrap();
+    let mut text = String::new();
+    let mut tokens = 0;
+    let mut done = false;
+    while let Some(chunk) = chunks.recv().await {
+        let chunk = chunk.unwrap();
+        assert!(!done, "chunk received after the last one");
+        text.push_str(&chunk.text);
+        tokens += chunk.tokens.len();
+        done = chunk.done;
+    }
+
+    assert!(done);
+    assert_eq!(Some(text), response.choices[0].message.content);
+    assert_eq!(tokens, response.usage.completion_tokens);
+}
+
+#[tokio::test]
+async fn tiny_model_streamed_chunks_match_response() -> anyhow::Result<()> {
+    let dir = std::env::temp_dir().join(format!("mistralrs_streaming_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    write_tiny_llama(&dir, 0)?;
+    let model = tiny_llama(&dir).build().await?;
+
+    let request = RequestBuilder::new()
+        .add_message(TextMessageRole::User, "t3 t7 t11 t5")
+        .set_sampling(SamplingParams {
+            max_len: Some(16),
+            ..SamplingParams::deterministic()
+        });
+    let response = model.send_chat_request(request.clone()).await?;
+
+    let mut chunks = model.send_chat_request_streaming(request.clone()).await?;
+    let mut text = String::new();
+    let mut tokens = 0;
+    let mut done = false;
+    while let Some(chunk) = chunks.recv().await {
+        let chunk = chunk?;
+        assert!(!done, "chunk received after the last one");
+        text.push_str(&chunk.text);
+        tokens += chunk.tokens.len();
+        done = chunk.done;
+    }
+    assert!(done);
+    assert_eq!(Some(text), response.choices[0].message.content);
+    assert_eq!(tokens, response.usage.completion_tokens);
+
+    // The chunks have the text of a single choice.
+    let err = model
+        .send_chat_request_streaming(request.set_sampler_n_choices(2))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("2 choices"), "{err}");
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
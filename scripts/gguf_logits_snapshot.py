@@ -0,0 +1,54 @@
+# Generate the reference logits snapshots of `mistralrs/tests/gguf_logits.rs` with llama.cpp.
+#
+# Requires `pip install llama-cpp-python huggingface_hub numpy`. To cover a new architecture, add
+# a small GGUF model of it to `MODELS` and commit the generated snapshot.
+
+import json
+import os
+
+import numpy as np
+from huggingface_hub import hf_hub_download
+from llama_cpp import Llama
+
+PROMPT = "The capital of France is"
+TOP_K = 20
+OUT_DIR = os.path.join(
+    os.path.dirname(__file__), "..", "mistralrs", "tests", "logits_snapshots"
+)
+
+# (architecture, model id, file, quantization)
+MODELS = [
+    (
+        "llama",
+        "TheBloke/TinyLlama-1.1B-Chat-v1.0-GGUF",
+        "tinyllama-1.1b-chat-v1.0.Q8_0.gguf",
+        "Q8_0",
+    ),
+    ("phi2", "TheBloke/phi-2-GGUF", "phi-2.Q8_0.gguf", "Q8_0"),
+]
+
+for architecture, model_id, file, quant in MODELS:
+    path = hf_hub_download(model_id, file)
+    llm = Llama(model_path=path, n_ctx=256, n_gpu_layers=0, verbose=False)
+    prompt_tokens = llm.tokenize(PROMPT.encode(), add_bos=True)
+    llm.eval(prompt_tokens)
+
+    logits = np.array(llm.scores[llm.n_tokens - 1], dtype=np.float64)
+    probs = np.exp(logits - logits.max())
+    probs /= probs.sum()
+    top = np.argsort(-probs)[:TOP_K]
+
+    snapshot = {
+        "architecture": architecture,
+        "model_id": model_id,
+        "file": file,
+        "quant": quant,
+        "prompt": PROMPT,
+        "prompt_tokens": prompt_tokens,
+        "top_tokens": [{"id": int(i), "prob": float(probs[i])} for i in top],
+    }
+    out = os.path.join(OUT_DIR, f"{architecture}.json")
+    with open(out, "w") as f:
+        json.dump(snapshot, f, indent=2)
+        f.write("\n")
+    print(f"Wrote {out}")